@@ -0,0 +1,157 @@
+// Roots of the bucket signed by the server, kept to hold it accountable for
+// them
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+use merkle::tree::Hash;
+
+/// File of the attested roots, one JSON record per line, in the client
+/// folder
+const ATTESTATIONS_FILE: &str = "/attestations.jsonl";
+
+/// Signature of a root of the bucket by the server
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Attestation {
+    /// Version of the bucket, the sequence number of the root
+    pub version: u64,
+    /// Unix time in seconds the root was recorded
+    pub timestamp: u64,
+    /// Hex encoded ed25519 public key of the server
+    pub public_key: String,
+    /// Hex encoded ed25519 signature
+    pub signature: String,
+}
+
+/// An attested root as recorded in the client folder
+#[derive(serde::Serialize)]
+struct AttestedRoot<'a> {
+    bucket_id: &'a str,
+    root: String,
+    leaves_count: usize,
+    #[serde(flatten)]
+    attestation: &'a Attestation,
+}
+
+/// The fields of a recorded root read back from the attestations file
+#[derive(serde::Deserialize)]
+struct RecordedRoot {
+    bucket_id: String,
+    root: String,
+    version: u64,
+}
+
+impl Attestation {
+    /// Returns true if the server signed `root` with `leaves_count` leaves
+    /// as the root of the bucket at this version
+    pub(crate) fn verify(
+        &self,
+        bucket_id: &str,
+        root: &Hash,
+        leaves_count: usize,
+    ) -> bool {
+        let message = format!(
+            "storage-attestation-v1\n{bucket_id}\n{}\n{}\n{leaves_count}\n{}",
+            self.version,
+            hex::encode(root),
+            self.timestamp
+        );
+
+        let key = hex::decode(&self.public_key)
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .and_then(|key| VerifyingKey::from_bytes(&key).ok());
+        let signature = hex::decode(&self.signature)
+            .ok()
+            .and_then(|signature| <[u8; 64]>::try_from(signature).ok())
+            .map(|signature| Signature::from_bytes(&signature));
+
+        match (key, signature) {
+            (Some(key), Some(signature)) => {
+                key.verify(message.as_bytes(), &signature).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// Appends the attested root to the attestations file of the client
+    /// folder
+    pub(crate) fn record(
+        &self,
+        client_folder: &str,
+        bucket_id: &str,
+        root: &Hash,
+        leaves_count: usize,
+    ) -> io::Result<()> {
+        let record = AttestedRoot {
+            bucket_id,
+            root: hex::encode(root),
+            leaves_count,
+            attestation: self,
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(client_folder.to_owned() + ATTESTATIONS_FILE)?
+            .write_all(line.as_bytes())
+    }
+}
+
+/// Returns the root of version `version` of the bucket, as attested by the
+/// server and recorded in the client folder, `None` if it was not recorded
+pub(crate) fn recorded_root(
+    client_folder: &str,
+    bucket_id: &str,
+    version: u64,
+) -> io::Result<Option<Hash>> {
+    let records = match fs::read_to_string(
+        client_folder.to_owned() + ATTESTATIONS_FILE,
+    ) {
+        Ok(records) => records,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    // The last record of a version wins
+    Ok(records
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<RecordedRoot>(line).ok())
+        .filter(|record| {
+            record.bucket_id == bucket_id && record.version == version
+        })
+        .filter_map(|record| hex::decode(record.root).ok())
+        .find_map(|root| <Hash>::try_from(root).ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_recorded_root() {
+        let folder = TempDir::new("attestations").unwrap();
+        let folder = folder.path().to_str().unwrap();
+        assert_eq!(recorded_root(folder, "b", 0).unwrap(), None);
+
+        let attestation = |version| Attestation {
+            version,
+            timestamp: 0,
+            public_key: String::new(),
+            signature: String::new(),
+        };
+        attestation(0).record(folder, "b", &[1; 32], 1).unwrap();
+        attestation(1).record(folder, "b", &[2; 32], 2).unwrap();
+        attestation(1).record(folder, "c", &[3; 32], 1).unwrap();
+
+        assert_eq!(recorded_root(folder, "b", 0).unwrap(), Some([1; 32]));
+        assert_eq!(recorded_root(folder, "b", 1).unwrap(), Some([2; 32]));
+        assert_eq!(recorded_root(folder, "c", 0).unwrap(), None);
+    }
+}
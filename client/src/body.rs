@@ -0,0 +1,57 @@
+// Bodies of the requests and replies exchanged with the storage servers
+
+use bytes::Bytes;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Frame, Incoming};
+use tokio_stream::{Stream, StreamExt};
+
+/// Error of a streamed request body
+pub(crate) type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Body of a request, buffered or streamed
+pub(crate) type Body = UnsyncBoxBody<Bytes, BoxError>;
+
+/// Returns an empty request body
+pub(crate) fn empty() -> Body {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
+
+/// Returns a request body sending `bytes` at once
+pub(crate) fn full(bytes: impl Into<Bytes>) -> Body {
+    Full::new(bytes.into())
+        .map_err(|never| match never {})
+        .boxed_unsync()
+}
+
+/// Returns a request body sending the chunks of `stream` as they come,
+/// aborting the request on the first error
+pub(crate) fn wrap_stream<S, E>(stream: S) -> Body
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Into<BoxError> + 'static,
+{
+    let frames = stream.map(|chunk| chunk.map(Frame::data).map_err(Into::into));
+    StreamBody::new(frames).boxed_unsync()
+}
+
+/// Receives the whole body of a reply
+pub(crate) async fn to_bytes(body: Incoming) -> Result<Bytes, hyper::Error> {
+    Ok(body.collect().await?.to_bytes())
+}
+
+/// Receives the next chunk of data of a reply, `None` at its end
+pub(crate) async fn next_chunk(
+    body: &mut Incoming,
+) -> Option<Result<Bytes, hyper::Error>> {
+    loop {
+        match body.frame().await? {
+            Ok(frame) => match frame.into_data() {
+                Ok(data) => return Some(Ok(data)),
+                // Trailers carry no data
+                Err(_) => continue,
+            },
+            Err(err) => return Some(Err(err)),
+        }
+    }
+}
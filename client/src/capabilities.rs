@@ -0,0 +1,23 @@
+// Server capabilities as advertised by `GET /capabilities`
+
+/// Features supported by the storage server
+///
+/// Missing fields default to the behavior of a server that predates the
+/// capabilities endpoint.
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct Capabilities {
+    pub protocol_version: u32,
+    pub max_body_size: Option<u64>,
+    pub content_encodings: Vec<String>,
+    pub range_requests: bool,
+    pub multiproof: bool,
+    pub max_batch_proofs: Option<usize>,
+    pub proof_by_hash: bool,
+    pub resumable_upload: bool,
+    pub max_chunk_size: Option<u64>,
+    pub multipart_upload: bool,
+    pub max_parts: Option<u64>,
+    pub upload_sessions: bool,
+    pub auth_schemes: Vec<String>,
+}
@@ -0,0 +1,715 @@
+// Encryption of the files of the client
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{self, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use merkle::tree::Hash;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+
+/// Key shared by the clients run with `--builtin-key`, and by all the files
+/// encrypted before the files were sealed
+const BUILTIN_KEY: Key = [0x24; 32];
+/// Key of the files of the client
+static KEY: OnceLock<Key> = OnceLock::new();
+/// Marks a file sealed with ChaCha20-Poly1305, segment by segment
+const MAGIC: &[u8; 4] = b"GSN2";
+/// Length of the nonce prefix of the segments of a file, the rest of their
+/// nonce being their counter
+const NONCE_LEN: usize = 7;
+/// Length of the header of a sealed file, the magic and the nonce
+const HEADER_LEN: usize = MAGIC.len() + NONCE_LEN;
+/// Length of the content of a segment, the last one being shorter
+const SEGMENT_LEN: usize = 64 * 1024;
+/// Length of the authentication tag closing each segment
+const TAG_LEN: usize = 16;
+
+/// Marks a file encrypted with ChaCha20 under a nonce of its own, without
+/// authentication
+const MAGIC_V1: &[u8; 4] = b"GSN1";
+/// Length of the header of such a file, the magic and the nonce
+const HEADER_V1_LEN: usize = MAGIC_V1.len() + 12;
+/// Nonce of the files uploaded before the nonces were drawn per file
+const LEGACY_NONCE: [u8; 12] = [0x24; 12];
+
+/// Size of a chunk read at once from a file
+const READ_SIZE: usize = 1024 * 1024;
+
+pub(crate) type Nonce = [u8; NONCE_LEN];
+pub(crate) type Key = [u8; 32];
+
+/// Encrypts the files with `key` instead of the built-in key
+///
+/// Must be called before the first transfer to take effect.
+pub(crate) fn init_key(key: Key) {
+    let _ = KEY.set(key);
+}
+
+fn key() -> Key {
+    KEY.get().copied().unwrap_or(BUILTIN_KEY)
+}
+
+/// Returns the check of the key of the client, recorded by the buckets to
+/// tell which key sealed their files without keeping it
+pub(crate) fn client_key_check() -> Hash {
+    key_check(&key())
+}
+
+/// Returns the check of the built-in key
+pub(crate) fn builtin_key_check() -> Hash {
+    key_check(&BUILTIN_KEY)
+}
+
+/// Parses a key given as 64 hex digits
+pub(crate) fn parse_key(key: &str) -> Result<Key, String> {
+    let key = hex::decode(key.trim()).map_err(|err| err.to_string())?;
+    key.try_into()
+        .map_err(|_| "the key must be 32 bytes, 64 hex digits".to_owned())
+}
+
+/// Reads a key file, holding either 32 raw bytes or 64 hex digits
+pub(crate) fn read_key_file(path: &Path) -> io::Result<Key> {
+    let bytes = fs::read(path)?;
+    if let Ok(key) = Key::try_from(bytes.as_slice()) {
+        return Ok(key);
+    }
+
+    let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidData, err);
+    let hex = String::from_utf8(bytes)
+        .map_err(|_| invalid("the key file is neither raw nor hex".into()))?;
+    parse_key(&hex).map_err(invalid)
+}
+
+/// Parameters deriving the key of the client from a passphrase with
+/// Argon2id, kept in the state file
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct KdfParams {
+    salt: [u8; 16],
+    /// Memory of the derivation, in KiB
+    m_cost: u32,
+    /// Number of passes over the memory
+    t_cost: u32,
+    /// Degree of parallelism
+    p_cost: u32,
+    /// Hash of the derived key, telling a mistyped passphrase
+    check: Hash,
+}
+
+impl KdfParams {
+    /// Draws the salt of a client, with the default costs of Argon2id, and
+    /// derives its key from `passphrase`
+    pub(crate) fn new(passphrase: &str) -> Result<(Self, Key), argon2::Error> {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        let mut params = KdfParams {
+            salt,
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            check: Hash::default(),
+        };
+        let key = params.hash(passphrase)?;
+        params.check = key_check(&key);
+
+        Ok((params, key))
+    }
+
+    /// Derives the key of the client from `passphrase`, `None` if it is not
+    /// the passphrase of the client
+    pub(crate) fn derive(
+        &self,
+        passphrase: &str,
+    ) -> Result<Option<Key>, argon2::Error> {
+        let key = self.hash(passphrase)?;
+        Ok((key_check(&key) == self.check).then_some(key))
+    }
+
+    fn hash(&self, passphrase: &str) -> Result<Key, argon2::Error> {
+        let params = Params::new(
+            self.m_cost,
+            self.t_cost,
+            self.p_cost,
+            Some(size_of::<Key>()),
+        )?;
+        let mut key = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)?;
+        Ok(key)
+    }
+}
+
+fn key_check(key: &Key) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(b"storage-client-key-check");
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+/// Draws the nonce of a file, so that no two files share a keystream
+pub(crate) fn random_nonce() -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Returns the data authenticated along with each segment of a file, so that
+/// a file served under another name or in another bucket fails to decrypt
+pub(crate) fn aad(bucket_id: &str, file_name: &str) -> Vec<u8> {
+    format!("{bucket_id}/{file_name}").into_bytes()
+}
+
+/// Returns the size of a file of `size` bytes once sealed, header included
+pub(crate) fn encrypted_size(size: u64) -> u64 {
+    let segments = size.div_ceil(SEGMENT_LEN as u64).max(1);
+    HEADER_LEN as u64 + size + segments * TAG_LEN as u64
+}
+
+/// Computes the hash a file will have once sealed with `nonce`, without
+/// keeping the encrypted content in memory
+pub(crate) fn encrypted_hash(
+    file_path: &str,
+    nonce: &Nonce,
+    aad: Vec<u8>,
+) -> io::Result<Hash> {
+    let mut encryptor = Encryptor::new(nonce, aad);
+    let mut hasher = Sha256::new();
+
+    read_chunks(file_path, |chunk| hasher.update(encryptor.update(chunk)))?;
+    hasher.update(encryptor.finish());
+
+    Ok(hasher.finalize().into())
+}
+
+/// Computes the hash of the content of a source file and the hash it had
+/// once encrypted before the nonces were drawn per file, the leaf of the
+/// files uploaded by then
+pub(crate) fn source_hashes(file_path: &str) -> io::Result<(Hash, Hash)> {
+    let mut cipher = ChaCha20::new(&BUILTIN_KEY.into(), &LEGACY_NONCE.into());
+    let mut source = Sha256::new();
+    let mut legacy = Sha256::new();
+
+    read_chunks(file_path, |chunk| {
+        source.update(&*chunk);
+        cipher.apply_keystream(chunk);
+        legacy.update(chunk);
+    })?;
+
+    Ok((source.finalize().into(), legacy.finalize().into()))
+}
+
+fn read_chunks(
+    file_path: &str,
+    mut f: impl FnMut(&mut [u8]),
+) -> io::Result<()> {
+    let mut file = fs::File::open(file_path)?;
+    let mut chunk = vec![0u8; READ_SIZE];
+    loop {
+        let n = file.read(&mut chunk)?;
+        if n == 0 {
+            return Ok(());
+        }
+        f(&mut chunk[..n]);
+    }
+}
+
+/// Seals a file as it is read with the key of the client, in segments
+/// authenticated along with `aad`
+///
+/// The sealed file starts with a header holding its nonce, the last segment
+/// is sealed as such so that a truncated file fails to decrypt.
+pub(crate) struct Encryptor {
+    stream: EncryptorBE32<ChaCha20Poly1305>,
+    aad: Vec<u8>,
+    /// Header of the file, until it is returned
+    header: Option<[u8; HEADER_LEN]>,
+    /// Content of the segment being filled
+    buffer: Vec<u8>,
+}
+
+impl Encryptor {
+    pub(crate) fn new(nonce: &Nonce, aad: Vec<u8>) -> Self {
+        let mut header = [0u8; HEADER_LEN];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        header[MAGIC.len()..].copy_from_slice(nonce);
+
+        Encryptor {
+            stream: EncryptorBE32::new(&key().into(), nonce.into()),
+            aad,
+            header: Some(header),
+            buffer: Vec::with_capacity(SEGMENT_LEN),
+        }
+    }
+
+    /// Returns the sealed bytes of the next piece of content, as many
+    /// segments as are complete
+    ///
+    /// A complete segment is kept until more content follows, since the
+    /// last one is sealed differently.
+    pub(crate) fn update(&mut self, content: &[u8]) -> Vec<u8> {
+        let mut sealed = self.header.take().map_or_else(Vec::new, Vec::from);
+        self.buffer.extend_from_slice(content);
+
+        let mut start = 0;
+        while self.buffer.len() - start > SEGMENT_LEN {
+            let segment = Payload {
+                msg: &self.buffer[start..start + SEGMENT_LEN],
+                aad: &self.aad,
+            };
+            let segment = self
+                .stream
+                .encrypt_next(segment)
+                .expect("segment count within the stream limit");
+            sealed.extend_from_slice(&segment);
+            start += SEGMENT_LEN;
+        }
+        self.buffer.drain(..start);
+
+        sealed
+    }
+
+    /// Returns the sealed bytes of the last segment, once the whole content
+    /// went through `update`
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        let mut sealed = self.header.take().map_or_else(Vec::new, Vec::from);
+        let segment = Payload {
+            msg: &self.buffer,
+            aad: &self.aad,
+        };
+        let segment = self
+            .stream
+            .encrypt_last(segment)
+            .expect("segment count within the stream limit");
+        sealed.extend_from_slice(&segment);
+        sealed
+    }
+}
+
+/// Reads a file sealed by an `Encryptor`, in pieces of any size
+pub(crate) struct EncryptedReader {
+    file: tokio::fs::File,
+    /// Encryptor of the file, until the file is read through
+    encryptor: Option<Encryptor>,
+    /// Sealed bytes not yet returned
+    buffer: Vec<u8>,
+}
+
+impl EncryptedReader {
+    pub(crate) async fn open(
+        file_path: &str,
+        encryptor: Encryptor,
+    ) -> io::Result<Self> {
+        Ok(EncryptedReader {
+            file: tokio::fs::File::open(file_path).await?,
+            encryptor: Some(encryptor),
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Returns the next `len` bytes of the sealed file, fewer at its end
+    pub(crate) async fn read(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut chunk = vec![0u8; READ_SIZE];
+        while self.buffer.len() < len {
+            let Some(encryptor) = &mut self.encryptor else {
+                break;
+            };
+            let n = self.file.read(&mut chunk).await?;
+            let sealed = if n > 0 {
+                encryptor.update(&chunk[..n])
+            } else {
+                self.encryptor
+                    .take()
+                    .expect("file not read through")
+                    .finish()
+            };
+            self.buffer.extend_from_slice(&sealed);
+        }
+
+        let rest = self.buffer.split_off(len.min(self.buffer.len()));
+        Ok(std::mem::replace(&mut self.buffer, rest))
+    }
+}
+
+/// Failure to decrypt a file
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DecryptError {
+    /// A segment failed authentication, or the file is truncated
+    #[error("failed authentication")]
+    Tampered,
+    /// The file is not sealed and the formats without authentication are
+    /// not accepted for it
+    #[error("legacy format without authentication")]
+    Legacy,
+}
+
+impl From<aead::Error> for DecryptError {
+    fn from(_: aead::Error) -> Self {
+        DecryptError::Tampered
+    }
+}
+
+/// Decrypts a file as it is received, in the format given by its header
+///
+/// A file without a header was uploaded before the nonces were drawn per
+/// file, it is decrypted with the nonce of the time. Neither it nor a file
+/// of the first header are authenticated, both are encrypted with the
+/// built-in key, so they are only accepted if `legacy` allows them: a server
+/// could otherwise strip the header of a sealed file. A sealed file is
+/// opened with the key of the client, or with the built-in key for the
+/// buckets sealed with it.
+pub(crate) struct Decryptor {
+    aad: Vec<u8>,
+    /// Whether the formats without authentication are accepted
+    legacy: bool,
+    /// Key opening a sealed file
+    key: Key,
+    /// First bytes of the file, until its format is known
+    head: Vec<u8>,
+    format: Option<Format>,
+}
+
+enum Format {
+    Sealed {
+        stream: DecryptorBE32<ChaCha20Poly1305>,
+        /// Sealed bytes of the segment being received
+        buffer: Vec<u8>,
+    },
+    Unsealed(ChaCha20),
+}
+
+impl Decryptor {
+    /// Decrypts a file authenticated along with `aad`, or in a legacy
+    /// format if `legacy`
+    pub(crate) fn new(aad: Vec<u8>, legacy: bool) -> Self {
+        Decryptor {
+            aad,
+            legacy,
+            key: key(),
+            head: Vec::new(),
+            format: None,
+        }
+    }
+
+    /// Opens a sealed file with the built-in key instead of the key of the
+    /// client
+    pub(crate) fn with_builtin_key(mut self) -> Self {
+        self.key = BUILTIN_KEY;
+        self
+    }
+
+    /// Returns the content of the next chunk of the encrypted file, an error
+    /// if a segment fails authentication or the format is not accepted
+    pub(crate) fn update(
+        &mut self,
+        chunk: &[u8],
+    ) -> Result<Vec<u8>, DecryptError> {
+        let Some(format) = &mut self.format else {
+            self.head.extend_from_slice(chunk);
+            return self.start(false);
+        };
+
+        match format {
+            Format::Sealed { stream, buffer } => {
+                buffer.extend_from_slice(chunk);
+
+                let mut content = Vec::new();
+                let mut start = 0;
+                while buffer.len() - start > SEGMENT_LEN + TAG_LEN {
+                    let end = start + SEGMENT_LEN + TAG_LEN;
+                    let segment = Payload {
+                        msg: &buffer[start..end],
+                        aad: &self.aad,
+                    };
+                    content.extend_from_slice(&stream.decrypt_next(segment)?);
+                    start = end;
+                }
+                buffer.drain(..start);
+
+                Ok(content)
+            }
+            Format::Unsealed(cipher) => {
+                let mut content = chunk.to_vec();
+                cipher.apply_keystream(&mut content);
+                Ok(content)
+            }
+        }
+    }
+
+    /// Returns the content still buffered once the whole file is received,
+    /// an error if the file is truncated or fails authentication
+    pub(crate) fn finish(mut self) -> Result<Vec<u8>, DecryptError> {
+        let mut content = match self.format {
+            Some(_) => Vec::new(),
+            None => self.start(true)?,
+        };
+
+        if let Some(Format::Sealed { stream, buffer }) = self.format {
+            let segment = Payload {
+                msg: &buffer,
+                aad: &self.aad,
+            };
+            content.extend_from_slice(&stream.decrypt_last(segment)?);
+        }
+        Ok(content)
+    }
+
+    /// Picks the format of the file from its first bytes, once they hold
+    /// the longest header or the file is `complete`, and returns the content
+    /// they hold
+    fn start(&mut self, complete: bool) -> Result<Vec<u8>, DecryptError> {
+        if self.head.len() < HEADER_V1_LEN && !complete {
+            return Ok(Vec::new());
+        }
+
+        let head = std::mem::take(&mut self.head);
+        let (format, start) = if head.starts_with(MAGIC)
+            && head.len() >= HEADER_LEN
+        {
+            let nonce = &head[MAGIC.len()..HEADER_LEN];
+            let sealed = Format::Sealed {
+                stream: DecryptorBE32::new(&self.key.into(), nonce.into()),
+                buffer: Vec::new(),
+            };
+            (sealed, HEADER_LEN)
+        } else if !self.legacy {
+            return Err(DecryptError::Legacy);
+        } else if head.starts_with(MAGIC_V1) && head.len() >= HEADER_V1_LEN {
+            let nonce = &head[MAGIC_V1.len()..HEADER_V1_LEN];
+            let cipher = ChaCha20::new(&BUILTIN_KEY.into(), nonce.into());
+            (Format::Unsealed(cipher), HEADER_V1_LEN)
+        } else {
+            let nonce = &LEGACY_NONCE.into();
+            (
+                Format::Unsealed(ChaCha20::new(&BUILTIN_KEY.into(), nonce)),
+                0,
+            )
+        };
+
+        self.format = Some(format);
+        self.update(&head[start..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use tempdir::TempDir;
+
+    /// Sizes around the segment boundaries
+    const SIZES: [usize; 8] = [
+        0,
+        1,
+        SEGMENT_LEN - 1,
+        SEGMENT_LEN,
+        SEGMENT_LEN + 1,
+        2 * SEGMENT_LEN,
+        2 * SEGMENT_LEN + 1,
+        3 * SEGMENT_LEN + 5,
+    ];
+
+    fn content(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Seals `content` given to the encryptor in pieces of `piece` bytes
+    fn seal(
+        content: &[u8],
+        nonce: &Nonce,
+        aad: &[u8],
+        piece: usize,
+    ) -> Vec<u8> {
+        let mut encryptor = Encryptor::new(nonce, aad.to_vec());
+        let mut sealed = Vec::new();
+        for piece in content.chunks(piece) {
+            sealed.extend_from_slice(&encryptor.update(piece));
+        }
+        sealed.extend_from_slice(&encryptor.finish());
+        sealed
+    }
+
+    /// Opens `sealed` received in chunks of `chunk` bytes
+    fn open(
+        sealed: &[u8],
+        aad: &[u8],
+        legacy: bool,
+        chunk: usize,
+    ) -> Result<Vec<u8>, DecryptError> {
+        let mut decryptor = Decryptor::new(aad.to_vec(), legacy);
+        let mut content = Vec::new();
+        for chunk in sealed.chunks(chunk) {
+            content.extend_from_slice(&decryptor.update(chunk)?);
+        }
+        content.extend_from_slice(&decryptor.finish()?);
+        Ok(content)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let aad = aad("bucket", "file");
+        for size in SIZES {
+            let content = content(size);
+            let nonce = random_nonce();
+            let sealed = seal(&content, &nonce, &aad, READ_SIZE);
+            assert_eq!(sealed.len() as u64, encrypted_size(size as u64));
+            assert_eq!(&sealed[..MAGIC.len()], MAGIC);
+            assert_eq!(&sealed[MAGIC.len()..HEADER_LEN], nonce);
+
+            // The content does not depend on how it is given or received
+            for piece in [1000, SEGMENT_LEN, SEGMENT_LEN + 1] {
+                assert_eq!(seal(&content, &nonce, &aad, piece), sealed);
+            }
+            for chunk in [1, 7, 4096, SEGMENT_LEN + TAG_LEN, sealed.len() + 1] {
+                let opened = open(&sealed, &aad, false, chunk);
+                assert_eq!(opened.unwrap(), content, "size {size}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_encrypted_hash() {
+        let dir = TempDir::new("cipher_hash").expect("valid temp dir");
+        let path = dir.path().join("file");
+        let content = content(2 * SEGMENT_LEN + 3);
+        fs::write(&path, &content).unwrap();
+
+        // The hash signed before the upload is the one of the sent bytes
+        let nonce = random_nonce();
+        let aad = aad("bucket", "file");
+        let hash = encrypted_hash(path.to_str().unwrap(), &nonce, aad.clone());
+        let sealed = seal(&content, &nonce, &aad, READ_SIZE);
+        assert_eq!(hash.unwrap(), <Hash>::from(Sha256::digest(&sealed)));
+    }
+
+    #[test]
+    fn test_nonces() {
+        let nonces: HashSet<Nonce> =
+            (0..10_000).map(|_| random_nonce()).collect();
+        assert_eq!(nonces.len(), 10_000);
+
+        // The same content sealed twice shares no keystream
+        let aad = aad("bucket", "file");
+        let content = content(SEGMENT_LEN + 1);
+        let first = seal(&content, &random_nonce(), &aad, READ_SIZE);
+        let second = seal(&content, &random_nonce(), &aad, READ_SIZE);
+        assert_ne!(first[..HEADER_LEN], second[..HEADER_LEN]);
+        let same = first[HEADER_LEN..]
+            .iter()
+            .zip(&second[HEADER_LEN..])
+            .filter(|(a, b)| a == b)
+            .count();
+        assert!(same < content.len() / 64, "{same} equal bytes");
+    }
+
+    #[test]
+    fn test_truncated() {
+        let aad = aad("bucket", "file");
+        for size in SIZES {
+            let sealed = seal(&content(size), &random_nonce(), &aad, READ_SIZE);
+
+            let mut cuts = vec![sealed.len() - 1, HEADER_LEN, TAG_LEN];
+            // Whole segments missing at the end
+            cuts.extend((1..size.div_ceil(SEGMENT_LEN)).map(|segments| {
+                HEADER_LEN + segments * (SEGMENT_LEN + TAG_LEN)
+            }));
+            for cut in cuts {
+                let opened = open(&sealed[..cut], &aad, false, 4096);
+                assert!(opened.is_err(), "size {size} cut at {cut}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_flipped_bit() {
+        let aad = aad("bucket", "file");
+        let sealed = seal(
+            &content(2 * SEGMENT_LEN + 1),
+            &random_nonce(),
+            &aad,
+            READ_SIZE,
+        );
+        assert!(open(&sealed, &aad, false, 4096).is_ok());
+
+        let last_segment = HEADER_LEN + 2 * (SEGMENT_LEN + TAG_LEN);
+        for index in [
+            MAGIC.len() + 1,
+            HEADER_LEN - 1,
+            HEADER_LEN,
+            HEADER_LEN + SEGMENT_LEN,
+            HEADER_LEN + SEGMENT_LEN + TAG_LEN + 9,
+            last_segment,
+            sealed.len() - 1,
+        ] {
+            for bit in [0, 7] {
+                let mut tampered = sealed.clone();
+                tampered[index] ^= 1 << bit;
+                let opened = open(&tampered, &aad, false, 4096);
+                assert!(
+                    matches!(opened, Err(DecryptError::Tampered)),
+                    "byte {index} bit {bit}"
+                );
+            }
+        }
+
+        // A file without the magic is not sealed, no longer authenticated
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        let opened = open(&tampered, &aad, false, 4096);
+        assert!(matches!(opened, Err(DecryptError::Legacy)));
+    }
+
+    #[test]
+    fn test_aad() {
+        let content = content(SEGMENT_LEN + 1);
+        let sealed =
+            seal(&content, &random_nonce(), &aad("bucket", "a"), READ_SIZE);
+        let opened = open(&sealed, &aad("bucket", "a"), false, 4096);
+        assert_eq!(opened.unwrap(), content);
+
+        // Neither served under another name nor from another bucket
+        for other in [aad("bucket", "b"), aad("other", "a")] {
+            let opened = open(&sealed, &other, false, 4096);
+            assert!(matches!(opened, Err(DecryptError::Tampered)));
+        }
+    }
+
+    #[test]
+    fn test_legacy() {
+        let content = content(1000);
+        let aad = aad("bucket", "file");
+
+        let mut headerless = content.clone();
+        ChaCha20::new(&BUILTIN_KEY.into(), &LEGACY_NONCE.into())
+            .apply_keystream(&mut headerless);
+        let nonce = [7u8; 12];
+        let mut v1 = [MAGIC_V1.as_slice(), &nonce].concat();
+        let mut body = content.clone();
+        ChaCha20::new(&BUILTIN_KEY.into(), &nonce.into())
+            .apply_keystream(&mut body);
+        v1.extend_from_slice(&body);
+
+        // Refused unless allowed, decrypted then
+        for encrypted in [headerless, v1] {
+            let opened = open(&encrypted, &aad, false, 4096);
+            assert!(matches!(opened, Err(DecryptError::Legacy)));
+            assert_eq!(open(&encrypted, &aad, true, 5).unwrap(), content);
+        }
+
+        // A sealed file is authenticated all the same
+        let sealed = seal(&content, &random_nonce(), &aad, READ_SIZE);
+        assert_eq!(open(&sealed, &aad, true, 4096).unwrap(), content);
+        let mut tampered = sealed;
+        tampered[HEADER_LEN] ^= 1;
+        let opened = open(&tampered, &aad, true, 4096);
+        assert!(matches!(opened, Err(DecryptError::Tampered)));
+    }
+}
@@ -1,27 +1,123 @@
-use hyper::{body::HttpBody as _, Client};
-use hyper::{Body, Method, Request, StatusCode};
+use hyper::body::{Body as _, Incoming};
+use hyper::http::request;
+use hyper::{Method, Request, StatusCode};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::{Client, ResponseFuture};
+use hyper_util::rt::TokioExecutor;
+
+use bytes::Bytes;
 
-use chacha20::cipher::{KeyIvInit, StreamCipher};
-use chacha20::ChaCha20;
 use rand::{self, RngCore};
 use sha2::{Digest, Sha256};
-use tokio::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsString;
 use std::fs;
-use std::path::Path;
-use std::sync::Arc;
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 use tokio::task::JoinSet;
-use tracing::{error, info};
+use tracing::{error, info, info_span, warn, Instrument};
 
 use merkle::tree as merkle;
 use merkle::Hash;
 
+use crate::attestation::{self, Attestation};
+use crate::body::{self, Body};
+use crate::capabilities::Capabilities;
+use crate::cipher::{
+    self, DecryptError, Decryptor, EncryptedReader, Encryptor, KdfParams,
+};
+use crate::journal;
+use crate::progress::{self, FileProgress};
+use crate::report::ReportEntry;
+use crate::retry::{self, RetryPolicy};
+use crate::signing::{Action, Signer};
+use crate::telemetry;
+use crate::throttle;
+
 pub(crate) const LOCAL_REPO: &str = "/downloaded_files";
-const STATE_FILE: &str = "/state_file.bin";
-const CHACHA_KEY: [u8; 32] = [0x24; 32];
+/// File receiving a download until its proof is verified
+const DOWNLOAD_PART_FILE: &str = "/download.part";
+const STATE_FILE: &str = "/buckets_v4.bin";
+/// State file of the clients predating the key checks of the buckets
+const BUCKETS_V3_FILE: &str = "/buckets_v3.bin";
+/// State file of the clients predating the key derivation parameters
+const BUCKETS_V2_FILE: &str = "/buckets_v2.bin";
+/// State file of the clients whose records predate the hash of the source
+/// files
+const BUCKETS_V1_FILE: &str = "/buckets.bin";
+/// State file of the clients holding a single bucket, loaded as the
+/// `DEFAULT_BUCKET`
+const LEGACY_STATE_FILE: &str = "/state_file.bin";
+/// Name of the bucket of a new client
+pub(crate) const DEFAULT_BUCKET: &str = "default";
+
+/// Size of a file chunk processed by a single upload pipeline stage
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// Number of chunks buffered between two upload pipeline stages
+const PIPELINE_DEPTH: usize = 4;
+
+/// Files larger than this are uploaded in chunks if the server supports it
+const RESUMABLE_THRESHOLD: u64 = 16 * 1024 * 1024;
+/// Size of a chunk of a resumable upload
+const RESUMABLE_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+/// Number of attempts to send a chunk of a resumable upload
+const CHUNK_ATTEMPTS: usize = 5;
+/// Delay between two attempts of a chunk of a large file
+const CHUNK_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Header carrying the token of the upload session of a request
+const SESSION_HEADER: &str = "x-upload-session";
+/// Header carrying the tags of an uploaded file
+const TAGS_HEADER: &str = "x-tags";
+/// Header carrying the id the server gave to a request
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Number of proofs requested at once if the server does not advertise a
+/// limit
+const BATCH_PROOFS: usize = 256;
+
+/// Number of files requested per page when listing the bucket
+const LIST_PAGE_SIZE: usize = 100;
+
+/// HTTP client of all the requests, pooling its connections to the servers
+static HTTP_CLIENT: OnceLock<Client<HttpConnector, Body>> = OnceLock::new();
+
+/// Sets up the HTTP client, speaking HTTP/2 without negotiation if `http2`
+///
+/// Over HTTP/2 the uploads and proof fetches to a server share a single
+/// connection. Must be called before the first request to take effect.
+pub(crate) fn init_http_client(http2: bool) {
+    let _ = HTTP_CLIENT.set(build_http_client(http2));
+}
+
+/// Returns a pooled HTTP client, speaking HTTP/2 only if `http2`
+fn build_http_client(http2: bool) -> Client<HttpConnector, Body> {
+    Client::builder(TokioExecutor::new())
+        .http2_only(http2)
+        .build_http()
+}
+
+/// Returns the HTTP client, over HTTP/1.1 unless set up otherwise
+fn http_client() -> &'static Client<HttpConnector, Body> {
+    HTTP_CLIENT.get_or_init(|| build_http_client(false))
+}
+
+/// Sends a request, carrying the trace context of the current span
+fn send(mut req: Request<Body>) -> ResponseFuture {
+    telemetry::inject(req.headers_mut());
+    http_client().request(req)
+}
+
+/// Sends a GET request, carrying the trace context of the current span
+fn get(uri: &str) -> Result<ResponseFuture, hyper::http::Error> {
+    Ok(send(Request::get(uri).body(body::empty())?))
+}
 
 #[derive(Debug, Error)]
 enum Error {
@@ -29,92 +125,708 @@ enum Error {
     InvalidProof,
     #[error("client is missing the Merkle root")]
     MissingMerkleRoot,
+    #[error(
+        "no attested root of version {0} recorded by the client, only the \
+         versions completed by this client with a signing server are recorded"
+    )]
+    UnrecordedRoot(u64),
     #[error("failed to download resource {0}: index: {1} status: {2}")]
     FailedDownload(String, String, StatusCode),
     #[error("failed to upload filename: {0}")]
     FailUpload(String),
+    #[error("failed to delete file index: {0} status: {1}")]
+    FailedDelete(String, StatusCode),
     #[error("failed to finalize the upload")]
     FailCloseUpload,
+    #[error("failed to open an upload session")]
+    FailBeginUpload,
+    #[error("failed to register the bucket owner, status: {0}")]
+    FailedRegisterOwner(StatusCode),
+    #[error("file {0} exceeds the server max body size {1}")]
+    FileTooLarge(String, u64),
+    #[error("inconsistent Merkle tree received")]
+    InvalidTree,
+    #[error("no server available")]
+    NoServerAvailable,
+    #[error("server root {0} differs from the local root {1}")]
+    RootMismatch(String, String),
+    #[error("bucket {0} already exists")]
+    BucketExists(String),
+    #[error("unknown bucket {0}")]
+    UnknownBucket(String),
+    #[error("invalid bucket name {0:?}")]
+    InvalidBucketName(String),
+    #[error("connection failed: {0}")]
+    Connection(String),
+    #[error("invalid state file {0}")]
+    InvalidState(String),
+    #[error("wrong passphrase, the client derived its key from another one")]
+    WrongPassphrase,
+    #[error("failed to derive the key: {0}")]
+    KeyDerivation(String),
+    #[error(
+        "the files of bucket {0} are sealed with another key, run the client \
+         with that key (--builtin-key for the buckets of the clients without \
+         keys) or select another bucket"
+    )]
+    WrongKey(String),
+    #[error(
+        "file index: {0} failed authentication, it was altered, swapped or \
+         sealed with another key"
+    )]
+    Tampered(String),
+    #[error(
+        "file index: {0} is in a legacy format without authentication, \
+         download it with --allow-legacy if it was uploaded by an older client"
+    )]
+    Legacy(String),
+    #[error(
+        "server error {code} ({status}): {message}, request id: {request_id}"
+    )]
+    Server {
+        status: StatusCode,
+        code: String,
+        message: String,
+        request_id: String,
+        /// Delay the server asked for before a retry
+        retry_after: Option<Duration>,
+    },
+}
+
+impl Error {
+    /// Returns true if the request may succeed if attempted again: the
+    /// server failed or could not be reached
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::Connection(_) => true,
+            Error::Server { status, .. }
+            | Error::FailedDownload(_, _, status) => {
+                retry::is_retried_status(*status)
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the delay the server asked for before a retry, if any
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Server { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// Returns true if a failed request may succeed if attempted again
+fn is_transient(err: &(dyn std::error::Error + 'static)) -> bool {
+    match err.downcast_ref::<Error>() {
+        Some(err) => err.is_transient(),
+        None => {
+            err.is::<hyper::Error>()
+                || err.is::<hyper_util::client::legacy::Error>()
+        }
+    }
 }
 
 pub struct ClientApp {
     folder: String,
     server_url: String,
+    /// Servers tried in order for reads when the primary is unavailable
+    standby_urls: Vec<String>,
 
+    /// Name of the selected bucket, the bucket of the requests
+    bucket_name: String,
     bucket_id: [u8; 32],
     merkle_tree: merkle::Tree,
+
+    /// Map a leaf hash to the record of the uploaded file
+    files: BTreeMap<Hash, FileRecord>,
+    /// Check of the key sealing the files of the bucket, see
+    /// [`BucketState::key_check`]
+    key_check: Option<Hash>,
+
+    /// The other buckets of the client, by name
+    buckets: BTreeMap<String, BucketState>,
+
+    /// Features supported by the server
+    capabilities: Capabilities,
+
+    /// Key owning the bucket, signing the changes of the bucket
+    signer: Arc<Signer>,
+
+    /// Tags of the uploaded files
+    upload_tags: Vec<String>,
+    /// Number of parts of a large file sent at once
+    parallel_parts: usize,
+    /// Retries of the requests failing transiently
+    retry: RetryPolicy,
+    /// Parameters deriving the key of the files from a passphrase
+    kdf: Option<KdfParams>,
+    /// Accept the downloaded files in a format without authentication, not
+    /// only those the records tell were uploaded in such a format
+    allow_legacy: bool,
+}
+
+/// Outcome of an upload batch
+#[derive(Debug, Default)]
+pub(crate) struct UploadSummary {
+    pub uploaded: usize,
+    pub failed: usize,
+    /// Merkle root of the bucket after the upload
+    pub root: Option<Hash>,
+    /// Outcome of each file of the batch
+    pub files: Vec<FileUpload>,
+    /// Number of bytes sent by the batch
+    pub bytes: u64,
+    /// Time taken by the transfers of the batch
+    pub elapsed: Duration,
+}
+
+/// Outcome of the upload of a file
+#[derive(Debug)]
+pub(crate) struct FileUpload {
+    pub file_name: String,
+    /// Hash and size of the encrypted file, or why it was not uploaded
+    pub result: Result<(Hash, u64), String>,
+    /// Leaf index of the file in the bucket after the batch
+    pub index: Option<usize>,
+}
+
+/// A bucket of the client, as listed by `buckets`
+#[derive(Debug)]
+pub(crate) struct BucketInfo {
+    pub name: String,
+    pub bucket_id: String,
+    pub leaves_count: usize,
+    pub root: Option<Hash>,
+    pub selected: bool,
+}
+
+/// A file downloaded and verified against the local Merkle root
+#[derive(Debug)]
+pub(crate) struct FileDownload {
+    pub hash: Hash,
+    /// Path of the decrypted file in the downloads folder
+    pub path: String,
+}
+
+/// A file of the bucket as listed by the server
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct RemoteFile {
+    pub index: usize,
+    pub filename: String,
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Usage of the bucket reported by the server, compared to its quotas
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct Usage {
+    pub files_count: usize,
+    pub bytes_used: u64,
+    pub quota_bytes: Option<u64>,
+    #[serde(default)]
+    pub quota_files: Option<usize>,
+}
+
+/// Client-side record of an uploaded file
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct FileRecord {
+    pub file_name: String,
+    /// Size of the source file
+    pub size: u64,
+    /// Hash of the content of the source file, `None` for the files
+    /// uploaded before the nonces were drawn per file
+    pub source_hash: Option<Hash>,
+
+    /// Unix timestamp of the last proof verification
+    pub last_verified: Option<u64>,
+    /// Outcome of the last proof verification
+    pub verified: Option<bool>,
 }
 
 impl ClientApp {
-    pub fn new(server_url: &str, client_folder: &str) -> Self {
+    pub fn new(
+        server_url: &str,
+        standby_urls: &[String],
+        client_folder: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let _ = fs::create_dir(client_folder);
         // Load state from disk
-        let (bucket_id, merkle_tree) = Self::read_from_file(client_folder);
+        let mut state = Self::read_from_file(client_folder)?;
+        let bucket = state
+            .buckets
+            .remove(&state.selected)
+            .unwrap_or_else(BucketState::new);
 
-        ClientApp {
-            bucket_id,
+        Ok(ClientApp {
+            bucket_name: state.selected,
+            bucket_id: bucket.bucket_id,
             server_url: server_url.to_owned(),
-            merkle_tree,
+            standby_urls: standby_urls.to_vec(),
+            merkle_tree: bucket.merkle_tree,
+            files: bucket.files,
+            key_check: bucket.key_check,
+            buckets: state.buckets,
             folder: client_folder.to_owned(),
+            capabilities: Capabilities::default(),
+            signer: Arc::new(Signer::load_or_create(client_folder)?),
+            upload_tags: Vec::new(),
+            parallel_parts: 1,
+            retry: RetryPolicy::default(),
+            kdf: state.kdf,
+            allow_legacy: false,
+        })
+    }
+
+    /// Selects the bucket `name`, created if the client has none of this
+    /// name
+    pub fn with_bucket(mut self, name: &str) -> Self {
+        let res = if name == self.bucket_name || self.buckets.contains_key(name)
+        {
+            self.select_bucket(name)
+        } else {
+            self.create_bucket(name)
+        };
+        if let Err(err) = res {
+            error!(event = "failed to select bucket", name, ?err);
+        }
+        self
+    }
+
+    /// Tags the files uploaded from now on with `tags`
+    pub fn with_upload_tags(mut self, tags: Vec<String>) -> Self {
+        self.upload_tags = tags;
+        self
+    }
+
+    /// Sends `parts` parts of a large file at once if the server supports
+    /// multi-part uploads
+    pub fn with_parallel_parts(mut self, parts: usize) -> Self {
+        self.parallel_parts = parts;
+        self
+    }
+
+    /// Retries the requests failing transiently according to `retry`
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Accepts any downloaded file in a legacy format without
+    /// authentication, see [`Decryptor`]
+    pub fn with_allow_legacy(mut self, allow_legacy: bool) -> Self {
+        self.allow_legacy = allow_legacy;
+        self
+    }
+
+    /// Fetches the server capabilities
+    ///
+    /// A server without the capabilities endpoint is assumed to support only
+    /// the base protocol.
+    pub async fn fetch_capabilities(&mut self) {
+        let uri = format!("{}/capabilities", self.server_url);
+
+        match Self::get_json::<Capabilities>(&uri).await {
+            Ok(capabilities) => {
+                info!(event = "server capabilities", ?capabilities);
+                self.capabilities = capabilities;
+            }
+            Err(err) => {
+                error!(event = "failed to fetch capabilities", ?err);
+                self.capabilities = Capabilities::default();
+            }
+        }
+    }
+
+    /// Sends a GET request and decodes a JSON response
+    async fn get_json<T: serde::de::DeserializeOwned>(
+        uri: &str,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let res = get(uri)?.await?;
+
+        if res.status() != StatusCode::OK {
+            let status = res.status();
+            let fallback =
+                Error::FailedDownload(uri.to_owned(), String::new(), status);
+            return Err(server_error(res, fallback).await.into());
         }
+
+        let bytes = body::to_bytes(res.into_body()).await?;
+        Ok(serde_json::from_slice(&bytes)?)
     }
 
-    /// Loads both bucket_id and the Merkle tree from disk, if STATE_FILE exists
+    /// Loads the buckets of the client from disk, if STATE_FILE exists
+    ///
+    /// The state files of the previous versions are loaded if it does not,
+    /// the state file of a single bucket client as its `DEFAULT_BUCKET`. If
+    /// no state file is found then a new bucket is generated.
     ///
-    /// If state file is not found then a new bucket id is generated
-    pub fn read_from_file(client_folder: &str) -> ([u8; 32], merkle::Tree) {
+    /// Returns an error if a state file cannot be decoded.
+    fn read_from_file(client_folder: &str) -> Result<State, Error> {
         let state_file = client_folder.to_owned() + STATE_FILE;
-        fs::read(&state_file).map_or_else(
-            |_| {
+        let v3_file = client_folder.to_owned() + BUCKETS_V3_FILE;
+        let v2_file = client_folder.to_owned() + BUCKETS_V2_FILE;
+        let v1_file = client_folder.to_owned() + BUCKETS_V1_FILE;
+        let state = fs::read(&state_file)
+            .map(|bytes| bincode::deserialize(&bytes))
+            .or_else(|_| {
+                fs::read(&v3_file).map(|bytes| {
+                    bincode::deserialize::<StateV3>(&bytes).map(State::from)
+                })
+            })
+            .or_else(|_| {
+                fs::read(&v2_file).map(|bytes| {
+                    bincode::deserialize::<StateV2>(&bytes).map(State::from)
+                })
+            })
+            .or_else(|_| {
+                fs::read(&v1_file).map(|bytes| {
+                    bincode::deserialize::<StateV1>(&bytes).map(State::from)
+                })
+            });
+        if let Ok(s) = state {
+            let s: State =
+                s.map_err(|_| Error::InvalidState(state_file.clone()))?;
+            info!(
+                event = "loaded state from disk",
+                buckets = s.buckets.len(),
+                selected = s.selected
+            );
+            return Ok(s);
+        }
+
+        let legacy_file = client_folder.to_owned() + LEGACY_STATE_FILE;
+        let bucket = match fs::read(&legacy_file) {
+            Err(_) => {
                 info!(event = "no state found", file = state_file);
-                let mut bucket_id = [0u8; 32];
-                rand::thread_rng().fill_bytes(&mut bucket_id[..]);
+                let bucket = BucketState::new();
                 info!(
                     event = "new bucket id",
-                    bucket_id = hex::encode(bucket_id)
+                    bucket_id = hex::encode(bucket.bucket_id)
                 );
-
-                (bucket_id, merkle::Tree::default())
-            },
-            |bytes| {
-                let s: State =
-                    bincode::deserialize(&bytes).expect("valid state file");
+                bucket
+            }
+            Ok(bytes) => {
+                // The file of the first clients holds no file records
+                let s: BucketState =
+                    bincode::deserialize::<BucketStateV1>(&bytes)
+                        .map(BucketState::from)
+                        .or_else(|_| {
+                            bincode::deserialize::<StateV0>(&bytes)
+                                .map(BucketState::from)
+                        })
+                        .map_err(|_| {
+                            Error::InvalidState(legacy_file.clone())
+                        })?;
 
                 info!(
                     event = "loaded state from disk",
+                    file = legacy_file,
                     leaves = s.merkle_tree.leaves().len(),
                     bucket_id = hex::encode(s.bucket_id)
                 );
 
-                (s.bucket_id, s.merkle_tree)
-            },
-        )
+                s
+            }
+        };
+
+        Ok(State {
+            selected: DEFAULT_BUCKET.to_owned(),
+            buckets: BTreeMap::from([(DEFAULT_BUCKET.to_owned(), bucket)]),
+            kdf: None,
+        })
     }
 
     /// Persist the current state to disk
     pub fn persist_state(&self) -> Result<(), Box<dyn std::error::Error>> {
         let state_file_path = self.folder.clone() + STATE_FILE;
+        let mut buckets = self.buckets.clone();
+        buckets.insert(
+            self.bucket_name.clone(),
+            BucketState {
+                merkle_tree: self.merkle_tree.clone(),
+                bucket_id: self.bucket_id,
+                files: self.files.clone(),
+                key_check: self.key_check,
+            },
+        );
         fs::write(
             &state_file_path,
             bincode::serialize(&State {
-                merkle_tree: self.merkle_tree.clone(),
-                bucket_id: self.bucket_id,
+                selected: self.bucket_name.clone(),
+                buckets,
+                kdf: self.kdf.clone(),
             })?,
         )?;
         info!(event = "state saved on disk", state_file_path);
         Ok(())
     }
 
+    /// Derives the key of the files from `passphrase` with Argon2id
+    ///
+    /// The parameters of the derivation are drawn on first use and kept in
+    /// the state file, so that the client derives the same key from the same
+    /// passphrase. Fails on a passphrase other than the first one.
+    pub fn passphrase_key(
+        &mut self,
+        passphrase: &str,
+    ) -> Result<cipher::Key, Box<dyn std::error::Error>> {
+        let derivation_failed =
+            |err: argon2::Error| Error::KeyDerivation(err.to_string());
+
+        if let Some(kdf) = &self.kdf {
+            return kdf
+                .derive(passphrase)
+                .map_err(derivation_failed)?
+                .ok_or_else(|| Error::WrongPassphrase.into());
+        }
+
+        let (kdf, key) =
+            KdfParams::new(passphrase).map_err(derivation_failed)?;
+        self.kdf = Some(kdf);
+        self.persist_state()?;
+        info!(event = "new key derivation parameters");
+        Ok(key)
+    }
+
+    /// Creates a bucket with a new random id and selects it
+    pub(crate) fn create_bucket(
+        &mut self,
+        name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if name.trim().is_empty() {
+            return Err(Error::InvalidBucketName(name.to_owned()).into());
+        }
+        if name == self.bucket_name || self.buckets.contains_key(name) {
+            return Err(Error::BucketExists(name.to_owned()).into());
+        }
+
+        let bucket = BucketState::new();
+        info!(
+            event = "new bucket id",
+            name,
+            bucket_id = hex::encode(bucket.bucket_id)
+        );
+        self.replace_bucket(name, bucket);
+        self.persist_state()
+    }
+
+    /// Selects the bucket of the following requests
+    pub(crate) fn select_bucket(
+        &mut self,
+        name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if name != self.bucket_name {
+            let bucket = self
+                .buckets
+                .remove(name)
+                .ok_or_else(|| Error::UnknownBucket(name.to_owned()))?;
+            self.replace_bucket(name, bucket);
+        }
+        info!(
+            event = "bucket selected",
+            name,
+            bucket_id = self.bucket_id()
+        );
+        self.persist_state()
+    }
+
+    /// Makes `bucket` the selected bucket, keeping the previous one
+    fn replace_bucket(&mut self, name: &str, bucket: BucketState) {
+        let previous = BucketState {
+            bucket_id: std::mem::replace(&mut self.bucket_id, bucket.bucket_id),
+            merkle_tree: std::mem::replace(
+                &mut self.merkle_tree,
+                bucket.merkle_tree,
+            ),
+            files: std::mem::replace(&mut self.files, bucket.files),
+            key_check: std::mem::replace(&mut self.key_check, bucket.key_check),
+        };
+        let previous_name =
+            std::mem::replace(&mut self.bucket_name, name.to_owned());
+        self.buckets.insert(previous_name, previous);
+    }
+
+    /// Lists the buckets of the client by name
+    pub(crate) fn buckets(&self) -> Vec<BucketInfo> {
+        let selected = (&self.bucket_name, &self.bucket_id, &self.merkle_tree);
+        let others = self.buckets.iter().map(|(name, bucket)| {
+            (name, &bucket.bucket_id, &bucket.merkle_tree)
+        });
+
+        let mut buckets: Vec<_> = std::iter::once(selected)
+            .chain(others)
+            .map(|(name, bucket_id, tree)| BucketInfo {
+                name: name.clone(),
+                bucket_id: hex::encode(bucket_id),
+                leaves_count: tree.leaves_count(),
+                root: tree.root_hash(),
+                selected: *name == self.bucket_name,
+            })
+            .collect();
+        buckets.sort_by(|a, b| a.name.cmp(&b.name));
+        buckets
+    }
+
+    /// Returns the files not yet uploaded to the bucket
+    pub(crate) fn new_files(
+        &self,
+        files: &[(OsString, String)],
+    ) -> Vec<(OsString, String)> {
+        let leaves = BTreeSet::from_iter(self.merkle_tree.leaves());
+        self.files_not_in(files, &leaves)
+    }
+
+    /// Returns the files whose content is not the source of a file of the
+    /// bucket
+    ///
+    /// Each upload draws a new nonce, so a file is known by the hash of its
+    /// content. The files uploaded before are known by their encrypted
+    /// content, one of `leaves`.
+    fn files_not_in(
+        &self,
+        files: &[(OsString, String)],
+        leaves: &BTreeSet<Hash>,
+    ) -> Vec<(OsString, String)> {
+        let sources = BTreeSet::from_iter(
+            self.files.values().filter_map(|r| r.source_hash),
+        );
+
+        files
+            .iter()
+            .filter(|(_, file_path)| match cipher::source_hashes(file_path) {
+                Ok((source, legacy)) => {
+                    !sources.contains(&source) && !leaves.contains(&legacy)
+                }
+                Err(err) => {
+                    error!(event = "failed to hash file", file_path, ?err);
+                    false
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Returns true if the source file was uploaded by the interrupted
+    /// batch, and left unchanged since
+    fn journaled(resumed: &[journal::JournaledFile], file_path: &str) -> bool {
+        resumed.iter().any(|file| {
+            file.file_path == file_path
+                && fs::metadata(file_path).is_ok_and(|m| m.len() == file.size)
+        })
+    }
+
+    /// Returns the batch of the bucket interrupted by a crash, if it can be
+    /// resumed
+    ///
+    /// The server rolls back the files of a session left open past its
+    /// time-to-live, so the batch is resumed only if all of its journaled
+    /// files are still in the bucket.
+    async fn resume_batch(&self) -> Option<journal::Interrupted> {
+        let batch = journal::read(&self.folder, &self.bucket_id())?;
+        let remote = match self.list_remote_files().await {
+            Ok(remote) => remote,
+            Err(err) => {
+                warn!(event = "failed to list remote files", ?err);
+                return None;
+            }
+        };
+        let remote = BTreeSet::from_iter(remote.iter().filter_map(|file| {
+            Hash::try_from(hex::decode(&file.hash).ok()?).ok()
+        }));
+
+        if batch.files.iter().all(|file| remote.contains(&file.hash)) {
+            info!(event = "resume upload batch", files = batch.files.len());
+            Some(batch)
+        } else {
+            warn!(event = "interrupted upload batch rolled back");
+            None
+        }
+    }
+
+    /// Returns the size of a source file
+    fn source_size(file_path: &str) -> u64 {
+        fs::metadata(file_path).map_or(0, |m| m.len())
+    }
+
     /// Upload a batch of files to the storage server
+    ///
+    /// Successfully uploaded files are removed from the local repo if
+    /// `remove_uploaded` is set. The Merkle root returned by the server is
+    /// checked against the recalculated local root.
+    #[tracing::instrument(
+        name = "upload",
+        skip_all,
+        fields(bucket_id = self.bucket_id(), files = files.len())
+    )]
     pub async fn upload_files(
         &mut self,
         files: &Vec<(OsString, String)>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let sorted_leaves = BTreeSet::from_iter(self.merkle_tree.leaves());
+        remove_uploaded: bool,
+    ) -> Result<UploadSummary, Box<dyn std::error::Error>> {
+        // The files of a bucket are all sealed with the same key
+        if self
+            .key_check
+            .is_some_and(|check| check != cipher::client_key_check())
+        {
+            return Err(Error::WrongKey(self.bucket_name.clone()).into());
+        }
+
+        // The bucket is claimed before its first file is uploaded
+        if let Err(err) = self.register_owner().await {
+            warn!(event = "failed to register bucket owner", ?err);
+        }
+        self.check_quota(files).await;
+
+        // The files of a batch interrupted by a crash are not sent again, its
+        // session is still open on the server
+        let resumed = self.resume_batch().await;
+        let (session, journal) = match resumed {
+            Some(ref batch) => (
+                batch.session.clone(),
+                journal::UploadJournal::resume(&self.folder, &self.bucket_id()),
+            ),
+            None => {
+                // Another device uploading to the bucket holds its session
+                // until it completes, the batch is retried later
+                let session = self.begin_upload().await?;
+                let journal = journal::UploadJournal::start(
+                    &self.folder,
+                    &self.bucket_id(),
+                    session.as_deref(),
+                );
+                (session, journal)
+            }
+        };
+        let journal = match journal {
+            Ok(journal) => Some(Arc::new(journal)),
+            Err(err) => {
+                warn!(event = "failed to open upload journal", ?err);
+                None
+            }
+        };
+        let headers = UploadHeaders {
+            session,
+            tags: (!self.upload_tags.is_empty())
+                .then(|| self.upload_tags.join(",")),
+        };
+
+        let resumed = resumed.map(|batch| batch.files).unwrap_or_default();
+        let mut sorted_leaves = BTreeSet::from_iter(self.merkle_tree.leaves());
+        sorted_leaves.extend(resumed.iter().map(|file| file.hash));
         let leaves = Arc::new(Mutex::new(sorted_leaves));
 
         // Async upload of all files to the server
         let mut async_clients = JoinSet::new();
+        let sizes: Vec<u64> = files
+            .iter()
+            .filter(|(_, file_path)| !Self::journaled(&resumed, file_path))
+            .map(|(_, file_path)| {
+                cipher::encrypted_size(Self::source_size(file_path))
+            })
+            .collect();
+        let batch =
+            progress::Batch::new("upload", sizes.len(), sizes.iter().sum());
 
         // Shuffle the files to test different order of uploads
         // let mut files = files.clone();
@@ -122,27 +834,157 @@ impl ClientApp {
 
         for (file, file_path) in files {
             let file_name = file.to_string_lossy().to_string();
+            if Self::journaled(&resumed, file_path) {
+                info!(event = "skip journaled file", file_name);
+                continue;
+            }
             let leaves = Arc::clone(&leaves);
+            let journal = journal.clone();
             let url = self.server_url.clone();
             let bucket_id = self.bucket_id();
             let file_path = file_path.clone();
+            let max_body_size = self.capabilities.max_body_size;
+            let chunk_size = self.resumable_chunk_size();
+            let parallel_parts = self.multipart_parts();
+            let max_parts = self.capabilities.max_parts.unwrap_or(u64::MAX);
+            let signer = self.signer();
+            let headers = headers.clone();
+            let retry = self.retry;
+            let source_size = Self::source_size(&file_path);
+            let size = cipher::encrypted_size(source_size);
+            let file_progress = batch.file(&file_name, size);
+            let upload_progress = file_progress.clone();
 
-            // Spawn a new task per a file upload
-            async_clients.spawn(async move {
-                match Self::encrypt_and_upload(
-                    &url,
-                    &bucket_id,
-                    file_name.clone(),
-                    &file_path,
-                )
+            // Spawn a new task per a file upload, traced under the batch
+            let span = info_span!("upload file", file_name);
+            let upload = async move {
+                // Large files are sent in chunks so that a dropped
+                // connection does not restart the whole upload, several at
+                // once if the server reassembles them
+                // The records keep the hash of the source, as the encrypted
+                // content differs at each upload
+                let path = file_path.clone();
+                let source_hash = match tokio::task::spawn_blocking(move || {
+                    cipher::source_hashes(&path)
+                })
                 .await
                 {
-                    Ok(hash) => {
+                    Ok(Ok((source_hash, _))) => source_hash,
+                    _ => {
+                        let err = Error::FailUpload(file_name.clone());
+                        error!(event = "failed to hash file", file_name, ?err);
+                        return (file_name, Err(err.to_string()));
+                    }
+                };
+
+                let mut attempt = 1;
+                let res = loop {
+                    let res = match (chunk_size, parallel_parts) {
+                        (Some(chunk_size), Some(parallel_parts))
+                            if size > RESUMABLE_THRESHOLD
+                                && size.div_ceil(chunk_size) <= max_parts =>
+                        {
+                            Self::encrypt_and_upload_multipart(
+                                &url,
+                                &bucket_id,
+                                file_name.clone(),
+                                &file_path,
+                                (chunk_size, parallel_parts),
+                                signer.clone(),
+                                &headers,
+                            )
+                            .await
+                        }
+                        (Some(chunk_size), _) if size > RESUMABLE_THRESHOLD => {
+                            Self::encrypt_and_upload_resumable(
+                                &url,
+                                &bucket_id,
+                                file_name.clone(),
+                                &file_path,
+                                chunk_size,
+                                signer.clone(),
+                                &headers,
+                            )
+                            .await
+                        }
+                        _ => {
+                            // Skip files the server would reject anyway
+                            if let Some(max) = max_body_size {
+                                if size > max {
+                                    let err = Error::FileTooLarge(
+                                        file_name.clone(),
+                                        max,
+                                    );
+                                    error!(
+                                        event = "skip file upload",
+                                        file_name,
+                                        ?err
+                                    );
+                                    return (file_name, Err(err.to_string()));
+                                }
+                            }
+
+                            Self::encrypt_and_upload(
+                                &url,
+                                &bucket_id,
+                                file_name.clone(),
+                                &file_path,
+                                signer.clone(),
+                                &headers,
+                            )
+                            .await
+                        }
+                    };
+
+                    // A file the server failed to store, or whose request was
+                    // cut, is sent again
+                    match res {
+                        Err(err)
+                            if retry.retries(attempt) && err.is_transient() =>
+                        {
+                            let delay = retry.delay(attempt, err.retry_after());
+                            warn!(
+                                event = "retry upload",
+                                file_name,
+                                attempt,
+                                ?delay,
+                                ?err
+                            );
+                            attempt += 1;
+                            upload_progress.restart();
+                            tokio::time::sleep(delay).await;
+                        }
+                        res => break res,
+                    }
+                };
+                upload_progress.finish();
+
+                match res {
+                    Ok((hash, _)) => {
                         info!(event = "file uploaded", file_name);
                         leaves.lock().await.insert(hash);
+                        if let Some(journal) = &journal {
+                            journal.record(journal::JournaledFile {
+                                file_name: file_name.clone(),
+                                file_path: file_path.clone(),
+                                hash,
+                                source_hash,
+                                size: source_size,
+                            });
+                        }
 
                         // Remove the file from the local repo
-                        fs::remove_file(file_path).expect("file removed");
+                        if remove_uploaded {
+                            fs::remove_file(file_path).expect("file removed");
+                        }
+
+                        let record = FileRecord {
+                            file_name: file_name.clone(),
+                            size: source_size,
+                            source_hash: Some(source_hash),
+                            ..Default::default()
+                        };
+                        (file_name, Ok((hash, record)))
                     }
                     Err(err) => {
                         error!(
@@ -150,16 +992,66 @@ impl ClientApp {
                             file_name,
                             ?err
                         );
+                        (file_name, Err(err.to_string()))
                     }
                 }
-            });
+            };
+            async_clients.spawn(file_progress.scope(upload).instrument(span));
         }
 
-        // Wait for all the uploaders to finish
-        async_clients.join_all().await;
+        // Wait for all the uploaders to finish, the journaled files count as
+        // uploaded by this batch
+        let mut uploaded = Vec::new();
+        let mut file_uploads = Vec::new();
+        let journaled = resumed.into_iter().map(|file| {
+            let record = FileRecord {
+                file_name: file.file_name.clone(),
+                size: file.size,
+                source_hash: Some(file.source_hash),
+                ..Default::default()
+            };
+            (file.file_name, Ok((file.hash, record)))
+        });
+        for (file_name, res) in journaled.chain(async_clients.join_all().await)
+        {
+            let result = match res {
+                Ok((hash, record)) => {
+                    let size = record.size;
+                    uploaded.push((hash, record));
+                    Ok((hash, size))
+                }
+                Err(err) => Err(err),
+            };
+            file_uploads.push(FileUpload {
+                file_name,
+                result,
+                index: None,
+            });
+        }
+        let (bytes, elapsed) = batch.finish();
+        let summary_uploaded = uploaded.len();
+        let summary_failed = file_uploads.len() - summary_uploaded;
+        self.files.extend(uploaded);
+        if summary_uploaded > 0 {
+            self.key_check.get_or_insert_with(cipher::client_key_check);
+        }
 
-        // Instruct the server to close the upload session
-        self.close_upload().await?;
+        // Instruct the server to close the upload session, the journal is
+        // kept to resume the batch unless the server dropped the session
+        let server_root =
+            match self.close_upload(headers.session.as_deref()).await {
+                Ok(server_root) => server_root,
+                Err(err) => {
+                    let expired = matches!(
+                        &err,
+                        Error::Server { code, .. } if code == "UPLOAD_NOT_FOUND"
+                    );
+                    if let (true, Some(journal)) = (expired, &journal) {
+                        journal.remove();
+                    }
+                    return Err(err.into());
+                }
+            };
 
         // Recalculate the Merkle trees
         let new_leaves = Vec::from_iter(leaves.lock().await.iter().copied());
@@ -168,10 +1060,20 @@ impl ClientApp {
             info!(event = "new leaf", leaf = hex::encode(l));
         });
 
+        for file in &mut file_uploads {
+            if let Ok((hash, _)) = &file.result {
+                file.index = new_leaves.binary_search(hash).ok();
+            }
+        }
+
         self.merkle_tree = merkle::Tree::build_from_leaves(new_leaves.clone());
         self.persist_state()?;
+        if let Some(journal) = &journal {
+            journal.remove();
+        }
 
-        if let Some(root_hex) = self.merkle_tree.root_hash() {
+        let root = self.merkle_tree.root_hash();
+        if let Some(root_hex) = root {
             info!(
                 event = "completed upload",
                 bucket_id = self.bucket_id(),
@@ -180,194 +1082,2174 @@ impl ClientApp {
             );
         }
 
-        Ok(())
+        // Make sure the server sealed the same set of files
+        if let Some(server_root) = server_root {
+            if Some(server_root) != root {
+                let err = Error::RootMismatch(
+                    hex::encode(server_root),
+                    root.map(hex::encode).unwrap_or_default(),
+                );
+                error!(event = "root mismatch", ?err);
+                return Err(err.into());
+            }
+        }
+
+        Ok(UploadSummary {
+            uploaded: summary_uploaded,
+            failed: summary_failed,
+            root,
+            files: file_uploads,
+            bytes,
+            elapsed,
+        })
+    }
+
+    /// Uploads the files whose content is not yet in the bucket, according
+    /// to the local records, the local tree and the files of the bucket on
+    /// the server, leaving the source files in place
+    ///
+    /// A changed file has a new hash, so it is uploaded again. If the server
+    /// cannot list the bucket, the local state alone decides.
+    pub async fn sync_files(
+        &mut self,
+        files: &[(OsString, String)],
+    ) -> Result<UploadSummary, Box<dyn std::error::Error>> {
+        let mut known = BTreeSet::from_iter(self.merkle_tree.leaves());
+        match self.list_remote_files().await {
+            Ok(remote) => known.extend(remote.iter().filter_map(|file| {
+                Hash::try_from(hex::decode(&file.hash).ok()?).ok()
+            })),
+            Err(err) => warn!(event = "failed to list remote files", ?err),
+        }
+
+        let delta = self.files_not_in(files, &known);
+        info!(event = "sync", files = files.len(), new_files = delta.len());
+        if delta.is_empty() {
+            return Ok(UploadSummary {
+                root: self.merkle_tree.root_hash(),
+                ..Default::default()
+            });
+        }
+
+        self.upload_files(&delta, false).await
     }
 
     /// Download and verify a file from the storage server
     ///
     /// If a valid proof is received, the file is decrypted and saved to the
-    /// downloads folder
+    /// downloads folder. Returns its hash and its path.
+    ///
+    /// Servers are tried in order, primary first: a standby is used when the
+    /// previous server keeps failing or serves a proof that does not match the
+    /// local Merkle root, in which case the divergence is reported.
+    #[tracing::instrument(
+        name = "download",
+        skip_all,
+        fields(bucket_id = self.bucket_id(), file_index)
+    )]
     pub async fn download_and_verify(
-        &self,
+        &mut self,
         file_index: &String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        // Download the file
-        let file_data = self.download_blob(file_index, "file").await?;
-        let hash: Hash = Sha256::digest(&file_data).into();
-        info!(
-            event = "file data received",
-            file_index,
-            hash = hex::encode(hash),
-        );
-
-        // Download the proof
-        info!(event = "request proof", file_index);
-        let bytes = self.download_blob(file_index, "proof").await?;
-
-        let proof: Vec<([u8; 32], u8)> = bincode::deserialize(&bytes)?;
-
-        // Verify the file with the proof
-        self.verify(proof, &hash).await?;
-        self.decrypt_and_save_file(&hash, &file_data)?;
+    ) -> Result<FileDownload, Box<dyn std::error::Error>> {
+        let mut result: Result<FileDownload, Box<dyn std::error::Error>> =
+            Err(Error::NoServerAvailable.into());
+        let mut verified = None;
+        let part_path = self.folder.clone() + DOWNLOAD_PART_FILE;
+        let aad =
+            cipher::aad(&self.bucket_id(), &self.file_name(file_index).await);
+        let legacy = self.allow_legacy || self.is_legacy(file_index);
+        let builtin_key = self.builtin_key()?;
 
-        Ok(())
-    }
+        for (i, url) in self.server_urls().into_iter().enumerate() {
+            if i > 0 {
+                warn!(event = "failover to standby server", url, file_index);
+            }
 
-    /// Verify the provided merkle path for a file
-    async fn verify(
-        &self,
-        proof: Vec<(Hash, u8)>,
-        hash: &Hash,
-    ) -> Result<(), Error> {
-        if let Some(merkle_root) = self.merkle_tree.root_hash() {
-            info!(
-                event = "checking proof",
-                hash = hex::encode(hash),
-                proof_len = proof.len(),
-                merkle_root = hex::encode(merkle_root)
-            );
+            let res = self
+                .download_file_and_proof(
+                    &url,
+                    file_index,
+                    &aad,
+                    legacy,
+                    builtin_key,
+                    &part_path,
+                )
+                .await;
+            let (hash, proof) = match res {
+                Ok(res) => res,
+                Err(err) => {
+                    match err.downcast_ref() {
+                        Some(Error::Tampered(_)) => {
+                            warn!(
+                                event = "file failed authentication",
+                                url, file_index
+                            );
+                            verified = Some(false);
+                        }
+                        Some(Error::Legacy(_)) => {
+                            warn!(
+                                event = "file in a legacy format",
+                                url, file_index
+                            )
+                        }
+                        _ => error!(event = "server unavailable", url, ?err),
+                    }
+                    result = Err(err);
+                    continue;
+                }
+            };
 
-            if !merkle::Tree::verify_proof(hash, &proof, &merkle_root) {
-                return Err(Error::InvalidProof);
+            // Verify the file with the proof
+            match self.verify(proof, &hash).await {
+                Ok(()) => {
+                    verified = Some(true);
+                    result = self
+                        .save_file(&hash, &part_path)
+                        .map(|path| FileDownload { hash, path });
+                    break;
+                }
+                Err(Error::InvalidProof) => {
+                    warn!(
+                        event = "server diverges from the local Merkle root",
+                        url, file_index
+                    );
+                    verified = Some(false);
+                    result = Err(Error::InvalidProof.into());
+                }
+                Err(err) => return Err(err.into()),
             }
+        }
 
-            return Ok(());
+        // The content of a file failing its proof is not kept
+        let _ = fs::remove_file(&part_path);
+        if let Some(verified) = verified {
+            self.record_verification(file_index, verified);
+            self.persist_state()?;
         }
 
-        Err(Error::MissingMerkleRoot)
+        result
     }
 
-    /// Decrypt and save the file to the downloads folder
-    /// File is named after the hash of the content
-    fn decrypt_and_save_file(
+    /// Compares the local Merkle root with the current root of the bucket
+    /// on the server
+    ///
+    /// Returns the server root and whether it matches the local one
+    pub async fn check_root(
         &self,
-        file_id: &[u8],
-        data: &[u8],
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut cipher = ChaCha20::new(&CHACHA_KEY.into(), &[0x24; 12].into());
-        let mut data = data.to_owned();
-        cipher.apply_keystream(&mut data);
+    ) -> Result<(Option<Hash>, bool), Box<dyn std::error::Error>> {
+        let uri = format!("{}/root/{}", self.server_url, self.bucket_id());
+        let server_root = Self::get_json::<BucketRoot>(&uri).await?.hash();
+        let root = self.merkle_tree.root_hash();
 
-        let local_repo = self.folder.to_owned() + LOCAL_REPO;
+        info!(
+            event = "root check",
+            server_root = server_root.map(hex::encode),
+            local_root = root.map(hex::encode),
+            matches = server_root == root
+        );
 
-        let _ = fs::create_dir_all(&local_repo);
-        let path = format!("{}/{}", local_repo, hex::encode(file_id));
+        Ok((server_root, server_root == root))
+    }
 
-        fs::write(Path::new(&path), data)?;
-        info!(event = "valid file saved", file = path);
+    /// Verifies that the server kept every file of version `from` of the
+    /// bucket in version `to`, the current version by default
+    ///
+    /// The leaves of `from` must rebuild the root the client recorded for
+    /// that version from an attestation of the server, so the server cannot
+    /// answer with the leaves of a root of its choosing, and each one must be
+    /// proven under the root of `to`: the recorded one if any, else the root
+    /// the server reports. Returns whether the retention proof is valid.
+    pub async fn verify_retention(
+        &self,
+        from: u64,
+        to: Option<u64>,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let root_uri = |version: Option<u64>| {
+            let uri = format!("{}/root/{}", self.server_url, self.bucket_id());
+            match version {
+                Some(version) => format!("{uri}?version={version}"),
+                None => uri,
+            }
+        };
+        let recorded = |version| {
+            attestation::recorded_root(&self.folder, &self.bucket_id(), version)
+        };
+        let from_root = recorded(from)?.ok_or(Error::UnrecordedRoot(from))?;
+        let to_root = match to.map(recorded).transpose()?.flatten() {
+            Some(root) => root,
+            None => Self::get_json::<BucketRoot>(&root_uri(to))
+                .await?
+                .hash()
+                .ok_or(Error::MissingMerkleRoot)?,
+        };
+
+        let mut uri = format!(
+            "{}/retention/{}?from={from}",
+            self.server_url,
+            self.bucket_id()
+        );
+        if let Some(to) = to {
+            uri.push_str(&format!("&to={to}"));
+        }
+        let bytes =
+            Self::get_blob(&uri, &from.to_string(), "retention").await?;
+        let (leaves, proofs): (Vec<Hash>, Vec<Vec<(Hash, u8)>>) =
+            bincode::deserialize(&bytes)?;
+
+        let retained = merkle::Tree::verify_retention(
+            &leaves, &from_root, &proofs, &to_root,
+        );
+        info!(
+            event = "retention verified",
+            bucket_id = self.bucket_id(),
+            from,
+            to,
+            from_root = hex::encode(from_root),
+            to_root = hex::encode(to_root),
+            retained
+        );
+
+        Ok(retained)
+    }
+
+    /// Restores the local Merkle tree from the tree of the bucket on the
+    /// server
+    ///
+    /// The received levels must match the tree rebuilt from the received
+    /// leaves. A non-empty local tree is replaced only if the roots match.
+    pub async fn restore_tree(
+        &mut self,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let uri = format!("{}/tree/{}", self.server_url, self.bucket_id());
+        let bytes = Self::get_blob(&uri, "", "tree").await?;
+        let levels: Vec<Vec<Hash>> = bincode::deserialize(&bytes)?;
+
+        let leaves = levels.first().cloned().unwrap_or_default();
+        let tree = merkle::Tree::build_from_leaves(leaves);
+        if tree.levels() != levels.as_slice() {
+            return Err(Error::InvalidTree.into());
+        }
+
+        let local_root = self.merkle_tree.root_hash();
+        if local_root.is_some() && local_root != tree.root_hash() {
+            let err = Error::RootMismatch(
+                tree.root_hash().map(hex::encode).unwrap_or_default(),
+                local_root.map(hex::encode).unwrap_or_default(),
+            );
+            error!(event = "root mismatch", ?err);
+            return Err(err.into());
+        }
+
+        info!(
+            event = "tree restored",
+            bucket_id = self.bucket_id(),
+            leaves_count = tree.leaves_count(),
+            root = tree.root_hash().map(hex::encode)
+        );
+
+        self.merkle_tree = tree;
+        self.persist_state()
+    }
+
+    /// Verifies the proofs of all the leaves of the bucket against the local
+    /// Merkle root, without downloading the files
+    ///
+    /// Proofs are requested in batches if the server supports it. Returns the
+    /// number of valid and invalid proofs.
+    pub async fn verify_all_proofs(
+        &self,
+    ) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+        let root = self
+            .merkle_tree
+            .root_hash()
+            .ok_or(Error::MissingMerkleRoot)?;
+        let leaves = self.merkle_tree.leaves();
+        let indices: Vec<usize> = (0..leaves.len()).collect();
+
+        let mut proofs = Vec::with_capacity(leaves.len());
+        if self.capabilities.multiproof {
+            let batch_size = self
+                .capabilities
+                .max_batch_proofs
+                .unwrap_or(BATCH_PROOFS)
+                .clamp(1, BATCH_PROOFS);
+            for batch in indices.chunks(batch_size) {
+                proofs.extend(self.download_proofs(batch).await?);
+            }
+        } else {
+            for index in &indices {
+                let bytes = self
+                    .download_blob(
+                        &self.server_url,
+                        &index.to_string(),
+                        "proof",
+                    )
+                    .await?;
+                proofs.push(bincode::deserialize(&bytes)?);
+            }
+        }
+
+        let valid = leaves
+            .iter()
+            .zip(&proofs)
+            .filter(|(leaf, proof)| {
+                merkle::Tree::verify_proof(leaf, proof, &root)
+            })
+            .count();
+        let invalid = leaves.len() - valid;
+
+        info!(
+            event = "proofs verified",
+            bucket_id = self.bucket_id(),
+            valid,
+            invalid
+        );
+
+        Ok((valid, invalid))
+    }
+
+    /// Downloads the proofs of several files in a single request, retried
+    /// while it fails transiently
+    async fn download_proofs(
+        &self,
+        indices: &[usize],
+    ) -> Result<Vec<Vec<(Hash, u8)>>, Box<dyn std::error::Error>> {
+        let uri = format!("{}/proofs/{}", self.server_url, self.bucket_id());
+        self.retried(&uri, || Self::request_proofs(&uri, indices))
+            .await
+    }
+
+    async fn request_proofs(
+        uri: &str,
+        indices: &[usize],
+    ) -> Result<Vec<Vec<(Hash, u8)>>, Box<dyn std::error::Error>> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .body(body::full(serde_json::to_vec(indices)?))?;
+
+        let res = send(req).await?;
+        if res.status() != StatusCode::OK {
+            let fallback = Error::FailedDownload(
+                "proofs".to_owned(),
+                format!("{:?}", indices),
+                res.status(),
+            );
+            return Err(server_error(res, fallback).await.into());
+        }
+
+        let bytes = body::to_bytes(res.into_body()).await?;
+        let proofs: Vec<Vec<(Hash, u8)>> = bincode::deserialize(&bytes)?;
+        if proofs.len() != indices.len() {
+            return Err(Error::InvalidProof.into());
+        }
+
+        Ok(proofs)
+    }
+
+    /// Lists the files of the bucket on the server, page by page
+    pub async fn list_remote_files(
+        &self,
+    ) -> Result<Vec<RemoteFile>, Box<dyn std::error::Error>> {
+        let mut files = Vec::new();
+
+        loop {
+            let uri = format!(
+                "{}/list/{}?offset={}&limit={}",
+                self.server_url,
+                self.bucket_id(),
+                files.len(),
+                LIST_PAGE_SIZE
+            );
+            let page = Self::get_json::<Vec<RemoteFile>>(&uri).await?;
+            let last_page = page.len() < LIST_PAGE_SIZE;
+            files.extend(page);
+
+            if last_page {
+                return Ok(files);
+            }
+        }
+    }
+
+    /// Deletes a file from the bucket on the server
+    ///
+    /// The leaf is removed from the local Merkle tree as well, and the new
+    /// local root is checked against the root returned by the server.
+    pub async fn delete_remote_file(
+        &mut self,
+        file_index: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut req = Request::builder().method(Method::DELETE).uri(format!(
+            "{}/file/{}/{}",
+            self.server_url,
+            self.bucket_id(),
+            file_index
+        ));
+        if let Some(signer) = self.signer() {
+            let (filename, hash) = self.remote_file(file_index).await?;
+            req = signer.sign(
+                req,
+                Action::DeleteFile,
+                &self.bucket_id(),
+                &filename,
+                Some(&hash),
+            );
+        }
+        let req = req.body(body::empty())?;
+
+        let res = send(req).await?;
+        if res.status() != StatusCode::OK {
+            let fallback =
+                Error::FailedDelete(file_index.to_string(), res.status());
+            return Err(server_error(res, fallback).await.into());
+        }
+
+        let body = body::to_bytes(res.into_body()).await?;
+        let server_root = BucketRoot::decode(&body);
+
+        let mut leaves = self.merkle_tree.leaves();
+        if file_index < leaves.len() {
+            let leaf = leaves.remove(file_index);
+            self.files.remove(&leaf);
+        }
+        self.merkle_tree = merkle::Tree::build_from_leaves(leaves);
+        self.persist_state()?;
+
+        let root = self.merkle_tree.root_hash();
+        info!(
+            event = "file deleted",
+            bucket_id = self.bucket_id(),
+            file_index,
+            root = root.map(hex::encode)
+        );
+
+        if server_root != root {
+            let err = Error::RootMismatch(
+                server_root.map(hex::encode).unwrap_or_default(),
+                root.map(hex::encode).unwrap_or_default(),
+            );
+            error!(event = "root mismatch", ?err);
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns the name and the leaf hash of a file of the bucket
+    ///
+    /// The local record is used when there is one, the server is asked
+    /// otherwise.
+    async fn remote_file(
+        &self,
+        file_index: usize,
+    ) -> Result<(String, Hash), Box<dyn std::error::Error>> {
+        let leaf = self.merkle_tree.leaves().get(file_index).copied();
+        if let Some(record) = leaf.and_then(|leaf| self.files.get(&leaf)) {
+            return Ok((record.file_name.clone(), leaf.expect("known leaf")));
+        }
+
+        let uri = format!(
+            "{}/list/{}?offset={}&limit=1",
+            self.server_url,
+            self.bucket_id(),
+            file_index
+        );
+        let file = Self::get_json::<Vec<RemoteFile>>(&uri)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(Error::FailedDelete(
+                file_index.to_string(),
+                StatusCode::NOT_FOUND,
+            ))?;
+        let hash = hex::decode(&file.hash)?
+            .try_into()
+            .map_err(|_| Error::InvalidTree)?;
+
+        Ok((file.filename, hash))
+    }
+
+    /// Returns the owner key if the server verifies signatures
+    fn signer(&self) -> Option<Arc<Signer>> {
+        self.capabilities
+            .auth_schemes
+            .iter()
+            .any(|scheme| scheme == "ed25519")
+            .then(|| self.signer.clone())
+    }
+
+    /// Registers the public key of the client as the owner of the bucket
+    ///
+    /// Registering the key again is accepted by the server.
+    async fn register_owner(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(signer) = self.signer() else {
+            return Ok(());
+        };
+
+        let body = serde_json::json!({ "public_key": signer.public_key() });
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/owner/{}", self.server_url, self.bucket_id()))
+            .header("Content-Type", "application/json")
+            .body(body::full(body.to_string()))?;
+
+        let res = send(req).await?;
+        if res.status() != StatusCode::OK {
+            let fallback = Error::FailedRegisterOwner(res.status());
+            return Err(server_error(res, fallback).await.into());
+        }
+
+        info!(
+            event = "bucket owner registered",
+            bucket_id = self.bucket_id(),
+            public_key = signer.public_key()
+        );
+        Ok(())
+    }
+
+    /// Warns if the batch does not fit in the quotas of the bucket
+    ///
+    /// The server refuses the files over the quota, the batch is uploaded
+    /// anyway. A bucket that does not exist yet has no usage.
+    async fn check_quota(&self, files: &[(OsString, String)]) {
+        let uri = format!("{}/stats/{}", self.server_url, self.bucket_id());
+        let Ok(usage) = Self::get_json::<Usage>(&uri).await else {
+            return;
+        };
+
+        let batch_size: u64 = files
+            .iter()
+            .filter_map(|(_, path)| fs::metadata(path).ok())
+            .map(|m| m.len())
+            .sum();
+
+        if let Some(quota) = usage.quota_bytes {
+            if usage.bytes_used + batch_size > quota {
+                warn!(
+                    event = "upload exceeds the bucket quota",
+                    bytes_used = usage.bytes_used,
+                    batch_size,
+                    quota_bytes = quota
+                );
+            }
+        }
+        if let Some(quota) = usage.quota_files {
+            if usage.files_count + files.len() > quota {
+                warn!(
+                    event = "upload exceeds the bucket files quota",
+                    files_count = usage.files_count,
+                    batch_files = files.len(),
+                    quota_files = quota
+                );
+            }
+        }
+    }
+
+    /// Returns the primary server followed by the standby servers
+    fn server_urls(&self) -> Vec<String> {
+        let mut urls = vec![self.server_url.clone()];
+        urls.extend(self.standby_urls.iter().cloned());
+        urls
+    }
+
+    /// Downloads a file and its proof from the same server
+    async fn download_file_and_proof(
+        &self,
+        url: &str,
+        file_index: &str,
+        aad: &[u8],
+        legacy: bool,
+        builtin_key: bool,
+        part_path: &str,
+    ) -> Result<(Hash, Vec<(Hash, u8)>), Box<dyn std::error::Error>> {
+        // Download the file
+        let file_progress = FileProgress::single(&format!("file {file_index}"));
+        let file_hash = file_progress
+            .clone()
+            .scope(self.download_file(
+                url,
+                file_index,
+                aad,
+                legacy,
+                builtin_key,
+                part_path,
+            ))
+            .await;
+        file_progress.finish();
+        let file_hash = file_hash?;
+        let hash = hex::encode(file_hash);
+        info!(event = "file data received", url, file_index, hash);
+
+        // Download the proof of the received content, by hash if supported
+        // so that it does not depend on the index ordering of the server
+        info!(event = "request proof", url, file_index);
+        let bytes = if self.capabilities.proof_by_hash {
+            self.download_blob(url, &hash, "proof_by_hash").await?
+        } else {
+            self.download_blob(url, file_index, "proof").await?
+        };
+        let proof: Vec<([u8; 32], u8)> = bincode::deserialize(&bytes)?;
+
+        Ok((file_hash, proof))
+    }
+
+    /// Returns the local record of the file at `file_index`, if any
+    fn record(&self, file_index: &str) -> Option<&FileRecord> {
+        file_index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| self.merkle_tree.leaves().get(index).copied())
+            .and_then(|leaf| self.files.get(&leaf))
+    }
+
+    /// Returns true if the files of the bucket are opened with the built-in
+    /// key rather than the key of the client
+    ///
+    /// The files of a bucket recorded as sealed with the built-in key are
+    /// opened with it, and a bucket sealed with yet another key is refused
+    /// rather than failing authentication file after file.
+    fn builtin_key(&self) -> Result<bool, Error> {
+        match self.key_check {
+            None => Ok(false),
+            Some(check) if check == cipher::client_key_check() => Ok(false),
+            Some(check) if check == cipher::builtin_key_check() => Ok(true),
+            Some(_) => Err(Error::WrongKey(self.bucket_name.clone())),
+        }
+    }
+
+    /// Returns true if the records tell the file at `file_index` was
+    /// uploaded before the files were sealed, in a legacy format
+    fn is_legacy(&self, file_index: &str) -> bool {
+        self.record(file_index)
+            .is_some_and(|record| record.source_hash.is_none())
+    }
+
+    /// Returns the name the file at `file_index` was uploaded under, which
+    /// its content is authenticated with
+    ///
+    /// The name is taken from the local records, or from the files of the
+    /// bucket on the server for a file the client has no record of.
+    async fn file_name(&self, file_index: &str) -> String {
+        let record = self.record(file_index);
+        if let Some(record) = record.filter(|r| !r.file_name.is_empty()) {
+            return record.file_name.clone();
+        }
+
+        match self.list_remote_files().await {
+            Ok(remote) => remote
+                .into_iter()
+                .find(|file| file.index.to_string() == file_index)
+                .map(|file| file.filename)
+                .unwrap_or_default(),
+            Err(err) => {
+                warn!(event = "failed to list remote files", ?err);
+                String::new()
+            }
+        }
+    }
+
+    /// Verify the provided merkle path for a file
+    async fn verify(
+        &self,
+        proof: Vec<(Hash, u8)>,
+        hash: &Hash,
+    ) -> Result<(), Error> {
+        if let Some(merkle_root) = self.merkle_tree.root_hash() {
+            info!(
+                event = "checking proof",
+                hash = hex::encode(hash),
+                proof_len = proof.len(),
+                merkle_root = hex::encode(merkle_root)
+            );
+
+            if !merkle::Tree::verify_proof(hash, &proof, &merkle_root) {
+                return Err(Error::InvalidProof);
+            }
+
+            return Ok(());
+        }
+
+        Err(Error::MissingMerkleRoot)
+    }
+
+    /// Records the outcome of a proof verification for the leaf at `file_index`
+    fn record_verification(&mut self, file_index: &str, verified: bool) {
+        let Some(leaf) = file_index
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| self.merkle_tree.leaves().get(index).copied())
+        else {
+            return;
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let record = self.files.entry(leaf).or_default();
+        record.last_verified = Some(now);
+        record.verified = Some(verified);
+    }
+
+    /// Returns a report entry for every leaf of the bucket
+    pub(crate) fn report(&self) -> Vec<ReportEntry> {
+        self.merkle_tree
+            .leaves()
+            .iter()
+            .enumerate()
+            .map(|(index, leaf)| {
+                let record = self.files.get(leaf).cloned().unwrap_or_default();
+                ReportEntry {
+                    index,
+                    leaf: hex::encode(leaf),
+                    file_name: record.file_name,
+                    size: record.size,
+                    last_verified: record.last_verified,
+                    verified: record.verified,
+                }
+            })
+            .collect()
+    }
+
+    /// Moves a verified file from `part_path` to the downloads folder
+    /// File is named after the hash of the content, its path is returned
+    fn save_file(
+        &self,
+        file_id: &[u8],
+        part_path: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let local_repo = self.folder.to_owned() + LOCAL_REPO;
+
+        let _ = fs::create_dir_all(&local_repo);
+        let path = format!("{}/{}", local_repo, hex::encode(file_id));
+
+        fs::rename(part_path, &path)?;
+        info!(event = "valid file saved", file = path);
+
+        Ok(path)
+    }
+
+    /// Encrypt and upload a file to the storage server
+    ///
+    /// The file goes through a pipeline of stages connected by bounded
+    /// channels (read -> encrypt and hash -> transmit), so reading and
+    /// encrypting chunk N overlaps with the network transmission of chunk N-1.
+    ///
+    /// Returns the hash and the size of the encrypted file on successful upload
+    async fn encrypt_and_upload(
+        url: &str,
+        bucket_id: &str,
+        file_name: String,
+        file_path: &String,
+        signer: Option<Arc<Signer>>,
+        headers: &UploadHeaders,
+    ) -> Result<(Hash, u64), Error> {
+        info!(event = "encrypting file", file_name, file_path);
+        let nonce = cipher::random_nonce();
+        let aad = cipher::aad(bucket_id, &file_name);
+
+        // The signature covers the hash, computed before the upload starts
+        let signed_hash = match signer {
+            Some(_) => {
+                let path = file_path.clone();
+                let aad = aad.clone();
+                let hash = tokio::task::spawn_blocking(move || {
+                    cipher::encrypted_hash(&path, &nonce, aad)
+                })
+                .await
+                .map_err(|_| Error::FailUpload(file_name.clone()))?
+                .map_err(|_| Error::FailUpload(file_name.clone()))?;
+                Some(hash)
+            }
+            None => None,
+        };
+
+        let (plain_tx, plain_rx) = mpsc::channel(PIPELINE_DEPTH);
+        let (cipher_tx, cipher_rx) = mpsc::channel(PIPELINE_DEPTH);
+
+        tokio::spawn(Self::read_chunks(file_path.clone(), plain_tx));
+        let encryptor = tokio::spawn(
+            Self::encrypt_chunks(
+                plain_rx,
+                cipher_tx,
+                Encryptor::new(&nonce, aad),
+            )
+            .instrument(info_span!("encrypt")),
+        );
+
+        info!(event = "uploading a file", file_name);
+
+        // The size of the sealed file follows from the size of the source,
+        // so the server can refuse a file over its limit before it is sent
+        let file_size = tokio::fs::metadata(file_path)
+            .await
+            .map_err(|_| Error::FailUpload(file_name.clone()))?
+            .len();
+        let file_size = cipher::encrypted_size(file_size);
+
+        // Upload the file to the storage server
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/upload_file/{}/{}", url, bucket_id, file_name))
+            .header("Content-Type", "application/octet-stream")
+            .header("Content-Length", file_size);
+        req = headers.in_session(headers.tagged(req));
+        if let (Some(signer), Some(hash)) = (&signer, &signed_hash) {
+            req = signer.sign(
+                req,
+                Action::Upload,
+                bucket_id,
+                &file_name,
+                Some(hash),
+            );
+        }
+        let req = req
+            .body(body::wrap_stream(progress::stream(throttle::stream(
+                ReceiverStream::new(cipher_rx),
+            ))))
+            .expect("TODO");
+
+        let res = send(req)
+            .await
+            .map_err(|err| Error::Connection(err.to_string()))?;
+
+        // The hash is known only once the last chunk has been encrypted
+        let (hash, size) = encryptor
+            .await
+            .map_err(|_| Error::FailUpload(file_name.clone()))?
+            .map_err(|_| Error::FailUpload(file_name.clone()))?;
+
+        if res.status() != StatusCode::OK {
+            Err(server_error(res, Error::FailUpload(file_name)).await)
+        } else if signed_hash.is_some_and(|signed| signed != hash) {
+            Err(Error::FailUpload(file_name))
+        } else {
+            Self::check_uploaded(res, &file_name, &hash).await?;
+            Ok((hash, size))
+        }
+    }
+
+    /// Checks the server stored the file under the hash computed locally
+    ///
+    /// Servers that predate the JSON upload reply are trusted.
+    async fn check_uploaded(
+        res: hyper::Response<Incoming>,
+        file_name: &str,
+        hash: &Hash,
+    ) -> Result<(), Error> {
+        let body = body::to_bytes(res.into_body())
+            .await
+            .map_err(|_| Error::FailUpload(file_name.to_owned()))?;
+        let Ok(uploaded) = serde_json::from_slice::<UploadedFile>(&body) else {
+            return Ok(());
+        };
+
+        if uploaded.hash != hex::encode(hash) {
+            error!(
+                event = "uploaded hash mismatch",
+                file_name,
+                server_hash = uploaded.hash
+            );
+            return Err(Error::FailUpload(file_name.to_owned()));
+        }
+        info!(event = "file stored", file_name, index = uploaded.index);
+
+        Ok(())
+    }
+
+    /// Returns the chunk size of resumable uploads, `None` if the server does
+    /// not support them
+    fn resumable_chunk_size(&self) -> Option<u64> {
+        if !self.capabilities.resumable_upload {
+            return None;
+        }
+
+        Some(
+            self.capabilities
+                .max_chunk_size
+                .map_or(RESUMABLE_CHUNK_SIZE, |max| {
+                    max.min(RESUMABLE_CHUNK_SIZE)
+                }),
+        )
+    }
+
+    /// Returns the number of parts of a large file sent at once, `None` if
+    /// they are sent one after the other
+    fn multipart_parts(&self) -> Option<usize> {
+        (self.capabilities.multipart_upload && self.parallel_parts > 1)
+            .then_some(self.parallel_parts)
+    }
+
+    /// Encrypt and upload a file to the storage server in chunks of
+    /// `chunk_size` bytes
+    ///
+    /// A chunk that fails to be sent is retried up to `CHUNK_ATTEMPTS` times,
+    /// so only that chunk is sent again after a dropped connection.
+    ///
+    /// Returns the hash and the size of the encrypted file on successful upload
+    async fn encrypt_and_upload_resumable(
+        url: &str,
+        bucket_id: &str,
+        file_name: String,
+        file_path: &String,
+        chunk_size: u64,
+        signer: Option<Arc<Signer>>,
+        headers: &UploadHeaders,
+    ) -> Result<(Hash, u64), Error> {
+        info!(event = "resumable upload", file_name, file_path);
+        let fail = || Error::FailUpload(file_name.clone());
+
+        let upload_id =
+            Self::upload_init(url, bucket_id, &file_name, headers).await?;
+
+        let encryptor = Encryptor::new(
+            &cipher::random_nonce(),
+            cipher::aad(bucket_id, &file_name),
+        );
+        let mut file = EncryptedReader::open(file_path, encryptor)
+            .await
+            .map_err(|_| fail())?;
+        let mut hasher = Sha256::new();
+        let mut offset = 0u64;
+
+        loop {
+            let chunk = file
+                .read(chunk_size as usize)
+                .instrument(info_span!("encrypt", offset))
+                .await
+                .map_err(|_| fail())?;
+            if chunk.is_empty() {
+                break;
+            }
+            hasher.update(&chunk);
+
+            let len = chunk.len();
+            offset = Self::send_chunk(
+                url,
+                &upload_id,
+                &file_name,
+                offset,
+                Bytes::from(chunk),
+            )
+            .await?;
+            progress::advance(len);
+        }
+
+        let hash = hasher.finalize().into();
+        Self::upload_finish(
+            url, bucket_id, &upload_id, &file_name, &hash, signer, headers,
+        )
+        .await?;
+
+        Ok((hash, offset))
+    }
+
+    /// Encrypt and upload a file to the storage server in parts of
+    /// `part_size` bytes, sending up to `parallel_parts` parts at once
+    ///
+    /// The parts are read, encrypted and hashed in order, and reassembled by
+    /// the server. A part that fails to be sent is retried up to
+    /// `CHUNK_ATTEMPTS` times.
+    ///
+    /// Returns the hash and the size of the encrypted file on successful upload
+    async fn encrypt_and_upload_multipart(
+        url: &str,
+        bucket_id: &str,
+        file_name: String,
+        file_path: &String,
+        (part_size, parallel_parts): (u64, usize),
+        signer: Option<Arc<Signer>>,
+        headers: &UploadHeaders,
+    ) -> Result<(Hash, u64), Error> {
+        info!(
+            event = "multi-part upload",
+            file_name, file_path, parallel_parts
+        );
+        let fail = || Error::FailUpload(file_name.clone());
+
+        let upload_id =
+            Self::upload_init(url, bucket_id, &file_name, headers).await?;
+
+        let encryptor = Encryptor::new(
+            &cipher::random_nonce(),
+            cipher::aad(bucket_id, &file_name),
+        );
+        let mut file = EncryptedReader::open(file_path, encryptor)
+            .await
+            .map_err(|_| fail())?;
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
+        let mut sending = JoinSet::new();
+
+        for number in 1u32.. {
+            let part = file
+                .read(part_size as usize)
+                .instrument(info_span!("encrypt", number))
+                .await
+                .map_err(|_| fail())?;
+            if part.is_empty() {
+                break;
+            }
+            hasher.update(&part);
+            size += part.len() as u64;
+
+            // The next part is read while the others are sent
+            if sending.len() >= parallel_parts {
+                let sent = sending.join_next().await.expect("a part is sent");
+                sent.map_err(|_| fail())??;
+            }
+            let send = Self::send_part(
+                url.to_owned(),
+                upload_id.clone(),
+                file_name.clone(),
+                number,
+                Bytes::from(part),
+            );
+            sending.spawn(progress::current().scope(send).in_current_span());
+        }
+        while let Some(sent) = sending.join_next().await {
+            sent.map_err(|_| fail())??;
+        }
+
+        let hash = hasher.finalize().into();
+        Self::upload_finish(
+            url, bucket_id, &upload_id, &file_name, &hash, signer, headers,
+        )
+        .await?;
+
+        Ok((hash, size))
+    }
+
+    /// Opens the resumable upload of a file
+    ///
+    /// Returns the id of the upload
+    async fn upload_init(
+        url: &str,
+        bucket_id: &str,
+        file_name: &str,
+        headers: &UploadHeaders,
+    ) -> Result<String, Error> {
+        let fail = || Error::FailUpload(file_name.to_owned());
+
+        let init = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/upload_init/{}/{}", url, bucket_id, file_name));
+        let init = headers
+            .tagged(init)
+            .body(body::empty())
+            .expect("valid request");
+        let res = send(init)
+            .await
+            .map_err(|err| Error::Connection(err.to_string()))?;
+        if res.status() != StatusCode::OK {
+            return Err(server_error(res, fail()).await);
+        }
+        let body = body::to_bytes(res.into_body()).await.map_err(|_| fail())?;
+        let UploadInit { upload_id } =
+            serde_json::from_slice(&body).map_err(|_| fail())?;
+
+        Ok(upload_id)
+    }
+
+    /// Seals a resumable upload into the bucket, signed with `hash`
+    async fn upload_finish(
+        url: &str,
+        bucket_id: &str,
+        upload_id: &str,
+        file_name: &str,
+        hash: &Hash,
+        signer: Option<Arc<Signer>>,
+        headers: &UploadHeaders,
+    ) -> Result<(), Error> {
+        let fail = || Error::FailUpload(file_name.to_owned());
+
+        let mut finish = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/upload_finish/{}", url, upload_id));
+        finish = headers.in_session(finish);
+        if let Some(signer) = &signer {
+            finish = signer.sign(
+                finish,
+                Action::Upload,
+                bucket_id,
+                file_name,
+                Some(hash),
+            );
+        }
+        let finish = finish.body(body::empty()).expect("valid request");
+        let res = send(finish)
+            .await
+            .map_err(|err| Error::Connection(err.to_string()))?;
+        if res.status() != StatusCode::OK {
+            return Err(server_error(res, fail()).await);
+        }
+        Self::check_uploaded(res, file_name, hash).await
+    }
+
+    /// Sends the part `number` of a multi-part upload
+    async fn send_part(
+        url: String,
+        upload_id: String,
+        file_name: String,
+        number: u32,
+        part: Bytes,
+    ) -> Result<(), Error> {
+        let mut attempt = 1;
+        loop {
+            let req = Request::builder()
+                .method(Method::PUT)
+                .uri(format!("{}/upload_part/{}", url, upload_id))
+                .header("Content-Type", "application/octet-stream")
+                .header("Upload-Part", number)
+                .body(throttle::body(part.clone()))
+                .expect("valid request");
+
+            match send(req).await {
+                Ok(res) if res.status() == StatusCode::OK => {
+                    progress::advance(part.len());
+                    return Ok(());
+                }
+                res if attempt < CHUNK_ATTEMPTS => {
+                    let status = res.as_ref().map(|r| r.status()).ok();
+                    let request_id = res.as_ref().map(request_id).ok();
+                    warn!(
+                        event = "retry part",
+                        file_name,
+                        number,
+                        attempt,
+                        ?status,
+                        ?request_id
+                    );
+                    attempt += 1;
+                    tokio::time::sleep(CHUNK_RETRY_DELAY).await;
+                }
+                Ok(res) => {
+                    let fallback = Error::FailUpload(file_name);
+                    return Err(server_error(res, fallback).await);
+                }
+                Err(_) => return Err(Error::FailUpload(file_name)),
+            }
+        }
+    }
+
+    /// Sends a chunk of a resumable upload starting at `offset`
+    ///
+    /// Returns the offset of the next chunk
+    async fn send_chunk(
+        url: &str,
+        upload_id: &str,
+        file_name: &str,
+        offset: u64,
+        chunk: Bytes,
+    ) -> Result<u64, Error> {
+        let next_offset = offset + chunk.len() as u64;
+
+        let mut attempt = 1;
+        loop {
+            let req = Request::builder()
+                .method(Method::PATCH)
+                .uri(format!("{}/upload_chunk/{}", url, upload_id))
+                .header("Content-Type", "application/octet-stream")
+                .header("Upload-Offset", offset)
+                .body(throttle::body(chunk.clone()))
+                .expect("valid request");
+
+            match send(req).await {
+                Ok(res) if res.status() == StatusCode::OK => {
+                    return Ok(next_offset)
+                }
+                Ok(res) if res.status() == StatusCode::CONFLICT => {
+                    // The chunk was stored even though the response to a
+                    // previous attempt was lost
+                    let body = body::to_bytes(res.into_body()).await;
+                    let server_offset = body
+                        .ok()
+                        .and_then(|b| serde_json::from_slice::<Offset>(&b).ok())
+                        .map(|o| o.offset);
+
+                    if server_offset == Some(next_offset) {
+                        return Ok(next_offset);
+                    }
+
+                    error!(
+                        event = "upload offset mismatch",
+                        file_name,
+                        offset,
+                        ?server_offset
+                    );
+                    return Err(Error::FailUpload(file_name.to_owned()));
+                }
+                res if attempt < CHUNK_ATTEMPTS => {
+                    let status = res.as_ref().map(|r| r.status()).ok();
+                    let request_id = res.as_ref().map(request_id).ok();
+                    warn!(
+                        event = "retry chunk",
+                        file_name,
+                        offset,
+                        attempt,
+                        ?status,
+                        ?request_id
+                    );
+                    attempt += 1;
+                    tokio::time::sleep(CHUNK_RETRY_DELAY).await;
+                }
+                Ok(res) => {
+                    let fallback = Error::FailUpload(file_name.to_owned());
+                    return Err(server_error(res, fallback).await);
+                }
+                Err(_) => return Err(Error::FailUpload(file_name.to_owned())),
+            }
+        }
+    }
 
-        Ok(())
+    /// First pipeline stage: reads a file in chunks of `CHUNK_SIZE`
+    ///
+    /// A read error is forwarded down the pipeline so that the upload request
+    /// is aborted instead of sending a truncated file.
+    async fn read_chunks(
+        file_path: String,
+        tx: mpsc::Sender<io::Result<Vec<u8>>>,
+    ) {
+        let mut file = match tokio::fs::File::open(&file_path).await {
+            Ok(file) => file,
+            Err(err) => {
+                let _ = tx.send(Err(err)).await;
+                return;
+            }
+        };
+
+        loop {
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            match file.read(&mut chunk).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    if tx.send(Ok(chunk)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    let _ = tx.send(Err(err)).await;
+                    break;
+                }
+            }
+        }
     }
 
-    /// Encrypt and upload a file to the storage server
+    /// Second pipeline stage: encrypts and hashes chunks in order, then hands
+    /// them over to the network stage
     ///
-    /// Returns the hash of the encrypted file on successful upload
-    async fn encrypt_and_upload(
-        url: &str,
-        bucket_id: &str,
-        file_name: String,
-        file_path: &String,
-    ) -> Result<Hash, Error> {
-        info!(event = "encrypting file", file_name, file_path);
-        let mut data = fs::read(file_path).expect("valid file path");
+    /// Returns the hash and the size of the whole encrypted file
+    async fn encrypt_chunks(
+        mut rx: mpsc::Receiver<io::Result<Vec<u8>>>,
+        tx: mpsc::Sender<io::Result<Bytes>>,
+        mut encryptor: Encryptor,
+    ) -> io::Result<(Hash, u64)> {
+        let mut hasher = Sha256::new();
+        let mut size = 0u64;
 
-        // encrypt the file with ChaCha20
-        let mut cipher = ChaCha20::new(&CHACHA_KEY.into(), &[0x24; 12].into());
-        cipher.apply_keystream(&mut data);
+        loop {
+            // The last segment is sealed once the whole file is read
+            let sealed = match rx.recv().await {
+                Some(Ok(chunk)) => encryptor.update(&chunk),
+                Some(Err(err)) => {
+                    let kind = err.kind();
+                    let _ = tx.send(Err(err)).await;
+                    return Err(kind.into());
+                }
+                None => break,
+            };
 
-        let hash: [u8; 32] = Sha256::digest(&data).into();
-        info!(event = "uploading a file", file_name);
+            if !sealed.is_empty() {
+                hasher.update(&sealed);
+                size += sealed.len() as u64;
+                if tx.send(Ok(Bytes::from(sealed))).await.is_err() {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+            }
+        }
 
-        // Upload the file to the storage server
-        let req = Request::builder()
-            .method(Method::POST)
-            .uri(format!("{}/upload_file/{}/{}", url, bucket_id, file_name))
-            .header("Content-Type", "application/octet-stream")
-            .body(Body::from(data))
-            .expect("TODO");
+        let sealed = encryptor.finish();
+        hasher.update(&sealed);
+        size += sealed.len() as u64;
+        if tx.send(Ok(Bytes::from(sealed))).await.is_err() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
 
-        let http_client = Client::new();
-        let res = http_client
-            .request(req)
-            .await
-            .map_err(|_| Error::FailUpload(file_name.clone()))?;
+        Ok((hasher.finalize().into(), size))
+    }
 
+    /// Opens an upload session of the bucket, if the server supports them
+    ///
+    /// Returns the token of the session, sent with the uploads and the
+    /// completion of the batch
+    async fn begin_upload(&self) -> Result<Option<String>, Error> {
+        if !self.capabilities.upload_sessions {
+            return Ok(None);
+        }
+
+        let mut req = Request::builder().method(Method::POST).uri(format!(
+            "{}/begin_upload/{}",
+            self.server_url,
+            self.bucket_id()
+        ));
+        if let Some(signer) = self.signer() {
+            req = signer.sign(
+                req,
+                Action::BeginUpload,
+                &self.bucket_id(),
+                "",
+                None,
+            );
+        }
+        let req = req.body(body::empty()).expect("valid request");
+        let res = send(req).await.map_err(|_| Error::FailBeginUpload)?;
         if res.status() != StatusCode::OK {
-            Err(Error::FailUpload(file_name))
-        } else {
-            Ok(hash)
+            return Err(server_error(res, Error::FailBeginUpload).await);
         }
+
+        let body = body::to_bytes(res.into_body())
+            .await
+            .map_err(|_| Error::FailBeginUpload)?;
+        let BeginUpload { session } = serde_json::from_slice(&body)
+            .map_err(|_| Error::FailBeginUpload)?;
+
+        Ok(Some(session))
     }
 
     /// Terminates the upload session on the server
-    async fn close_upload(&self) -> Result<(), Error> {
-        let http_client = Client::new();
-        if let Ok(req) = Request::builder()
+    ///
+    /// Returns the Merkle root of the bucket calculated by the server, if
+    /// reported
+    async fn close_upload(
+        &self,
+        session: Option<&str>,
+    ) -> Result<Option<Hash>, Error> {
+        let mut req = Request::builder()
             .method(Method::POST)
             .uri(format!(
                 "{}/complete_upload/{}",
                 self.server_url,
                 self.bucket_id()
             ))
-            .header("Content-Type", "application/octet-stream")
-            .body(Body::empty())
-        {
-            let res = http_client
-                .request(req)
-                .await
-                .map_err(|_| Error::FailCloseUpload)?;
+            .header("Content-Type", "application/octet-stream");
+        if let Some(session) = session {
+            req = req.header(SESSION_HEADER, session);
+        }
+        if let Some(signer) = self.signer() {
+            req =
+                signer.sign(req, Action::Complete, &self.bucket_id(), "", None);
+        }
+        if let Ok(req) = req.body(body::empty()) {
+            let res = send(req).await.map_err(|_| Error::FailCloseUpload)?;
 
             if res.status() != StatusCode::OK {
-                error!(event = "failed to close upload file");
+                error!(
+                    event = "failed to close upload file",
+                    status = %res.status(),
+                    request_id = request_id(&res)
+                );
             } else {
                 info!(event = "bucket finalized", bucket_id = self.bucket_id());
+
+                let body = body::to_bytes(res.into_body())
+                    .await
+                    .map_err(|_| Error::FailCloseUpload)?;
+                let Ok(root) = serde_json::from_slice::<BucketRoot>(&body)
+                else {
+                    return Ok(None);
+                };
+                if let (Some(hash), Some(attestation)) =
+                    (root.hash(), &root.attestation)
+                {
+                    self.keep_attestation(
+                        attestation,
+                        &hash,
+                        root.leaves_count,
+                    );
+                }
+
+                return Ok(root.hash());
             };
         };
 
-        Ok(())
+        Ok(None)
+    }
+
+    /// Keeps the attestation of a root replied by the server in the client
+    /// folder, once its signature is verified
+    fn keep_attestation(
+        &self,
+        attestation: &Attestation,
+        root: &Hash,
+        leaves_count: usize,
+    ) {
+        let bucket_id = self.bucket_id();
+        if !attestation.verify(&bucket_id, root, leaves_count) {
+            warn!(
+                event = "invalid root attestation",
+                bucket_id,
+                version = attestation.version,
+                root = hex::encode(root)
+            );
+            return;
+        }
+
+        if let Err(err) =
+            attestation.record(&self.folder, &bucket_id, root, leaves_count)
+        {
+            error!(event = "failed to record root attestation", ?err);
+        }
+    }
+
+    /// Sends a request to `uri`, retried while it fails transiently, up to
+    /// the attempts of the retry policy
+    async fn retried<T, F, Fut>(
+        &self,
+        uri: &str,
+        request: F,
+    ) -> Result<T, Box<dyn std::error::Error>>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match request().await {
+                Ok(res) => return Ok(res),
+                Err(err)
+                    if self.retry.retries(attempt) && is_transient(&*err) =>
+                {
+                    let retry_after = err
+                        .downcast_ref::<Error>()
+                        .and_then(Error::retry_after);
+                    let delay = self.retry.delay(attempt, retry_after);
+                    warn!(event = "retry download", uri, attempt, ?delay, ?err);
+                    attempt += 1;
+                    progress::current().restart();
+                    tokio::time::sleep(delay).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
     }
 
-    /// Downloads a blob/binary object from the storage server
+    /// Downloads a blob/binary object from a storage server
     async fn download_blob(
         &self,
+        url: &str,
         file_index: &str,
         resource_type: &str,
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
         let uri = format!(
             "{}/{}/{}/{}",
-            self.server_url,
+            url,
             resource_type,
             self.bucket_id(),
             file_index
         );
 
-        let client = Client::new();
-        let mut res = client.get(uri.parse()?).await?;
+        self.retried(&uri, || Self::get_blob(&uri, file_index, resource_type))
+            .await
+    }
+
+    /// Downloads a file into `part_path`, decrypted as it is received
+    ///
+    /// Returns the hash of the encrypted content, to be checked against the
+    /// proof before the file is kept.
+    async fn download_file(
+        &self,
+        url: &str,
+        file_index: &str,
+        aad: &[u8],
+        legacy: bool,
+        builtin_key: bool,
+        part_path: &str,
+    ) -> Result<Hash, Box<dyn std::error::Error>> {
+        let uri = format!("{}/file/{}/{}", url, self.bucket_id(), file_index);
+
+        self.retried(&uri, || {
+            Self::get_file(
+                &uri,
+                file_index,
+                aad,
+                legacy,
+                builtin_key,
+                part_path,
+            )
+        })
+        .await
+    }
+
+    async fn get_blob(
+        uri: &str,
+        file_index: &str,
+        resource_type: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut res = Self::get_served(uri, file_index, resource_type).await?;
 
         let mut bytes = Vec::new();
-        while let Some(chunk) = res.data().await {
+        while let Some(chunk) = Self::next_chunk(&mut res).await {
             bytes.extend_from_slice(&chunk?);
         }
 
+        Ok(bytes)
+    }
+
+    /// Receives a file chunk by chunk, hashing and decrypting each one so
+    /// that a file of any size is downloaded in constant memory
+    ///
+    /// Fails as soon as a segment of the file fails authentication with
+    /// `aad`, or if the file is in a legacy format unless `legacy`. A sealed
+    /// file is opened with the built-in key if `builtin_key`.
+    async fn get_file(
+        uri: &str,
+        file_index: &str,
+        aad: &[u8],
+        legacy: bool,
+        builtin_key: bool,
+        part_path: &str,
+    ) -> Result<Hash, Box<dyn std::error::Error>> {
+        let mut res = Self::get_served(uri, file_index, "file").await?;
+        let tampered = |err| match err {
+            DecryptError::Tampered => Error::Tampered(file_index.to_owned()),
+            DecryptError::Legacy => Error::Legacy(file_index.to_owned()),
+        };
+
+        let mut file = tokio::fs::File::create(part_path).await?;
+        let mut decryptor = Decryptor::new(aad.to_vec(), legacy);
+        if builtin_key {
+            decryptor = decryptor.with_builtin_key();
+        }
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = Self::next_chunk(&mut res).await {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            let content = decryptor.update(&chunk).map_err(tampered)?;
+            file.write_all(&content).await?;
+        }
+        file.write_all(&decryptor.finish().map_err(tampered)?)
+            .await?;
+        file.sync_all().await?;
+
+        Ok(hasher.finalize().into())
+    }
+
+    /// Sends a GET request for a blob, failing unless the server serves it
+    async fn get_served(
+        uri: &str,
+        file_index: &str,
+        resource_type: &str,
+    ) -> Result<hyper::Response<Incoming>, Box<dyn std::error::Error>> {
+        let res = get(uri)?.await?;
+
         if res.status() != hyper::StatusCode::OK {
-            return Err(Error::FailedDownload(
+            let fallback = Error::FailedDownload(
                 resource_type.to_owned(),
                 file_index.to_owned(),
                 res.status(),
-            )
-            .into());
+            );
+            return Err(server_error(res, fallback).await.into());
         }
 
-        Ok(bytes)
+        if let Some(len) = res.body().size_hint().exact() {
+            progress::set_length(len);
+        }
+        Ok(res)
+    }
+
+    /// Receives the next chunk of a download, paced by the bandwidth limit
+    async fn next_chunk(
+        res: &mut hyper::Response<Incoming>,
+    ) -> Option<Result<Bytes, hyper::Error>> {
+        let chunk = body::next_chunk(res.body_mut()).await?;
+        if let Ok(chunk) = &chunk {
+            throttle::pace(chunk.len()).await;
+            progress::advance(chunk.len());
+        }
+        Some(chunk)
     }
 
     pub(crate) fn bucket_id(&self) -> String {
         hex::encode(self.bucket_id)
     }
+
+    /// Returns the name of the selected bucket
+    pub(crate) fn bucket_name(&self) -> &str {
+        &self.bucket_name
+    }
+
+    /// Returns the local Merkle root of the bucket
+    pub(crate) fn root(&self) -> Option<Hash> {
+        self.merkle_tree.root_hash()
+    }
+}
+
+/// Response of the server to an upload_init request
+#[derive(serde::Deserialize)]
+struct UploadInit {
+    upload_id: String,
+}
+
+/// Headers of the uploads of a batch
+#[derive(Clone, Default)]
+struct UploadHeaders {
+    /// Token of the upload session of the batch
+    session: Option<String>,
+    /// Tags of the files, comma separated
+    tags: Option<String>,
+}
+
+impl UploadHeaders {
+    /// Adds the tags to a request creating a file
+    fn tagged(&self, req: request::Builder) -> request::Builder {
+        match &self.tags {
+            Some(tags) => req.header(TAGS_HEADER, tags),
+            None => req,
+        }
+    }
+
+    /// Adds the session token to a request storing a file
+    fn in_session(&self, req: request::Builder) -> request::Builder {
+        match &self.session {
+            Some(session) => req.header(SESSION_HEADER, session),
+            None => req,
+        }
+    }
 }
 
+/// Response of the server to an upload request
+#[derive(serde::Deserialize)]
+struct UploadedFile {
+    hash: String,
+    /// Index of the file in the bucket once the upload is completed
+    index: usize,
+}
+
+/// Response of the server to a begin_upload request
+#[derive(serde::Deserialize)]
+struct BeginUpload {
+    session: String,
+}
+
+/// Error reply of the server
+#[derive(serde::Deserialize)]
+struct ErrorReply {
+    code: String,
+    message: String,
+    #[serde(default)]
+    request_id: String,
+}
+
+/// Returns the id the server gave to a request, to find it in the server
+/// logs
+fn request_id(res: &hyper::Response<Incoming>) -> String {
+    res.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|request_id| request_id.to_str().ok())
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// Returns the error replied by the server, `fallback` if the body of the
+/// response is not an error reply
+///
+/// The error is logged with the id of the request.
+async fn server_error(
+    res: hyper::Response<Incoming>,
+    fallback: Error,
+) -> Error {
+    let status = res.status();
+    let request_id = request_id(&res);
+    let retry_after = res
+        .headers()
+        .get(hyper::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(retry::parse_retry_after);
+    let body = body::to_bytes(res.into_body()).await;
+    match body.map(|body| serde_json::from_slice::<ErrorReply>(&body)) {
+        Ok(Ok(reply)) => {
+            // Servers predating the header only give it in the body
+            let request_id = if reply.request_id.is_empty() {
+                request_id
+            } else {
+                reply.request_id
+            };
+            error!(
+                event = "server error",
+                %status,
+                code = reply.code,
+                message = reply.message,
+                request_id
+            );
+            Error::Server {
+                status,
+                code: reply.code,
+                message: reply.message,
+                request_id,
+                retry_after,
+            }
+        }
+        _ => {
+            error!(event = "server error", %status, request_id, err = %fallback);
+            fallback
+        }
+    }
+}
+
+/// Offset of a resumable upload reported by the server
+#[derive(serde::Deserialize)]
+struct Offset {
+    offset: u64,
+}
+
+/// Merkle root of the bucket reported by the server after a change
+#[derive(serde::Deserialize)]
+struct BucketRoot {
+    root: Option<String>,
+    #[serde(default)]
+    leaves_count: usize,
+    /// Signature of the root by the server, if it has an identity key
+    #[serde(default)]
+    attestation: Option<Attestation>,
+}
+
+impl BucketRoot {
+    /// Decodes the root from a JSON response body
+    fn decode(body: &[u8]) -> Option<Hash> {
+        serde_json::from_slice::<BucketRoot>(body)
+            .ok()
+            .and_then(|r| r.hash())
+    }
+
+    fn hash(&self) -> Option<Hash> {
+        self.root
+            .as_ref()
+            .and_then(|root| hex::decode(root).ok())
+            .and_then(|root| root.try_into().ok())
+    }
+}
+
+/// Buckets of the client, saved in STATE_FILE
 #[derive(serde::Serialize, serde::Deserialize)]
 struct State {
+    /// Name of the selected bucket
+    selected: String,
+    buckets: BTreeMap<String, BucketState>,
+    /// Parameters deriving the key of the files from a passphrase, if the
+    /// client was given one
+    kdf: Option<KdfParams>,
+}
+
+/// State of a bucket, the whole state of the clients of a single bucket
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct BucketState {
+    merkle_tree: merkle::Tree,
+    bucket_id: [u8; 32],
+    files: BTreeMap<Hash, FileRecord>,
+    /// Check of the key sealing the files of the bucket, recorded on its
+    /// first upload, `None` until then or if the key was not recorded
+    key_check: Option<Hash>,
+}
+
+impl BucketState {
+    /// Returns an empty bucket with a random id
+    fn new() -> Self {
+        let mut bucket_id = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bucket_id[..]);
+
+        BucketState {
+            bucket_id,
+            merkle_tree: merkle::Tree::default(),
+            files: BTreeMap::new(),
+            key_check: None,
+        }
+    }
+
+    /// Returns the check of the built-in key if the bucket has files, the
+    /// key of all the files uploaded before the clients had keys of their
+    /// own
+    fn builtin_key_check(merkle_tree: &merkle::Tree) -> Option<Hash> {
+        (merkle_tree.leaves_count() > 0).then(cipher::builtin_key_check)
+    }
+}
+
+/// Buckets of the client as saved in BUCKETS_V3_FILE, before the buckets
+/// recorded the key sealing their files
+#[derive(serde::Deserialize)]
+struct StateV3 {
+    selected: String,
+    buckets: BTreeMap<String, BucketStateV3>,
+    kdf: Option<KdfParams>,
+}
+
+/// State of a bucket as saved in BUCKETS_V3_FILE and BUCKETS_V2_FILE
+#[derive(serde::Deserialize)]
+struct BucketStateV3 {
+    merkle_tree: merkle::Tree,
+    bucket_id: [u8; 32],
+    files: BTreeMap<Hash, FileRecord>,
+}
+
+impl From<StateV3> for State {
+    fn from(state: StateV3) -> Self {
+        // The clients of this state sealed the files with a key of their own
+        // or with the built-in one, the key of their buckets is unknown
+        let buckets = state.buckets.into_iter().map(|(name, bucket)| {
+            let bucket = BucketState {
+                merkle_tree: bucket.merkle_tree,
+                bucket_id: bucket.bucket_id,
+                files: bucket.files,
+                key_check: None,
+            };
+            (name, bucket)
+        });
+
+        State {
+            selected: state.selected,
+            buckets: buckets.collect(),
+            kdf: state.kdf,
+        }
+    }
+}
+
+/// Buckets of the client as saved in BUCKETS_V2_FILE, before the key
+/// derivation parameters
+#[derive(serde::Deserialize)]
+struct StateV2 {
+    selected: String,
+    buckets: BTreeMap<String, BucketStateV3>,
+}
+
+impl From<StateV2> for State {
+    fn from(state: StateV2) -> Self {
+        // The clients of this state sealed the files with the built-in key
+        let buckets = state.buckets.into_iter().map(|(name, bucket)| {
+            let bucket = BucketState {
+                key_check: BucketState::builtin_key_check(&bucket.merkle_tree),
+                merkle_tree: bucket.merkle_tree,
+                bucket_id: bucket.bucket_id,
+                files: bucket.files,
+            };
+            (name, bucket)
+        });
+
+        State {
+            selected: state.selected,
+            buckets: buckets.collect(),
+            kdf: None,
+        }
+    }
+}
+
+/// Buckets of the client as saved in BUCKETS_V1_FILE, before the records
+/// kept the hash of the source files
+#[derive(serde::Deserialize)]
+struct StateV1 {
+    selected: String,
+    buckets: BTreeMap<String, BucketStateV1>,
+}
+
+/// State of a bucket as saved in BUCKETS_V1_FILE and LEGACY_STATE_FILE
+#[derive(serde::Deserialize)]
+struct BucketStateV1 {
     merkle_tree: merkle::Tree,
     bucket_id: [u8; 32],
+    files: BTreeMap<Hash, FileRecordV1>,
+}
+
+#[derive(serde::Deserialize)]
+struct FileRecordV1 {
+    file_name: String,
+    size: u64,
+    last_verified: Option<u64>,
+    verified: Option<bool>,
+}
+
+impl From<StateV1> for State {
+    fn from(state: StateV1) -> Self {
+        State {
+            selected: state.selected,
+            buckets: state
+                .buckets
+                .into_iter()
+                .map(|(name, bucket)| (name, bucket.into()))
+                .collect(),
+            kdf: None,
+        }
+    }
+}
+
+/// State of the single bucket saved in LEGACY_STATE_FILE, before the client
+/// kept the records of the uploaded files
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StateV0 {
+    merkle_tree: merkle::Tree,
+    bucket_id: [u8; 32],
+}
+
+impl From<StateV0> for BucketState {
+    fn from(state: StateV0) -> Self {
+        BucketState {
+            key_check: BucketState::builtin_key_check(&state.merkle_tree),
+            merkle_tree: state.merkle_tree,
+            bucket_id: state.bucket_id,
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+impl From<BucketStateV1> for BucketState {
+    fn from(bucket: BucketStateV1) -> Self {
+        let files = bucket.files.into_iter().map(|(hash, record)| {
+            let record = FileRecord {
+                file_name: record.file_name,
+                size: record.size,
+                source_hash: None,
+                last_verified: record.last_verified,
+                verified: record.verified,
+            };
+            (hash, record)
+        });
+
+        BucketState {
+            key_check: BucketState::builtin_key_check(&bucket.merkle_tree),
+            merkle_tree: bucket.merkle_tree,
+            bucket_id: bucket.bucket_id,
+            files: files.collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+    use tokio::task::JoinHandle;
+
+    /// The read and encrypt stages of an upload, and their output
+    struct Pipeline {
+        reader: JoinHandle<()>,
+        encryptor: JoinHandle<io::Result<(Hash, u64)>>,
+        rx: mpsc::Receiver<io::Result<Bytes>>,
+    }
+
+    /// Writes a source file of `size` bytes into `dir`
+    fn source_file(dir: &TempDir, size: usize) -> (String, Vec<u8>) {
+        let path = dir.path().join("source");
+        let content: Vec<u8> = (0..size).map(|i| (i % 253) as u8).collect();
+        fs::write(&path, &content).unwrap();
+        (path.to_str().unwrap().to_owned(), content)
+    }
+
+    /// Runs the read and encrypt stages of the upload pipeline on a file
+    fn pipeline(file_path: &str, aad: &[u8]) -> Pipeline {
+        let (plain_tx, plain_rx) = mpsc::channel(PIPELINE_DEPTH);
+        let (cipher_tx, cipher_rx) = mpsc::channel(PIPELINE_DEPTH);
+        let encryptor = Encryptor::new(&cipher::random_nonce(), aad.to_vec());
+
+        let reader = tokio::spawn(ClientApp::read_chunks(
+            file_path.to_owned(),
+            plain_tx,
+        ));
+        let encryptor = tokio::spawn(ClientApp::encrypt_chunks(
+            plain_rx, cipher_tx, encryptor,
+        ));
+        Pipeline {
+            reader,
+            encryptor,
+            rx: cipher_rx,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_order() {
+        let size = 3 * CHUNK_SIZE + 5;
+        let dir = TempDir::new("pipeline_order").expect("valid temp dir");
+        let (path, content) = source_file(&dir, size);
+        let aad = cipher::aad("bucket", "file");
+        let Pipeline {
+            reader,
+            encryptor,
+            mut rx,
+        } = pipeline(&path, &aad);
+
+        let mut sent = Vec::new();
+        while let Some(chunk) = rx.recv().await {
+            sent.extend_from_slice(&chunk.unwrap());
+        }
+        reader.await.unwrap();
+        let (hash, sent_size) = encryptor.await.unwrap().unwrap();
+
+        // The chunks are sent in the order of the file, as a whole sealed
+        // file whose hash and size are returned
+        assert_eq!(sent_size, sent.len() as u64);
+        assert_eq!(sent_size, cipher::encrypted_size(size as u64));
+        assert_eq!(hash, <Hash>::from(Sha256::digest(&sent)));
+        let mut decryptor = Decryptor::new(aad, false);
+        let mut opened = decryptor.update(&sent).unwrap();
+        opened.extend_from_slice(&decryptor.finish().unwrap());
+        assert!(opened == content);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_back_pressure() {
+        let chunks = 4 * PIPELINE_DEPTH;
+        let dir =
+            TempDir::new("pipeline_back_pressure").expect("valid temp dir");
+        let (path, _) = source_file(&dir, chunks * CHUNK_SIZE);
+        let (plain_tx, plain_rx) = mpsc::channel(PIPELINE_DEPTH);
+        let (cipher_tx, mut rx) = mpsc::channel(PIPELINE_DEPTH);
+        let plain = plain_tx.downgrade();
+        let encryptor = Encryptor::new(
+            &cipher::random_nonce(),
+            cipher::aad("bucket", "file"),
+        );
+        let reader =
+            tokio::spawn(ClientApp::read_chunks(path.clone(), plain_tx));
+        let encryptor = tokio::spawn(ClientApp::encrypt_chunks(
+            plain_rx, cipher_tx, encryptor,
+        ));
+
+        // A stalled upload stops the stages once their channels are full,
+        // instead of the file being read into memory
+        let plain_capacity = || plain.upgrade().map_or(0, |tx| tx.capacity());
+        while rx.len() < PIPELINE_DEPTH || plain_capacity() > 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!reader.is_finished());
+        assert!(!encryptor.is_finished());
+        assert_eq!(rx.len(), PIPELINE_DEPTH);
+        assert_eq!(plain_capacity(), 0);
+
+        // They resume as the upload goes on
+        let mut received = 0;
+        while let Some(chunk) = rx.recv().await {
+            chunk.unwrap();
+            received += 1;
+        }
+        reader.await.unwrap();
+        encryptor.await.unwrap().unwrap();
+        assert!(received > chunks);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_error() {
+        let (plain_tx, plain_rx) = mpsc::channel(PIPELINE_DEPTH);
+        let (cipher_tx, mut cipher_rx) = mpsc::channel(PIPELINE_DEPTH);
+        let encryptor = Encryptor::new(
+            &cipher::random_nonce(),
+            cipher::aad("bucket", "file"),
+        );
+        let encryptor = tokio::spawn(ClientApp::encrypt_chunks(
+            plain_rx, cipher_tx, encryptor,
+        ));
+
+        // A read failing partway is forwarded after the chunks before it, and
+        // the file is not completed
+        for _ in 0..2 {
+            plain_tx.send(Ok(vec![1; CHUNK_SIZE])).await.unwrap();
+        }
+        plain_tx
+            .send(Err(io::Error::other("disk failure")))
+            .await
+            .unwrap();
+        let res = encryptor.await.unwrap();
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::Other);
+        assert!(cipher_rx.recv().await.unwrap().is_ok());
+        assert!(cipher_rx.recv().await.unwrap().is_ok());
+        assert!(cipher_rx.recv().await.unwrap().is_err());
+        assert!(cipher_rx.recv().await.is_none());
+
+        // So is a file that cannot be opened
+        let missing = "/nonexistent/pipeline_source";
+        let Pipeline {
+            reader,
+            encryptor,
+            mut rx,
+        } = pipeline(missing, &cipher::aad("bucket", "file"));
+        let err = rx.recv().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(rx.recv().await.is_none());
+        reader.await.unwrap();
+        assert!(encryptor.await.unwrap().is_err());
+
+        // An upload cut partway stops the stages
+        let dir = TempDir::new("pipeline_cut").expect("valid temp dir");
+        let (path, _) = source_file(&dir, 4 * PIPELINE_DEPTH * CHUNK_SIZE);
+        let Pipeline {
+            reader,
+            encryptor,
+            mut rx,
+        } = pipeline(&path, &cipher::aad("bucket", "file"));
+        assert!(rx.recv().await.unwrap().is_ok());
+        drop(rx);
+        reader.await.unwrap();
+        let res = encryptor.await.unwrap();
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_read_baseline_state() {
+        let dir = TempDir::new("baseline_state").expect("valid temp dir");
+        let folder = dir.path().to_str().unwrap();
+        let legacy_file = folder.to_owned() + LEGACY_STATE_FILE;
+
+        // The state file of the first clients, without file records
+        let merkle_tree = merkle::Tree::build_from_leaves(vec![[1u8; 32]]);
+        let baseline = StateV0 {
+            merkle_tree,
+            bucket_id: [2u8; 32],
+        };
+        fs::write(&legacy_file, bincode::serialize(&baseline).unwrap())
+            .unwrap();
+
+        let mut state = ClientApp::read_from_file(folder).unwrap();
+        assert_eq!(state.selected, DEFAULT_BUCKET);
+        let bucket = state.buckets.remove(DEFAULT_BUCKET).unwrap();
+        assert_eq!(bucket.bucket_id, [2u8; 32]);
+        assert_eq!(bucket.merkle_tree.leaves(), vec![[1u8; 32]]);
+        assert!(bucket.files.is_empty());
+        // Its files were sealed with the built-in key
+        assert_eq!(bucket.key_check, Some(cipher::builtin_key_check()));
+
+        // A corrupted state file is an error
+        fs::write(&legacy_file, [0u8; 8]).unwrap();
+        assert!(matches!(
+            ClientApp::read_from_file(folder),
+            Err(Error::InvalidState(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bucket_key_check() {
+        let dir = TempDir::new("bucket_key_check").expect("valid temp dir");
+        let folder = dir.path().to_str().unwrap();
+        let mut client =
+            ClientApp::new("http://127.0.0.1:1", &[], folder).unwrap();
+
+        // A bucket without uploads, or sealed with the key of the client, is
+        // opened with it
+        assert!(matches!(client.builtin_key(), Ok(false)));
+        client.key_check = Some(cipher::client_key_check());
+        assert!(matches!(client.builtin_key(), Ok(false)));
+
+        // A bucket sealed with another key is refused before any request
+        client.key_check = Some([7u8; 32]);
+        assert!(matches!(client.builtin_key(), Err(Error::WrongKey(_))));
+        let err = client.upload_files(&vec![], false).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::WrongKey(_))));
+
+        // The key check is kept with the bucket
+        client.persist_state().unwrap();
+        client.create_bucket("other").unwrap();
+        assert_eq!(client.key_check, None);
+        client.select_bucket(DEFAULT_BUCKET).unwrap();
+        assert_eq!(client.key_check, Some([7u8; 32]));
+        let state = ClientApp::read_from_file(folder).unwrap();
+        assert_eq!(state.buckets["other"].key_check, None);
+        assert_eq!(state.buckets[DEFAULT_BUCKET].key_check, Some([7u8; 32]));
+    }
 }
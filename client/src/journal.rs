@@ -0,0 +1,232 @@
+// Upload journal module for the client
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::Mutex;
+
+use merkle::tree::Hash;
+use tracing::warn;
+
+/// Journal of the batch being uploaded to a bucket, followed by the bucket id
+const JOURNAL_FILE: &str = "/upload_journal_";
+
+/// A line of the journal
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line {
+    /// The first line, the upload session of the batch if any
+    Batch { session: Option<String> },
+    /// A file of the batch accepted by the server
+    File(JournaledFile),
+}
+
+/// A file of a batch accepted by the server
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct JournaledFile {
+    pub file_name: String,
+    /// Path of the source file
+    pub file_path: String,
+    #[serde(with = "hex")]
+    pub hash: Hash,
+    /// Hash of the content of the source file
+    #[serde(with = "hex")]
+    pub source_hash: Hash,
+    pub size: u64,
+}
+
+/// A batch the client did not complete, as found in the journal
+pub(crate) struct Interrupted {
+    pub session: Option<String>,
+    pub files: Vec<JournaledFile>,
+}
+
+/// Appends the files of a batch to the journal of its bucket as the server
+/// accepts them, so that a batch interrupted by a crash can be resumed
+pub(crate) struct UploadJournal {
+    path: String,
+    file: Mutex<File>,
+}
+
+fn journal_path(folder: &str, bucket_id: &str) -> String {
+    format!("{folder}{JOURNAL_FILE}{bucket_id}.jsonl")
+}
+
+/// Reads the batch interrupted while uploading to the bucket, if any
+///
+/// A line torn by the crash ends the journal.
+pub(crate) fn read(folder: &str, bucket_id: &str) -> Option<Interrupted> {
+    let path = journal_path(folder, bucket_id);
+    let file = File::open(&path).ok()?;
+    let mut lines = BufReader::new(file)
+        .lines()
+        .map_while(|line| serde_json::from_str::<Line>(&line.ok()?).ok());
+
+    let Some(Line::Batch { session }) = lines.next() else {
+        warn!(event = "invalid upload journal", path);
+        return None;
+    };
+    let files = lines
+        .filter_map(|line| match line {
+            Line::File(file) => Some(file),
+            Line::Batch { .. } => None,
+        })
+        .collect();
+
+    Some(Interrupted { session, files })
+}
+
+impl UploadJournal {
+    /// Starts the journal of a new batch, replacing the previous one
+    pub(crate) fn start(
+        folder: &str,
+        bucket_id: &str,
+        session: Option<&str>,
+    ) -> io::Result<Self> {
+        let path = journal_path(folder, bucket_id);
+        let journal = UploadJournal {
+            file: Mutex::new(File::create(&path)?),
+            path,
+        };
+        journal.append(&Line::Batch {
+            session: session.map(str::to_owned),
+        })?;
+        Ok(journal)
+    }
+
+    /// Reopens the journal of an interrupted batch to record its next files
+    pub(crate) fn resume(folder: &str, bucket_id: &str) -> io::Result<Self> {
+        let path = journal_path(folder, bucket_id);
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(UploadJournal {
+            file: Mutex::new(file),
+            path,
+        })
+    }
+
+    /// Records a file accepted by the server
+    ///
+    /// A failure is only logged, the file is then sent again if the batch is
+    /// resumed.
+    pub(crate) fn record(&self, file: JournaledFile) {
+        if let Err(err) = self.append(&Line::File(file)) {
+            warn!(
+                event = "failed to write upload journal",
+                path = self.path,
+                ?err
+            );
+        }
+    }
+
+    /// Removes the journal once its batch is completed or cannot be resumed
+    pub(crate) fn remove(&self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!(
+                event = "failed to remove upload journal",
+                path = self.path,
+                ?err
+            );
+        }
+    }
+
+    fn append(&self, line: &Line) -> io::Result<()> {
+        let mut line = serde_json::to_string(line)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().expect("journal lock");
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn file(name: &str, byte: u8) -> JournaledFile {
+        JournaledFile {
+            file_name: name.to_owned(),
+            file_path: format!("/src/{name}"),
+            hash: [byte; 32],
+            source_hash: [byte + 1; 32],
+            size: u64::from(byte),
+        }
+    }
+
+    fn names(interrupted: &Interrupted) -> Vec<&str> {
+        interrupted
+            .files
+            .iter()
+            .map(|file| file.file_name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_journal() {
+        let dir = TempDir::new("journal_round_trip").expect("valid temp dir");
+        let folder = dir.path().to_str().unwrap();
+        assert!(read(folder, "bucket").is_none());
+
+        let journal = UploadJournal::start(folder, "bucket", Some("s1"))
+            .expect("journal created");
+        journal.record(file("a", 1));
+        journal.record(file("b", 2));
+        drop(journal);
+
+        let interrupted = read(folder, "bucket").expect("journal");
+        assert_eq!(interrupted.session.as_deref(), Some("s1"));
+        assert_eq!(names(&interrupted), ["a", "b"]);
+        let b = &interrupted.files[1];
+        assert_eq!(b.file_path, "/src/b");
+        assert_eq!(b.hash, [2; 32]);
+        assert_eq!(b.source_hash, [3; 32]);
+        assert_eq!(b.size, 2);
+        // The journals are kept per bucket
+        assert!(read(folder, "other").is_none());
+
+        // A resumed batch appends to the journal
+        let journal = UploadJournal::resume(folder, "bucket").unwrap();
+        journal.record(file("c", 3));
+        let interrupted = read(folder, "bucket").expect("journal");
+        assert_eq!(names(&interrupted), ["a", "b", "c"]);
+
+        // A new batch replaces it
+        let journal = UploadJournal::start(folder, "bucket", None).unwrap();
+        let interrupted = read(folder, "bucket").expect("journal");
+        assert_eq!(interrupted.session, None);
+        assert!(interrupted.files.is_empty());
+
+        journal.remove();
+        assert!(read(folder, "bucket").is_none());
+    }
+
+    #[test]
+    fn test_corrupted_journal() {
+        let dir = TempDir::new("journal_corrupted").expect("valid temp dir");
+        let folder = dir.path().to_str().unwrap();
+        let path = journal_path(folder, "bucket");
+
+        // A line torn by a crash ends the journal
+        let journal = UploadJournal::start(folder, "bucket", None).unwrap();
+        journal.record(file("a", 1));
+        journal.record(file("b", 2));
+        let mut content = fs::read_to_string(&path).unwrap();
+        content.truncate(content.len() - 10);
+        fs::write(&path, &content).unwrap();
+        assert_eq!(names(&read(folder, "bucket").unwrap()), ["a"]);
+
+        // So does a line that is not one of the journal
+        content.insert_str(content.find('\n').unwrap() + 1, "garbage\n");
+        fs::write(&path, &content).unwrap();
+        assert!(read(folder, "bucket").unwrap().files.is_empty());
+
+        // A journal not starting with its batch is ignored
+        let file_line = serde_json::to_string(&Line::File(file("a", 1)));
+        fs::write(&path, file_line.unwrap() + "\n").unwrap();
+        assert!(read(folder, "bucket").is_none());
+        fs::write(&path, "").unwrap();
+        assert!(read(folder, "bucket").is_none());
+        fs::write(&path, [0xff, 0xfe, b'\n']).unwrap();
+        assert!(read(folder, "bucket").is_none());
+    }
+}
@@ -1,33 +1,165 @@
+mod attestation;
+mod body;
+mod capabilities;
+mod cipher;
 mod http_client;
+mod journal;
+mod logging;
+mod output;
+mod progress;
 mod prompt;
+mod report;
+mod retry;
+mod schedule;
+mod signing;
+mod telemetry;
+mod throttle;
+mod watch;
 
 use clap::Parser;
+use http_client::ClientApp;
+use logging::LogConfig;
+use output::{OutputFormat, Record};
+use retry::RetryPolicy;
+use schedule::Schedule;
 use std::path::Path;
-use tracing::info;
-use tracing_subscriber::fmt::Subscriber;
+use tracing::{error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
 
 #[derive(Parser)]
 struct Config {
     /// Storage server URL
     server_url: String,
+    /// Standby server URL used for downloads when the primary fails
+    /// (repeatable, tried in order)
+    #[arg(long = "standby")]
+    standby_urls: Vec<String>,
     client_dir: String,
     /// The path to the folder to upload
     source_dir: std::path::PathBuf,
+
+    /// Name of the bucket of the client to use, created if missing
+    /// (the last selected one by default)
+    #[arg(long)]
+    bucket: Option<String>,
+
+    /// Run unattended, uploading new files at this interval (e.g. `6h`)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    every: Option<std::time::Duration>,
+    /// Run unattended, uploading new files on this cron schedule in UTC
+    /// (e.g. `0 0 */6 * * *`)
+    #[arg(long, conflicts_with = "every", value_parser = Schedule::parse_cron)]
+    cron: Option<Schedule>,
+    /// Run unattended, uploading the files created or modified in the
+    /// source folder as they change
+    #[arg(long, conflicts_with_all = ["every", "cron"])]
+    watch: bool,
+    /// Upload the files of the source folder neither in the local Merkle
+    /// tree nor on the server, then exit
+    #[arg(long, conflicts_with_all = ["every", "cron", "watch"])]
+    sync: bool,
+    /// Quiet time after a change of the watched folder before its files are
+    /// uploaded, so that the files being written are sent once
+    #[arg(long, default_value = "2s", value_parser = humantime::parse_duration)]
+    watch_debounce: std::time::Duration,
+
+    /// Speak HTTP/2 to the servers without negotiation, multiplexing the
+    /// requests over one connection per server
+    #[arg(long)]
+    http2: bool,
+
+    /// Number of parts of a large file sent at once when the server
+    /// supports multi-part uploads, 1 sends its chunks one after the other
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    parallel_parts: u64,
+
+    /// Number of attempts of an upload, download or proof request failing
+    /// on a 5xx, 408 or 429 response or a connection error, 1 disables the
+    /// retries
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..))]
+    retry_attempts: u64,
+    /// Delay before the first retry of a request, doubled at each retry and
+    /// jittered
+    #[arg(long, default_value = "500ms", value_parser = humantime::parse_duration)]
+    retry_delay: std::time::Duration,
+    /// Bound of the delay between two attempts of a request
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    retry_max_delay: std::time::Duration,
+
+    /// Maximum byte rate of all the transfers together, uploads and
+    /// downloads (e.g. `5MB/s`, `512KiB/s`, `10M`), unbounded by default
+    #[arg(long, value_parser = throttle::parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Key encrypting the files, as 64 hex digits, instead of the key built
+    /// in the client
+    #[arg(long, env = "STORAGE_CLIENT_KEY", hide_env_values = true, value_parser = cipher::parse_key)]
+    key: Option<cipher::Key>,
+    /// File holding the key encrypting the files, as 32 raw bytes or 64 hex
+    /// digits
+    #[arg(long, conflicts_with = "key")]
+    key_file: Option<std::path::PathBuf>,
+    /// Ask on startup for a passphrase the key encrypting the files is
+    /// derived from with Argon2id
+    #[arg(long, conflicts_with_all = ["key", "key_file"])]
+    passphrase: bool,
+    /// Encrypt the files with the key built in the client, shared by every
+    /// client, when given no key
+    #[arg(long, conflicts_with_all = ["key", "key_file", "passphrase"])]
+    builtin_key: bool,
+    /// Accept the downloaded files encrypted without authentication by the
+    /// clients of before the files were sealed, not only those the records
+    /// of the client tell were uploaded so
+    #[arg(long)]
+    allow_legacy: bool,
+
+    /// Tag the uploaded files, to list them by tag on the server
+    /// (repeatable or comma separated)
+    #[arg(long = "tag", value_delimiter = ',')]
+    tags: Vec<String>,
+
+    /// Export the spans of the uploads and downloads to the OTLP collector
+    /// at this gRPC endpoint (e.g. `http://localhost:4317`), the servers
+    /// continue their traces
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Format of the results printed on stdout, `json` prints one JSON
+    /// object per line and moves the interactive prompt to stderr
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    #[command(flatten)]
+    logging: LogConfig,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Config::parse();
 
-    let s = Subscriber::builder()
-        .with_max_level(tracing::Level::INFO)
-        .with_writer(std::io::stderr);
+    // The logs on stderr are written above the progress bars
+    progress::init_progress(args.output == OutputFormat::Text);
+    let (logs, _log_guard) =
+        logging::layer(&args.logging).expect("log file is writable");
+
+    let provider = args.otlp_endpoint.as_deref().map(|endpoint| {
+        telemetry::tracer_provider(endpoint).expect("valid OTLP endpoint")
+    });
+    let otel = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(telemetry::tracer(provider))
+    });
 
     tracing::subscriber::set_global_default(
-        s.json().flatten_event(true).finish(),
+        tracing_subscriber::registry()
+            .with(logging::filter(&args.logging))
+            .with(logs)
+            .with(otel),
     )
     .expect("valid default subscriber");
 
+    http_client::init_http_client(args.http2);
+    throttle::init_rate_limit(args.limit_rate);
+
     let url = args.server_url;
     let src_folder: &Path = args.source_dir.as_ref();
     let client_dir = args.client_dir.as_str();
@@ -36,5 +168,109 @@ async fn main() {
         &src_folder, url
     );
 
-    prompt::run_loop(url, src_folder, client_dir).await;
+    let client =
+        match ClientApp::new(url.as_str(), &args.standby_urls, client_dir) {
+            Ok(client) => client,
+            Err(err) => {
+                error!(event = "failed to load the client state", ?err);
+                args.output.print(&Record::error("state", &*err));
+                std::process::exit(1);
+            }
+        };
+    let mut client = client
+        .with_upload_tags(args.tags)
+        .with_parallel_parts(args.parallel_parts as usize)
+        .with_retry_policy(RetryPolicy {
+            attempts: args.retry_attempts as usize,
+            base_delay: args.retry_delay,
+            max_delay: args.retry_max_delay,
+        })
+        .with_allow_legacy(args.allow_legacy);
+    if let Some(bucket) = &args.bucket {
+        client = client.with_bucket(bucket);
+    }
+
+    // The files are encrypted with the key of the user, the built-in key
+    // only if asked for
+    let key: Result<_, Box<dyn std::error::Error>> =
+        if let Some(key_file) = &args.key_file {
+            cipher::read_key_file(key_file).map(Some).map_err(|err| {
+                format!("failed to read {}: {err}", key_file.display()).into()
+            })
+        } else if args.passphrase {
+            prompt::prompt_passphrase(args.output)
+                .map_err(Into::into)
+                .and_then(|passphrase| client.passphrase_key(&passphrase))
+                .map(Some)
+        } else if args.key.is_some() || args.builtin_key {
+            Ok(args.key)
+        } else {
+            Err(
+                "no key given, pass --key-file, --key, STORAGE_CLIENT_KEY or \
+                 --passphrase, or --builtin-key to use the key shared by \
+                 every client"
+                    .into(),
+            )
+        };
+    match key {
+        Ok(Some(key)) => cipher::init_key(key),
+        Ok(None) => warn!(event = "files use the built-in key"),
+        Err(err) => {
+            error!(event = "failed to get the key", ?err);
+            args.output.print(&Record::error("key", &*err));
+            std::process::exit(1);
+        }
+    }
+
+    let mut failed = false;
+    let schedule = args.every.map(Schedule::Every).or(args.cron);
+    match schedule {
+        _ if args.sync => {
+            client.fetch_capabilities().await;
+
+            let files = prompt::read_source_files(src_folder);
+            match client.sync_files(&files).await {
+                Ok(summary) => args.output.print_upload(&summary),
+                Err(err) => {
+                    error!(event = "sync failed", ?err);
+                    args.output.print(&Record::error("sync", &*err));
+                    failed = true;
+                }
+            }
+        }
+        _ if args.watch => {
+            client.fetch_capabilities().await;
+
+            watch::run_watch(
+                client,
+                src_folder,
+                args.watch_debounce,
+                args.output,
+            )
+            .await;
+        }
+        Some(schedule) => {
+            client.fetch_capabilities().await;
+
+            schedule::run_schedule(
+                client,
+                schedule,
+                src_folder,
+                client_dir,
+                args.output,
+            )
+            .await;
+        }
+        None => {
+            prompt::run_loop(client, src_folder, client_dir, args.output).await;
+        }
+    }
+
+    // Flush the spans still batched
+    if let Some(provider) = provider {
+        let _ = provider.shutdown();
+    }
+    if failed {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,360 @@
+// Output module for the client
+
+use clap::ValueEnum;
+use indicatif::HumanBytes;
+use tracing::error;
+
+use crate::http_client::{BucketInfo, FileDownload, RemoteFile, UploadSummary};
+
+/// Format of the results printed on stdout
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Lines for humans, below the interactive prompt
+    #[default]
+    Text,
+    /// One JSON object per line, tagged by its `type`, the interactive
+    /// prompt is moved to stderr
+    Json,
+}
+
+/// Outcome of a file of a command
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Status {
+    Ok,
+    Failed,
+}
+
+/// A result of a command
+#[derive(serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Record {
+    BucketId {
+        name: String,
+        bucket_id: String,
+    },
+    /// A bucket of the client
+    Bucket {
+        name: String,
+        bucket_id: String,
+        leaves_count: usize,
+        root: Option<String>,
+        /// Whether the commands apply to this bucket
+        selected: bool,
+    },
+    /// A file of the source folder
+    LocalFile {
+        index: usize,
+        file_name: String,
+    },
+    /// A file of the bucket on the server
+    RemoteFile {
+        index: usize,
+        filename: String,
+        hash: String,
+        size: u64,
+    },
+    Root {
+        server_root: Option<String>,
+        local_root: Option<String>,
+        matches: bool,
+    },
+    Retention {
+        version: u64,
+        retained: bool,
+    },
+    Proofs {
+        valid: usize,
+        invalid: usize,
+    },
+    /// A file of an upload batch
+    Upload {
+        file_name: String,
+        status: Status,
+        hash: Option<String>,
+        /// Leaf index of the file after the batch
+        index: Option<usize>,
+        size: Option<u64>,
+        error: Option<String>,
+    },
+    /// The end of an upload batch
+    UploadSummary {
+        uploaded: usize,
+        failed: usize,
+        root: Option<String>,
+        /// Number of bytes sent
+        bytes: u64,
+        elapsed_ms: u64,
+    },
+    Download {
+        index: usize,
+        status: Status,
+        hash: Option<String>,
+        /// Path of the decrypted file
+        path: Option<String>,
+        error: Option<String>,
+    },
+    /// A file of the downloads folder
+    DownloadedFile {
+        path: String,
+    },
+    Report {
+        path: String,
+    },
+    /// A command that failed as a whole
+    Error {
+        command: &'static str,
+        error: String,
+    },
+}
+
+impl Record {
+    pub(crate) fn bucket(bucket: BucketInfo) -> Self {
+        Record::Bucket {
+            name: bucket.name,
+            bucket_id: bucket.bucket_id,
+            leaves_count: bucket.leaves_count,
+            root: bucket.root.map(hex::encode),
+            selected: bucket.selected,
+        }
+    }
+
+    pub(crate) fn remote_file(file: RemoteFile) -> Self {
+        Record::RemoteFile {
+            index: file.index,
+            filename: file.filename,
+            hash: file.hash,
+            size: file.size,
+        }
+    }
+
+    pub(crate) fn download(
+        index: usize,
+        res: &Result<FileDownload, Box<dyn std::error::Error>>,
+    ) -> Self {
+        match res {
+            Ok(file) => Record::Download {
+                index,
+                status: Status::Ok,
+                hash: Some(hex::encode(file.hash)),
+                path: Some(file.path.clone()),
+                error: None,
+            },
+            Err(err) => Record::Download {
+                index,
+                status: Status::Failed,
+                hash: None,
+                path: None,
+                error: Some(err.to_string()),
+            },
+        }
+    }
+
+    pub(crate) fn error(
+        command: &'static str,
+        err: &dyn std::error::Error,
+    ) -> Self {
+        Record::Error {
+            command,
+            error: err.to_string(),
+        }
+    }
+
+    /// Returns the line printed in the text format, if any; uploads are
+    /// printed as a table and errors are only logged
+    fn text(&self) -> Option<String> {
+        let text = match self {
+            Record::BucketId { name, bucket_id } => {
+                format!("My bucket_id: {bucket_id} ({name})")
+            }
+            Record::Bucket {
+                name,
+                bucket_id,
+                leaves_count,
+                selected,
+                ..
+            } => format!(
+                "{}{name}: {bucket_id} ({leaves_count} files)",
+                if *selected { "* " } else { "  " }
+            ),
+            Record::LocalFile { index, file_name } => {
+                format!("{index}: {file_name:?}")
+            }
+            Record::RemoteFile {
+                index,
+                filename,
+                hash,
+                size,
+            } => format!("{index}: {filename} ({size} bytes) {hash}"),
+            Record::Root {
+                server_root,
+                matches,
+                ..
+            } => format!(
+                "server root: {} ({})",
+                server_root.as_deref().unwrap_or_default(),
+                if *matches { "matches" } else { "differs" }
+            ),
+            Record::Retention { version, retained } => format!(
+                "version {version}: {}",
+                if *retained {
+                    "files retained"
+                } else {
+                    "files missing"
+                }
+            ),
+            Record::Proofs { valid, invalid } => {
+                format!("valid proofs: {valid}, invalid: {invalid}")
+            }
+            Record::DownloadedFile { path } => {
+                format!("downloaded file: {path}")
+            }
+            Record::Report { path } => format!("report saved: {path}"),
+            Record::Download {
+                index,
+                path: Some(path),
+                ..
+            } => format!("downloaded file {index}: {path}"),
+            Record::Download {
+                index,
+                error: Some(error),
+                ..
+            } => format!("failed to download file {index}: {error}"),
+            Record::Upload { .. }
+            | Record::UploadSummary { .. }
+            | Record::Download { .. }
+            | Record::Error { .. } => return None,
+        };
+        Some(text)
+    }
+}
+
+impl OutputFormat {
+    /// Prints a result on stdout
+    pub(crate) fn print(&self, record: &Record) {
+        match self {
+            OutputFormat::Text => {
+                if let Some(text) = record.text() {
+                    println!("{text}");
+                }
+            }
+            OutputFormat::Json => match serde_json::to_string(record) {
+                Ok(line) => println!("{line}"),
+                Err(err) => error!(event = "failed to print result", ?err),
+            },
+        }
+    }
+
+    /// Prints the outcome of each file of an upload batch, then the root of
+    /// the bucket, as a table in the text format
+    pub(crate) fn print_upload(&self, summary: &UploadSummary) {
+        if *self == OutputFormat::Text {
+            print!("{}", upload_table(summary));
+            return;
+        }
+
+        for file in &summary.files {
+            let record = match &file.result {
+                Ok((hash, size)) => Record::Upload {
+                    file_name: file.file_name.clone(),
+                    status: Status::Ok,
+                    hash: Some(hex::encode(hash)),
+                    index: file.index,
+                    size: Some(*size),
+                    error: None,
+                },
+                Err(error) => Record::Upload {
+                    file_name: file.file_name.clone(),
+                    status: Status::Failed,
+                    hash: None,
+                    index: None,
+                    size: None,
+                    error: Some(error.clone()),
+                },
+            };
+            self.print(&record);
+        }
+        self.print(&Record::UploadSummary {
+            uploaded: summary.uploaded,
+            failed: summary.failed,
+            root: summary.root.map(hex::encode),
+            bytes: summary.bytes,
+            elapsed_ms: summary.elapsed.as_millis() as u64,
+        });
+    }
+
+    /// Asks a question of the interactive prompt, on stderr in the JSON
+    /// format so that stdout only carries the results
+    pub(crate) fn ask<'a>(
+        &self,
+        question: impl Into<requestty::Question<'a>>,
+    ) -> requestty::Result<requestty::Answer> {
+        use requestty::prompt::{backend, events};
+
+        match self {
+            OutputFormat::Text => requestty::prompt_one(question),
+            OutputFormat::Json => {
+                let mut stderr = backend::get_backend(std::io::stderr());
+                let mut events = events::get_events();
+                requestty::prompt_one_with(question, &mut stderr, &mut events)
+            }
+        }
+    }
+}
+
+/// Formats the outcome of each file of an upload batch, the failed ones
+/// last, followed by the totals of the batch
+fn upload_table(summary: &UploadSummary) -> String {
+    let mut files: Vec<_> = summary.files.iter().collect();
+    files.sort_by_key(|file| file.result.is_err());
+
+    let width = files
+        .iter()
+        .map(|file| file.file_name.chars().count())
+        .chain(["FILE".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut table = String::new();
+    if !files.is_empty() {
+        table.push_str(&format!(
+            "{:<width$}  {:<6}  {:>10}  {:>5}  HASH / ERROR\n",
+            "FILE", "STATUS", "SIZE", "INDEX"
+        ));
+    }
+    for file in files {
+        let row = match &file.result {
+            Ok((hash, size)) => format!(
+                "{:<width$}  {:<6}  {:>10}  {:>5}  {}",
+                file.file_name,
+                "ok",
+                HumanBytes(*size).to_string(),
+                file.index.map(|i| i.to_string()).unwrap_or_default(),
+                hex::encode(hash)
+            ),
+            Err(error) => format!(
+                "{:<width$}  {:<6}  {:>10}  {:>5}  {error}",
+                file.file_name, "failed", "", ""
+            ),
+        };
+        table.push_str(&row);
+        table.push('\n');
+    }
+
+    let secs = summary.elapsed.as_secs_f64();
+    let rate = if secs > 0.0 {
+        summary.bytes as f64 / secs
+    } else {
+        0.0
+    };
+    table.push_str(&format!(
+        "uploaded: {}, failed: {}, sent {} in {:.1}s ({}/s), root: {}\n",
+        summary.uploaded,
+        summary.failed,
+        HumanBytes(summary.bytes),
+        secs,
+        HumanBytes(rate as u64),
+        summary.root.map(hex::encode).unwrap_or_default()
+    ));
+    table
+}
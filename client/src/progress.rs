@@ -0,0 +1,193 @@
+// Progress bars of the transfers
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use indicatif::{
+    MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+use tokio_stream::{Stream, StreamExt};
+
+/// Bars of the transfers in progress, drawn on stderr
+static BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+tokio::task_local! {
+    /// Progress of the file transferred by the current task
+    static FILE: FileProgress;
+}
+
+/// Draws the progress bars on stderr if `enabled` and stderr is a terminal
+///
+/// Must be called before the first transfer to take effect.
+pub(crate) fn init_progress(enabled: bool) {
+    let target = if enabled {
+        ProgressDrawTarget::stderr()
+    } else {
+        ProgressDrawTarget::hidden()
+    };
+    let _ = BARS.set(MultiProgress::with_draw_target(target));
+}
+
+fn bars() -> &'static MultiProgress {
+    BARS.get_or_init(|| {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    })
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("valid template")
+        .progress_chars("=> ")
+}
+
+/// Writes the logs on stderr above the progress bars
+pub(crate) struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        bars().suspend(|| io::stderr().write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Aggregate progress of a batch of transfers
+pub(crate) struct Batch {
+    total: ProgressBar,
+    started: Instant,
+}
+
+impl Batch {
+    /// Starts a batch of `files` transfers of `bytes` in total
+    pub(crate) fn new(action: &str, files: usize, bytes: u64) -> Self {
+        let total = bars().add(ProgressBar::new(bytes));
+        total.set_style(style(
+            "{prefix:.bold} [{bar:30}] {bytes}/{total_bytes} \
+             {bytes_per_sec} eta {eta}",
+        ));
+        total.set_prefix(format!("{action} {files} files"));
+
+        Batch {
+            total,
+            started: Instant::now(),
+        }
+    }
+
+    /// Adds the bar of a file of `size` bytes
+    pub(crate) fn file(&self, file_name: &str, size: u64) -> FileProgress {
+        let bar = bars().insert_before(&self.total, ProgressBar::new(size));
+        bar.set_style(file_style());
+        bar.set_message(file_name.to_owned());
+
+        FileProgress {
+            bar,
+            total: Some(self.total.clone()),
+        }
+    }
+
+    /// Clears the bars of the batch, returns the number of bytes it
+    /// transferred and how long it took
+    pub(crate) fn finish(self) -> (u64, Duration) {
+        self.total.finish_and_clear();
+        (self.total.position(), self.started.elapsed())
+    }
+}
+
+fn file_style() -> ProgressStyle {
+    style(
+        "{msg:24!} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} eta {eta}",
+    )
+}
+
+/// Progress of the transfer of a file
+#[derive(Clone)]
+pub(crate) struct FileProgress {
+    bar: ProgressBar,
+    /// Aggregate bar of the batch of the file
+    total: Option<ProgressBar>,
+}
+
+impl FileProgress {
+    /// Adds the bar of a file transferred on its own, of a size known once
+    /// the transfer starts
+    pub(crate) fn single(file_name: &str) -> Self {
+        let bar = bars().add(ProgressBar::new(0));
+        bar.set_style(file_style());
+        bar.set_message(file_name.to_owned());
+
+        FileProgress { bar, total: None }
+    }
+
+    fn hidden() -> Self {
+        FileProgress {
+            bar: ProgressBar::hidden(),
+            total: None,
+        }
+    }
+
+    /// Runs the transfer of the file, reporting its bytes to this bar
+    pub(crate) async fn scope<F: Future>(self, transfer: F) -> F::Output {
+        FILE.scope(self, transfer).await
+    }
+
+    /// Counts `bytes` transferred
+    pub(crate) fn advance(&self, bytes: usize) {
+        self.bar.inc(bytes as u64);
+        if let Some(total) = &self.total {
+            total.inc(bytes as u64);
+        }
+    }
+
+    /// Starts the transfer over, after a failed attempt
+    pub(crate) fn restart(&self) {
+        if let Some(total) = &self.total {
+            total.set_position(
+                total.position().saturating_sub(self.bar.position()),
+            );
+        }
+        self.bar.reset();
+    }
+
+    /// Clears the bar once the transfer is over
+    pub(crate) fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Returns the progress of the file transferred by the current task
+pub(crate) fn current() -> FileProgress {
+    FILE.try_with(FileProgress::clone)
+        .unwrap_or_else(|_| FileProgress::hidden())
+}
+
+/// Counts `bytes` transferred by the current task
+pub(crate) fn advance(bytes: usize) {
+    current().advance(bytes);
+}
+
+/// Sets the size of the file transferred by the current task
+pub(crate) fn set_length(bytes: u64) {
+    current().bar.set_length(bytes);
+}
+
+/// Counts the chunks of a body stream sent for the current task
+pub(crate) fn stream<S, E>(
+    chunks: S,
+) -> impl Stream<Item = Result<Bytes, E>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Send + 'static,
+{
+    let progress = current();
+    chunks.map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            progress.advance(chunk.len());
+        }
+        chunk
+    })
+}
@@ -1,110 +1,364 @@
 // Prompt module for the client
 
 use crate::http_client::{ClientApp, LOCAL_REPO};
+use crate::output::{OutputFormat, Record};
+use crate::report::{self, ReportFormat, REPORT_FILE};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use requestty::Question;
 use std::{ffi::OsString, fs, io, path::Path};
 
-use tracing::error;
+use tracing::{error, warn};
+
+/// Gitignore-style file of the source folder listing the files not to
+/// upload
+pub(crate) const IGNORE_FILE: &str = ".storageignore";
 
 pub(crate) enum Commands {
     BucketID,
+    ListBuckets,
+    CreateBucket(String),
+    SelectBucket(String),
     ListFiles,
+    ListRemoteFiles,
+    CheckRoot,
+    VerifyRetention(u64),
+    VerifyAllProofs,
+    RestoreTree,
     UploadAll,
+    Sync,
     DownloadFile(usize),
+    DeleteRemoteFile(usize),
     ListDownloadedFiles,
+    Report(ReportFormat),
     Exit,
 }
 
-fn prompt() -> requestty::Result<Commands> {
-    let answer = requestty::prompt_one(
+fn prompt(
+    output: OutputFormat,
+    client: &ClientApp,
+) -> requestty::Result<Commands> {
+    let answer = output.ask(
         Question::select("command")
-            .message("Client")
+            .message(format!("Client ({})", client.bucket_name()))
             .choice("My Bucket ID")
+            .choice("List buckets")
+            .choice("Create bucket")
+            .choice("Select bucket")
             .choice("List available files")
+            .choice("List remote files")
+            .choice("Check bucket root")
+            .choice("Verify the files of an older version are kept")
+            .choice("Verify all proofs")
+            .choice("Restore Merkle tree from server")
             .choice("Upload all files")
+            .choice("Sync new or changed files")
             .choice("Download file by index")
+            .choice("Delete remote file")
             .choice("List downloaded files")
+            .choice("Export bucket report")
             .choice("Exit")
             .build(),
     )?;
 
     match answer.as_list_item().unwrap().index {
         0 => Ok(Commands::BucketID),
-        1 => Ok(Commands::ListFiles),
-        2 => Ok(Commands::UploadAll),
+        1 => Ok(Commands::ListBuckets),
+        2 => {
+            // Ask for the name after selecting "Create bucket"
+            let name = output.ask(
+                Question::input("name")
+                    .message("Name of the new bucket")
+                    .validate(|name, _| {
+                        if name.trim().is_empty() {
+                            Err("Name must not be empty".into())
+                        } else {
+                            Ok(())
+                        }
+                    })
+                    .build(),
+            )?;
+            Ok(Commands::CreateBucket(
+                name.as_string().unwrap_or_default().to_owned(),
+            ))
+        }
         3 => {
+            // Ask for the bucket after selecting "Select bucket"
+            let names: Vec<String> =
+                client.buckets().into_iter().map(|b| b.name).collect();
+            let answer = output.ask(
+                Question::select("bucket")
+                    .message("Bucket")
+                    .choices(names.iter().cloned())
+                    .build(),
+            )?;
+            let index = answer.as_list_item().unwrap().index;
+            Ok(Commands::SelectBucket(names[index].clone()))
+        }
+        4 => Ok(Commands::ListFiles),
+        5 => Ok(Commands::ListRemoteFiles),
+        6 => Ok(Commands::CheckRoot),
+        7 => {
+            // Ask for the older version whose files must be kept
+            prompt_index(output, "Enter the older version of the bucket")
+                .map(|version| Commands::VerifyRetention(version as u64))
+        }
+        8 => Ok(Commands::VerifyAllProofs),
+        9 => Ok(Commands::RestoreTree),
+        10 => Ok(Commands::UploadAll),
+        11 => Ok(Commands::Sync),
+        12 => {
             // Ask for the file index after selecting "Download file by index"
-            let index_question = Question::int("index")
-                .message("Enter the file index to download")
-                .validate(|index, _| {
-                    if index >= 0 {
-                        Ok(())
-                    } else {
-                        Err("Index must be a non-negative number".into())
-                    }
-                })
-                .build();
-
-            let index_answer = requestty::prompt_one(index_question)?;
+            prompt_index(output, "Enter the file index to download")
+                .map(Commands::DownloadFile)
+        }
+        13 => {
+            // Ask for the file index after selecting "Delete remote file"
+            prompt_index(output, "Enter the file index to delete")
+                .map(Commands::DeleteRemoteFile)
+        }
+        14 => Ok(Commands::ListDownloadedFiles),
+        15 => {
+            // Ask for the report format after selecting "Export bucket report"
+            let format_answer = output.ask(
+                Question::select("format")
+                    .message("Report format")
+                    .choice("CSV")
+                    .choice("JSON")
+                    .build(),
+            )?;
 
-            if let Some(index) = index_answer.as_int() {
-                Ok(Commands::DownloadFile(index as usize))
-            } else {
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Invalid index",
-                )
-                .into())
+            match format_answer.as_list_item().unwrap().index {
+                0 => Ok(Commands::Report(ReportFormat::Csv)),
+                _ => Ok(Commands::Report(ReportFormat::Json)),
             }
         }
-        4 => Ok(Commands::ListDownloadedFiles),
-        5 => Ok(Commands::Exit),
+        16 => Ok(Commands::Exit),
         _ => unreachable!(),
     }
 }
 
+/// Asks for a file index
+fn prompt_index(
+    output: OutputFormat,
+    message: &str,
+) -> requestty::Result<usize> {
+    let index_question = Question::int("index")
+        .message(message)
+        .validate(|index, _| {
+            if index >= 0 {
+                Ok(())
+            } else {
+                Err("Index must be a non-negative number".into())
+            }
+        })
+        .build();
+
+    let index_answer = output.ask(index_question)?;
+
+    if let Some(index) = index_answer.as_int() {
+        Ok(index as usize)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid index").into())
+    }
+}
+
+/// Asks for the passphrase the key of the files is derived from
+pub(crate) fn prompt_passphrase(
+    output: OutputFormat,
+) -> requestty::Result<String> {
+    let passphrase_question = Question::password("passphrase")
+        .message("Passphrase of the encryption key")
+        .mask('*')
+        .validate(|passphrase, _| {
+            if passphrase.is_empty() {
+                Err("Passphrase must not be empty".into())
+            } else {
+                Ok(())
+            }
+        })
+        .build();
+
+    let passphrase_answer = output.ask(passphrase_question)?;
+
+    match passphrase_answer.as_string() {
+        Some(passphrase) => Ok(passphrase.to_owned()),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid passphrase",
+        )
+        .into()),
+    }
+}
+
 pub(crate) async fn run_loop(
-    server_url: String,
+    mut client: ClientApp,
     src_folder: &Path,
     client_dir: &str,
+    output: OutputFormat,
 ) {
-    let mut client = ClientApp::new(server_url.as_str(), client_dir);
+    client.fetch_capabilities().await;
 
     loop {
-        match prompt().unwrap() {
+        match prompt(output, &client).unwrap() {
             // List all files in the SRC folder
             Commands::BucketID => {
-                println!("My bucket_id: {}", client.bucket_id());
+                output.print(&Record::BucketId {
+                    name: client.bucket_name().to_owned(),
+                    bucket_id: client.bucket_id(),
+                });
+            }
+            // List the buckets of the client
+            Commands::ListBuckets => {
+                for bucket in client.buckets() {
+                    output.print(&Record::bucket(bucket));
+                }
+            }
+            // Create a bucket and select it
+            Commands::CreateBucket(name) => {
+                if let Err(err) = client.create_bucket(&name) {
+                    error!("Error creating bucket: {:?}", err);
+                    output.print(&Record::error("create_bucket", &*err));
+                }
+            }
+            // Select the bucket of the following commands
+            Commands::SelectBucket(name) => {
+                if let Err(err) = client.select_bucket(&name) {
+                    error!("Error selecting bucket: {:?}", err);
+                    output.print(&Record::error("select_bucket", &*err));
+                }
             }
 
             // List all files in the SRC folder
             Commands::ListFiles => {
-                let files = read_files(src_folder);
+                let files = read_source_files(src_folder);
                 for (index, file) in files.iter().enumerate() {
-                    println!("{}: {:?}", index, file.0);
+                    output.print(&Record::LocalFile {
+                        index,
+                        file_name: file.0.to_string_lossy().into_owned(),
+                    });
+                }
+            }
+            // List all files of the bucket on the server
+            Commands::ListRemoteFiles => match client.list_remote_files().await
+            {
+                Ok(files) => {
+                    for file in files {
+                        output.print(&Record::remote_file(file));
+                    }
+                }
+                Err(err) => {
+                    error!("Error listing remote files: {:?}", err);
+                    output.print(&Record::error("list_remote_files", &*err));
+                }
+            },
+            // Compare the local root with the root on the server
+            Commands::CheckRoot => match client.check_root().await {
+                Ok((server_root, matches)) => {
+                    output.print(&Record::Root {
+                        server_root: server_root.map(hex::encode),
+                        local_root: client.root().map(hex::encode),
+                        matches,
+                    });
+                }
+                Err(err) => {
+                    error!("Error checking root: {:?}", err);
+                    output.print(&Record::error("check_root", &*err));
+                }
+            },
+            // Verify that the server kept the files of an older version
+            Commands::VerifyRetention(from) => {
+                match client.verify_retention(from, None).await {
+                    Ok(retained) => output.print(&Record::Retention {
+                        version: from,
+                        retained,
+                    }),
+                    Err(err) => {
+                        error!("Error verifying retention: {:?}", err);
+                        output.print(&Record::error("verify_retention", &*err));
+                    }
+                }
+            }
+            // Verify the proofs of all files without downloading them
+            Commands::VerifyAllProofs => {
+                match client.verify_all_proofs().await {
+                    Ok((valid, invalid)) => {
+                        output.print(&Record::Proofs { valid, invalid })
+                    }
+                    Err(err) => {
+                        error!("Error verifying proofs: {:?}", err);
+                        output
+                            .print(&Record::error("verify_all_proofs", &*err));
+                    }
+                }
+            }
+            // Restore the local Merkle tree from the server
+            Commands::RestoreTree => {
+                if let Err(err) = client.restore_tree().await {
+                    error!("Error restoring tree: {:?}", err);
+                    output.print(&Record::error("restore_tree", &*err));
                 }
             }
             // Upload all files from SRC folder to the server
             Commands::UploadAll => {
-                let files = read_files(src_folder);
-                if let Err(err) = client.upload_files(&files).await {
-                    error!("Error uploading: {:?}", err);
+                let files = read_source_files(src_folder);
+                match client.upload_files(&files, true).await {
+                    Ok(summary) => output.print_upload(&summary),
+                    Err(err) => {
+                        error!("Error uploading: {:?}", err);
+                        output.print(&Record::error("upload", &*err));
+                    }
+                }
+            }
+            // Upload the files neither in the local tree nor on the server
+            Commands::Sync => {
+                let files = read_source_files(src_folder);
+                match client.sync_files(&files).await {
+                    Ok(summary) => output.print_upload(&summary),
+                    Err(err) => {
+                        error!("Error syncing: {:?}", err);
+                        output.print(&Record::error("sync", &*err));
+                    }
                 }
             }
             // Download a file by index
             Commands::DownloadFile(file_index) => {
-                if let Err(err) =
-                    client.download_and_verify(&file_index.to_string()).await
-                {
+                let res =
+                    client.download_and_verify(&file_index.to_string()).await;
+                if let Err(err) = &res {
                     error!("Error downloading file: {:?}", err);
                 }
+                output.print(&Record::download(file_index, &res));
+            }
+            // Delete a file from the bucket on the server
+            Commands::DeleteRemoteFile(file_index) => {
+                if let Err(err) = client.delete_remote_file(file_index).await {
+                    error!("Error deleting file: {:?}", err);
+                    output.print(&Record::error("delete_remote_file", &*err));
+                }
             }
             // List all files in the download folder
             Commands::ListDownloadedFiles => {
                 let local_repo = client_dir.to_owned() + LOCAL_REPO;
                 let files = read_files(&local_repo);
-                for (_, file) in files.iter() {
-                    println!("downloaded file: {}", file);
+                for (_, file) in files {
+                    output.print(&Record::DownloadedFile { path: file });
+                }
+            }
+
+            // Export a report of every leaf in the bucket
+            Commands::Report(format) => {
+                let path = format!(
+                    "{}{}.{}",
+                    client_dir,
+                    REPORT_FILE,
+                    format.extension()
+                );
+                match report::write_report(&client.report(), format, &path) {
+                    Ok(()) => output.print(&Record::Report { path }),
+                    Err(err) => {
+                        error!("Error writing report: {:?}", err);
+                        output.print(&Record::error("report", &err));
+                    }
                 }
             }
 
@@ -115,7 +369,9 @@ pub(crate) async fn run_loop(
     }
 }
 
-fn read_files<P: AsRef<Path>>(src_folder: P) -> Vec<(OsString, String)> {
+pub(crate) fn read_files<P: AsRef<Path>>(
+    src_folder: P,
+) -> Vec<(OsString, String)> {
     if let Ok(dir) = fs::read_dir(src_folder) {
         dir.filter_map(|entry| {
             entry.ok().and_then(|e| {
@@ -131,3 +387,39 @@ fn read_files<P: AsRef<Path>>(src_folder: P) -> Vec<(OsString, String)> {
         Vec::new()
     }
 }
+
+/// Lists the files of the source folder to upload, leaving out those
+/// matched by its IGNORE_FILE
+pub(crate) fn read_source_files<P: AsRef<Path>>(
+    src_folder: P,
+) -> Vec<(OsString, String)> {
+    let src_folder = src_folder.as_ref();
+    let ignore = read_ignore_file(src_folder);
+
+    read_files(src_folder)
+        .into_iter()
+        .filter(|(_, file_path)| !ignore.matched(file_path, false).is_ignore())
+        .collect()
+}
+
+/// Reads the IGNORE_FILE of the source folder, if any
+///
+/// Invalid patterns are skipped with a warning, the valid ones still apply.
+fn read_ignore_file(src_folder: &Path) -> Gitignore {
+    let path = src_folder.join(IGNORE_FILE);
+    let mut builder = GitignoreBuilder::new(src_folder);
+    if let Some(err) = builder.add(&path) {
+        let missing = err
+            .io_error()
+            .is_some_and(|err| err.kind() == io::ErrorKind::NotFound);
+        if missing {
+            return Gitignore::empty();
+        }
+        warn!(event = "invalid ignore file", path = %path.display(), %err);
+    }
+
+    builder.build().unwrap_or_else(|err| {
+        warn!(event = "invalid ignore file", path = %path.display(), %err);
+        Gitignore::empty()
+    })
+}
@@ -0,0 +1,74 @@
+// Bucket report module for the client
+
+use std::{fs, io, path::Path};
+
+pub(crate) const REPORT_FILE: &str = "/bucket_report";
+
+#[derive(Clone, Copy)]
+pub(crate) enum ReportFormat {
+    Csv,
+    Json,
+}
+
+impl ReportFormat {
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "csv",
+            ReportFormat::Json => "json",
+        }
+    }
+}
+
+/// A single bucket leaf as listed in the report
+#[derive(serde::Serialize)]
+pub(crate) struct ReportEntry {
+    pub index: usize,
+    pub leaf: String,
+    pub file_name: String,
+    pub size: u64,
+    /// Unix timestamp of the last proof verification
+    pub last_verified: Option<u64>,
+    pub verified: Option<bool>,
+}
+
+/// Writes the report entries to `path` in the requested format
+pub(crate) fn write_report<P: AsRef<Path>>(
+    entries: &[ReportEntry],
+    format: ReportFormat,
+    path: P,
+) -> io::Result<()> {
+    let data = match format {
+        ReportFormat::Csv => to_csv(entries),
+        ReportFormat::Json => serde_json::to_string_pretty(entries)?,
+    };
+
+    fs::write(path, data)
+}
+
+fn to_csv(entries: &[ReportEntry]) -> String {
+    let mut csv =
+        String::from("index,leaf,file_name,size,last_verified,verified\n");
+
+    for e in entries {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            e.index,
+            e.leaf,
+            escape_csv(&e.file_name),
+            e.size,
+            e.last_verified.map(|t| t.to_string()).unwrap_or_default(),
+            e.verified.map(|v| v.to_string()).unwrap_or_default(),
+        ));
+    }
+
+    csv
+}
+
+/// Quotes a CSV field if it contains a separator, a quote or a line break
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
@@ -0,0 +1,165 @@
+// Retry policy of the requests to the storage servers
+
+use std::time::Duration;
+
+use hyper::StatusCode;
+use rand::Rng;
+
+/// Retries of the uploads, downloads and proof requests that fail
+/// transiently, on a 5xx, 408 or 429 response or a connection error
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    /// Number of attempts of a request, 1 disables the retries
+    pub attempts: usize,
+    /// Delay before the first retry, doubled at each retry
+    pub base_delay: Duration,
+    /// Bound of the delay between two attempts
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns true if a request may be attempted again after `attempt`,
+    /// counted from 1
+    pub(crate) fn retries(&self, attempt: usize) -> bool {
+        attempt < self.attempts
+    }
+
+    /// Returns the delay before the retry following `attempt`, the one the
+    /// server asked for in `retry_after` if any
+    ///
+    /// The delay is drawn between half and all of the exponential backoff,
+    /// so that the clients failing together do not retry together. Both are
+    /// bounded by `max_delay`.
+    pub(crate) fn delay(
+        &self,
+        attempt: usize,
+        retry_after: Option<Duration>,
+    ) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+
+        let exponent = attempt.saturating_sub(1).min(16) as u32;
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Returns true if a request answered with `status` may succeed if sent
+/// again: the server failed, timed out receiving it or is rate limiting
+pub(crate) fn is_retried_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || matches!(
+            status,
+            StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+        )
+}
+
+/// Parses a `Retry-After` header given in seconds, the form the storage
+/// servers use
+pub(crate) fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn test_retries() {
+        let policy = policy();
+        assert!(policy.retries(1));
+        assert!(policy.retries(2));
+        assert!(!policy.retries(3));
+
+        let once = RetryPolicy {
+            attempts: 1,
+            ..policy
+        };
+        assert!(!once.retries(1));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = policy();
+        let ms = Duration::from_millis;
+        for _ in 0..100 {
+            let delay = policy.delay(1, None);
+            assert!(ms(50) <= delay && delay <= ms(100), "{delay:?}");
+            let delay = policy.delay(2, None);
+            assert!(ms(100) <= delay && delay <= ms(200), "{delay:?}");
+            let delay = policy.delay(3, None);
+            assert!(ms(200) <= delay && delay <= ms(400), "{delay:?}");
+
+            // The backoff is bounded, however many attempts were made
+            for attempt in [5, 17, 64, usize::MAX] {
+                let delay = policy.delay(attempt, None);
+                assert!(ms(500) <= delay && delay <= ms(1000), "{delay:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("-1"), None);
+        assert_eq!(parse_retry_after("1.5"), None);
+        assert_eq!(parse_retry_after(""), None);
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2026 07:28:00 GMT"), None);
+
+        // The delay asked by the server replaces the backoff, bounded alike
+        let policy = policy();
+        let asked = Some(Duration::from_millis(300));
+        assert_eq!(policy.delay(1, asked), Duration::from_millis(300));
+        assert_eq!(policy.delay(1, Some(Duration::ZERO)), Duration::ZERO);
+        let asked = Some(Duration::from_secs(60));
+        assert_eq!(policy.delay(3, asked), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_retried_statuses() {
+        for status in [
+            StatusCode::REQUEST_TIMEOUT,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::GATEWAY_TIMEOUT,
+        ] {
+            assert!(is_retried_status(status), "{status}");
+        }
+        for status in [
+            StatusCode::OK,
+            StatusCode::BAD_REQUEST,
+            StatusCode::UNAUTHORIZED,
+            StatusCode::NOT_FOUND,
+            StatusCode::CONFLICT,
+            StatusCode::PAYLOAD_TOO_LARGE,
+        ] {
+            assert!(!is_retried_status(status), "{status}");
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// Scheduled background upload mode for the client
+
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::{error, info};
+
+use crate::http_client::ClientApp;
+use crate::output::{OutputFormat, Record};
+use crate::prompt::read_source_files;
+
+pub(crate) const STATUS_FILE: &str = "/schedule_status.json";
+
+/// When the scheduled uploads run
+#[derive(Clone)]
+pub(crate) enum Schedule {
+    /// A fixed interval between two runs
+    Every(Duration),
+    /// A cron expression, in UTC
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    /// Parses a cron expression, e.g. `0 0 */6 * * *`
+    pub(crate) fn parse_cron(expr: &str) -> Result<Self, String> {
+        cron::Schedule::from_str(expr)
+            .map(|s| Schedule::Cron(Box::new(s)))
+            .map_err(|err| err.to_string())
+    }
+
+    /// Returns the delay until the next run
+    fn next_delay(&self) -> Option<Duration> {
+        match self {
+            Schedule::Every(every) => Some(*every),
+            Schedule::Cron(schedule) => schedule
+                .upcoming(chrono::Utc)
+                .next()
+                .and_then(|next| (next - chrono::Utc::now()).to_std().ok()),
+        }
+    }
+}
+
+/// Status of the scheduled uploads, written to `STATUS_FILE` after each run
+#[derive(Default, serde::Serialize)]
+struct ScheduleStatus {
+    /// Unix timestamp of the last run
+    last_run: Option<u64>,
+    /// Unix timestamp of the next run
+    next_run: Option<u64>,
+    /// Error of the last run, if it failed
+    last_error: Option<String>,
+    last_uploaded: usize,
+    last_failed: usize,
+    /// Merkle root of the bucket after the last run
+    root: Option<String>,
+
+    // Counters since the agent started
+    runs: u64,
+    failed_runs: u64,
+    total_uploaded: u64,
+    total_failed: u64,
+}
+
+/// Runs unattended: periodically scans the source folder and uploads the
+/// files not yet in the bucket, leaving the source folder untouched
+///
+/// The outcome of each run is printed in the `output` format as well.
+pub(crate) async fn run_schedule(
+    mut client: ClientApp,
+    schedule: Schedule,
+    src_folder: &Path,
+    client_dir: &str,
+    output: OutputFormat,
+) {
+    let status_path = client_dir.to_owned() + STATUS_FILE;
+    let mut status = ScheduleStatus::default();
+
+    loop {
+        status.runs += 1;
+        status.last_run = Some(now());
+
+        let files = client.new_files(&read_source_files(src_folder));
+        info!(event = "scheduled upload", new_files = files.len());
+
+        match client.upload_files(&files, false).await {
+            Ok(summary) => {
+                output.print_upload(&summary);
+                status.last_error = None;
+                status.last_uploaded = summary.uploaded;
+                status.last_failed = summary.failed;
+                status.total_uploaded += summary.uploaded as u64;
+                status.total_failed += summary.failed as u64;
+                status.root = summary.root.map(hex::encode);
+            }
+            Err(err) => {
+                error!(event = "scheduled upload failed", ?err);
+                output.print(&Record::error("upload", &*err));
+                status.failed_runs += 1;
+                status.last_error = Some(err.to_string());
+            }
+        }
+
+        let Some(delay) = schedule.next_delay() else {
+            error!(event = "no upcoming scheduled run");
+            status.next_run = None;
+            write_status(&status_path, &status);
+            break;
+        };
+
+        status.next_run = Some(now() + delay.as_secs());
+        write_status(&status_path, &status);
+
+        info!(
+            event = "next scheduled upload",
+            delay_secs = delay.as_secs()
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn write_status(path: &str, status: &ScheduleStatus) {
+    let res = serde_json::to_vec_pretty(status)
+        .map_err(std::io::Error::from)
+        .and_then(|data| std::fs::write(path, data));
+
+    if let Err(err) = res {
+        error!(event = "failed to write status file", path, ?err);
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
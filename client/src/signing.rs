@@ -0,0 +1,103 @@
+// Signatures of the bucket owner, verified by servers advertising the
+// `ed25519` auth scheme
+
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ed25519_dalek::{Signer as _, SigningKey};
+use hyper::http::request::Builder;
+use rand::RngCore;
+use tracing::info;
+
+use merkle::tree::Hash;
+
+/// File of the seed of the owner key, in the client folder
+const KEY_FILE: &str = "/owner_key.bin";
+
+/// Bucket operations signed by the owner
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Action {
+    Upload,
+    Complete,
+    DeleteFile,
+    BeginUpload,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Upload => "upload",
+            Action::Complete => "complete",
+            Action::DeleteFile => "delete_file",
+            Action::BeginUpload => "begin_upload",
+        }
+    }
+}
+
+/// The ed25519 key owning the bucket of the client
+pub(crate) struct Signer {
+    key: SigningKey,
+}
+
+impl Signer {
+    /// Loads the owner key from the client folder, generating it on first
+    /// use
+    pub(crate) fn load_or_create(client_folder: &str) -> io::Result<Self> {
+        let key_file = client_folder.to_owned() + KEY_FILE;
+
+        let seed = match fs::read(&key_file) {
+            Ok(seed) => seed.try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid owner key")
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let mut seed = [0u8; 32];
+                rand::thread_rng().fill_bytes(&mut seed);
+                fs::write(&key_file, seed)?;
+                info!(event = "new owner key", file = key_file);
+                seed
+            }
+            Err(err) => return Err(err),
+        };
+
+        Ok(Signer {
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    /// Returns the hex encoded public key
+    pub(crate) fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().as_bytes())
+    }
+
+    /// Adds the signature headers of an operation to a request
+    ///
+    /// The file name and hash are empty for the operations on the whole
+    /// bucket.
+    pub(crate) fn sign(
+        &self,
+        req: Builder,
+        action: Action,
+        bucket_id: &str,
+        filename: &str,
+        hash: Option<&Hash>,
+    ) -> Builder {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut random = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut random);
+        let nonce = format!("{timestamp}:{}", hex::encode(random));
+
+        let message = format!(
+            "storage-signature-v1\n{}\n{bucket_id}\n{filename}\n{}\n{nonce}",
+            action.as_str(),
+            hash.map(hex::encode).unwrap_or_default()
+        );
+        let signature = self.key.sign(message.as_bytes());
+
+        req.header("x-signature", hex::encode(signature.to_bytes()))
+            .header("x-signature-nonce", nonce)
+    }
+}
@@ -0,0 +1,64 @@
+// Spans of the client exported over OTLP, their trace context propagated to
+// the servers so an upload is traced end to end
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::propagation::{Injector, TextMapPropagator};
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Name of the client in the exported spans
+const SERVICE_NAME: &str = "storage-client";
+
+/// Returns the provider exporting the spans to the OTLP collector listening
+/// on `endpoint` (e.g. `http://localhost:4317`)
+pub(crate) fn tracer_provider(
+    endpoint: &str,
+) -> Result<TracerProvider, TraceError> {
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            SERVICE_NAME,
+        )]))
+        .build())
+}
+
+/// Returns the tracer of the client spans
+pub(crate) fn tracer(provider: &TracerProvider) -> Tracer {
+    provider.tracer(SERVICE_NAME)
+}
+
+/// Adds the W3C trace context of the current span to the headers of a
+/// request, so the server spans are children of the client span
+///
+/// Nothing is added if the spans are not exported.
+pub(crate) fn inject(headers: &mut HeaderMap) {
+    let context = Span::current().context();
+    TraceContextPropagator::new()
+        .inject_context(&context, &mut HeaderInjector(headers));
+}
+
+/// Writes the trace context in the headers of an http request
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Bandwidth limit of the client
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::body::{self, Body};
+
+/// Size of the pieces a buffered body is sent in, so that a large chunk
+/// does not go out in a single burst
+const PIECE_SIZE: usize = 64 * 1024;
+
+/// Byte rate shared by all the transfers, up and down
+static LIMITER: OnceLock<Limiter> = OnceLock::new();
+
+/// Paces the bytes going through it at `rate` bytes per second, whatever
+/// the number of transfers sharing it
+struct Limiter {
+    rate: u64,
+    /// When the bytes counted so far have gone through at `rate`
+    free_at: Mutex<Instant>,
+}
+
+impl Limiter {
+    /// Counts `bytes` gone through, returns how long they wait
+    fn reserve(&self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let mut free_at = self.free_at.lock().expect("valid lock");
+
+        let spent = Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+        *free_at = (*free_at).max(now) + spent;
+        free_at.duration_since(now)
+    }
+}
+
+/// Limits the transfers to `rate` bytes per second, unbounded if `None`
+///
+/// Must be called before the first transfer to take effect.
+pub(crate) fn init_rate_limit(rate: Option<u64>) {
+    if let Some(rate) = rate {
+        let _ = LIMITER.set(Limiter {
+            rate,
+            free_at: Mutex::new(Instant::now()),
+        });
+    }
+}
+
+/// Waits until `bytes` may go through
+pub(crate) async fn pace(bytes: usize) {
+    if let Some(limiter) = LIMITER.get() {
+        let wait = limiter.reserve(bytes as u64);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Paces the chunks of a body stream
+pub(crate) fn stream<S, E>(
+    chunks: S,
+) -> impl Stream<Item = Result<Bytes, E>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: Send + 'static,
+{
+    chunks.then(|chunk| async move {
+        if let Ok(chunk) = &chunk {
+            pace(chunk.len()).await;
+        }
+        chunk
+    })
+}
+
+/// Returns the body of a buffered request, sent in paced pieces if the
+/// rate is limited
+pub(crate) fn body(bytes: Bytes) -> Body {
+    if LIMITER.get().is_none() {
+        return body::full(bytes);
+    }
+
+    let pieces = (0..bytes.len()).step_by(PIECE_SIZE).map(move |start| {
+        let end = (start + PIECE_SIZE).min(bytes.len());
+        Ok::<_, std::io::Error>(bytes.slice(start..end))
+    });
+    body::wrap_stream(stream(tokio_stream::iter(pieces)))
+}
+
+/// Parses a byte rate such as `5MB/s`, `512KiB/s`, `10M` or `1000000`
+///
+/// `KB`, `MB` and `GB` are powers of 1000, `KiB`, `MiB` and `GiB` powers
+/// of 1024, as are `K`, `M` and `G` as curl takes them, a bare number is in
+/// bytes per second.
+pub(crate) fn parse_rate(rate: &str) -> Result<u64, String> {
+    let value = rate.trim();
+    let value = value.strip_suffix("/s").unwrap_or(value);
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid rate {rate:?}, e.g. 5MB/s"))?;
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        _ => return Err(format!("unknown unit {unit:?} of rate {rate:?}")),
+    };
+
+    let bytes = (number * multiplier as f64) as u64;
+    if bytes == 0 {
+        return Err(format!("rate {rate:?} must be at least 1 byte/s"));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("10M"), Ok(10 << 20));
+        assert_eq!(parse_rate("512k"), Ok(512 << 10));
+        assert_eq!(parse_rate("1g/s"), Ok(1 << 30));
+        assert_eq!(parse_rate("5MB/s"), Ok(5_000_000));
+        assert_eq!(parse_rate("512KiB/s"), Ok(512 << 10));
+        assert_eq!(parse_rate("1.5kb"), Ok(1_500));
+        assert_eq!(parse_rate(" 2 MiB/s "), Ok(2 << 20));
+        assert_eq!(parse_rate("1000000"), Ok(1_000_000));
+        assert_eq!(parse_rate("100B/s"), Ok(100));
+
+        for invalid in ["", "fast", "M", "-1M", "10X", "10 M B", "1e3", "0"] {
+            assert!(parse_rate(invalid).is_err(), "{invalid:?}");
+        }
+        // Less than a byte per second
+        assert!(parse_rate("0.5").is_err());
+    }
+}
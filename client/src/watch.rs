@@ -0,0 +1,100 @@
+// Watch mode for the client
+
+use std::path::Path;
+use std::time::Duration;
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::http_client::ClientApp;
+use crate::output::{OutputFormat, Record};
+use crate::prompt::read_source_files;
+
+/// Runs unattended: uploads the files of the source folder not yet in the
+/// bucket, then again each time files are created or modified, once the
+/// folder has not changed for `debounce`
+///
+/// Each batch completes the upload, so the bucket is resealed with the new
+/// files. The source folder is left untouched.
+pub(crate) async fn run_watch(
+    mut client: ClientApp,
+    src_folder: &Path,
+    debounce: Duration,
+    output: OutputFormat,
+) {
+    // The watcher calls back from a thread of its own
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            error!(event = "failed to watch source folder", ?err);
+            return;
+        }
+    };
+    if let Err(err) = watcher.watch(src_folder, RecursiveMode::NonRecursive) {
+        error!(event = "failed to watch source folder", ?err);
+        return;
+    }
+    info!(
+        event = "watching source folder",
+        path = %src_folder.display(),
+        debounce_ms = debounce.as_millis() as u64
+    );
+
+    upload(&mut client, src_folder, output).await;
+
+    while let Some(res) = rx.recv().await {
+        let mut changed = is_change(&res);
+
+        // Wait for the writes in progress to settle
+        loop {
+            match tokio::time::timeout(debounce, rx.recv()).await {
+                Ok(Some(res)) => changed |= is_change(&res),
+                Ok(None) => return,
+                Err(_) => break,
+            }
+        }
+
+        if changed {
+            upload(&mut client, src_folder, output).await;
+        }
+    }
+}
+
+/// Returns true if a file of the folder was created or modified
+fn is_change(res: &notify::Result<Event>) -> bool {
+    match res {
+        Ok(event) => {
+            matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+        }
+        Err(err) => {
+            warn!(event = "watch error", ?err);
+            false
+        }
+    }
+}
+
+/// Uploads the files of the source folder not yet in the bucket, if any
+async fn upload(
+    client: &mut ClientApp,
+    src_folder: &Path,
+    output: OutputFormat,
+) {
+    let files = client.new_files(&read_source_files(src_folder));
+    if files.is_empty() {
+        return;
+    }
+    info!(event = "watched upload", new_files = files.len());
+
+    match client.upload_files(&files, false).await {
+        Ok(summary) => output.print_upload(&summary),
+        Err(err) => {
+            error!(event = "watched upload failed", ?err);
+            output.print(&Record::error("upload", &*err));
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::ser::SerializeSeq;
 use sha2::{Digest, Sha256};
 
@@ -37,7 +39,7 @@ impl Tree {
         let mut proof = Vec::new();
         let mut idx = index;
         for level in &self.levels[..self.levels.len() - 1] {
-            let is_left_node = idx % 2 == 0;
+            let is_left_node = idx.is_multiple_of(2);
             let pair_idx = if is_left_node { idx + 1 } else { idx - 1 };
 
             match pair_idx.cmp(&level.len()) {
@@ -45,7 +47,7 @@ impl Tree {
                     proof.push((level[pair_idx], is_left_node as u8))
                 }
                 std::cmp::Ordering::Equal => {
-                    assert!(level.len() % 2 != 0);
+                    assert!(!level.len().is_multiple_of(2));
                     proof.push((level[pair_idx - 1], 0));
                 }
                 _ => panic!("Invalid index"),
@@ -89,6 +91,54 @@ impl Tree {
         hash == *root
     }
 
+    /// Get the retention proof of the leaves of an older tree in this tree
+    ///
+    /// The proof is the proof of each leaf of the older tree in this tree,
+    /// `None` if one of them is not a leaf of this tree. It is not an RFC
+    /// 6962 consistency proof, which relies on the older tree being a prefix
+    /// of the newer one: it holds for leaves in any order, such as leaves
+    /// sorted by hash. The price is its size, `log n` hashes per leaf of the
+    /// older tree, and the leaves of the older tree travel with it.
+    pub fn retention_proof(
+        &self,
+        old_leaves: &[Hash],
+    ) -> Option<Vec<Vec<(Hash, u8)>>> {
+        let leaves = self.leaves();
+        let indices: HashMap<&Hash, usize> = leaves
+            .iter()
+            .enumerate()
+            .map(|(i, leaf)| (leaf, i))
+            .collect();
+
+        old_leaves
+            .iter()
+            .map(|leaf| indices.get(leaf).map(|&i| self.get_proof(i)))
+            .collect()
+    }
+
+    /// Verifies that the tree of `new_root` retained the leaves of the tree
+    /// of `old_leaves`, whose root is `old_root`: every leaf of the older
+    /// tree is a leaf of the newer one
+    ///
+    /// It proves that no leaf was removed, not where the leaves added since
+    /// stand, nor that the older leaves kept their positions. `old_root`
+    /// must be a root the verifier trusts, since the proof itself carries
+    /// the older leaves.
+    pub fn verify_retention(
+        old_leaves: &[Hash],
+        old_root: &Hash,
+        proofs: &[Vec<(Hash, u8)>],
+        new_root: &Hash,
+    ) -> bool {
+        let old_tree = Tree::build_from_leaves(old_leaves.to_vec());
+        old_tree.root_hash() == Some(*old_root)
+            && old_leaves.len() == proofs.len()
+            && old_leaves
+                .iter()
+                .zip(proofs)
+                .all(|(leaf, proof)| Tree::verify_proof(leaf, proof, new_root))
+    }
+
     pub fn build_from_leaves(leaves: Level) -> Tree {
         if leaves.is_empty() {
             return Tree::default();
@@ -111,6 +161,38 @@ impl Tree {
         }
     }
 
+    /// Restores a tree from its levels, as returned by [`Tree::levels`]
+    ///
+    /// Only the number of nodes of each level is checked, not their hashes,
+    /// see [`Tree::verify`]. Returns `None` if the levels do not form a tree.
+    pub fn from_levels(levels: Vec<Level>) -> Option<Tree> {
+        if levels.is_empty() {
+            return Some(Tree::default());
+        }
+
+        let shaped = levels
+            .windows(2)
+            .all(|pair| pair[1].len() == pair[0].len().div_ceil(2));
+        let root = match levels.last() {
+            Some(root_level) if shaped && root_level.len() == 1 => {
+                root_level[0]
+            }
+            _ => return None,
+        };
+
+        Some(Tree {
+            root: Some(root),
+            levels,
+        })
+    }
+
+    /// Returns whether each node of the tree is the hash of its children
+    pub fn verify(&self) -> bool {
+        self.levels
+            .windows(2)
+            .all(|pair| Tree::build_next_level(&pair[0]) == pair[1])
+    }
+
     /// Returns the number of leaves in the tree
     pub fn leaves_count(&self) -> usize {
         if let Some(leaves) = self.levels.first() {
@@ -120,6 +202,11 @@ impl Tree {
         }
     }
 
+    /// Returns the levels of the tree, from the leaves up to the root
+    pub fn levels(&self) -> &[Level] {
+        &self.levels
+    }
+
     /// Returns a copy of the leaves in the tree
     pub fn leaves(&self) -> Vec<Hash> {
         self.levels.first().unwrap_or(&vec![]).clone()
@@ -206,6 +293,74 @@ mod tests {
         assert!(mt.root_hash().is_none());
     }
 
+    #[test]
+    fn test_retention_proof() {
+        let leaves: Vec<Hash> = (0..20u8).map(|i| [i; 32]).collect();
+        let old_tree = Tree::build_from_leaves(leaves[..7].to_vec());
+        let old_root = old_tree.root_hash().expect("valid root");
+
+        // Leaves are not appended in order
+        let mut new_leaves = leaves.clone();
+        new_leaves.reverse();
+        let new_tree = Tree::build_from_leaves(new_leaves);
+        let new_root = new_tree.root_hash().expect("valid root");
+
+        let proofs = new_tree.retention_proof(&leaves[..7]).unwrap();
+        assert!(Tree::verify_retention(
+            &leaves[..7],
+            &old_root,
+            &proofs,
+            &new_root
+        ));
+
+        // Other leaves than those of the older tree
+        assert!(!Tree::verify_retention(
+            &leaves[1..8],
+            &old_root,
+            &new_tree.retention_proof(&leaves[1..8]).unwrap(),
+            &new_root
+        ));
+        // A leaf missing from the newer tree
+        let rewritten = Tree::build_from_leaves(leaves[1..].to_vec());
+        assert!(rewritten.retention_proof(&leaves[..7]).is_none());
+        assert!(!Tree::verify_retention(
+            &leaves[..7],
+            &old_root,
+            &proofs,
+            &rewritten.root_hash().unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_from_levels() {
+        let leaves: Vec<Hash> = (0..11u8).map(|i| [i; 32]).collect();
+        let mt = Tree::build_from_leaves(leaves);
+
+        let restored = Tree::from_levels(mt.levels().to_vec()).unwrap();
+        assert_eq!(restored.root_hash(), mt.root_hash());
+        assert_eq!(restored.get_proof(7), mt.get_proof(7));
+        assert!(restored.verify());
+
+        // A corrupted node keeps the shape of the tree
+        let mut levels = mt.levels().to_vec();
+        levels[1][2] = [0; 32];
+        let corrupted = Tree::from_levels(levels.clone()).unwrap();
+        assert!(!corrupted.verify());
+
+        // A missing node does not
+        levels[1].pop();
+        assert!(Tree::from_levels(levels).is_none());
+        let mut levels = mt.levels().to_vec();
+        levels.pop();
+        assert!(Tree::from_levels(levels).is_none());
+
+        let empty = Tree::from_levels(Vec::new()).unwrap();
+        assert_eq!(empty.root_hash(), None);
+        let single = Tree::from_levels(vec![vec![[1; 32]]]).unwrap();
+        assert_eq!(single.root_hash(), Some([1; 32]));
+        assert!(single.verify());
+    }
+
     #[test]
     fn test_serialize_tree() {
         // Generate random hashes
@@ -0,0 +1,9 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Use the bundled protoc, so building needs no system installation
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+
+    tonic_build::configure()
+        .bytes(["."])
+        .compile_protos(&["proto/storage.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,567 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+use axum::extract::{RawPathParams, Request, State};
+use axum::http::header::AUTHORIZATION;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+use tokio::sync::MutexGuard;
+use utoipa::ToSchema;
+
+use crate::accounts::Accounts;
+use crate::app::ServerState;
+use crate::jwt::JwtVerifier;
+use crate::tls::ClientCert;
+
+/// Kind of access a bucket route needs, granted separately by JWTs
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Operation {
+    /// Downloads, proofs, listings
+    Read,
+    /// Uploads and upload completions
+    Write,
+    /// Deletions of files and buckets
+    Delete,
+}
+
+/// An API key granting access to buckets
+///
+/// Keys are stored by their hash, the key itself is returned only once when
+/// it is created.
+#[derive(
+    Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize, ToSchema,
+)]
+pub(crate) struct ApiKey {
+    /// Bucket ids the key grants access to, a scope ending with `*` grants
+    /// the bucket ids starting with the rest of it
+    pub scopes: Vec<String>,
+    /// Unix timestamp of the key creation
+    pub created_at: u64,
+}
+
+impl ApiKey {
+    /// Returns true if the key grants access to `bucket_id`
+    pub(crate) fn covers(&self, bucket_id: &str) -> bool {
+        covers(&self.scopes, bucket_id)
+    }
+}
+
+/// Returns true if one of `scopes` is `bucket_id`, or a `<prefix>*` pattern
+/// matching it
+pub(crate) fn covers(scopes: &[String], bucket_id: &str) -> bool {
+    scopes.iter().any(|scope| match scope.strip_suffix('*') {
+        Some(prefix) => bucket_id.starts_with(prefix),
+        None => scope == bucket_id,
+    })
+}
+
+/// An API key of the former single bucket layout
+#[derive(serde::Deserialize)]
+pub(crate) struct LegacyApiKey {
+    bucket_id: String,
+    created_at: u64,
+}
+
+impl From<LegacyApiKey> for ApiKey {
+    fn from(key: LegacyApiKey) -> Self {
+        ApiKey {
+            scopes: vec![key.bucket_id],
+            created_at: key.created_at,
+        }
+    }
+}
+
+/// Per-bucket limits adjusted at runtime through the admin API
+#[derive(
+    Clone, Debug, Default, serde::Serialize, serde::Deserialize, ToSchema,
+)]
+pub(crate) struct BucketLimits {
+    /// Overrides the server-wide quota
+    pub quota_bytes: Option<u64>,
+    /// Maximum number of requests per second
+    pub rate_limit: Option<u32>,
+}
+
+#[derive(Debug)]
+pub(crate) enum AccessDenied {
+    Unauthorized,
+    RateLimited,
+}
+
+/// Returns the identifier of an API key, its hex encoded hash
+pub(crate) fn key_id(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Access rules applied to bucket requests
+///
+/// The keys, limits and accounts are updated at runtime by the admin and
+/// account routes, each under a lock of its own, so that the requests
+/// checking them never wait on a whole-server lock.
+#[derive(Default)]
+pub(crate) struct AccessControl {
+    /// Map an API key id to the key
+    api_keys: RwLock<HashMap<String, ApiKey>>,
+    /// Map a bucket id to its limits
+    limits: RwLock<HashMap<String, BucketLimits>>,
+    /// Client certificate fingerprints and the buckets they are granted
+    client_certs: Vec<(String, String)>,
+    /// Whether all the buckets require an API key
+    require_api_key: bool,
+    /// Verifier of the JWTs accepted in place of API keys, if configured
+    jwt: Option<Arc<JwtVerifier>>,
+    /// User accounts, whose keys grant access to the buckets they own
+    accounts: Accounts,
+    rate_limiter: RateLimiter,
+    /// Serializes the updates, each persisted before it is applied
+    updates: tokio::sync::Mutex<()>,
+}
+
+impl AccessControl {
+    pub(crate) fn new(
+        api_keys: HashMap<String, ApiKey>,
+        limits: HashMap<String, BucketLimits>,
+    ) -> Self {
+        AccessControl {
+            api_keys: RwLock::new(api_keys),
+            limits: RwLock::new(limits),
+            client_certs: Vec::new(),
+            require_api_key: false,
+            jwt: None,
+            accounts: Accounts::default(),
+            rate_limiter: RateLimiter::default(),
+            updates: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Accepts the JWTs verified by `jwt` wherever an API key is required
+    pub(crate) fn with_jwt(mut self, jwt: Option<JwtVerifier>) -> Self {
+        self.jwt = jwt.map(Arc::new);
+        self
+    }
+
+    /// Returns the JWT verifier, if configured
+    pub(crate) fn jwt(&self) -> Option<Arc<JwtVerifier>> {
+        self.jwt.clone()
+    }
+
+    /// Grants the keys of `accounts` access to the buckets they own
+    pub(crate) fn with_accounts(mut self, accounts: Accounts) -> Self {
+        self.accounts = accounts;
+        self
+    }
+
+    pub(crate) fn accounts(&self) -> &Accounts {
+        &self.accounts
+    }
+
+    /// Waits for the other updates of the keys, limits and accounts
+    ///
+    /// An update reads the current entries, persists the new ones then
+    /// applies them, holding the returned guard throughout so that the
+    /// entries in memory end up as persisted.
+    pub(crate) async fn lock_updates(&self) -> MutexGuard<'_, ()> {
+        self.updates.lock().await
+    }
+
+    /// Requires an API key on all the buckets, not only on those named by
+    /// the scope of a key
+    pub(crate) fn with_required_api_key(mut self, required: bool) -> Self {
+        self.require_api_key = required;
+        self
+    }
+
+    /// Sets the `--client-cert` grants
+    pub(crate) fn with_client_certs(
+        mut self,
+        client_certs: Vec<(String, String)>,
+    ) -> Self {
+        self.client_certs = client_certs;
+        self
+    }
+
+    /// Checks that a request is allowed to access a bucket
+    ///
+    /// A bucket covered by the scope of an API key or owned by an account, or
+    /// any bucket with `--require-api-key` or a JWT verifier, accepts only
+    /// requests carrying as a `Bearer` token a key covering it, a key of the
+    /// account owning it, or a JWT granting `operation` on it. A bucket with
+    /// granted client certificates only accepts requests whose connection is
+    /// authenticated with one of them.
+    pub(crate) fn check(
+        &self,
+        bucket_id: &str,
+        operation: Operation,
+        authorization: Option<&str>,
+        client_cert: Option<&str>,
+    ) -> Result<(), AccessDenied> {
+        let mut grants = self
+            .client_certs
+            .iter()
+            .filter(|(_, granted)| granted == bucket_id)
+            .peekable();
+        if grants.peek().is_some()
+            && !grants.any(|(cert, _)| Some(cert.as_str()) == client_cert)
+        {
+            return Err(AccessDenied::Unauthorized);
+        }
+
+        let owner = self.accounts.owner(bucket_id);
+        if self.require_api_key
+            || self.jwt.is_some()
+            || owner.is_some()
+            || self.api_keys().values().any(|key| key.covers(bucket_id))
+        {
+            let key = authorization
+                .and_then(|h| h.strip_prefix("Bearer "))
+                .ok_or(AccessDenied::Unauthorized)?;
+
+            let key_id = key_id(key);
+            let covers = self
+                .api_keys()
+                .get(&key_id)
+                .map(|key| key.covers(bucket_id));
+            let granted = match covers {
+                Some(covers) => covers,
+                None => match self.accounts.key_account(&key_id) {
+                    Some(account_id) => owner == Some(account_id),
+                    None => self
+                        .jwt
+                        .as_ref()
+                        .and_then(|jwt| jwt.verify(key))
+                        .is_some_and(|claims| {
+                            claims.allows(bucket_id, operation)
+                        }),
+                },
+            };
+            if !granted {
+                return Err(AccessDenied::Unauthorized);
+            }
+        }
+
+        self.rate_limit(bucket_id)
+    }
+
+    /// Counts a request on a bucket against its rate limit
+    pub(crate) fn rate_limit(
+        &self,
+        bucket_id: &str,
+    ) -> Result<(), AccessDenied> {
+        if let Some(limit) = self.limits(bucket_id).rate_limit {
+            if !self.rate_limiter.check(bucket_id, limit) {
+                return Err(AccessDenied::RateLimited);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn api_keys(&self) -> RwLockReadGuard<'_, HashMap<String, ApiKey>> {
+        self.api_keys.read().expect("valid lock")
+    }
+
+    pub(crate) fn insert_key(&self, key_id: String, key: ApiKey) {
+        self.api_keys
+            .write()
+            .expect("valid lock")
+            .insert(key_id, key);
+    }
+
+    pub(crate) fn remove_key(&self, key_id: &str) -> Option<ApiKey> {
+        self.api_keys.write().expect("valid lock").remove(key_id)
+    }
+
+    /// Returns the ids of the keys granting access to a bucket
+    pub(crate) fn key_ids(&self, bucket_id: &str) -> Vec<String> {
+        self.api_keys()
+            .iter()
+            .filter(|(_, key)| key.covers(bucket_id))
+            .map(|(key_id, _)| key_id.clone())
+            .collect()
+    }
+
+    /// Returns all the keys by their id
+    pub(crate) fn keys(&self) -> HashMap<String, ApiKey> {
+        self.api_keys().clone()
+    }
+
+    pub(crate) fn limits(&self, bucket_id: &str) -> BucketLimits {
+        let limits = self.limits.read().expect("valid lock");
+        limits.get(bucket_id).cloned().unwrap_or_default()
+    }
+
+    pub(crate) fn set_limits(&self, bucket_id: String, limits: BucketLimits) {
+        self.limits
+            .write()
+            .expect("valid lock")
+            .insert(bucket_id, limits);
+    }
+}
+
+/// Fixed one-second window request counter per bucket
+#[derive(Default)]
+struct RateLimiter {
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    /// Counts a request and returns false if the bucket exceeded `limit`
+    /// requests in the current window
+    fn check(&self, bucket_id: &str, limit: u32) -> bool {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().expect("valid lock");
+
+        let (start, count) =
+            windows.entry(bucket_id.to_owned()).or_insert((now, 0));
+        if now.duration_since(*start) >= Duration::from_secs(1) {
+            *start = now;
+            *count = 0;
+        }
+
+        *count += 1;
+        *count <= limit
+    }
+}
+
+/// Checks the request is allowed to perform `operation` on the bucket of its
+/// `bucket_id` path parameter
+pub(crate) async fn bucket_access(
+    State((state, operation)): State<(Arc<ServerState>, Operation)>,
+    params: RawPathParams,
+    req: Request,
+    next: Next,
+) -> Response {
+    let bucket_id = path_param(&params, "bucket_id");
+    if let Err(err) = check_request(&state, bucket_id, operation, &req) {
+        return err.into_response();
+    }
+
+    next.run(req).await
+}
+
+/// Checks the request is allowed to write the bucket of its `destination_id`
+/// path parameter, the destination of a copy
+pub(crate) async fn destination_access(
+    State(state): State<Arc<ServerState>>,
+    params: RawPathParams,
+    req: Request,
+    next: Next,
+) -> Response {
+    let bucket_id = path_param(&params, "destination_id");
+    if let Err(err) = check_request(&state, bucket_id, Operation::Write, &req) {
+        return err.into_response();
+    }
+
+    next.run(req).await
+}
+
+/// Returns the value of the path parameter `name`, empty if there is none
+pub(crate) fn path_param<'a>(params: &'a RawPathParams, name: &str) -> &'a str {
+    params
+        .iter()
+        .find_map(|(param, value)| (param == name).then_some(value))
+        .unwrap_or_default()
+}
+
+fn check_request(
+    state: &ServerState,
+    bucket_id: &str,
+    operation: Operation,
+    req: &Request,
+) -> Result<(), AccessDenied> {
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok());
+    let client_cert = req.extensions().get::<ClientCert>();
+    state.access.check(
+        bucket_id,
+        operation,
+        authorization,
+        client_cert.map(|cert| cert.0.as_str()),
+    )
+}
+
+/// Refuses the requests not carrying `token` as a `Bearer` token, or all of
+/// them if `token` is unset
+pub(crate) async fn bearer_auth(
+    State(token): State<Option<String>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    // The digests are compared rather than the tokens, so that the time
+    // taken does not tell how much of a token matches
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok());
+    let authorized = match (token, authorization) {
+        (Some(token), Some(authorization)) => authorization
+            .strip_prefix("Bearer ")
+            .is_some_and(|presented| {
+                Sha256::digest(presented.as_bytes())
+                    == Sha256::digest(token.as_bytes())
+            }),
+        _ => false,
+    };
+
+    if !authorized {
+        return AccessDenied::Unauthorized.into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::{Account, AccountKey};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_access_control() {
+        let access = AccessControl::default();
+
+        // Buckets without keys are open
+        assert!(access.check("bucket", Operation::Read, None, None).is_ok());
+
+        let key = "secret";
+        access.insert_key(
+            key_id(key),
+            ApiKey {
+                scopes: vec!["bucket".to_string()],
+                created_at: 0,
+            },
+        );
+
+        assert!(access.check("bucket", Operation::Read, None, None).is_err());
+        assert!(access
+            .check("bucket", Operation::Read, Some("Bearer wrong"), None)
+            .is_err());
+        assert!(access
+            .check("bucket", Operation::Read, Some("Bearer secret"), None)
+            .is_ok());
+        assert!(access
+            .check("other", Operation::Read, Some("Bearer secret"), None)
+            .is_ok());
+
+        access.set_limits(
+            "other".to_string(),
+            BucketLimits {
+                quota_bytes: None,
+                rate_limit: Some(2),
+            },
+        );
+        assert!(access.check("other", Operation::Read, None, None).is_ok());
+        assert!(access.check("other", Operation::Read, None, None).is_ok());
+        assert!(matches!(
+            access.check("other", Operation::Read, None, None),
+            Err(AccessDenied::RateLimited)
+        ));
+
+        access.remove_key(&key_id(key));
+        assert!(access.check("bucket", Operation::Read, None, None).is_ok());
+    }
+
+    #[test]
+    fn test_key_scopes() {
+        let access = AccessControl::default();
+        access.insert_key(
+            key_id("team"),
+            ApiKey {
+                scopes: vec!["team-*".to_string(), "shared".to_string()],
+                created_at: 0,
+            },
+        );
+
+        // Prefix scopes close the buckets they match, as exact scopes do
+        assert!(access.check("team-a", Operation::Read, None, None).is_err());
+        assert!(access
+            .check("team-a", Operation::Read, Some("Bearer other"), None)
+            .is_err());
+        assert!(access
+            .check("team-a", Operation::Read, Some("Bearer team"), None)
+            .is_ok());
+        assert!(access.check("shared", Operation::Read, None, None).is_err());
+        assert!(access
+            .check("shared", Operation::Read, Some("Bearer team"), None)
+            .is_ok());
+        assert_eq!(access.key_ids("team-b"), vec![key_id("team")]);
+        assert!(access.key_ids("other").is_empty());
+        assert!(access.check("other", Operation::Read, None, None).is_ok());
+
+        let access = access.with_required_api_key(true);
+        assert!(access.check("team-a", Operation::Read, None, None).is_err());
+        assert!(access
+            .check("team-a", Operation::Read, Some("Bearer team"), None)
+            .is_ok());
+        assert!(access
+            .check("other", Operation::Read, Some("Bearer team"), None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_account_keys() {
+        let account = Account {
+            buckets: BTreeSet::from(["mine".to_string()]),
+            ..Account::default()
+        };
+        let key = AccountKey {
+            account_id: "alice".to_string(),
+            created_at: 0,
+        };
+        let access = AccessControl::default().with_accounts(Accounts::new(
+            HashMap::from([("alice".to_string(), account)]),
+            HashMap::from([(key_id("alice_key"), key)]),
+        ));
+
+        // The buckets of an account are closed, the others stay open
+        assert!(access.check("mine", Operation::Read, None, None).is_err());
+        assert!(access
+            .check("mine", Operation::Read, Some("Bearer alice_key"), None)
+            .is_ok());
+        assert!(access
+            .check("mine", Operation::Read, Some("Bearer other"), None)
+            .is_err());
+        assert!(access.check("open", Operation::Read, None, None).is_ok());
+
+        let access = access.with_required_api_key(true);
+        assert!(access
+            .check("open", Operation::Read, Some("Bearer alice_key"), None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_client_certs() {
+        let access = AccessControl::default().with_client_certs(vec![
+            ("cert_a".to_string(), "bucket".to_string()),
+            ("cert_b".to_string(), "bucket".to_string()),
+            ("cert_b".to_string(), "other".to_string()),
+        ]);
+
+        assert!(access.check("bucket", Operation::Read, None, None).is_err());
+        assert!(access
+            .check("bucket", Operation::Read, None, Some("cert_c"))
+            .is_err());
+        assert!(access
+            .check("bucket", Operation::Read, None, Some("cert_a"))
+            .is_ok());
+        assert!(access
+            .check("bucket", Operation::Read, None, Some("cert_b"))
+            .is_ok());
+        assert!(access
+            .check("other", Operation::Read, None, Some("cert_a"))
+            .is_err());
+        assert!(access
+            .check("other", Operation::Read, None, Some("cert_b"))
+            .is_ok());
+
+        // Buckets without granted certificates are open
+        assert!(access
+            .check("open", Operation::Read, None, Some("cert_a"))
+            .is_ok());
+        assert!(access.check("open", Operation::Read, None, None).is_ok());
+    }
+}
@@ -0,0 +1,998 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::async_trait;
+use axum::extract::{FromRequestParts, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::Router;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::access::{self, AccessDenied};
+use crate::app::ServerState;
+use crate::client_bucket::ClientBucket;
+use crate::errors::{ApiError, ErrorBody, ErrorCode};
+use crate::extract::{Json, Path};
+use crate::quota::QuotaExceeded;
+
+/// A user account owning a set of buckets
+///
+/// The buckets of an account only accept the requests carrying one of its
+/// keys, an API key covering them or a JWT granting them.
+#[derive(
+    Clone, Debug, Default, PartialEq, Serialize, Deserialize, ToSchema,
+)]
+pub(crate) struct Account {
+    /// Ids of the buckets owned by the account
+    pub buckets: BTreeSet<String>,
+    /// Maximum number of bytes stored over all the buckets of the account
+    pub quota_bytes: Option<u64>,
+    /// Unix timestamp of the account creation
+    pub created_at: u64,
+}
+
+/// A key authenticating the requests of an account
+///
+/// Keys are stored by their hash, like the API keys.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AccountKey {
+    pub account_id: String,
+    /// Unix timestamp of the key creation
+    pub created_at: u64,
+}
+
+/// The accounts and their keys
+///
+/// The accounts are read by every request on a bucket and updated by the
+/// account routes, under a lock of their own.
+#[derive(Default)]
+pub(crate) struct Accounts {
+    entries: RwLock<AccountEntries>,
+}
+
+#[derive(Default)]
+struct AccountEntries {
+    /// Map an account id to the account
+    accounts: HashMap<String, Account>,
+    /// Map an account key id to the key
+    keys: HashMap<String, AccountKey>,
+    /// Map a bucket id to the id of the account owning it
+    owners: HashMap<String, String>,
+}
+
+impl Accounts {
+    pub(crate) fn new(
+        accounts: HashMap<String, Account>,
+        keys: HashMap<String, AccountKey>,
+    ) -> Self {
+        let owners = accounts
+            .iter()
+            .flat_map(|(account_id, account)| {
+                account
+                    .buckets
+                    .iter()
+                    .map(|bucket_id| (bucket_id.clone(), account_id.clone()))
+            })
+            .collect();
+
+        Accounts {
+            entries: RwLock::new(AccountEntries {
+                accounts,
+                keys,
+                owners,
+            }),
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, AccountEntries> {
+        self.entries.read().expect("valid lock")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, AccountEntries> {
+        self.entries.write().expect("valid lock")
+    }
+
+    pub(crate) fn get(&self, account_id: &str) -> Option<Account> {
+        self.read().accounts.get(account_id).cloned()
+    }
+
+    /// Returns the accounts, sorted by id
+    pub(crate) fn all(&self) -> Vec<(String, Account)> {
+        let mut accounts: Vec<(String, Account)> = self
+            .read()
+            .accounts
+            .iter()
+            .map(|(account_id, account)| (account_id.clone(), account.clone()))
+            .collect();
+        accounts.sort_by(|(a, _), (b, _)| a.cmp(b));
+        accounts
+    }
+
+    /// Returns the id of the account owning a bucket
+    pub(crate) fn owner(&self, bucket_id: &str) -> Option<String> {
+        self.read().owners.get(bucket_id).cloned()
+    }
+
+    /// Returns the id of the account of a key
+    pub(crate) fn key_account(&self, key_id: &str) -> Option<String> {
+        self.read()
+            .keys
+            .get(key_id)
+            .map(|key| key.account_id.clone())
+    }
+
+    /// Returns the keys of an account by their id, oldest first
+    pub(crate) fn keys(&self, account_id: &str) -> Vec<(String, AccountKey)> {
+        let mut keys: Vec<(String, AccountKey)> = self
+            .read()
+            .keys
+            .iter()
+            .filter(|(_, key)| key.account_id == account_id)
+            .map(|(key_id, key)| (key_id.clone(), key.clone()))
+            .collect();
+        keys.sort_by_key(|(_, key)| key.created_at);
+        keys
+    }
+
+    /// Inserts or replaces an account
+    pub(crate) fn insert(&self, account_id: String, account: Account) {
+        let mut entries = self.write();
+        entries.owners.retain(|_, owner| *owner != account_id);
+        for bucket_id in &account.buckets {
+            entries.owners.insert(bucket_id.clone(), account_id.clone());
+        }
+        entries.accounts.insert(account_id, account);
+    }
+
+    /// Removes an account and its keys, its buckets are no longer owned
+    pub(crate) fn remove(&self, account_id: &str) -> Option<Account> {
+        let mut entries = self.write();
+        entries.owners.retain(|_, owner| owner != account_id);
+        entries.keys.retain(|_, key| key.account_id != account_id);
+        entries.accounts.remove(account_id)
+    }
+
+    pub(crate) fn insert_key(&self, key_id: String, key: AccountKey) {
+        self.write().keys.insert(key_id, key);
+    }
+
+    pub(crate) fn remove_key(&self, key_id: &str) -> Option<AccountKey> {
+        self.write().keys.remove(key_id)
+    }
+}
+
+/// Account to create
+#[derive(Debug, Deserialize, ToSchema)]
+struct NewAccount {
+    account_id: String,
+    /// Maximum number of bytes stored over all the buckets of the account
+    quota_bytes: Option<u64>,
+}
+
+/// Quota of an account to set
+#[derive(Debug, Deserialize, ToSchema)]
+struct AccountQuota {
+    quota_bytes: Option<u64>,
+}
+
+/// An account as listed by GET /admin/accounts
+#[derive(Serialize, ToSchema)]
+struct AccountEntry<'a> {
+    account_id: &'a str,
+    #[serde(flatten)]
+    account: &'a Account,
+}
+
+/// A key of an account as listed by GET /account/keys
+#[derive(Serialize, ToSchema)]
+struct AccountKeyEntry<'a> {
+    key_id: &'a str,
+    created_at: u64,
+}
+
+/// Bytes stored in a bucket of an account
+#[derive(Clone, Serialize, ToSchema)]
+pub(crate) struct BucketUsage {
+    pub bucket_id: String,
+    pub bytes_used: u64,
+}
+
+/// Usage of an account over all its buckets
+#[derive(Clone, Serialize, ToSchema)]
+pub(crate) struct AccountUsage {
+    pub account_id: String,
+    /// Buckets of the account, sorted by id
+    pub buckets: Vec<BucketUsage>,
+    pub bytes_used: u64,
+    pub quota_bytes: Option<u64>,
+}
+
+/// Returns the usage of an account
+///
+/// `current` is a bucket of the account locked by the caller. The other
+/// buckets of the account are read as they are in memory, or as last
+/// persisted if a request updates them.
+pub(crate) async fn usage(
+    state: &ServerState,
+    account_id: &str,
+    account: &Account,
+    current: Option<&ClientBucket>,
+) -> Result<AccountUsage, String> {
+    let mut buckets = Vec::with_capacity(account.buckets.len());
+    for bucket_id in &account.buckets {
+        let live = match current.filter(|bucket| &bucket.bucket_id == bucket_id)
+        {
+            Some(bucket) => Some(bucket.bytes_used),
+            None => state.buckets.get(bucket_id).and_then(|bucket| {
+                bucket.try_read().ok().map(|bucket| bucket.bytes_used)
+            }),
+        };
+        let bytes_used = match live {
+            Some(bytes_used) => bytes_used,
+            None => state
+                .db
+                .read()
+                .await
+                .bucket_bytes_used(bucket_id)?
+                .unwrap_or_default(),
+        };
+
+        buckets.push(BucketUsage {
+            bucket_id: bucket_id.clone(),
+            bytes_used,
+        });
+    }
+
+    Ok(AccountUsage {
+        account_id: account_id.to_owned(),
+        bytes_used: buckets.iter().map(|bucket| bucket.bytes_used).sum(),
+        buckets,
+        quota_bytes: account.quota_bytes,
+    })
+}
+
+/// Checks that a new file of `file_size` bytes fits in the quota of the
+/// account owning `bucket`, if any
+///
+/// Returns `413 Payload Too Large` with the usage of the account otherwise.
+pub(crate) async fn check_quota(
+    state: &ServerState,
+    bucket: &ClientBucket,
+    file_size: u64,
+) -> Result<(), ApiError> {
+    let accounts = state.access.accounts();
+    let Some(account_id) = accounts.owner(&bucket.bucket_id) else {
+        return Ok(());
+    };
+    let Some(account) = accounts.get(&account_id) else {
+        return Ok(());
+    };
+    let Some(quota_bytes) = account.quota_bytes else {
+        return Ok(());
+    };
+
+    let usage = usage(state, &account_id, &account, Some(bucket))
+        .await
+        .map_err(|err| {
+            error!(event = "failed to read account usage", account_id, err);
+            ApiError::internal()
+        })?;
+    if usage.bytes_used.saturating_add(file_size) <= quota_bytes {
+        return Ok(());
+    }
+
+    warn!(
+        event = "account quota exceeded",
+        account_id,
+        bucket_id = bucket.bucket_id,
+        file_size
+    );
+    Err(
+        ApiError::new(ErrorCode::QuotaExceeded, "account quota exceeded")
+            .with_detail("quota", QuotaExceeded::Bytes)
+            .with_detail("usage", usage),
+    )
+}
+
+/// Returns a new random key and its id
+fn new_key() -> (String, String) {
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = hex::encode(key);
+    let key_id = access::key_id(&key);
+
+    (key, key_id)
+}
+
+/// Returns the current unix timestamp
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn account_not_found(account_id: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::NotFound,
+        format!("account {account_id} not found"),
+    )
+}
+
+/// Id of the account whose key the request carries as a `Bearer` token
+struct KeyAccount(String);
+
+#[async_trait]
+impl FromRequestParts<Arc<ServerState>> for KeyAccount {
+    type Rejection = AccessDenied;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<ServerState>,
+    ) -> Result<Self, AccessDenied> {
+        let key = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(access::key_id);
+
+        key.and_then(|key_id| state.access.accounts().key_account(&key_id))
+            .map(KeyAccount)
+            .ok_or(AccessDenied::Unauthorized)
+    }
+}
+
+/// Account routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(
+    handle_create_account,
+    handle_list_accounts,
+    handle_set_quota,
+    handle_assign_bucket,
+    handle_delete_account,
+    handle_account,
+    handle_create_bucket,
+    handle_list_keys,
+    handle_create_key,
+    handle_revoke_key,
+))]
+pub(crate) struct ApiDoc;
+
+/// Returns the account routes of the admin API, nested in /admin
+pub(crate) fn admin_routes(state: Arc<ServerState>) -> Router {
+    Router::new()
+        // Create an account and its first key
+        // POST /admin/accounts
+        // List the accounts
+        // GET /admin/accounts
+        .route(
+            "/accounts",
+            post(handle_create_account).get(handle_list_accounts),
+        )
+        // Set the quota of an account
+        // PUT /admin/accounts/:account_id
+        // Delete an account and its keys
+        // DELETE /admin/accounts/:account_id
+        .route(
+            "/accounts/:account_id",
+            put(handle_set_quota).delete(handle_delete_account),
+        )
+        // Give a bucket to an account
+        // PUT /admin/accounts/:account_id/buckets/:bucket_id
+        .route(
+            "/accounts/:account_id/buckets/:bucket_id",
+            put(handle_assign_bucket),
+        )
+        .with_state(state)
+}
+
+/// Returns the routes of the accounts, authenticated by their keys
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    Router::new()
+        // Get the account with the usage of its buckets
+        // GET /account
+        .route("/account", get(handle_account))
+        // Create a bucket owned by the account
+        // POST /account/buckets/:bucket_id
+        .route("/account/buckets/:bucket_id", post(handle_create_bucket))
+        // List the keys of the account
+        // GET /account/keys
+        // Create a key of the account
+        // POST /account/keys
+        .route(
+            "/account/keys",
+            get(handle_list_keys).post(handle_create_key),
+        )
+        // Revoke a key of the account
+        // DELETE /account/keys/:key_id
+        .route("/account/keys/:key_id", delete(handle_revoke_key))
+        .with_state(state)
+}
+
+/// Handles account creation
+///
+/// The key of the account is returned only in this response. Returns
+/// `400 Bad Request` if the account id is empty or holds a `/`, and
+/// `409 Conflict` if the account exists.
+#[utoipa::path(
+    post,
+    path = "/admin/accounts",
+    tag = "accounts",
+    request_body = NewAccount,
+    responses(
+        (status = 200, description = "`{\"account_id\", \"key_id\", \"key\"}`, the key is not returned again", body = Object),
+        (status = 400, description = "Invalid account id", body = ErrorBody),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 409, description = "The account exists", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_create_account(
+    State(state): State<Arc<ServerState>>,
+    Json(new_account): Json<NewAccount>,
+) -> Result<Response, ApiError> {
+    let NewAccount {
+        account_id,
+        quota_bytes,
+    } = new_account;
+    if account_id.is_empty() || account_id.contains('/') {
+        return Err(ApiError::bad_request("invalid account id"));
+    }
+
+    let _updates = state.access.lock_updates().await;
+    if state.access.accounts().get(&account_id).is_some() {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            format!("account {account_id} exists"),
+        ));
+    }
+
+    let account = Account {
+        buckets: BTreeSet::new(),
+        quota_bytes,
+        created_at: now(),
+    };
+    let (key, key_id) = new_key();
+    let account_key = AccountKey {
+        account_id: account_id.clone(),
+        created_at: account.created_at,
+    };
+
+    {
+        let db = state.db.read().await;
+        let res = db
+            .update_account(&account_id, &account)
+            .and_then(|_| db.update_account_key(&key_id, &account_key));
+        if let Err(err) = res {
+            error!(event = "failed to persist account", account_id, err);
+            return Err(ApiError::internal());
+        }
+    }
+    let accounts = state.access.accounts();
+    accounts.insert(account_id.clone(), account);
+    accounts.insert_key(key_id.clone(), account_key);
+
+    info!(event = "account created", account_id, key_id, quota_bytes);
+
+    Ok(Json(serde_json::json!({
+        "account_id": account_id,
+        "key_id": key_id,
+        "key": key,
+    }))
+    .into_response())
+}
+
+/// Handles listing the accounts
+#[utoipa::path(
+    get,
+    path = "/admin/accounts",
+    tag = "accounts",
+    responses(
+        (status = 200, description = "Accounts, sorted by id", body = Vec<AccountEntry>),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_list_accounts(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let accounts = state.access.accounts().all();
+    let entries: Vec<AccountEntry> = accounts
+        .iter()
+        .map(|(account_id, account)| AccountEntry {
+            account_id,
+            account,
+        })
+        .collect();
+
+    Ok(Json(entries).into_response())
+}
+
+/// Handles account quota update
+///
+/// The quota applies to the files stored from now on, the account may
+/// already exceed it. Returns `404 Not Found` if the account does not exist.
+#[utoipa::path(
+    put,
+    path = "/admin/accounts/{account_id}",
+    tag = "accounts",
+    params(("account_id" = String, Path, description = "Id of the account")),
+    request_body = AccountQuota,
+    responses(
+        (status = 200, description = "The account", body = Account),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 404, description = "The account does not exist", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_set_quota(
+    Path(account_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    Json(quota): Json<AccountQuota>,
+) -> Result<Response, ApiError> {
+    let _updates = state.access.lock_updates().await;
+    let mut account = state
+        .access
+        .accounts()
+        .get(&account_id)
+        .ok_or_else(|| account_not_found(&account_id))?;
+    account.quota_bytes = quota.quota_bytes;
+
+    if let Err(err) =
+        state.db.read().await.update_account(&account_id, &account)
+    {
+        error!(event = "failed to persist account", account_id, err);
+        return Err(ApiError::internal());
+    }
+
+    info!(event = "account quota updated", account_id, ?quota);
+    state.access.accounts().insert(account_id, account.clone());
+
+    Ok(Json(account).into_response())
+}
+
+/// Handles giving a bucket to an account
+///
+/// The bucket may already store files. Returns `404 Not Found` if the
+/// account does not exist and `409 Conflict` if another account owns the
+/// bucket.
+#[utoipa::path(
+    put,
+    path = "/admin/accounts/{account_id}/buckets/{bucket_id}",
+    tag = "accounts",
+    params(
+        ("account_id" = String, Path, description = "Id of the account"),
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+    ),
+    responses(
+        (status = 200, description = "The account", body = Account),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 404, description = "The account does not exist", body = ErrorBody),
+        (status = 409, description = "Another account owns the bucket", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_assign_bucket(
+    Path((account_id, bucket_id)): Path<(String, String)>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let _updates = state.access.lock_updates().await;
+    let account = add_bucket(&state, &account_id, bucket_id).await?;
+
+    Ok(Json(account).into_response())
+}
+
+/// Adds a bucket to an account and returns the account
+///
+/// The caller holds the updates of the accounts. Returns `404 Not Found` if
+/// the account does not exist and `409 Conflict` if another account owns
+/// the bucket.
+async fn add_bucket(
+    state: &ServerState,
+    account_id: &str,
+    bucket_id: String,
+) -> Result<Account, ApiError> {
+    let accounts = state.access.accounts();
+    let mut account = accounts
+        .get(account_id)
+        .ok_or_else(|| account_not_found(account_id))?;
+    match accounts.owner(&bucket_id) {
+        Some(owner) if owner == account_id => return Ok(account),
+        Some(_) => {
+            return Err(ApiError::new(
+                ErrorCode::Conflict,
+                format!("bucket {bucket_id} belongs to another account"),
+            ))
+        }
+        None => {}
+    }
+    account.buckets.insert(bucket_id.clone());
+
+    if let Err(err) = state.db.read().await.update_account(account_id, &account)
+    {
+        error!(event = "failed to persist account", account_id, err);
+        return Err(ApiError::internal());
+    }
+
+    info!(event = "bucket added to account", account_id, bucket_id);
+    accounts.insert(account_id.to_owned(), account.clone());
+
+    Ok(account)
+}
+
+/// Handles account deletion
+///
+/// The keys of the account are revoked, its buckets and their files are
+/// kept without an owner. Returns `404 Not Found` if the account does not
+/// exist.
+#[utoipa::path(
+    delete,
+    path = "/admin/accounts/{account_id}",
+    tag = "accounts",
+    params(("account_id" = String, Path, description = "Id of the account")),
+    responses(
+        (status = 200, description = "Account deleted", body = String, content_type = "text/plain"),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 404, description = "The account does not exist", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_delete_account(
+    Path(account_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let _updates = state.access.lock_updates().await;
+    let accounts = state.access.accounts();
+    if accounts.get(&account_id).is_none() {
+        return Err(account_not_found(&account_id));
+    }
+    let key_ids: Vec<String> = accounts
+        .keys(&account_id)
+        .into_iter()
+        .map(|(key_id, _)| key_id)
+        .collect();
+
+    {
+        let db = state.db.read().await;
+        let res = key_ids
+            .iter()
+            .try_for_each(|key_id| db.delete_account_key(key_id))
+            .and_then(|_| db.delete_account(&account_id));
+        if let Err(err) = res {
+            error!(event = "failed to delete account", account_id, err);
+            return Err(ApiError::internal());
+        }
+    }
+    accounts.remove(&account_id);
+
+    info!(event = "account deleted", account_id);
+
+    Ok("Account deleted".into_response())
+}
+
+/// Handles account request
+///
+/// Replies with the buckets of the account and the bytes they store
+#[utoipa::path(
+    get,
+    path = "/account",
+    tag = "accounts",
+    responses(
+        (status = 200, description = "Usage of the account", body = AccountUsage),
+        (status = 401, description = "Missing or invalid account key", body = ErrorBody),
+    ),
+    security(("account_key" = [])),
+)]
+async fn handle_account(
+    KeyAccount(account_id): KeyAccount,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let account = state
+        .access
+        .accounts()
+        .get(&account_id)
+        .ok_or_else(|| account_not_found(&account_id))?;
+
+    match usage(&state, &account_id, &account, None).await {
+        Ok(usage) => Ok(Json(usage).into_response()),
+        Err(err) => {
+            error!(event = "failed to read account usage", account_id, err);
+            Err(ApiError::internal())
+        }
+    }
+}
+
+/// Handles bucket creation by an account
+///
+/// The bucket is owned by the account, the files are uploaded to it as to
+/// any bucket with a key of the account. Returns `409 Conflict` if the
+/// bucket exists, its files can only be given to an account by the admin
+/// API.
+#[utoipa::path(
+    post,
+    path = "/account/buckets/{bucket_id}",
+    tag = "accounts",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "The account", body = Account),
+        (status = 401, description = "Missing or invalid account key", body = ErrorBody),
+        (status = 409, description = "The bucket exists", body = ErrorBody),
+    ),
+    security(("account_key" = [])),
+)]
+async fn handle_create_bucket(
+    KeyAccount(account_id): KeyAccount,
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let _updates = state.access.lock_updates().await;
+
+    let stored = state.db.read().await.bucket_bytes_used(&bucket_id);
+    let exists = match stored {
+        Ok(stored) => {
+            stored.is_some() || state.buckets.get(&bucket_id).is_some()
+        }
+        Err(err) => {
+            error!(event = "failed to read bucket", bucket_id, err);
+            return Err(ApiError::internal());
+        }
+    };
+    if exists || state.access.accounts().owner(&bucket_id).is_some() {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            format!("bucket {bucket_id} exists"),
+        ));
+    }
+
+    let account = add_bucket(&state, &account_id, bucket_id).await?;
+    Ok(Json(account).into_response())
+}
+
+/// Handles listing the keys of an account, without the keys themselves
+#[utoipa::path(
+    get,
+    path = "/account/keys",
+    tag = "accounts",
+    responses(
+        (status = 200, description = "Keys of the account, oldest first", body = Vec<AccountKeyEntry>),
+        (status = 401, description = "Missing or invalid account key", body = ErrorBody),
+    ),
+    security(("account_key" = [])),
+)]
+async fn handle_list_keys(
+    KeyAccount(account_id): KeyAccount,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let keys = state.access.accounts().keys(&account_id);
+    let keys: Vec<AccountKeyEntry> = keys
+        .iter()
+        .map(|(key_id, key)| AccountKeyEntry {
+            key_id,
+            created_at: key.created_at,
+        })
+        .collect();
+
+    Ok(Json(keys).into_response())
+}
+
+/// Handles account key creation
+///
+/// The key is returned only in this response, the server stores its hash
+#[utoipa::path(
+    post,
+    path = "/account/keys",
+    tag = "accounts",
+    responses(
+        (status = 200, description = "`{\"key_id\", \"key\"}`, the key is not returned again", body = Object),
+        (status = 401, description = "Missing or invalid account key", body = ErrorBody),
+    ),
+    security(("account_key" = [])),
+)]
+async fn handle_create_key(
+    KeyAccount(account_id): KeyAccount,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let (key, key_id) = new_key();
+    let account_key = AccountKey {
+        account_id: account_id.clone(),
+        created_at: now(),
+    };
+
+    let _updates = state.access.lock_updates().await;
+    let res = state
+        .db
+        .read()
+        .await
+        .update_account_key(&key_id, &account_key);
+    if let Err(err) = res {
+        error!(event = "failed to persist account key", account_id, err);
+        return Err(ApiError::internal());
+    }
+    state
+        .access
+        .accounts()
+        .insert_key(key_id.clone(), account_key);
+
+    info!(event = "account key created", account_id, key_id);
+
+    Ok(Json(serde_json::json!({
+        "key_id": key_id,
+        "key": key,
+    }))
+    .into_response())
+}
+
+/// Handles account key revocation
+///
+/// The key carried by the request may be revoked. Returns `404 Not Found`
+/// if the account has no such key.
+#[utoipa::path(
+    delete,
+    path = "/account/keys/{key_id}",
+    tag = "accounts",
+    params(("key_id" = String, Path, description = "Id of the key")),
+    responses(
+        (status = 200, description = "Key revoked", body = String, content_type = "text/plain"),
+        (status = 401, description = "Missing or invalid account key", body = ErrorBody),
+        (status = 404, description = "The account has no such key", body = ErrorBody),
+    ),
+    security(("account_key" = [])),
+)]
+async fn handle_revoke_key(
+    KeyAccount(account_id): KeyAccount,
+    Path(key_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let _updates = state.access.lock_updates().await;
+    if state.access.accounts().key_account(&key_id).as_ref()
+        != Some(&account_id)
+    {
+        return Err(ApiError::new(
+            ErrorCode::NotFound,
+            format!("key {key_id} not found"),
+        ));
+    }
+
+    if let Err(err) = state.db.read().await.delete_account_key(&key_id) {
+        error!(event = "failed to delete account key", account_id, err);
+        return Err(ApiError::internal());
+    }
+    state.access.accounts().remove_key(&key_id);
+
+    info!(event = "account key revoked", account_id, key_id);
+
+    Ok("Key revoked".into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app;
+    use crate::blob_store::MemoryStore;
+    use crate::Config;
+    use axum::body::Body;
+    use axum::http::{Request, Response, StatusCode};
+    use clap::Parser;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+
+    #[tokio::test]
+    async fn test_accounts() {
+        let config = Config::parse_from([
+            "server",
+            "127.0.0.1:0",
+            "--admin-token",
+            "admin",
+        ]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = app::run_server_with_store(config, store).await;
+        tokio::spawn(server);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let request = |method: &str, path: &str, token: &str, body: String| {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("http://{addr}{path}"))
+                .header("authorization", format!("Bearer {token}"))
+                .body(Body::from(body))
+                .unwrap();
+            client.request(request)
+        };
+        let json = |res: Response<Incoming>| async move {
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let new_account = r#"{"account_id": "alice", "quota_bytes": 3}"#;
+        let res =
+            request("POST", "/admin/accounts", "admin", new_account.into())
+                .await
+                .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let key = json(res).await["key"].as_str().unwrap().to_owned();
+        let res =
+            request("POST", "/admin/accounts", "admin", new_account.into())
+                .await
+                .unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // The buckets of the account are closed to the other requests
+        let res = request("POST", "/account/buckets/a", &key, String::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("POST", "/upload_file/a/a.txt", "", "ab".into())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = request("POST", "/upload_file/a/a.txt", &key, "ab".into())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The quota covers all the buckets of the account
+        request("POST", "/account/buckets/b", &key, String::new())
+            .await
+            .unwrap();
+        let res = request("POST", "/upload_file/b/b.txt", &key, "cd".into())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res = request("POST", "/upload_file/b/b.txt", &key, "c".into())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = request("GET", "/account", &key, String::new())
+            .await
+            .unwrap();
+        let usage = json(res).await;
+        assert_eq!(usage["bytes_used"], 3);
+        assert_eq!(usage["buckets"][0]["bucket_id"], "a");
+        assert_eq!(usage["buckets"][1]["bytes_used"], 1);
+
+        // A bucket of another account or with files cannot be taken
+        let res = request("POST", "/account/buckets/a", &key, String::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // Keys are managed by the account
+        let res = request("POST", "/account/keys", &key, String::new())
+            .await
+            .unwrap();
+        let second = json(res).await;
+        let path = format!("/account/keys/{}", access::key_id(&key));
+        let res = request("DELETE", &path, &key, String::new()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("GET", "/account", &key, String::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let second = second["key"].as_str().unwrap();
+        let res = request("GET", "/file/a/0", second, String::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The buckets of a deleted account are open again
+        let res =
+            request("DELETE", "/admin/accounts/alice", "admin", "".into())
+                .await
+                .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("GET", "/file/a/0", "", String::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,787 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{middleware, Router};
+use clap::Args;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::access::{self, ApiKey, BucketLimits};
+use crate::accounts;
+use crate::app::{Eviction, ServerState};
+use crate::backup;
+use crate::errors::{ApiError, ErrorBody, ErrorCode};
+use crate::extract::{Json, Path};
+use crate::gc::{self, GcReport};
+use crate::lifecycle::LifecyclePolicy;
+use crate::naming::NamingPolicy;
+use crate::ownership;
+use crate::quota::Usage;
+use crate::replication::{self, ReplicaStatus};
+use crate::scrub::{self, ScrubStatus};
+use crate::throttle::BandwidthLimits;
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct AdminConfig {
+    /// Bearer token required by the /admin routes; they are disabled if unset
+    #[arg(long)]
+    pub admin_token: Option<String>,
+
+    /// Folder receiving the backups made with POST /admin/backup
+    #[arg(long, default_value = "./backups")]
+    pub backup_dir: String,
+
+    /// Reject the requests of every bucket not carrying an API key covering
+    /// it, instead of only those of the buckets named by a key
+    #[arg(long)]
+    pub require_api_key: bool,
+}
+
+/// Scopes of an API key to create
+#[derive(Debug, Deserialize, ToSchema)]
+struct NewKey {
+    /// Bucket ids or `<prefix>*` patterns the key grants access to
+    scopes: Vec<String>,
+}
+
+/// An API key as listed by GET /admin/keys
+#[derive(Debug, Serialize, ToSchema)]
+struct KeyEntry<'a> {
+    key_id: &'a str,
+    #[serde(flatten)]
+    key: &'a ApiKey,
+}
+
+/// A stored bucket as listed by GET /admin/buckets
+#[derive(Serialize, ToSchema)]
+struct BucketEntry {
+    #[serde(flatten)]
+    usage: Usage,
+    /// Whether the bucket is loaded in memory
+    resident: bool,
+}
+
+/// Admin routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(
+    handle_create_key,
+    handle_all_keys,
+    handle_list_keys,
+    handle_revoke_key,
+    handle_get_limits,
+    handle_set_limits,
+    handle_get_lifecycle,
+    handle_set_lifecycle,
+    handle_get_naming,
+    handle_set_naming,
+    handle_get_bandwidth,
+    handle_set_bandwidth,
+    handle_backup,
+    handle_gc,
+    handle_scrub_status,
+    handle_scrub,
+    handle_buckets,
+    handle_evict,
+    handle_replication,
+))]
+pub(crate) struct ApiDoc;
+
+/// Returns the /admin route group
+pub(crate) fn routes(config: AdminConfig, state: Arc<ServerState>) -> Router {
+    let backup_dir = config.backup_dir;
+    let admin = Router::new()
+        // Create an API key with several scopes
+        // POST /admin/keys
+        // List all the API keys
+        // GET /admin/keys
+        .route("/keys", post(handle_create_key).get(handle_all_keys))
+        // Create an API key scoped to a bucket
+        // POST /admin/keys/:bucket_id
+        // List the ids of the API keys covering a bucket
+        // GET /admin/keys/:bucket_id
+        // Revoke an API key, the parameter is the id of the key
+        // DELETE /admin/keys/:key_id
+        .route(
+            "/keys/:id",
+            post(|Path(bucket_id): Path<String>, state: State<_>| {
+                let new_key = NewKey {
+                    scopes: vec![bucket_id],
+                };
+                handle_create_key(state, Json(new_key))
+            })
+            .get(handle_list_keys)
+            .delete(handle_revoke_key),
+        )
+        // Get the limits of a bucket
+        // GET /admin/limits/:bucket_id
+        // Set the limits of a bucket
+        // PUT /admin/limits/:bucket_id
+        .route(
+            "/limits/:bucket_id",
+            get(handle_get_limits).put(handle_set_limits),
+        )
+        // Get the lifecycle policy of a bucket
+        // GET /admin/lifecycle/:bucket_id
+        // Set the lifecycle policy of a bucket
+        // PUT /admin/lifecycle/:bucket_id
+        .route(
+            "/lifecycle/:bucket_id",
+            get(handle_get_lifecycle).put(handle_set_lifecycle),
+        )
+        // Get the naming policy of a bucket
+        // GET /admin/naming/:bucket_id
+        // Set the naming policy of a bucket
+        // PUT /admin/naming/:bucket_id
+        .route(
+            "/naming/:bucket_id",
+            get(handle_get_naming).put(handle_set_naming),
+        )
+        // Get the byte rates of a bucket
+        // GET /admin/bandwidth/:bucket_id
+        // Set the byte rates of a bucket
+        // PUT /admin/bandwidth/:bucket_id
+        .route(
+            "/bandwidth/:bucket_id",
+            get(handle_get_bandwidth).put(handle_set_bandwidth),
+        )
+        // Back up the database and the blobs
+        // POST /admin/backup
+        .route(
+            "/backup",
+            post(move |State(state): State<Arc<ServerState>>| {
+                handle_backup(backup_dir.clone(), state)
+            }),
+        )
+        // Collect the orphaned blobs
+        // POST /admin/gc
+        .route("/gc", post(handle_gc))
+        // Get the outcome of the scrubber
+        // GET /admin/scrub
+        // Verify the stored blobs now
+        // POST /admin/scrub
+        .route("/scrub", get(handle_scrub_status).post(handle_scrub))
+        // List the stored buckets with their usage
+        // GET /admin/buckets
+        .route("/buckets", get(handle_buckets))
+        // Persist a bucket and drop it from memory
+        // POST /admin/evict/:bucket_id
+        .route("/evict/:bucket_id", post(handle_evict))
+        // Get the replication lag of the replicas
+        // GET /admin/replication
+        .route("/replication", get(handle_replication))
+        .with_state(state.clone())
+        .merge(ownership::admin_routes(state.clone()))
+        .merge(accounts::admin_routes(state))
+        .route_layer(middleware::from_fn_with_state(
+            config.admin_token,
+            access::bearer_auth,
+        ));
+
+    Router::new().nest("/admin", admin)
+}
+
+/// Handles API key creation
+///
+/// The key is returned only in this response, the server stores its hash.
+/// Returns `400 Bad Request` if no scope is given.
+#[utoipa::path(
+    post,
+    path = "/admin/keys",
+    tag = "admin",
+    request_body = NewKey,
+    responses(
+        (status = 200, description = "`{\"key_id\", \"key\", \"scopes\"}`, the key is not returned again", body = Object),
+        (status = 400, description = "No scope given", body = ErrorBody),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_create_key(
+    State(state): State<Arc<ServerState>>,
+    Json(new_key): Json<NewKey>,
+) -> Result<Response, ApiError> {
+    let scopes = new_key.scopes;
+    if scopes.is_empty() || scopes.iter().any(|scope| scope.is_empty()) {
+        return Err(ApiError::bad_request("missing scopes"));
+    }
+
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    let key = hex::encode(key);
+    let key_id = access::key_id(&key);
+
+    let record = ApiKey {
+        scopes: scopes.clone(),
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    };
+
+    let _updates = state.access.lock_updates().await;
+    if let Err(err) = state.db.read().await.update_api_key(&key_id, &record) {
+        error!(event = "failed to persist api key", ?scopes, err);
+        return Err(ApiError::internal());
+    }
+    state.access.insert_key(key_id.clone(), record);
+
+    info!(event = "api key created", ?scopes, key_id);
+
+    Ok(Json(serde_json::json!({
+        "key_id": key_id,
+        "key": key,
+        "scopes": scopes,
+    }))
+    .into_response())
+}
+
+/// Handles listing all the API keys, without the keys themselves
+#[utoipa::path(
+    get,
+    path = "/admin/keys",
+    tag = "admin",
+    responses(
+        (status = 200, description = "API keys, oldest first", body = Vec<KeyEntry>),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_all_keys(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let all_keys = state.access.keys();
+    let mut keys: Vec<KeyEntry> = all_keys
+        .iter()
+        .map(|(key_id, key)| KeyEntry { key_id, key })
+        .collect();
+    keys.sort_by_key(|entry| entry.key.created_at);
+
+    Ok(Json(keys).into_response())
+}
+
+/// Handles listing the ids of the API keys covering a bucket
+#[utoipa::path(
+    get,
+    path = "/admin/keys/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Ids of the API keys covering the bucket", body = Vec<String>),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_list_keys(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let key_ids = state.access.key_ids(&bucket_id);
+    Ok(Json(key_ids))
+}
+
+/// Handles API key revocation
+///
+/// Returns `404 Not Found` if the key does not exist
+#[utoipa::path(
+    delete,
+    path = "/admin/keys/{key_id}",
+    tag = "admin",
+    params(("key_id" = String, Path, description = "Id of the API key")),
+    responses(
+        (status = 200, description = "Key revoked", body = String, content_type = "text/plain"),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 404, description = "The key does not exist", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_revoke_key(
+    Path(key_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let _updates = state.access.lock_updates().await;
+    if let Err(err) = state.db.read().await.delete_api_key(&key_id) {
+        error!(event = "failed to delete api key", key_id, err);
+        return Err(ApiError::internal());
+    }
+
+    let key = state.access.remove_key(&key_id).ok_or_else(|| {
+        ApiError::new(ErrorCode::NotFound, format!("key {key_id} not found"))
+    })?;
+
+    info!(event = "api key revoked", scopes = ?key.scopes, key_id);
+
+    Ok("Key revoked".into_response())
+}
+
+/// Handles bucket limits request
+#[utoipa::path(
+    get,
+    path = "/admin/limits/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Limits of the bucket", body = BucketLimits),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_get_limits(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let limits = state.access.limits(&bucket_id);
+    Ok(Json(limits))
+}
+
+/// Handles bucket limits update
+#[utoipa::path(
+    put,
+    path = "/admin/limits/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    request_body = BucketLimits,
+    responses(
+        (status = 200, description = "Limits of the bucket", body = BucketLimits),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_set_limits(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    Json(limits): Json<BucketLimits>,
+) -> Result<Response, ApiError> {
+    let _updates = state.access.lock_updates().await;
+    if let Err(err) = state
+        .db
+        .read()
+        .await
+        .update_bucket_limits(&bucket_id, &limits)
+    {
+        error!(event = "failed to persist bucket limits", bucket_id, err);
+        return Err(ApiError::internal());
+    }
+
+    info!(event = "bucket limits updated", bucket_id, ?limits);
+    state.access.set_limits(bucket_id, limits.clone());
+
+    Ok(Json(limits).into_response())
+}
+
+/// Handles bucket lifecycle policy request
+#[utoipa::path(
+    get,
+    path = "/admin/lifecycle/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Lifecycle policy of the bucket", body = LifecyclePolicy),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_get_lifecycle(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let policy = state.db.read().await.lifecycle_policy(&bucket_id);
+    match policy {
+        Ok(policy) => Ok(Json(policy.unwrap_or_default()).into_response()),
+        Err(err) => {
+            error!(event = "failed to read lifecycle policy", bucket_id, err);
+            Err(ApiError::internal())
+        }
+    }
+}
+
+/// Handles bucket lifecycle policy update
+///
+/// A policy without `expire_after` is removed. It applies to the files
+/// uploaded from now on.
+#[utoipa::path(
+    put,
+    path = "/admin/lifecycle/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    request_body = LifecyclePolicy,
+    responses(
+        (status = 200, description = "Lifecycle policy of the bucket", body = LifecyclePolicy),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_set_lifecycle(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    Json(policy): Json<LifecyclePolicy>,
+) -> Result<Response, ApiError> {
+    let res = state.db.read().await.set_lifecycle_policy(
+        &bucket_id,
+        policy.expire_after.is_some().then_some(&policy),
+    );
+    if let Err(err) = res {
+        error!(event = "failed to persist lifecycle policy", bucket_id, err);
+        return Err(ApiError::internal());
+    }
+
+    info!(event = "bucket lifecycle updated", bucket_id, ?policy);
+    Ok(Json(policy).into_response())
+}
+
+/// Handles bucket naming policy request
+#[utoipa::path(
+    get,
+    path = "/admin/naming/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Naming policy of the bucket", body = NamingPolicy),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_get_naming(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let policy = state.db.read().await.naming_policy(&bucket_id);
+    match policy {
+        Ok(policy) => Ok(Json(policy).into_response()),
+        Err(err) => {
+            error!(event = "failed to read naming policy", bucket_id, err);
+            Err(ApiError::internal())
+        }
+    }
+}
+
+/// Handles bucket naming policy update
+///
+/// The default policy is removed. It applies to the files uploaded from now
+/// on, the files already named alike are kept.
+#[utoipa::path(
+    put,
+    path = "/admin/naming/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    request_body = NamingPolicy,
+    responses(
+        (status = 200, description = "Naming policy of the bucket", body = NamingPolicy),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_set_naming(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    Json(policy): Json<NamingPolicy>,
+) -> Result<Response, ApiError> {
+    let res = state.db.read().await.set_naming_policy(
+        &bucket_id,
+        (policy != NamingPolicy::default()).then_some(&policy),
+    );
+    if let Err(err) = res {
+        error!(event = "failed to persist naming policy", bucket_id, err);
+        return Err(ApiError::internal());
+    }
+
+    info!(event = "bucket naming updated", bucket_id, ?policy);
+    Ok(Json(policy).into_response())
+}
+
+/// Handles bucket byte rates request
+#[utoipa::path(
+    get,
+    path = "/admin/bandwidth/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Byte rates of the bucket", body = BandwidthLimits),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_get_bandwidth(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let limits = state.throttle.limits(&bucket_id);
+    Ok(Json(limits))
+}
+
+/// Handles bucket byte rates update
+///
+/// Limits without rates are removed. They apply to the uploads and
+/// downloads started from now on.
+#[utoipa::path(
+    put,
+    path = "/admin/bandwidth/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    request_body = BandwidthLimits,
+    responses(
+        (status = 200, description = "Byte rates of the bucket", body = BandwidthLimits),
+        (status = 400, description = "A rate is 0", body = ErrorBody),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_set_bandwidth(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    Json(limits): Json<BandwidthLimits>,
+) -> Result<Response, ApiError> {
+    if [limits.upload_rate, limits.download_rate].contains(&Some(0)) {
+        return Err(ApiError::bad_request(
+            "a rate must be at least 1 byte per second",
+        ));
+    }
+
+    let res = state.db.read().await.update_bandwidth_limits(
+        &bucket_id,
+        (limits != BandwidthLimits::default()).then_some(&limits),
+    );
+    if let Err(err) = res {
+        error!(event = "failed to persist bandwidth limits", bucket_id, err);
+        return Err(ApiError::internal());
+    }
+
+    info!(event = "bucket bandwidth updated", bucket_id, ?limits);
+    state.throttle.set_limits(bucket_id, limits.clone());
+
+    Ok(Json(limits).into_response())
+}
+
+/// Handles backup request
+///
+/// Replies with the path of the backup folder
+#[utoipa::path(
+    post,
+    path = "/admin/backup",
+    tag = "admin",
+    responses(
+        (status = 200, description = "`{\"path\"}`, the folder of the backup", body = Object),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_backup(
+    backup_dir: String,
+    state: Arc<ServerState>,
+) -> Result<Response, ApiError> {
+    match backup::create(&state, std::path::Path::new(&backup_dir)).await {
+        Ok(path) => Ok(Json(serde_json::json!({
+            "path": path,
+        }))
+        .into_response()),
+        Err(err) => {
+            error!(event = "failed to back up", backup_dir, ?err);
+            Err(ApiError::internal())
+        }
+    }
+}
+
+/// Handles garbage collection request
+///
+/// Replies with the report of the collection
+#[utoipa::path(
+    post,
+    path = "/admin/gc",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Report of the collection", body = GcReport),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_gc(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    match gc::collect(&state).await {
+        Ok(report) => Ok(Json(report).into_response()),
+        Err(err) => {
+            error!(event = "failed to collect garbage", ?err);
+            Err(ApiError::internal())
+        }
+    }
+}
+
+/// Handles scrubber status request
+///
+/// The number of corrupted blobs found since startup is the corruption
+/// metric to monitor
+#[utoipa::path(
+    get,
+    path = "/admin/scrub",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Outcome of the scrubber passes", body = ScrubStatus),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_scrub_status(
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let status = state.scrub_status.lock().expect("unpoisoned lock").clone();
+    Ok(Json(status))
+}
+
+/// Handles scrub request
+///
+/// Replies with the status once all the blobs are verified
+#[utoipa::path(
+    post,
+    path = "/admin/scrub",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Outcome of the pass", body = ScrubStatus),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_scrub(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    match scrub::scrub(&state).await {
+        Ok(status) => Ok(Json(status).into_response()),
+        Err(err) => {
+            error!(event = "failed to scrub blobs", ?err);
+            Err(ApiError::internal())
+        }
+    }
+}
+
+/// Handles bucket listing request
+///
+/// The usage of the buckets in memory is the current one, the others are
+/// read from the database without being loaded
+#[utoipa::path(
+    get,
+    path = "/admin/buckets",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Stored buckets, sorted by id", body = Vec<BucketEntry>),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_buckets(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let (bucket_ids, resident) = {
+        let stored = state.db.read().await.bucket_ids().map_err(|err| {
+            error!(event = "failed to list buckets", err);
+            ApiError::internal()
+        })?;
+        // Buckets created since they were last persisted are only in memory
+        let bucket_ids: BTreeSet<String> =
+            stored.into_iter().chain(state.buckets.keys()).collect();
+        let resident: BTreeMap<_, _> = bucket_ids
+            .iter()
+            .filter_map(|id| Some((id.clone(), state.buckets.get(id)?)))
+            .collect();
+        (bucket_ids, resident)
+    };
+
+    let mut entries = Vec::with_capacity(bucket_ids.len());
+    for bucket_id in bucket_ids {
+        let entry = match resident.get(&bucket_id) {
+            Some(bucket) => {
+                let bucket = bucket.read().await;
+                BucketEntry {
+                    usage: state.usage(&bucket),
+                    resident: true,
+                }
+            }
+            None => {
+                let bucket = state.db.read().await.read_bucket(&bucket_id);
+                match bucket {
+                    Ok(Some(bucket)) => BucketEntry {
+                        usage: state.usage(&bucket),
+                        resident: false,
+                    },
+                    // Deleted meanwhile
+                    Ok(None) => continue,
+                    Err(err) => {
+                        error!(event = "failed to read bucket", bucket_id, err);
+                        return Err(ApiError::internal());
+                    }
+                }
+            }
+        };
+        entries.push(entry);
+    }
+
+    Ok(Json(entries).into_response())
+}
+
+/// Handles bucket eviction request
+///
+/// The bucket is persisted and loaded again on its next access. Replies
+/// `{"evicted": false}` if it is not in memory, and returns `409 Conflict`
+/// if a request uses it.
+#[utoipa::path(
+    post,
+    path = "/admin/evict/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "`{\"evicted\"}`, whether the bucket was in memory", body = Object),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+        (status = 409, description = "A request uses the bucket", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_evict(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let evicted = match state.evict_bucket(&bucket_id).await {
+        Ok(Eviction::Evicted) => true,
+        Ok(Eviction::NotResident) => false,
+        Ok(Eviction::InUse) => {
+            return Err(ApiError::new(
+                ErrorCode::Conflict,
+                format!("bucket {bucket_id} is in use"),
+            ))
+        }
+        Err(err) => {
+            error!(event = "failed to evict bucket", bucket_id, err);
+            return Err(ApiError::internal());
+        }
+    };
+
+    Ok(Json(serde_json::json!({
+        "evicted": evicted,
+    }))
+    .into_response())
+}
+
+/// Handles replication lag request
+#[utoipa::path(
+    get,
+    path = "/admin/replication",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Replication lag of each replica of `--replica`", body = Vec<ReplicaStatus>),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_replication(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    match replication::status(&state).await {
+        Ok(status) => Ok(Json(status).into_response()),
+        Err(err) => {
+            error!(event = "failed to read replication queues", err);
+            Err(ApiError::internal())
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{Method, StatusCode};
+use axum::routing;
+use axum::Router;
+use http_body_util::BodyExt;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::app::ServerState;
+use crate::blobs;
+use crate::database::{RootRecord, DB};
+use crate::errors::{ApiError, ErrorBody};
+use crate::extract::{Json, Path};
+use crate::peers::PeerConfig;
+use crate::replication::{self, Applied, ReplicatedBucket};
+
+/// Last persisted root of a bucket, as exchanged between peers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub(crate) struct PeerRoot {
+    /// Hex encoded root
+    pub root: String,
+    /// Unix time in seconds of the update recording the root
+    pub timestamp: u64,
+}
+
+impl From<&RootRecord> for PeerRoot {
+    fn from(record: &RootRecord) -> Self {
+        PeerRoot {
+            root: hex::encode(record.root),
+            timestamp: record.timestamp,
+        }
+    }
+}
+
+/// Outcome of a reconciliation with a peer
+#[derive(Debug, Default)]
+struct Reconciliation {
+    /// Buckets whose state was taken from the peer
+    buckets: usize,
+    /// Blobs downloaded from the peer
+    blobs: usize,
+}
+
+/// Spawns the periodic reconciliation with the peers, if
+/// `--anti-entropy-interval` is set
+pub(crate) fn spawn(state: Arc<ServerState>, config: &PeerConfig) {
+    let Some(interval) = config.anti_entropy_interval.filter(|secs| *secs > 0)
+    else {
+        return;
+    };
+    let peers = config.peers.clone();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval));
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            for peer in &peers {
+                match sync_peer(&state, peer).await {
+                    Ok(sync) => info!(
+                        event = "peer reconciled",
+                        peer,
+                        buckets = sync.buckets,
+                        blobs = sync.blobs
+                    ),
+                    Err(err) => {
+                        warn!(event = "failed to reconcile peer", peer, ?err)
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Takes from a peer the buckets whose root there is more recent
+///
+/// The roots of all the buckets are compared first, so only the leaf sets of
+/// the diverging buckets are downloaded, then only the blobs this server
+/// lacks. Buckets this server does not have are left to the replication,
+/// so a bucket deleted here is not brought back by a peer.
+async fn sync_peer(
+    state: &Arc<ServerState>,
+    peer: &str,
+) -> io::Result<Reconciliation> {
+    let (peers, store) = { (state.peers.clone(), state.store.clone()) };
+    let mut sync = Reconciliation::default();
+
+    let remote: BTreeMap<String, PeerRoot> =
+        get(&peers, &format!("{peer}/peer/roots")).await?;
+    let local = {
+        let db = state.db.read().await;
+        peer_roots(&db).map_err(io::Error::other)?
+    };
+
+    for bucket_id in outdated(&local, &remote) {
+        let url = format!("{peer}/peer/buckets/{bucket_id}");
+        let replicated: ReplicatedBucket = get(&peers, &url).await?;
+        let files = replicated
+            .decode_files()
+            .ok_or_else(|| io::Error::other("invalid file hash"))?;
+
+        let mut applied = replication::apply_bucket(
+            state,
+            &bucket_id,
+            files.clone(),
+            replicated.bytes_used,
+            Some(peer.to_owned()),
+        )
+        .await;
+        if let Ok(Applied::Missing(missing)) = &applied {
+            for hash in missing {
+                let tmp_key = blobs::replica_key(hash);
+                let fetched =
+                    peers.fetch_blob_from(peer, &*store, hash, &tmp_key).await;
+                if !matches!(fetched, Ok(true)) {
+                    let _ = store.delete(&tmp_key).await;
+                    let key = blobs::blob_key(hash);
+                    return Err(io::Error::other(format!("{key} not fetched")));
+                }
+                sync.blobs += 1;
+            }
+            applied = replication::apply_bucket(
+                state,
+                &bucket_id,
+                files,
+                replicated.bytes_used,
+                Some(peer.to_owned()),
+            )
+            .await;
+        }
+
+        match applied {
+            Ok(Applied::Done(root)) => {
+                let root = root.root;
+                info!(event = "bucket reconciled", peer, bucket_id, root);
+                replication::enqueue(state, &bucket_id).await;
+                sync.buckets += 1;
+            }
+            // Released by the peer meanwhile, taken at the next pass
+            Ok(Applied::Missing(_)) => {
+                warn!(event = "bucket changed during reconciliation", bucket_id)
+            }
+            Err(()) => {
+                error!(event = "failed to reconcile bucket", peer, bucket_id)
+            }
+        }
+    }
+
+    Ok(sync)
+}
+
+/// Returns the buckets present on both sides whose last root differs and is
+/// more recent on the peer
+///
+/// Ties on the timestamp are broken by the root, so both sides agree on the
+/// state to keep.
+fn outdated(
+    local: &BTreeMap<String, PeerRoot>,
+    remote: &BTreeMap<String, PeerRoot>,
+) -> Vec<String> {
+    remote
+        .iter()
+        .filter(|(bucket_id, remote)| {
+            local.get(*bucket_id).is_some_and(|local| {
+                // A seal records the same root again with a later timestamp
+                remote.root != local.root
+                    && (remote.timestamp, &remote.root)
+                        > (local.timestamp, &local.root)
+            })
+        })
+        .map(|(bucket_id, _)| bucket_id.clone())
+        .collect()
+}
+
+fn peer_roots(db: &DB) -> Result<BTreeMap<String, PeerRoot>, String> {
+    Ok(db
+        .last_roots()?
+        .iter()
+        .map(|(bucket_id, record)| (bucket_id.clone(), record.into()))
+        .collect())
+}
+
+/// Sends a GET request to a peer and parses its JSON reply
+async fn get<T: DeserializeOwned>(
+    peers: &PeerConfig,
+    url: &str,
+) -> io::Result<T> {
+    let req = peers
+        .request(Method::GET, url)
+        .body(Body::empty())
+        .map_err(io::Error::other)?;
+    let res = Client::builder(TokioExecutor::new())
+        .build_http()
+        .request(req)
+        .await
+        .map_err(io::Error::other)?;
+    if res.status() != StatusCode::OK {
+        return Err(io::Error::other(format!("status {}", res.status())));
+    }
+
+    let body = res
+        .into_body()
+        .collect()
+        .await
+        .map_err(io::Error::other)?
+        .to_bytes();
+    serde_json::from_slice(&body).map_err(io::Error::other)
+}
+
+/// Reconciliation routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_roots, handle_bucket,))]
+pub(crate) struct ApiDoc;
+
+/// Returns the routes serving the reconciliations of the peers, nested in
+/// the /peer route group
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    Router::new()
+        // Last root of every bucket
+        // GET /peer/roots
+        .route("/peer/roots", routing::get(handle_roots))
+        // Files of a bucket
+        // GET /peer/buckets/:bucket_id
+        .route("/peer/buckets/:bucket_id", routing::get(handle_bucket))
+        .with_state(state)
+}
+
+/// Handles the roots request of a peer
+#[utoipa::path(
+    get,
+    path = "/peer/roots",
+    tag = "peer",
+    responses(
+        (status = 200, description = "Last root of every bucket", body = BTreeMap<String, PeerRoot>),
+    ),
+    security(("peer_token" = [])),
+)]
+async fn handle_roots(
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<BTreeMap<String, PeerRoot>>, ApiError> {
+    let db = state.db.read().await;
+    match peer_roots(&db) {
+        Ok(roots) => Ok(Json(roots)),
+        Err(err) => {
+            error!(event = "failed to read roots", err);
+            Err(ApiError::internal())
+        }
+    }
+}
+
+/// Handles the bucket request of a peer
+///
+/// Returns `404 Not Found` if the bucket does not exist
+#[utoipa::path(
+    get,
+    path = "/peer/buckets/{bucket_id}",
+    tag = "peer",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Files of the bucket", body = ReplicatedBucket),
+        (status = 404, description = "The bucket does not exist", body = ErrorBody),
+    ),
+    security(("peer_token" = [])),
+)]
+async fn handle_bucket(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<ReplicatedBucket>, ApiError> {
+    let loaded = state.bucket(&bucket_id).await.map_err(|err| {
+        error!(event = "failed to load bucket", bucket_id, err);
+        ApiError::internal()
+    })?;
+    let Some(bucket) = loaded else {
+        return Err(ApiError::bucket_not_found(&bucket_id));
+    };
+
+    let replicated = ReplicatedBucket::from(&*bucket.read().await);
+    Ok(Json(replicated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_root(root: u8, timestamp: u64) -> PeerRoot {
+        PeerRoot {
+            root: hex::encode([root; 32]),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test_outdated() {
+        let local = BTreeMap::from([
+            ("same".to_string(), peer_root(1, 10)),
+            ("resealed".to_string(), peer_root(1, 10)),
+            ("newer".to_string(), peer_root(1, 10)),
+            ("older".to_string(), peer_root(1, 10)),
+            ("tie".to_string(), peer_root(1, 10)),
+            ("local_only".to_string(), peer_root(1, 10)),
+        ]);
+        let remote = BTreeMap::from([
+            ("same".to_string(), peer_root(1, 10)),
+            ("resealed".to_string(), peer_root(1, 11)),
+            ("newer".to_string(), peer_root(2, 11)),
+            ("older".to_string(), peer_root(2, 9)),
+            ("tie".to_string(), peer_root(2, 10)),
+            ("remote_only".to_string(), peer_root(1, 10)),
+        ]);
+
+        assert_eq!(outdated(&local, &remote), vec!["newer", "tie"]);
+        assert_eq!(outdated(&remote, &local), vec!["older"]);
+    }
+}
@@ -1,249 +1,1954 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
 
 use std::sync::Arc;
+use std::time::Instant;
 
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, FromRequestParts, State};
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{middleware, Router};
+use bytes::Buf;
 use sha2::{Digest, Sha256};
 use std::net::SocketAddr;
-use tokio::fs;
-use tokio::sync::RwLock;
-use tracing::{error, info};
-use warp::Filter;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, Mutex, Notify, RwLock};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{error, info, info_span, warn};
+use utoipa::{IntoParams, OpenApi, ToSchema};
 
-use crate::{client_bucket::ClientBucket, database::DB};
+use crate::access::{self, AccessControl, Operation};
+use crate::accounts::{self, Accounts};
+use crate::admin;
+use crate::anti_entropy;
+use crate::attestation::{self, Attestor};
+use crate::audit::{self, AuditOp, AuditRecord, Peer};
+use crate::backup;
+use crate::batch::{self, SessionToken, UploadBatches};
+use crate::blob_store::BlobStore;
+use crate::blobs;
+use crate::capabilities::Capabilities;
+use crate::client_bucket::{BucketRoot, BucketState, ClientBucket};
+use crate::cluster::{self, Cluster};
+use crate::compression::{self, BodyDecoder, DecodeError};
+use crate::copy;
+use crate::cors;
+use crate::database::{JournalEntry, DB};
+use crate::dav;
+use crate::disk::{self, DiskStatus};
+use crate::erasure;
+use crate::errors::{self, ApiError, ErrorBody, ErrorCode};
+use crate::events::{self, Event};
+use crate::export;
+use crate::extract::{self, Json, Path, Query};
+use crate::file_meta::{self, FileMeta, UploadMeta};
+use crate::gc::{self, GcConfig};
+use crate::grpc;
+use crate::health;
+use crate::http3;
+use crate::jwt;
+use crate::lifecycle;
+use crate::limits::{self, Concurrency, LimitsConfig, Work};
+use crate::listener;
+use crate::lru::Lru;
+use crate::metrics::{self, Metrics};
+use crate::naming::{self, DuplicateNames, Placement};
+use crate::openapi;
+use crate::ownership::{self, Action, Nonces, Signed};
+use crate::peers::{self, PeerConfig};
+use crate::presign::{self, Presigner};
+use crate::quota::{QuotaConfig, Usage};
+use crate::replication::{self, ReplicaProgress};
+use crate::resumable::{self, UploadSessions};
+use crate::scrub::{self, ScrubConfig, ScrubStatus};
+use crate::sharded_map::ShardedMap;
+use crate::shutdown;
+use crate::telemetry;
+use crate::throttle::Throttle;
+use crate::tls;
+use crate::versions::{self, VersionQuery};
+use crate::webhooks::{self, Webhooks};
+use crate::Config;
+
+/// Default number of entries of a list page
+const LIST_DEFAULT_LIMIT: usize = 100;
+/// Maximum number of entries of a list page
+const LIST_MAX_LIMIT: usize = 1000;
+
+/// Maximum number of proofs of a batch proof request
+pub(crate) const MAX_BATCH_PROOFS: usize = 1024;
 
-#[derive(Clone)]
 pub struct ServerState {
     /// Map a Bucket id to a (MerkleTree, files) pair
-    buckets: HashMap<String, Arc<RwLock<ClientBucket>>>,
-    db: Arc<RwLock<DB>>,
+    ///
+    /// The state itself is shared without a lock: its fields updated at
+    /// runtime lock themselves, as the shards of this map do. A shard is
+    /// only locked to clone the handle of a bucket, never while locking the
+    /// bucket.
+    pub(crate) buckets: ShardedMap<String, Arc<RwLock<ClientBucket>>>,
+    pub(crate) db: Arc<RwLock<DB>>,
+    /// Storage of the file contents
+    pub(crate) store: Arc<dyn BlobStore>,
+    /// Files of at most this size are stored in the database
+    pub(crate) inline_threshold: u64,
+    /// Folder of the files stored with the legacy per-bucket layout
+    pub(crate) uploads_dir: PathBuf,
+    pub(crate) quota: QuotaConfig,
+    /// Collection of the orphaned blobs
+    pub(crate) gc: GcConfig,
+    /// Verification of the stored blobs
+    pub(crate) scrub: ScrubConfig,
+    pub(crate) scrub_status: std::sync::Mutex<ScrubStatus>,
+    /// Servers holding copies of the blobs
+    pub(crate) peers: PeerConfig,
+    /// Wakes the replication tasks up when a bucket is queued
+    pub(crate) replication: Arc<Notify>,
+    /// Outcome of the last push to each replica
+    pub(crate) replication_status:
+        std::sync::Mutex<BTreeMap<String, ReplicaProgress>>,
+    /// Nodes sharing the buckets with this one, if any
+    pub(crate) cluster: Option<Cluster>,
+    /// API keys and per-bucket limits
+    pub(crate) access: AccessControl,
+    /// Resumable uploads in progress
+    pub(crate) uploads: UploadSessions,
+    /// Upload sessions of the buckets, see `begin_upload`
+    pub(crate) batches: UploadBatches,
+    /// Nonces of the recent requests signed by bucket owners
+    pub(crate) nonces: Nonces,
+    /// Signs the pre-signed download URLs
+    pub(crate) presigner: Presigner,
+    /// Signs the roots of the buckets with the identity key of the server
+    pub(crate) attestor: Attestor,
+    /// Upload size limit and timeouts
+    pub(crate) limits: LimitsConfig,
+    /// Permits of the disk writes, Merkle rebuilds and proof generations
+    pub(crate) concurrency: Concurrency,
+    /// Byte rates of the uploaded and downloaded bodies
+    pub(crate) throttle: Throttle,
+    /// Whether the disk is too full to accept uploads
+    pub(crate) disk: DiskStatus,
+    /// Bucket events waiting for delivery to the webhooks
+    pub(crate) webhooks: Webhooks,
+    /// Bucket events streamed to the subscribers of `/events`
+    pub(crate) events: broadcast::Sender<Event>,
+    /// Request latencies and Merkle tree recomputations, see `/metrics`
+    pub(crate) metrics: Metrics,
+    /// Serializes the evictions of buckets, and holds the buckets being
+    /// loaded from the database and those being deleted, see [`Pending`]
+    loading: Mutex<HashMap<String, Pending>>,
+    /// Order of use of the buckets in memory
+    recent: std::sync::Mutex<Lru<String>>,
+    /// Number of buckets kept in memory
+    max_resident_buckets: usize,
+    /// Number of versions of each bucket whose files are kept
+    pub(crate) keep_versions: usize,
+}
+
+/// A bucket missing from memory for a while, see `ServerState::loading`
+enum Pending {
+    /// Read from the database by a request, the other requests for the
+    /// bucket wait for the lock instead of reading it again
+    Load(Arc<Mutex<()>>),
+    /// Deleted, neither loaded nor created again until it is erased
+    Delete,
 }
 
 impl ServerState {
-    fn load_buckets_from_db() -> Self {
-        //  Load buckets from the database
-        let db = DB::create_or_open("./db");
-        let buckets = db.read_all_buckets().expect("bucket is persisted");
-
-        let buckets = buckets
-            .into_iter()
-            .map(|(bucket_id, mut bucket)| {
-                info!(
-                    event = "load bucket from db",
-                    bucket_id,
-                    files_count = bucket.files.len()
-                );
-                bucket.calculate_merkle_tree();
-                (bucket_id, Arc::new(RwLock::new(bucket)))
-            })
-            .collect();
+    /// Buckets are loaded from the database on first access, see
+    /// [`ServerState::bucket`]
+    pub(crate) async fn open(
+        config: &Config,
+        db: DB,
+        store: Arc<dyn BlobStore>,
+    ) -> Self {
+        let db = db.with_metadata_key(config.metadata_key);
+        blobs::remove_tmp(&*store).await;
+        replay_journal(&db, &*store).await;
+
+        let access = AccessControl::new(
+            db.read_all_api_keys().expect("api keys are persisted"),
+            db.read_all_bucket_limits().expect("limits are persisted"),
+        )
+        .with_accounts(Accounts::new(
+            db.read_all_accounts().expect("accounts are persisted"),
+            db.read_all_account_keys()
+                .expect("account keys are persisted"),
+        ))
+        .with_client_certs(config.tls.client_certs.clone())
+        .with_required_api_key(config.admin.require_api_key)
+        .with_jwt(jwt::verifier(&config.jwt).await);
+        let throttle = Throttle::new(
+            &config.limits,
+            db.read_all_bandwidth_limits()
+                .expect("bandwidth limits are persisted"),
+        );
 
         ServerState {
-            buckets,
+            buckets: ShardedMap::new(),
             db: Arc::new(RwLock::new(db)),
+            store,
+            inline_threshold: config.inline_threshold,
+            uploads_dir: PathBuf::from(&config.uploads_dir),
+            quota: config.quota.clone(),
+            gc: config.gc.clone(),
+            scrub: config.scrub.clone(),
+            scrub_status: std::sync::Mutex::default(),
+            peers: config.peers.clone(),
+            replication: Arc::new(Notify::new()),
+            replication_status: std::sync::Mutex::default(),
+            cluster: Cluster::new(&config.cluster),
+            access,
+            uploads: UploadSessions::new(),
+            batches: UploadBatches::new(),
+            nonces: Nonces::default(),
+            presigner: Presigner::new(&config.presign),
+            attestor: Attestor::new(&config.attestation),
+            limits: config.limits.clone(),
+            concurrency: Concurrency::new(&config.limits),
+            throttle,
+            disk: DiskStatus::default(),
+            webhooks: Webhooks::new(&config.webhooks),
+            events: events::channel(),
+            metrics: Metrics::default(),
+            loading: Mutex::default(),
+            recent: std::sync::Mutex::new(Lru::new()),
+            max_resident_buckets: config.max_resident_buckets,
+            keep_versions: config.keep_versions,
+        }
+    }
+
+    /// Returns a bucket, loading it from the database on first access
+    ///
+    /// Returns `None` if the bucket is neither loaded nor stored, an error if
+    /// its record cannot be read
+    pub(crate) async fn bucket(
+        &self,
+        bucket_id: &String,
+    ) -> Result<Option<Arc<RwLock<ClientBucket>>>, String> {
+        if let Some(bucket) = self.buckets.get(bucket_id) {
+            self.touch(bucket_id);
+            return Ok(Some(bucket));
+        }
+
+        // Only marking the bucket as loading holds the lock, the database is
+        // read without it
+        let load = loop {
+            let mut loading = self.loading.lock().await;
+
+            // Another request may have loaded the bucket meanwhile
+            if let Some(bucket) = self.buckets.get(bucket_id) {
+                self.touch(bucket_id);
+                return Ok(Some(bucket));
+            }
+            match loading.get(bucket_id) {
+                Some(Pending::Delete) => return Ok(None),
+                Some(Pending::Load(load)) => {
+                    let load = load.clone();
+                    drop(loading);
+                    // Look again once the other request is done
+                    drop(load.lock().await);
+                }
+                None => {
+                    let load = Arc::new(Mutex::new(()));
+                    let guard = load.clone().lock_owned().await;
+                    loading.insert(bucket_id.clone(), Pending::Load(load));
+                    break guard;
+                }
+            }
+        };
+
+        let bucket = self.load_bucket(bucket_id).await;
+
+        let mut loading = self.loading.lock().await;
+        loading.remove(bucket_id);
+        drop(load);
+        let Some((bucket, restored)) = bucket? else {
+            return Ok(None);
+        };
+        let bucket = self.buckets.get_or_insert_with(bucket_id.clone(), || {
+            Arc::new(RwLock::new(bucket))
+        });
+        self.touch(bucket_id);
+        drop(loading);
+
+        if restored {
+            verify_tree(bucket_id.clone(), bucket.clone(), self.db.clone());
+        }
+        self.evict_idle_buckets().await;
+
+        Ok(Some(bucket))
+    }
+
+    /// Reads a bucket from the database, moving its legacy files to the
+    /// blob store
+    ///
+    /// Returns `None` if the bucket is not stored, otherwise the bucket and
+    /// whether its tree was restored rather than rebuilt. A migration failing
+    /// to persist is logged, the bucket is persisted again once evicted.
+    async fn load_bucket(
+        &self,
+        bucket_id: &String,
+    ) -> Result<Option<(ClientBucket, bool)>, String> {
+        let read = self.db.read().await.read_bucket(bucket_id)?;
+        let Some(mut bucket) = read else {
+            return Ok(None);
+        };
+        if blobs::migrate_bucket(
+            &*self.store,
+            &self.db,
+            &mut bucket,
+            self.inline_threshold,
+            &self.uploads_dir,
+        )
+        .await
+        {
+            let db = self.db.write().await;
+            if let Err(err) = db.update_bucket(&bucket) {
+                error!(
+                    event = "failed to persist migrated bucket",
+                    bucket_id, err
+                );
+            }
+        }
+        // A tree failing to read is rebuilt like a missing one
+        let tree = self.db.read().await.read_tree(&bucket);
+        let tree = tree.unwrap_or_else(|err| {
+            error!(event = "failed to read tree", bucket_id, err);
+            None
+        });
+
+        info!(
+            event = "load bucket from db",
+            bucket_id,
+            files_count = bucket.files.len(),
+            stored_tree = tree.is_some()
+        );
+
+        // Rebuilding the tree hashes all the files, the stored one is only
+        // verified once the bucket is served
+        let restored = tree.is_some();
+        match tree {
+            Some(tree) => bucket.merkle_tree = tree,
+            None => bucket.calculate_merkle_tree(),
         }
+
+        Ok(Some((bucket, restored)))
+    }
+
+    /// Publishes a bucket event to the webhooks and the `/events` streams
+    pub(crate) fn notify(&self, event: Option<Event>) {
+        let Some(event) = event else {
+            return;
+        };
+        // Fails only when nobody is subscribed
+        let _ = self.events.send(event.clone());
+        self.webhooks.notify(event);
+    }
+
+    /// Drops a bucket from memory before it is erased
+    ///
+    /// Returns false if the bucket is not in memory. Otherwise the bucket is
+    /// neither loaded nor created again until [`ServerState::erased_bucket`]
+    /// is called, so that no request changes it while it is erased.
+    pub(crate) async fn unload_bucket(&self, bucket_id: &String) -> bool {
+        let mut loading = self.loading.lock().await;
+        if self.buckets.remove(bucket_id).is_none() {
+            return false;
+        }
+        self.recent
+            .lock()
+            .expect("unpoisoned lock")
+            .remove(bucket_id);
+        loading.insert(bucket_id.clone(), Pending::Delete);
+
+        true
+    }
+
+    /// Lets a bucket unloaded by [`ServerState::unload_bucket`] be loaded or
+    /// created again, once it is erased or failed to be
+    pub(crate) async fn erased_bucket(&self, bucket_id: &String) {
+        self.loading.lock().await.remove(bucket_id);
+    }
+
+    /// Erases a bucket unloaded by [`ServerState::unload_bucket`] from the
+    /// database and releases the blobs of its files
+    ///
+    /// The bucket is left empty and deleted, so that the requests still
+    /// holding it neither change it nor release its blobs again. Blobs
+    /// shared with other buckets are kept.
+    pub(crate) async fn erase_bucket(
+        &self,
+        bucket: &mut ClientBucket,
+        peer: Option<String>,
+    ) -> Result<(), String> {
+        let bucket_id = bucket.bucket_id.clone();
+        let db = self.db.write().await;
+        db.delete_bucket(&bucket_id).and_then(|_| db.flush())?;
+        audit::record(
+            &db,
+            &bucket_id,
+            AuditRecord::new(AuditOp::DeleteBucket, peer),
+        );
+
+        bucket.state = BucketState::Deleted;
+        bucket.bytes_used = 0;
+        for file_hash in std::mem::take(&mut bucket.files).keys() {
+            if let Err(err) = blobs::release(&*self.store, &db, file_hash).await
+            {
+                let hash = hex::encode(file_hash);
+                error!(event = "failed to release blob", bucket_id, hash, ?err);
+            }
+        }
+        bucket.calculate_merkle_tree();
+
+        Ok(())
+    }
+
+    /// Marks a bucket as the most recently used one
+    fn touch(&self, bucket_id: &String) {
+        self.recent
+            .lock()
+            .expect("unpoisoned lock")
+            .touch(bucket_id);
+    }
+
+    /// Evicts the least recently used buckets beyond `max_resident_buckets`
+    ///
+    /// An evicted bucket is persisted and dropped from memory, it is loaded
+    /// again on its next access. Buckets used by a request are skipped.
+    async fn evict_idle_buckets(&self) {
+        let candidates: Vec<String> = {
+            let recent = self.recent.lock().expect("unpoisoned lock");
+            let excess = recent.len().saturating_sub(self.max_resident_buckets);
+            recent.oldest().take(excess).cloned().collect()
+        };
+        if candidates.is_empty() {
+            return;
+        }
+
+        // The bucket must not be loaded again before it is persisted
+        let _loading = self.loading.lock().await;
+
+        for bucket_id in candidates {
+            if let Err(err) = self.evict_locked(&bucket_id).await {
+                error!(event = "failed to evict bucket", bucket_id, err);
+            }
+        }
+    }
+
+    /// Evicts a bucket now, see the admin API
+    pub(crate) async fn evict_bucket(
+        &self,
+        bucket_id: &String,
+    ) -> Result<Eviction, String> {
+        let _loading = self.loading.lock().await;
+        self.evict_locked(bucket_id).await
     }
 
-    /// Persists the bucket to the database
-    async fn persist_bucket_lockless(
+    /// Persists a bucket and drops it from memory unless a request uses it
+    ///
+    /// Callers hold `loading`. A bucket failing to persist stays in memory.
+    async fn evict_locked(
+        &self,
+        bucket_id: &String,
+    ) -> Result<Eviction, String> {
+        // Only the map refers to an idle bucket, no request can reach it once
+        // it is removed
+        let bucket = self
+            .buckets
+            .remove_if(bucket_id, |bucket| Arc::strong_count(bucket) == 1);
+
+        let Some(bucket) = bucket else {
+            if self.buckets.get(bucket_id).is_some() {
+                return Ok(Eviction::InUse);
+            }
+            self.recent
+                .lock()
+                .expect("unpoisoned lock")
+                .remove(bucket_id);
+            return Ok(Eviction::NotResident);
+        };
+
+        let res = self.persist_bucket_lockless(&*bucket.read().await).await;
+        if let Err(err) = res {
+            // Keep the bucket in memory rather than losing its changes
+            self.buckets.insert(bucket_id.clone(), bucket);
+            return Err(err);
+        }
+
+        self.recent
+            .lock()
+            .expect("unpoisoned lock")
+            .remove(bucket_id);
+        info!(event = "bucket evicted", bucket_id);
+
+        Ok(Eviction::Evicted)
+    }
+
+    /// Returns the usage of a bucket compared to its effective quota
+    ///
+    /// A quota set through the admin API overrides the server-wide one
+    pub(crate) fn usage(&self, bucket: &ClientBucket) -> Usage {
+        let quota_bytes = self
+            .access
+            .limits(&bucket.bucket_id)
+            .quota_bytes
+            .or(self.quota.quota_bytes);
+
+        Usage::new(bucket, quota_bytes, self.quota.quota_files)
+    }
+
+    /// Persists the bucket to the database, recording its version
+    ///
+    /// A failure to record the version is logged, the bucket is persisted.
+    #[tracing::instrument(
+        name = "persist bucket",
+        skip_all,
+        fields(bucket_id = bucket.bucket_id.as_str())
+    )]
+    pub(crate) async fn persist_bucket_lockless(
         &self,
         bucket: &ClientBucket,
     ) -> Result<(), String> {
-        let db_handle = self.db.read().await;
-        db_handle.update_bucket(bucket)?;
-        db_handle.flush()
-    }
-}
-
-pub async fn run_server(addr: &str) {
-    let state = Arc::new(RwLock::new(ServerState::load_buckets_from_db()));
-
-    // File upload_file
-    // POST /upload/:bucket_id/:filename
-    let upload = warp::path("upload_file")
-        .and(warp::post())
-        .and(warp::path::param())
-        .and(warp::path::param())
-        .and(warp::body::bytes())
-        .and(with_state(state.clone()))
-        .and_then(handle_upload_file);
-
-    // File complete_upload
-    // POST /upload/:bucket_id/
-    let complete_upload = warp::path("complete_upload")
-        .and(warp::post())
-        .and(warp::path::param())
-        .and(with_state(state.clone()))
-        .and_then(handle_complete_upload);
-
-    // File request
-    // GET /file/:bucket_id/:file_index
-    let download = warp::path("file")
-        .and(warp::get())
-        .and(warp::path::param())
-        .and(warp::path::param())
-        .and(with_state(state.clone()))
-        .and_then(handle_download_file);
-
-    // Proof request
-    // GET /proof/:bucket_id/:file_index
-    let proof = warp::path("proof")
-        .and(warp::get())
-        .and(warp::path::param())
-        .and(warp::path::param())
-        .and(with_state(state.clone()))
-        .and_then(handle_download_proof);
-
-    let addr: SocketAddr = addr.parse().expect("parsable address");
-
-    warp::serve(upload.or(complete_upload).or(download).or(proof))
-        .run(addr)
-        .await;
-}
-
-fn with_state(
-    state: Arc<RwLock<ServerState>>,
-) -> impl Filter<
-    Extract = (Arc<RwLock<ServerState>>,),
-    Error = std::convert::Infallible,
-> + Clone {
-    warp::any().map(move || state.clone())
+        // A request holding a bucket from before its deletion must not
+        // store it again
+        if bucket.state == BucketState::Deleted {
+            return Err("bucket deleted".to_owned());
+        }
+
+        {
+            let db_handle = self.db.read().await;
+            db_handle.update_bucket(bucket)?;
+            db_handle.flush()?;
+        }
+
+        if let Err(err) = versions::record(self, bucket).await {
+            let bucket_id = &bucket.bucket_id;
+            error!(event = "failed to record version", bucket_id, err);
+        }
+        Ok(())
+    }
 }
 
+/// Outcome of the eviction of a bucket
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Eviction {
+    Evicted,
+    /// The bucket is not in memory
+    NotResident,
+    /// A request uses the bucket, it stays in memory
+    InUse,
+}
+
+/// Verifies in the background the stored Merkle tree a bucket was loaded
+/// with, see [`DB::read_tree`]
+///
+/// A tree with a node which is not the hash of its children is rebuilt from
+/// the files, and stored again by the next update of the bucket.
+fn verify_tree(
+    bucket_id: String,
+    bucket: Arc<RwLock<ClientBucket>>,
+    db: Arc<RwLock<DB>>,
+) {
+    tokio::spawn(async move {
+        let tree = bucket.read().await.merkle_tree.clone();
+        let valid = tokio::task::spawn_blocking(move || tree.verify())
+            .await
+            .unwrap_or(false);
+        if valid {
+            return;
+        }
+
+        error!(event = "stored merkle tree is corrupted", bucket_id);
+        let mut bucket = bucket.write().await;
+        bucket.calculate_merkle_tree();
+        if let Err(err) = db.read().await.delete_tree(&bucket_id) {
+            error!(event = "failed to delete tree", bucket_id, err);
+        }
+    });
+}
+
+/// Adds the files journaled by `upload_file` to their buckets
+///
+/// They were uploaded before a shutdown which prevented their bucket from
+/// being persisted. Their blobs were referenced on upload, files whose blob
+/// is missing are dropped.
+async fn replay_journal(db: &DB, store: &dyn BlobStore) {
+    let files = db.journaled_files().expect("journal is persisted");
+
+    let mut buckets: BTreeMap<String, ClientBucket> = BTreeMap::new();
+    for (bucket_id, hash, entry) in files {
+        let bucket = buckets.entry(bucket_id.clone()).or_insert_with(|| {
+            db.read_bucket(&bucket_id)
+                .expect("bucket is persisted")
+                .unwrap_or_else(|| ClientBucket::new(bucket_id.clone()))
+        });
+        if bucket.files.contains_key(&hash) {
+            continue;
+        }
+
+        if !matches!(blobs::size(store, db, &hash).await, Ok(Some(_))) {
+            let filename = entry.filename;
+            warn!(event = "journaled blob missing", bucket_id, filename);
+            continue;
+        }
+        bucket.files.insert(hash, entry.filename);
+        bucket.bytes_used += entry.size;
+    }
+
+    for (bucket_id, mut bucket) in buckets {
+        bucket.calculate_merkle_tree();
+        db.update_bucket(&bucket).expect("bucket is persisted");
+        info!(
+            event = "journal replayed",
+            bucket_id,
+            files_count = bucket.files.len()
+        );
+    }
+    db.flush().expect("database is flushed");
+}
+
+pub async fn run_server(config: Config) {
+    let store = erasure::store(&config.blobs_dir, &config.erasure)
+        .expect("erasure coding is valid");
+    if let Some(backup) = &config.restore_from {
+        let db_path = DB::backend_path(&config.db_dir);
+        backup::restore(std::path::Path::new(backup), &db_path, &*store)
+            .await
+            .expect("backup is restored");
+    }
+
+    let db = DB::create_or_open(&config.db_dir);
+    let state = Arc::new(ServerState::open(&config, db, store).await);
+    gc::spawn(state.clone(), &config.gc);
+    scrub::spawn(state.clone(), &config.scrub);
+    replication::spawn(state.clone(), &config.peers);
+    resumable::spawn(state.clone(), &config.limits);
+    anti_entropy::spawn(state.clone(), &config.peers);
+    jwt::spawn(state.clone(), &config.jwt);
+    lifecycle::spawn(state.clone(), &config.lifecycle);
+    webhooks::spawn(state.clone(), &config.webhooks);
+    let dirs = [&config.db_dir, &config.blobs_dir]
+        .into_iter()
+        .chain(&config.erasure.erasure_dir)
+        .cloned()
+        .collect();
+    disk::spawn(state.clone(), &config.disk, dirs);
+
+    let (addr, server) = serve(config, state);
+    info!(event = "listening", %addr);
+    server.await;
+}
+
+/// Binds the server to `config.listen_addr`, with the file contents in
+/// `store` and the records in an in-memory database
+///
+/// Nothing is written to disk, which lets tests and embedding applications
+/// run the full API. The address may use port 0 to get an ephemeral port.
+/// Returns the bound address and the future running the server.
+pub async fn run_server_with_store(
+    config: Config,
+    store: Arc<dyn BlobStore>,
+) -> (SocketAddr, impl Future<Output = ()>) {
+    let state =
+        Arc::new(ServerState::open(&config, DB::in_memory(), store).await);
+    gc::spawn(state.clone(), &config.gc);
+    scrub::spawn(state.clone(), &config.scrub);
+    replication::spawn(state.clone(), &config.peers);
+    resumable::spawn(state.clone(), &config.limits);
+    anti_entropy::spawn(state.clone(), &config.peers);
+    jwt::spawn(state.clone(), &config.jwt);
+    lifecycle::spawn(state.clone(), &config.lifecycle);
+    webhooks::spawn(state.clone(), &config.webhooks);
+
+    serve(config, state)
+}
+
+/// Binds the routes to `config.listen_addr`, over HTTPS if `--tls-cert` and
+/// `--tls-key` are set, see [`listener::bind`]
+///
+/// The returned future runs the server until SIGTERM or SIGINT, then shuts
+/// it down gracefully, see [`shutdown::run`].
+fn serve(
+    config: Config,
+    state: Arc<ServerState>,
+) -> (SocketAddr, Pin<Box<dyn Future<Output = ()> + Send>>) {
+    let addr: SocketAddr =
+        config.listen_addr.parse().expect("parsable address");
+    let tls = config.tls.clone();
+    let grpc = config.grpc.clone();
+    let http3 = config.http3.clone();
+    let limits = config.limits.clone();
+    let timeout = config.shutdown.timeout();
+    let (trigger, stopped) = shutdown::trigger();
+    let routes = routes(config, state.clone());
+    let http3 = http3::serve(&http3, &tls, routes.clone(), trigger.subscribe());
+
+    let acceptor = tls
+        .tls_cert
+        .is_some()
+        .then(|| tls::acceptor(&tls).expect("valid TLS"));
+    let (addr, server) =
+        listener::bind(addr, acceptor, &limits, routes, stopped);
+
+    // The gRPC and HTTP/3 listeners, if any, shut down along with the HTTP
+    // one
+    let server: Pin<Box<dyn Future<Output = ()> + Send>> =
+        match grpc::serve(&grpc, &tls, state.clone(), trigger.subscribe()) {
+            Some(grpc) => Box::pin(async move {
+                tokio::join!(server, grpc);
+            }),
+            None => Box::pin(server),
+        };
+    let server: Pin<Box<dyn Future<Output = ()> + Send>> = match http3 {
+        Some(http3) => Box::pin(async move {
+            tokio::join!(server, http3);
+        }),
+        None => server,
+    };
+
+    let server = shutdown::run(state, timeout, trigger, server);
+    (addr, Box::pin(server))
+}
+
+/// Returns all the routes of the API
+pub(crate) fn routes(config: Config, state: Arc<ServerState>) -> Router {
+    // Read requests are answered within `--request-timeout`
+    let read_timeout = config.limits.request_timeout();
+    let timeout =
+        || middleware::from_fn_with_state(read_timeout, limits::timeout);
+    let access = |operation| {
+        middleware::from_fn_with_state(
+            (state.clone(), operation),
+            access::bucket_access,
+        )
+    };
+
+    let writes = Router::new()
+        // File upload_file
+        // POST /upload_file/:bucket_id/:filename
+        .route(
+            "/upload_file/:bucket_id/:filename",
+            post(handle_upload_file),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            config.limits.max_upload_size,
+            limits::content_length_limit,
+        ))
+        // File complete_upload
+        // POST /complete_upload/:bucket_id
+        .route("/complete_upload/:bucket_id", post(handle_complete_upload))
+        .route_layer(access(Operation::Write));
+
+    let deletes = Router::new()
+        // File deletion
+        // DELETE /file/:bucket_id/:file_index
+        .route("/file/:bucket_id/:file_index", delete(handle_delete_file))
+        // Bucket deletion
+        // DELETE /bucket/:bucket_id
+        .route("/bucket/:bucket_id", delete(handle_delete_bucket))
+        .route_layer(access(Operation::Delete));
+
+    // File request, answered without credentials for a pre-signed URL
+    // GET /file/:bucket_id/:file_index?version=
+    let download = Router::new()
+        .route(
+            "/file/:bucket_id/:file_index",
+            get(
+                |Path((bucket_id, file_index)): Path<(String, String)>,
+                 Query(query): Query<VersionQuery>,
+                 State(state): State<Arc<ServerState>>| {
+                    handle_download_file(
+                        bucket_id,
+                        file_index,
+                        query.version,
+                        state,
+                    )
+                },
+            ),
+        )
+        .route_layer(timeout())
+        .route_layer(access(Operation::Read))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            presign::presigned_download,
+        ));
+
+    let reads = Router::new()
+        // Merkle root of a bucket
+        // GET /root/:bucket_id?version=
+        .route("/root/:bucket_id", get(handle_root))
+        // Bucket usage statistics
+        // GET /stats/:bucket_id
+        .route("/stats/:bucket_id", get(handle_stats))
+        .route_layer(timeout())
+        .route_layer(access(Operation::Read));
+
+    // Proofs, trees and listings are compressed if the client accepts it,
+    // unlike the file contents which are encrypted
+    let compressed = Router::new()
+        // Proof request
+        // GET /proof/:bucket_id/:file_index
+        .route("/proof/:bucket_id/:file_index", get(handle_download_proof))
+        // Proof request by the hash of the file
+        // GET /proof_by_hash/:bucket_id/:hex_hash
+        .route(
+            "/proof_by_hash/:bucket_id/:hex_hash",
+            get(handle_download_proof_by_hash),
+        )
+        // Roots recorded for a bucket
+        // GET /roots/:bucket_id
+        .route("/roots/:bucket_id", get(handle_roots))
+        // Serialized Merkle tree of a bucket
+        // GET /tree/:bucket_id
+        .route("/tree/:bucket_id", get(handle_tree))
+        // List files of a bucket
+        // GET /list/:bucket_id?offset=&limit=
+        .route("/list/:bucket_id", get(handle_list_files))
+        // Search files of a bucket by filename or metadata prefix
+        // GET /search/:bucket_id?prefix=&key=&offset=&limit=
+        .route("/search/:bucket_id", get(handle_search_files))
+        // Batch proof request, the body is a JSON array of file indices
+        // POST /proofs/:bucket_id
+        .route(
+            "/proofs/:bucket_id",
+            post(handle_download_proofs)
+                .route_layer(DefaultBodyLimit::max(64 * 1024)),
+        )
+        .route_layer(timeout())
+        .route_layer(access(Operation::Read))
+        .with_state(state.clone())
+        .merge(versions::routes(state.clone(), read_timeout))
+        .route_layer(middleware::from_fn(compression::encode_replies));
+
+    // Server capabilities
+    // GET /capabilities
+    let current = Arc::new(Capabilities::current(&config));
+    let capabilities = Router::new().route(
+        "/capabilities",
+        get(move || async move { handle_capabilities(&current) }),
+    );
+
+    let local = writes
+        .merge(deletes)
+        .merge(download)
+        .merge(reads)
+        .with_state(state.clone())
+        .merge(compressed)
+        .merge(capabilities)
+        .merge(presign::routes(state.clone()))
+        .merge(file_meta::routes(state.clone(), read_timeout))
+        .merge(dav::routes(state.clone()))
+        .merge(openapi::routes())
+        .merge(health::routes(state.clone()))
+        .merge(metrics::routes(state.clone()))
+        .merge(attestation::routes(state.clone()))
+        .merge(ownership::routes(state.clone()))
+        .merge(audit::routes(state.clone()))
+        .merge(events::routes(state.clone()))
+        .merge(resumable::routes(state.clone()))
+        .merge(batch::routes(state.clone()))
+        .merge(copy::routes(state.clone()))
+        .merge(export::routes(state.clone()))
+        .merge(accounts::routes(state.clone()))
+        .merge(peers::routes(config.peers, state.clone()))
+        .merge(admin::routes(config.admin, state.clone()))
+        .fallback(|| async { ApiError::new(ErrorCode::NotFound, "not found") })
+        .method_not_allowed_fallback(|| async {
+            ApiError::new(ErrorCode::MethodNotAllowed, "method not allowed")
+        });
+
+    // Requests of buckets owned by other nodes go to their owner. Each
+    // request runs in a span continuing the trace of the client, if any.
+    // The bodies are limited by the routes themselves
+    let routes = local
+        .layer(DefaultBodyLimit::disable())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            cluster::forward,
+        ))
+        .layer(middleware::from_fn_with_state(state, metrics::instrument))
+        .layer(middleware::from_fn(errors::handle))
+        .layer(middleware::from_fn(telemetry::trace));
+
+    // Error replies carry the CORS headers too, for browsers to read them
+    match cors::cors(&config.cors) {
+        Some(cors) => routes.layer(middleware::from_fn_with_state(
+            Arc::new(cors),
+            cors::handle,
+        )),
+        None => routes,
+    }
+}
+
+/// Handles capabilities request
+#[utoipa::path(
+    get,
+    path = "/capabilities",
+    tag = "server",
+    responses(
+        (status = 200, description = "Features supported by the server", body = Capabilities),
+    ),
+)]
+fn handle_capabilities(capabilities: &Capabilities) -> Response {
+    Json(capabilities).into_response()
+}
+
+/// Bucket and file routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(
+    handle_upload_file,
+    handle_complete_upload,
+    handle_download_file,
+    handle_delete_file,
+    handle_delete_bucket,
+    handle_download_proof,
+    handle_download_proof_by_hash,
+    handle_download_proofs,
+    handle_root,
+    handle_roots,
+    handle_tree,
+    handle_list_files,
+    handle_search_files,
+    handle_stats,
+    handle_capabilities,
+))]
+pub(crate) struct ApiDoc;
+
 /// Handles handle_complete_upload request
 ///
 /// Completes a async-upload of bucket of files by calculating the Merkle tree
+/// and closes the upload session of the request, if any
+#[utoipa::path(
+    post,
+    path = "/complete_upload/{bucket_id}",
+    tag = "files",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+        ("X-Upload-Session" = Option<String>, Header, description = "Token of the upload session, required while the bucket has one"),
+        ("X-Signature" = Option<String>, Header, description = "Signature of the owner of the bucket, required on owned buckets"),
+        ("X-Signature-Nonce" = Option<String>, Header, description = "Nonce covered by the signature, `<unix seconds>:<random>`"),
+    ),
+    responses(
+        (status = 200, description = "Merkle root of the bucket", body = BucketRoot),
+        (status = 401, description = "Missing or invalid credentials or owner signature", body = ErrorBody),
+        (status = 404, description = "The upload session does not exist or expired", body = ErrorBody),
+        (status = 409, description = "Another upload session of the bucket is open, or the bucket is sealed and the request has no session", body = ErrorBody),
+        (status = 503, description = "Too many concurrent Merkle rebuilds, retry after `Retry-After` seconds", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
 async fn handle_complete_upload(
+    Path(bucket_id): Path<String>,
+    SessionToken(session): SessionToken,
+    signed: Signed,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let root =
+        complete_upload(bucket_id, session.as_deref(), &signed, peer, state)
+            .await?;
+
+    Ok(Json(root))
+}
+
+/// Calculates the Merkle tree of a bucket and persists it
+///
+/// The request must be signed by the owner of the bucket, if it has one, and
+/// carry the token of its upload session, if it has one, which is closed.
+/// The bucket is sealed, its next uploads need a new session. Returns the
+/// new root of the bucket.
+pub(crate) async fn complete_upload(
     bucket_id: String,
-    state: Arc<RwLock<ServerState>>,
-) -> Result<impl warp::Reply, warp::Rejection> {
+    session: Option<&str>,
+    signed: &Signed,
+    peer: Option<String>,
+    state: Arc<ServerState>,
+) -> Result<BucketRoot, ApiError> {
+    let _permit = {
+        ownership::verify(
+            &state,
+            Action::Complete,
+            &bucket_id,
+            "",
+            None,
+            signed,
+        )
+        .await?;
+        state.concurrency.acquire(Work::Merkle)?
+    };
+
     let bucket: Arc<RwLock<ClientBucket>> =
-        get_or_create_bucket(bucket_id.clone(), state.clone()).await;
+        get_or_create_bucket(bucket_id.clone(), state.clone()).await?;
 
     let mut bucket = bucket.write().await;
 
-    let bucket_dir =
-        bucket.get_or_create_dir().await.expect("valid bucket dir");
-    info!(request = "complete upload", bucket_dir);
+    info!(request = "complete upload", bucket_id);
 
-    bucket.calculate_merkle_tree();
+    batch::check(&state, &mut bucket, session).await?;
+    batch::commit(&state, &bucket_id, session);
+
+    let previous_root = bucket.merkle_tree.root_hash();
+    let started = Instant::now();
+    info_span!("merkle tree", bucket_id)
+        .in_scope(|| bucket.calculate_merkle_tree());
+    let elapsed = started.elapsed();
+    let leaves_count = bucket.merkle_tree.leaves_count();
+    state.metrics.observe_merkle(elapsed, leaves_count);
 
-    if let Some(root) = bucket.merkle_tree.root_hash() {
-        let root_hex = hex::encode(root);
-        info!(event = "complete upload", bucket_id, root = root_hex);
+    let root = bucket.merkle_tree.root_hash().map(hex::encode);
+    if let Some(root_hex) = &root {
+        info!(
+            event = "complete upload",
+            bucket_id,
+            root = root_hex,
+            leaves_count,
+            merkle_ms = elapsed.as_millis() as u64
+        );
     }
 
-    info!(event = "persist new bucket state");
+    // Further uploads need a new session
+    bucket.state = BucketState::Sealed;
     state
+        .db
         .read()
         .await
+        .record_seal(&bucket)
+        .expect("seal is recorded");
+
+    info!(event = "persist new bucket state");
+    state
         .persist_bucket_lockless(&bucket)
         .await
         .expect("bucket is persisted");
+    let record = AuditRecord::new(AuditOp::CompleteUpload, peer)
+        .with_root(bucket.merkle_tree.root_hash());
+    audit::record(&*state.db.write().await, &bucket_id, record);
+    replication::enqueue(&state, &bucket_id).await;
 
-    Ok(warp::reply::with_status(
-        "File upload completed",
-        warp::http::StatusCode::OK,
-    ))
+    let root = bucket.merkle_tree.root_hash();
+    state.notify(Some(Event::UploadCompleted {
+        bucket_id: bucket_id.clone(),
+        root: root.map(hex::encode),
+        leaves_count: bucket.merkle_tree.leaves_count(),
+    }));
+    state.notify(Event::root_changed(&bucket_id, previous_root, root));
+
+    Ok(attestation::attested_root(&state, &bucket).await)
 }
 
 /// Handles file upload request
 ///
-/// Duplicated files per a bucket are not allowed
+/// Duplicated files per a bucket are refused with `409 Conflict`. A gzip or zstd
+/// `Content-Encoding` body is decompressed before it is hashed and stored.
+/// The body is streamed to the blob store chunk by chunk, so the file is
+/// never held in memory as a whole. Returns `413 Payload Too Large` if the
+/// file exceeds `--max-upload-size`, `408 Request Timeout` if the body is
+/// not received within `--upload-timeout`. A file over the quota of the
+/// bucket is refused with its current usage.
+#[utoipa::path(
+    post,
+    path = "/upload_file/{bucket_id}/{filename}",
+    tag = "files",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+        ("filename" = String, Path, description = "Name of the file"),
+        ("Content-Encoding" = Option<String>, Header, description = "`gzip` or `zstd` if the body is compressed"),
+        ("X-Expire-After" = Option<u64>, Header, description = "Seconds the file is kept"),
+        ("X-Upload-Session" = Option<String>, Header, description = "Token of the upload session, required while the bucket has one"),
+        ("X-Meta-<key>" = Option<String>, Header, description = "Client metadata of the file, see `/meta`"),
+        ("X-Tags" = Option<String>, Header, description = "Comma separated tags of the file, 16 at most, see `/list`"),
+        ("X-Signature" = Option<String>, Header, description = "Signature of the owner of the bucket, required on owned buckets"),
+        ("X-Signature-Nonce" = Option<String>, Header, description = "Nonce covered by the signature, `<unix seconds>:<random>`"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "File uploaded", body = UploadedFile),
+        (status = 400, description = "Client metadata over 2 KiB or not UTF-8, or invalid tags", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials or owner signature", body = ErrorBody),
+        (status = 408, description = "The body was not received within `--upload-timeout`", body = ErrorBody),
+        (status = 404, description = "The upload session does not exist or expired", body = ErrorBody),
+        (status = 409, description = "The file is already in the bucket, another file has its name and the naming policy rejects duplicates, another upload session of the bucket is open, or the bucket is sealed and the request has no session", body = ErrorBody),
+        (status = 413, description = "The file exceeds `--max-upload-size` or the byte quota of the bucket or of its account", body = ErrorBody),
+        (status = 503, description = "Too many concurrent writes, retry after `Retry-After` seconds", body = ErrorBody),
+        (status = 507, description = "The disk is over `--disk-high-watermark`", body = ErrorBody),
+        (status = 415, description = "Unsupported `Content-Encoding`", body = ErrorBody),
+        (status = 429, description = "The file exceeds the file quota of the bucket", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
 async fn handle_upload_file(
+    Path((bucket_id, filename)): Path<(String, String)>,
+    options: UploadOptions,
+    signed: Signed,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+    body: Body,
+) -> Result<Json<UploadedFile>, ApiError> {
+    info!(request = "upload", bucket_id, filename);
+
+    // Refuse upfront the uploads to a full bucket, the size of the file is
+    // checked once it is received
+    if let Some(bucket) = get_bucket(bucket_id.clone(), state.clone()).await {
+        let bucket = bucket.read().await;
+        let usage = state.usage(&bucket);
+        if let Some(exceeded) = usage.exceeded_by(0) {
+            return Err(exceeded.error(&usage));
+        }
+    }
+
+    // Receive the body in a temporary blob, without holding the bucket lock
+    let (store, limits, body, _permit) = {
+        state.disk.check_writable()?;
+        let permit = state.concurrency.acquire(Work::Write)?;
+        let body = state.throttle.upload(&bucket_id, body.into_data_stream());
+        (state.store.clone(), state.limits.clone(), body, permit)
+    };
+    let tmp_key = blobs::tmp_key();
+    let received = tokio::time::timeout(
+        limits.upload_timeout(),
+        receive_body(
+            &*store,
+            &tmp_key,
+            options.content_encoding.as_deref(),
+            limits.max_upload_size,
+            body,
+        ),
+    )
+    .await
+    .unwrap_or(Err(ReceiveError::Timeout));
+    let (file_hash, file_size) = match received {
+        Ok(received) => received,
+        Err(err) => {
+            let _ = store.delete(&tmp_key).await;
+
+            error!(event = "failed to upload", filename, bucket_id, error = %err);
+            return Err(ApiError::from(err));
+        }
+    };
+
+    let file = ReceivedFile {
+        filename,
+        tmp_key: &tmp_key,
+        hash: file_hash,
+        size: file_size,
+        expire_after: options.expire_after,
+        session: options.session,
+        meta: options.meta,
+        duplicates: None,
+    };
+    let uploaded = store_file(bucket_id, file, &signed, peer, state).await?;
+
+    Ok(Json(uploaded))
+}
+
+/// Reply of an upload request
+#[derive(serde::Serialize, ToSchema)]
+pub(crate) struct UploadedFile {
+    /// Hash of the file, the leaf of the file in the Merkle tree
+    pub hash: String,
+    /// Index of the file in the bucket, addressing its downloads and proofs
+    /// once the upload is completed. Later uploads and deletions may shift it
+    pub index: usize,
+    /// Name of the file in the bucket, suffixed if the naming policy of the
+    /// bucket renames duplicates
+    pub filename: String,
+    /// Whether the Merkle root of the bucket does not cover the file yet,
+    /// until `complete_upload`
+    pub bucket_root_pending: bool,
+}
+
+/// Optional headers of an upload request
+struct UploadOptions {
+    /// Compression of the body, gzip or zstd
+    content_encoding: Option<String>,
+    /// Seconds the file is kept, given by `X-Expire-After`
+    expire_after: Option<u64>,
+    /// Token of the upload session, given by `X-Upload-Session`
+    session: Option<String>,
+    /// Content type and client metadata of the file
+    meta: UploadMeta,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for UploadOptions {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Self, ApiError> {
+        let content_encoding =
+            extract::optional_header(&parts.headers, "content-encoding")?;
+        let expire_after =
+            extract::optional_header(&parts.headers, "x-expire-after")?;
+        let SessionToken(session) =
+            SessionToken::from_request_parts(parts, state).await?;
+        let meta = UploadMeta::from_request_parts(parts, state).await?;
+        Ok(UploadOptions {
+            content_encoding,
+            expire_after,
+            session,
+            meta,
+        })
+    }
+}
+
+/// A file received in a temporary blob, to be stored in a bucket
+pub(crate) struct ReceivedFile<'a> {
+    pub filename: String,
+    /// Temporary blob holding the content of the file
+    pub tmp_key: &'a str,
+    pub hash: [u8; 32],
+    pub size: u64,
+    /// Seconds the file is kept, given by `X-Expire-After`
+    pub expire_after: Option<u64>,
+    /// Token of the upload session of the file, given by `X-Upload-Session`
+    pub session: Option<String>,
+    /// Content type and client metadata of the file
+    pub meta: UploadMeta,
+    /// Handling of another file of the same name, the naming policy of the
+    /// bucket if `None`
+    pub duplicates: Option<DuplicateNames>,
+}
+
+/// Moves a fully received file into its bucket
+///
+/// The file must be signed by the owner of the bucket, if it has one, and
+/// fit in its quota, and belong to the upload session of the bucket, if it
+/// has one. The blob at `tmp_key` is removed if it cannot be
+/// stored. Without `expire_after`, the file expires after the retention of
+/// the lifecycle policy of the bucket, if any. Returns the hash and the index
+/// of the file.
+#[tracing::instrument(
+    name = "store file",
+    skip_all,
+    fields(bucket_id, filename = file.filename.as_str())
+)]
+pub(crate) async fn store_file(
     bucket_id: String,
-    filename: String,
-    body: bytes::Bytes,
-    state: Arc<RwLock<ServerState>>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let bucket: Arc<RwLock<ClientBucket>> =
-        get_or_create_bucket(bucket_id.clone(), state.clone()).await;
+    file: ReceivedFile<'_>,
+    signed: &Signed,
+    peer: Option<String>,
+    state: Arc<ServerState>,
+) -> Result<UploadedFile, ApiError> {
+    let ReceivedFile {
+        filename,
+        tmp_key,
+        hash: file_hash,
+        size: file_size,
+        expire_after,
+        session,
+        meta,
+        duplicates,
+    } = file;
+
+    let bucket =
+        match get_or_create_bucket(bucket_id.clone(), state.clone()).await {
+            Ok(bucket) => bucket,
+            Err(err) => {
+                let _ = state.store.delete(tmp_key).await;
+
+                return Err(err);
+            }
+        };
 
     let mut bucket = bucket.write().await;
 
-    let bucket_dir =
-        bucket.get_or_create_dir().await.expect("valid bucket dir");
+    // The owner is verified under the bucket lock, which its registration
+    // holds as well
+    let verified = ownership::verify(
+        &state,
+        Action::Upload,
+        &bucket_id,
+        &filename,
+        Some(&file_hash),
+        signed,
+    )
+    .await;
+    if let Err(err) = verified {
+        let _ = state.store.delete(tmp_key).await;
 
-    info!(request = "upload", bucket_dir, filename);
+        return Err(err);
+    }
 
-    let file_hash = Sha256::digest(&body).into();
+    if let Err(err) =
+        batch::check(&state, &mut bucket, session.as_deref()).await
+    {
+        let _ = state.store.delete(tmp_key).await;
+
+        return Err(err);
+    }
 
     // Check if file already exists in the bucket
     if bucket.files.contains_key(&file_hash) {
+        let _ = state.store.delete(tmp_key).await;
+
         let reply = "file already uploaded";
         error!(event = "failed to upload", filename, bucket_id, reply);
 
-        return Ok(warp::reply::with_status(
-            reply,
-            warp::http::StatusCode::BAD_REQUEST,
-        ));
+        return Err(ApiError::new(ErrorCode::Conflict, reply));
     }
 
-    // Save the file on disk
-    let file_path: String = format!("{}/{}", bucket_dir, filename);
-    if let Err(err) = fs::write(file_path.clone(), body).await {
-        error!(event = "Failed to write file", filename, bucket_id, error = ?err);
+    let (filename, replaced) =
+        match place_file(&state, &bucket, filename, duplicates).await {
+            Ok(placed) => placed,
+            Err(err) => {
+                let _ = state.store.delete(tmp_key).await;
 
-        return Ok(warp::reply::with_status(
-            "Failed to write file",
-            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-        ));
+                return Err(err);
+            }
+        };
+    // A replaced file frees its bytes
+    let replaced_size = match &replaced {
+        Some(hash) => {
+            let db = state.db.read().await;
+            blobs::size(&*state.store, &db, hash)
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_default()
+        }
+        None => 0,
+    };
+    let growth = file_size.saturating_sub(replaced_size);
+
+    let usage = state.usage(&bucket);
+    if let Some(exceeded) = usage.exceeded_by(growth) {
+        let _ = state.store.delete(tmp_key).await;
+
+        warn!(
+            event = "quota exceeded",
+            filename,
+            bucket_id,
+            quota = ?exceeded,
+            file_size
+        );
+        return Err(exceeded.error(&usage));
     }
+    if let Err(err) = accounts::check_quota(&state, &bucket, growth).await {
+        let _ = state.store.delete(tmp_key).await;
 
-    bucket.files.insert(file_hash, file_path.clone());
+        return Err(err);
+    }
 
-    info!(event = "file uploaded", file_path, bucket_id, filename);
+    // The blob is brought into its stored form without the database lock,
+    // then moved in place, unless another bucket stores the same content,
+    // and the file journaled until the bucket is persisted. Only these hold
+    // the write lock of the database, so the garbage collector sees the
+    // reference together with the file.
+    let staged = match blobs::stage(
+        &*state.store,
+        tmp_key,
+        state.inline_threshold,
+    )
+    .await
+    {
+        Ok(staged) => staged,
+        Err(err) => {
+            error!(event = "Failed to write file", filename, bucket_id, error = ?err);
+            let _ = state.store.delete(tmp_key).await;
 
-    Ok(warp::reply::with_status(
-        "File uploaded",
-        warp::http::StatusCode::OK,
-    ))
+            return Err(ApiError::internal());
+        }
+    };
+    let res = {
+        let db = state.db.write().await;
+        let refs =
+            blobs::add_ref(&*state.store, &db, &file_hash, &staged).await;
+
+        let entry = JournalEntry {
+            filename: filename.clone(),
+            size: file_size,
+        };
+        if refs.is_ok() {
+            if let Err(err) = db.journal_file(&bucket_id, &file_hash, &entry) {
+                error!(
+                    event = "failed to journal file",
+                    filename, bucket_id, err
+                );
+            }
+        }
+        refs
+    };
+    staged.discard(&*state.store).await;
+    let refs = match res {
+        Ok(refs) => refs,
+        Err(err) => {
+            error!(event = "Failed to write file", filename, bucket_id, error = ?err);
+
+            return Err(ApiError::internal());
+        }
+    };
+
+    {
+        let db = state.db.read().await;
+        let expire_after = expire_after.or_else(|| {
+            db.lifecycle_policy(&bucket_id)
+                .ok()
+                .flatten()
+                .and_then(|policy| policy.expire_after)
+        });
+        let expires_at = lifecycle::expires_at(expire_after);
+        if let Err(err) = db.set_expiration(&bucket_id, &file_hash, expires_at)
+        {
+            error!(
+                event = "failed to set file expiration",
+                filename, bucket_id, err
+            );
+        }
+
+        let meta = FileMeta::new(file_size, meta);
+        if let Err(err) = db.set_file_meta(&bucket_id, &file_hash, &meta) {
+            error!(event = "failed to set file meta", filename, bucket_id, err);
+        }
+
+        let record = AuditRecord::new(AuditOp::Upload, peer.clone())
+            .with_file(&filename, &file_hash)
+            .with_root(bucket.merkle_tree.root_hash());
+        audit::record(&db, &bucket_id, record);
+    }
+
+    let bytes_before = bucket.bytes_used;
+    let replaced = match replaced {
+        Some(hash) => take_file(&state, &mut bucket, &hash)
+            .await
+            .map(|filename| (hash, filename)),
+        None => None,
+    };
+    bucket.files.insert(file_hash, filename.clone());
+    batch::record(&state, &bucket_id, session.as_deref(), file_hash);
+
+    bucket.bytes_used += file_size;
+
+    state.quota.alert(&state.usage(&bucket), bytes_before);
+
+    // The files are ordered by hash, as the leaves of the tree
+    let index = bucket.files.range(..file_hash).count();
+
+    let blob_key = blobs::blob_key(&file_hash);
+    info!(
+        event = "file uploaded",
+        blob_key, refs, bucket_id, filename, index
+    );
+
+    let hash = hex::encode(file_hash);
+    state.notify(Some(Event::FileUploaded {
+        bucket_id: bucket_id.clone(),
+        filename: filename.clone(),
+        hash: hash.clone(),
+    }));
+
+    // The leaf of the replaced file is replaced in the tree right away, as
+    // by a deletion
+    let bucket_root_pending = match replaced {
+        Some((replaced, replaced_name)) => {
+            let previous_root = bucket.merkle_tree.root_hash();
+            bucket.calculate_merkle_tree();
+            if let Err(err) = state.persist_bucket_lockless(&bucket).await {
+                error!(event = "failed to persist bucket", bucket_id, err);
+            }
+            info!(event = "file replaced", bucket_id, filename);
+            release_file(&state, &bucket, &replaced, replaced_name, peer).await;
+            replication::enqueue(&state, &bucket_id).await;
+
+            let root = bucket.merkle_tree.root_hash();
+            state.notify(Event::root_changed(&bucket_id, previous_root, root));
+            false
+        }
+        None => true,
+    };
+
+    Ok(UploadedFile {
+        hash,
+        index,
+        filename,
+        bucket_root_pending,
+    })
+}
+
+/// Returns the name of a file in its bucket and the hash of the file it
+/// replaces, if any, following `duplicates` or the naming policy of the
+/// bucket
+pub(crate) async fn place_file(
+    state: &ServerState,
+    bucket: &ClientBucket,
+    filename: String,
+    duplicates: Option<DuplicateNames>,
+) -> Result<(String, Option<[u8; 32]>), ApiError> {
+    let duplicates = match duplicates {
+        Some(duplicates) => duplicates,
+        None => {
+            let policy = state.db.read().await.naming_policy(&bucket.bucket_id);
+            match policy {
+                Ok(policy) => policy.duplicates,
+                Err(err) => {
+                    let bucket_id = &bucket.bucket_id;
+                    error!(
+                        event = "failed to read naming policy",
+                        bucket_id, err
+                    );
+                    return Err(ApiError::internal());
+                }
+            }
+        }
+    };
+
+    Ok(match naming::place(bucket, filename, duplicates)? {
+        Placement::New(filename) => (filename, None),
+        Placement::Replace(filename, hash) => (filename, Some(hash)),
+    })
+}
+
+#[derive(Debug)]
+pub(crate) enum ReceiveError {
+    Decode(DecodeError),
+    Body(Box<dyn std::error::Error + Send + Sync>),
+    Io(std::io::Error),
+    /// The content exceeds `--max-upload-size`
+    TooLarge,
+    /// The body was not received within `--upload-timeout`
+    Timeout,
+}
+
+impl std::fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReceiveError::Decode(err) => write!(f, "decode error: {:?}", err),
+            ReceiveError::Body(err) => write!(f, "body error: {}", err),
+            ReceiveError::Io(err) => write!(f, "io error: {}", err),
+            ReceiveError::TooLarge => write!(f, "content too large"),
+            ReceiveError::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+impl From<ReceiveError> for ApiError {
+    fn from(err: ReceiveError) -> Self {
+        match err {
+            ReceiveError::Decode(DecodeError::Unsupported(_)) => {
+                ApiError::bad_request("unsupported content encoding")
+                    .with_status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            }
+            ReceiveError::Decode(DecodeError::TooLarge) => ApiError::new(
+                ErrorCode::PayloadTooLarge,
+                "decompressed body too large",
+            ),
+            ReceiveError::Decode(DecodeError::Corrupted) => {
+                ApiError::bad_request("invalid compressed body")
+            }
+            ReceiveError::Body(_) => {
+                ApiError::bad_request("failed to receive body")
+            }
+            ReceiveError::Io(_) => ApiError::internal(),
+            ReceiveError::TooLarge => {
+                ApiError::new(ErrorCode::PayloadTooLarge, "payload too large")
+            }
+            ReceiveError::Timeout => {
+                ApiError::new(ErrorCode::RequestTimeout, "upload timed out")
+            }
+        }
+    }
+}
+
+/// Writes a (possibly compressed) body stream to the blob at `key`
+///
+/// Returns the hash and the size of the decoded content, which may not
+/// exceed `max_size`. A compressed body is refused as soon as it inflates
+/// beyond `max_size`, or beyond [`compression::MAX_DECOMPRESSED_SIZE`] if
+/// the size is not bounded.
+#[tracing::instrument(name = "receive body", skip_all, fields(key))]
+pub(crate) async fn receive_body<S, B, E>(
+    store: &dyn BlobStore,
+    key: &str,
+    content_encoding: Option<&str>,
+    max_size: Option<u64>,
+    mut body: S,
+) -> Result<([u8; 32], u64), ReceiveError>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: Buf,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let limit = max_size.unwrap_or(compression::MAX_DECOMPRESSED_SIZE);
+    let mut decoder = BodyDecoder::new(content_encoding, limit)
+        .map_err(ReceiveError::Decode)?;
+
+    let mut file = store.create(key).await.map_err(ReceiveError::Io)?;
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|err| ReceiveError::Body(err.into()))?;
+        while chunk.has_remaining() {
+            let data = decoder
+                .decode(chunk.chunk())
+                .map_err(ReceiveError::Decode)?;
+            chunk.advance(chunk.chunk().len());
+
+            hasher.update(&data);
+            size += data.len() as u64;
+            if max_size.is_some_and(|max| size > max) {
+                return Err(ReceiveError::TooLarge);
+            }
+            file.write_all(&data).await.map_err(ReceiveError::Io)?;
+        }
+    }
+
+    let data = decoder.finish().map_err(ReceiveError::Decode)?;
+    hasher.update(&data);
+    size += data.len() as u64;
+    if max_size.is_some_and(|max| size > max) {
+        return Err(ReceiveError::TooLarge);
+    }
+    file.write_all(&data).await.map_err(ReceiveError::Io)?;
+    file.flush().await.map_err(ReceiveError::Io)?;
+
+    Ok((hasher.finalize().into(), size))
 }
 
 /// Handles file download request
 ///
-/// Returns `404 Not Found` if the (bucket_id-file_index) does not exist
-async fn handle_download_file(
+/// Returns `404 Not Found` if the (bucket_id-file_index) does not exist, or
+/// does not exist in the requested version of the bucket. The file content
+/// is streamed from the blob store.
+#[utoipa::path(
+    get,
+    path = "/file/{bucket_id}/{file_index}",
+    tag = "files",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+        ("file_index" = usize, Path, description = "Leaf index of the file"),
+        VersionQuery,
+        ("expires" = Option<u64>, Query, description = "Expiry of a pre-signed URL, see `/presign`"),
+        ("sig" = Option<String>, Query, description = "Signature of a pre-signed URL, see `/presign`"),
+    ),
+    responses(
+        (status = 200, description = "Content of the file", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 401, description = "Missing or invalid credentials, or expired pre-signed URL", body = ErrorBody),
+        (status = 404, description = "The bucket, the version or the file does not exist", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+pub(crate) async fn handle_download_file(
     bucket_id: String,
     file_index: String,
-    state: Arc<RwLock<ServerState>>,
-) -> Result<impl warp::Reply, warp::Rejection> {
+    version: Option<u64>,
+    state: Arc<ServerState>,
+) -> Result<impl IntoResponse, ApiError> {
     let bucket: Arc<RwLock<ClientBucket>> =
         get_bucket(bucket_id.clone(), state.clone())
             .await
-            .ok_or(warp::reject::not_found())?;
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
 
     let bucket = bucket.read().await;
 
-    info!(request = "download_file", bucket_id, file_index);
+    info!(request = "download_file", bucket_id, file_index, version);
 
     let index = file_index
         .parse::<usize>()
-        .map_err(|_| warp::reject::not_found())?;
+        .map_err(|_| ApiError::file_not_found(&file_index))?;
+
+    let (file_hash, file_size, stream) = {
+        let db = state.db.read().await;
+        let file_hash = match version {
+            Some(version) => {
+                versions::file_hash(&db, &bucket_id, version, index)?
+            }
+            None => bucket
+                .get_file_hash(index)
+                .ok_or_else(|| ApiError::file_not_found(index))?,
+        };
+        let (file_size, stream) = blobs::stream(&*state.store, &db, &file_hash)
+            .await
+            .map_err(|_| ApiError::file_not_found(index))?;
+        let stream = state.throttle.download(&bucket_id, stream);
+        (file_hash, file_size, stream)
+    };
+
+    // Stream the file instead of reading it whole in memory
+    let body = Body::from_stream(stream);
+
+    let blob_key = blobs::blob_key(&file_hash);
+    info!(event = "file downloaded", blob_key, file_size);
+    Ok(([(CONTENT_LENGTH, file_size.to_string())], body))
+}
+
+/// Handles file deletion request
+///
+/// Removes the file from the bucket, then replies with the new
+/// Merkle root of the bucket.
+/// Returns `404 Not Found` if the (bucket_id-file_index) does not exist
+#[utoipa::path(
+    delete,
+    path = "/file/{bucket_id}/{file_index}",
+    tag = "files",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+        ("file_index" = usize, Path, description = "Leaf index of the file"),
+        ("X-Signature" = Option<String>, Header, description = "Signature of the owner of the bucket, required on owned buckets"),
+        ("X-Signature-Nonce" = Option<String>, Header, description = "Nonce covered by the signature, `<unix seconds>:<random>`"),
+    ),
+    responses(
+        (status = 200, description = "New Merkle root of the bucket", body = BucketRoot),
+        (status = 401, description = "Missing or invalid credentials or owner signature", body = ErrorBody),
+        (status = 404, description = "The bucket or the file does not exist", body = ErrorBody),
+        (status = 503, description = "Too many concurrent Merkle rebuilds, retry after `Retry-After` seconds", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_delete_file(
+    Path((bucket_id, file_index)): Path<(String, String)>,
+    signed: Signed,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    info!(request = "delete_file", bucket_id, file_index);
+
+    let index = file_index
+        .parse::<usize>()
+        .map_err(|_| ApiError::file_not_found(&file_index))?;
+
+    let file = |bucket: &ClientBucket| {
+        bucket
+            .get_file_hash(index)
+            .map(|_| index)
+            .ok_or_else(|| ApiError::file_not_found(index))
+    };
+    let root = delete_file(bucket_id, file, &signed, peer, state).await?;
+
+    Ok(Json(root))
+}
+
+/// Removes a file from a bucket and releases its blob
+///
+/// `file` returns the index of the file, it is called under the write lock
+/// of the bucket. The request must be signed by the owner of the bucket, if
+/// it has one. Returns the new root of the bucket.
+pub(crate) async fn delete_file<F>(
+    bucket_id: String,
+    file: F,
+    signed: &Signed,
+    peer: Option<String>,
+    state: Arc<ServerState>,
+) -> Result<BucketRoot, ApiError>
+where
+    F: FnOnce(&ClientBucket) -> Result<usize, ApiError>,
+{
+    let bucket: Arc<RwLock<ClientBucket>> =
+        get_bucket(bucket_id.clone(), state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+
+    let mut bucket = bucket.write().await;
+    let index = file(&bucket)?;
+
+    let file_hash = bucket
+        .get_file_hash(index)
+        .ok_or_else(|| ApiError::file_not_found(index))?;
+    let filename = bucket.files.get(&file_hash).cloned().unwrap_or_default();
+    let action = Action::DeleteFile;
+    ownership::verify(
+        &state,
+        action,
+        &bucket_id,
+        &filename,
+        Some(&file_hash),
+        signed,
+    )
+    .await?;
+    // Refused before the file is taken, the tree is rebuilt right after
+    let _permit = state.concurrency.acquire(Work::Merkle)?;
 
-    let file_path = bucket
-        .get_filepath(index)
-        .ok_or(warp::reject::not_found())?;
+    let filename = take_file(&state, &mut bucket, &file_hash)
+        .await
+        .ok_or_else(|| ApiError::file_not_found(index))?;
+
+    let previous_root = bucket.merkle_tree.root_hash();
+    bucket.calculate_merkle_tree();
+
+    let root = bucket.merkle_tree.root_hash().map(hex::encode);
+    info!(event = "file deleted", filename, bucket_id, root);
 
-    let data = fs::read(file_path)
+    state
+        .persist_bucket_lockless(&bucket)
         .await
-        .map_err(|_| warp::reject::not_found())?;
+        .expect("bucket is persisted");
+    release_file(&state, &bucket, &file_hash, filename, peer).await;
+    replication::enqueue(&state, &bucket_id).await;
 
-    info!(event = "file downloaded", file_path);
-    Ok(warp::reply::with_status(data, warp::http::StatusCode::OK))
+    let root = bucket.merkle_tree.root_hash();
+    state.notify(Event::root_changed(&bucket_id, previous_root, root));
+
+    Ok(bucket.root())
+}
+
+/// Removes the file of `file_hash` from the files of a bucket, returning
+/// its name
+///
+/// Its blob is still referenced, see [`release_file`].
+pub(crate) async fn take_file(
+    state: &ServerState,
+    bucket: &mut ClientBucket,
+    file_hash: &[u8; 32],
+) -> Option<String> {
+    let filename = bucket.files.remove(file_hash)?;
+
+    let file_size = {
+        let db = state.db.read().await;
+        blobs::size(&*state.store, &db, file_hash)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    };
+    bucket.bytes_used = bucket.bytes_used.saturating_sub(file_size);
+
+    Some(filename)
+}
+
+/// Releases the blob of a file taken from a bucket, once the bucket is
+/// persisted without it, and records the deletion
+pub(crate) async fn release_file(
+    state: &ServerState,
+    bucket: &ClientBucket,
+    file_hash: &[u8; 32],
+    filename: String,
+    peer: Option<String>,
+) {
+    // The blob is removed once no bucket references it
+    let res = {
+        let db = state.db.write().await;
+        let record = AuditRecord::new(AuditOp::DeleteFile, peer)
+            .with_file(&filename, file_hash)
+            .with_root(bucket.merkle_tree.root_hash());
+        audit::record(&db, &bucket.bucket_id, record);
+        blobs::release(&*state.store, &db, file_hash).await
+    };
+    if let Err(err) = res {
+        error!(event = "failed to release blob", filename, ?err);
+    }
+
+    state.notify(Some(Event::FileDeleted {
+        bucket_id: bucket.bucket_id.clone(),
+        filename,
+        hash: hex::encode(file_hash),
+        expired: false,
+    }));
+}
+
+/// Handles bucket deletion request
+///
+/// Releases the blobs of all files of the bucket and erases the bucket from
+/// the database. API keys and limits of the bucket are left to the
+/// admin API.
+/// Returns `404 Not Found` if the bucket does not exist
+#[utoipa::path(
+    delete,
+    path = "/bucket/{bucket_id}",
+    tag = "buckets",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+        ("X-Signature" = Option<String>, Header, description = "Signature of the owner of the bucket, required on owned buckets"),
+        ("X-Signature-Nonce" = Option<String>, Header, description = "Nonce covered by the signature, `<unix seconds>:<random>`"),
+    ),
+    responses(
+        (status = 200, description = "Bucket deleted", body = String, content_type = "text/plain"),
+        (status = 401, description = "Missing or invalid credentials or owner signature", body = ErrorBody),
+        (status = 404, description = "The bucket does not exist", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_delete_bucket(
+    Path(bucket_id): Path<String>,
+    signed: Signed,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let bucket = {
+        ownership::verify(
+            &state,
+            Action::DeleteBucket,
+            &bucket_id,
+            "",
+            None,
+            &signed,
+        )
+        .await?;
+        state
+            .bucket(&bucket_id)
+            .await
+            .map_err(|err| {
+                error!(event = "failed to load bucket", bucket_id, err);
+                ApiError::internal()
+            })?
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?
+    };
+
+    if !state.unload_bucket(&bucket_id).await {
+        return Err(ApiError::bucket_not_found(&bucket_id));
+    }
+
+    // Wait for the requests in flight on the bucket, with no lock held.
+    // The bucket is neither loaded nor created again meanwhile
+    let mut bucket = bucket.write().await;
+    let files_count = bucket.files.len();
+    let bytes_used = bucket.bytes_used;
+
+    info!(request = "delete_bucket", bucket_id);
+
+    {
+        let db = state.db.write().await;
+        if let Err(err) = versions::forget(&*state.store, &db, &bucket_id).await
+        {
+            error!(event = "failed to drop versions", bucket_id, err);
+        }
+    }
+    let erased = state.erase_bucket(&mut bucket, peer).await;
+    state.erased_bucket(&bucket_id).await;
+    if let Err(err) = erased {
+        error!(event = "failed to delete bucket", bucket_id, err);
+
+        return Err(ApiError::internal());
+    }
+    replication::enqueue(&state, &bucket_id).await;
+
+    info!(event = "bucket deleted", bucket_id, files_count, bytes_used);
+
+    Ok("Bucket deleted")
 }
 
 /// Handles proof download request
 ///
 /// Returns `404 Not Found` if the (bucket_id-file_index) does not exist
+#[utoipa::path(
+    get,
+    path = "/proof/{bucket_id}/{file_index}",
+    tag = "proofs",
+    params(("bucket_id" = String, Path, description = "Id of the bucket"), ("file_index" = usize, Path, description = "Leaf index of the file")),
+    responses(
+        (status = 200, description = "Bincode encoded Merkle proof, a `Vec<([u8; 32], u8)>`", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, description = "The bucket or the file does not exist", body = ErrorBody),
+        (status = 503, description = "Too many concurrent proof generations, retry after `Retry-After` seconds", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
 async fn handle_download_proof(
-    bucket_id: String,
-    file_index: String,
-    state: Arc<RwLock<ServerState>>,
-) -> Result<impl warp::Reply, warp::Rejection> {
+    Path((bucket_id, file_index)): Path<(String, String)>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
     let bucket: Arc<RwLock<ClientBucket>> =
         get_bucket(bucket_id.clone(), state.clone())
             .await
-            .ok_or(warp::reject::not_found())?;
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
 
     let bucket = bucket.read().await;
 
@@ -251,59 +1956,687 @@ async fn handle_download_proof(
 
     let index = file_index
         .parse::<usize>()
-        .map_err(|_| warp::reject::not_found())?;
+        .map_err(|_| ApiError::file_not_found(&file_index))?;
+    if index >= bucket.merkle_tree.leaves_count() {
+        return Err(ApiError::file_not_found(index));
+    }
 
-    let file_path = bucket
-        .get_filepath(index)
-        .ok_or(warp::reject::not_found())?;
+    let blob_key = bucket
+        .get_file_hash(index)
+        .map(|hash| blobs::blob_key(&hash))
+        .ok_or_else(|| ApiError::file_not_found(index))?;
 
     // Generate merkle path for the file
     //
-    let proof: Vec<([u8; 32], u8)> = bucket.merkle_tree.get_proof(index);
+    let _permit = state.concurrency.acquire(Work::Proof)?;
+    let proof: Vec<([u8; 32], u8)> = info_span!("proof", index)
+        .in_scope(|| bucket.merkle_tree.get_proof(index));
     let proof_bytes =
         bincode::serialize(&proof).expect("valid proof serialization");
 
-    info!(event = "proof downloaded", file_path, index);
+    info!(event = "proof downloaded", blob_key, index);
+
+    Ok(proof_bytes)
+}
+
+/// Handles bucket root request
+///
+/// Returns the current Merkle root of the bucket and its leaves count, or
+/// those of the requested version, signed by the server once recorded
+#[utoipa::path(
+    get,
+    path = "/root/{bucket_id}",
+    tag = "buckets",
+    params(("bucket_id" = String, Path, description = "Id of the bucket"), VersionQuery),
+    responses(
+        (status = 200, description = "Merkle root of the bucket", body = BucketRoot),
+        (status = 404, description = "The bucket or the version does not exist", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_root(
+    Path(bucket_id): Path<String>,
+    Query(query): Query<VersionQuery>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let bucket: Arc<RwLock<ClientBucket>> =
+        get_bucket(bucket_id.clone(), state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+
+    info!(request = "root", bucket_id, version = query.version);
+
+    let Some(version) = query.version else {
+        let bucket = bucket.read().await;
+        let root = attestation::attested_root(&state, &bucket).await;
+        return Ok(Json(root));
+    };
 
-    Ok(warp::reply::with_status(
-        proof_bytes,
-        warp::http::StatusCode::OK,
+    let record = state
+        .db
+        .read()
+        .await
+        .root_version(&bucket_id, version)
+        .map_err(|err| {
+            error!(event = "failed to read root", bucket_id, err);
+            ApiError::internal()
+        })?
+        .ok_or_else(|| versions::version_not_found(&bucket_id, version))?;
+
+    Ok(Json(BucketRoot {
+        root: Some(hex::encode(record.root)),
+        leaves_count: record.leaves_count,
+        attestation: Some(state.attestor.attest(&bucket_id, version, &record)),
+    }))
+}
+
+/// A root of a bucket as listed by the roots request
+#[derive(serde::Serialize, ToSchema)]
+struct RootEntry {
+    root: String,
+    leaves_count: usize,
+    timestamp: u64,
+}
+
+/// Handles bucket roots request
+///
+/// Returns the roots the bucket had each time it was persisted, oldest
+/// first. The files of the bucket are not loaded.
+#[utoipa::path(
+    get,
+    path = "/roots/{bucket_id}",
+    tag = "buckets",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Roots of the bucket, oldest first", body = Vec<RootEntry>),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_roots(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    info!(request = "roots", bucket_id);
+
+    let roots = state.db.read().await.roots(&bucket_id).map_err(|err| {
+        error!(event = "failed to read roots", bucket_id, err);
+        ApiError::internal()
+    })?;
+
+    let entries: Vec<RootEntry> = roots
+        .into_iter()
+        .map(|record| RootEntry {
+            root: hex::encode(record.root),
+            leaves_count: record.leaves_count,
+            timestamp: record.timestamp,
+        })
+        .collect();
+
+    Ok(Json(entries))
+}
+
+/// Handles bucket tree request
+///
+/// Streams all the levels of the Merkle tree, from the leaves up to the root,
+/// bincode encoded as a `Vec<Vec<[u8; 32]>>`
+#[utoipa::path(
+    get,
+    path = "/tree/{bucket_id}",
+    tag = "proofs",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Bincode encoded levels of the Merkle tree, a `Vec<Vec<[u8; 32]>>`", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, description = "The bucket does not exist", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_tree(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let bucket: Arc<RwLock<ClientBucket>> =
+        get_bucket(bucket_id.clone(), state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+
+    let tree = bucket.read().await.merkle_tree.clone();
+
+    info!(
+        request = "tree",
+        bucket_id,
+        leaves_count = tree.leaves_count(),
+        levels = tree.levels().len()
+    );
+
+    // The sequence length followed by the levels, encoded one at a time
+    let header = bincode::serialize(&(tree.levels().len() as u64))
+        .expect("valid length serialization");
+    let levels = (0..tree.levels().len()).map(move |i| {
+        bincode::serialize(&tree.levels()[i])
+            .map(bytes::Bytes::from)
+            .map_err(std::io::Error::other)
+    });
+    let chunks = std::iter::once(Ok(bytes::Bytes::from(header))).chain(levels);
+
+    Ok((
+        [(CONTENT_TYPE, "application/octet-stream")],
+        Body::from_stream(tokio_stream::iter(chunks)),
     ))
 }
 
+/// Pagination of a list request
+#[derive(serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListQuery {
+    /// Index of the first file listed, 0 by default, among the files with
+    /// `tag` if set
+    offset: Option<usize>,
+    /// Number of files listed, 100 by default and 1000 at most
+    limit: Option<usize>,
+    /// Lists only the files uploaded with this tag
+    tag: Option<String>,
+}
+
+/// A file of a bucket, as listed by the list request
+#[derive(serde::Serialize, ToSchema)]
+struct ListEntry {
+    index: usize,
+    filename: String,
+    hash: String,
+    size: u64,
+}
+
+/// Handles list files request
+///
+/// Returns the files of the bucket ordered by their leaf index, `limit`
+/// entries from `offset`. With `tag`, only the files uploaded with the tag
+/// are listed, still with their leaf index.
+#[utoipa::path(
+    get,
+    path = "/list/{bucket_id}",
+    tag = "buckets",
+    params(("bucket_id" = String, Path, description = "Id of the bucket"), ListQuery),
+    responses(
+        (status = 200, description = "Files of the bucket", body = Vec<ListEntry>),
+        (status = 404, description = "The bucket does not exist", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_list_files(
+    Path(bucket_id): Path<String>,
+    Query(query): Query<ListQuery>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let bucket: Arc<RwLock<ClientBucket>> =
+        get_bucket(bucket_id.clone(), state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+
+    let bucket = bucket.read().await;
+
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(LIST_DEFAULT_LIMIT)
+        .min(LIST_MAX_LIMIT);
+
+    info!(request = "list", bucket_id, offset, limit, tag = ?query.tag);
+
+    let db = state.db.read().await;
+    let files = bucket.files.iter().enumerate().filter(|(_, (hash, _))| {
+        query
+            .tag
+            .as_ref()
+            .is_none_or(|tag| file_meta::has_tag(&db, &bucket_id, hash, tag))
+    });
+    let entries =
+        list_entries(&state, &db, files.skip(offset).take(limit)).await;
+
+    Ok(Json(entries))
+}
+
+/// Returns the list entries of files of a bucket given with their index
+async fn list_entries<'a, I>(
+    state: &ServerState,
+    db: &DB,
+    files: I,
+) -> Vec<ListEntry>
+where
+    I: Iterator<Item = (usize, (&'a [u8; 32], &'a String))>,
+{
+    let mut entries = Vec::new();
+    for (index, (hash, filename)) in files {
+        let size = blobs::size(&*state.store, db, hash)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+
+        entries.push(ListEntry {
+            index,
+            filename: filename.clone(),
+            hash: hex::encode(hash),
+            size,
+        });
+    }
+
+    entries
+}
+
+/// Query of the search request
+#[derive(serde::Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchQuery {
+    /// Prefix of the file names searched
+    prefix: String,
+    /// Searches the values of this client metadata key instead of the file
+    /// names, see `/meta`
+    key: Option<String>,
+    /// Index of the first match listed, 0 by default
+    offset: Option<usize>,
+    /// Number of matches listed, 100 by default and 1000 at most
+    limit: Option<usize>,
+}
+
+/// Handles search request
+///
+/// Returns the files of the bucket whose name starts with `prefix`, or whose
+/// metadata `key` does, ordered by their leaf index, `limit` matches from
+/// `offset`
+#[utoipa::path(
+    get,
+    path = "/search/{bucket_id}",
+    tag = "buckets",
+    params(("bucket_id" = String, Path, description = "Id of the bucket"), SearchQuery),
+    responses(
+        (status = 200, description = "Matching files of the bucket", body = Vec<ListEntry>),
+        (status = 404, description = "The bucket does not exist", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_search_files(
+    Path(bucket_id): Path<String>,
+    Query(query): Query<SearchQuery>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let bucket: Arc<RwLock<ClientBucket>> =
+        get_bucket(bucket_id.clone(), state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+
+    let bucket = bucket.read().await;
+
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(LIST_DEFAULT_LIMIT)
+        .min(LIST_MAX_LIMIT);
+    let prefix = &query.prefix;
+
+    info!(request = "search", bucket_id, prefix, key = ?query.key, offset, limit);
+
+    let db = state.db.read().await;
+    let files =
+        bucket
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, (hash, filename))| match &query.key {
+                Some(key) => file_meta::metadata_starts_with(
+                    &db, &bucket_id, hash, key, prefix,
+                ),
+                None => filename.starts_with(prefix.as_str()),
+            });
+    let entries =
+        list_entries(&state, &db, files.skip(offset).take(limit)).await;
+
+    Ok(Json(entries))
+}
+
+/// Handles proof download request by leaf hash
+///
+/// Returns `404 Not Found` if the hash is not a leaf of the bucket
+#[utoipa::path(
+    get,
+    path = "/proof_by_hash/{bucket_id}/{hex_hash}",
+    tag = "proofs",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+        ("hex_hash" = String, Path, description = "Hex encoded hash of the file"),
+    ),
+    responses(
+        (status = 200, description = "Bincode encoded Merkle proof, a `Vec<([u8; 32], u8)>`", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, description = "The bucket or the file does not exist", body = ErrorBody),
+        (status = 503, description = "Too many concurrent proof generations, retry after `Retry-After` seconds", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_download_proof_by_hash(
+    Path((bucket_id, hex_hash)): Path<(String, String)>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let bucket: Arc<RwLock<ClientBucket>> =
+        get_bucket(bucket_id.clone(), state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+
+    let bucket = bucket.read().await;
+
+    info!(request = "download_proof_by_hash", bucket_id, hex_hash);
+
+    let hash: [u8; 32] = hex::decode(&hex_hash)
+        .ok()
+        .and_then(|hash| hash.try_into().ok())
+        .ok_or_else(|| ApiError::file_not_found(&hex_hash))?;
+
+    let index = bucket
+        .merkle_tree
+        .leaves()
+        .iter()
+        .position(|leaf| *leaf == hash)
+        .ok_or_else(|| ApiError::file_not_found(&hex_hash))?;
+
+    let _permit = state.concurrency.acquire(Work::Proof)?;
+    let proof: Vec<([u8; 32], u8)> = info_span!("proof", index)
+        .in_scope(|| bucket.merkle_tree.get_proof(index));
+    let proof_bytes =
+        bincode::serialize(&proof).expect("valid proof serialization");
+
+    info!(event = "proof downloaded", hex_hash, index);
+
+    Ok(proof_bytes)
+}
+
+/// Handles batch proof download request
+///
+/// Replies with the proofs of the requested files, in the order of the
+/// request. Returns `404 Not Found` if any of the indices does not exist
+#[utoipa::path(
+    post,
+    path = "/proofs/{bucket_id}",
+    tag = "proofs",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    request_body(content = Vec<usize>, description = "Leaf indices of the files"),
+    responses(
+        (status = 200, description = "Bincode encoded Merkle proofs, a `Vec<Vec<([u8; 32], u8)>>`", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 400, description = "More proofs than `max_batch_proofs` requested", body = ErrorBody),
+        (status = 404, description = "The bucket or a file does not exist", body = ErrorBody),
+        (status = 503, description = "Too many concurrent proof generations, retry after `Retry-After` seconds", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_download_proofs(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    Json(indices): Json<Vec<usize>>,
+) -> Result<Vec<u8>, ApiError> {
+    let bucket: Arc<RwLock<ClientBucket>> =
+        get_bucket(bucket_id.clone(), state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+
+    let bucket = bucket.read().await;
+
+    info!(
+        request = "download_proofs",
+        bucket_id,
+        count = indices.len()
+    );
+
+    if indices.len() > MAX_BATCH_PROOFS {
+        return Err(ApiError::bad_request("too many proofs requested")
+            .with_detail("max", MAX_BATCH_PROOFS));
+    }
+
+    let leaves_count = bucket.merkle_tree.leaves_count();
+    if let Some(index) = indices.iter().find(|index| **index >= leaves_count) {
+        return Err(ApiError::file_not_found(index));
+    }
+
+    let _permit = state.concurrency.acquire(Work::Proof)?;
+    let proofs: Vec<Vec<([u8; 32], u8)>> =
+        info_span!("proofs", count = indices.len()).in_scope(|| {
+            indices
+                .iter()
+                .map(|index| bucket.merkle_tree.get_proof(*index))
+                .collect()
+        });
+    let proofs_bytes =
+        bincode::serialize(&proofs).expect("valid proof serialization");
+
+    info!(event = "proofs downloaded", bucket_id, count = proofs.len());
+
+    Ok(proofs_bytes)
+}
+
+/// Handles bucket stats request
+///
+/// Returns the current usage of the bucket compared to the configured quota
+#[utoipa::path(
+    get,
+    path = "/stats/{bucket_id}",
+    tag = "buckets",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Usage of the bucket", body = Usage),
+        (status = 404, description = "The bucket does not exist", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_stats(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let bucket: Arc<RwLock<ClientBucket>> =
+        get_bucket(bucket_id.clone(), state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+
+    let bucket = bucket.read().await;
+
+    info!(request = "stats", bucket_id);
+
+    let usage = state.usage(&bucket);
+    Ok(Json(usage))
+}
+
 /// Returns an existing bucket or creates a new one
 ///
-/// This function tries to get a bucket from the state.
-/// If the bucket does not exist, it locks the entire state and creates a new bucket.
-async fn get_or_create_bucket(
+/// This function tries to get a bucket from the state, loading it from the
+/// database if needed. If the bucket does not exist, it is created. Only the
+/// shard of the bucket map holding the bucket is locked. A bucket being
+/// deleted is refused with `409 Conflict` rather than created again.
+pub(crate) async fn get_or_create_bucket(
     bucket_id: String,
-    state: Arc<RwLock<ServerState>>,
-) -> Arc<RwLock<ClientBucket>> {
-    let res = get_bucket(bucket_id.clone(), state.clone()).await;
-
-    match res {
-        Some(bucket) => bucket,
-        None => {
-            // Lock the entire state and create a new bucket
-            let mut state_guard = state.write().await;
+    state: Arc<ServerState>,
+) -> Result<Arc<RwLock<ClientBucket>>, ApiError> {
+    // A bucket failing to load must not be replaced by an empty one
+    let loaded = state.bucket(&bucket_id).await.map_err(|err| {
+        error!(event = "failed to load bucket", bucket_id, err);
+        ApiError::internal()
+    })?;
+    if let Some(bucket) = loaded {
+        return Ok(bucket);
+    }
 
-            state_guard
-                .buckets
-                .entry(bucket_id.clone())
-                .or_insert(Arc::new(RwLock::new(ClientBucket::new(bucket_id))))
-                .clone()
+    let bucket = {
+        let loading = state.loading.lock().await;
+        if let Some(Pending::Delete) = loading.get(&bucket_id) {
+            return Err(ApiError::new(
+                ErrorCode::Conflict,
+                "bucket is being deleted",
+            ));
         }
-    }
+        state.buckets.get_or_insert_with(bucket_id.clone(), || {
+            Arc::new(RwLock::new(ClientBucket::new(bucket_id.clone())))
+        })
+    };
+    state.touch(&bucket_id);
+    state.evict_idle_buckets().await;
+
+    Ok(bucket)
 }
 
 /// Returns an existing bucket or none
-/// This function acquires only a read lock on the state
-async fn get_bucket(
+/// Only the shard of the bucket map holding the bucket is locked. A bucket
+/// failing to load is logged and not found.
+pub(crate) async fn get_bucket(
     bucket_id: String,
-    state: Arc<RwLock<ServerState>>,
+    state: Arc<ServerState>,
 ) -> Option<Arc<RwLock<ClientBucket>>> {
-    let state_guard = state.read().await;
-
     // Get bucket by id
-    state_guard.buckets.get(&bucket_id).cloned()
+    match state.bucket(&bucket_id).await {
+        Ok(bucket) => bucket,
+        Err(err) => {
+            error!(event = "failed to load bucket", bucket_id, err);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use axum::http::{Method, Request};
+    use clap::Parser;
+    use http_body_util::BodyExt;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_run_server_with_store() {
+        let config = Config::parse_from([
+            "server",
+            "127.0.0.1:0",
+            "--inline-threshold",
+            "0",
+        ]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = run_server_with_store(config, store.clone()).await;
+        tokio::spawn(server);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{addr}/upload_file/bucket/a.txt"))
+            .body(Body::from("hello"))
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let uploaded: serde_json::Value =
+            serde_json::from_slice(&body).unwrap();
+
+        let hash: [u8; 32] = Sha256::digest(b"hello").into();
+        assert_eq!(uploaded["hash"], hex::encode(hash));
+        assert_eq!(uploaded["index"], 0);
+        assert_eq!(uploaded["bucket_root_pending"], true);
+        assert_eq!(store.get(&blobs::blob_key(&hash)).await.unwrap(), "hello");
+
+        let uri = format!("http://{addr}/file/bucket/0").parse().unwrap();
+        let response = client.get(uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_receive_compressed_body() {
+        let store = MemoryStore::new();
+        let data = vec![0x24u8; 4096];
+        let zst = zstd::encode_all(&data[..], 0).unwrap();
+        let body = |body: &[u8]| Body::from(body.to_vec()).into_data_stream();
+
+        let received =
+            receive_body(&store, "a", Some("zstd"), Some(4096), body(&zst))
+                .await
+                .unwrap();
+        assert_eq!(received, (Sha256::digest(&data).into(), 4096));
+
+        // The upload size limit applies to the decompressed body
+        let received =
+            receive_body(&store, "b", Some("zstd"), Some(4095), body(&zst))
+                .await;
+        assert!(matches!(
+            received,
+            Err(ReceiveError::Decode(DecodeError::TooLarge))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_load_bucket_once() {
+        let config = Config::parse_from(["server", "127.0.0.1:0"]);
+        let store = Arc::new(MemoryStore::new());
+        let state = ServerState::open(&config, DB::in_memory(), store).await;
+        let id = "a".to_owned();
+        let bucket = ClientBucket::new(id.clone());
+        state.db.read().await.update_bucket(&bucket).unwrap();
+
+        // Requests arriving while the bucket is read share the loaded one
+        let (a, b) = tokio::join!(state.bucket(&id), state.bucket(&id));
+        assert!(Arc::ptr_eq(&a.unwrap().unwrap(), &b.unwrap().unwrap()));
+        assert!(state.loading.lock().await.is_empty());
+        assert!(state.bucket(&"b".to_owned()).await.unwrap().is_none());
+        assert!(state.loading.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete_bucket_in_use() {
+        let config = Config::parse_from(["server", "127.0.0.1:0"]);
+        let store = Arc::new(MemoryStore::new());
+        let state =
+            Arc::new(ServerState::open(&config, DB::in_memory(), store).await);
+        let routes = routes(config, state.clone());
+        let request = |method: &str, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::from("a"))
+                .unwrap();
+            routes.clone().oneshot(request)
+        };
+
+        let res = request("POST", "/upload_file/a/a.txt").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("POST", "/complete_upload/a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // A request in flight holds the bucket while it is deleted
+        let id = "a".to_owned();
+        let stale = state.bucket(&id).await.unwrap().unwrap();
+        let in_flight = stale.read().await;
+        let deletion = tokio::spawn({
+            let deleted = request("DELETE", "/bucket/a");
+            async move { deleted.await.unwrap() }
+        });
+        while state.buckets.get(&id).is_some() {
+            tokio::task::yield_now().await;
+        }
+
+        // Meanwhile the bucket is neither loaded nor created again
+        let res = request("GET", "/root/a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = request("POST", "/upload_file/a/b.txt").await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        drop(in_flight);
+        assert_eq!(deletion.await.unwrap().status(), StatusCode::OK);
+
+        // The requests still holding the bucket can no longer store it
+        let stale = stale.read().await;
+        assert_eq!(stale.state, BucketState::Deleted);
+        assert!(stale.files.is_empty());
+        assert!(state.persist_bucket_lockless(&stale).await.is_err());
+        assert!(state.db.read().await.read_bucket(&id).unwrap().is_none());
+
+        // Once erased, the bucket is created again by an upload
+        let res = request("POST", "/upload_file/a/b.txt").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("GET", "/list/a").await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let listed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+    }
 }
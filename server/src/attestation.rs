@@ -0,0 +1,206 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::routing::get;
+use axum::Router;
+use clap::Args;
+use ed25519_dalek::{Signer, SigningKey};
+use rand::RngCore;
+use serde::Serialize;
+use tracing::{error, info};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::app::ServerState;
+use crate::client_bucket::{BucketRoot, ClientBucket};
+use crate::database::RootRecord;
+use crate::extract::Json;
+use crate::metadata_cipher;
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct AttestationConfig {
+    /// Hex encoded 32 bytes seed of the ed25519 identity key signing the
+    /// roots of the buckets, random by default so the identity changes on
+    /// restart
+    #[arg(long, env = "STORAGE_IDENTITY_KEY", value_parser = metadata_cipher::parse_key)]
+    pub identity_key: Option<[u8; 32]>,
+}
+
+/// Signs the roots the server vouches for with its identity key
+pub(crate) struct Attestor {
+    key: SigningKey,
+}
+
+/// Signed statement of the server that a bucket had a root at a version
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct Attestation {
+    /// Version of the bucket, the sequence number of the root
+    pub version: u64,
+    /// Unix time in seconds the root was recorded
+    pub timestamp: u64,
+    /// Hex encoded ed25519 public key of the server
+    pub public_key: String,
+    /// Hex encoded ed25519 signature of the attestation message
+    pub signature: String,
+}
+
+/// Public identity of the server, as replied by the identity request
+#[derive(Serialize, ToSchema)]
+struct Identity {
+    /// Hex encoded ed25519 public key signing the root attestations
+    public_key: String,
+}
+
+impl Attestor {
+    pub(crate) fn new(config: &AttestationConfig) -> Self {
+        let seed = config.identity_key.unwrap_or_else(|| {
+            let mut seed = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut seed);
+            seed
+        });
+        let attestor = Attestor {
+            key: SigningKey::from_bytes(&seed),
+        };
+
+        info!(event = "identity key", public_key = attestor.public_key());
+        attestor
+    }
+
+    /// Returns the hex encoded public key
+    pub(crate) fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().as_bytes())
+    }
+
+    /// Signs the root of a bucket recorded at `version`
+    pub(crate) fn attest(
+        &self,
+        bucket_id: &str,
+        version: u64,
+        record: &RootRecord,
+    ) -> Attestation {
+        let message = message(
+            bucket_id,
+            version,
+            &record.root,
+            record.leaves_count,
+            record.timestamp,
+        );
+
+        Attestation {
+            version,
+            timestamp: record.timestamp,
+            public_key: self.public_key(),
+            signature: hex::encode(
+                self.key.sign(message.as_bytes()).to_bytes(),
+            ),
+        }
+    }
+}
+
+/// Returns the message signed by the server for the root of a bucket
+pub(crate) fn message(
+    bucket_id: &str,
+    version: u64,
+    root: &[u8; 32],
+    leaves_count: usize,
+    timestamp: u64,
+) -> String {
+    format!(
+        "storage-attestation-v1\n{bucket_id}\n{version}\n{}\n{leaves_count}\n{timestamp}",
+        hex::encode(root)
+    )
+}
+
+/// Returns the root of a bucket, attested if it is the last recorded root
+///
+/// The root of a bucket whose last state could not be persisted has no
+/// attestation.
+pub(crate) async fn attested_root(
+    state: &ServerState,
+    bucket: &ClientBucket,
+) -> BucketRoot {
+    let mut root = bucket.root();
+
+    let bucket_id = &bucket.bucket_id;
+    let last_root = state.db.read().await.last_root(bucket_id);
+    match last_root {
+        Ok(Some((version, record)))
+            if bucket.merkle_tree.root_hash() == Some(record.root) =>
+        {
+            root.attestation =
+                Some(state.attestor.attest(bucket_id, version, &record));
+        }
+        Ok(_) => {}
+        Err(err) => error!(event = "failed to read root", bucket_id, err),
+    }
+
+    root
+}
+
+/// Server identity route of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_identity))]
+pub(crate) struct ApiDoc;
+
+/// Returns the server identity route
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    // Public key of the server
+    // GET /identity
+    Router::new()
+        .route("/identity", get(handle_identity))
+        .with_state(state)
+}
+
+/// Handles identity request
+///
+/// Returns the public key verifying the root attestations of the server
+#[utoipa::path(
+    get,
+    path = "/identity",
+    tag = "server",
+    responses(
+        (status = 200, description = "Public key of the server", body = Identity),
+    ),
+)]
+async fn handle_identity(
+    State(state): State<Arc<ServerState>>,
+) -> Json<Identity> {
+    let public_key = state.attestor.public_key();
+    Json(Identity { public_key })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signature, Verifier};
+
+    #[test]
+    fn test_attest() {
+        let config = AttestationConfig {
+            identity_key: Some([7u8; 32]),
+        };
+        let attestor = Attestor::new(&config);
+        assert_eq!(attestor.public_key(), Attestor::new(&config).public_key());
+
+        let record = RootRecord {
+            root: [1u8; 32],
+            leaves_count: 3,
+            timestamp: 1_700_000_000,
+        };
+        let attestation = attestor.attest("bucket_id", 2, &record);
+        assert_eq!(attestation.version, 2);
+        assert_eq!(attestation.timestamp, record.timestamp);
+
+        let signature: [u8; 64] = hex::decode(&attestation.signature)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signature = Signature::from_bytes(&signature);
+        let key = attestor.key.verifying_key();
+        let signed = message("bucket_id", 2, &record.root, 3, record.timestamp);
+        assert!(key.verify(signed.as_bytes(), &signature).is_ok());
+
+        // The attestation does not vouch for another bucket
+        let other = message("other", 2, &record.root, 3, record.timestamp);
+        assert!(key.verify(other.as_bytes(), &signature).is_err());
+    }
+}
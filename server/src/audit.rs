@@ -0,0 +1,250 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::async_trait;
+use axum::extract::{FromRequestParts, State};
+use axum::http::request::Parts;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{middleware, Router};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::access::{self, Operation};
+use crate::app::ServerState;
+use crate::database::DB;
+use crate::errors::ApiError;
+use crate::extract::{Json, Path, Query};
+use crate::tls::RemoteAddr;
+
+/// Default number of records of an audit page
+const AUDIT_DEFAULT_LIMIT: usize = 100;
+/// Maximum number of records of an audit page
+const AUDIT_MAX_LIMIT: usize = 1000;
+
+/// Bucket changes recorded in the audit log
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuditOp {
+    Upload,
+    CompleteUpload,
+    DeleteFile,
+    DeleteBucket,
+    /// Deletion of an expired file by the lifecycle sweeper
+    Expire,
+    /// State of the bucket applied from another server
+    Replicate,
+    /// File copied from another bucket
+    CopyFile,
+}
+
+impl AuditOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditOp::Upload => "upload",
+            AuditOp::CompleteUpload => "complete_upload",
+            AuditOp::DeleteFile => "delete_file",
+            AuditOp::DeleteBucket => "delete_bucket",
+            AuditOp::Expire => "expire",
+            AuditOp::Replicate => "replicate",
+            AuditOp::CopyFile => "copy_file",
+        }
+    }
+}
+
+/// A change of a bucket, chained to the previous record of the bucket
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AuditRecord {
+    /// Unix time in seconds
+    pub timestamp: u64,
+    pub operation: AuditOp,
+    /// Address of the client, or URL of the server the change comes from
+    pub peer: Option<String>,
+    pub filename: Option<String>,
+    pub file_hash: Option<[u8; 32]>,
+    /// Merkle root of the bucket after the change
+    pub root: Option<[u8; 32]>,
+    /// Hash of the previous record of the bucket, zeros for the first one
+    pub prev_hash: [u8; 32],
+}
+
+impl AuditRecord {
+    /// Returns a record of a change made now, chained by
+    /// [`DB::append_audit`]
+    pub(crate) fn new(operation: AuditOp, peer: Option<String>) -> Self {
+        AuditRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            operation,
+            peer,
+            filename: None,
+            file_hash: None,
+            root: None,
+            prev_hash: [0u8; 32],
+        }
+    }
+
+    pub(crate) fn with_file(mut self, filename: &str, hash: &[u8; 32]) -> Self {
+        self.filename = Some(filename.to_owned());
+        self.file_hash = Some(*hash);
+        self
+    }
+
+    pub(crate) fn with_root(mut self, root: Option<[u8; 32]>) -> Self {
+        self.root = root;
+        self
+    }
+
+    /// Returns the hash chaining the record at `seq` to the next one
+    ///
+    /// It covers the fields as listed by `GET /audit`, so the chain can be
+    /// verified without access to the database.
+    pub(crate) fn hash(&self, seq: u64) -> [u8; 32] {
+        let message = format!(
+            "{seq}\n{}\n{}\n{}\n{}\n{}\n{}\n{}",
+            self.timestamp,
+            self.operation.as_str(),
+            self.peer.as_deref().unwrap_or_default(),
+            self.filename.as_deref().unwrap_or_default(),
+            self.file_hash.map(hex::encode).unwrap_or_default(),
+            self.root.map(hex::encode).unwrap_or_default(),
+            hex::encode(self.prev_hash)
+        );
+        Sha256::digest(message.as_bytes()).into()
+    }
+}
+
+/// Appends a record to the audit log of a bucket, the failure is logged
+///
+/// The database write lock must be held, so the records are chained in
+/// order.
+pub(crate) fn record(db: &DB, bucket_id: &str, record: AuditRecord) {
+    let operation = record.operation;
+    if let Err(err) = db.append_audit(bucket_id, record) {
+        error!(event = "failed to audit", bucket_id, ?operation, err);
+    }
+}
+
+/// Address of the client
+///
+/// The listeners pass it to the routes in a [`RemoteAddr`] request
+/// extension.
+pub(crate) struct Peer(pub Option<String>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Peer {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _: &S,
+    ) -> Result<Self, Infallible> {
+        let addr = parts.extensions.get::<RemoteAddr>();
+        Ok(Peer(addr.map(|addr| addr.0.to_string())))
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AuditQuery {
+    /// Index of the first record listed, 0 by default
+    offset: Option<usize>,
+    /// Number of records listed, 100 by default and 1000 at most
+    limit: Option<usize>,
+}
+
+/// A record of the audit log, as listed by the audit request
+#[derive(Serialize, ToSchema)]
+struct AuditEntry {
+    seq: u64,
+    timestamp: u64,
+    operation: AuditOp,
+    peer: Option<String>,
+    filename: Option<String>,
+    file_hash: Option<String>,
+    root: Option<String>,
+    prev_hash: String,
+    hash: String,
+}
+
+impl AuditEntry {
+    fn new(seq: u64, record: AuditRecord) -> Self {
+        AuditEntry {
+            seq,
+            hash: hex::encode(record.hash(seq)),
+            timestamp: record.timestamp,
+            operation: record.operation,
+            peer: record.peer,
+            filename: record.filename,
+            file_hash: record.file_hash.map(hex::encode),
+            root: record.root.map(hex::encode),
+            prev_hash: hex::encode(record.prev_hash),
+        }
+    }
+}
+
+/// Audit route of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_audit))]
+pub(crate) struct ApiDoc;
+
+/// Returns the audit route
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    // Audit log of a bucket, oldest first
+    // GET /audit/:bucket_id?offset=&limit=
+    Router::new()
+        .route("/audit/:bucket_id", get(handle_audit))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Read),
+            access::bucket_access,
+        ))
+        .with_state(state)
+}
+
+/// Handles audit request
+///
+/// The log of a deleted bucket is kept, it is empty for a bucket that never
+/// existed.
+#[utoipa::path(
+    get,
+    path = "/audit/{bucket_id}",
+    tag = "buckets",
+    params(("bucket_id" = String, Path, description = "Id of the bucket"), AuditQuery),
+    responses(
+        (status = 200, description = "Audit log of the bucket, oldest first", body = Vec<AuditEntry>),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_audit(
+    Path(bucket_id): Path<String>,
+    Query(query): Query<AuditQuery>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let offset = query.offset.unwrap_or_default();
+    let limit = query
+        .limit
+        .unwrap_or(AUDIT_DEFAULT_LIMIT)
+        .min(AUDIT_MAX_LIMIT);
+
+    info!(request = "audit", bucket_id, offset, limit);
+
+    let records = state.db.read().await.audit_log(&bucket_id, offset, limit);
+    match records {
+        Ok(records) => {
+            let entries: Vec<AuditEntry> = records
+                .into_iter()
+                .map(|(seq, record)| AuditEntry::new(seq, record))
+                .collect();
+            Ok(Json(entries))
+        }
+        Err(err) => {
+            error!(event = "failed to read audit log", bucket_id, err);
+            Err(ApiError::internal())
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::info;
+
+use crate::app::ServerState;
+use crate::blob_store::{BlobStore, LocalStore};
+use crate::blobs;
+
+/// Folder of a backup holding the database checkpoint
+const DB_DIR: &str = "rocksdb";
+/// Folder of a backup holding the blobs
+const BLOBS_DIR: &str = "blobs";
+
+/// Writes a backup of the database and the blobs into a new folder of
+/// `backup_dir`, returning its path
+///
+/// The database is checkpointed and the blobs are copied under the database
+/// write lock, so the backup is consistent: uploads and deletions wait for
+/// the end of the backup before updating blob references. Bucket changes not
+/// persisted yet are not part of the backup.
+pub(crate) async fn create(
+    state: &ServerState,
+    backup_dir: &Path,
+) -> io::Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let path = backup_dir.join(timestamp.to_string());
+    if path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("backup {path:?} already exists"),
+        ));
+    }
+    tokio::fs::create_dir_all(&path).await?;
+
+    let db = state.db.write().await;
+    db.checkpoint(path.join(DB_DIR)).map_err(io::Error::other)?;
+
+    let backup_store = LocalStore::new(path.join(BLOBS_DIR));
+    let blobs_count = copy_blobs(&*state.store, &backup_store).await?;
+    drop(db);
+
+    info!(event = "backup created", ?path, blobs_count);
+    Ok(path)
+}
+
+/// Restores a backup made by [`create`] into `db_path` and `store`
+///
+/// Refuses to overwrite an existing database.
+pub(crate) async fn restore(
+    backup: &Path,
+    db_path: &Path,
+    store: &dyn BlobStore,
+) -> io::Result<()> {
+    if db_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("database {db_path:?} already exists"),
+        ));
+    }
+    copy_dir(&backup.join(DB_DIR), db_path)?;
+
+    let backup_store = LocalStore::new(backup.join(BLOBS_DIR));
+    let blobs_count = copy_blobs(&backup_store, store).await?;
+
+    info!(event = "backup restored", ?backup, blobs_count);
+    Ok(())
+}
+
+/// Copies all the blobs of `from`, except the uploads in progress, returning
+/// the number of blobs copied
+async fn copy_blobs(
+    from: &dyn BlobStore,
+    to: &dyn BlobStore,
+) -> io::Result<usize> {
+    let keys: Vec<String> = from
+        .list("")
+        .await?
+        .into_iter()
+        .filter(|key| !blobs::is_tmp_key(key) && !blobs::is_staged_key(key))
+        .collect();
+
+    for key in &keys {
+        blobs::copy_blob(from, to, key).await?;
+    }
+
+    Ok(keys.len())
+}
+
+/// Copies the files of a folder, which has no subfolder
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_copy_blobs() {
+        let from = MemoryStore::new();
+        from.put("ab/abcd", Bytes::from("data")).await.unwrap();
+        from.put(&blobs::tmp_key(), Bytes::from("part"))
+            .await
+            .unwrap();
+
+        let to = MemoryStore::new();
+        assert_eq!(copy_blobs(&from, &to).await.unwrap(), 1);
+        assert_eq!(to.list("").await.unwrap(), ["ab/abcd"]);
+        assert_eq!(to.get("ab/abcd").await.unwrap(), "data");
+    }
+}
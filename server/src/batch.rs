@@ -0,0 +1,569 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use axum::async_trait;
+use axum::extract::{FromRequestParts, State};
+use axum::http::request::Parts;
+use axum::response::IntoResponse;
+use axum::routing::post;
+use axum::{middleware, Router};
+use serde::Serialize;
+use tracing::{error, info};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::access::{self, Operation};
+use crate::app::{self, ServerState};
+use crate::audit::Peer;
+use crate::client_bucket::{BucketState, ClientBucket};
+use crate::errors::{ApiError, ErrorBody, ErrorCode};
+use crate::events::Event;
+use crate::extract::{self, Json, Path};
+use crate::ownership::{self, Action, Signed};
+use crate::replication;
+use crate::sharded_map::ShardedMap;
+
+/// Header carrying the token of the upload session of a request
+pub(crate) const SESSION_HEADER: &str = "x-upload-session";
+
+/// Upload session of a bucket, opened by `begin_upload`
+///
+/// While a bucket has a session, its uploads and completions must carry the
+/// token of the session, so the files of two clients uploading at once are
+/// not sealed by the completion of one of them.
+pub(crate) struct UploadBatch {
+    token: String,
+    /// Pushed back by each request of the session
+    expires_at: Instant,
+    /// Hashes of the files uploaded in the session, removed if it is
+    /// aborted or expires
+    files: Vec<[u8; 32]>,
+}
+
+/// Open upload sessions by bucket id
+pub(crate) type UploadBatches = ShardedMap<String, Arc<Mutex<UploadBatch>>>;
+
+/// Reply of the begin_upload request
+#[derive(Serialize, ToSchema)]
+struct BeginUpload {
+    /// Token to send in `X-Upload-Session`
+    session: String,
+    /// Seconds the session stays open without requests
+    expires_in: u64,
+}
+
+/// Reply of the abort_upload request
+#[derive(Serialize, ToSchema)]
+struct AbortUpload {
+    /// Number of files of the session removed from the bucket
+    removed: usize,
+}
+
+/// Upload session routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_begin_upload, handle_abort_upload))]
+pub(crate) struct ApiDoc;
+
+/// Returns the upload session route group
+///
+/// `begin_upload` opens the session of a bucket, `complete_upload` with its
+/// token seals the files of the session and closes it, `abort_upload` removes
+/// them and closes it.
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    Router::new()
+        // Open the upload session of a bucket
+        // POST /begin_upload/:bucket_id
+        .route("/begin_upload/:bucket_id", post(handle_begin_upload))
+        // Close the upload session of a bucket, removing its files
+        // POST /abort_upload/:bucket_id
+        .route("/abort_upload/:bucket_id", post(handle_abort_upload))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Write),
+            access::bucket_access,
+        ))
+        .with_state(state)
+}
+
+/// Token of the upload session of a request, if any
+pub(crate) struct SessionToken(pub Option<String>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for SessionToken {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, ApiError> {
+        extract::optional_header(&parts.headers, SESSION_HEADER)
+            .map(SessionToken)
+    }
+}
+
+/// Handles begin_upload request
+///
+/// Owned buckets require the signature of the owner. Returns `409 Conflict`
+/// with the seconds left to the session of another client, if any
+#[utoipa::path(
+    post,
+    path = "/begin_upload/{bucket_id}",
+    tag = "uploads",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+    ),
+    responses(
+        (status = 200, description = "Upload session opened", body = BeginUpload),
+        (status = 401, description = "Missing or invalid credentials, or missing owner signature", body = ErrorBody),
+        (status = 409, description = "Another upload session of the bucket is open", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_begin_upload(
+    Path(bucket_id): Path<String>,
+    signed: Signed,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    ownership::verify(
+        &state,
+        Action::BeginUpload,
+        &bucket_id,
+        "",
+        None,
+        &signed,
+    )
+    .await?;
+
+    let token = open(bucket_id, state.clone()).await?;
+    let ttl = state.limits.upload_session_ttl();
+
+    Ok(Json(BeginUpload {
+        session: token,
+        expires_in: ttl.as_secs(),
+    }))
+}
+
+/// Opens the upload session of a bucket, re-opening it if it is sealed
+///
+/// Returns the token of the session.
+pub(crate) async fn open(
+    bucket_id: String,
+    state: Arc<ServerState>,
+) -> Result<String, ApiError> {
+    let bucket =
+        app::get_or_create_bucket(bucket_id.clone(), state.clone()).await?;
+    let mut bucket = bucket.write().await;
+
+    expire(&state, &mut bucket).await;
+    if let Some(batch) = state.batches.get(&bucket_id) {
+        return Err(in_progress(&batch.lock().expect("unpoisoned lock")));
+    }
+
+    let token = hex::encode(rand::random::<[u8; 16]>());
+    let batch = UploadBatch {
+        token: token.clone(),
+        expires_at: Instant::now() + state.limits.upload_session_ttl(),
+        files: Vec::new(),
+    };
+    state
+        .batches
+        .insert(bucket_id.clone(), Arc::new(Mutex::new(batch)));
+
+    info!(event = "upload session opened", bucket_id);
+
+    Ok(token)
+}
+
+/// Handles abort_upload request
+///
+/// The files uploaded in the session are removed, the files of the bucket
+/// are left as they were when it was opened. Owned buckets require the
+/// signature of the owner.
+#[utoipa::path(
+    post,
+    path = "/abort_upload/{bucket_id}",
+    tag = "uploads",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+        ("X-Upload-Session" = String, Header, description = "Token of the upload session"),
+    ),
+    responses(
+        (status = 200, description = "Upload session aborted", body = AbortUpload),
+        (status = 401, description = "Missing or invalid credentials, or missing owner signature", body = ErrorBody),
+        (status = 404, description = "The upload session does not exist or expired", body = ErrorBody),
+        (status = 409, description = "Another upload session of the bucket is open", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_abort_upload(
+    Path(bucket_id): Path<String>,
+    SessionToken(token): SessionToken,
+    signed: Signed,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    ownership::verify(
+        &state,
+        Action::AbortUpload,
+        &bucket_id,
+        "",
+        None,
+        &signed,
+    )
+    .await?;
+
+    let Some(token) = token else {
+        return Err(session_not_found());
+    };
+    let removed = abort(bucket_id, &token, peer, state).await?;
+
+    Ok(Json(AbortUpload { removed }))
+}
+
+/// Closes the upload session `token` of a bucket, removing its files
+///
+/// Returns the number of files removed.
+pub(crate) async fn abort(
+    bucket_id: String,
+    token: &str,
+    peer: Option<String>,
+    state: Arc<ServerState>,
+) -> Result<usize, ApiError> {
+    let bucket = app::get_bucket(bucket_id.clone(), state.clone())
+        .await
+        .ok_or_else(session_not_found)?;
+    let mut bucket = bucket.write().await;
+
+    check(&state, &mut bucket, Some(token)).await?;
+    let batch = state
+        .batches
+        .remove(&bucket_id)
+        .ok_or_else(session_not_found)?;
+    let removed = rollback(&state, &mut bucket, &batch, peer).await;
+
+    info!(event = "upload session aborted", bucket_id, removed);
+
+    Ok(removed)
+}
+
+/// Checks that a request may change the files of a bucket
+///
+/// Requests without `token` are allowed when the bucket has no session and
+/// is not sealed, requests with one only if it is the token of the session
+/// of the bucket, whose expiration is then pushed back. A deleted bucket is
+/// not found. Called under the write lock of the bucket, which serializes
+/// the sessions of a bucket.
+pub(crate) async fn check(
+    state: &ServerState,
+    bucket: &mut ClientBucket,
+    token: Option<&str>,
+) -> Result<(), ApiError> {
+    if bucket.state == BucketState::Deleted {
+        return Err(ApiError::bucket_not_found(&bucket.bucket_id));
+    }
+    expire(state, bucket).await;
+
+    let Some(batch) = state.batches.get(&bucket.bucket_id) else {
+        return match token {
+            Some(_) => Err(session_not_found()),
+            None if bucket.state == BucketState::Sealed => Err(sealed()),
+            None => Ok(()),
+        };
+    };
+
+    let mut batch = batch.lock().expect("unpoisoned lock");
+    if token != Some(batch.token.as_str()) {
+        return Err(in_progress(&batch));
+    }
+    batch.expires_at = Instant::now() + state.limits.upload_session_ttl();
+
+    Ok(())
+}
+
+/// Adds a file uploaded with `token` to the session of the bucket
+pub(crate) fn record(
+    state: &ServerState,
+    bucket_id: &str,
+    token: Option<&str>,
+    hash: [u8; 32],
+) {
+    let Some(batch) = state.batches.get(&bucket_id.to_owned()) else {
+        return;
+    };
+    let mut batch = batch.lock().expect("unpoisoned lock");
+    if token == Some(batch.token.as_str()) {
+        batch.files.push(hash);
+    }
+}
+
+/// Closes the session of `token` once its files are sealed by a completion
+pub(crate) fn commit(
+    state: &ServerState,
+    bucket_id: &str,
+    token: Option<&str>,
+) {
+    let Some(token) = token else {
+        return;
+    };
+    let closed = state.batches.remove_if(&bucket_id.to_owned(), |batch| {
+        batch.lock().expect("unpoisoned lock").token == token
+    });
+    if closed.is_some() {
+        info!(event = "upload session committed", bucket_id);
+    }
+}
+
+/// Closes the session of a bucket if it expired, removing its files
+async fn expire(state: &ServerState, bucket: &mut ClientBucket) {
+    let now = Instant::now();
+    let Some(batch) = state.batches.remove_if(&bucket.bucket_id, |batch| {
+        batch.lock().expect("unpoisoned lock").expires_at <= now
+    }) else {
+        return;
+    };
+
+    let removed = rollback(state, bucket, &batch, None).await;
+    info!(
+        event = "upload session expired",
+        bucket_id = bucket.bucket_id,
+        removed
+    );
+}
+
+/// Removes the files uploaded in a session from its bucket
+///
+/// They are usually not sealed into the Merkle tree yet, which is then left
+/// as is. It is recalculated if a completion-free change of the bucket, like
+/// a deletion, sealed some of them. Returns the number of files removed.
+async fn rollback(
+    state: &ServerState,
+    bucket: &mut ClientBucket,
+    batch: &Mutex<UploadBatch>,
+    peer: Option<String>,
+) -> usize {
+    let hashes =
+        std::mem::take(&mut batch.lock().expect("unpoisoned lock").files);
+
+    let leaves = bucket.merkle_tree.leaves();
+    let mut sealed = false;
+    let mut removed = Vec::new();
+    for hash in hashes {
+        if let Some(filename) = app::take_file(state, bucket, &hash).await {
+            sealed |= leaves.contains(&hash);
+            removed.push((hash, filename));
+        }
+    }
+    if removed.is_empty() {
+        return 0;
+    }
+
+    let previous_root = bucket.merkle_tree.root_hash();
+    if sealed {
+        bucket.calculate_merkle_tree();
+    }
+    if let Err(err) = state.persist_bucket_lockless(bucket).await {
+        error!(
+            event = "failed to persist bucket",
+            bucket_id = bucket.bucket_id,
+            err
+        );
+    }
+    let count = removed.len();
+    for (hash, filename) in removed {
+        app::release_file(state, bucket, &hash, filename, peer.clone()).await;
+    }
+    if sealed {
+        replication::enqueue(state, &bucket.bucket_id).await;
+        let root = bucket.merkle_tree.root_hash();
+        state.notify(Event::root_changed(
+            &bucket.bucket_id,
+            previous_root,
+            root,
+        ));
+    }
+
+    count
+}
+
+fn in_progress(batch: &UploadBatch) -> ApiError {
+    let expires_in = batch
+        .expires_at
+        .saturating_duration_since(Instant::now())
+        .as_secs();
+
+    ApiError::new(
+        ErrorCode::UploadInProgress,
+        "another upload session of the bucket is open",
+    )
+    .with_detail("expires_in", expires_in)
+}
+
+fn sealed() -> ApiError {
+    ApiError::new(
+        ErrorCode::BucketSealed,
+        "the bucket is sealed, begin an upload session to change it",
+    )
+}
+
+fn session_not_found() -> ApiError {
+    ApiError::new(
+        ErrorCode::UploadNotFound,
+        "upload session not found or expired",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use crate::Config;
+    use axum::body::Body;
+    use axum::http::{Request, Response, StatusCode};
+    use clap::Parser;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+
+    #[tokio::test]
+    async fn test_upload_session() {
+        let config = Config::parse_from(["server", "127.0.0.1:0"]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = app::run_server_with_store(config, store).await;
+        tokio::spawn(server);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let request =
+            |path: &str, session: Option<&str>, body: &'static str| {
+                let mut request = Request::builder()
+                    .method("POST")
+                    .uri(format!("http://{addr}{path}"));
+                if let Some(session) = session {
+                    request = request.header(SESSION_HEADER, session);
+                }
+                client.request(request.body(Body::from(body)).unwrap())
+            };
+        let json = |res: Response<Incoming>| async move {
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let res = request("/begin_upload/b", None, "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let session = json(res).await["session"].as_str().unwrap().to_owned();
+        let session = Some(session.as_str());
+
+        let res = request("/begin_upload/b", None, "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(json(res).await["code"], "UPLOAD_IN_PROGRESS");
+        let res = request("/upload_file/b/a.txt", None, "a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = request("/complete_upload/b", None, "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = request("/upload_file/b/a.txt", session, "a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("/complete_upload/b", session, "").await.unwrap();
+        assert_eq!(json(res).await["leaves_count"], 1);
+
+        // The session is closed by the completion
+        let res = request("/upload_file/b/b.txt", session, "b").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = request("/begin_upload/b", None, "").await.unwrap();
+        let session = json(res).await["session"].as_str().unwrap().to_owned();
+        let session = Some(session.as_str());
+        let res = request("/upload_file/b/b.txt", session, "b").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("/abort_upload/b", session, "").await.unwrap();
+        assert_eq!(json(res).await["removed"], 1);
+
+        // The completion sealed the bucket, it takes a new session
+        let res = request("/upload_file/b/c.txt", None, "c").await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(json(res).await["code"], "BUCKET_SEALED");
+        let res = request("/complete_upload/b", None, "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = request("/begin_upload/b", None, "").await.unwrap();
+        let session = json(res).await["session"].as_str().unwrap().to_owned();
+        let session = Some(session.as_str());
+        let res = request("/upload_file/b/c.txt", session, "c").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("/complete_upload/b", session, "").await.unwrap();
+        assert_eq!(json(res).await["leaves_count"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_owned_upload_session() {
+        use ed25519_dalek::{Signer, SigningKey};
+        use ownership::{NONCE_HEADER, SIGNATURE_HEADER};
+
+        let config = Config::parse_from(["server", "127.0.0.1:0"]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = app::run_server_with_store(config, store).await;
+        tokio::spawn(server);
+
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let request = |path: &str,
+                       session: Option<&str>,
+                       action: Option<Action>,
+                       body: String| {
+            let mut request = Request::builder()
+                .method("POST")
+                .uri(format!("http://{addr}/{path}/b"));
+            if let Some(session) = session {
+                request = request.header(SESSION_HEADER, session);
+            }
+            if let Some(action) = action {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let nonce = format!("{now}:{}", rand::random::<u64>());
+                let message = ownership::message(action, "b", "", None, &nonce);
+                let signature = key.sign(message.as_bytes());
+                request = request
+                    .header(SIGNATURE_HEADER, hex::encode(signature.to_bytes()))
+                    .header(NONCE_HEADER, nonce);
+            }
+            client.request(request.body(Body::from(body)).unwrap())
+        };
+
+        let owner = serde_json::json!({
+            "public_key": hex::encode(key.verifying_key().as_bytes())
+        });
+        let res = request("owner", None, None, owner.to_string())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Sessions of an owned bucket are opened and aborted by its owner
+        let res = request("begin_upload", None, None, String::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let action = Some(Action::AbortUpload);
+        let res = request("begin_upload", None, action, String::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let action = Some(Action::BeginUpload);
+        let res = request("begin_upload", None, action, String::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let session = body["session"].as_str();
+
+        let res = request("abort_upload", session, None, String::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let action = Some(Action::AbortUpload);
+        let res = request("abort_upload", session, action, String::new())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use tokio::fs;
+use tokio::io::AsyncWrite;
+use tokio_stream::Stream;
+use tokio_util::io::ReaderStream;
+
+/// A stream of the content of a blob
+pub(crate) type BlobStream =
+    Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// A writer filling a blob
+pub(crate) type BlobWriter = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// Storage of the file contents, addressed by `/` separated keys
+///
+/// Handlers go through this trait instead of the filesystem so that the
+/// storage can be swapped without touching them.
+#[async_trait::async_trait]
+pub(crate) trait BlobStore: Send + Sync {
+    /// Stores a whole blob, replacing any existing one
+    async fn put(&self, key: &str, data: Bytes) -> io::Result<()>;
+
+    /// Reads a whole blob
+    async fn get(&self, key: &str) -> io::Result<Bytes>;
+
+    /// Streams a blob, returning its size along with the stream
+    async fn stream(&self, key: &str) -> io::Result<(u64, BlobStream)>;
+
+    /// Deletes a blob, a missing blob is not an error
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Lists the keys starting with `prefix`
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Returns the size of a blob, `None` if it does not exist
+    async fn size(&self, key: &str) -> io::Result<Option<u64>>;
+
+    /// Moves a blob to another key, replacing any existing one
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Creates an empty blob and returns a writer filling it
+    async fn create(&self, key: &str) -> io::Result<BlobWriter>;
+
+    /// Returns a writer appending to an existing blob
+    async fn append(&self, key: &str) -> io::Result<BlobWriter>;
+
+    /// Shrinks a blob to its first `len` bytes
+    async fn truncate(&self, key: &str, len: u64) -> io::Result<()>;
+}
+
+/// Blob store on the local filesystem, a blob `key` is the file `root/key`
+pub(crate) struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub(crate) fn new<P: AsRef<Path>>(root: P) -> Self {
+        LocalStore {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    /// Returns the path of a blob, creating its parent folder
+    async fn path_with_dir(&self, key: &str) -> io::Result<PathBuf> {
+        let path = self.path(key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        Ok(path)
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for LocalStore {
+    async fn put(&self, key: &str, data: Bytes) -> io::Result<()> {
+        fs::write(self.path_with_dir(key).await?, data).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        fs::read(self.path(key)).await.map(Bytes::from)
+    }
+
+    async fn stream(&self, key: &str) -> io::Result<(u64, BlobStream)> {
+        let file = fs::File::open(self.path(key)).await?;
+        let size = file.metadata().await?.len();
+        Ok((size, Box::pin(ReaderStream::new(file))))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        // Only the folder of the prefix is walked
+        let dir = match prefix.rfind('/') {
+            Some(end) => self.path(&prefix[..end]),
+            None => self.root.clone(),
+        };
+
+        let mut keys = Vec::new();
+        let mut dirs = vec![dir];
+
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+
+                let key = path
+                    .strip_prefix(&self.root)
+                    .map(|key| key.to_string_lossy().replace('\\', "/"))
+                    .unwrap_or_default();
+                if key.starts_with(prefix) {
+                    keys.push(key);
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn size(&self, key: &str) -> io::Result<Option<u64>> {
+        match fs::metadata(self.path(key)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.path(from), self.path_with_dir(to).await?).await
+    }
+
+    async fn create(&self, key: &str) -> io::Result<BlobWriter> {
+        let file = fs::File::create(self.path_with_dir(key).await?).await?;
+        Ok(Box::pin(file))
+    }
+
+    async fn append(&self, key: &str) -> io::Result<BlobWriter> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .open(self.path(key))
+            .await?;
+        Ok(Box::pin(file))
+    }
+
+    async fn truncate(&self, key: &str, len: u64) -> io::Result<()> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(self.path(key))
+            .await?;
+        file.set_len(len).await
+    }
+}
+
+/// Blob store keeping the blobs in memory, for tests and embedded servers
+#[derive(Clone, Default)]
+pub(crate) struct MemoryStore {
+    blobs: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryStore {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    fn not_found(key: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, format!("no blob {key}"))
+    }
+
+    fn writer(&self, key: &str) -> BlobWriter {
+        Box::pin(MemoryWriter {
+            blobs: self.blobs.clone(),
+            key: key.to_owned(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for MemoryStore {
+    async fn put(&self, key: &str, data: Bytes) -> io::Result<()> {
+        let mut blobs = self.blobs.lock().expect("unpoisoned lock");
+        blobs.insert(key.to_owned(), data.to_vec());
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        let blobs = self.blobs.lock().expect("unpoisoned lock");
+        let data = blobs.get(key).ok_or_else(|| Self::not_found(key))?;
+        Ok(Bytes::copy_from_slice(data))
+    }
+
+    async fn stream(&self, key: &str) -> io::Result<(u64, BlobStream)> {
+        let data = self.get(key).await?;
+        let size = data.len() as u64;
+        Ok((size, Box::pin(tokio_stream::once(Ok(data)))))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.blobs.lock().expect("unpoisoned lock").remove(key);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let blobs = self.blobs.lock().expect("unpoisoned lock");
+        Ok(blobs
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn size(&self, key: &str) -> io::Result<Option<u64>> {
+        let blobs = self.blobs.lock().expect("unpoisoned lock");
+        Ok(blobs.get(key).map(|data| data.len() as u64))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut blobs = self.blobs.lock().expect("unpoisoned lock");
+        let data = blobs.remove(from).ok_or_else(|| Self::not_found(from))?;
+        blobs.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    async fn create(&self, key: &str) -> io::Result<BlobWriter> {
+        let mut blobs = self.blobs.lock().expect("unpoisoned lock");
+        blobs.insert(key.to_owned(), Vec::new());
+        Ok(self.writer(key))
+    }
+
+    async fn append(&self, key: &str) -> io::Result<BlobWriter> {
+        if !self
+            .blobs
+            .lock()
+            .expect("unpoisoned lock")
+            .contains_key(key)
+        {
+            return Err(Self::not_found(key));
+        }
+        Ok(self.writer(key))
+    }
+
+    async fn truncate(&self, key: &str, len: u64) -> io::Result<()> {
+        let mut blobs = self.blobs.lock().expect("unpoisoned lock");
+        let data = blobs.get_mut(key).ok_or_else(|| Self::not_found(key))?;
+        data.truncate(len as usize);
+        Ok(())
+    }
+}
+
+/// Writer appending to a blob of a [`MemoryStore`]
+struct MemoryWriter {
+    blobs: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    key: String,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut blobs = self.blobs.lock().expect("unpoisoned lock");
+        let res = match blobs.get_mut(&self.key) {
+            Some(data) => {
+                data.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            None => Err(MemoryStore::not_found(&self.key)),
+        };
+        Poll::Ready(res)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::erasure::{ErasureConfig, ErasureStore};
+    use tempdir::TempDir;
+    use tokio::io::AsyncWriteExt;
+    use tokio_stream::StreamExt;
+
+    /// Reads a whole stream of blob content
+    async fn collect(mut stream: BlobStream) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data)
+    }
+
+    /// Exercises every operation of a store, which must be empty
+    async fn check_store(store: &dyn BlobStore) {
+        store.put("ab/abcd", Bytes::from("data")).await.unwrap();
+        assert_eq!(store.get("ab/abcd").await.unwrap(), "data");
+        assert_eq!(store.size("ab/abcd").await.unwrap(), Some(4));
+        assert_eq!(store.size("ab/none").await.unwrap(), None);
+
+        let mut writer = store.create("tmp/1").await.unwrap();
+        writer.write_all(b"hello ").await.unwrap();
+        writer.flush().await.unwrap();
+        let mut writer = store.append("tmp/1").await.unwrap();
+        writer.write_all(b"world!!").await.unwrap();
+        writer.flush().await.unwrap();
+        store.truncate("tmp/1", 11).await.unwrap();
+
+        store.rename("tmp/1", "cd/cdef").await.unwrap();
+        let (size, stream) = store.stream("cd/cdef").await.unwrap();
+        assert_eq!(size, 11);
+        assert_eq!(collect(stream).await.unwrap(), b"hello world");
+
+        assert_eq!(store.list("").await.unwrap(), ["ab/abcd", "cd/cdef"]);
+        assert_eq!(store.list("cd/").await.unwrap(), ["cd/cdef"]);
+
+        store.delete("ab/abcd").await.unwrap();
+        store.delete("ab/abcd").await.unwrap();
+        assert_eq!(store.list("").await.unwrap(), ["cd/cdef"]);
+
+        assert!(store.append("ab/abcd").await.is_err());
+        assert!(store.rename("ab/abcd", "ef/efgh").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_store() {
+        let tmp_dir = TempDir::new("test_local_store").expect("valid temp dir");
+        check_store(&LocalStore::new(tmp_dir.path())).await;
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        check_store(&MemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_erasure_store() {
+        let tmp_dir = TempDir::new("test_erasure").expect("valid temp dir");
+        let config = ErasureConfig {
+            erasure_dir: (0..3)
+                .map(|i| {
+                    tmp_dir.path().join(i.to_string()).display().to_string()
+                })
+                .collect(),
+            erasure_parity: 1,
+        };
+        let store = ErasureStore::new(tmp_dir.path().join("staging"), &config)
+            .expect("valid erasure config");
+        check_store(&store).await;
+    }
+}
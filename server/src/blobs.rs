@@ -0,0 +1,449 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use bytes::Bytes;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::RwLock;
+use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
+
+use crate::blob_store::{BlobStore, BlobStream};
+use crate::client_bucket::{ClientBucket, LEGACY_UPLOADS_PREFIX};
+use crate::database::DB;
+
+/// Prefix of the blobs receiving uploads
+const TMP_PREFIX: &str = "tmp/";
+
+/// Prefix of the received blobs staged in their final form, see [`stage`]
+const STAGED_PREFIX: &str = "staged/";
+
+/// Prefix of the quarantined blobs
+const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// Returns the key of the blob of a file, `<hash[0..2]>/<hash>`
+///
+/// Identical files uploaded to several buckets share the same blob.
+pub(crate) fn blob_key(hash: &[u8; 32]) -> String {
+    let hash = hex::encode(hash);
+    format!("{}/{}", &hash[..2], hash)
+}
+
+/// Returns the hash of the blob at `key`, `None` if `key` is not a
+/// [`blob_key`]
+pub(crate) fn key_hash(key: &str) -> Option<[u8; 32]> {
+    let (_, hash) = key.split_once('/')?;
+    let hash: [u8; 32] = hex::decode(hash).ok()?.try_into().ok()?;
+    (blob_key(&hash) == key).then_some(hash)
+}
+
+/// Returns the key a blob is moved to when it is quarantined
+pub(crate) fn quarantine_key(key: &str) -> String {
+    format!("{QUARANTINE_PREFIX}{key}")
+}
+
+/// Returns the key of a blob pushed by a primary server, until a bucket
+/// state refers to it
+pub(crate) fn replica_key(hash: &[u8; 32]) -> String {
+    format!("{}peer-{}", TMP_PREFIX, hex::encode(hash))
+}
+
+/// Returns a new key to receive an upload before its hash is known
+pub(crate) fn tmp_key() -> String {
+    format!("{}{}", TMP_PREFIX, hex::encode(rand::random::<[u8; 16]>()))
+}
+
+/// Returns true if `key` is the key of an upload in progress
+pub(crate) fn is_tmp_key(key: &str) -> bool {
+    key.starts_with(TMP_PREFIX)
+}
+
+/// Returns true if `key` is the key of a blob staged by [`stage`]
+pub(crate) fn is_staged_key(key: &str) -> bool {
+    key.starts_with(STAGED_PREFIX)
+}
+
+/// Removes the uploads left unfinished by a previous run, and the blobs
+/// they staged
+pub(crate) async fn remove_tmp(store: &dyn BlobStore) {
+    for prefix in [TMP_PREFIX, STAGED_PREFIX] {
+        let keys = match store.list(prefix).await {
+            Ok(keys) => keys,
+            Err(err) => {
+                warn!(event = "failed to list temporary blobs", ?err);
+                return;
+            }
+        };
+
+        for key in keys {
+            if let Err(err) = store.delete(&key).await {
+                warn!(event = "failed to remove temporary blob", key, ?err);
+            }
+        }
+    }
+}
+
+/// Returns the size of the blob of `hash`, `None` if it is not stored
+pub(crate) async fn size(
+    store: &dyn BlobStore,
+    db: &DB,
+    hash: &[u8; 32],
+) -> io::Result<Option<u64>> {
+    if let Some(data) = db.inline_blob(hash).map_err(io::Error::other)? {
+        return Ok(Some(data.len() as u64));
+    }
+    store.size(&blob_key(hash)).await
+}
+
+/// Streams the blob of `hash`, returning its size along with the stream
+pub(crate) async fn stream(
+    store: &dyn BlobStore,
+    db: &DB,
+    hash: &[u8; 32],
+) -> io::Result<(u64, BlobStream)> {
+    if let Some(data) = db.inline_blob(hash).map_err(io::Error::other)? {
+        let size = data.len() as u64;
+        let stream = tokio_stream::once(Ok(Bytes::from(data)));
+        return Ok((size, Box::pin(stream)));
+    }
+    store.stream(&blob_key(hash)).await
+}
+
+/// A received file in the form of its blob, ready to be referenced by
+/// [`add_ref`]
+pub(crate) enum Staged {
+    /// Content of at most `--inline-threshold` bytes, stored in the database
+    Inline(Bytes),
+    /// Blob stored, and erasure coded, at a staging key
+    Stored(String),
+}
+
+impl Staged {
+    /// Deletes the staged blob if [`add_ref`] did not move it into place
+    pub(crate) async fn discard(&self, store: &dyn BlobStore) {
+        if let Staged::Stored(key) = self {
+            if let Err(err) = store.delete(key).await {
+                warn!(event = "failed to remove staged blob", key, ?err);
+            }
+        }
+    }
+}
+
+/// Turns the blob at `tmp_key` into the form it is stored in
+///
+/// Blobs of at most `inline_threshold` bytes are read to be moved into the
+/// database, the others are moved to a staging key, which encodes them with
+/// erasure coding. This is the costly part of storing a blob, done without
+/// the database lock so that only the cheap [`add_ref`] runs under it.
+pub(crate) async fn stage(
+    store: &dyn BlobStore,
+    tmp_key: &str,
+    inline_threshold: u64,
+) -> io::Result<Staged> {
+    if store.size(tmp_key).await?.unwrap_or_default() <= inline_threshold {
+        let data = store.get(tmp_key).await?;
+        store.delete(tmp_key).await?;
+        return Ok(Staged::Inline(data));
+    }
+
+    let key = format!(
+        "{}{}",
+        STAGED_PREFIX,
+        hex::encode(rand::random::<[u8; 16]>())
+    );
+    store.rename(tmp_key, &key).await?;
+    Ok(Staged::Stored(key))
+}
+
+/// Stores a staged file as the blob of `hash` and counts a new reference
+/// to it
+///
+/// The staged blob is moved into place if the blob is not stored yet,
+/// otherwise it is left to [`Staged::discard`]. Callers serialize reference
+/// updates by holding the database write lock.
+///
+/// Returns the number of references to the blob
+pub(crate) async fn add_ref(
+    store: &dyn BlobStore,
+    db: &DB,
+    hash: &[u8; 32],
+    staged: &Staged,
+) -> io::Result<u64> {
+    let refs = db.blob_refs(hash).map_err(io::Error::other)?;
+
+    if refs == 0 || size(store, db, hash).await?.is_none() {
+        match staged {
+            Staged::Inline(data) => db
+                .set_inline_blob(hash, Some(data))
+                .map_err(io::Error::other)?,
+            Staged::Stored(key) => store.rename(key, &blob_key(hash)).await?,
+        }
+    }
+
+    db.set_blob_refs(hash, refs + 1).map_err(io::Error::other)?;
+
+    Ok(refs + 1)
+}
+
+/// Counts a new reference to a stored blob, for a copy of a file
+///
+/// Returns `None`, counting nothing, if the blob is no longer referenced.
+/// Callers hold the database write lock as for [`add_ref`].
+pub(crate) fn share(db: &DB, hash: &[u8; 32]) -> Result<Option<u64>, String> {
+    let refs = db.blob_refs(hash)?;
+    if refs == 0 {
+        return Ok(None);
+    }
+    db.set_blob_refs(hash, refs + 1)?;
+
+    Ok(Some(refs + 1))
+}
+
+/// Drops a reference to the blob of `hash`, removing the blob once it is no
+/// longer referenced
+///
+/// Returns the number of remaining references
+pub(crate) async fn release(
+    store: &dyn BlobStore,
+    db: &DB,
+    hash: &[u8; 32],
+) -> io::Result<u64> {
+    let refs = db
+        .blob_refs(hash)
+        .map_err(io::Error::other)?
+        .saturating_sub(1);
+    db.set_blob_refs(hash, refs).map_err(io::Error::other)?;
+
+    if refs == 0 {
+        db.set_inline_blob(hash, None).map_err(io::Error::other)?;
+        let key = blob_key(hash);
+        store.delete(&key).await?;
+        info!(event = "blob removed", key);
+    }
+
+    Ok(refs)
+}
+
+/// Moves the files of a bucket stored with the legacy per-bucket layout,
+/// `<uploads_dir>/<bucket_id>/<filename>`, to the blob store
+///
+/// The bytes used by the bucket are counted again once its files are blobs,
+/// the records of the first servers hold no byte count. Returns true if at
+/// least one file was migrated, the bucket record then changed and must be
+/// persisted
+pub(crate) async fn migrate_bucket(
+    store: &dyn BlobStore,
+    db: &RwLock<DB>,
+    bucket: &mut ClientBucket,
+    inline_threshold: u64,
+    uploads_dir: &Path,
+) -> bool {
+    // The records hold the paths as written by the former layout
+    let legacy: Vec<([u8; 32], PathBuf)> = bucket
+        .files
+        .iter()
+        .filter_map(|(hash, name)| {
+            let path = name.strip_prefix(LEGACY_UPLOADS_PREFIX)?;
+            match legacy_path(uploads_dir, path) {
+                Some(path) => Some((*hash, path)),
+                None => {
+                    warn!(event = "legacy path escapes uploads folder", name);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    let mut migrated = false;
+    for (hash, path) in &legacy {
+        let tmp_key = tmp_key();
+        let res = async {
+            copy_file(store, path, &tmp_key).await?;
+            let staged = stage(store, &tmp_key, inline_threshold).await?;
+            let refs = add_ref(store, &*db.write().await, hash, &staged).await;
+            staged.discard(store).await;
+            refs
+        };
+
+        // A file that failed keeps its legacy name, the next load retries it
+        match res.await {
+            Ok(refs) => {
+                info!(event = "blob migrated", path = %path.display(), refs)
+            }
+            Err(err) => {
+                warn!(event = "failed to migrate blob", path = %path.display(), ?err);
+                continue;
+            }
+        }
+        if let Err(err) = fs::remove_file(path).await {
+            warn!(event = "failed to remove legacy file", path = %path.display(), ?err);
+        }
+
+        // The record keeps only the file name from now on
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        bucket.files.insert(*hash, filename);
+        migrated = true;
+    }
+    if !migrated {
+        return false;
+    }
+
+    let db = db.read().await;
+    let mut bytes_used = 0;
+    for hash in bucket.files.keys() {
+        match size(store, &db, hash).await {
+            Ok(size) => bytes_used += size.unwrap_or_default(),
+            Err(err) => {
+                warn!(event = "failed to count the bucket bytes", ?err);
+                return true;
+            }
+        }
+    }
+    bucket.bytes_used = bytes_used;
+
+    true
+}
+
+/// Returns the path of a file recorded by the legacy layout, relative to
+/// `uploads_dir`, `None` if it would point outside of it
+///
+/// The recorded paths were built from client supplied file names, a name
+/// like `../../etc/passwd` must not be read, and removed, by the migration.
+fn legacy_path(uploads_dir: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path.trim_start_matches('/'));
+    let contained = path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+
+    contained.then(|| uploads_dir.join(path))
+}
+
+/// Copies a blob from a store to another
+pub(crate) async fn copy_blob(
+    from: &dyn BlobStore,
+    to: &dyn BlobStore,
+    key: &str,
+) -> io::Result<()> {
+    let (_, mut stream) = from.stream(key).await?;
+    let mut writer = to.create(key).await?;
+
+    while let Some(chunk) = stream.next().await {
+        writer.write_all(&chunk?).await?;
+    }
+    writer.flush().await
+}
+
+/// Copies a local file into the store
+async fn copy_file(
+    store: &dyn BlobStore,
+    path: &Path,
+    key: &str,
+) -> io::Result<()> {
+    let mut reader = ReaderStream::new(fs::File::open(path).await?);
+    let mut writer = store.create(key).await?;
+
+    while let Some(chunk) = reader.next().await {
+        writer.write_all(&chunk?).await?;
+    }
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_legacy_path() {
+        let uploads_dir = Path::new("/data/buckets");
+        assert_eq!(
+            legacy_path(uploads_dir, "/bucket/a.txt"),
+            Some(PathBuf::from("/data/buckets/bucket/a.txt"))
+        );
+        assert_eq!(legacy_path(uploads_dir, "/bucket/../../etc/passwd"), None);
+        assert!(legacy_path(uploads_dir, "/bucket/./a.txt").is_some());
+        assert_eq!(legacy_path(uploads_dir, "/"), None);
+    }
+
+    #[tokio::test]
+    async fn test_add_ref() {
+        let store = MemoryStore::new();
+        let db = DB::in_memory();
+        let hash = [1u8; 32];
+
+        // The first copy is moved into place, the next ones discarded
+        for expected in [1, 2] {
+            let tmp_key = tmp_key();
+            store.put(&tmp_key, Bytes::from("hello")).await.unwrap();
+            let staged = stage(&store, &tmp_key, 0).await.unwrap();
+            assert!(matches!(staged, Staged::Stored(_)));
+            let refs = add_ref(&store, &db, &hash, &staged).await.unwrap();
+            assert_eq!(refs, expected);
+            staged.discard(&store).await;
+        }
+        assert_eq!(store.list("").await.unwrap(), vec![blob_key(&hash)]);
+
+        // Small blobs are moved into the database
+        let tmp_key = tmp_key();
+        store.put(&tmp_key, Bytes::from("hi")).await.unwrap();
+        let staged = stage(&store, &tmp_key, 2).await.unwrap();
+        assert!(matches!(staged, Staged::Inline(_)));
+        add_ref(&store, &db, &[2u8; 32], &staged).await.unwrap();
+        assert_eq!(db.inline_blob(&[2u8; 32]), Ok(Some(b"hi".to_vec())));
+        assert_eq!(store.list("").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_bucket() {
+        let uploads_dir = TempDir::new("uploads").unwrap();
+        let bucket_dir = uploads_dir.path().join("bucket");
+        std::fs::create_dir(&bucket_dir).unwrap();
+        std::fs::write(bucket_dir.join("a.txt"), "hello").unwrap();
+        std::fs::write(bucket_dir.join("b.txt"), "world!").unwrap();
+
+        let store = MemoryStore::new();
+        let db = RwLock::new(DB::in_memory());
+        let mut bucket = ClientBucket::new("bucket".to_owned());
+        let records = [
+            ([1u8; 32], "a.txt"),
+            ([2u8; 32], "b.txt"),
+            ([3u8; 32], "c.txt"),
+        ];
+        for (hash, name) in records {
+            let path = format!("{LEGACY_UPLOADS_PREFIX}/bucket/{name}");
+            bucket.files.insert(hash, path);
+        }
+
+        // The bucket of the first servers is counted once migrated
+        let migrated =
+            migrate_bucket(&store, &db, &mut bucket, 0, uploads_dir.path())
+                .await;
+        assert!(migrated);
+        assert_eq!(bucket.files[&[1u8; 32]], "a.txt");
+        assert_eq!(bucket.bytes_used, 11);
+        assert!(!bucket_dir.join("a.txt").exists());
+
+        // A missing file keeps its legacy record and is retried
+        let legacy = format!("{LEGACY_UPLOADS_PREFIX}/bucket/c.txt");
+        assert_eq!(bucket.files[&[3u8; 32]], legacy);
+        let migrated =
+            migrate_bucket(&store, &db, &mut bucket, 0, uploads_dir.path())
+                .await;
+        assert!(!migrated);
+        assert_eq!(bucket.files[&[3u8; 32]], legacy);
+
+        std::fs::write(bucket_dir.join("c.txt"), "!").unwrap();
+        let migrated =
+            migrate_bucket(&store, &db, &mut bucket, 0, uploads_dir.path())
+                .await;
+        assert!(migrated);
+        assert_eq!(bucket.files[&[3u8; 32]], "c.txt");
+        assert_eq!(bucket.bytes_used, 12);
+    }
+}
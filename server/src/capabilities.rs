@@ -0,0 +1,130 @@
+use crate::{app, compression, resumable, Config};
+
+/// Version of the HTTP API spoken by the server
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+
+/// Features supported by this server instance, served at `GET /capabilities`
+/// so that clients can adapt instead of assuming a fixed server
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub(crate) struct Capabilities {
+    pub protocol_version: u32,
+    /// Maximum accepted upload body size, `None` if unbounded
+    pub max_body_size: Option<u64>,
+    /// Maximum size of a decompressed upload body
+    pub max_decompressed_size: u64,
+    /// Supported `Content-Encoding` values of upload bodies
+    pub content_encodings: Vec<&'static str>,
+    /// Supported `Accept-Encoding` values of the proof, tree and listing
+    /// replies
+    pub accept_encodings: Vec<&'static str>,
+    /// Whether `Range` requests are served on file downloads
+    pub range_requests: bool,
+    /// Whether several proofs can be requested at once
+    pub multiproof: bool,
+    /// Maximum number of proofs of a batch proof request
+    pub max_batch_proofs: usize,
+    /// Whether proofs can be requested by leaf hash
+    pub proof_by_hash: bool,
+    /// Whether files can be uploaded in chunks through `upload_init`
+    pub resumable_upload: bool,
+    /// Maximum size of a chunk of a resumable upload
+    pub max_chunk_size: u64,
+    /// Whether the chunks of a resumable upload can be sent at once as
+    /// numbered parts through `upload_part`
+    pub multipart_upload: bool,
+    /// Maximum number of parts of a multi-part upload, each of at most
+    /// `max_chunk_size` bytes
+    pub max_parts: u32,
+    /// Whether uploads can be grouped in a session through `begin_upload`
+    pub upload_sessions: bool,
+    /// Authentication schemes enabled on this server: `ed25519` owner
+    /// signatures, `api_key` keys issued through the admin API, `jwt`
+    /// tokens, `mtls` client certificates and `presigned_url` downloads
+    pub auth_schemes: Vec<&'static str>,
+}
+
+impl Capabilities {
+    pub(crate) fn current(config: &Config) -> Self {
+        let max_body_size = config.limits.max_upload_size;
+        Capabilities {
+            protocol_version: PROTOCOL_VERSION,
+            max_body_size,
+            max_decompressed_size: max_body_size
+                .unwrap_or(compression::MAX_DECOMPRESSED_SIZE),
+            content_encodings: vec!["gzip", "zstd"],
+            accept_encodings: compression::Encoding::ALL
+                .iter()
+                .map(|encoding| encoding.as_str())
+                .collect(),
+            range_requests: false,
+            multiproof: true,
+            max_batch_proofs: app::MAX_BATCH_PROOFS,
+            proof_by_hash: true,
+            resumable_upload: true,
+            max_chunk_size: resumable::MAX_CHUNK_SIZE,
+            multipart_upload: true,
+            max_parts: resumable::MAX_PARTS,
+            upload_sessions: true,
+            auth_schemes: auth_schemes(config),
+        }
+    }
+}
+
+/// Returns the authentication schemes enabled by `config`
+fn auth_schemes(config: &Config) -> Vec<&'static str> {
+    // Owner signatures and pre-signed URLs need no configuration
+    let mut schemes = vec!["ed25519"];
+    if config.admin.admin_token.is_some() || config.admin.require_api_key {
+        schemes.push("api_key");
+    }
+    if config.jwt.jwt_public_key.is_some() || config.jwt.jwks_url.is_some() {
+        schemes.push("jwt");
+    }
+    if config.tls.tls_client_ca.is_some() {
+        schemes.push("mtls");
+    }
+    schemes.push("presigned_url");
+    schemes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_auth_schemes() {
+        let schemes = |args: &[&str]| {
+            let config = Config::parse_from(
+                ["server", "127.0.0.1:0"].iter().chain(args),
+            );
+            Capabilities::current(&config).auth_schemes
+        };
+
+        // Only the schemes needing no configuration are advertised by default
+        assert_eq!(schemes(&[]), ["ed25519", "presigned_url"]);
+
+        // The others follow the options enabling them
+        assert_eq!(
+            schemes(&["--admin-token", "secret"]),
+            ["ed25519", "api_key", "presigned_url"]
+        );
+        assert_eq!(
+            schemes(&["--jwks-url", "https://idp.example/jwks.json"]),
+            ["ed25519", "jwt", "presigned_url"]
+        );
+        assert_eq!(
+            schemes(&[
+                "--jwt-public-key",
+                "jwt.pem",
+                "--tls-cert",
+                "cert.pem",
+                "--tls-key",
+                "key.pem",
+                "--tls-client-ca",
+                "ca.pem",
+            ]),
+            ["ed25519", "jwt", "mtls", "presigned_url"]
+        );
+    }
+}
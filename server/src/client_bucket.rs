@@ -1,9 +1,11 @@
 use merkle::tree as merkle;
 use std::collections::BTreeMap;
-use std::io;
-use tokio::fs;
 
-pub(crate) const UPLOADS_DIR: &str = "./buckets";
+use crate::attestation::Attestation;
+
+/// Prefix of the file paths recorded by the former per-bucket layout,
+/// `./buckets/<bucket_id>/<filename>`
+pub(crate) const LEGACY_UPLOADS_PREFIX: &str = "./buckets";
 
 /// Represents a bucket of files uploaded by a client together with calculated
 /// Merkle tree
@@ -11,9 +13,45 @@ pub(crate) const UPLOADS_DIR: &str = "./buckets";
 pub(crate) struct ClientBucket {
     pub bucket_id: String,
 
-    /// Map file hash to file path
+    /// Map file hash to file name, the content is stored in the blob of the
+    /// hash
     pub files: BTreeMap<[u8; 32], String>,
     pub merkle_tree: merkle::Tree,
+
+    /// Total size of the stored files in bytes
+    pub bytes_used: u64,
+
+    /// Stored with the bucket metadata, buckets of the former single record
+    /// layout are open
+    #[serde(skip)]
+    pub state: BucketState,
+}
+
+/// Lifecycle state of a bucket
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize,
+)]
+pub(crate) enum BucketState {
+    /// Files are uploaded without upload session until the bucket is sealed
+    #[default]
+    Open,
+    /// Sealed by `complete_upload`, files are only uploaded in a new upload
+    /// session, sealed by its completion
+    Sealed,
+    /// Erased by a bucket deletion, the requests still holding the bucket
+    /// neither change nor persist it. Never stored
+    Deleted,
+}
+
+/// Merkle root of a bucket as returned to clients
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub(crate) struct BucketRoot {
+    /// Hex encoded root, `None` for an empty bucket
+    pub root: Option<String>,
+    pub leaves_count: usize,
+    /// Signature of the root by the server, see `/identity`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<Attestation>,
 }
 
 impl ClientBucket {
@@ -22,6 +60,8 @@ impl ClientBucket {
             bucket_id,
             files: BTreeMap::new(),
             merkle_tree: merkle::Tree::default(),
+            bytes_used: 0,
+            state: BucketState::Open,
         }
     }
 
@@ -31,18 +71,16 @@ impl ClientBucket {
         self.merkle_tree = merkle::Tree::build_from_leaves(leaves);
     }
 
-    pub(crate) fn get_filepath(&self, index: usize) -> Option<&String> {
-        self.files.iter().nth(index).map(|(_, path)| path)
-    }
-
-    /// Creates bucket folder if it does not exist
-    pub(crate) async fn get_or_create_dir(&self) -> io::Result<String> {
-        let bucket_dir: String = self.get_dir();
-        fs::create_dir_all(&bucket_dir).await?;
-        Ok(bucket_dir)
+    pub(crate) fn root(&self) -> BucketRoot {
+        BucketRoot {
+            root: self.merkle_tree.root_hash().map(hex::encode),
+            leaves_count: self.merkle_tree.leaves_count(),
+            attestation: None,
+        }
     }
 
-    pub(crate) fn get_dir(&self) -> String {
-        format!("{}/{}", UPLOADS_DIR, self.bucket_id)
+    /// Returns the hash of the file at `index`, which addresses its blob
+    pub(crate) fn get_file_hash(&self, index: usize) -> Option<[u8; 32]> {
+        self.files.keys().nth(index).copied()
     }
 }
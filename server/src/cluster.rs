@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::header::{HOST, LOCATION};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use clap::Args;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
+
+use crate::app::ServerState;
+use crate::errors::{ApiError, ErrorCode};
+
+/// Number of points of each node on the hash ring
+const VIRTUAL_NODES: usize = 64;
+
+/// Header marking a request forwarded by another node, which is always
+/// served locally so that nodes disagreeing on the ring cannot loop
+const FORWARDED_HEADER: &str = "x-cluster-forwarded";
+
+/// Routes whose first parameter is a bucket id
+const BUCKET_ROUTES: &[&str] = &[
+    "upload_file",
+    "upload_init",
+    "begin_upload",
+    "abort_upload",
+    "complete_upload",
+    "file",
+    "bucket",
+    "proof",
+    "proof_by_hash",
+    "proofs",
+    "root",
+    "roots",
+    "retention",
+    "tree",
+    "list",
+    "search",
+    "copy",
+    "export",
+    "import",
+    "stats",
+    "meta",
+    "owner",
+    "presign",
+    "audit",
+    "events",
+    "dav",
+];
+
+/// Routes whose first parameter is an upload session id
+const SESSION_ROUTES: &[&str] =
+    &["upload_chunk", "upload_part", "upload_finish"];
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct ClusterConfig {
+    /// URLs of all the nodes of the cluster, this one included, buckets are
+    /// spread across them by consistent hashing of their id
+    #[arg(long = "cluster-node", value_delimiter = ',')]
+    pub cluster_nodes: Vec<String>,
+
+    /// URL of this node, one of `--cluster-node`
+    #[arg(long)]
+    pub node_url: Option<String>,
+
+    /// Redirect the requests of buckets owned by other nodes instead of
+    /// proxying them
+    #[arg(long)]
+    pub cluster_redirect: bool,
+}
+
+/// Consistent hash ring mapping keys to nodes
+///
+/// Each node is placed at several points of the ring, a key belongs to the
+/// node of the first point following its hash. Adding or removing a node
+/// only moves the keys of its points.
+#[derive(Clone, Debug)]
+pub(crate) struct Ring {
+    points: BTreeMap<u64, usize>,
+    nodes: Vec<String>,
+}
+
+impl Ring {
+    pub(crate) fn new(nodes: &[String]) -> Self {
+        let mut points = BTreeMap::new();
+        for (index, node) in nodes.iter().enumerate() {
+            for point in 0..VIRTUAL_NODES {
+                points.insert(ring_hash(&format!("{node}#{point}")), index);
+            }
+        }
+
+        Ring {
+            points,
+            nodes: nodes.to_vec(),
+        }
+    }
+
+    /// Returns the node owning `key`, `None` if the ring is empty
+    pub(crate) fn owner(&self, key: &str) -> Option<&str> {
+        let hash = ring_hash(key);
+        let (_, index) = self
+            .points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())?;
+
+        Some(&self.nodes[*index])
+    }
+}
+
+fn ring_hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"))
+}
+
+/// This node and the ring of the cluster it belongs to
+#[derive(Clone, Debug)]
+pub(crate) struct Cluster {
+    ring: Ring,
+    node_url: String,
+    redirect: bool,
+}
+
+impl Cluster {
+    /// Returns `None` if no cluster is configured
+    pub(crate) fn new(config: &ClusterConfig) -> Option<Self> {
+        if config.cluster_nodes.is_empty() {
+            return None;
+        }
+
+        let node_url = config.node_url.clone().expect("--node-url is set");
+        assert!(
+            config.cluster_nodes.contains(&node_url),
+            "--node-url is one of --cluster-node"
+        );
+
+        Some(Cluster {
+            ring: Ring::new(&config.cluster_nodes),
+            node_url,
+            redirect: config.cluster_redirect,
+        })
+    }
+
+    /// Returns true if this node owns `key`
+    pub(crate) fn owns(&self, key: &str) -> bool {
+        self.remote_owner(key).is_none()
+    }
+
+    /// Returns the URL of the node owning `key`, `None` if it is this one
+    fn remote_owner(&self, key: &str) -> Option<&str> {
+        self.ring.owner(key).filter(|owner| *owner != self.node_url)
+    }
+}
+
+/// Returns true if `key`, a bucket or upload session id, belongs to this
+/// node
+///
+/// Always true outside of a cluster.
+pub(crate) fn owns(cluster: &Option<Cluster>, key: &str) -> bool {
+    cluster.as_ref().is_none_or(|cluster| cluster.owns(key))
+}
+
+/// Returns the bucket or upload session id routing a request path
+fn routing_key(path: &str) -> Option<&str> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    let route = segments.next()?;
+    let key = segments.next().filter(|key| !key.is_empty())?;
+
+    (BUCKET_ROUTES.contains(&route) || SESSION_ROUTES.contains(&route))
+        .then_some(key)
+}
+
+/// Forwards the requests of buckets owned by other nodes to their owner
+///
+/// The requests served by this node go on to the local routes.
+pub(crate) async fn forward(
+    State(state): State<Arc<ServerState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let forwarded = req.headers().contains_key(FORWARDED_HEADER);
+    let owner = match (&state.cluster, forwarded) {
+        (Some(cluster), false) => routing_key(req.uri().path())
+            .and_then(|key| cluster.remote_owner(key))
+            .map(|owner| (owner.to_owned(), cluster.redirect)),
+        _ => None,
+    };
+    let Some((owner, redirect)) = owner else {
+        return next.run(req).await;
+    };
+
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or(req.uri().path(), |path| path.as_str());
+    let url = format!("{owner}{path}");
+    if redirect {
+        info!(event = "request redirected", url);
+        return (StatusCode::TEMPORARY_REDIRECT, [(LOCATION, url)])
+            .into_response();
+    }
+
+    proxy(req, url).await
+}
+
+/// Sends a request to its owner and returns the response of the owner
+async fn proxy(req: Request, url: String) -> Response {
+    let (parts, body) = req.into_parts();
+
+    let mut req = Request::builder().method(parts.method).uri(&url);
+    for (name, value) in &parts.headers {
+        if name != HOST {
+            req = req.header(name, value);
+        }
+    }
+    let req = req.header(FORWARDED_HEADER, "1").body(body);
+
+    let res = match req {
+        Ok(req) => Client::builder(TokioExecutor::new())
+            .build_http()
+            .request(req)
+            .await
+            .map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+    match res {
+        Ok(res) => {
+            info!(event = "request proxied", url, status = %res.status());
+            res.map(Body::new)
+        }
+        Err(err) => {
+            error!(event = "failed to proxy request", url, err);
+            ApiError::new(ErrorCode::BadGateway, "bad gateway").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring() {
+        let nodes: Vec<String> =
+            (0..3).map(|i| format!("http://node{i}")).collect();
+        let ring = Ring::new(&nodes);
+
+        let keys: Vec<String> =
+            (0..1000).map(|i| format!("bucket{i}")).collect();
+        let owners: Vec<&str> =
+            keys.iter().map(|key| ring.owner(key).unwrap()).collect();
+        for node in &nodes {
+            let owned = owners.iter().filter(|owner| *owner == node).count();
+            assert!(owned > 200, "{node} owns {owned} keys");
+        }
+
+        // A new node only takes keys over
+        let mut grown = nodes.clone();
+        grown.push("http://node3".to_string());
+        let grown_ring = Ring::new(&grown);
+        for (key, owner) in keys.iter().zip(&owners) {
+            let new_owner = grown_ring.owner(key).unwrap();
+            assert!(new_owner == *owner || new_owner == "http://node3");
+        }
+
+        assert_eq!(Ring::new(&[]).owner("bucket"), None);
+    }
+
+    #[test]
+    fn test_routing_key() {
+        assert_eq!(routing_key("/file/bucket_id/0"), Some("bucket_id"));
+        assert_eq!(
+            routing_key("/complete_upload/bucket_id"),
+            Some("bucket_id")
+        );
+        assert_eq!(routing_key("/upload_chunk/abcd"), Some("abcd"));
+        assert_eq!(routing_key("/capabilities"), None);
+        assert_eq!(routing_key("/admin/keys/bucket_id"), None);
+        assert_eq!(routing_key("/root/"), None);
+    }
+}
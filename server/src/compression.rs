@@ -0,0 +1,474 @@
+use std::io::{self, Write};
+
+use axum::body::{Body, HttpBody};
+use axum::extract::Request;
+use axum::http::header::{
+    HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY,
+};
+use axum::middleware::Next;
+use axum::response::Response;
+use bytes::Bytes;
+use tokio_stream::StreamExt;
+
+/// Upper bound of a decompressed upload body on the routes without an upload
+/// size limit
+pub(crate) const MAX_DECOMPRESSED_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Replies smaller than this are not worth compressing
+const MIN_ENCODED_SIZE: u64 = 1024;
+/// Compression level of the zstd encoded replies, the default one
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum DecodeError {
+    /// The Content-Encoding is neither gzip nor zstd
+    Unsupported(String),
+    /// The decompressed body exceeds the size limit
+    TooLarge,
+    /// The body is not a valid stream for the declared encoding
+    Corrupted,
+}
+
+/// Incremental decoder of an upload body according to its `Content-Encoding`
+///
+/// Bodies without encoding (or with `identity`) are passed through as they
+/// are. Decompression fails as soon as the output grows beyond `limit` bytes.
+pub(crate) struct BodyDecoder {
+    inner: Inner,
+}
+
+enum Inner {
+    Identity,
+    Gzip(flate2::write::GzDecoder<LimitedWriter>),
+    Zstd(zstd::stream::write::Decoder<'static, LimitedWriter>),
+}
+
+/// Output of a decoder, refusing any write past its limit
+///
+/// The decoders write their output in small blocks, so a body inflating far
+/// beyond the limit fails after at most one block, before it is held in
+/// memory.
+struct LimitedWriter {
+    buf: Vec<u8>,
+    /// Number of bytes that can still be written
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl LimitedWriter {
+    fn new(limit: u64) -> Self {
+        LimitedWriter {
+            buf: Vec::new(),
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+
+    /// Takes the bytes written so far
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+    /// Returns the error of a failed write of the decoder
+    fn error(&self) -> DecodeError {
+        if self.exceeded {
+            DecodeError::TooLarge
+        } else {
+            DecodeError::Corrupted
+        }
+    }
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.len() as u64 > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::other("decoded body too large"));
+        }
+        self.remaining -= data.len() as u64;
+        self.buf.extend_from_slice(data);
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BodyDecoder {
+    pub(crate) fn new(
+        encoding: Option<&str>,
+        limit: u64,
+    ) -> Result<Self, DecodeError> {
+        let encoding = encoding.map(|e| e.trim().to_ascii_lowercase());
+
+        let inner = match encoding.as_deref() {
+            None | Some("identity") => Inner::Identity,
+            Some("gzip") => Inner::Gzip(flate2::write::GzDecoder::new(
+                LimitedWriter::new(limit),
+            )),
+            Some("zstd") => Inner::Zstd(
+                zstd::stream::write::Decoder::new(LimitedWriter::new(limit))
+                    .map_err(|_| DecodeError::Corrupted)?,
+            ),
+            Some(other) => {
+                return Err(DecodeError::Unsupported(other.to_owned()))
+            }
+        };
+
+        Ok(BodyDecoder { inner })
+    }
+
+    /// Decodes the next chunk of the body, returning the decoded bytes
+    /// available so far
+    pub(crate) fn decode(
+        &mut self,
+        chunk: &[u8],
+    ) -> Result<Vec<u8>, DecodeError> {
+        Ok(match &mut self.inner {
+            Inner::Identity => chunk.to_vec(),
+            Inner::Gzip(decoder) => {
+                if decoder.write_all(chunk).is_err() {
+                    return Err(decoder.get_ref().error());
+                }
+                decoder.get_mut().take()
+            }
+            Inner::Zstd(decoder) => {
+                if decoder.write_all(chunk).is_err() {
+                    return Err(decoder.get_ref().error());
+                }
+                decoder.get_mut().take()
+            }
+        })
+    }
+
+    /// Completes decoding once the whole body is consumed, returning the
+    /// remaining decoded bytes
+    pub(crate) fn finish(mut self) -> Result<Vec<u8>, DecodeError> {
+        Ok(match &mut self.inner {
+            Inner::Identity => Vec::new(),
+            Inner::Gzip(decoder) => {
+                if decoder.try_finish().is_err() {
+                    return Err(decoder.get_ref().error());
+                }
+                decoder.get_mut().take()
+            }
+            Inner::Zstd(decoder) => {
+                if decoder.flush().is_err() {
+                    return Err(decoder.get_ref().error());
+                }
+                decoder.get_mut().take()
+            }
+        })
+    }
+}
+
+/// Encoding of a reply body
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// Encodings of the replies, preferred first
+    pub(crate) const ALL: [Encoding; 2] = [Encoding::Zstd, Encoding::Gzip];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Returns the encoding of a reply preferred by an `Accept-Encoding`
+/// header, `None` if the body should be sent as is
+///
+/// The encoding of the highest quality wins, zstd over gzip on a tie. An
+/// encoding given a zero quality, explicitly or through `*;q=0`, is never
+/// used.
+pub(crate) fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut qualities = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or_default().trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        qualities.push((coding.to_ascii_lowercase(), quality));
+    }
+
+    let quality = |encoding: Encoding| {
+        let of = |coding: &str| {
+            qualities
+                .iter()
+                .find(|(name, _)| name == coding)
+                .map(|(_, q)| *q)
+        };
+        of(encoding.as_str()).or_else(|| of("*")).unwrap_or(0.0)
+    };
+
+    Encoding::ALL
+        .into_iter()
+        .map(|encoding| (encoding, quality(encoding)))
+        .filter(|(_, q)| *q > 0.0)
+        // `max_by` keeps the last maximum, so the preferred encodings come
+        // last
+        .rev()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(encoding, _)| encoding)
+}
+
+/// Incremental encoder of a reply body, the counterpart of [`BodyDecoder`]
+pub(crate) enum BodyEncoder {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl BodyEncoder {
+    pub(crate) fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => BodyEncoder::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            )),
+            Encoding::Zstd => BodyEncoder::Zstd(
+                zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?,
+            ),
+        })
+    }
+
+    /// Encodes the next chunk of the body, returning the encoded bytes
+    /// available so far
+    pub(crate) fn encode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(match self {
+            BodyEncoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                std::mem::take(encoder.get_mut())
+            }
+            BodyEncoder::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                std::mem::take(encoder.get_mut())
+            }
+        })
+    }
+
+    /// Completes encoding once the whole body is consumed, returning the
+    /// remaining encoded bytes
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            BodyEncoder::Gzip(encoder) => encoder.finish(),
+            BodyEncoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Compresses the successful replies as negotiated with the
+/// `Accept-Encoding` header of their request
+///
+/// Replies are encoded as they are streamed, so a large reply is never held
+/// in memory. Replies with a `Content-Encoding` already, or known to be
+/// smaller than 1 KiB, are sent as is.
+pub(crate) async fn encode_replies(req: Request, next: Next) -> Response {
+    let encoding = req
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|accept_encoding| accept_encoding.to_str().ok())
+        .and_then(negotiate);
+    encode_reply(next.run(req).await, encoding)
+}
+
+/// Returns a reply with its body encoded with `encoding`, if worth it
+fn encode_reply(reply: Response, encoding: Option<Encoding>) -> Response {
+    if !reply.status().is_success()
+        || reply.headers().contains_key(CONTENT_ENCODING)
+    {
+        return reply;
+    }
+
+    let (mut parts, body) = reply.into_parts();
+    // Caches must not serve a reply encoded for another request
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    let small = body
+        .size_hint()
+        .exact()
+        .is_some_and(|size| size < MIN_ENCODED_SIZE);
+    let encoder = match encoding {
+        Some(encoding) if !small => BodyEncoder::new(encoding)
+            .ok()
+            .map(|encoder| (encoding, encoder)),
+        _ => None,
+    };
+    let Some((encoding, encoder)) = encoder else {
+        return Response::from_parts(parts, body);
+    };
+
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+
+    // The end of the body is marked with `None` to flush the encoder
+    let mut encoder = Some(encoder);
+    let chunks = body
+        .into_data_stream()
+        .map(Some)
+        .chain(tokio_stream::once(None))
+        .map(move |chunk| {
+            let encoded = match (chunk, encoder.as_mut()) {
+                (Some(chunk), Some(encoder)) => {
+                    encoder.encode(&chunk.map_err(io::Error::other)?)
+                }
+                (None, Some(_)) => encoder.take().expect("encoder").finish(),
+                _ => Ok(Vec::new()),
+            };
+            encoded.map(Bytes::from)
+        });
+
+    Response::from_parts(parts, Body::from_stream(chunks))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use axum::routing::get;
+    use axum::{middleware, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Decodes a body split in chunks of `chunk_size` bytes
+    fn decode_chunked(
+        encoding: Option<&str>,
+        body: &[u8],
+        chunk_size: usize,
+        limit: u64,
+    ) -> Result<Vec<u8>, DecodeError> {
+        let mut decoder = BodyDecoder::new(encoding, limit)?;
+        let mut data = Vec::new();
+        for chunk in body.chunks(chunk_size) {
+            data.extend(decoder.decode(chunk)?);
+        }
+        data.extend(decoder.finish()?);
+        Ok(data)
+    }
+
+    #[test]
+    fn test_decode_body() {
+        let data = vec![0x24u8; 4096];
+
+        let mut gz = flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        );
+        gz.write_all(&data).unwrap();
+        let gz = gz.finish().unwrap();
+
+        let zst = zstd::encode_all(&data[..], 0).unwrap();
+
+        for chunk_size in [1, 7, 4096] {
+            assert_eq!(
+                decode_chunked(Some("gzip"), &gz, chunk_size, 4096).unwrap(),
+                data
+            );
+            assert_eq!(
+                decode_chunked(Some("ZSTD"), &zst, chunk_size, 4096).unwrap(),
+                data
+            );
+        }
+        assert_eq!(decode_chunked(None, &data, 1000, 0).unwrap(), data);
+
+        // Decompressed size limit
+        assert_eq!(
+            decode_chunked(Some("gzip"), &gz, 16, 4095),
+            Err(DecodeError::TooLarge)
+        );
+        assert_eq!(
+            decode_chunked(Some("zstd"), &zst, 16, 1024),
+            Err(DecodeError::TooLarge)
+        );
+
+        // A small chunk inflating far beyond the limit fails before being
+        // decoded in full
+        let zeros = || io::Read::take(io::repeat(0), 64 << 20);
+        let bomb = zstd::encode_all(zeros(), 3).unwrap();
+        let mut decoder = BodyDecoder::new(Some("zstd"), 4096).unwrap();
+        assert_eq!(decoder.decode(&bomb), Err(DecodeError::TooLarge));
+        let mut gz =
+            flate2::read::GzEncoder::new(zeros(), flate2::Compression::best());
+        let mut bomb = Vec::new();
+        io::Read::read_to_end(&mut gz, &mut bomb).unwrap();
+        let mut decoder = BodyDecoder::new(Some("gzip"), 4096).unwrap();
+        assert_eq!(decoder.decode(&bomb), Err(DecodeError::TooLarge));
+
+        // Invalid input
+        assert_eq!(
+            decode_chunked(Some("gzip"), &data, 16, 4096),
+            Err(DecodeError::Corrupted)
+        );
+        assert_eq!(
+            decode_chunked(Some("br"), &data, 16, 4096),
+            Err(DecodeError::Unsupported("br".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip, zstd"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("gzip;q=1.0, zstd;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*"), Some(Encoding::Zstd));
+        assert_eq!(negotiate("*;q=0.5, zstd;q=0"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("br, identity"), None);
+        assert_eq!(negotiate("GZIP;q=0"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_encode_replies() {
+        let data = vec![0x24u8; 4096];
+        let routes = Router::new()
+            .route("/big", get(move || async move { data.clone() }))
+            .route("/small", get(|| async { b"small".to_vec() }))
+            .layer(middleware::from_fn(encode_replies));
+        let request = |path: &str, accept_encoding: Option<&str>| {
+            let mut req = Request::get(path);
+            if let Some(accept_encoding) = accept_encoding {
+                req = req.header(ACCEPT_ENCODING, accept_encoding);
+            }
+            let req = req.body(Body::empty()).unwrap();
+            let res = routes.clone().oneshot(req);
+            async move {
+                let (parts, body) = res.await.unwrap().into_parts();
+                (parts, to_bytes(body, usize::MAX).await.unwrap())
+            }
+        };
+
+        let (res, body) = request("/big", Some("gzip, zstd")).await;
+        assert_eq!(res.headers[CONTENT_ENCODING], "zstd");
+        assert_eq!(res.headers[VARY], "accept-encoding");
+        assert!(body.len() < 4096);
+        let decoded = zstd::decode_all(&body[..]).unwrap();
+        assert_eq!(decoded, vec![0x24u8; 4096]);
+
+        let (res, body) = request("/big", Some("gzip")).await;
+        assert_eq!(res.headers[CONTENT_ENCODING], "gzip");
+        let decoded = decode_chunked(Some("gzip"), &body, 4096, 4096).unwrap();
+        assert_eq!(decoded, vec![0x24u8; 4096]);
+
+        let (res, body) = request("/big", None).await;
+        assert!(!res.headers.contains_key(CONTENT_ENCODING));
+        assert_eq!(body.len(), 4096);
+
+        let (res, body) = request("/small", Some("zstd")).await;
+        assert!(!res.headers.contains_key(CONTENT_ENCODING));
+        assert_eq!(body, "small");
+    }
+}
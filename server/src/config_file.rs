@@ -0,0 +1,178 @@
+use std::ffi::OsString;
+use std::fs;
+
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command, CommandFactory};
+use toml::{Table, Value};
+
+/// Returns the command line arguments completed with the options of the
+/// `--config` file
+///
+/// The file is a TOML document whose keys are the long names of the
+/// options, like `max-upload-size`, optionally grouped in tables such as
+/// `[tls]`. An option set on the command line or through its environment
+/// variable keeps that value, the file only replaces the defaults. Invalid
+/// command lines are returned as is, for clap to report them.
+pub(crate) fn args<C: CommandFactory>(
+    args: Vec<OsString>,
+) -> Result<Vec<OsString>, String> {
+    let command = C::command();
+    let Ok(matches) = command.clone().try_get_matches_from(&args) else {
+        return Ok(args);
+    };
+    let Some(path) = matches.get_one::<String>("config") else {
+        return Ok(args);
+    };
+
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("cannot read {path}: {err}"))?;
+    let table: Table = text
+        .parse()
+        .map_err(|err| format!("invalid config file {path}: {err}"))?;
+    let mut options = Vec::new();
+    flatten(table, &mut options);
+
+    file_args(&command, &matches, options).map(|file_args| {
+        let mut args = args;
+        args.extend(file_args);
+        args
+    })
+}
+
+/// Collects the options of a table and of its nested tables
+fn flatten(table: Table, options: &mut Vec<(String, Value)>) {
+    for (key, value) in table {
+        match value {
+            Value::Table(table) => flatten(table, options),
+            value => options.push((key, value)),
+        }
+    }
+}
+
+/// Returns the arguments setting the options not set otherwise
+fn file_args(
+    command: &Command,
+    matches: &ArgMatches,
+    options: Vec<(String, Value)>,
+) -> Result<Vec<OsString>, String> {
+    let mut args = Vec::new();
+    let mut positional = Vec::new();
+
+    for (key, value) in options {
+        let arg = find_arg(command, &key)
+            .ok_or_else(|| format!("unknown option `{key}` in config file"))?;
+        let source = matches.value_source(arg.get_id().as_str());
+        if matches!(
+            source,
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        let values = values
+            .into_iter()
+            .map(|value| scalar(&key, value))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if arg.is_positional() {
+            positional.extend(values);
+        } else if matches!(arg.get_action(), ArgAction::SetTrue) {
+            if values.iter().any(|value| value == "true") {
+                args.push(format!("--{key}"));
+            }
+        } else {
+            for value in values {
+                args.push(format!("--{key}"));
+                args.push(value);
+            }
+        }
+    }
+
+    // Positional arguments go last, after the values of the options
+    args.extend(positional);
+    Ok(args.into_iter().map(OsString::from).collect())
+}
+
+/// Returns the argument named `key` in a config file, its long name or, for
+/// a positional argument, its id in kebab case
+fn find_arg<'a>(command: &'a Command, key: &str) -> Option<&'a Arg> {
+    command.get_arguments().find(|arg| match arg.get_long() {
+        Some(long) => long == key,
+        None => arg.get_id().as_str().replace('_', "-") == key,
+    })
+}
+
+/// Returns a value of the config file as a command line value
+fn scalar(key: &str, value: Value) -> Result<String, String> {
+    match value {
+        Value::String(value) => Ok(value),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Float(value) => Ok(value.to_string()),
+        Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(format!("unsupported value of `{key}` in config file")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use clap::Parser;
+
+    #[test]
+    fn test_args() {
+        let dir = tempdir::TempDir::new("config").unwrap();
+        let path = dir.path().join("server.toml");
+        fs::write(
+            &path,
+            r#"
+listen-addr = "0.0.0.0:9000"
+inline-threshold = 1024
+in-memory = true
+
+[limits]
+max-upload-size = 4096
+
+[peers]
+peer = ["http://a:7878", "http://b:7878"]
+"#,
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let parse = |cli: &[&str]| {
+            let cli = cli.iter().map(OsString::from).collect();
+            Config::parse_from(args::<Config>(cli).unwrap())
+        };
+
+        let config = parse(&["server", "--config", path]);
+        assert_eq!(config.listen_addr, "0.0.0.0:9000");
+        assert_eq!(config.inline_threshold, 1024);
+        assert!(config.in_memory);
+        assert_eq!(config.limits.max_upload_size, Some(4096));
+        assert_eq!(config.peers.peers.len(), 2);
+
+        // The command line overrides the file
+        let config = parse(&[
+            "server",
+            "127.0.0.1:0",
+            "--config",
+            path,
+            "--inline-threshold",
+            "0",
+        ]);
+        assert_eq!(config.listen_addr, "127.0.0.1:0");
+        assert_eq!(config.inline_threshold, 0);
+        assert_eq!(config.limits.max_upload_size, Some(4096));
+
+        fs::write(dir.path().join("bad.toml"), "no-such-option = 1").unwrap();
+        let bad = dir.path().join("bad.toml");
+        let cli = ["server", "--config", bad.to_str().unwrap()];
+        let cli = cli.iter().map(OsString::from).collect();
+        assert!(args::<Config>(cli).is_err());
+    }
+}
@@ -0,0 +1,369 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::routing::post;
+use axum::{middleware, Router};
+use serde::Deserialize;
+use tracing::{error, info, warn};
+use utoipa::{IntoParams, OpenApi};
+
+use crate::access::{self, Operation};
+use crate::accounts;
+use crate::app::{self, ServerState, UploadedFile};
+use crate::audit::{self, AuditOp, AuditRecord, Peer};
+use crate::batch::{self, SessionToken};
+use crate::blobs;
+use crate::cluster;
+use crate::errors::{ApiError, ErrorBody, ErrorCode};
+use crate::events::Event;
+use crate::extract::{Json, Path, Query};
+use crate::file_meta::{FileMeta, UploadMeta};
+use crate::lifecycle;
+use crate::ownership::{self, Action, Signed};
+use crate::replication;
+
+/// Query of the copy request
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct CopyQuery {
+    /// Name of the copy, the name of the source file by default
+    filename: Option<String>,
+}
+
+/// File copy route of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_copy))]
+pub(crate) struct ApiDoc;
+
+/// Returns the file copy route
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    // Copy a file to another bucket
+    // POST /copy/:bucket_id/:file_index/:destination_id?filename=
+    Router::new()
+        .route(
+            "/copy/:bucket_id/:file_index/:destination_id",
+            post(handle_copy),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            access::destination_access,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Read),
+            access::bucket_access,
+        ))
+        .with_state(state)
+}
+
+/// Handles file copy request
+///
+/// The copy shares the blob of the source file, no content is transferred.
+/// It carries the metadata and tags of the source file, and the Merkle tree
+/// of the destination bucket covers it once replied, as after
+/// `complete_upload`. The destination is checked like an upload: a copy
+/// already in the bucket is refused with `409 Conflict`, as is a copy over
+/// the quota of the bucket or one missing the token of its upload session.
+/// Returns `400 Bad Request` if another node of the cluster owns the
+/// destination bucket.
+#[utoipa::path(
+    post,
+    path = "/copy/{bucket_id}/{file_index}/{destination_id}",
+    tag = "files",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the source bucket"),
+        ("file_index" = usize, Path, description = "Leaf index of the file"),
+        ("destination_id" = String, Path, description = "Id of the destination bucket"),
+        CopyQuery,
+        ("X-Upload-Session" = Option<String>, Header, description = "Token of the upload session of the destination, required while it has one"),
+        ("X-Signature" = Option<String>, Header, description = "Signature of the owner of the destination over the upload of the copy"),
+        ("X-Signature-Nonce" = Option<String>, Header, description = "Nonce of the signature"),
+    ),
+    responses(
+        (status = 200, description = "File copied, `bucket_root_pending` is false", body = UploadedFile),
+        (status = 400, description = "Another node owns the destination bucket", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials or signature", body = ErrorBody),
+        (status = 404, description = "The source bucket or file does not exist", body = ErrorBody),
+        (status = 409, description = "The destination already holds the file or rejects its name, has an upload session, or is sealed and the request has no session", body = ErrorBody),
+        (status = 413, description = "The copy exceeds the quota of the destination or of its account", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_copy(
+    Path((bucket_id, file_index, destination_id)): Path<(
+        String,
+        String,
+        String,
+    )>,
+    Query(CopyQuery { filename }): Query<CopyQuery>,
+    SessionToken(session): SessionToken,
+    signed: Signed,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<UploadedFile>, ApiError> {
+    info!(request = "copy", bucket_id, file_index, destination_id);
+
+    if !cluster::owns(&state.cluster, &destination_id) {
+        return Err(ApiError::bad_request(format!(
+            "bucket {destination_id} belongs to another node"
+        )));
+    }
+
+    // The source is released before the destination is locked, so two
+    // copies in opposite directions cannot wait for each other
+    let (file_hash, filename, meta) = {
+        let bucket = app::get_bucket(bucket_id.clone(), state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+        let bucket = bucket.read().await;
+
+        let index = file_index
+            .parse::<usize>()
+            .map_err(|_| ApiError::file_not_found(&file_index))?;
+        let (hash, name) = bucket
+            .files
+            .iter()
+            .nth(index)
+            .ok_or_else(|| ApiError::file_not_found(index))?;
+
+        let db = state.db.read().await;
+        let meta = match db.file_meta(&bucket_id, hash) {
+            Ok(Some(meta)) => meta,
+            // Files uploaded before the metadata were recorded only have a
+            // size
+            Ok(None) => FileMeta {
+                size: blobs::size(&*state.store, &db, hash)
+                    .await
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default(),
+                ..FileMeta::default()
+            },
+            Err(err) => {
+                error!(event = "failed to read file meta", bucket_id, err);
+                return Err(ApiError::internal());
+            }
+        };
+
+        let filename = filename.unwrap_or_else(|| name.clone());
+        (*hash, filename, meta)
+    };
+
+    let bucket =
+        app::get_or_create_bucket(destination_id.clone(), state.clone())
+            .await?;
+    let mut bucket = bucket.write().await;
+
+    // The owner is verified under the bucket lock, which its registration
+    // holds as well
+    ownership::verify(
+        &state,
+        Action::Upload,
+        &destination_id,
+        &filename,
+        Some(&file_hash),
+        &signed,
+    )
+    .await?;
+    batch::check(&state, &mut bucket, session.as_deref()).await?;
+
+    if bucket.files.contains_key(&file_hash) {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            "file already uploaded",
+        ));
+    }
+
+    let (filename, replaced) =
+        app::place_file(&state, &bucket, filename, None).await?;
+    // A replaced file frees its bytes
+    let replaced_size = match &replaced {
+        Some(hash) => {
+            let db = state.db.read().await;
+            blobs::size(&*state.store, &db, hash)
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_default()
+        }
+        None => 0,
+    };
+    let growth = meta.size.saturating_sub(replaced_size);
+
+    let usage = state.usage(&bucket);
+    if let Some(exceeded) = usage.exceeded_by(growth) {
+        warn!(
+            event = "quota exceeded",
+            filename,
+            bucket_id = destination_id,
+            quota = ?exceeded,
+            file_size = meta.size
+        );
+        return Err(exceeded.error(&usage));
+    }
+    accounts::check_quota(&state, &bucket, growth).await?;
+
+    // The reference is counted under the database lock, so the source file
+    // cannot be deleted meanwhile and the garbage collector sees it
+    {
+        let db = state.db.write().await;
+        let refs = match blobs::share(&db, &file_hash) {
+            Ok(Some(refs)) => refs,
+            // The source file was deleted since it was read
+            Ok(None) => return Err(ApiError::file_not_found(&file_index)),
+            Err(err) => {
+                error!(event = "failed to copy file", bucket_id, err);
+                return Err(ApiError::internal());
+            }
+        };
+
+        let expire_after = db
+            .lifecycle_policy(&destination_id)
+            .ok()
+            .flatten()
+            .and_then(|policy| policy.expire_after);
+        let expires_at = lifecycle::expires_at(expire_after);
+        if let Err(err) =
+            db.set_expiration(&destination_id, &file_hash, expires_at)
+        {
+            error!(
+                event = "failed to set file expiration",
+                filename,
+                bucket_id = destination_id,
+                err
+            );
+        }
+
+        let upload = UploadMeta {
+            content_type: meta.content_type,
+            metadata: meta.metadata,
+            tags: meta.tags,
+        };
+        let meta = FileMeta::new(meta.size, upload);
+        if let Err(err) = db.set_file_meta(&destination_id, &file_hash, &meta) {
+            error!(
+                event = "failed to set file meta",
+                filename,
+                bucket_id = destination_id,
+                err
+            );
+        }
+
+        let blob_key = blobs::blob_key(&file_hash);
+        info!(
+            event = "file copied",
+            blob_key, refs, bucket_id, destination_id, filename
+        );
+    }
+
+    let bytes_before = bucket.bytes_used;
+    let replaced = match replaced {
+        Some(hash) => app::take_file(&state, &mut bucket, &hash)
+            .await
+            .map(|filename| (hash, filename)),
+        None => None,
+    };
+    bucket.files.insert(file_hash, filename.clone());
+    batch::record(&state, &destination_id, session.as_deref(), file_hash);
+
+    bucket.bytes_used += meta.size;
+    state.quota.alert(&state.usage(&bucket), bytes_before);
+
+    let previous_root = bucket.merkle_tree.root_hash();
+    bucket.calculate_merkle_tree();
+    if let Err(err) = state.persist_bucket_lockless(&bucket).await {
+        // The copy stays in memory and is persisted with the bucket later
+        error!(event = "failed to persist bucket", destination_id, err);
+    }
+
+    let root = bucket.merkle_tree.root_hash();
+    let record = AuditRecord::new(AuditOp::CopyFile, peer.clone())
+        .with_file(&filename, &file_hash)
+        .with_root(root);
+    audit::record(&*state.db.write().await, &destination_id, record);
+    if let Some((replaced, replaced_name)) = replaced {
+        app::release_file(&state, &bucket, &replaced, replaced_name, peer)
+            .await;
+    }
+    replication::enqueue(&state, &destination_id).await;
+
+    let hash = hex::encode(file_hash);
+    state.notify(Some(Event::FileUploaded {
+        bucket_id: destination_id.clone(),
+        filename: filename.clone(),
+        hash: hash.clone(),
+    }));
+    state.notify(Event::root_changed(&destination_id, previous_root, root));
+
+    Ok(Json(UploadedFile {
+        hash,
+        index: bucket.files.range(..file_hash).count(),
+        filename,
+        bucket_root_pending: false,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use crate::Config;
+    use axum::body::Body;
+    use axum::http::{Request, Response, StatusCode};
+    use clap::Parser;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+
+    #[tokio::test]
+    async fn test_copy() {
+        let config = Config::parse_from(["server", "127.0.0.1:0"]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = app::run_server_with_store(config, store).await;
+        tokio::spawn(server);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let request = |method: &str, path: &str, body: &'static str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("http://{addr}{path}"))
+                .body(Body::from(body))
+                .unwrap();
+            client.request(request)
+        };
+        let body = |res: Response<Incoming>| async move {
+            res.into_body().collect().await.unwrap().to_bytes()
+        };
+
+        let res = request("POST", "/upload_file/a/a.txt", "a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        request("POST", "/complete_upload/a", "").await.unwrap();
+
+        let res = request("POST", "/copy/a/0/b?filename=b.txt", "")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let copied: serde_json::Value =
+            serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(copied["index"], 0);
+        assert_eq!(copied["bucket_root_pending"], false);
+
+        let res = request("POST", "/copy/a/0/b", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = request("POST", "/copy/a/1/b", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = request("GET", "/meta/b/0", "").await.unwrap();
+        let meta: serde_json::Value =
+            serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(meta["filename"], "b.txt");
+        assert_eq!(meta["hash"], copied["hash"]);
+
+        // The copy keeps the blob once the source file is deleted
+        let res = request("DELETE", "/file/a/0", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("GET", "/file/b/0", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body(res).await, "a");
+    }
+}
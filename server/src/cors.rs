@@ -0,0 +1,264 @@
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::header::{
+    HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+};
+use axum::http::uri::Authority;
+use axum::http::{Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use clap::Args;
+
+use crate::errors::REQUEST_ID_HEADER;
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct CorsConfig {
+    /// Origin allowed to call the API from a browser, like
+    /// `https://dashboard.example.com`, or `*` for any origin (repeatable or
+    /// comma separated). Cross-origin requests are refused by default
+    #[arg(long, value_delimiter = ',', value_parser = parse_origin)]
+    pub cors_origin: Vec<String>,
+
+    /// Methods allowed in cross-origin requests (comma separated)
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "GET,HEAD,POST,PUT,PATCH,DELETE",
+        value_parser = parse_method
+    )]
+    pub cors_method: Vec<Method>,
+
+    /// Request headers allowed in cross-origin requests (comma separated)
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "authorization,content-type,content-encoding,x-expire-after,x-request-id,x-signature,x-signature-nonce,x-upload-session,x-tags,upload-offset,upload-part",
+        value_parser = parse_header
+    )]
+    pub cors_header: Vec<HeaderName>,
+
+    /// Seconds browsers may cache the answer to a preflight request
+    #[arg(long, default_value_t = 600)]
+    pub cors_max_age: u64,
+}
+
+/// Cross-origin policy of the routes
+pub(crate) struct Cors {
+    /// Allowed origins, `None` for any
+    origins: Option<Vec<String>>,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    max_age: u64,
+}
+
+/// Returns the CORS policy of the routes, `None` if no origin is allowed
+pub(crate) fn cors(config: &CorsConfig) -> Option<Cors> {
+    if config.cors_origin.is_empty() {
+        return None;
+    }
+
+    let any = config.cors_origin.iter().any(|origin| origin == "*");
+    Some(Cors {
+        origins: (!any).then(|| config.cors_origin.clone()),
+        methods: config.cors_method.clone(),
+        headers: config.cors_header.clone(),
+        max_age: config.cors_max_age,
+    })
+}
+
+impl Cors {
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.as_ref().is_none_or(|origins| {
+            origins.iter().any(|allowed| allowed == origin)
+        })
+    }
+
+    /// Returns true if the preflight request of `req` is allowed
+    fn allows_preflight(&self, req: &Request) -> bool {
+        let headers = req.headers();
+        let method = headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+        if !method.is_some_and(|method| self.methods.contains(&method)) {
+            return false;
+        }
+
+        headers
+            .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .all(|names| match names.to_str() {
+                Ok(names) => names
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .all(|name| {
+                        HeaderName::try_from(name)
+                            .is_ok_and(|name| self.headers.contains(&name))
+                    }),
+                Err(_) => false,
+            })
+    }
+
+    fn join<T: AsRef<str>>(items: &[T]) -> HeaderValue {
+        let items: Vec<&str> = items.iter().map(AsRef::as_ref).collect();
+        HeaderValue::from_str(&items.join(", ")).expect("valid header value")
+    }
+}
+
+/// Applies the CORS policy to the requests carrying an `Origin`
+///
+/// Requests from other origins, and preflight requests of other methods or
+/// headers, are refused with `403 Forbidden`. Preflight requests are
+/// answered here. Replies, errors included, expose their `X-Request-Id` to
+/// the allowed origins.
+pub(crate) async fn handle(
+    State(cors): State<Arc<Cors>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(origin) = req.headers().get(ORIGIN).cloned() else {
+        return next.run(req).await;
+    };
+    if !origin
+        .to_str()
+        .is_ok_and(|origin| cors.allows_origin(origin))
+    {
+        return forbidden("origin not allowed");
+    }
+
+    let preflight = req.method() == Method::OPTIONS
+        && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+    if preflight {
+        if !cors.allows_preflight(&req) {
+            return forbidden("method or header not allowed");
+        }
+        let methods: Vec<&str> =
+            cors.methods.iter().map(Method::as_str).collect();
+        let headers = [
+            (ACCESS_CONTROL_ALLOW_ORIGIN, origin),
+            (ACCESS_CONTROL_ALLOW_METHODS, Cors::join(&methods)),
+            (ACCESS_CONTROL_ALLOW_HEADERS, Cors::join(&cors.headers)),
+            (ACCESS_CONTROL_MAX_AGE, cors.max_age.into()),
+        ];
+        return (StatusCode::OK, headers).into_response();
+    }
+
+    let mut res = next.run(req).await;
+    let headers = res.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(REQUEST_ID_HEADER),
+    );
+    res
+}
+
+fn forbidden(reason: &str) -> Response {
+    let message = format!("CORS request forbidden: {reason}");
+    (StatusCode::FORBIDDEN, message).into_response()
+}
+
+/// Parses an origin, `<scheme>://<host>[:<port>]` or `*`
+fn parse_origin(origin: &str) -> Result<String, String> {
+    if origin == "*" {
+        return Ok(origin.to_owned());
+    }
+
+    let valid = origin.split_once("://").is_some_and(|(scheme, host)| {
+        !scheme.is_empty()
+            && !host.is_empty()
+            && !host.contains('/')
+            && host.parse::<Authority>().is_ok()
+    });
+    if valid {
+        Ok(origin.to_owned())
+    } else {
+        Err(format!("invalid origin `{origin}`"))
+    }
+}
+
+fn parse_method(method: &str) -> Result<Method, String> {
+    method
+        .parse()
+        .map_err(|_| format!("invalid method `{method}`"))
+}
+
+fn parse_header(header: &str) -> Result<HeaderName, String> {
+    header
+        .parse()
+        .map_err(|_| format!("invalid header `{header}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::routing::any;
+    use axum::{middleware, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::ownership::{NONCE_HEADER, SIGNATURE_HEADER};
+
+    #[tokio::test]
+    async fn test_cors() {
+        assert!(parse_origin("https://a.example:8080").is_ok());
+        assert!(parse_origin("*").is_ok());
+        assert!(parse_origin("a.example").is_err());
+        assert!(parse_origin("https://a.example/path").is_err());
+
+        assert!(cors(&CorsConfig::default()).is_none());
+
+        let config = CorsConfig {
+            cors_origin: vec!["https://a.example".to_owned()],
+            cors_method: vec![Method::GET, Method::POST],
+            cors_header: vec![HeaderName::from_static(SIGNATURE_HEADER)],
+            cors_max_age: 60,
+        };
+        let routes = Router::new().route("/", any(|| async { "ok" })).layer(
+            middleware::from_fn_with_state(
+                Arc::new(cors(&config).unwrap()),
+                handle,
+            ),
+        );
+        let preflight = |header| {
+            Request::options("/")
+                .header("origin", "https://a.example")
+                .header("access-control-request-method", "POST")
+                .header("access-control-request-headers", header)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let request = |origin| {
+            Request::get("/")
+                .header("origin", origin)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = routes.clone().oneshot(preflight(NONCE_HEADER)).await;
+        assert_eq!(res.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let res = routes.clone().oneshot(preflight(SIGNATURE_HEADER)).await;
+        let res = res.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["access-control-max-age"], "60");
+
+        let res = routes.clone().oneshot(request("https://a.example")).await;
+        let res = res.unwrap();
+        assert_eq!(
+            res.headers()["access-control-allow-origin"],
+            "https://a.example"
+        );
+        assert_eq!(
+            res.headers()["access-control-expose-headers"],
+            REQUEST_ID_HEADER
+        );
+
+        let res = routes.oneshot(request("https://b.example")).await;
+        assert_eq!(res.unwrap().status(), StatusCode::FORBIDDEN);
+    }
+}
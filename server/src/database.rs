@@ -1,14 +1,174 @@
-use std::{collections::HashMap, path::Path};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::client_bucket::ClientBucket;
+use crate::access::{ApiKey, BucketLimits, LegacyApiKey};
+use crate::accounts::{Account, AccountKey};
+use crate::audit::AuditRecord;
+use crate::client_bucket::{BucketState, ClientBucket};
+use crate::file_meta::FileMeta;
+use crate::lifecycle::LifecyclePolicy;
+use crate::metadata_cipher::MetadataCipher;
+use crate::naming::NamingPolicy;
+use crate::throttle::BandwidthLimits;
+use merkle::tree as merkle;
 
+use rocksdb::checkpoint::Checkpoint;
 use rocksdb::{
-    OptimisticTransactionDB, OptimisticTransactionOptions, WriteOptions,
+    ColumnFamily, ColumnFamilyDescriptor, Direction, Env, IteratorMode,
+    OptimisticTransactionDB, OptimisticTransactionOptions, Options,
+    WriteOptions,
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::info;
 
+/// Column family of the API keys, keyed by the key hash
+const CF_API_KEYS: &str = "api_keys";
+/// Column family of the user accounts, keyed by the account id
+const CF_ACCOUNTS: &str = "accounts";
+/// Column family of the keys of the accounts, keyed by the key hash
+const CF_ACCOUNT_KEYS: &str = "account_keys";
+/// Column family of the per-bucket limits, keyed by the bucket id
+const CF_BUCKET_LIMITS: &str = "bucket_limits";
+/// Column family of the per-bucket byte rates, keyed by the bucket id
+const CF_BANDWIDTH_LIMITS: &str = "bandwidth_limits";
+/// Column family of the number of references to a blob, keyed by the blob
+/// hash
+const CF_BLOB_REFS: &str = "blob_refs";
+/// Column family of the content of the small blobs, keyed by the blob hash
+const CF_INLINE_BLOBS: &str = "inline_blobs";
+/// Column family of the bucket metadata, keyed by the bucket id
+const CF_BUCKETS: &str = "buckets";
+/// Column family of the file names, keyed by `<bucket_id>/<file hash>`
+const CF_FILES: &str = "files";
+/// Column family of the successive roots of the buckets, keyed by
+/// `<bucket_id>/<big endian sequence number>`
+const CF_ROOTS: &str = "roots";
+/// Column family of the files uploaded since their bucket was last
+/// persisted, keyed by `<bucket_id>/<file hash>`
+const CF_JOURNAL: &str = "journal";
+/// Column family of the buckets to push to each replica, keyed by
+/// `<replica url>\0<bucket_id>`
+const CF_REPLICATION: &str = "replication";
+/// Column family of the ed25519 public keys owning the buckets, keyed by the
+/// bucket id
+const CF_BUCKET_OWNERS: &str = "bucket_owners";
+/// Column family of the expiration time of the files, keyed by
+/// `<bucket_id>/<file hash>`
+const CF_EXPIRATIONS: &str = "expirations";
+/// Column family of the lifecycle policies of the buckets, keyed by the
+/// bucket id
+const CF_LIFECYCLE_POLICIES: &str = "lifecycle_policies";
+/// Column family of the naming policies of the buckets, keyed by the bucket
+/// id
+const CF_NAMING_POLICIES: &str = "naming_policies";
+/// Column family of the audit log of the buckets, keyed by
+/// `<bucket_id>/<big endian sequence number>`
+const CF_AUDIT: &str = "audit";
+/// Column family of the metadata of the files, keyed by
+/// `<bucket_id>/<file hash>`
+const CF_FILE_META: &str = "file_meta";
+/// Column family of the files of the kept versions of the buckets, keyed by
+/// `<bucket_id>/<big endian version>`
+const CF_VERSIONS: &str = "versions";
+/// Column family of the interior levels of the Merkle trees of the buckets,
+/// keyed by the bucket id
+const CF_TREES: &str = "trees";
+
+/// Metadata of a bucket, stored apart from its files
+#[derive(Serialize, Deserialize)]
+struct BucketMeta {
+    bytes_used: u64,
+    state: BucketState,
+}
+
+/// Metadata of a bucket stored before its state
+#[derive(Deserialize)]
+struct LegacyBucketMeta {
+    bytes_used: u64,
+}
+
+impl BucketMeta {
+    fn deserialize(value: &[u8]) -> Result<Self, String> {
+        bincode::deserialize::<BucketMeta>(value)
+            .or_else(|_| {
+                bincode::deserialize::<LegacyBucketMeta>(value).map(|meta| {
+                    BucketMeta {
+                        bytes_used: meta.bytes_used,
+                        state: BucketState::Open,
+                    }
+                })
+            })
+            .map_err(|_| "Failed to deserialize bucket".to_owned())
+    }
+}
+
+/// Bucket of the single record layout as written before the buckets counted
+/// their bytes
+#[derive(Serialize, Deserialize)]
+struct BaselineBucket {
+    bucket_id: String,
+    files: BTreeMap<[u8; 32], String>,
+    merkle_tree: merkle::Tree,
+}
+
+impl From<BaselineBucket> for ClientBucket {
+    fn from(bucket: BaselineBucket) -> Self {
+        let mut client_bucket = ClientBucket::new(bucket.bucket_id);
+        client_bucket.files = bucket.files;
+        client_bucket.merkle_tree = bucket.merkle_tree;
+        client_bucket
+    }
+}
+
+/// A Merkle root of a bucket, recorded each time a bucket is persisted with
+/// a new root or sealed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RootRecord {
+    pub root: [u8; 32],
+    pub leaves_count: usize,
+    /// Unix time in seconds
+    pub timestamp: u64,
+}
+
+impl RootRecord {
+    /// Returns the record of the current root of a bucket, if it has one
+    fn of(bucket: &ClientBucket) -> Option<Self> {
+        Some(RootRecord {
+            root: bucket.merkle_tree.root_hash()?,
+            leaves_count: bucket.merkle_tree.leaves_count(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// A file accepted by `upload_file`, journaled until its bucket is persisted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct JournalEntry {
+    pub filename: String,
+    pub size: u64,
+}
+
+/// A key and its value
+type Entry = (Vec<u8>, Vec<u8>);
+
+/// The hash and the name of a file of a bucket version
+pub(crate) type VersionFile = ([u8; 32], String);
+
+/// Returns the prefix of the keys of a bucket in the files and roots column
+/// families
+fn bucket_prefix(bucket_id: &str) -> Vec<u8> {
+    format!("{bucket_id}/").into_bytes()
+}
+
 pub(crate) struct DB {
     backend: OptimisticTransactionDB,
+
+    /// Encrypts bucket records at rest, if set
+    cipher: Option<MetadataCipher>,
 }
 
 impl DB {
@@ -17,73 +177,1227 @@ impl DB {
     where
         T: AsRef<Path>,
     {
-        let path = path.as_ref().join("rocksdb");
+        let path = Self::backend_path(path);
         info!("Open database in {path:?}");
 
-        let backend = OptimisticTransactionDB::open_default(path)
-            .expect("should be a valid database in {path}");
+        Self::open(path, Options::default())
+    }
+
+    /// Returns the folder of the RocksDB files of a database created in
+    /// `path`
+    pub(crate) fn backend_path<T: AsRef<Path>>(path: T) -> PathBuf {
+        path.as_ref().join("rocksdb")
+    }
+
+    /// Creates an empty database kept in memory, discarded once dropped
+    pub(crate) fn in_memory() -> Self {
+        let env = Env::mem_env().expect("valid in-memory env");
+        let mut opts = Options::default();
+        opts.set_env(&env);
+
+        Self::open("/rocksdb", opts)
+    }
+
+    fn open<T: AsRef<Path>>(path: T, mut opts: Options) -> Self {
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+
+        let cfs = [
+            CF_API_KEYS,
+            CF_ACCOUNTS,
+            CF_ACCOUNT_KEYS,
+            CF_BUCKET_LIMITS,
+            CF_BANDWIDTH_LIMITS,
+            CF_BLOB_REFS,
+            CF_INLINE_BLOBS,
+            CF_BUCKETS,
+            CF_FILES,
+            CF_ROOTS,
+            CF_JOURNAL,
+            CF_REPLICATION,
+            CF_BUCKET_OWNERS,
+            CF_EXPIRATIONS,
+            CF_LIFECYCLE_POLICIES,
+            CF_NAMING_POLICIES,
+            CF_AUDIT,
+            CF_FILE_META,
+            CF_VERSIONS,
+            CF_TREES,
+        ]
+        .map(|name| ColumnFamilyDescriptor::new(name, Options::default()));
+
+        let backend =
+            OptimisticTransactionDB::open_cf_descriptors(&opts, path, cfs)
+                .expect("should be a valid database");
+
+        Self {
+            backend,
+            cipher: None,
+        }
+    }
 
-        Self { backend }
+    /// Encrypts the bucket records written from now on with `key`
+    ///
+    /// Records written in plain text remain readable
+    pub(crate) fn with_metadata_key(mut self, key: Option<[u8; 32]>) -> Self {
+        self.cipher = key.as_ref().map(MetadataCipher::new);
+        self
     }
 
     /// Updates the bucket in the database
+    ///
+    /// The metadata and the file records are written in their own column
+    /// families, only the files added or removed since the previous update
+    /// are written, and the expiration and the metadata of the removed files
+    /// are dropped. The
+    /// root is recorded if it changed, along with the Merkle tree, and the
+    /// journal of the bucket is cleared.
     pub(crate) fn update_bucket(
         &self,
         bucket: &ClientBucket,
     ) -> Result<(), String> {
-        let key = bucket.bucket_id.as_bytes();
-        let value = bincode::serialize(bucket).unwrap();
+        let bucket_id = &bucket.bucket_id;
+        let prefix = bucket_prefix(bucket_id);
+
+        let meta = BucketMeta {
+            bytes_used: bucket.bytes_used,
+            state: bucket.state,
+        };
+        let meta =
+            bincode::serialize(&meta).map_err(|_| "Failed to serialize")?;
 
         // Create a new RocksDB transaction
         let write_options = WriteOptions::default();
         let tx_options = OptimisticTransactionOptions::default();
         let inner = self.backend.transaction_opt(&write_options, &tx_options);
-        inner.put(key, value)?;
+        inner.put_cf(self.cf(CF_BUCKETS)?, bucket_id.as_bytes(), meta)?;
+
+        // Sync the file records with the bucket map
+        let files_cf = self.cf(CF_FILES)?;
+        let mut stored = Vec::new();
+        for (key, _) in self.prefix_entries(CF_FILES, &prefix)? {
+            match file_hash(&prefix, &key) {
+                Some(hash) if bucket.files.contains_key(&hash) => {
+                    stored.push(hash)
+                }
+                _ => {
+                    inner.delete_cf(files_cf, &key)?;
+                    inner.delete_cf(self.cf(CF_EXPIRATIONS)?, &key)?;
+                    inner.delete_cf(self.cf(CF_FILE_META)?, &key)?;
+                }
+            }
+        }
+        for (hash, filename) in &bucket.files {
+            if stored.contains(hash) {
+                continue;
+            }
+            let key = [&prefix[..], hash].concat();
+            let mut value = bincode::serialize(filename)
+                .map_err(|_| "Failed to serialize")?;
+            if let Some(cipher) = &self.cipher {
+                value = cipher.encrypt(&key, &value);
+            }
+            inner.put_cf(files_cf, key, value)?;
+        }
+
+        if let Some(root) = bucket.merkle_tree.root_hash() {
+            let last = self.last_root(bucket_id)?;
+            let changed =
+                last.as_ref().map(|(_, record)| record.root) != Some(root);
+            let trees_cf = self.cf(CF_TREES)?;
+            // Trees are stored from their first update
+            if changed
+                || self
+                    .backend
+                    .get_pinned_cf(trees_cf, bucket_id.as_bytes())?
+                    .is_none()
+            {
+                // The leaves are the keys of the file records
+                let levels = &bucket.merkle_tree.levels()[1..];
+                let levels = bincode::serialize(levels)
+                    .map_err(|_| "Failed to serialize")?;
+                inner.put_cf(trees_cf, bucket_id.as_bytes(), levels)?;
+            }
+
+            if let Some(record) = RootRecord::of(bucket).filter(|_| changed) {
+                let seq = last.map(|(seq, _)| seq + 1).unwrap_or_default();
+                let record = bincode::serialize(&record)
+                    .map_err(|_| "Failed to serialize")?;
+                let key = [&prefix[..], &seq.to_be_bytes()].concat();
+                inner.put_cf(self.cf(CF_ROOTS)?, key, record)?;
+            }
+        } else if bucket.files.is_empty() {
+            inner.delete_cf(self.cf(CF_TREES)?, bucket_id.as_bytes())?;
+        }
+
+        // The journaled files are part of the bucket from now on
+        for (key, _) in self.prefix_entries(CF_JOURNAL, &prefix)? {
+            inner.delete_cf(self.cf(CF_JOURNAL)?, key)?;
+        }
+
+        // Drop the record of the former single record layout
+        inner.delete(bucket_id.as_bytes())?;
         inner.commit()?;
 
         Ok(())
     }
 
-    /// Flushes the database
-    pub(crate) fn flush(&self) -> Result<(), String> {
-        self.backend.flush()?;
+    /// Deletes a bucket, its files and their expiration and metadata, its
+    /// roots, tree and versions, its journal and its owner from the database
+    pub(crate) fn delete_bucket(&self, bucket_id: &str) -> Result<(), String> {
+        let prefix = bucket_prefix(bucket_id);
+
+        let write_options = WriteOptions::default();
+        let tx_options = OptimisticTransactionOptions::default();
+        let inner = self.backend.transaction_opt(&write_options, &tx_options);
+        inner.delete(bucket_id.as_bytes())?;
+        inner.delete_cf(self.cf(CF_BUCKETS)?, bucket_id.as_bytes())?;
+        inner.delete_cf(self.cf(CF_BUCKET_OWNERS)?, bucket_id.as_bytes())?;
+        inner.delete_cf(self.cf(CF_TREES)?, bucket_id.as_bytes())?;
+        for cf_name in [
+            CF_FILES,
+            CF_ROOTS,
+            CF_JOURNAL,
+            CF_EXPIRATIONS,
+            CF_FILE_META,
+            CF_VERSIONS,
+        ] {
+            for (key, _) in self.prefix_entries(cf_name, &prefix)? {
+                inner.delete_cf(self.cf(cf_name)?, key)?;
+            }
+        }
+        inner.commit()?;
 
         Ok(())
     }
 
-    pub(crate) fn read_all_buckets(
+    /// Records the root of a bucket being sealed as a new version if it is
+    /// its last recorded root, [`DB::update_bucket`] records the others
+    ///
+    /// Each seal of a bucket is a version, even without new files.
+    pub(crate) fn record_seal(
+        &self,
+        bucket: &ClientBucket,
+    ) -> Result<(), String> {
+        let Some(record) = RootRecord::of(bucket) else {
+            return Ok(());
+        };
+        let Some((seq, last)) = self.last_root(&bucket.bucket_id)? else {
+            return Ok(());
+        };
+        if last.root != record.root {
+            return Ok(());
+        }
+
+        let key = [
+            &bucket_prefix(&bucket.bucket_id)[..],
+            &(seq + 1).to_be_bytes(),
+        ]
+        .concat();
+        let record =
+            bincode::serialize(&record).map_err(|_| "Failed to serialize")?;
+        self.backend.put_cf(self.cf(CF_ROOTS)?, key, record)?;
+
+        Ok(())
+    }
+
+    /// Returns the roots recorded for a bucket, oldest first
+    ///
+    /// The file records are not read.
+    pub(crate) fn roots(
+        &self,
+        bucket_id: &str,
+    ) -> Result<Vec<RootRecord>, String> {
+        self.prefix_entries(CF_ROOTS, &bucket_prefix(bucket_id))?
+            .into_iter()
+            .map(|(_, value)| {
+                bincode::deserialize(&value)
+                    .map_err(|_| "Failed to deserialize root".to_owned())
+            })
+            .collect()
+    }
+
+    /// Returns the root of a bucket at `version`, the sequence number of the
+    /// root
+    pub(crate) fn root_version(
+        &self,
+        bucket_id: &str,
+        version: u64,
+    ) -> Result<Option<RootRecord>, String> {
+        let key =
+            [&bucket_prefix(bucket_id)[..], &version.to_be_bytes()].concat();
+        self.backend
+            .get_cf(self.cf(CF_ROOTS)?, key)?
+            .map(|value| {
+                bincode::deserialize(&value)
+                    .map_err(|_| "Failed to deserialize root".to_owned())
+            })
+            .transpose()
+    }
+
+    /// Records the files of a bucket at `version`, ordered by leaf index, or
+    /// drops them if `files` is `None`
+    ///
+    /// The file names are encrypted like the file records.
+    pub(crate) fn set_version_files(
+        &self,
+        bucket_id: &str,
+        version: u64,
+        files: Option<&[VersionFile]>,
+    ) -> Result<(), String> {
+        let key =
+            [&bucket_prefix(bucket_id)[..], &version.to_be_bytes()].concat();
+        let cf = self.cf(CF_VERSIONS)?;
+        let Some(files) = files else {
+            return Ok(self.backend.delete_cf(cf, key)?);
+        };
+
+        let mut value =
+            bincode::serialize(files).map_err(|_| "Failed to serialize")?;
+        if let Some(cipher) = &self.cipher {
+            value = cipher.encrypt(&key, &value);
+        }
+        self.backend.put_cf(cf, key, value)?;
+
+        Ok(())
+    }
+
+    /// Returns the files of a bucket at `version` ordered by leaf index,
+    /// `None` if the version is not kept
+    pub(crate) fn version_files(
+        &self,
+        bucket_id: &str,
+        version: u64,
+    ) -> Result<Option<Vec<VersionFile>>, String> {
+        let key =
+            [&bucket_prefix(bucket_id)[..], &version.to_be_bytes()].concat();
+        let Some(value) = self.backend.get_cf(self.cf(CF_VERSIONS)?, &key)?
+        else {
+            return Ok(None);
+        };
+
+        let value = self.decrypt(bucket_id, &key, value)?;
+        bincode::deserialize(&value)
+            .map(Some)
+            .map_err(|_| "Failed to deserialize version".to_owned())
+    }
+
+    /// Returns the kept versions of a bucket, oldest first
+    pub(crate) fn kept_versions(
+        &self,
+        bucket_id: &str,
+    ) -> Result<Vec<u64>, String> {
+        let prefix = bucket_prefix(bucket_id);
+        self.prefix_entries(CF_VERSIONS, &prefix)?
+            .into_iter()
+            .map(|(key, _)| {
+                let version = key[prefix.len()..]
+                    .try_into()
+                    .map_err(|_| "Invalid version key")?;
+                Ok(u64::from_be_bytes(version))
+            })
+            .collect()
+    }
+
+    /// Journals a file uploaded to a bucket, until the next update of the
+    /// bucket
+    ///
+    /// The file name is encrypted like the file records.
+    pub(crate) fn journal_file(
+        &self,
+        bucket_id: &str,
+        hash: &[u8; 32],
+        entry: &JournalEntry,
+    ) -> Result<(), String> {
+        let key = [&bucket_prefix(bucket_id)[..], hash].concat();
+        let mut value =
+            bincode::serialize(entry).map_err(|_| "Failed to serialize")?;
+        if let Some(cipher) = &self.cipher {
+            value = cipher.encrypt(&key, &value);
+        }
+        self.backend.put_cf(self.cf(CF_JOURNAL)?, key, value)?;
+
+        Ok(())
+    }
+
+    /// Returns the journaled files of all the buckets, with their bucket id
+    /// and hash
+    pub(crate) fn journaled_files(
+        &self,
+    ) -> Result<Vec<(String, [u8; 32], JournalEntry)>, String> {
+        let mut files = Vec::new();
+        for (key, value) in self.prefix_entries(CF_JOURNAL, &[])? {
+            // Bucket ids are path segments, the hash follows the last `/`
+            let split = key.len().checked_sub(33).ok_or("Invalid key")?;
+            let bucket_id = String::from_utf8_lossy(&key[..split]).to_string();
+            let hash =
+                file_hash(&key[..=split], &key).ok_or("Invalid journal key")?;
+
+            let value = self.decrypt(&bucket_id, &key, value)?;
+            let entry = bincode::deserialize(&value)
+                .map_err(|_| "Failed to deserialize journal entry")?;
+            files.push((bucket_id, hash, entry));
+        }
+
+        Ok(files)
+    }
+
+    /// Queues a bucket to be pushed to a replica
+    ///
+    /// Returns the sequence number of the queue entry, a bucket queued again
+    /// before it is pushed gets a new one.
+    pub(crate) fn queue_replication(
+        &self,
+        replica: &str,
+        bucket_id: &str,
+    ) -> Result<u64, String> {
+        let seq = rand::random::<u64>();
+        let key = replication_key(replica, bucket_id);
+        self.write_cf(CF_REPLICATION, &key, Some(&seq))?;
+
+        Ok(seq)
+    }
+
+    /// Returns the buckets queued for a replica with their sequence number
+    pub(crate) fn replication_queue(
+        &self,
+        replica: &str,
+    ) -> Result<Vec<(String, u64)>, String> {
+        let prefix = replication_key(replica, "");
+        self.prefix_entries(CF_REPLICATION, &prefix)?
+            .into_iter()
+            .map(|(key, value)| {
+                let bucket_id = String::from_utf8_lossy(&key[prefix.len()..]);
+                let seq = bincode::deserialize(&value)
+                    .map_err(|_| "Failed to deserialize value")?;
+                Ok((bucket_id.to_string(), seq))
+            })
+            .collect()
+    }
+
+    /// Removes a bucket from the queue of a replica, unless it was queued
+    /// again since `seq`
+    ///
+    /// Callers hold the database write lock so no entry is queued meanwhile.
+    pub(crate) fn dequeue_replication(
+        &self,
+        replica: &str,
+        bucket_id: &str,
+        seq: u64,
+    ) -> Result<(), String> {
+        let key = replication_key(replica, bucket_id);
+        let cf = self.cf(CF_REPLICATION)?;
+        let stored = self.backend.get_cf(cf, &key)?;
+        let stored: Option<u64> = stored
+            .map(|value| bincode::deserialize(&value))
+            .transpose()
+            .map_err(|_| "Failed to deserialize value")?;
+        if stored == Some(seq) {
+            self.write_cf::<u64>(CF_REPLICATION, &key, None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the last recorded root of every bucket having one
+    pub(crate) fn last_roots(
+        &self,
+    ) -> Result<BTreeMap<String, RootRecord>, String> {
+        let mut roots = BTreeMap::new();
+        for key in self.keys(CF_BUCKETS)? {
+            let bucket_id = String::from_utf8(key)
+                .map_err(|_| "Invalid bucket id".to_owned())?;
+            if let Some((_, record)) = self.last_root(&bucket_id)? {
+                roots.insert(bucket_id, record);
+            }
+        }
+
+        Ok(roots)
+    }
+
+    /// Returns the ids of the stored buckets, sorted
+    ///
+    /// Buckets of the former single record layout are listed once updated.
+    pub(crate) fn bucket_ids(&self) -> Result<Vec<String>, String> {
+        self.keys(CF_BUCKETS)?
+            .into_iter()
+            .map(|key| {
+                String::from_utf8(key)
+                    .map_err(|_| "Invalid bucket id".to_owned())
+            })
+            .collect()
+    }
+
+    /// Returns the last recorded root of a bucket with its sequence number
+    pub(crate) fn last_root(
+        &self,
+        bucket_id: &str,
+    ) -> Result<Option<(u64, RootRecord)>, String> {
+        let prefix = bucket_prefix(bucket_id);
+        let end = [&prefix[..], &u64::MAX.to_be_bytes()].concat();
+
+        let mode = IteratorMode::From(&end, Direction::Reverse);
+        let Some(entry) =
+            self.backend.iterator_cf(self.cf(CF_ROOTS)?, mode).next()
+        else {
+            return Ok(None);
+        };
+        let (key, value) = entry?;
+
+        let Some(seq) = key.strip_prefix(&prefix[..]) else {
+            return Ok(None);
+        };
+        let seq =
+            u64::from_be_bytes(seq.try_into().map_err(|_| "Invalid root key")?);
+        let record = bincode::deserialize(&value)
+            .map_err(|_| "Failed to deserialize root")?;
+
+        Ok(Some((seq, record)))
+    }
+
+    /// Updates an API key in the database
+    pub(crate) fn update_api_key(
+        &self,
+        key_id: &str,
+        key: &ApiKey,
+    ) -> Result<(), String> {
+        self.write_cf(CF_API_KEYS, key_id.as_bytes(), Some(key))
+    }
+
+    /// Deletes an API key from the database
+    pub(crate) fn delete_api_key(&self, key_id: &str) -> Result<(), String> {
+        self.write_cf::<ApiKey>(CF_API_KEYS, key_id.as_bytes(), None)
+    }
+
+    /// Reads all the API keys, converting those of the single bucket layout
+    ///
+    /// A record of the former layout ends with its timestamp, it is too
+    /// short to be read as a list of scopes.
+    pub(crate) fn read_all_api_keys(
+        &self,
+    ) -> Result<HashMap<String, ApiKey>, String> {
+        self.read_all_raw_cf(CF_API_KEYS)?
+            .into_iter()
+            .map(|(key_id, value)| {
+                let key = bincode::deserialize::<ApiKey>(&value)
+                    .or_else(|_| {
+                        bincode::deserialize::<LegacyApiKey>(&value)
+                            .map(ApiKey::from)
+                    })
+                    .map_err(|_| "Failed to deserialize value")?;
+                Ok((key_id, key))
+            })
+            .collect()
+    }
+
+    /// Updates an account in the database
+    pub(crate) fn update_account(
+        &self,
+        account_id: &str,
+        account: &Account,
+    ) -> Result<(), String> {
+        self.write_cf(CF_ACCOUNTS, account_id.as_bytes(), Some(account))
+    }
+
+    /// Deletes an account from the database, its keys are deleted apart
+    pub(crate) fn delete_account(
+        &self,
+        account_id: &str,
+    ) -> Result<(), String> {
+        self.write_cf::<Account>(CF_ACCOUNTS, account_id.as_bytes(), None)
+    }
+
+    pub(crate) fn read_all_accounts(
+        &self,
+    ) -> Result<HashMap<String, Account>, String> {
+        self.read_all_cf(CF_ACCOUNTS)
+    }
+
+    /// Updates a key of an account in the database
+    pub(crate) fn update_account_key(
+        &self,
+        key_id: &str,
+        key: &AccountKey,
+    ) -> Result<(), String> {
+        self.write_cf(CF_ACCOUNT_KEYS, key_id.as_bytes(), Some(key))
+    }
+
+    /// Deletes a key of an account from the database
+    pub(crate) fn delete_account_key(
+        &self,
+        key_id: &str,
+    ) -> Result<(), String> {
+        self.write_cf::<AccountKey>(CF_ACCOUNT_KEYS, key_id.as_bytes(), None)
+    }
+
+    pub(crate) fn read_all_account_keys(
+        &self,
+    ) -> Result<HashMap<String, AccountKey>, String> {
+        self.read_all_cf(CF_ACCOUNT_KEYS)
+    }
+
+    /// Updates the limits of a bucket in the database
+    pub(crate) fn update_bucket_limits(
+        &self,
+        bucket_id: &str,
+        limits: &BucketLimits,
+    ) -> Result<(), String> {
+        self.write_cf(CF_BUCKET_LIMITS, bucket_id.as_bytes(), Some(limits))
+    }
+
+    pub(crate) fn read_all_bucket_limits(
+        &self,
+    ) -> Result<HashMap<String, BucketLimits>, String> {
+        self.read_all_cf(CF_BUCKET_LIMITS)
+    }
+
+    /// Updates the byte rates of a bucket in the database, or removes them
+    /// if `limits` is `None`
+    pub(crate) fn update_bandwidth_limits(
+        &self,
+        bucket_id: &str,
+        limits: Option<&BandwidthLimits>,
+    ) -> Result<(), String> {
+        self.write_cf(CF_BANDWIDTH_LIMITS, bucket_id.as_bytes(), limits)
+    }
+
+    pub(crate) fn read_all_bandwidth_limits(
+        &self,
+    ) -> Result<HashMap<String, BandwidthLimits>, String> {
+        self.read_all_cf(CF_BANDWIDTH_LIMITS)
+    }
+
+    /// Returns the public key owning a bucket, if one was registered
+    pub(crate) fn bucket_owner(
+        &self,
+        bucket_id: &str,
+    ) -> Result<Option<[u8; 32]>, String> {
+        let cf = self.cf(CF_BUCKET_OWNERS)?;
+        match self.backend.get_cf(cf, bucket_id.as_bytes())? {
+            Some(value) => bincode::deserialize(&value)
+                .map(Some)
+                .map_err(|_| "Failed to deserialize value".to_owned()),
+            None => Ok(None),
+        }
+    }
+
+    /// Registers the public key owning a bucket
+    pub(crate) fn set_bucket_owner(
+        &self,
+        bucket_id: &str,
+        public_key: &[u8; 32],
+    ) -> Result<(), String> {
+        self.write_cf(CF_BUCKET_OWNERS, bucket_id.as_bytes(), Some(public_key))
+    }
+
+    /// Sets the unix time after which a file expires, or clears it if
+    /// `expires_at` is `None`
+    pub(crate) fn set_expiration(
+        &self,
+        bucket_id: &str,
+        hash: &[u8; 32],
+        expires_at: Option<u64>,
+    ) -> Result<(), String> {
+        let key = [&bucket_prefix(bucket_id)[..], hash].concat();
+        self.write_cf(CF_EXPIRATIONS, &key, expires_at.as_ref())
+    }
+
+    /// Records the metadata of a file of a bucket
+    ///
+    /// The metadata are encrypted like the file records, as they hold client
+    /// data.
+    pub(crate) fn set_file_meta(
+        &self,
+        bucket_id: &str,
+        hash: &[u8; 32],
+        meta: &FileMeta,
+    ) -> Result<(), String> {
+        let key = [&bucket_prefix(bucket_id)[..], hash].concat();
+        let mut value =
+            bincode::serialize(meta).map_err(|_| "Failed to serialize")?;
+        if let Some(cipher) = &self.cipher {
+            value = cipher.encrypt(&key, &value);
+        }
+        self.backend.put_cf(self.cf(CF_FILE_META)?, key, value)?;
+
+        Ok(())
+    }
+
+    /// Returns the metadata of a file of a bucket, `None` if not recorded
+    pub(crate) fn file_meta(
+        &self,
+        bucket_id: &str,
+        hash: &[u8; 32],
+    ) -> Result<Option<FileMeta>, String> {
+        let key = [&bucket_prefix(bucket_id)[..], hash].concat();
+        let Some(value) = self.backend.get_cf(self.cf(CF_FILE_META)?, &key)?
+        else {
+            return Ok(None);
+        };
+
+        let value = self.decrypt(bucket_id, &key, value)?;
+        let meta = bincode::deserialize(&value)
+            .map_err(|_| "Failed to deserialize file metadata")?;
+
+        Ok(Some(meta))
+    }
+
+    /// Returns the files expired at `now`, as `(bucket_id, file hash)`
+    pub(crate) fn expired_files(
+        &self,
+        now: u64,
+    ) -> Result<Vec<(String, [u8; 32])>, String> {
+        let mut expired = Vec::new();
+        for (key, value) in self.prefix_entries(CF_EXPIRATIONS, &[])? {
+            let expires_at: u64 = bincode::deserialize(&value)
+                .map_err(|_| "Failed to deserialize value")?;
+            if expires_at > now {
+                continue;
+            }
+
+            // The key ends with `/<file hash>`
+            let Some(split) = key.len().checked_sub(33) else {
+                continue;
+            };
+            let (Ok(bucket_id), Ok(hash)) = (
+                String::from_utf8(key[..split].to_vec()),
+                key[split + 1..].try_into(),
+            ) else {
+                continue;
+            };
+            expired.push((bucket_id, hash));
+        }
+
+        Ok(expired)
+    }
+
+    /// Appends a record to the audit log of a bucket, chained to the last
+    /// record of the bucket
+    ///
+    /// The log is kept when the bucket is deleted. Records are encrypted like
+    /// the file records, as they hold file names.
+    pub(crate) fn append_audit(
+        &self,
+        bucket_id: &str,
+        mut record: AuditRecord,
+    ) -> Result<(), String> {
+        let prefix = bucket_prefix(bucket_id);
+        let end = [&prefix[..], &u64::MAX.to_be_bytes()].concat();
+
+        let mode = IteratorMode::From(&end, Direction::Reverse);
+        let last =
+            match self.backend.iterator_cf(self.cf(CF_AUDIT)?, mode).next() {
+                Some(entry) => {
+                    let (key, value) = entry?;
+                    match key.strip_prefix(&prefix[..]) {
+                        Some(seq) => Some(
+                            self.audit_record(bucket_id, seq, &key, value)?,
+                        ),
+                        None => None,
+                    }
+                }
+                None => None,
+            };
+
+        let seq = match last {
+            Some((seq, last)) => {
+                record.prev_hash = last.hash(seq);
+                seq + 1
+            }
+            None => 0,
+        };
+
+        let key = [&prefix[..], &seq.to_be_bytes()].concat();
+        let mut value =
+            bincode::serialize(&record).map_err(|_| "Failed to serialize")?;
+        if let Some(cipher) = &self.cipher {
+            value = cipher.encrypt(&key, &value);
+        }
+        self.backend.put_cf(self.cf(CF_AUDIT)?, key, value)?;
+
+        Ok(())
+    }
+
+    /// Returns a page of the audit log of a bucket with the sequence numbers
+    /// of the records, oldest first
+    pub(crate) fn audit_log(
+        &self,
+        bucket_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(u64, AuditRecord)>, String> {
+        let prefix = bucket_prefix(bucket_id);
+        self.prefix_entries(CF_AUDIT, &prefix)?
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(key, value)| {
+                let seq = &key[prefix.len()..];
+                self.audit_record(bucket_id, seq, &key, value)
+            })
+            .collect()
+    }
+
+    fn audit_record(
+        &self,
+        bucket_id: &str,
+        seq: &[u8],
+        key: &[u8],
+        value: impl Into<Vec<u8>>,
+    ) -> Result<(u64, AuditRecord), String> {
+        let seq = u64::from_be_bytes(
+            seq.try_into().map_err(|_| "Invalid audit key")?,
+        );
+        let value = self.decrypt(bucket_id, key, value.into())?;
+        let record = bincode::deserialize(&value)
+            .map_err(|_| "Failed to deserialize audit record")?;
+
+        Ok((seq, record))
+    }
+
+    /// Returns the lifecycle policy of a bucket, if one was set
+    pub(crate) fn lifecycle_policy(
+        &self,
+        bucket_id: &str,
+    ) -> Result<Option<LifecyclePolicy>, String> {
+        let cf = self.cf(CF_LIFECYCLE_POLICIES)?;
+        match self.backend.get_cf(cf, bucket_id.as_bytes())? {
+            Some(value) => bincode::deserialize(&value)
+                .map(Some)
+                .map_err(|_| "Failed to deserialize value".to_owned()),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the lifecycle policy of a bucket, or removes it if `policy` is
+    /// `None`
+    pub(crate) fn set_lifecycle_policy(
+        &self,
+        bucket_id: &str,
+        policy: Option<&LifecyclePolicy>,
+    ) -> Result<(), String> {
+        self.write_cf(CF_LIFECYCLE_POLICIES, bucket_id.as_bytes(), policy)
+    }
+
+    /// Returns the naming policy of a bucket, the default one unless set
+    pub(crate) fn naming_policy(
+        &self,
+        bucket_id: &str,
+    ) -> Result<NamingPolicy, String> {
+        let cf = self.cf(CF_NAMING_POLICIES)?;
+        match self.backend.get_cf(cf, bucket_id.as_bytes())? {
+            Some(value) => bincode::deserialize(&value)
+                .map_err(|_| "Failed to deserialize value".to_owned()),
+            None => Ok(NamingPolicy::default()),
+        }
+    }
+
+    /// Sets the naming policy of a bucket, or removes it if `policy` is
+    /// `None`
+    pub(crate) fn set_naming_policy(
+        &self,
+        bucket_id: &str,
+        policy: Option<&NamingPolicy>,
+    ) -> Result<(), String> {
+        self.write_cf(CF_NAMING_POLICIES, bucket_id.as_bytes(), policy)
+    }
+
+    /// Returns the number of references to a blob
+    pub(crate) fn blob_refs(&self, hash: &[u8; 32]) -> Result<u64, String> {
+        let cf = self.cf(CF_BLOB_REFS)?;
+
+        match self.backend.get_cf(cf, hash)? {
+            Some(value) => bincode::deserialize(&value)
+                .map_err(|_| "Failed to deserialize value".to_owned()),
+            None => Ok(0),
+        }
+    }
+
+    /// Updates the number of references to a blob, forgetting the blob when
+    /// it drops to zero
+    pub(crate) fn set_blob_refs(
+        &self,
+        hash: &[u8; 32],
+        refs: u64,
+    ) -> Result<(), String> {
+        let refs = (refs > 0).then_some(refs);
+        self.write_cf(CF_BLOB_REFS, hash, refs.as_ref())
+    }
+
+    /// Returns the number of references of all the blobs
+    pub(crate) fn all_blob_refs(&self) -> Result<Vec<([u8; 32], u64)>, String> {
+        self.prefix_entries(CF_BLOB_REFS, &[])?
+            .into_iter()
+            .map(|(key, value)| {
+                let hash = key.try_into().map_err(|_| "Invalid blob key")?;
+                let refs = bincode::deserialize(&value)
+                    .map_err(|_| "Failed to deserialize value")?;
+                Ok((hash, refs))
+            })
+            .collect()
+    }
+
+    /// Counts the references to each blob from the file records, the
+    /// journal and the kept versions, which are the actual references of the
+    /// buckets
+    pub(crate) fn file_refs(&self) -> Result<HashMap<[u8; 32], u64>, String> {
+        let mut refs = HashMap::new();
+        for cf_name in [CF_FILES, CF_JOURNAL] {
+            for key in self.keys(cf_name)? {
+                let split = key.len().checked_sub(32).ok_or("Invalid key")?;
+                let hash =
+                    file_hash(&key[..split], &key).ok_or("Invalid file key")?;
+                *refs.entry(hash).or_default() += 1;
+            }
+        }
+
+        // Each kept version references the blobs of its files
+        for (key, value) in self.prefix_entries(CF_VERSIONS, &[])? {
+            // `<bucket_id>/` followed by the version
+            let split = key.len().checked_sub(9).ok_or("Invalid key")?;
+            let bucket_id = String::from_utf8_lossy(&key[..split]);
+            let value = self.decrypt(&bucket_id, &key, value)?;
+            let files: Vec<([u8; 32], String)> =
+                bincode::deserialize(&value)
+                    .map_err(|_| "Failed to deserialize version")?;
+            for (hash, _) in files {
+                *refs.entry(hash).or_default() += 1;
+            }
+        }
+
+        Ok(refs)
+    }
+
+    /// Returns the hashes of the blobs stored in the database
+    pub(crate) fn inline_blob_hashes(&self) -> Result<Vec<[u8; 32]>, String> {
+        self.keys(CF_INLINE_BLOBS)?
+            .into_iter()
+            .map(|key| {
+                key.try_into().map_err(|_| "Invalid blob key".to_owned())
+            })
+            .collect()
+    }
+
+    fn cf(&self, cf_name: &str) -> Result<&ColumnFamily, String> {
+        self.backend
+            .cf_handle(cf_name)
+            .ok_or_else(|| format!("missing column family {cf_name}"))
+    }
+
+    /// Reads the entries of a column family whose key starts with `prefix`
+    fn prefix_entries(
+        &self,
+        cf_name: &str,
+        prefix: &[u8],
+    ) -> Result<Vec<Entry>, String> {
+        let mut entries = Vec::new();
+
+        let mut iter = self.backend.raw_iterator_cf(self.cf(cf_name)?);
+        iter.seek(prefix);
+
+        while iter.valid() {
+            let key = iter.key().expect("non empty key");
+            if !key.starts_with(prefix) {
+                break;
+            }
+            let value = iter.value().expect("non empty value");
+
+            entries.push((key.to_vec(), value.to_vec()));
+            iter.next();
+        }
+        iter.status()?;
+
+        Ok(entries)
+    }
+
+    /// Reads the keys of a column family, without their values
+    fn keys(&self, cf_name: &str) -> Result<Vec<Vec<u8>>, String> {
+        let mut keys = Vec::new();
+
+        let mut iter = self.backend.raw_iterator_cf(self.cf(cf_name)?);
+        iter.seek_to_first();
+
+        while iter.valid() {
+            keys.push(iter.key().expect("non empty key").to_vec());
+            iter.next();
+        }
+        iter.status()?;
+
+        Ok(keys)
+    }
+
+    /// Returns the content of a blob stored in the database
+    pub(crate) fn inline_blob(
+        &self,
+        hash: &[u8; 32],
+    ) -> Result<Option<Vec<u8>>, String> {
+        let cf = self.cf(CF_INLINE_BLOBS)?;
+        match self.backend.get_cf(cf, hash)? {
+            Some(value) => bincode::deserialize(&value)
+                .map(Some)
+                .map_err(|_| "Failed to deserialize value".to_owned()),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores the content of a blob in the database, or deletes it if
+    /// `data` is `None`
+    pub(crate) fn set_inline_blob(
+        &self,
+        hash: &[u8; 32],
+        data: Option<&[u8]>,
+    ) -> Result<(), String> {
+        self.write_cf(CF_INLINE_BLOBS, hash, data.as_ref())
+    }
+
+    /// Writes a value into a column family, or deletes the key if the value
+    /// is `None`
+    fn write_cf<V: Serialize>(
         &self,
-    ) -> Result<HashMap<String, ClientBucket>, String> {
-        let mut buckets = HashMap::new();
+        cf_name: &str,
+        key: &[u8],
+        value: Option<&V>,
+    ) -> Result<(), String> {
+        let cf = self.cf(cf_name)?;
 
         let write_options = WriteOptions::default();
         let tx_options = OptimisticTransactionOptions::default();
         let inner = self.backend.transaction_opt(&write_options, &tx_options);
 
-        let mut iter = inner.raw_iterator();
+        match value {
+            Some(value) => {
+                let value = bincode::serialize(value)
+                    .map_err(|_| "Failed to serialize value")?;
+                inner.put_cf(cf, key, value)?;
+            }
+            None => inner.delete_cf(cf, key)?,
+        }
+        inner.commit()?;
+
+        Ok(())
+    }
+
+    /// Reads all the entries of a column family keyed by a string
+    fn read_all_cf<V: DeserializeOwned>(
+        &self,
+        cf_name: &str,
+    ) -> Result<HashMap<String, V>, String> {
+        self.read_all_raw_cf(cf_name)?
+            .into_iter()
+            .map(|(key, value)| {
+                let value = bincode::deserialize(&value)
+                    .map_err(|_| "Failed to deserialize value")?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    /// Reads all the entries of a column family keyed by strings, without
+    /// decoding their values
+    fn read_all_raw_cf(
+        &self,
+        cf_name: &str,
+    ) -> Result<HashMap<String, Vec<u8>>, String> {
+        let cf = self.cf(cf_name)?;
+
+        let mut entries = HashMap::new();
+
+        let mut iter = self.backend.raw_iterator_cf(cf);
         iter.seek_to_first();
 
         while iter.valid() {
             let key = iter.key().expect("non empty key");
             let value = iter.value().expect("non empty value");
 
-            let bucket_id = String::from_utf8_lossy(key).to_string();
-
-            buckets.insert(
-                bucket_id,
-                bincode::deserialize(value)
-                    .map_err(|_| "Failed to deserialize bucket")?,
+            entries.insert(
+                String::from_utf8_lossy(key).to_string(),
+                value.to_vec(),
             );
             iter.next();
         }
 
-        Ok(buckets)
+        Ok(entries)
     }
+
+    /// Writes a consistent snapshot of the database into the new folder
+    /// `path`
+    pub(crate) fn checkpoint<T: AsRef<Path>>(
+        &self,
+        path: T,
+    ) -> Result<(), String> {
+        Checkpoint::new(&self.backend)?.create_checkpoint(path)?;
+
+        Ok(())
+    }
+
+    /// Flushes the database
+    pub(crate) fn flush(&self) -> Result<(), String> {
+        self.backend.flush()?;
+
+        Ok(())
+    }
+
+    /// Checks that the database answers reads
+    pub(crate) fn check(&self) -> Result<(), String> {
+        self.backend.get_cf(self.cf(CF_BUCKETS)?, b"")?;
+
+        Ok(())
+    }
+
+    /// Returns the number of bytes stored in a bucket as last persisted,
+    /// `None` if it is not stored
+    ///
+    /// The file records are not read.
+    pub(crate) fn bucket_bytes_used(
+        &self,
+        bucket_id: &str,
+    ) -> Result<Option<u64>, String> {
+        match self
+            .backend
+            .get_cf(self.cf(CF_BUCKETS)?, bucket_id.as_bytes())?
+        {
+            Some(meta) => {
+                BucketMeta::deserialize(&meta).map(|meta| Some(meta.bytes_used))
+            }
+            None => Ok(self
+                .read_legacy_bucket(bucket_id)?
+                .map(|bucket| bucket.bytes_used)),
+        }
+    }
+
+    /// Reads a bucket, `None` if it is not stored
+    ///
+    /// Buckets stored in the former single record layout are read as well,
+    /// they are moved to the new layout by their next update.
+    pub(crate) fn read_bucket(
+        &self,
+        bucket_id: &str,
+    ) -> Result<Option<ClientBucket>, String> {
+        let Some(meta) = self
+            .backend
+            .get_cf(self.cf(CF_BUCKETS)?, bucket_id.as_bytes())?
+        else {
+            return self.read_legacy_bucket(bucket_id);
+        };
+        let meta = BucketMeta::deserialize(&meta)?;
+
+        let prefix = bucket_prefix(bucket_id);
+        let mut files = BTreeMap::new();
+        for (key, value) in self.prefix_entries(CF_FILES, &prefix)? {
+            let hash = file_hash(&prefix, &key).ok_or("Invalid file key")?;
+            let value = self.decrypt(bucket_id, &key, value)?;
+            let filename = bincode::deserialize(&value)
+                .map_err(|_| "Failed to deserialize file")?;
+            files.insert(hash, filename);
+        }
+
+        let mut bucket = ClientBucket::new(bucket_id.to_owned());
+        bucket.files = files;
+        bucket.bytes_used = meta.bytes_used;
+        bucket.state = meta.state;
+
+        Ok(Some(bucket))
+    }
+
+    /// Returns the Merkle tree of a bucket stored by its last update, `None`
+    /// if it is not stored or does not match the files and the last root of
+    /// the bucket
+    ///
+    /// The leaves are the files of `bucket`, the hashes of the interior
+    /// nodes are not verified, see [`merkle::Tree::verify`].
+    pub(crate) fn read_tree(
+        &self,
+        bucket: &ClientBucket,
+    ) -> Result<Option<merkle::Tree>, String> {
+        let bucket_id = &bucket.bucket_id;
+        let Some(levels) = self
+            .backend
+            .get_pinned_cf(self.cf(CF_TREES)?, bucket_id.as_bytes())?
+        else {
+            return Ok(None);
+        };
+        let levels: Vec<merkle::Level> = bincode::deserialize(&levels)
+            .map_err(|_| "Failed to deserialize tree")?;
+
+        let mut tree = vec![bucket.files.keys().copied().collect()];
+        tree.extend(levels);
+        let Some(tree) = merkle::Tree::from_levels(tree) else {
+            return Ok(None);
+        };
+        let last = self.last_root(bucket_id)?;
+        if last.map(|(_, record)| record.root) != tree.root_hash() {
+            return Ok(None);
+        }
+
+        Ok(Some(tree))
+    }
+
+    /// Deletes the Merkle tree of a bucket, it is stored again by the next
+    /// update of the bucket
+    pub(crate) fn delete_tree(&self, bucket_id: &str) -> Result<(), String> {
+        self.write_cf::<Vec<merkle::Level>>(
+            CF_TREES,
+            bucket_id.as_bytes(),
+            None,
+        )
+    }
+
+    /// Reads a bucket stored as a single record in the default column family
+    ///
+    /// The records written before the buckets counted their bytes are read
+    /// with no bytes used, the bytes are counted once the files are moved to
+    /// the blob store, see [`crate::blobs::migrate_bucket`].
+    fn read_legacy_bucket(
+        &self,
+        bucket_id: &str,
+    ) -> Result<Option<ClientBucket>, String> {
+        let key = bucket_id.as_bytes();
+        let Some(value) = self.backend.get(key)? else {
+            return Ok(None);
+        };
+
+        let value = self.decrypt(bucket_id, key, value)?;
+        let bucket = bincode::deserialize::<ClientBucket>(&value)
+            .or_else(|_| {
+                bincode::deserialize::<BaselineBucket>(&value)
+                    .map(ClientBucket::from)
+            })
+            .map_err(|_| "Failed to deserialize bucket")?;
+
+        Ok(Some(bucket))
+    }
+
+    fn decrypt(
+        &self,
+        bucket_id: &str,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt(key, &value),
+            None if MetadataCipher::is_encrypted(&value) => Err(format!(
+                "bucket {bucket_id} is encrypted, missing metadata key"
+            )),
+            None => Ok(value),
+        }
+    }
+}
+
+/// Returns the key of a bucket in the replication queue of a replica
+fn replication_key(replica: &str, bucket_id: &str) -> Vec<u8> {
+    [replica.as_bytes(), &[0], bucket_id.as_bytes()].concat()
+}
+
+/// Extracts the file hash of a key of the files column family
+fn file_hash(prefix: &[u8], key: &[u8]) -> Option<[u8; 32]> {
+    key.strip_prefix(prefix)?.try_into().ok()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audit::AuditOp;
+    use crate::naming::DuplicateNames;
     use tempdir::TempDir;
 
     #[test]
@@ -101,12 +1415,552 @@ mod tests {
         }
 
         let db = DB::create_or_open(tmp_dir.path());
-        let buckets = db.read_all_buckets().expect("valid load");
-
-        assert_eq!(buckets.len(), 1);
-
-        let bucket = buckets.get("bucket_id").unwrap();
+        let bucket = db.read_bucket("bucket_id").expect("valid load").unwrap();
         assert_eq!(bucket.bucket_id, "bucket_id");
         assert_eq!(bucket.files.len(), 2);
     }
+
+    #[test]
+    fn test_delete_bucket() {
+        let tmp_dir =
+            TempDir::new("test_delete_bucket").expect("valid temp dir");
+        {
+            let db = DB::create_or_open(tmp_dir.path());
+
+            let mut kept = ClientBucket::new("kept".to_string());
+            kept.files.insert([1u8; 32], "file_1".to_string());
+            kept.calculate_merkle_tree();
+            assert!(db.update_bucket(&kept).is_ok());
+
+            let mut deleted = ClientBucket::new("deleted".to_string());
+            deleted.files.insert([2u8; 32], "file_2".to_string());
+            deleted.calculate_merkle_tree();
+            assert!(db.update_bucket(&deleted).is_ok());
+            assert_eq!(db.roots("deleted").expect("valid roots").len(), 1);
+
+            assert!(db.delete_bucket("deleted").is_ok());
+            assert!(db.flush().is_ok());
+        }
+
+        // The deletion is persisted, along with the roots of the bucket, and
+        // the other buckets are left untouched
+        let db = DB::create_or_open(tmp_dir.path());
+        assert!(db.read_bucket("deleted").expect("valid load").is_none());
+        assert_eq!(db.bucket_bytes_used("deleted"), Ok(None));
+        assert!(db.roots("deleted").expect("valid roots").is_empty());
+        assert_eq!(db.file_refs().unwrap().get(&[2u8; 32]), None);
+
+        let kept = db.read_bucket("kept").expect("valid load").unwrap();
+        assert_eq!(kept.files.len(), 1);
+        assert_eq!(db.roots("kept").expect("valid roots").len(), 1);
+    }
+
+    #[test]
+    fn test_bucket_owner() {
+        let tmp_dir =
+            TempDir::new("test_bucket_owner").expect("valid temp dir");
+        {
+            let db = DB::create_or_open(tmp_dir.path());
+
+            assert_eq!(db.bucket_owner("bucket_id"), Ok(None));
+            assert!(db.set_bucket_owner("bucket_id", &[3u8; 32]).is_ok());
+            assert!(db.set_bucket_owner("deleted", &[4u8; 32]).is_ok());
+            assert!(db.flush().is_ok());
+        }
+
+        // The owners are persisted and dropped with their bucket
+        let db = DB::create_or_open(tmp_dir.path());
+        assert_eq!(db.bucket_owner("bucket_id"), Ok(Some([3u8; 32])));
+        assert_eq!(db.bucket_owner("deleted"), Ok(Some([4u8; 32])));
+        assert!(db.delete_bucket("deleted").is_ok());
+        assert_eq!(db.bucket_owner("deleted"), Ok(None));
+        assert_eq!(db.bucket_owner("bucket_id"), Ok(Some([3u8; 32])));
+    }
+
+    #[test]
+    fn test_bucket_records() {
+        let tmp_dir =
+            TempDir::new("test_bucket_records").expect("valid temp dir");
+        let db = DB::create_or_open(tmp_dir.path());
+
+        let mut bucket = ClientBucket::new("bucket_id".to_string());
+        bucket.files.insert([1u8; 32], "file_1".to_string());
+        bucket.files.insert([2u8; 32], "file_2".to_string());
+        bucket.bytes_used = 10;
+        bucket.calculate_merkle_tree();
+        assert!(db.update_bucket(&bucket).is_ok());
+        assert!(db.update_bucket(&bucket).is_ok());
+
+        // A root is recorded only when it changes
+        let roots = db.roots("bucket_id").expect("valid roots");
+        assert_eq!(roots.len(), 1);
+        assert_eq!(Some(roots[0].root), bucket.merkle_tree.root_hash());
+        assert_eq!(roots[0].leaves_count, 2);
+
+        bucket.files.remove(&[1u8; 32]);
+        bucket.calculate_merkle_tree();
+        assert!(db.update_bucket(&bucket).is_ok());
+        assert_eq!(db.roots("bucket_id").expect("valid roots").len(), 2);
+
+        let stored = db.read_bucket("bucket_id").expect("valid load").unwrap();
+        assert_eq!(stored.files, bucket.files);
+        assert_eq!(stored.bytes_used, 10);
+
+        // A bucket of the single record layout is moved on update
+        let legacy = ClientBucket::new("legacy".to_string());
+        let value = bincode::serialize(&legacy).unwrap();
+        assert!(db.backend.put("legacy", value).is_ok());
+        assert!(db.read_bucket("legacy").expect("valid load").is_some());
+        assert!(db.update_bucket(&legacy).is_ok());
+        assert!(db.backend.get("legacy").unwrap().is_none());
+        assert!(db.read_bucket("legacy").expect("valid load").is_some());
+
+        // So is a bucket written before the buckets counted their bytes
+        let files = BTreeMap::from([([5u8; 32], "./buckets/v0/a".to_owned())]);
+        let baseline = BaselineBucket {
+            bucket_id: "v0".to_owned(),
+            files: files.clone(),
+            merkle_tree: merkle::Tree::build_from_leaves(vec![[5u8; 32]]),
+        };
+        let value = bincode::serialize(&baseline).unwrap();
+        assert!(db.backend.put("v0", value).is_ok());
+        let stored = db.read_bucket("v0").expect("valid load").unwrap();
+        assert_eq!(stored.files, files);
+        assert_eq!(stored.bytes_used, 0);
+        assert_eq!(db.bucket_bytes_used("v0"), Ok(Some(0)));
+        assert!(db.update_bucket(&stored).is_ok());
+        assert!(db.backend.get("v0").unwrap().is_none());
+        assert!(db.delete_bucket("v0").is_ok());
+
+        // Empty buckets have no root
+        let last_roots = db.last_roots().expect("valid roots");
+        let roots = db.roots("bucket_id").expect("valid roots");
+        assert_eq!(last_roots.len(), 1);
+        assert_eq!(last_roots.get("bucket_id"), roots.last());
+        assert_eq!(
+            db.bucket_ids(),
+            Ok(vec!["bucket_id".into(), "legacy".into()])
+        );
+
+        assert!(db.delete_bucket("bucket_id").is_ok());
+        assert!(db.read_bucket("bucket_id").expect("valid load").is_none());
+        assert!(db.roots("bucket_id").expect("valid roots").is_empty());
+        assert!(db.last_roots().expect("valid roots").is_empty());
+    }
+
+    #[test]
+    fn test_bucket_state() {
+        let tmp_dir =
+            TempDir::new("test_bucket_state").expect("valid temp dir");
+        let db = DB::create_or_open(tmp_dir.path());
+
+        let mut bucket = ClientBucket::new("bucket_id".to_string());
+        bucket.files.insert([1u8; 32], "file_1".to_string());
+        bucket.calculate_merkle_tree();
+        bucket.state = BucketState::Sealed;
+        assert!(db.update_bucket(&bucket).is_ok());
+        let stored = db.read_bucket("bucket_id").expect("valid load").unwrap();
+        assert_eq!(stored.state, BucketState::Sealed);
+
+        // Sealing again records the unchanged root as a new version
+        assert!(db.record_seal(&bucket).is_ok());
+        let roots = db.roots("bucket_id").expect("valid roots");
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0].root, roots[1].root);
+        bucket.files.insert([2u8; 32], "file_2".to_string());
+        bucket.calculate_merkle_tree();
+        assert!(db.record_seal(&bucket).is_ok());
+        assert!(db.update_bucket(&bucket).is_ok());
+        assert_eq!(db.roots("bucket_id").expect("valid roots").len(), 3);
+
+        // Metadata stored before the state are open buckets
+        let meta = bincode::serialize(&10u64).unwrap();
+        let buckets = db.cf(CF_BUCKETS).unwrap();
+        assert!(db.backend.put_cf(buckets, "legacy", meta).is_ok());
+        let legacy = db.read_bucket("legacy").expect("valid load").unwrap();
+        assert_eq!(legacy.state, BucketState::Open);
+        assert_eq!(legacy.bytes_used, 10);
+    }
+
+    #[test]
+    fn test_trees() {
+        let tmp_dir = TempDir::new("test_trees").expect("valid temp dir");
+        let db = DB::create_or_open(tmp_dir.path());
+
+        let mut bucket = ClientBucket::new("bucket_id".to_string());
+        for i in 0..5u8 {
+            bucket.files.insert([i; 32], format!("file_{i}"));
+        }
+        bucket.calculate_merkle_tree();
+        assert!(db.update_bucket(&bucket).is_ok());
+
+        let stored = db.read_bucket("bucket_id").expect("valid load").unwrap();
+        let tree = db.read_tree(&stored).expect("valid tree").unwrap();
+        assert_eq!(tree.levels(), bucket.merkle_tree.levels());
+
+        // A tree of another number of files is not restored, one of other
+        // files fails its verification
+        let mut other = stored.clone();
+        other.files.remove(&[0; 32]);
+        assert!(db.read_tree(&other).expect("valid tree").is_none());
+        other.files.insert([9; 32], "file_9".to_string());
+        let tree = db.read_tree(&other).expect("valid tree").unwrap();
+        assert!(!tree.verify());
+
+        // Trees missing before the update are stored by the next one
+        assert!(db.delete_tree("bucket_id").is_ok());
+        assert!(db.read_tree(&stored).expect("valid tree").is_none());
+        assert!(db.update_bucket(&bucket).is_ok());
+        assert!(db.read_tree(&stored).expect("valid tree").is_some());
+
+        bucket.files.clear();
+        bucket.calculate_merkle_tree();
+        assert!(db.update_bucket(&bucket).is_ok());
+        assert!(db.read_tree(&stored).expect("valid tree").is_none());
+
+        bucket.files.insert([1; 32], "file_1".to_string());
+        bucket.calculate_merkle_tree();
+        assert!(db.update_bucket(&bucket).is_ok());
+        assert!(db.delete_bucket("bucket_id").is_ok());
+        let trees = db.keys(CF_TREES).expect("valid keys");
+        assert!(trees.is_empty());
+    }
+
+    #[test]
+    fn test_journal() {
+        let tmp_dir = TempDir::new("test_journal").expect("valid temp dir");
+        let db = DB::create_or_open(tmp_dir.path())
+            .with_metadata_key(Some([9u8; 32]));
+
+        let entry = JournalEntry {
+            filename: "file_1".to_string(),
+            size: 10,
+        };
+        assert!(db.journal_file("bucket_id", &[1u8; 32], &entry).is_ok());
+        assert!(db.journal_file("other", &[2u8; 32], &entry).is_ok());
+        assert_eq!(
+            db.journaled_files().expect("valid journal"),
+            [
+                ("bucket_id".to_string(), [1u8; 32], entry.clone()),
+                ("other".to_string(), [2u8; 32], entry.clone()),
+            ]
+        );
+
+        // Persisting or deleting a bucket clears its journal
+        let bucket = ClientBucket::new("bucket_id".to_string());
+        assert!(db.update_bucket(&bucket).is_ok());
+        assert!(db.delete_bucket("other").is_ok());
+        assert!(db.journaled_files().expect("valid journal").is_empty());
+    }
+
+    #[test]
+    fn test_expirations() {
+        let tmp_dir = TempDir::new("test_expirations").expect("valid temp dir");
+        let db = DB::create_or_open(tmp_dir.path());
+
+        assert!(db.set_expiration("bucket_id", &[1u8; 32], Some(10)).is_ok());
+        assert!(db.set_expiration("bucket_id", &[2u8; 32], Some(20)).is_ok());
+        assert!(db.set_expiration("other", &[3u8; 32], Some(10)).is_ok());
+        assert_eq!(
+            db.expired_files(15).expect("valid expirations"),
+            [
+                ("bucket_id".to_string(), [1u8; 32]),
+                ("other".to_string(), [3u8; 32]),
+            ]
+        );
+
+        // Removing a file or its bucket drops its expiration
+        let mut bucket = ClientBucket::new("bucket_id".to_string());
+        bucket.files.insert([2u8; 32], "file_2".to_string());
+        assert!(db.update_bucket(&bucket).is_ok());
+        bucket.files.clear();
+        assert!(db.update_bucket(&bucket).is_ok());
+        assert!(db.set_expiration("bucket_id", &[1u8; 32], None).is_ok());
+        assert!(db.delete_bucket("other").is_ok());
+        assert!(db.expired_files(u64::MAX).expect("valid").is_empty());
+
+        let policy = LifecyclePolicy {
+            expire_after: Some(60),
+        };
+        assert!(db.set_lifecycle_policy("bucket_id", Some(&policy)).is_ok());
+        assert_eq!(
+            db.lifecycle_policy("bucket_id").expect("valid policy"),
+            Some(policy)
+        );
+        assert!(db.set_lifecycle_policy("bucket_id", None).is_ok());
+        assert_eq!(db.lifecycle_policy("bucket_id").expect("valid"), None);
+    }
+
+    #[test]
+    fn test_naming_policy() {
+        let tmp_dir =
+            TempDir::new("test_naming_policy").expect("valid temp dir");
+        let db = DB::create_or_open(tmp_dir.path());
+
+        let default = db.naming_policy("bucket_id").expect("valid policy");
+        assert_eq!(default.duplicates, DuplicateNames::Reject);
+
+        let policy = NamingPolicy {
+            duplicates: DuplicateNames::Suffix,
+        };
+        assert!(db.set_naming_policy("bucket_id", Some(&policy)).is_ok());
+        assert_eq!(db.naming_policy("bucket_id").expect("valid"), policy);
+        assert!(db.set_naming_policy("bucket_id", None).is_ok());
+        assert_eq!(db.naming_policy("bucket_id").expect("valid"), default);
+    }
+
+    #[test]
+    fn test_file_meta() {
+        let tmp_dir = TempDir::new("test_file_meta").expect("valid temp dir");
+        let db = DB::create_or_open(tmp_dir.path())
+            .with_metadata_key(Some([9u8; 32]));
+
+        let meta = FileMeta {
+            size: 10,
+            uploaded_at: 20,
+            content_type: Some("text/plain".to_string()),
+            metadata: BTreeMap::from([("k".to_string(), "v".to_string())]),
+            tags: vec!["t".to_string()],
+        };
+        assert!(db.set_file_meta("bucket_id", &[1u8; 32], &meta).is_ok());
+        assert!(db.set_file_meta("other", &[2u8; 32], &meta).is_ok());
+        assert_eq!(
+            db.file_meta("bucket_id", &[1u8; 32]).expect("valid meta"),
+            Some(meta.clone())
+        );
+        assert_eq!(db.file_meta("bucket_id", &[2u8; 32]), Ok(None));
+
+        // Removing a file or its bucket drops its metadata
+        let mut bucket = ClientBucket::new("bucket_id".to_string());
+        bucket.files.insert([1u8; 32], "file_1".to_string());
+        assert!(db.update_bucket(&bucket).is_ok());
+        bucket.files.clear();
+        assert!(db.update_bucket(&bucket).is_ok());
+        assert!(db.delete_bucket("other").is_ok());
+        assert_eq!(db.file_meta("bucket_id", &[1u8; 32]), Ok(None));
+        assert_eq!(db.file_meta("other", &[2u8; 32]), Ok(None));
+    }
+
+    #[test]
+    fn test_versions() {
+        let tmp_dir = TempDir::new("test_versions").expect("valid temp dir");
+        let db = DB::create_or_open(tmp_dir.path())
+            .with_metadata_key(Some([9u8; 32]));
+
+        let mut bucket = ClientBucket::new("bucket_id".to_string());
+        bucket.files.insert([1u8; 32], "file_1".to_string());
+        bucket.calculate_merkle_tree();
+        assert!(db.update_bucket(&bucket).is_ok());
+        let (version, record) = db.last_root("bucket_id").unwrap().unwrap();
+        assert_eq!(db.root_version("bucket_id", version), Ok(Some(record)));
+        assert_eq!(db.root_version("bucket_id", version + 1), Ok(None));
+
+        let files = vec![([1u8; 32], "file_1".to_string())];
+        assert!(db.set_version_files("bucket_id", 0, Some(&files)).is_ok());
+        assert!(db.set_version_files("bucket_id", 1, Some(&files)).is_ok());
+        assert_eq!(db.version_files("bucket_id", 0), Ok(Some(files.clone())));
+        assert_eq!(db.kept_versions("bucket_id"), Ok(vec![0, 1]));
+
+        // The file record and both versions reference the blob
+        assert_eq!(db.file_refs().unwrap().get(&[1u8; 32]), Some(&3));
+
+        assert!(db.set_version_files("bucket_id", 0, None).is_ok());
+        assert_eq!(db.version_files("bucket_id", 0), Ok(None));
+        assert!(db.delete_bucket("bucket_id").is_ok());
+        assert_eq!(db.kept_versions("bucket_id"), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let tmp_dir = TempDir::new("test_audit_log").expect("valid temp dir");
+        let db = DB::create_or_open(tmp_dir.path())
+            .with_metadata_key(Some([9u8; 32]));
+
+        let upload = AuditRecord::new(AuditOp::Upload, Some("peer".into()))
+            .with_file("file_1", &[1u8; 32]);
+        let complete = AuditRecord::new(AuditOp::CompleteUpload, None)
+            .with_root(Some([2u8; 32]));
+        assert!(db.append_audit("bucket_id", upload.clone()).is_ok());
+        assert!(db.append_audit("other", upload.clone()).is_ok());
+        assert!(db.append_audit("bucket_id", complete.clone()).is_ok());
+
+        // The records are chained, and kept once the bucket is deleted
+        assert!(db.delete_bucket("bucket_id").is_ok());
+        let log = db.audit_log("bucket_id", 0, 10).expect("valid log");
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0], (0, upload.clone()));
+        assert_eq!(log[1].0, 1);
+        assert_eq!(log[1].1.prev_hash, upload.hash(0));
+        assert_eq!(log[1].1.root, complete.root);
+
+        assert_eq!(db.audit_log("bucket_id", 1, 10).expect("valid").len(), 1);
+        assert_eq!(db.audit_log("other", 0, 10).expect("valid").len(), 1);
+    }
+
+    #[test]
+    fn test_replication_queue() {
+        let db = DB::in_memory();
+
+        let seq = db.queue_replication("http://a", "bucket_1").unwrap();
+        assert!(db.queue_replication("http://b", "bucket_2").is_ok());
+        assert_eq!(
+            db.replication_queue("http://a"),
+            Ok(vec![("bucket_1".to_string(), seq)])
+        );
+
+        // A bucket queued again stays in the queue
+        let new_seq = db.queue_replication("http://a", "bucket_1").unwrap();
+        assert!(db.dequeue_replication("http://a", "bucket_1", seq).is_ok());
+        assert_eq!(db.replication_queue("http://a").unwrap().len(), 1);
+
+        assert!(db
+            .dequeue_replication("http://a", "bucket_1", new_seq)
+            .is_ok());
+        assert_eq!(db.replication_queue("http://a"), Ok(vec![]));
+        assert_eq!(db.replication_queue("http://b").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_encrypted_buckets() {
+        let tmp_dir =
+            TempDir::new("test_encrypted_buckets").expect("valid temp dir");
+        let key = Some([9u8; 32]);
+        {
+            let db = DB::create_or_open(tmp_dir.path());
+
+            let mut plain_bucket = ClientBucket::new("plain".to_string());
+            plain_bucket.files.insert([1u8; 32], "file_1".to_string());
+            assert!(db.update_bucket(&plain_bucket).is_ok());
+
+            let db = db.with_metadata_key(key);
+            let mut bucket = ClientBucket::new("encrypted".to_string());
+            bucket.files.insert([2u8; 32], "secret_name".to_string());
+            assert!(db.update_bucket(&bucket).is_ok());
+            assert!(db.flush().is_ok());
+        }
+
+        // Encrypted records require the key
+        let db = DB::create_or_open(tmp_dir.path());
+        assert!(db.read_bucket("encrypted").is_err());
+
+        let db = db.with_metadata_key(key);
+        let bucket = db.read_bucket("encrypted").expect("valid load").unwrap();
+        assert_eq!(bucket.files.get(&[2u8; 32]).unwrap(), "secret_name");
+        let bucket = db.read_bucket("plain").expect("valid load").unwrap();
+        assert_eq!(bucket.files.len(), 1);
+    }
+
+    #[test]
+    fn test_accounts() {
+        let tmp_dir = TempDir::new("test_accounts").expect("valid temp dir");
+        {
+            let db = DB::create_or_open(tmp_dir.path());
+
+            let mut account = Account::default();
+            account.buckets.insert("bucket_id".to_string());
+            assert!(db.update_account("alice", &account).is_ok());
+            assert!(db.update_account("bob", &account).is_ok());
+            assert!(db.delete_account("bob").is_ok());
+
+            let key = AccountKey {
+                account_id: "alice".to_string(),
+                created_at: 1,
+            };
+            assert!(db.update_account_key("key_1", &key).is_ok());
+            assert!(db.update_account_key("key_2", &key).is_ok());
+            assert!(db.delete_account_key("key_2").is_ok());
+
+            let mut bucket = ClientBucket::new("bucket_id".to_string());
+            bucket.bytes_used = 42;
+            assert!(db.update_bucket(&bucket).is_ok());
+            assert!(db.flush().is_ok());
+        }
+
+        let db = DB::create_or_open(tmp_dir.path());
+
+        let accounts = db.read_all_accounts().expect("valid load");
+        assert_eq!(accounts.len(), 1);
+        assert!(accounts.get("alice").unwrap().buckets.contains("bucket_id"));
+        let keys = db.read_all_account_keys().expect("valid load");
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys.get("key_1").unwrap().account_id, "alice");
+
+        assert_eq!(db.bucket_bytes_used("bucket_id"), Ok(Some(42)));
+        assert_eq!(db.bucket_bytes_used("other"), Ok(None));
+    }
+
+    #[test]
+    fn test_api_keys_and_limits() {
+        let tmp_dir =
+            TempDir::new("test_api_keys_and_limits").expect("valid temp dir");
+        {
+            let db = DB::create_or_open(tmp_dir.path());
+
+            let key = ApiKey {
+                scopes: vec!["bucket_id".to_string()],
+                created_at: 1,
+            };
+            assert!(db.update_api_key("key_1", &key).is_ok());
+            assert!(db.update_api_key("key_2", &key).is_ok());
+            assert!(db.delete_api_key("key_1").is_ok());
+
+            // A key of the single bucket layout
+            let legacy = bincode::serialize(&("legacy_bucket", 2u64)).unwrap();
+            let cf = db.cf(CF_API_KEYS).unwrap();
+            assert!(db.backend.put_cf(cf, "key_3", legacy).is_ok());
+
+            let limits = BucketLimits {
+                quota_bytes: Some(1024),
+                rate_limit: None,
+            };
+            assert!(db.update_bucket_limits("bucket_id", &limits).is_ok());
+
+            let bandwidth = BandwidthLimits {
+                upload_rate: Some(4096),
+                download_rate: None,
+            };
+            assert!(db
+                .update_bandwidth_limits("bucket_id", Some(&bandwidth))
+                .is_ok());
+            assert!(db
+                .update_bandwidth_limits("other", Some(&bandwidth))
+                .is_ok());
+            assert!(db.update_bandwidth_limits("other", None).is_ok());
+            assert!(db.flush().is_ok());
+        }
+
+        let db = DB::create_or_open(tmp_dir.path());
+
+        let keys = db.read_all_api_keys().expect("valid load");
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys.get("key_2").unwrap().scopes, vec!["bucket_id"]);
+        let legacy = ApiKey {
+            scopes: vec!["legacy_bucket".to_string()],
+            created_at: 2,
+        };
+        assert_eq!(keys.get("key_3"), Some(&legacy));
+
+        let limits = db.read_all_bucket_limits().expect("valid load");
+        assert_eq!(limits.get("bucket_id").unwrap().quota_bytes, Some(1024));
+
+        let bandwidth = db.read_all_bandwidth_limits().expect("valid load");
+        assert_eq!(bandwidth.len(), 1);
+        assert_eq!(bandwidth["bucket_id"].upload_rate, Some(4096));
+
+        // Buckets are stored apart from the other column families
+        assert!(db.read_bucket("bucket_id").expect("valid load").is_none());
+
+        let hash = [7u8; 32];
+        assert_eq!(db.blob_refs(&hash), Ok(0));
+        assert!(db.set_blob_refs(&hash, 2).is_ok());
+        assert_eq!(db.blob_refs(&hash), Ok(2));
+        assert!(db.set_blob_refs(&hash, 0).is_ok());
+        assert_eq!(db.blob_refs(&hash), Ok(0));
+
+        assert_eq!(db.inline_blob(&hash), Ok(None));
+        assert!(db.set_inline_blob(&hash, Some(b"data")).is_ok());
+        assert_eq!(db.inline_blob(&hash), Ok(Some(b"data".to_vec())));
+        assert!(db.set_inline_blob(&hash, None).is_ok());
+        assert_eq!(db.inline_blob(&hash), Ok(None));
+    }
 }
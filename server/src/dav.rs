@@ -0,0 +1,569 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::{Extension, State};
+use axum::http::header::{
+    ALLOW, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE,
+};
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::any;
+use axum::Router;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use percent_encoding::{
+    percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC,
+};
+use tracing::{error, info};
+
+use crate::access::{AccessDenied, Operation};
+use crate::app::{self, ReceivedFile, ServerState};
+use crate::audit::Peer;
+use crate::batch;
+use crate::blobs;
+use crate::client_bucket::ClientBucket;
+use crate::errors::{ApiError, ErrorCode};
+use crate::file_meta::UploadMeta;
+use crate::limits::Work;
+use crate::naming::DuplicateNames;
+use crate::ownership::Signed;
+use crate::tls::ClientCert;
+
+/// Methods of the WebDAV routes
+const ALLOWED_METHODS: &str = "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE";
+
+/// Characters of a file name escaped in the `href` of a WebDAV response
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Returns the WebDAV routes, serving each bucket as a collection of its
+/// files at `/dav/:bucket_id/`
+///
+/// Files are addressed by name rather than by index. A `PUT` replaces the
+/// file of the same name, if any, and both `PUT` and `DELETE` complete the
+/// upload of the bucket, so its Merkle root follows the mounted folder.
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    // WebDAV uses methods of its own, like PROPFIND, the requests are
+    // dispatched by `handle_dav`
+    Router::new()
+        .route("/dav", any(handle_dav))
+        .route("/dav/*path", any(handle_dav))
+        .with_state(state)
+}
+
+/// Dispatches a WebDAV request by its method and its path
+///
+/// - `OPTIONS /dav/...`, probed by clients before authenticating
+/// - `PROPFIND /dav/:bucket_id`, bucket listing
+/// - `PROPFIND /dav/:bucket_id/:filename`, file properties
+/// - `GET /dav/:bucket_id/:filename`, file download
+/// - `PUT /dav/:bucket_id/:filename`, file upload
+/// - `DELETE /dav/:bucket_id/:filename`, file deletion
+async fn handle_dav(
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    client_cert: Option<Extension<ClientCert>>,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+    body: Body,
+) -> Response {
+    if method == Method::OPTIONS {
+        return ([("dav", "1"), (ALLOW.as_str(), ALLOWED_METHODS)], "")
+            .into_response();
+    }
+
+    let (operation, bucket_id, filename) = match target(&method, uri.path()) {
+        Ok(target) => target,
+        Err(err) => return err.into_response(),
+    };
+    if let Err(err) =
+        check_access(&state, &bucket_id, operation, &headers, client_cert)
+    {
+        return denied(err);
+    }
+
+    let res = match filename {
+        None => {
+            let depth = headers
+                .get("depth")
+                .and_then(|depth| depth.to_str().ok())
+                .map(str::to_owned);
+            handle_list(bucket_id, depth, state).await
+        }
+        Some(filename) => match method.as_str() {
+            "PROPFIND" => handle_stat(bucket_id, filename, state).await,
+            "GET" | "HEAD" => handle_get(bucket_id, filename, state).await,
+            "PUT" => handle_put(bucket_id, filename, body, peer, state).await,
+            _ => handle_delete(bucket_id, filename, peer, state).await,
+        },
+    };
+    res.unwrap_or_else(IntoResponse::into_response)
+}
+
+/// Returns the operation of a request, its bucket id and the percent-decoded
+/// file name, if any
+///
+/// WebDAV clients encode the names of the files they mount, while bucket
+/// ids are taken as is, like in the other routes. A collection may be
+/// addressed with a trailing slash.
+fn target(
+    method: &Method,
+    path: &str,
+) -> Result<(Operation, String, Option<String>), ApiError> {
+    let operation = match method.as_str() {
+        "PROPFIND" | "GET" | "HEAD" => Operation::Read,
+        "PUT" => Operation::Write,
+        "DELETE" => Operation::Delete,
+        _ => {
+            return Err(ApiError::new(
+                ErrorCode::MethodNotAllowed,
+                "method not allowed",
+            ))
+        }
+    };
+
+    let path = path.strip_prefix("/dav").unwrap_or_default();
+    let mut segments: Vec<&str> = path.split('/').skip(1).collect();
+    if segments.last() == Some(&"") {
+        segments.pop();
+    }
+    let not_found = || ApiError::new(ErrorCode::NotFound, "not found");
+    let (bucket_id, filename) = match segments[..] {
+        [bucket_id] if method.as_str() == "PROPFIND" => (bucket_id, None),
+        [bucket_id, filename] => (bucket_id, Some(filename)),
+        _ => return Err(not_found()),
+    };
+    if bucket_id.is_empty() {
+        return Err(not_found());
+    }
+    let filename = filename
+        .map(|filename| {
+            percent_decode_str(filename)
+                .decode_utf8()
+                .map(|filename| filename.into_owned())
+                .map_err(|_| ApiError::bad_request("invalid file name"))
+        })
+        .transpose()?;
+
+    Ok((operation, bucket_id.to_owned(), filename))
+}
+
+/// Checks the request is allowed to perform `operation` on the bucket, as
+/// [`crate::access::bucket_access`] does
+///
+/// WebDAV clients authenticate with HTTP Basic, the password is taken as
+/// the API key or JWT and the user name is ignored.
+fn check_access(
+    state: &ServerState,
+    bucket_id: &str,
+    operation: Operation,
+    headers: &HeaderMap,
+    client_cert: Option<Extension<ClientCert>>,
+) -> Result<(), AccessDenied> {
+    let authorization = headers
+        .get(AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .map(bearer);
+    state.access.check(
+        bucket_id,
+        operation,
+        authorization.as_deref(),
+        client_cert.as_ref().map(|cert| cert.0 .0.as_str()),
+    )
+}
+
+/// Returns the reply of a request refused by [`check_access`]
+///
+/// The requests lacking credentials are replied with a Basic authentication
+/// challenge, so WebDAV clients prompt for them.
+fn denied(err: AccessDenied) -> Response {
+    match err {
+        AccessDenied::Unauthorized => (
+            [(WWW_AUTHENTICATE, "Basic realm=\"storage\"")],
+            ApiError::from(err),
+        )
+            .into_response(),
+        err => err.into_response(),
+    }
+}
+
+/// Returns a Basic authorization as the Bearer one of its password, other
+/// authorizations as is
+fn bearer(authorization: &str) -> String {
+    let password = authorization
+        .strip_prefix("Basic ")
+        .and_then(|credentials| BASE64_STANDARD.decode(credentials).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .and_then(|credentials| {
+            credentials
+                .split_once(':')
+                .map(|(_, password)| password.to_owned())
+        });
+
+    match password {
+        Some(password) => format!("Bearer {password}"),
+        None => authorization.to_owned(),
+    }
+}
+
+/// A resource of a PROPFIND response
+struct Entry {
+    href: String,
+    /// Hash and size of a file, `None` for the bucket collection
+    file: Option<([u8; 32], u64)>,
+}
+
+/// Handles PROPFIND request on a bucket
+///
+/// Lists the files of the bucket, unless `Depth` is 0. A bucket without
+/// files is an empty collection, so a new bucket can be mounted.
+async fn handle_list(
+    bucket_id: String,
+    depth: Option<String>,
+    state: Arc<ServerState>,
+) -> Result<Response, ApiError> {
+    info!(request = "dav list", bucket_id, depth);
+
+    let mut entries = vec![Entry {
+        href: format!("/dav/{bucket_id}/"),
+        file: None,
+    }];
+    if depth.as_deref() == Some("0") {
+        return Ok(multistatus(&entries));
+    }
+
+    if let Some(bucket) =
+        app::get_bucket(bucket_id.clone(), state.clone()).await
+    {
+        let bucket = bucket.read().await;
+        let db = state.db.read().await;
+
+        // A name uploaded several times is served by its first file
+        let mut names = HashSet::new();
+        for (hash, filename) in &bucket.files {
+            if !names.insert(filename) {
+                continue;
+            }
+            let size = blobs::size(&*state.store, &db, hash)
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+            entries.push(Entry {
+                href: href(&bucket_id, filename),
+                file: Some((*hash, size)),
+            });
+        }
+    }
+
+    Ok(multistatus(&entries))
+}
+
+/// Handles PROPFIND request on a file
+async fn handle_stat(
+    bucket_id: String,
+    filename: String,
+    state: Arc<ServerState>,
+) -> Result<Response, ApiError> {
+    info!(request = "dav stat", bucket_id, filename);
+
+    let bucket = app::get_bucket(bucket_id.clone(), state.clone())
+        .await
+        .ok_or_else(|| ApiError::file_not_found(&filename))?;
+    let bucket = bucket.read().await;
+    let (_, hash) = find(&bucket, &filename)?;
+
+    let size = {
+        let db = state.db.read().await;
+        blobs::size(&*state.store, &db, &hash)
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default()
+    };
+
+    let entry = Entry {
+        href: href(&bucket_id, &filename),
+        file: Some((hash, size)),
+    };
+    Ok(multistatus(&[entry]))
+}
+
+/// Handles GET request, streaming the file as the download route does
+async fn handle_get(
+    bucket_id: String,
+    filename: String,
+    state: Arc<ServerState>,
+) -> Result<Response, ApiError> {
+    let index = {
+        let bucket = app::get_bucket(bucket_id.clone(), state.clone())
+            .await
+            .ok_or_else(|| ApiError::file_not_found(&filename))?;
+        let bucket = bucket.read().await;
+        find(&bucket, &filename)?.0
+    };
+
+    app::handle_download_file(bucket_id, index.to_string(), None, state)
+        .await
+        .map(IntoResponse::into_response)
+}
+
+/// Handles PUT request
+///
+/// The file is stored as an upload would, then the file of the same name,
+/// if any, is deleted. Replies `201 Created` for a new file and
+/// `204 No Content` for a replaced one.
+async fn handle_put(
+    bucket_id: String,
+    filename: String,
+    body: Body,
+    peer: Option<String>,
+    state: Arc<ServerState>,
+) -> Result<Response, ApiError> {
+    info!(request = "dav put", bucket_id, filename);
+
+    let (store, limits, body, _permit) = {
+        state.disk.check_writable()?;
+        let permit = state.concurrency.acquire(Work::Write)?;
+        let body = state.throttle.upload(&bucket_id, body.into_data_stream());
+        (state.store.clone(), state.limits.clone(), body, permit)
+    };
+    let tmp_key = blobs::tmp_key();
+    let received = tokio::time::timeout(
+        limits.upload_timeout(),
+        app::receive_body(
+            &*store,
+            &tmp_key,
+            None,
+            limits.max_upload_size,
+            body,
+        ),
+    )
+    .await
+    .unwrap_or(Err(app::ReceiveError::Timeout));
+    let (hash, size) = match received {
+        Ok(received) => received,
+        Err(err) => {
+            let _ = store.delete(&tmp_key).await;
+
+            error!(event = "failed to upload", filename, bucket_id, error = %err);
+            return Err(err.into());
+        }
+    };
+
+    let previous = match app::get_bucket(bucket_id.clone(), state.clone()).await
+    {
+        Some(bucket) => find(&*bucket.read().await, &filename)
+            .ok()
+            .map(|(_, hash)| hash),
+        None => None,
+    };
+    if previous == Some(hash) {
+        let _ = store.delete(&tmp_key).await;
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+
+    // WebDAV clients cannot sign, owned buckets refuse their changes. Each
+    // write runs in an upload session of its own, refused while another
+    // client has one open
+    let token = match batch::open(bucket_id.clone(), state.clone()).await {
+        Ok(token) => token,
+        Err(err) => {
+            let _ = store.delete(&tmp_key).await;
+            return Err(err);
+        }
+    };
+    let file = ReceivedFile {
+        filename,
+        tmp_key: &tmp_key,
+        hash,
+        size,
+        expire_after: None,
+        session: Some(token.clone()),
+        meta: UploadMeta::default(),
+        // A PUT replaces the file of the same name
+        duplicates: Some(DuplicateNames::Overwrite),
+    };
+    let res = write(bucket_id.clone(), file, peer.clone(), state.clone()).await;
+    if let Err(err) = res {
+        let _ = batch::abort(bucket_id, &token, peer, state).await;
+        return Err(err);
+    }
+
+    Ok(match previous {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::CREATED,
+    }
+    .into_response())
+}
+
+/// Stores a file received by a PUT request in its upload session and
+/// completes the session
+async fn write(
+    bucket_id: String,
+    file: ReceivedFile<'_>,
+    peer: Option<String>,
+    state: Arc<ServerState>,
+) -> Result<(), ApiError> {
+    let signed = Signed::default();
+    let token = file.session.clone();
+    app::store_file(
+        bucket_id.clone(),
+        file,
+        &signed,
+        peer.clone(),
+        state.clone(),
+    )
+    .await?;
+    app::complete_upload(bucket_id, token.as_deref(), &signed, peer, state)
+        .await?;
+
+    Ok(())
+}
+
+/// Handles DELETE request
+async fn handle_delete(
+    bucket_id: String,
+    filename: String,
+    peer: Option<String>,
+    state: Arc<ServerState>,
+) -> Result<Response, ApiError> {
+    info!(request = "dav delete", bucket_id, filename);
+
+    let file = |bucket: &ClientBucket| find(bucket, &filename).map(|(i, _)| i);
+    let signed = Signed::default();
+    app::delete_file(bucket_id, file, &signed, peer, state).await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// Returns the index and the hash of the first file named `filename`
+fn find(
+    bucket: &ClientBucket,
+    filename: &str,
+) -> Result<(usize, [u8; 32]), ApiError> {
+    bucket
+        .files
+        .iter()
+        .enumerate()
+        .find(|(_, (_, name))| *name == filename)
+        .map(|(index, (hash, _))| (index, *hash))
+        .ok_or_else(|| ApiError::file_not_found(filename))
+}
+
+/// Returns the `href` of a file
+fn href(bucket_id: &str, filename: &str) -> String {
+    format!(
+        "/dav/{bucket_id}/{}",
+        utf8_percent_encode(filename, SEGMENT)
+    )
+}
+
+/// Returns the `207 Multi-Status` reply describing `entries`
+fn multistatus(entries: &[Entry]) -> Response {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    for entry in entries {
+        let props = match entry.file {
+            None => {
+                "<D:resourcetype><D:collection/></D:resourcetype>".to_owned()
+            }
+            Some((hash, size)) => format!(
+                "<D:resourcetype/>\
+                 <D:getcontentlength>{size}</D:getcontentlength>\
+                 <D:getcontenttype>application/octet-stream</D:getcontenttype>\
+                 <D:getetag>\"{}\"</D:getetag>",
+                hex::encode(hash)
+            ),
+        };
+        let _ = write!(
+            xml,
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop>{props}\
+             </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>\
+             </D:response>",
+            escape(&entry.href)
+        );
+        xml.push('\n');
+    }
+    xml.push_str("</D:multistatus>\n");
+
+    Response::builder()
+        .status(StatusCode::MULTI_STATUS)
+        .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+        .body(Body::from(xml))
+        .expect("valid multistatus reply")
+}
+
+/// Escapes a text of an XML element
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bearer() {
+        let basic = format!("Basic {}", BASE64_STANDARD.encode("me:key:1"));
+        assert_eq!(bearer(&basic), "Bearer key:1");
+        assert_eq!(bearer("Bearer key"), "Bearer key");
+    }
+
+    #[tokio::test]
+    async fn test_dav() {
+        use crate::blob_store::MemoryStore;
+        use crate::Config;
+        use axum::http::Request;
+        use clap::Parser;
+        use http_body_util::BodyExt;
+        use hyper_util::client::legacy::Client;
+        use hyper_util::rt::TokioExecutor;
+
+        let config = Config::parse_from(["server", "127.0.0.1:0"]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = app::run_server_with_store(config, store).await;
+        tokio::spawn(server);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let request = |method: &str, path: &str, body: &'static str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("http://{addr}{path}"))
+                .body(Body::from(body))
+                .unwrap();
+            client.request(request)
+        };
+
+        let res = request("PUT", "/dav/b/a%20b.txt", "hello").await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = request("PUT", "/dav/b/a%20b.txt", "hello!").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let res = request("PROPFIND", "/dav/b/", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::MULTI_STATUS);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<D:href>/dav/b/</D:href>"));
+        assert!(body.contains("<D:href>/dav/b/a%20b.txt</D:href>"));
+        assert!(body.contains("<D:getcontentlength>6</D:getcontentlength>"));
+
+        let res = request("GET", "/dav/b/a%20b.txt", "").await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello!");
+
+        let res = request("DELETE", "/dav/b/a%20b.txt", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = request("PROPFIND", "/dav/b/a%20b.txt", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
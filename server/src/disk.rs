@@ -0,0 +1,218 @@
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clap::Args;
+use tracing::{error, info, warn};
+
+use crate::app::ServerState;
+use crate::errors::{ApiError, ErrorCode};
+
+#[derive(Args, Clone, Debug)]
+pub(crate) struct DiskConfig {
+    /// Percentage of the disk space used above which the uploads are
+    /// refused with `507 Insufficient Storage`, downloads and proofs are
+    /// still served
+    #[arg(long, default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub disk_high_watermark: u8,
+
+    /// Percentage of the disk space used under which the uploads are
+    /// accepted again once the high watermark was reached
+    #[arg(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub disk_low_watermark: u8,
+
+    /// Seconds between two measures of the free disk space, 0 disables the
+    /// watermarks
+    #[arg(long, default_value_t = 10)]
+    pub disk_check_interval: u64,
+}
+
+/// Space of the filesystem holding a folder
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct DiskUsage {
+    pub total_bytes: u64,
+    /// Bytes the server may still write
+    pub available_bytes: u64,
+}
+
+impl DiskUsage {
+    /// Returns the percentage of the space that can no longer be written
+    pub(crate) fn used_percent(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        let used = self.total_bytes.saturating_sub(self.available_bytes);
+        used as f64 * 100.0 / self.total_bytes as f64
+    }
+}
+
+/// Returns the space of the filesystem holding `path`
+fn measure(path: &Path) -> io::Result<DiskUsage> {
+    let stat = rustix::fs::statvfs(path)?;
+    Ok(DiskUsage {
+        total_bytes: stat.f_blocks.saturating_mul(stat.f_frsize),
+        available_bytes: stat.f_bavail.saturating_mul(stat.f_frsize),
+    })
+}
+
+/// Whether the uploads are refused for lack of disk space, updated by the
+/// task of [`spawn`]
+#[derive(Default)]
+pub(crate) struct DiskStatus {
+    read_only: AtomicBool,
+    /// Usage of the fullest filesystem at the last measure
+    usage: Mutex<Option<DiskUsage>>,
+}
+
+impl DiskStatus {
+    /// Returns `507 Insufficient Storage` while the disk is over the high
+    /// watermark
+    pub(crate) fn check_writable(&self) -> Result<(), ApiError> {
+        if self.read_only.load(Ordering::Relaxed) {
+            return Err(ApiError::new(
+                ErrorCode::InsufficientStorage,
+                "the disk is nearly full, uploads are refused",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records a measure, entering the read-only mode over the high
+    /// watermark and leaving it under the low one
+    fn update(&self, usage: DiskUsage, config: &DiskConfig) {
+        *self.usage.lock().expect("unpoisoned lock") = Some(usage);
+
+        let used_percent = usage.used_percent();
+        let high = f64::from(config.disk_high_watermark);
+        let low = f64::from(
+            config.disk_low_watermark.min(config.disk_high_watermark),
+        );
+        let read_only = self.read_only.load(Ordering::Relaxed);
+        if !read_only && used_percent >= high {
+            self.read_only.store(true, Ordering::Relaxed);
+            error!(
+                event = "disk high watermark reached, refusing uploads",
+                used_percent,
+                available_bytes = usage.available_bytes
+            );
+        } else if read_only && used_percent < low {
+            self.read_only.store(false, Ordering::Relaxed);
+            info!(
+                event = "disk under low watermark, accepting uploads",
+                used_percent,
+                available_bytes = usage.available_bytes
+            );
+        }
+    }
+
+    /// Writes the disk gauges in the Prometheus text format
+    pub(crate) fn render(&self, out: &mut String) {
+        let Some(usage) = *self.usage.lock().expect("unpoisoned lock") else {
+            return;
+        };
+        let read_only = u8::from(self.read_only.load(Ordering::Relaxed));
+
+        let _ = write!(
+            out,
+            "# HELP disk_total_bytes Size of the fullest filesystem of the \
+             database and the blobs\n\
+             # TYPE disk_total_bytes gauge\n\
+             disk_total_bytes {}\n\
+             # HELP disk_available_bytes Bytes the server may still write on \
+             the fullest filesystem\n\
+             # TYPE disk_available_bytes gauge\n\
+             disk_available_bytes {}\n\
+             # HELP disk_read_only Whether the uploads are refused for lack \
+             of disk space\n\
+             # TYPE disk_read_only gauge\n\
+             disk_read_only {read_only}\n",
+            usage.total_bytes, usage.available_bytes,
+        );
+    }
+}
+
+/// Spawns the measures of the filesystems holding `dirs`, unless
+/// `--disk-check-interval` is 0
+///
+/// The fullest filesystem decides whether the uploads are refused.
+pub(crate) fn spawn(
+    state: Arc<ServerState>,
+    config: &DiskConfig,
+    dirs: Vec<String>,
+) {
+    if config.disk_check_interval == 0 {
+        return;
+    }
+    let config = config.clone();
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(
+            config.disk_check_interval,
+        ));
+
+        loop {
+            interval.tick().await;
+            let mut fullest: Option<DiskUsage> = None;
+            for dir in &dirs {
+                match measure(Path::new(dir)) {
+                    Ok(usage) => {
+                        if fullest.is_none_or(|fullest| {
+                            usage.used_percent() > fullest.used_percent()
+                        }) {
+                            fullest = Some(usage);
+                        }
+                    }
+                    Err(err) => {
+                        warn!(event = "failed to measure disk", dir, %err)
+                    }
+                }
+            }
+            if let Some(usage) = fullest {
+                state.disk.update(usage, &config);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    #[test]
+    fn test_watermarks() {
+        let config = DiskConfig {
+            disk_high_watermark: 95,
+            disk_low_watermark: 90,
+            disk_check_interval: 10,
+        };
+        let usage = |used: u64| DiskUsage {
+            total_bytes: 100,
+            available_bytes: 100 - used,
+        };
+        let status = DiskStatus::default();
+
+        status.update(usage(94), &config);
+        assert!(status.check_writable().is_ok());
+        status.update(usage(95), &config);
+        let err = status.check_writable().unwrap_err();
+        assert_eq!(err.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        // Uploads stay refused until the usage drops under the low watermark
+        status.update(usage(92), &config);
+        assert!(status.check_writable().is_err());
+        status.update(usage(89), &config);
+        assert!(status.check_writable().is_ok());
+
+        let mut out = String::new();
+        status.render(&mut out);
+        assert!(out.contains("disk_available_bytes 11\n"));
+        assert!(out.contains("disk_read_only 0\n"));
+
+        let usage = measure(Path::new(".")).unwrap();
+        assert!(usage.total_bytes >= usage.available_bytes);
+    }
+}
@@ -0,0 +1,633 @@
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use bytes::Bytes;
+use clap::Args;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::warn;
+
+use crate::blob_store::{BlobStore, BlobStream, BlobWriter, LocalStore};
+use crate::blobs;
+
+/// Size of the block of each shard in a stripe of a blob
+const BLOCK_SIZE: usize = 64 * 1024;
+/// Size of the header of a shard, the size of the blob followed by the
+/// first bytes of its SHA-256
+const HEADER_SIZE: u64 = 16;
+/// Size of the checksum preceding each block of a shard
+const CHECKSUM_SIZE: usize = 32;
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct ErasureConfig {
+    /// Folder of a shard of the blobs, one per disk (repeatable or comma
+    /// separated). Blobs are split into data and parity shards spread
+    /// across these folders instead of being stored whole in `--blobs-dir`
+    #[arg(long, value_delimiter = ',')]
+    pub erasure_dir: Vec<String>,
+
+    /// Number of parity shards among the `--erasure-dir` folders, which is
+    /// the number of folders that can be lost without losing blobs
+    #[arg(long, default_value_t = 2)]
+    pub erasure_parity: usize,
+}
+
+/// Returns the blob store of the server, erasure coded if `--erasure-dir`
+/// is set
+pub(crate) fn store(
+    blobs_dir: &str,
+    config: &ErasureConfig,
+) -> Result<Arc<dyn BlobStore>, String> {
+    if config.erasure_dir.is_empty() {
+        return Ok(Arc::new(LocalStore::new(blobs_dir)));
+    }
+    Ok(Arc::new(ErasureStore::new(blobs_dir, config)?))
+}
+
+/// Blob store splitting each blob into data and parity shards, one per
+/// folder, with Reed-Solomon codes
+///
+/// A blob is read back as long as the shards of at most `parity` folders
+/// are missing or corrupted. The blobs are cut into stripes of `BLOCK_SIZE`
+/// bytes per data shard, so they are encoded and decoded a stripe at a time.
+/// Each block of a shard is preceded by its SHA-256, a corrupted block
+/// counts as missing. The uploads in progress, under `tmp/`, are stored
+/// whole in the staging folder and encoded once staged, before the database
+/// lock is taken to reference them, see [`blobs::stage`]. The blobs
+/// stored whole there before erasure coding was enabled are still served.
+pub(crate) struct ErasureStore {
+    staging: LocalStore,
+    shards: Vec<PathBuf>,
+    coder: Arc<ReedSolomon>,
+}
+
+impl ErasureStore {
+    pub(crate) fn new<P: AsRef<Path>>(
+        staging: P,
+        config: &ErasureConfig,
+    ) -> Result<Self, String> {
+        let shards = config.erasure_dir.len();
+        if shards <= config.erasure_parity {
+            return Err(format!(
+                "{shards} erasure folders leave no data shard next to {} parity shards",
+                config.erasure_parity
+            ));
+        }
+        let coder = ReedSolomon::new(
+            shards - config.erasure_parity,
+            config.erasure_parity,
+        )
+        .map_err(|err| err.to_string())?;
+
+        Ok(ErasureStore {
+            staging: LocalStore::new(staging),
+            shards: config.erasure_dir.iter().map(PathBuf::from).collect(),
+            coder: Arc::new(coder),
+        })
+    }
+
+    /// Returns the paths of the shards of a blob, one per folder
+    fn paths(&self, key: &str) -> Vec<PathBuf> {
+        self.shards.iter().map(|dir| dir.join(key)).collect()
+    }
+
+    /// Opens the shards of a blob, `None` for the missing ones
+    ///
+    /// Returns the size of the blob along with them, read from the first
+    /// valid header.
+    async fn open(
+        &self,
+        key: &str,
+    ) -> io::Result<(u64, Vec<Option<fs::File>>)> {
+        let mut files = Vec::with_capacity(self.shards.len());
+        let mut size = None;
+        for path in self.paths(key) {
+            let Ok(mut file) = fs::File::open(&path).await else {
+                files.push(None);
+                continue;
+            };
+            if size.is_none() {
+                size = read_header(&mut file).await;
+            }
+            files.push(Some(file));
+        }
+
+        if files.iter().all(Option::is_none) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("blob {key} not found"),
+            ));
+        }
+        let size = size.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no valid shard header of blob {key}"),
+            )
+        })?;
+
+        Ok((size, files))
+    }
+
+    /// Copies a blob to another key through a stream, then deletes it
+    async fn move_blob(&self, from: &str, to: &str) -> io::Result<()> {
+        let (_, mut stream) = self.stream(from).await?;
+        let mut writer = self.create(to).await?;
+        while let Some(chunk) = stream.next().await {
+            writer.write_all(&chunk?).await?;
+        }
+        writer.flush().await?;
+
+        self.delete(from).await
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for ErasureStore {
+    async fn put(&self, key: &str, data: Bytes) -> io::Result<()> {
+        if blobs::is_tmp_key(key) {
+            return self.staging.put(key, data).await;
+        }
+
+        let mut writer = self.create(key).await?;
+        writer.write_all(&data).await?;
+        writer.flush().await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Bytes> {
+        if blobs::is_tmp_key(key) {
+            return self.staging.get(key).await;
+        }
+
+        let (size, mut stream) = self.stream(key).await?;
+        let mut data = Vec::with_capacity(size as usize);
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data.into())
+    }
+
+    async fn stream(&self, key: &str) -> io::Result<(u64, BlobStream)> {
+        if blobs::is_tmp_key(key) {
+            return self.staging.stream(key).await;
+        }
+
+        let (size, files) = match self.open(key).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return self.staging.stream(key).await
+            }
+            res => res?,
+        };
+        let (tx, rx) = mpsc::channel(2);
+        let reader = StripeReader {
+            key: key.to_owned(),
+            coder: self.coder.clone(),
+            files,
+        };
+        tokio::spawn(reader.send(size, tx));
+
+        Ok((size, Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        if blobs::is_tmp_key(key) {
+            return self.staging.delete(key).await;
+        }
+
+        for path in self.paths(key) {
+            match fs::remove_file(path).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(err)
+                }
+                _ => {}
+            }
+        }
+        self.staging.delete(key).await
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        // A blob is listed as long as one of its shards is left
+        let mut keys: BTreeSet<String> =
+            self.staging.list(prefix).await?.into_iter().collect();
+        for dir in &self.shards {
+            keys.extend(LocalStore::new(dir).list(prefix).await?);
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    async fn size(&self, key: &str) -> io::Result<Option<u64>> {
+        if blobs::is_tmp_key(key) {
+            return self.staging.size(key).await;
+        }
+
+        match self.open(key).await {
+            Ok((size, _)) => Ok(Some(size)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.staging.size(key).await
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        match (blobs::is_tmp_key(from), blobs::is_tmp_key(to)) {
+            (true, true) => self.staging.rename(from, to).await,
+            (false, false) => {
+                let mut renamed = false;
+                for (from, to) in
+                    self.paths(from).into_iter().zip(self.paths(to))
+                {
+                    if let Some(dir) = to.parent() {
+                        fs::create_dir_all(dir).await?;
+                    }
+                    match fs::rename(from, to).await {
+                        Ok(()) => renamed = true,
+                        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                            return Err(err)
+                        }
+                        Err(_) => {}
+                    }
+                }
+                if !renamed {
+                    return self.staging.rename(from, to).await;
+                }
+                Ok(())
+            }
+            // The blob is encoded, or decoded, on the way
+            _ => self.move_blob(from, to).await,
+        }
+    }
+
+    async fn create(&self, key: &str) -> io::Result<BlobWriter> {
+        if blobs::is_tmp_key(key) {
+            return self.staging.create(key).await;
+        }
+
+        let mut files = Vec::with_capacity(self.shards.len());
+        for path in self.paths(key) {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            let mut file = fs::File::create(path).await?;
+            file.write_all(&header(0)).await?;
+            files.push(file);
+        }
+
+        Ok(Box::pin(StripeWriter {
+            coder: self.coder.clone(),
+            files: Some(files),
+            pending: None,
+            buffer: Vec::new(),
+            stripe: 0,
+            size: 0,
+            dirty: false,
+        }))
+    }
+
+    async fn append(&self, key: &str) -> io::Result<BlobWriter> {
+        if blobs::is_tmp_key(key) {
+            return self.staging.append(key).await;
+        }
+        Err(unsupported("append to", key))
+    }
+
+    async fn truncate(&self, key: &str, len: u64) -> io::Result<()> {
+        if blobs::is_tmp_key(key) {
+            return self.staging.truncate(key, len).await;
+        }
+        Err(unsupported("truncate", key))
+    }
+}
+
+/// Returns the error of an operation the encoded blobs do not support
+fn unsupported(operation: &str, key: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot {operation} the erasure coded blob {key}"),
+    )
+}
+
+/// Returns the header of the shards of a blob of `size` bytes
+fn header(size: u64) -> [u8; HEADER_SIZE as usize] {
+    let size = size.to_be_bytes();
+    let mut header = [0u8; HEADER_SIZE as usize];
+    header[..8].copy_from_slice(&size);
+    header[8..].copy_from_slice(&Sha256::digest(size)[..8]);
+    header
+}
+
+/// Reads the size of the blob from the header of a shard, `None` if the
+/// header is corrupted
+async fn read_header(file: &mut fs::File) -> Option<u64> {
+    let mut buf = [0u8; HEADER_SIZE as usize];
+    file.read_exact(&mut buf).await.ok()?;
+    let size = u64::from_be_bytes(buf[..8].try_into().ok()?);
+    (header(size) == buf).then_some(size)
+}
+
+/// Returns the offset of the block of stripe `stripe` in a shard
+fn block_offset(stripe: u64) -> u64 {
+    HEADER_SIZE + stripe * (CHECKSUM_SIZE + BLOCK_SIZE) as u64
+}
+
+/// Write of stripes to the shard files, returning the files once done
+type PendingWrite =
+    Pin<Box<dyn Future<Output = io::Result<Vec<fs::File>>> + Send>>;
+
+/// Writes the stripes of a blob to its shards
+///
+/// A stripe is written once full. On flush, the last partial stripe is
+/// written padded, with the size of the blob in the headers, and written
+/// again if more data follow.
+struct StripeWriter {
+    coder: Arc<ReedSolomon>,
+    /// The shard files, held by `pending` while it writes them
+    files: Option<Vec<fs::File>>,
+    pending: Option<PendingWrite>,
+    /// Data of the current stripe
+    buffer: Vec<u8>,
+    /// Index of the current stripe
+    stripe: u64,
+    /// Size of the blob written so far
+    size: u64,
+    /// Whether data were written since the last flush
+    dirty: bool,
+}
+
+impl StripeWriter {
+    fn stripe_size(&self) -> usize {
+        self.coder.data_shard_count() * BLOCK_SIZE
+    }
+
+    /// Drives the pending write of the shards to completion
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(pending) = &mut self.pending {
+            let files = ready!(pending.as_mut().poll(cx));
+            self.pending = None;
+            self.files = Some(files?);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Starts writing `data` as stripe `stripe`, and the headers if `size`
+    /// is set
+    fn start(
+        &mut self,
+        stripe: u64,
+        data: Vec<u8>,
+        size: Option<u64>,
+    ) -> io::Result<()> {
+        let files = self.files.take().ok_or_else(|| {
+            io::Error::other("shard files lost by a failed write")
+        })?;
+        let coder = self.coder.clone();
+        self.pending =
+            Some(Box::pin(write_stripe(coder, files, stripe, data, size)));
+        Ok(())
+    }
+}
+
+/// Encodes a stripe of data and writes its blocks to the shard files
+async fn write_stripe(
+    coder: Arc<ReedSolomon>,
+    mut files: Vec<fs::File>,
+    stripe: u64,
+    data: Vec<u8>,
+    size: Option<u64>,
+) -> io::Result<Vec<fs::File>> {
+    if !data.is_empty() {
+        let mut blocks = vec![vec![0u8; BLOCK_SIZE]; coder.total_shard_count()];
+        for (block, chunk) in blocks.iter_mut().zip(data.chunks(BLOCK_SIZE)) {
+            block[..chunk.len()].copy_from_slice(chunk);
+        }
+        coder.encode(&mut blocks).map_err(io::Error::other)?;
+
+        for (file, block) in files.iter_mut().zip(&blocks) {
+            file.seek(SeekFrom::Start(block_offset(stripe))).await?;
+            file.write_all(&Sha256::digest(block)).await?;
+            file.write_all(block).await?;
+        }
+    }
+
+    if let Some(size) = size {
+        for file in &mut files {
+            file.seek(SeekFrom::Start(0)).await?;
+            file.write_all(&header(size)).await?;
+            file.flush().await?;
+        }
+    }
+
+    Ok(files)
+}
+
+impl AsyncWrite for StripeWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_pending(cx))?;
+
+        let len = buf.len().min(self.stripe_size() - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        self.size += len as u64;
+        self.dirty = true;
+
+        if self.buffer.len() == self.stripe_size() {
+            let data = std::mem::take(&mut self.buffer);
+            let stripe = self.stripe;
+            self.start(stripe, data, None)?;
+            self.stripe += 1;
+        }
+
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+
+        if self.dirty {
+            self.dirty = false;
+            let (stripe, data, size) =
+                (self.stripe, self.buffer.clone(), self.size);
+            self.start(stripe, data, Some(size))?;
+            ready!(self.poll_pending(cx))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Reads the stripes of a blob from its shards, reconstructing the missing
+/// or corrupted blocks
+struct StripeReader {
+    key: String,
+    coder: Arc<ReedSolomon>,
+    files: Vec<Option<fs::File>>,
+}
+
+impl StripeReader {
+    /// Sends the `size` bytes of the blob, a stripe at a time
+    async fn send(mut self, size: u64, tx: mpsc::Sender<io::Result<Bytes>>) {
+        let stripe_size = (self.coder.data_shard_count() * BLOCK_SIZE) as u64;
+        let mut left = size;
+        let mut stripe = 0;
+
+        while left > 0 {
+            let chunk = match self.read(stripe).await {
+                Ok(mut data) => {
+                    data.truncate(left.min(stripe_size) as usize);
+                    Ok(Bytes::from(data))
+                }
+                Err(err) => Err(err),
+            };
+
+            let failed = chunk.is_err();
+            if tx.send(chunk).await.is_err() || failed {
+                return;
+            }
+            left = left.saturating_sub(stripe_size);
+            stripe += 1;
+        }
+    }
+
+    /// Returns the data of stripe `stripe`
+    async fn read(&mut self, stripe: u64) -> io::Result<Vec<u8>> {
+        let mut blocks = Vec::with_capacity(self.files.len());
+        for (shard, file) in self.files.iter_mut().enumerate() {
+            let block = match file {
+                Some(file) => read_block(file, stripe).await,
+                None => None,
+            };
+            if block.is_none() {
+                let key = &self.key;
+                warn!(event = "erasure shard missing", key, shard, stripe);
+            }
+            blocks.push(block);
+        }
+
+        self.coder.reconstruct_data(&mut blocks).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("blob {} cannot be reconstructed: {err}", self.key),
+            )
+        })?;
+
+        Ok(blocks
+            .into_iter()
+            .take(self.coder.data_shard_count())
+            .flat_map(|block| block.unwrap_or_default())
+            .collect())
+    }
+}
+
+/// Reads the block of stripe `stripe` of a shard, `None` if it is missing or
+/// corrupted
+async fn read_block(file: &mut fs::File, stripe: u64) -> Option<Vec<u8>> {
+    file.seek(SeekFrom::Start(block_offset(stripe)))
+        .await
+        .ok()?;
+    let mut buf = vec![0u8; CHECKSUM_SIZE + BLOCK_SIZE];
+    file.read_exact(&mut buf).await.ok()?;
+
+    let block = buf.split_off(CHECKSUM_SIZE);
+    (Sha256::digest(&block)[..] == buf[..]).then_some(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[tokio::test]
+    async fn test_erasure_store() {
+        let tmp_dir = TempDir::new("test_erasure_store").unwrap();
+        let dirs: Vec<String> = (0..4)
+            .map(|i| {
+                tmp_dir
+                    .path()
+                    .join(format!("disk{i}"))
+                    .display()
+                    .to_string()
+            })
+            .collect();
+        let config = ErasureConfig {
+            erasure_dir: dirs.clone(),
+            erasure_parity: 2,
+        };
+        let store =
+            ErasureStore::new(tmp_dir.path().join("staging"), &config).unwrap();
+
+        // Three stripes, the last one partial
+        let data: Bytes = (0..5 * BLOCK_SIZE + 17)
+            .map(|i| i as u8)
+            .collect::<Vec<u8>>()
+            .into();
+        store.put("tmp/upload", data.clone()).await.unwrap();
+        store.rename("tmp/upload", "ab/blob").await.unwrap();
+        assert_eq!(
+            store.size("ab/blob").await.unwrap(),
+            Some(data.len() as u64)
+        );
+        assert_eq!(store.get("ab/blob").await.unwrap(), data);
+        assert_eq!(store.list("").await.unwrap(), ["ab/blob"]);
+
+        // Two shards lost, one of them corrupted
+        fs::remove_file(Path::new(&dirs[0]).join("ab/blob"))
+            .await
+            .unwrap();
+        let shard = Path::new(&dirs[3]).join("ab/blob");
+        let mut corrupted = fs::read(&shard).await.unwrap();
+        corrupted[HEADER_SIZE as usize + CHECKSUM_SIZE] ^= 1;
+        fs::write(&shard, corrupted).await.unwrap();
+        assert_eq!(store.get("ab/blob").await.unwrap(), data);
+
+        // Three shards lost
+        fs::remove_file(Path::new(&dirs[1]).join("ab/blob"))
+            .await
+            .unwrap();
+        assert!(store.get("ab/blob").await.is_err());
+
+        store.delete("ab/blob").await.unwrap();
+        assert_eq!(store.size("ab/blob").await.unwrap(), None);
+
+        // Flushing in the middle of a stripe
+        let mut writer = store.create("cd/blob").await.unwrap();
+        writer.write_all(b"hello ").await.unwrap();
+        writer.flush().await.unwrap();
+        writer.write_all(b"world").await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(store.get("cd/blob").await.unwrap(), "hello world");
+
+        store.put("ef/empty", Bytes::new()).await.unwrap();
+        assert_eq!(store.get("ef/empty").await.unwrap(), "");
+
+        assert!(ErasureStore::new(
+            tmp_dir.path(),
+            &ErasureConfig {
+                erasure_dir: dirs[..2].to_vec(),
+                erasure_parity: 2,
+            }
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use axum::extract::Request;
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Json, Response};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::{info, Span};
+use utoipa::ToSchema;
+
+use crate::access::AccessDenied;
+
+/// Header carrying the id of a request, set on all the replies
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Machine-readable code of an error reply
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub(crate) enum ErrorCode {
+    BadRequest,
+    /// The owner signature of a request is missing or invalid
+    InvalidSignature,
+    Unauthorized,
+    NotFound,
+    BucketNotFound,
+    FileNotFound,
+    UploadNotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    Conflict,
+    /// A chunk does not start at the offset of its upload session
+    OffsetMismatch,
+    /// Another client holds the upload session of the bucket
+    UploadInProgress,
+    /// The bucket is sealed, its uploads need a new upload session
+    BucketSealed,
+    /// Another file of the bucket has the name of an uploaded file
+    DuplicateFilename,
+    /// A replicated bucket state refers to blobs the replica lacks
+    MissingBlobs,
+    PayloadTooLarge,
+    QuotaExceeded,
+    /// The disk is over its high watermark, uploads are refused
+    InsufficientStorage,
+    RateLimited,
+    Internal,
+    BadGateway,
+    Unavailable,
+}
+
+impl ErrorCode {
+    /// Returns the status of the replies with this code, unless overridden
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidSignature | ErrorCode::Unauthorized => {
+                StatusCode::UNAUTHORIZED
+            }
+            ErrorCode::NotFound
+            | ErrorCode::BucketNotFound
+            | ErrorCode::FileNotFound
+            | ErrorCode::UploadNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            ErrorCode::Conflict
+            | ErrorCode::OffsetMismatch
+            | ErrorCode::UploadInProgress
+            | ErrorCode::BucketSealed
+            | ErrorCode::DuplicateFilename
+            | ErrorCode::MissingBlobs => StatusCode::CONFLICT,
+            ErrorCode::PayloadTooLarge | ErrorCode::QuotaExceeded => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::BadGateway => StatusCode::BAD_GATEWAY,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// Body of an error reply
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ErrorBody<'a> {
+    code: ErrorCode,
+    message: &'a str,
+    /// Id of the request, the `X-Request-Id` header of the request or a
+    /// generated one
+    request_id: &'a str,
+    /// Fields of some errors, like the `offset` of an upload session
+    #[serde(flatten)]
+    #[schema(ignore)]
+    details: &'a Map<String, Value>,
+}
+
+/// An error reply of the API, an [`ErrorBody`] with the status of the error
+///
+/// Handlers fail with it, the reply is built once the request id is known,
+/// see [`handle`].
+#[derive(Clone, Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    code: ErrorCode,
+    message: String,
+    /// Fields added to the body, like the usage of an exceeded quota
+    details: Map<String, Value>,
+    /// Seconds after which the request may be retried, the `Retry-After`
+    /// header of the reply
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub(crate) fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status: code.status(),
+            code,
+            message: message.into(),
+            details: Map::new(),
+            retry_after: None,
+        }
+    }
+
+    pub(crate) fn bad_request(message: impl Into<String>) -> Self {
+        ApiError::new(ErrorCode::BadRequest, message)
+    }
+
+    pub(crate) fn bucket_not_found(bucket_id: &str) -> Self {
+        ApiError::new(
+            ErrorCode::BucketNotFound,
+            format!("bucket {bucket_id} not found"),
+        )
+    }
+
+    pub(crate) fn file_not_found(file: impl std::fmt::Display) -> Self {
+        ApiError::new(ErrorCode::FileNotFound, format!("file {file} not found"))
+    }
+
+    /// The cause of the error is logged by the handler, not returned
+    pub(crate) fn internal() -> Self {
+        ApiError::new(ErrorCode::Internal, "internal server error")
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub(crate) fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub(crate) fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub(crate) fn with_detail(
+        mut self,
+        key: &str,
+        value: impl Serialize,
+    ) -> Self {
+        let value = serde_json::to_value(value).unwrap_or_default();
+        self.details.insert(key.to_owned(), value);
+        self
+    }
+
+    pub(crate) fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    /// Returns the reply of the error to the request `request_id`
+    pub(crate) fn reply(&self, request_id: &str) -> Response {
+        let body = ErrorBody {
+            code: self.code,
+            message: &self.message,
+            request_id,
+            details: &self.details,
+        };
+
+        let mut reply = (self.status, Json(&body)).into_response();
+        if let Ok(value) = HeaderValue::from_str(request_id) {
+            reply.headers_mut().insert(REQUEST_ID_HEADER, value);
+        }
+        if let Some(seconds) = self.retry_after {
+            reply.headers_mut().insert(RETRY_AFTER, seconds.into());
+        }
+        reply
+    }
+}
+
+/// The response of an error only carries its status and the error itself,
+/// [`handle`] replaces it with the reply of the error
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut res = self.status.into_response();
+        res.extensions_mut().insert(self);
+        res
+    }
+}
+
+impl From<&AccessDenied> for ApiError {
+    fn from(err: &AccessDenied) -> Self {
+        match err {
+            AccessDenied::Unauthorized => {
+                ApiError::new(ErrorCode::Unauthorized, "unauthorized")
+            }
+            AccessDenied::RateLimited => {
+                ApiError::new(ErrorCode::RateLimited, "rate limit exceeded")
+            }
+        }
+    }
+}
+
+impl From<AccessDenied> for ApiError {
+    fn from(err: AccessDenied) -> Self {
+        (&err).into()
+    }
+}
+
+impl IntoResponse for AccessDenied {
+    fn into_response(self) -> Response {
+        ApiError::from(self).into_response()
+    }
+}
+
+/// Returns a random request id, a UUID v4
+pub(crate) fn new_request_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Returns the id of a request, given by the client in `X-Request-Id` or
+/// generated
+fn request_id(req: &Request) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|request_id| request_id.to_str().ok())
+        .map_or_else(new_request_id, str::to_owned)
+}
+
+/// Replies to the failed requests with their [`ApiError`]
+///
+/// All the replies carry the id of the request in `X-Request-Id`. The id is
+/// recorded in the span of the request, see [`crate::telemetry::trace`], so
+/// every event logged while handling the request carries it.
+pub(crate) async fn handle(req: Request, next: Next) -> Response {
+    let request_id = request_id(&req);
+    Span::current().record("request_id", request_id.as_str());
+
+    let mut res = next.run(req).await;
+    if let Some(err) = res.extensions_mut().remove::<ApiError>() {
+        info!(
+            event = "request failed",
+            request_id,
+            code = ?err.code,
+            status = err.status().as_u16()
+        );
+        let mut reply = err.reply(&request_id);
+        // Headers set along with the error, like an authentication challenge
+        for (name, value) in res.headers() {
+            reply.headers_mut().entry(name).or_insert(value.clone());
+        }
+        return reply;
+    }
+
+    // Replies proxied from another node carry its id already
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().entry(REQUEST_ID_HEADER).or_insert(value);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::{to_bytes, Body};
+    use axum::middleware;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::extract::Path;
+
+    fn not_found() -> ApiError {
+        ApiError::new(ErrorCode::NotFound, "not found")
+    }
+
+    async fn json(res: Response) -> Value {
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_error_reply() {
+        let routes = Router::new()
+            .route(
+                "/bucket/:bucket_id",
+                get(|Path(bucket_id): Path<String>| async move {
+                    Err::<(), _>(
+                        ApiError::bucket_not_found(&bucket_id)
+                            .with_detail("bucket_id", &bucket_id),
+                    )
+                }),
+            )
+            .fallback(|| async { not_found() })
+            .layer(middleware::from_fn(handle));
+
+        let req = Request::get("/bucket/b")
+            .header(REQUEST_ID_HEADER, "abc")
+            .body(Body::empty())
+            .unwrap();
+        let res = routes.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], "abc");
+        let body = json(res).await;
+        assert_eq!(body["code"], "BUCKET_NOT_FOUND");
+        assert_eq!(body["message"], "bucket b not found");
+        assert_eq!(body["request_id"], "abc");
+        assert_eq!(body["bucket_id"], "b");
+
+        // Unmatched routes get a generated request id
+        let req = Request::get("/nothing").body(Body::empty()).unwrap();
+        let res = routes.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let request_id = res.headers()[REQUEST_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = json(res).await;
+        assert_eq!(body["code"], "NOT_FOUND");
+        assert_eq!(body["request_id"], request_id);
+        assert!(uuid::Uuid::parse_str(&request_id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_request_id_header() {
+        let routes = Router::new()
+            .route("/ok", get(|| async {}))
+            .layer(middleware::from_fn(handle));
+
+        let req = Request::get("/ok")
+            .header(REQUEST_ID_HEADER, "abc")
+            .body(Body::empty())
+            .unwrap();
+        let res = routes.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], "abc");
+
+        let req = Request::get("/ok").body(Body::empty()).unwrap();
+        let res = routes.oneshot(req).await.unwrap();
+        let request_id = res.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(request_id).is_ok());
+    }
+}
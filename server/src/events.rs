@@ -0,0 +1,180 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::response::sse::{self, KeepAlive, Sse};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{middleware, Router};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use tracing::info;
+use utoipa::OpenApi;
+
+use crate::access::{self, Operation};
+use crate::app::ServerState;
+use crate::extract::Path;
+
+/// Number of events kept for the slowest subscriber, which misses the
+/// older ones
+const EVENTS_CAPACITY: usize = 1024;
+/// Interval of the comments keeping idle streams open
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Bucket events, posted to the webhooks and streamed by `/events`
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    /// A file was uploaded, it joins the Merkle tree once completed
+    FileUploaded {
+        bucket_id: String,
+        filename: String,
+        hash: String,
+    },
+    /// The uploaded files were added to the Merkle tree of the bucket
+    UploadCompleted {
+        bucket_id: String,
+        root: Option<String>,
+        leaves_count: usize,
+    },
+    /// The Merkle root of the bucket changed
+    RootChanged {
+        bucket_id: String,
+        previous_root: Option<String>,
+        root: Option<String>,
+    },
+    /// A file was deleted, by a client or once expired
+    FileDeleted {
+        bucket_id: String,
+        filename: String,
+        hash: String,
+        expired: bool,
+    },
+}
+
+impl Event {
+    /// Returns a `root_changed` event, `None` if the root did not change
+    pub(crate) fn root_changed(
+        bucket_id: &str,
+        previous_root: Option<[u8; 32]>,
+        root: Option<[u8; 32]>,
+    ) -> Option<Self> {
+        (previous_root != root).then(|| Event::RootChanged {
+            bucket_id: bucket_id.to_owned(),
+            previous_root: previous_root.map(hex::encode),
+            root: root.map(hex::encode),
+        })
+    }
+
+    pub(crate) fn bucket_id(&self) -> &str {
+        match self {
+            Event::FileUploaded { bucket_id, .. }
+            | Event::UploadCompleted { bucket_id, .. }
+            | Event::RootChanged { bucket_id, .. }
+            | Event::FileDeleted { bucket_id, .. } => bucket_id,
+        }
+    }
+
+    /// Name of the event, as in its `event` field
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Event::FileUploaded { .. } => "file_uploaded",
+            Event::UploadCompleted { .. } => "upload_completed",
+            Event::RootChanged { .. } => "root_changed",
+            Event::FileDeleted { .. } => "file_deleted",
+        }
+    }
+}
+
+/// Events route of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_events))]
+pub(crate) struct ApiDoc;
+
+/// Returns the events route
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    // Stream of the events of a bucket
+    // GET /events/:bucket_id
+    Router::new()
+        .route("/events/:bucket_id", get(handle_events))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Read),
+            access::bucket_access,
+        ))
+        .with_state(state)
+}
+
+/// Handles events request
+///
+/// Streams the events of the bucket as server-sent events, from the time
+/// of the request. A subscriber too slow to keep up receives a `lagged`
+/// event with the number of events it missed, and should read the state of
+/// the bucket again.
+#[utoipa::path(
+    get,
+    path = "/events/{bucket_id}",
+    tag = "buckets",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Server-sent events of the bucket: `file_uploaded`, `upload_completed`, `root_changed`, `file_deleted` and `lagged`", body = String, content_type = "text/event-stream"),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_events(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> impl IntoResponse {
+    info!(request = "events", bucket_id);
+
+    let receiver = state.events.subscribe();
+    let stream =
+        BroadcastStream::new(receiver).filter_map(move |event| match event {
+            Ok(event) if event.bucket_id() == bucket_id => Some(
+                sse::Event::default().event(event.name()).json_data(&event),
+            ),
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                Some(Ok(sse::Event::default()
+                    .event("lagged")
+                    .data(missed.to_string())))
+            }
+        });
+
+    Sse::new(stream).keep_alive(KeepAlive::new().interval(KEEP_ALIVE))
+}
+
+/// Returns the sender of the events, without subscribers
+pub(crate) fn channel() -> broadcast::Sender<Event> {
+    broadcast::channel(EVENTS_CAPACITY).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let root = Some([1u8; 32]);
+        assert!(Event::root_changed("bucket", root, root).is_none());
+
+        let event = Event::root_changed("bucket", None, root).unwrap();
+        assert_eq!(event.bucket_id(), "bucket");
+        let payload = serde_json::to_value(&event).unwrap();
+        assert_eq!(payload["event"], event.name());
+        assert_eq!(payload["bucket_id"], "bucket");
+        assert_eq!(payload["previous_root"], serde_json::Value::Null);
+        assert_eq!(payload["root"], hex::encode([1u8; 32]));
+
+        let event = Event::FileUploaded {
+            bucket_id: "bucket".to_owned(),
+            filename: "a.txt".to_owned(),
+            hash: hex::encode([2u8; 32]),
+        };
+        let payload = serde_json::to_value(&event).unwrap();
+        assert_eq!(payload["event"], event.name());
+        assert_eq!(payload["filename"], "a.txt");
+    }
+}
@@ -0,0 +1,927 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{middleware, Router};
+use bytes::{Buf, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{error, info, Instrument};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::access::{self, Operation};
+use crate::app::{self, ReceivedFile, ServerState};
+use crate::attestation;
+use crate::audit::Peer;
+use crate::batch;
+use crate::blob_store::BlobStore;
+use crate::blobs;
+use crate::client_bucket::BucketRoot;
+use crate::errors::{ApiError, ErrorBody, ErrorCode};
+use crate::extract::{Json, Path};
+use crate::file_meta::UploadMeta;
+use crate::limits::Work;
+use crate::naming::DuplicateNames;
+use crate::ownership::Signed;
+
+/// Size of the blocks of a tar archive
+const BLOCK_SIZE: usize = 512;
+/// Path of the manifest in the archive
+const MANIFEST_PATH: &str = "manifest.json";
+/// Largest manifest read by an import, about 200 000 files
+const MAX_MANIFEST_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Manifest of an exported bucket, the first entry of its archive
+#[derive(Serialize, ToSchema)]
+struct Manifest {
+    bucket_id: String,
+    /// Root of the bucket, attested if it is the last recorded root
+    #[serde(flatten)]
+    root: BucketRoot,
+    /// Files covered by the root, in the order of the leaves
+    files: Vec<ManifestEntry>,
+}
+
+/// A file of an exported bucket
+#[derive(Serialize, ToSchema)]
+struct ManifestEntry {
+    index: usize,
+    filename: String,
+    /// Hex encoded hash of the file, its leaf in the Merkle tree
+    hash: String,
+    /// Path of the content of the file in the archive, `blobs/<hash>`
+    path: String,
+    #[serde(skip)]
+    leaf: [u8; 32],
+}
+
+/// The fields of a manifest read back by an import
+#[derive(Deserialize)]
+struct ImportedManifest {
+    root: Option<String>,
+    files: Vec<ImportedEntry>,
+}
+
+#[derive(Deserialize)]
+struct ImportedEntry {
+    filename: String,
+    hash: String,
+    path: String,
+}
+
+/// A file of an imported archive, received in a temporary blob
+struct ImportedFile {
+    filename: String,
+    hash: [u8; 32],
+    size: u64,
+    tmp_key: String,
+}
+
+/// Bucket export and import routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_export, handle_import), components(schemas(Manifest)))]
+pub(crate) struct ApiDoc;
+
+/// Returns the bucket export and import routes
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    // Export a bucket as a tar archive
+    // GET /export/:bucket_id
+    let export = Router::new()
+        .route("/export/:bucket_id", get(handle_export))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Read),
+            access::bucket_access,
+        ));
+
+    // Import a tar archive made by an export into an empty bucket
+    // POST /import/:bucket_id
+    let import = Router::new()
+        .route("/import/:bucket_id", post(handle_import))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Write),
+            access::bucket_access,
+        ));
+
+    export.merge(import).with_state(state)
+}
+
+/// Handles bucket export request
+///
+/// Streams a tar archive of the files covered by the Merkle root of the
+/// bucket, as stored (encrypted by the client), after a `manifest.json`
+/// giving the root and the name and hash of each file. The root is attested
+/// if it is the last recorded one, so the archive is verified offline by
+/// hashing the files and rebuilding the tree from the hashes in the order
+/// of the manifest. The files uploaded since the last `complete_upload` are
+/// left out. The archive is cut short, and fails to be read, if a file is
+/// deleted while it is streamed.
+#[utoipa::path(
+    get,
+    path = "/export/{bucket_id}",
+    tag = "buckets",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Tar archive of the manifest and the files", body = Vec<u8>, content_type = "application/x-tar"),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 404, description = "The bucket does not exist", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_export(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let bucket = app::get_bucket(bucket_id.clone(), state.clone())
+        .await
+        .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+
+    let manifest = {
+        let bucket = bucket.read().await;
+        let root = attestation::attested_root(&state, &bucket).await;
+        let files = bucket
+            .merkle_tree
+            .leaves()
+            .into_iter()
+            .enumerate()
+            .map(|(index, hash)| ManifestEntry {
+                index,
+                filename: bucket.files.get(&hash).cloned().unwrap_or_default(),
+                hash: hex::encode(hash),
+                path: format!("blobs/{}", hex::encode(hash)),
+                leaf: hash,
+            })
+            .collect();
+        Manifest {
+            bucket_id: bucket_id.clone(),
+            root,
+            files,
+        }
+    };
+
+    info!(
+        request = "export",
+        bucket_id,
+        files_count = manifest.files.len()
+    );
+
+    // The files are streamed one after the other without holding the
+    // bucket lock
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(
+        async move {
+            if let Err(err) = write_archive(&sender, &manifest, &state).await {
+                error!(event = "failed to export bucket", %err);
+                // The error aborts the body
+                let _ = sender.send(Err(err)).await;
+            }
+        }
+        .in_current_span(),
+    );
+
+    Ok((
+        [
+            (CONTENT_TYPE, "application/x-tar".to_owned()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{bucket_id}.tar\""),
+            ),
+        ],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    )
+        .into_response())
+}
+
+/// Sends the manifest and the files of a bucket as tar entries
+async fn write_archive(
+    sender: &mpsc::Sender<io::Result<Bytes>>,
+    manifest: &Manifest,
+    state: &ServerState,
+) -> io::Result<()> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let json = serde_json::to_vec_pretty(manifest).map_err(io::Error::other)?;
+    send(sender, header(MANIFEST_PATH, json.len() as u64, mtime)?).await?;
+    let size = json.len() as u64;
+    send(sender, Bytes::from(json)).await?;
+    send(sender, padding(size)).await?;
+
+    for file in &manifest.files {
+        let (size, mut stream) = {
+            let db = state.db.read().await;
+            let (size, stream) =
+                blobs::stream(&*state.store, &db, &file.leaf).await?;
+            (size, state.throttle.download(&manifest.bucket_id, stream))
+        };
+
+        send(sender, header(&file.path, size, mtime)?).await?;
+        let mut sent = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            sent += chunk.len() as u64;
+            send(sender, chunk).await?;
+        }
+        if sent != size {
+            return Err(io::Error::other(format!(
+                "blob {} changed while exported",
+                file.hash
+            )));
+        }
+        send(sender, padding(size)).await?;
+    }
+
+    // The archive ends with two empty blocks
+    send(sender, Bytes::from_static(&[0; 2 * BLOCK_SIZE])).await
+}
+
+/// Sends a part of the archive
+async fn send(
+    sender: &mpsc::Sender<io::Result<Bytes>>,
+    data: Bytes,
+) -> io::Result<()> {
+    sender.send(Ok(data)).await.map_err(io::Error::other)
+}
+
+/// Returns the ustar header of a regular file of `size` bytes
+fn header(path: &str, size: u64, mtime: u64) -> io::Result<Bytes> {
+    let mut header = [0u8; BLOCK_SIZE];
+    if path.len() >= 100 {
+        return Err(io::Error::other(format!("path too long: {path}")));
+    }
+    header[..path.len()].copy_from_slice(path.as_bytes());
+    numeric(&mut header[100..108], 0o644);
+    numeric(&mut header[108..116], 0);
+    numeric(&mut header[116..124], 0);
+    numeric(&mut header[124..136], size);
+    numeric(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|byte| u64::from(*byte)).sum();
+    numeric(&mut header[148..155], checksum);
+
+    Ok(Bytes::copy_from_slice(&header))
+}
+
+/// Writes `value` in a numeric field of a header, as NUL terminated octal
+/// digits, or in base-256 if it is too large, as GNU tar does for the
+/// files over 8 GiB
+fn numeric(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if value < 1 << (3 * digits) {
+        let octal = format!("{value:0digits$o}");
+        field[..digits].copy_from_slice(octal.as_bytes());
+        field[digits] = 0;
+    } else {
+        field.fill(0);
+        let len = field.len();
+        field[len - 8..].copy_from_slice(&value.to_be_bytes());
+        field[0] = 0x80;
+    }
+}
+
+/// Returns the zeros completing the last block of an entry of `size` bytes
+fn padding(size: u64) -> Bytes {
+    Bytes::from(vec![0; padding_len(size)])
+}
+
+fn padding_len(size: u64) -> usize {
+    let rest = size as usize % BLOCK_SIZE;
+    if rest == 0 {
+        0
+    } else {
+        BLOCK_SIZE - rest
+    }
+}
+
+/// Handles bucket import request
+///
+/// Reads an archive made by `/export`: the manifest first, then the
+/// content of each of its files. The root of the manifest must be the root
+/// of the tree of its hashes, in their order, and each file must hash to its
+/// entry, otherwise nothing is stored and `400 Bad Request` is returned. The
+/// bucket must be empty. The files are stored in an upload session opened
+/// before the archive is received and completed once they are all stored,
+/// so the bucket ends up with the root of the manifest, attested by this
+/// server, or is left unchanged. Files of the same name are
+/// suffixed, as the naming policy `suffix` does. The import is unsigned,
+/// owned buckets refuse it. The archive must be received within
+/// `--upload-timeout`, otherwise `408 Request Timeout` is returned.
+#[utoipa::path(
+    post,
+    path = "/import/{bucket_id}",
+    tag = "buckets",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    request_body(content = Vec<u8>, description = "Tar archive made by an export", content_type = "application/x-tar"),
+    responses(
+        (status = 200, description = "Root of the imported bucket", body = BucketRoot),
+        (status = 400, description = "Invalid archive, or files not matching the manifest", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 408, description = "The archive was not received in time", body = ErrorBody),
+        (status = 409, description = "The bucket is not empty, or another upload session of the bucket is open", body = ErrorBody),
+        (status = 413, description = "A file exceeds the maximum upload size", body = ErrorBody),
+        (status = 507, description = "The disk is nearly full", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_import(
+    Path(bucket_id): Path<String>,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+    body: Body,
+) -> Result<Json<BucketRoot>, ApiError> {
+    info!(request = "import", bucket_id);
+
+    // The session is opened first, so no other upload lands in the bucket
+    // while the archive is received and a failed import leaves it unchanged
+    let token = batch::open(bucket_id.clone(), state.clone()).await?;
+    let abort = |err: ApiError| {
+        let (bucket_id, token) = (bucket_id.clone(), token.clone());
+        let (peer, state) = (peer.clone(), state.clone());
+        async move {
+            let _ = batch::abort(bucket_id, &token, peer, state).await;
+            err
+        }
+    };
+
+    if let Some(bucket) =
+        app::get_bucket(bucket_id.clone(), state.clone()).await
+    {
+        if !bucket.read().await.files.is_empty() {
+            let err =
+                ApiError::new(ErrorCode::Conflict, "the bucket is not empty");
+            return Err(abort(err).await);
+        }
+    }
+
+    let prepared = {
+        state.disk.check_writable().and_then(|_| {
+            let permit = state.concurrency.acquire(Work::Write)?;
+            let body =
+                state.throttle.upload(&bucket_id, body.into_data_stream());
+            Ok((state.store.clone(), state.limits.clone(), body, permit))
+        })
+    };
+    let (store, limits, body, _permit) = match prepared {
+        Ok(prepared) => prepared,
+        Err(err) => return Err(abort(err).await),
+    };
+
+    let mut tmp_keys = Vec::new();
+    let mut reader = TarReader::new(body);
+    let received = tokio::time::timeout(
+        limits.upload_timeout(),
+        read_archive(
+            &*store,
+            limits.max_upload_size,
+            &mut reader,
+            &mut tmp_keys,
+        ),
+    )
+    .await
+    .unwrap_or_else(|_| {
+        Err(ApiError::new(ErrorCode::RequestTimeout, "upload timed out"))
+    });
+    let (root, files) = match received {
+        Ok(received) => received,
+        Err(err) => {
+            for tmp_key in &tmp_keys {
+                let _ = store.delete(tmp_key).await;
+            }
+            error!(
+                event = "failed to import",
+                bucket_id,
+                error = err.message()
+            );
+            return Err(abort(err).await);
+        }
+    };
+
+    let signed = Signed::default();
+    let files_count = files.len();
+    for file in files {
+        let file = ReceivedFile {
+            filename: file.filename,
+            tmp_key: &file.tmp_key,
+            hash: file.hash,
+            size: file.size,
+            expire_after: None,
+            session: Some(token.clone()),
+            meta: UploadMeta::default(),
+            duplicates: Some(DuplicateNames::Suffix),
+        };
+        let stored = app::store_file(
+            bucket_id.clone(),
+            file,
+            &signed,
+            peer.clone(),
+            state.clone(),
+        )
+        .await;
+        if let Err(err) = stored {
+            // The stored files left their temporary blobs
+            for tmp_key in &tmp_keys {
+                let _ = store.delete(tmp_key).await;
+            }
+            return Err(abort(err).await);
+        }
+    }
+
+    let completed = app::complete_upload(
+        bucket_id.clone(),
+        Some(&token),
+        &signed,
+        peer.clone(),
+        state.clone(),
+    )
+    .await;
+    let bucket_root = match completed {
+        Ok(bucket_root) => bucket_root,
+        Err(err) => return Err(abort(err).await),
+    };
+    if bucket_root.root != root {
+        error!(
+            event = "imported root mismatch",
+            bucket_id,
+            expected = ?root,
+            root = ?bucket_root.root
+        );
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            "the bucket changed during the import",
+        ));
+    }
+
+    info!(event = "bucket imported", bucket_id, files_count);
+    Ok(Json(bucket_root))
+}
+
+/// Reads the manifest and the files of an archive, the files in temporary
+/// blobs whose keys are pushed to `tmp_keys`
+///
+/// Returns the root of the manifest and the files in the order of the
+/// manifest.
+async fn read_archive<S, B>(
+    store: &dyn BlobStore,
+    max_size: Option<u64>,
+    reader: &mut TarReader<S>,
+    tmp_keys: &mut Vec<String>,
+) -> Result<(Option<String>, Vec<ImportedFile>), ApiError>
+where
+    S: Stream<Item = Result<B, axum::Error>> + Unpin,
+    B: Buf,
+{
+    let manifest = match reader.next_entry().await? {
+        Some((path, size)) if path == MANIFEST_PATH => {
+            if size > MAX_MANIFEST_SIZE {
+                return Err(ApiError::new(
+                    ErrorCode::PayloadTooLarge,
+                    "manifest too large",
+                ));
+            }
+            let json = reader.take(size as usize).await?;
+            reader.take(padding_len(size)).await?;
+            serde_json::from_slice::<ImportedManifest>(&json)
+                .map_err(|_| ApiError::bad_request("invalid manifest"))?
+        }
+        _ => {
+            return Err(ApiError::bad_request(format!(
+                "the archive does not start with {MANIFEST_PATH}"
+            )))
+        }
+    };
+
+    // The bucket rebuilds its tree from the hashes in increasing order
+    let mut leaves = Vec::with_capacity(manifest.files.len());
+    for file in &manifest.files {
+        let hash = hex::decode(&file.hash)
+            .ok()
+            .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+            .ok_or_else(|| {
+                ApiError::bad_request("invalid hash in the manifest")
+                    .with_detail("hash", &file.hash)
+            })?;
+        if leaves.last().is_some_and(|last| *last >= hash) {
+            return Err(ApiError::bad_request(
+                "the files of the manifest are not in the order of the leaves",
+            ));
+        }
+        leaves.push(hash);
+    }
+    let computed = merkle::tree::Tree::build_from_leaves(leaves.clone())
+        .root_hash()
+        .map(hex::encode);
+    if computed != manifest.root {
+        return Err(ApiError::bad_request(
+            "the root of the manifest does not match its files",
+        )
+        .with_detail("root", &manifest.root)
+        .with_detail("computed_root", computed));
+    }
+
+    let mut pending: HashMap<String, usize> = HashMap::new();
+    for (index, file) in manifest.files.iter().enumerate() {
+        if pending.insert(file.path.clone(), index).is_some() {
+            return Err(ApiError::bad_request(
+                "the manifest names a path twice",
+            )
+            .with_detail("path", &file.path));
+        }
+    }
+    let mut received: Vec<Option<ImportedFile>> =
+        manifest.files.iter().map(|_| None).collect();
+
+    while let Some((path, size)) = reader.next_entry().await? {
+        let index = pending.remove(&path).ok_or_else(|| {
+            ApiError::bad_request("unexpected entry in the archive")
+                .with_detail("path", &path)
+        })?;
+        if max_size.is_some_and(|max| size > max) {
+            return Err(ApiError::new(
+                ErrorCode::PayloadTooLarge,
+                "file too large",
+            )
+            .with_detail("path", &path));
+        }
+
+        let tmp_key = blobs::tmp_key();
+        tmp_keys.push(tmp_key.clone());
+        let hash = reader.copy(size, store, &tmp_key).await?;
+        if hash != leaves[index] {
+            return Err(ApiError::bad_request(
+                "the content of a file does not match its hash",
+            )
+            .with_detail("path", &path));
+        }
+        received[index] = Some(ImportedFile {
+            filename: manifest.files[index].filename.clone(),
+            hash,
+            size,
+            tmp_key,
+        });
+    }
+
+    if let Some(path) = pending.into_keys().next() {
+        return Err(ApiError::bad_request("missing file in the archive")
+            .with_detail("path", path));
+    }
+    let files: Vec<ImportedFile> = received.into_iter().flatten().collect();
+    if files.len() != manifest.files.len() {
+        return Err(ApiError::bad_request("missing file in the archive"));
+    }
+    Ok((manifest.root, files))
+}
+
+/// Reads the entries of a tar archive from a request body
+struct TarReader<S> {
+    body: S,
+    buffer: BytesMut,
+}
+
+impl<S, B> TarReader<S>
+where
+    S: Stream<Item = Result<B, axum::Error>> + Unpin,
+    B: Buf,
+{
+    fn new(body: S) -> Self {
+        TarReader {
+            body,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Receives a chunk of the body, false at its end
+    async fn receive(&mut self) -> Result<bool, ApiError> {
+        match self.body.next().await {
+            Some(Ok(mut chunk)) => {
+                while chunk.has_remaining() {
+                    self.buffer.extend_from_slice(chunk.chunk());
+                    chunk.advance(chunk.chunk().len());
+                }
+                Ok(true)
+            }
+            Some(Err(_)) => {
+                Err(ApiError::bad_request("failed to receive body"))
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the next `len` bytes of the archive
+    async fn take(&mut self, len: usize) -> Result<Bytes, ApiError> {
+        while self.buffer.len() < len {
+            if !self.receive().await? {
+                return Err(ApiError::bad_request("truncated archive"));
+            }
+        }
+        Ok(self.buffer.split_to(len).freeze())
+    }
+
+    /// Returns the path and the size of the next regular file, `None` at
+    /// the end of the archive
+    ///
+    /// The other entries, directories or extended headers added by another
+    /// tool, are skipped.
+    async fn next_entry(&mut self) -> Result<Option<(String, u64)>, ApiError> {
+        loop {
+            let header = self.take(BLOCK_SIZE).await?;
+            if header.iter().all(|byte| *byte == 0) {
+                return Ok(None);
+            }
+
+            let invalid = || ApiError::bad_request("invalid tar header");
+            let checksum =
+                parse_numeric(&header[148..156]).ok_or_else(invalid)?;
+            let sum: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| match i {
+                    148..=155 => u64::from(b' '),
+                    _ => u64::from(*byte),
+                })
+                .sum();
+            if checksum != sum {
+                return Err(invalid());
+            }
+            let size = parse_numeric(&header[124..136]).ok_or_else(invalid)?;
+
+            if !matches!(header[156], b'0' | 0) {
+                self.skip(size + padding_len(size) as u64).await?;
+                continue;
+            }
+
+            let name = text(&header[..100]);
+            let prefix = text(&header[345..500]);
+            let path = if header[257..262] == *b"ustar" && !prefix.is_empty() {
+                format!("{prefix}/{name}")
+            } else {
+                name
+            };
+            return Ok(Some((path, size)));
+        }
+    }
+
+    /// Discards the next `len` bytes of the archive as they are received
+    async fn skip(&mut self, mut len: u64) -> Result<(), ApiError> {
+        loop {
+            let buffered = usize::try_from(len)
+                .map_or(self.buffer.len(), |len| len.min(self.buffer.len()));
+            self.buffer.advance(buffered);
+            len -= buffered as u64;
+            if len == 0 {
+                return Ok(());
+            }
+            if !self.receive().await? {
+                return Err(ApiError::bad_request("truncated archive"));
+            }
+        }
+    }
+
+    /// Writes the `size` bytes of the current entry to a new blob at `key`
+    /// and returns their hash
+    async fn copy(
+        &mut self,
+        size: u64,
+        store: &dyn BlobStore,
+        key: &str,
+    ) -> Result<[u8; 32], ApiError> {
+        let mut file =
+            store.create(key).await.map_err(|_| ApiError::internal())?;
+        let mut hasher = Sha256::new();
+        let mut remaining = size;
+
+        while remaining > 0 {
+            if self.buffer.is_empty() && !self.receive().await? {
+                return Err(ApiError::bad_request("truncated archive"));
+            }
+            let len = self.buffer.len().min(remaining as usize);
+            let data = self.buffer.split_to(len);
+            hasher.update(&data);
+            file.write_all(&data)
+                .await
+                .map_err(|_| ApiError::internal())?;
+            remaining -= len as u64;
+        }
+        file.flush().await.map_err(|_| ApiError::internal())?;
+        self.take(padding_len(size)).await?;
+
+        Ok(hasher.finalize().into())
+    }
+}
+
+/// Reads a numeric field of a header, octal digits or base-256
+fn parse_numeric(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let (high, low) = field.split_at(field.len() - 8);
+        if high[0] != 0x80 || high[1..].iter().any(|byte| *byte != 0) {
+            return None;
+        }
+        return Some(u64::from_be_bytes(low.try_into().ok()?));
+    }
+    let digits = text(field);
+    let digits = digits.trim_matches(' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// Returns the NUL terminated text of a header field
+fn text(field: &[u8]) -> String {
+    let end = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use crate::Config;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use clap::Parser;
+    use http_body_util::BodyExt;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use sha2::{Digest, Sha256};
+
+    /// Returns the entries of a tar archive by path
+    fn entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while archive[offset..offset + BLOCK_SIZE].iter().any(|b| *b != 0) {
+            let header = &archive[offset..offset + BLOCK_SIZE];
+            let path = String::from_utf8(
+                header[..100].split(|b| *b == 0).next().unwrap().to_vec(),
+            )
+            .unwrap();
+            let size = std::str::from_utf8(&header[124..135]).unwrap();
+            let size = usize::from_str_radix(size, 8).unwrap();
+
+            let checksum = std::str::from_utf8(&header[148..154]).unwrap();
+            let mut blank = header.to_vec();
+            blank[148..156].fill(b' ');
+            let sum: u64 = blank.iter().map(|b| u64::from(*b)).sum();
+            assert_eq!(u64::from_str_radix(checksum, 8).unwrap(), sum);
+
+            offset += BLOCK_SIZE;
+            entries.push((path, archive[offset..offset + size].to_vec()));
+            offset += size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        }
+        assert_eq!(archive.len(), offset + 2 * BLOCK_SIZE);
+        entries
+    }
+
+    /// Returns a tar archive of `entries`
+    fn tar(entries: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for (path, content) in entries {
+            let size = content.len() as u64;
+            archive.extend_from_slice(&header(path, size, 0).unwrap());
+            archive.extend_from_slice(content);
+            archive.extend_from_slice(&padding(size));
+        }
+        archive.extend_from_slice(&[0; 2 * BLOCK_SIZE]);
+        archive
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let config = Config::parse_from(["server", "127.0.0.1:0"]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = app::run_server_with_store(config, store).await;
+        tokio::spawn(server);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let request = |method: &str, path: &str, body: Vec<u8>| {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("http://{addr}{path}"))
+                .body(Body::from(body))
+                .unwrap();
+            client.request(request)
+        };
+
+        let large = vec![7u8; 100 * 1024];
+        for (name, content) in [("a.txt", b"a".to_vec()), ("b", large)] {
+            let path = format!("/upload_file/b/{name}");
+            let res = request("POST", &path, content).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        request("POST", "/complete_upload/b", vec![]).await.unwrap();
+
+        let res = request("GET", "/export/b", vec![]).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/x-tar");
+        let archive = res.into_body().collect().await.unwrap().to_bytes();
+
+        let entries = entries(&archive);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].0, MANIFEST_PATH);
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&entries[0].1).unwrap();
+        let res = request("GET", "/root/b", vec![]).await.unwrap();
+        let root: serde_json::Value = serde_json::from_slice(
+            &res.into_body().collect().await.unwrap().to_bytes(),
+        )
+        .unwrap();
+        assert_eq!(manifest["bucket_id"], "b");
+        assert_eq!(manifest["root"], root["root"]);
+
+        // Each file is the blob of its hash
+        let files = manifest["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        for (file, (path, content)) in files.iter().zip(&entries[1..]) {
+            assert_eq!(file["path"], path.as_str());
+            assert_eq!(file["hash"], hex::encode(Sha256::digest(content)));
+        }
+        assert!(files.iter().any(|file| file["filename"] == "a.txt"));
+
+        let res = request("GET", "/export/missing", vec![]).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // The archive restores the bucket with the same root
+        let res = request("POST", "/import/c", archive.to_vec())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let imported: serde_json::Value = serde_json::from_slice(
+            &res.into_body().collect().await.unwrap().to_bytes(),
+        )
+        .unwrap();
+        assert_eq!(imported["root"], root["root"]);
+        let res = request("POST", "/import/c", archive.to_vec())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // A file not matching its hash is refused, nothing is stored
+        let mut tampered = entries.clone();
+        tampered[1].1[0] ^= 1;
+        let res = request("POST", "/import/d", tar(&tampered)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = request("GET", "/list/d", vec![]).await.unwrap();
+        let list = res.into_body().collect().await.unwrap().to_bytes();
+        assert!(!String::from_utf8_lossy(&list).contains("a.txt"));
+
+        // So is a manifest naming a path twice, one of its files would be
+        // left out
+        let mut twice = manifest.clone();
+        twice["files"][1]["path"] = twice["files"][0]["path"].clone();
+        let mut tampered = entries.clone();
+        tampered[0].1 = serde_json::to_vec(&twice).unwrap();
+        let res = request("POST", "/import/d", tar(&tampered)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // So is a manifest whose root does not match its files
+        let mut manifest = manifest.clone();
+        manifest["root"] = serde_json::Value::String(hex::encode([0u8; 32]));
+        let mut tampered = entries.clone();
+        tampered[0].1 = serde_json::to_vec(&manifest).unwrap();
+        let res = request("POST", "/import/d", tar(&tampered)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // As is a file left out of the archive
+        let res = request("POST", "/import/d", tar(&entries[..2]))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // A bucket with an open upload session is not imported into
+        let res = request("POST", "/begin_upload/f", vec![]).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("POST", "/import/f", tar(&entries)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = request("POST", "/import/d", tar(&entries)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Extended headers of other tools are skipped
+        let mut pax = header("PaxHeaders/a", 29, 0).unwrap().to_vec();
+        pax[156] = b'x';
+        pax[148..156].fill(b' ');
+        let checksum: u64 = pax.iter().map(|byte| u64::from(*byte)).sum();
+        numeric(&mut pax[148..155], checksum);
+        pax.extend_from_slice(b"29 path=PaxHeaders/a/ignored\n");
+        pax.extend_from_slice(&padding(29));
+        pax.extend_from_slice(&tar(&entries));
+        let res = request("POST", "/import/e", pax).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,141 @@
+use std::str::FromStr;
+
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::rejection::BytesRejection;
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::errors::{ApiError, ErrorCode};
+
+/// Path parameters of a request, a request whose parameters do not parse
+/// is not found
+pub(crate) struct Path<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Self, ApiError> {
+        match axum::extract::Path::from_request_parts(parts, state).await {
+            Ok(axum::extract::Path(params)) => Ok(Path(params)),
+            Err(_) => Err(ApiError::new(ErrorCode::NotFound, "not found")),
+        }
+    }
+}
+
+/// Query of a request, refused with `400 Bad Request` if it does not parse
+pub(crate) struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Self, ApiError> {
+        match axum::extract::Query::from_request_parts(parts, state).await {
+            Ok(axum::extract::Query(query)) => Ok(Query(query)),
+            Err(err) => Err(ApiError::bad_request(err.body_text())),
+        }
+    }
+}
+
+/// JSON body of a request, or JSON reply
+///
+/// Unlike [`axum::Json`], a body without `Content-Type` is accepted, as the
+/// client does not set it. Other content types are refused with
+/// `415 Unsupported Media Type`, and bodies over the
+/// [`axum::extract::DefaultBodyLimit`] of the route with
+/// `413 Payload Too Large`.
+pub(crate) struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, ApiError> {
+        let json = match req.headers().get(CONTENT_TYPE) {
+            None => true,
+            Some(content_type) => content_type
+                .to_str()
+                .is_ok_and(|content_type| content_type.contains("json")),
+        };
+        if !json {
+            return Err(ApiError::bad_request("unsupported media type")
+                .with_status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+        }
+
+        let body = Bytes::from_request(req, state).await.map_err(
+            |err: BytesRejection| match err.status() {
+                StatusCode::PAYLOAD_TOO_LARGE => ApiError::new(
+                    ErrorCode::PayloadTooLarge,
+                    "payload too large",
+                ),
+                _ => ApiError::bad_request(err.body_text()),
+            },
+        )?;
+        serde_json::from_slice(&body).map(Json).map_err(|err| {
+            ApiError::bad_request(format!(
+                "request body deserialize error: {err}"
+            ))
+        })
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// Returns the value of the header `name`, refusing the request with
+/// `400 Bad Request` if it is missing or does not parse
+pub(crate) fn header<T: FromStr>(
+    headers: &HeaderMap,
+    name: &str,
+) -> Result<T, ApiError> {
+    optional_header(headers, name)?.ok_or_else(|| {
+        ApiError::bad_request(format!("missing request header {name:?}"))
+    })
+}
+
+/// Returns the value of the header `name`, if any, refusing the request with
+/// `400 Bad Request` if it does not parse
+pub(crate) fn optional_header<T: FromStr>(
+    headers: &HeaderMap,
+    name: &str,
+) -> Result<Option<T>, ApiError> {
+    let Some(value) = headers.get(name) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Some)
+        .ok_or_else(|| {
+            ApiError::bad_request(format!("invalid request header {name:?}"))
+        })
+}
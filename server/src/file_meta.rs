@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::async_trait;
+use axum::extract::{FromRequestParts, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use axum::routing::get;
+use axum::{middleware, Router};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::access::{self, Operation};
+use crate::app::{get_bucket, ServerState};
+use crate::blobs;
+use crate::database::DB;
+use crate::errors::{ApiError, ErrorBody};
+use crate::extract::{Json, Path};
+use crate::limits;
+
+/// Prefix of the upload headers carrying client metadata, `X-Meta-<key>`
+const METADATA_HEADER_PREFIX: &str = "x-meta-";
+
+/// Maximum size of the client metadata of a file, keys and values included
+const MAX_METADATA_SIZE: usize = 2048;
+
+/// Header carrying the tags of an upload, comma separated
+const TAGS_HEADER: &str = "x-tags";
+/// Maximum number of tags of a file
+const MAX_TAGS: usize = 16;
+/// Maximum length of a tag
+const MAX_TAG_LEN: usize = 64;
+
+/// Metadata of a file, recorded on upload
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FileMeta {
+    pub size: u64,
+    /// Unix time in seconds
+    pub uploaded_at: u64,
+    pub content_type: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub tags: Vec<String>,
+}
+
+impl FileMeta {
+    /// Returns the metadata of a file of `size` bytes uploaded now
+    pub(crate) fn new(size: u64, upload: UploadMeta) -> Self {
+        FileMeta {
+            size,
+            uploaded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            content_type: upload.content_type,
+            metadata: upload.metadata,
+            tags: upload.tags,
+        }
+    }
+}
+
+/// Returns whether a file of a bucket was uploaded with `tag`
+pub(crate) fn has_tag(
+    db: &DB,
+    bucket_id: &str,
+    hash: &[u8; 32],
+    tag: &str,
+) -> bool {
+    db.file_meta(bucket_id, hash)
+        .ok()
+        .flatten()
+        .is_some_and(|meta| meta.tags.iter().any(|t| t == tag))
+}
+
+/// Returns whether the value of the client metadata `key` of a file of a
+/// bucket starts with `prefix`
+pub(crate) fn metadata_starts_with(
+    db: &DB,
+    bucket_id: &str,
+    hash: &[u8; 32],
+    key: &str,
+    prefix: &str,
+) -> bool {
+    db.file_meta(bucket_id, hash)
+        .ok()
+        .flatten()
+        .is_some_and(|meta| {
+            meta.metadata
+                .get(key)
+                .is_some_and(|value| value.starts_with(prefix))
+        })
+}
+
+/// Metadata given by the client with a file
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UploadMeta {
+    /// `Content-Type` of the upload
+    pub content_type: Option<String>,
+    /// `X-Meta-<key>: <value>` headers of the upload, by key
+    pub metadata: BTreeMap<String, String>,
+    /// Tags of the `X-Tags` header of the upload, sorted
+    pub tags: Vec<String>,
+}
+
+/// Metadata of a file of a bucket, as replied by the meta request
+#[derive(Serialize, ToSchema)]
+struct FileMetaEntry {
+    index: usize,
+    filename: String,
+    hash: String,
+    size: u64,
+    /// Unix time in seconds, unknown for the files uploaded before the
+    /// metadata were recorded
+    uploaded_at: Option<u64>,
+    content_type: Option<String>,
+    /// Metadata given with `X-Meta-<key>` headers on upload
+    metadata: BTreeMap<String, String>,
+    /// Tags given with the `X-Tags` header on upload
+    tags: Vec<String>,
+}
+
+/// File metadata routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_file_meta))]
+pub(crate) struct ApiDoc;
+
+/// Returns the file metadata route
+pub(crate) fn routes(
+    state: Arc<ServerState>,
+    read_timeout: Duration,
+) -> Router {
+    // Metadata of a file
+    // GET /meta/:bucket_id/:file_index
+    Router::new()
+        .route("/meta/:bucket_id/:file_index", get(handle_file_meta))
+        .route_layer(middleware::from_fn_with_state(
+            read_timeout,
+            limits::timeout,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Read),
+            access::bucket_access,
+        ))
+        .with_state(state)
+}
+
+/// Extracts the metadata of an upload from its headers
+///
+/// Refuses with `400 Bad Request` metadata larger than `MAX_METADATA_SIZE`
+/// or whose values are not UTF-8, and more than `MAX_TAGS` tags or tags
+/// longer than `MAX_TAG_LEN`.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for UploadMeta {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, ApiError> {
+        parse_upload_meta(&parts.headers)
+    }
+}
+
+fn parse_upload_meta(headers: &HeaderMap) -> Result<UploadMeta, ApiError> {
+    let invalid = |name: &str| {
+        ApiError::bad_request(format!("header {name} is not valid UTF-8"))
+    };
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .map(|value| value.to_str().map(str::to_owned))
+        .transpose()
+        .map_err(|_| invalid("content-type"))?;
+
+    let mut metadata = BTreeMap::new();
+    let mut size = 0;
+    for (name, value) in headers {
+        let Some(key) = name.as_str().strip_prefix(METADATA_HEADER_PREFIX)
+        else {
+            continue;
+        };
+        let value = value.to_str().map_err(|_| invalid(name.as_str()))?;
+
+        size += key.len() + value.len();
+        if size > MAX_METADATA_SIZE {
+            return Err(ApiError::bad_request(format!(
+                "file metadata exceed {MAX_METADATA_SIZE} bytes"
+            )));
+        }
+        metadata.insert(key.to_owned(), value.to_owned());
+    }
+
+    let mut tags = Vec::new();
+    for value in headers.get_all(TAGS_HEADER) {
+        let value = value.to_str().map_err(|_| invalid(TAGS_HEADER))?;
+        for tag in value.split(',').map(str::trim) {
+            if tag.is_empty() {
+                continue;
+            }
+            if tag.len() > MAX_TAG_LEN {
+                return Err(ApiError::bad_request(format!(
+                    "tag {tag} exceeds {MAX_TAG_LEN} bytes"
+                )));
+            }
+            tags.push(tag.to_owned());
+        }
+    }
+    tags.sort();
+    tags.dedup();
+    if tags.len() > MAX_TAGS {
+        return Err(ApiError::bad_request(format!(
+            "a file has at most {MAX_TAGS} tags"
+        )));
+    }
+
+    Ok(UploadMeta {
+        content_type,
+        metadata,
+        tags,
+    })
+}
+
+/// Handles file metadata request
+///
+/// Returns `404 Not Found` if the (bucket_id-file_index) does not exist
+#[utoipa::path(
+    get,
+    path = "/meta/{bucket_id}/{file_index}",
+    tag = "files",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+        ("file_index" = usize, Path, description = "Leaf index of the file"),
+    ),
+    responses(
+        (status = 200, description = "Metadata of the file", body = FileMetaEntry),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 404, description = "The bucket or the file does not exist", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_file_meta(
+    Path((bucket_id, file_index)): Path<(String, String)>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<FileMetaEntry>, ApiError> {
+    let bucket = get_bucket(bucket_id.clone(), state.clone())
+        .await
+        .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+    let bucket = bucket.read().await;
+
+    info!(request = "file meta", bucket_id, file_index);
+
+    let index = file_index
+        .parse::<usize>()
+        .map_err(|_| ApiError::file_not_found(&file_index))?;
+    let (hash, filename) = bucket
+        .files
+        .iter()
+        .nth(index)
+        .ok_or_else(|| ApiError::file_not_found(index))?;
+
+    let db = state.db.read().await;
+    let meta = db.file_meta(&bucket_id, hash).map_err(|err| {
+        error!(event = "failed to read file meta", bucket_id, err);
+        ApiError::internal()
+    })?;
+
+    // Files uploaded before the metadata were recorded only have a size
+    let entry = match meta {
+        Some(meta) => FileMetaEntry {
+            index,
+            filename: filename.clone(),
+            hash: hex::encode(hash),
+            size: meta.size,
+            uploaded_at: Some(meta.uploaded_at),
+            content_type: meta.content_type,
+            metadata: meta.metadata,
+            tags: meta.tags,
+        },
+        None => FileMetaEntry {
+            index,
+            filename: filename.clone(),
+            hash: hex::encode(hash),
+            size: blobs::size(&*state.store, &db, hash)
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_default(),
+            uploaded_at: None,
+            content_type: None,
+            metadata: BTreeMap::new(),
+            tags: Vec::new(),
+        },
+    };
+
+    Ok(Json(entry))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_upload_meta() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "text/plain".parse().unwrap());
+        headers.insert("x-meta-author", "alice".parse().unwrap());
+        headers.insert("x-expire-after", "60".parse().unwrap());
+        headers.insert("x-tags", "photos, invoices,,photos".parse().unwrap());
+
+        let meta = parse_upload_meta(&headers).unwrap();
+        assert_eq!(meta.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(
+            meta.metadata,
+            BTreeMap::from([("author".to_owned(), "alice".to_owned())])
+        );
+        assert_eq!(meta.tags, ["invoices", "photos"]);
+
+        let tags = (0..=MAX_TAGS).map(|i| i.to_string()).collect::<Vec<_>>();
+        let mut many = HeaderMap::new();
+        many.insert("x-tags", tags.join(",").parse().unwrap());
+        assert!(parse_upload_meta(&many).is_err());
+
+        let large = "a".repeat(MAX_METADATA_SIZE);
+        headers.insert("x-meta-large", large.parse().unwrap());
+        assert!(parse_upload_meta(&headers).is_err());
+    }
+}
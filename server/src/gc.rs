@@ -0,0 +1,290 @@
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Args;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::app::ServerState;
+use crate::blob_store::BlobStore;
+use crate::blobs;
+use crate::database::DB;
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct GcConfig {
+    /// Seconds between two collections of the orphaned blobs, collections
+    /// only run through POST /admin/gc if unset
+    #[arg(long)]
+    pub gc_interval: Option<u64>,
+
+    /// Move the orphaned blobs under `quarantine/` in the blob store instead
+    /// of removing them
+    #[arg(long)]
+    pub gc_quarantine: bool,
+}
+
+/// Outcome of a collection
+#[derive(Debug, Default, PartialEq, Serialize, ToSchema)]
+pub(crate) struct GcReport {
+    /// Number of blobs referenced by no bucket
+    pub orphans: usize,
+    /// Total size of the orphaned blobs
+    pub orphan_bytes: u64,
+    /// Number of blobs whose reference count was corrected
+    pub refs_fixed: usize,
+}
+
+/// Spawns the periodic collection, if `--gc-interval` is set
+pub(crate) fn spawn(state: Arc<ServerState>, config: &GcConfig) {
+    let Some(interval) = config.gc_interval.filter(|secs| *secs > 0) else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval));
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            if let Err(err) = collect(&state).await {
+                error!(event = "failed to collect garbage", ?err);
+            }
+        }
+    });
+}
+
+/// Number of blobs checked again and collected per hold of the database
+/// write lock
+const BATCH_SIZE: usize = 64;
+
+/// Removes, or quarantines, the blobs referenced by no bucket and corrects
+/// the reference counts
+///
+/// Failed or interrupted uploads may leave blobs and references behind. The
+/// references of the buckets are read from their persisted file records and
+/// from the upload journal, which also covers the files of the buckets not
+/// persisted yet.
+pub(crate) async fn collect(state: &ServerState) -> io::Result<GcReport> {
+    let report =
+        collect_blobs(&*state.store, &state.db, state.gc.gc_quarantine).await?;
+
+    info!(
+        event = "garbage collected",
+        orphans = report.orphans,
+        orphan_bytes = report.orphan_bytes,
+        refs_fixed = report.refs_fixed
+    );
+    Ok(report)
+}
+
+/// A blob found by the scan of a collection
+enum Candidate {
+    /// Blob of the store referenced by no file
+    Stored { key: String },
+    /// Blob inlined in the database referenced by no file
+    Inline,
+    /// Blob counted with a wrong number of references
+    Refs(u64),
+}
+
+/// Collects the blobs in two passes: the candidates are listed without
+/// blocking the uploads, then collected in batches under the database write
+/// lock
+///
+/// Every reference change goes through the count of its blob, so a
+/// candidate whose count changed since the scan is left to the next
+/// collection.
+async fn collect_blobs(
+    store: &dyn BlobStore,
+    db: &RwLock<DB>,
+    quarantine: bool,
+) -> io::Result<GcReport> {
+    let keys = store.list("").await?;
+    let candidates = {
+        let db = db.read().await;
+        scan(&db, keys).map_err(io::Error::other)?
+    };
+
+    let mut report = GcReport::default();
+    for batch in candidates.chunks(BATCH_SIZE) {
+        let db = db.write().await;
+        for (hash, stored, candidate) in batch {
+            if db.blob_refs(hash).map_err(io::Error::other)? != *stored {
+                continue;
+            }
+            let blob = (hash, *stored, candidate);
+            collect_blob(store, &db, blob, quarantine, &mut report).await?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Lists the blobs to collect and the counts to fix, with the count of each
+/// blob when it was listed
+fn scan(
+    db: &DB,
+    keys: Vec<String>,
+) -> Result<Vec<([u8; 32], u64, Candidate)>, String> {
+    let file_refs = db.file_refs()?;
+    let mut candidates = Vec::new();
+
+    // Uploads in progress and quarantined blobs have no blob key
+    for key in keys {
+        let Some(hash) = blobs::key_hash(&key) else {
+            continue;
+        };
+        if !file_refs.contains_key(&hash) {
+            candidates.push((
+                hash,
+                db.blob_refs(&hash)?,
+                Candidate::Stored { key },
+            ));
+        }
+    }
+
+    for hash in db.inline_blob_hashes()? {
+        if !file_refs.contains_key(&hash) {
+            candidates.push((hash, db.blob_refs(&hash)?, Candidate::Inline));
+        }
+    }
+
+    // Counted blobs no longer referenced are dropped, referenced blobs
+    // missing a count get one
+    let counted = db.all_blob_refs()?;
+    let mut hashes: HashSet<[u8; 32]> = file_refs.keys().copied().collect();
+    hashes.extend(counted.iter().map(|(hash, _)| *hash));
+    for hash in hashes {
+        let refs = file_refs.get(&hash).copied().unwrap_or_default();
+        let stored = db.blob_refs(&hash)?;
+        if refs != stored {
+            candidates.push((hash, stored, Candidate::Refs(refs)));
+        }
+    }
+
+    Ok(candidates)
+}
+
+/// Collects a candidate blob, or fixes its count
+async fn collect_blob(
+    store: &dyn BlobStore,
+    db: &DB,
+    (hash, stored, candidate): (&[u8; 32], u64, &Candidate),
+    quarantine: bool,
+    report: &mut GcReport,
+) -> io::Result<()> {
+    match candidate {
+        Candidate::Stored { key } => {
+            let Some(size) = store.size(key).await? else {
+                return Ok(());
+            };
+            report.orphans += 1;
+            report.orphan_bytes += size;
+            if quarantine {
+                store.rename(key, &blobs::quarantine_key(key)).await?;
+            } else {
+                store.delete(key).await?;
+            }
+            warn!(event = "orphaned blob collected", key, quarantine);
+        }
+        Candidate::Inline => {
+            let key = blobs::blob_key(hash);
+            if let Some(data) =
+                db.inline_blob(hash).map_err(io::Error::other)?
+            {
+                report.orphans += 1;
+                report.orphan_bytes += data.len() as u64;
+                if quarantine {
+                    let key = blobs::quarantine_key(&key);
+                    store.put(&key, data.into()).await?;
+                }
+            }
+            db.set_inline_blob(hash, None).map_err(io::Error::other)?;
+            warn!(event = "orphaned blob collected", key, quarantine);
+        }
+        Candidate::Refs(refs) => {
+            let refs = *refs;
+            db.set_blob_refs(hash, refs).map_err(io::Error::other)?;
+            report.refs_fixed += 1;
+
+            let key = blobs::blob_key(hash);
+            warn!(event = "blob references fixed", key, stored, refs);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use crate::client_bucket::ClientBucket;
+    use crate::database::JournalEntry;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_collect_blobs() {
+        let store = MemoryStore::new();
+        let db = DB::in_memory();
+
+        let (kept, journaled, orphan, inline) =
+            ([1u8; 32], [2u8; 32], [3u8; 32], [4u8; 32]);
+        for hash in [kept, journaled, orphan] {
+            let key = blobs::blob_key(&hash);
+            store.put(&key, Bytes::from("data")).await.unwrap();
+        }
+        assert!(db.set_inline_blob(&inline, Some(b"small")).is_ok());
+        let tmp_key = blobs::tmp_key();
+        store.put(&tmp_key, Bytes::from("part")).await.unwrap();
+
+        let mut bucket = ClientBucket::new("bucket_id".to_string());
+        bucket.files.insert(kept, "kept".to_string());
+        assert!(db.update_bucket(&bucket).is_ok());
+        let entry = JournalEntry {
+            filename: "journaled".to_string(),
+            size: 4,
+        };
+        assert!(db.journal_file("bucket_id", &journaled, &entry).is_ok());
+
+        // A leaked reference and a missing one
+        assert!(db.set_blob_refs(&orphan, 1).is_ok());
+        assert!(db.set_blob_refs(&kept, 2).is_ok());
+
+        let db = RwLock::new(db);
+        let report = collect_blobs(&store, &db, true).await.unwrap();
+        assert_eq!(
+            report,
+            GcReport {
+                orphans: 2,
+                orphan_bytes: 9,
+                refs_fixed: 3,
+            }
+        );
+
+        let mut keys = vec![
+            blobs::blob_key(&kept),
+            blobs::blob_key(&journaled),
+            blobs::quarantine_key(&blobs::blob_key(&orphan)),
+            blobs::quarantine_key(&blobs::blob_key(&inline)),
+            tmp_key,
+        ];
+        keys.sort();
+        assert_eq!(store.list("").await.unwrap(), keys);
+        let db = db.into_inner();
+        assert_eq!(db.inline_blob(&inline), Ok(None));
+        assert_eq!(db.blob_refs(&kept), Ok(1));
+        assert_eq!(db.blob_refs(&journaled), Ok(1));
+        assert_eq!(db.blob_refs(&orphan), Ok(0));
+
+        // Nothing is left to collect
+        let db = RwLock::new(db);
+        let report = collect_blobs(&store, &db, false).await.unwrap();
+        assert_eq!(report, GcReport::default());
+    }
+}
@@ -0,0 +1,556 @@
+// The RPCs return tonic statuses, whatever their size
+#![allow(clippy::result_large_err)]
+
+use std::fs;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use clap::Args;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio_stream::{Stream, StreamExt};
+use tonic::metadata::{MetadataMap, MetadataValue};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::{error, info, info_span};
+
+use crate::access::Operation;
+use crate::app::{self, ReceivedFile, ServerState};
+use crate::batch;
+use crate::blobs;
+use crate::client_bucket;
+use crate::errors::{ApiError, ErrorCode};
+use crate::file_meta::UploadMeta;
+use crate::limits::Work;
+use crate::ownership::{self, Signed};
+use crate::tls::{self, TlsConfig};
+
+use proto::storage_server::{Storage, StorageServer};
+use proto::upload_request::Part;
+use proto::{
+    BucketRoot, CompleteUploadRequest, DownloadReply, FileRequest, ProofReply,
+    ProofStep, UploadReply, UploadRequest,
+};
+
+/// Code generated from `proto/storage.proto`
+pub(crate) mod proto {
+    // The client is only used by the tests
+    #![allow(dead_code)]
+    tonic::include_proto!("storage.v1");
+}
+
+/// Metadata of an error status carrying the code of the error, as in the
+/// error replies of the HTTP API
+const ERROR_CODE_METADATA: &str = "x-error-code";
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct GrpcConfig {
+    /// Serve the gRPC API of `proto/storage.proto` on this address, over
+    /// TLS with `--tls-cert`
+    #[arg(long, env = "STORAGE_GRPC_ADDR")]
+    pub grpc_addr: Option<String>,
+}
+
+/// Binds the gRPC service to `--grpc-addr`, if set
+///
+/// The returned future runs the service until `stopped` is set, then waits
+/// for the in-flight calls to complete.
+pub(crate) fn serve(
+    config: &GrpcConfig,
+    tls: &TlsConfig,
+    state: Arc<ServerState>,
+    mut stopped: watch::Receiver<bool>,
+) -> Option<Pin<Box<dyn Future<Output = ()> + Send>>> {
+    let addr = config.grpc_addr.as_ref()?;
+
+    let listener =
+        std::net::TcpListener::bind(addr).expect("bindable gRPC address");
+    listener
+        .set_nonblocking(true)
+        .expect("non-blocking listener");
+    let listener = TcpListener::from_std(listener).expect("tokio listener");
+    let addr = listener.local_addr().expect("bound address");
+    let incoming =
+        TcpIncoming::from_listener(listener, true, None).expect("listener");
+
+    let mut server = Server::builder();
+    if let (Some(cert), Some(key)) = (&tls.tls_cert, &tls.tls_key) {
+        let identity = Identity::from_pem(
+            fs::read(cert).expect("readable certificate"),
+            fs::read(key).expect("readable private key"),
+        );
+        let mut tls_config = ServerTlsConfig::new().identity(identity);
+        if let Some(ca) = &tls.tls_client_ca {
+            let ca = fs::read(ca).expect("readable client CA");
+            tls_config = tls_config.client_ca_root(Certificate::from_pem(ca));
+        }
+        server = server.tls_config(tls_config).expect("valid TLS config");
+    }
+
+    let service = StorageServer::new(StorageService { state });
+    let server = server.add_service(service).serve_with_incoming_shutdown(
+        incoming,
+        async move {
+            let _ = stopped.wait_for(|stopped| *stopped).await;
+        },
+    );
+    info!(event = "grpc listening", %addr);
+
+    Some(Box::pin(async move {
+        if let Err(err) = server.await {
+            error!(event = "grpc server failed", %err);
+        }
+    }))
+}
+
+/// Credentials of a call, as the HTTP routes get them from their headers
+/// and their connection
+struct Credentials {
+    /// `Bearer` API key or JWT of the `authorization` metadata
+    authorization: Option<String>,
+    /// Fingerprint of the client certificate of the connection
+    client_cert: Option<String>,
+}
+
+impl Credentials {
+    fn of<T>(request: &Request<T>) -> Self {
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let client_cert = request
+            .peer_certs()
+            .and_then(|certs| certs.first().map(|cert| tls::fingerprint(cert)));
+
+        Credentials {
+            authorization,
+            client_cert,
+        }
+    }
+}
+
+/// The gRPC service, serving the buckets of the HTTP API
+struct StorageService {
+    state: Arc<ServerState>,
+}
+
+impl StorageService {
+    /// Checks that a call is allowed to perform `operation` on a bucket,
+    /// like [`crate::access::bucket_access`] for the HTTP routes
+    async fn authorize(
+        &self,
+        credentials: &Credentials,
+        bucket_id: &str,
+        operation: Operation,
+    ) -> Result<(), Status> {
+        self.state
+            .access
+            .check(
+                bucket_id,
+                operation,
+                credentials.authorization.as_deref(),
+                credentials.client_cert.as_deref(),
+            )
+            .map_err(|err| ApiError::from(&err).into())
+    }
+
+    /// Returns the hash of the file at `file_index` in a bucket
+    async fn file_hash(
+        &self,
+        bucket_id: &str,
+        file_index: u64,
+    ) -> Result<[u8; 32], ApiError> {
+        let bucket = app::get_bucket(bucket_id.to_owned(), self.state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(bucket_id))?;
+        let bucket = bucket.read().await;
+
+        let index = usize::try_from(file_index).unwrap_or(usize::MAX);
+        bucket
+            .get_file_hash(index)
+            .ok_or_else(|| ApiError::file_not_found(file_index))
+    }
+}
+
+#[tonic::async_trait]
+impl Storage for StorageService {
+    /// Receives the content in a temporary blob, then stores it as the
+    /// upload route does
+    async fn upload(
+        &self,
+        request: Request<Streaming<UploadRequest>>,
+    ) -> Result<Response<UploadReply>, Status> {
+        let credentials = Credentials::of(&request);
+        let signed = signed(request.metadata());
+        let session = upload_session(request.metadata());
+        let peer = request.remote_addr().map(|addr| addr.to_string());
+
+        let mut request = request;
+        let first = request.get_mut().message().await?;
+        let Some(UploadRequest {
+            part: Some(Part::Header(header)),
+        }) = first
+        else {
+            return Err(Status::invalid_argument(
+                "the first message must be the file header",
+            ));
+        };
+        self.authorize(&credentials, &header.bucket_id, Operation::Write)
+            .await?;
+        let parts = request.into_inner();
+        let bucket_id = header.bucket_id;
+        let filename = header.filename;
+
+        info!(request = "grpc upload", bucket_id, filename);
+
+        let chunks = parts.map(|part| match part?.part {
+            Some(Part::Chunk(chunk)) => Ok(chunk),
+            _ => Err(Status::invalid_argument("expected a chunk")),
+        });
+
+        let (store, limits, chunks, _permit) = {
+            let state = &self.state;
+            state.disk.check_writable()?;
+            let permit = state.concurrency.acquire(Work::Write)?;
+            let chunks = state.throttle.upload(&bucket_id, chunks);
+            (state.store.clone(), state.limits.clone(), chunks, permit)
+        };
+        let tmp_key = blobs::tmp_key();
+        let received = tokio::time::timeout(
+            limits.upload_timeout(),
+            app::receive_body(
+                &*store,
+                &tmp_key,
+                None,
+                limits.max_upload_size,
+                chunks,
+            ),
+        )
+        .await
+        .unwrap_or(Err(app::ReceiveError::Timeout));
+        let (hash, size) = match received {
+            Ok(received) => received,
+            Err(err) => {
+                let _ = store.delete(&tmp_key).await;
+
+                error!(event = "failed to upload", filename, bucket_id, error = %err);
+                return Err(ApiError::from(err).into());
+            }
+        };
+
+        let file = ReceivedFile {
+            filename,
+            tmp_key: &tmp_key,
+            hash,
+            size,
+            expire_after: header.expire_after,
+            session,
+            meta: UploadMeta::default(),
+            duplicates: None,
+        };
+        app::store_file(bucket_id, file, &signed, peer, self.state.clone())
+            .await?;
+
+        Ok(Response::new(UploadReply {
+            hash: Bytes::copy_from_slice(&hash),
+        }))
+    }
+
+    type DownloadStream =
+        Pin<Box<dyn Stream<Item = Result<DownloadReply, Status>> + Send>>;
+
+    async fn download(
+        &self,
+        request: Request<FileRequest>,
+    ) -> Result<Response<Self::DownloadStream>, Status> {
+        let bucket_id = &request.get_ref().bucket_id;
+        self.authorize(&Credentials::of(&request), bucket_id, Operation::Read)
+            .await?;
+        let FileRequest {
+            bucket_id,
+            file_index,
+        } = request.into_inner();
+
+        info!(request = "grpc download", bucket_id, file_index);
+
+        let hash = self.file_hash(&bucket_id, file_index).await?;
+        let (_, stream) = {
+            let state = &self.state;
+            let db = state.db.read().await;
+            let (size, stream) = blobs::stream(&*state.store, &db, &hash)
+                .await
+                .map_err(|_| ApiError::file_not_found(file_index))?;
+            (size, state.throttle.download(&bucket_id, stream))
+        };
+
+        let stream = stream.map(|chunk| match chunk {
+            Ok(chunk) => Ok(DownloadReply { chunk }),
+            Err(err) => {
+                error!(event = "failed to stream file", %err);
+                Err(ApiError::internal().into())
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_proof(
+        &self,
+        request: Request<FileRequest>,
+    ) -> Result<Response<ProofReply>, Status> {
+        let bucket_id = &request.get_ref().bucket_id;
+        self.authorize(&Credentials::of(&request), bucket_id, Operation::Read)
+            .await?;
+        let FileRequest {
+            bucket_id,
+            file_index,
+        } = request.into_inner();
+
+        info!(request = "grpc proof", bucket_id, file_index);
+
+        let bucket = app::get_bucket(bucket_id.clone(), self.state.clone())
+            .await
+            .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+        let bucket = bucket.read().await;
+        let index = usize::try_from(file_index).unwrap_or(usize::MAX);
+        if index >= bucket.merkle_tree.leaves_count() {
+            return Err(ApiError::file_not_found(file_index).into());
+        }
+        let hash = bucket.merkle_tree.leaves()[index];
+
+        let _permit = self.state.concurrency.acquire(Work::Proof)?;
+        let steps = info_span!("proof", index)
+            .in_scope(|| bucket.merkle_tree.get_proof(index))
+            .into_iter()
+            .map(|(sibling, left)| ProofStep {
+                sibling: Bytes::copy_from_slice(&sibling),
+                left: left > 0,
+            })
+            .collect();
+        let root = bucket.merkle_tree.root_hash().unwrap_or_default();
+
+        Ok(Response::new(ProofReply {
+            hash: Bytes::copy_from_slice(&hash),
+            steps,
+            root: Bytes::copy_from_slice(&root),
+        }))
+    }
+
+    async fn complete_upload(
+        &self,
+        request: Request<CompleteUploadRequest>,
+    ) -> Result<Response<BucketRoot>, Status> {
+        let bucket_id = &request.get_ref().bucket_id;
+        self.authorize(&Credentials::of(&request), bucket_id, Operation::Write)
+            .await?;
+        let signed = signed(request.metadata());
+        let session = upload_session(request.metadata());
+        let peer = request.remote_addr().map(|addr| addr.to_string());
+        let bucket_id = request.into_inner().bucket_id;
+
+        let root = app::complete_upload(
+            bucket_id,
+            session.as_deref(),
+            &signed,
+            peer,
+            self.state.clone(),
+        )
+        .await?;
+
+        Ok(Response::new(root.into()))
+    }
+}
+
+/// Extracts the owner signature of a call, carried in the metadata named as
+/// the signature headers
+fn signed(metadata: &MetadataMap) -> Signed {
+    let value = |key| {
+        metadata
+            .get(key)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    Signed::new(
+        value(ownership::SIGNATURE_HEADER),
+        value(ownership::NONCE_HEADER),
+    )
+}
+
+/// Extracts the token of the upload session of a call, carried in the
+/// metadata named as the session header
+fn upload_session(metadata: &MetadataMap) -> Option<String> {
+    metadata
+        .get(batch::SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
+
+impl From<client_bucket::BucketRoot> for BucketRoot {
+    fn from(root: client_bucket::BucketRoot) -> Self {
+        BucketRoot {
+            root: root
+                .root
+                .map(|root| hex::decode(root).expect("hex encoded root"))
+                .map(Bytes::from),
+            leaves_count: root.leaves_count as u64,
+        }
+    }
+}
+
+impl From<ApiError> for Status {
+    fn from(err: ApiError) -> Self {
+        let code = match err.code() {
+            ErrorCode::BadRequest => Code::InvalidArgument,
+            ErrorCode::InvalidSignature | ErrorCode::Unauthorized => {
+                Code::Unauthenticated
+            }
+            ErrorCode::NotFound
+            | ErrorCode::BucketNotFound
+            | ErrorCode::FileNotFound
+            | ErrorCode::UploadNotFound => Code::NotFound,
+            ErrorCode::MethodNotAllowed => Code::Unimplemented,
+            ErrorCode::RequestTimeout => Code::DeadlineExceeded,
+            ErrorCode::Conflict | ErrorCode::DuplicateFilename => {
+                Code::AlreadyExists
+            }
+            ErrorCode::OffsetMismatch
+            | ErrorCode::UploadInProgress
+            | ErrorCode::BucketSealed
+            | ErrorCode::MissingBlobs => Code::FailedPrecondition,
+            ErrorCode::PayloadTooLarge
+            | ErrorCode::QuotaExceeded
+            | ErrorCode::RateLimited
+            | ErrorCode::InsufficientStorage => Code::ResourceExhausted,
+            ErrorCode::Internal => Code::Internal,
+            ErrorCode::BadGateway | ErrorCode::Unavailable => Code::Unavailable,
+        };
+
+        let mut status = Status::new(code, err.message());
+        if let Ok(serde_json::Value::String(code)) =
+            serde_json::to_value(err.code())
+        {
+            if let Ok(code) = MetadataValue::try_from(code) {
+                status.metadata_mut().insert(ERROR_CODE_METADATA, code);
+            }
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::storage_client::StorageClient;
+    use super::proto::FileHeader;
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use crate::Config;
+    use clap::Parser;
+    use merkle::tree::Tree;
+    use sha2::{Digest, Sha256};
+
+    async fn connect() -> StorageClient<tonic::transport::Channel> {
+        // Reserve a port for the gRPC listener
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let grpc_addr = format!("127.0.0.1:{port}");
+        let config = Config::parse_from([
+            "server",
+            "127.0.0.1:0",
+            "--grpc-addr",
+            &grpc_addr,
+        ]);
+        let store = Arc::new(MemoryStore::new());
+        let (_, server) = app::run_server_with_store(config, store).await;
+        tokio::spawn(server);
+
+        StorageClient::connect(format!("http://{grpc_addr}"))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_grpc() {
+        let mut client = connect().await;
+
+        let header = FileHeader {
+            bucket_id: "bucket".to_owned(),
+            filename: "a.txt".to_owned(),
+            expire_after: None,
+        };
+        let parts = [
+            Part::Header(header),
+            Part::Chunk(Bytes::from("hel")),
+            Part::Chunk(Bytes::from("lo")),
+        ]
+        .map(|part| UploadRequest { part: Some(part) });
+        let reply = client.upload(tokio_stream::iter(parts)).await.unwrap();
+        let hash: [u8; 32] = Sha256::digest(b"hello").into();
+        assert_eq!(reply.into_inner().hash, hash[..]);
+
+        let request = CompleteUploadRequest {
+            bucket_id: "bucket".to_owned(),
+        };
+        let root = client.complete_upload(request).await.unwrap().into_inner();
+        assert_eq!(root.leaves_count, 1);
+
+        let request = FileRequest {
+            bucket_id: "bucket".to_owned(),
+            file_index: 0,
+        };
+        let mut chunks =
+            client.download(request.clone()).await.unwrap().into_inner();
+        let mut content = Vec::new();
+        while let Some(reply) = chunks.message().await.unwrap() {
+            content.extend_from_slice(&reply.chunk);
+        }
+        assert_eq!(content, b"hello");
+
+        let proof = client.get_proof(request).await.unwrap().into_inner();
+        let proof = proof
+            .steps
+            .iter()
+            .map(|step| (step.sibling[..].try_into().unwrap(), step.left as u8))
+            .collect();
+        let root: [u8; 32] = root.root.unwrap()[..].try_into().unwrap();
+        assert!(Tree::verify_proof(&hash, &proof, &root));
+
+        let request = FileRequest {
+            bucket_id: "bucket".to_owned(),
+            file_index: 1,
+        };
+        let err = client.get_proof(request).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+        let code = err.metadata().get(ERROR_CODE_METADATA).unwrap();
+        assert_eq!(code, "FILE_NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn test_grpc_proof_before_complete() {
+        let mut client = connect().await;
+
+        let header = FileHeader {
+            bucket_id: "bucket".to_owned(),
+            filename: "a.txt".to_owned(),
+            expire_after: None,
+        };
+        let parts = [Part::Header(header), Part::Chunk(Bytes::from("hello"))]
+            .map(|part| UploadRequest { part: Some(part) });
+        client.upload(tokio_stream::iter(parts)).await.unwrap();
+
+        // The file is stored but not yet a leaf of the tree
+        let request = FileRequest {
+            bucket_id: "bucket".to_owned(),
+            file_index: 0,
+        };
+        let err = client.get_proof(request).await.unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+        let code = err.metadata().get(ERROR_CODE_METADATA).unwrap();
+        assert_eq!(code, "FILE_NOT_FOUND");
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use bytes::Bytes;
+use serde::Serialize;
+use tracing::warn;
+use utoipa::{OpenApi, ToSchema};
+
+use crate::app::ServerState;
+use crate::blobs;
+use crate::extract::Json;
+
+/// Time allowed to each readiness check, a hung disk makes it fail
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of the readiness checks
+#[derive(Serialize, ToSchema)]
+struct Readiness {
+    ready: bool,
+    /// The database answers reads
+    database: bool,
+    /// A blob can be written to and deleted from the storage
+    storage: bool,
+}
+
+/// Probe routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_healthz, handle_readyz,))]
+pub(crate) struct ApiDoc;
+
+/// Returns the liveness and readiness routes, which require no credentials
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    Router::new()
+        // Liveness probe
+        // GET /healthz
+        .route("/healthz", get(handle_healthz))
+        // Readiness probe
+        // GET /readyz
+        .route("/readyz", get(handle_readyz))
+        .with_state(state)
+}
+
+/// Handles healthz request
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "server",
+    responses(
+        (status = 200, description = "The server is alive", body = String, content_type = "text/plain"),
+    ),
+)]
+async fn handle_healthz() -> &'static str {
+    "ok"
+}
+
+/// Handles readyz request
+///
+/// The journal is replayed and the database opened before the server
+/// listens, so a server answering has recovered its buckets. Replies
+/// `503 Service Unavailable` if the database or the storage fails.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "server",
+    responses(
+        (status = 200, description = "The server is ready", body = Readiness),
+        (status = 503, description = "A check failed", body = Readiness),
+    ),
+)]
+async fn handle_readyz(
+    State(state): State<Arc<ServerState>>,
+) -> impl IntoResponse {
+    let database = tokio::time::timeout(CHECK_TIMEOUT, async {
+        state.db.read().await.check()
+    })
+    .await
+    .map_err(|_| "timed out".to_owned())
+    .and_then(|res| res);
+    if let Err(err) = &database {
+        warn!(event = "database not ready", err);
+    }
+
+    let storage = tokio::time::timeout(CHECK_TIMEOUT, async {
+        let key = blobs::tmp_key();
+        state.store.put(&key, Bytes::from_static(b"ready")).await?;
+        state.store.delete(&key).await
+    })
+    .await;
+    let storage = match storage {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+            warn!(event = "storage not ready", ?err);
+            false
+        }
+        Err(_) => {
+            warn!(event = "storage not ready", err = "timed out");
+            false
+        }
+    };
+
+    let readiness = Readiness {
+        ready: database.is_ok() && storage,
+        database: database.is_ok(),
+        storage,
+    };
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::run_server_with_store;
+    use crate::blob_store::{BlobStore, MemoryStore};
+    use crate::Config;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use clap::Parser;
+    use http_body_util::BodyExt;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_probes() {
+        let config = Config::parse_from(["server", "127.0.0.1:0"]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = run_server_with_store(config, store.clone()).await;
+        tokio::spawn(server);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let uri = format!("http://{addr}/healthz").parse().unwrap();
+        let response = client.get(uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let uri = format!("http://{addr}/readyz").parse().unwrap();
+        let response = client.get(uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let readiness: serde_json::Value =
+            serde_json::from_slice(&body).unwrap();
+        assert_eq!(readiness["database"], true);
+        assert_eq!(readiness["storage"], true);
+
+        // The probe blob is not left behind
+        assert!(store.list("").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,298 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::http::{Request, Response, Version};
+use axum::Router;
+use bytes::{Buf, Bytes};
+use clap::Args;
+use h3::server::RequestResolver;
+use http_body_util::BodyExt;
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::pki_types::CertificateDer;
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tower::ServiceExt;
+use tracing::{info, warn};
+
+use crate::tls::{self, ClientCert, RemoteAddr, TlsConfig};
+
+/// Headers specific to an HTTP/1.1 connection, not allowed over HTTP/3
+const CONNECTION_HEADERS: &[&str] =
+    &["connection", "keep-alive", "transfer-encoding", "upgrade"];
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct Http3Config {
+    /// Serve the API over HTTP/3 on this UDP address, with the certificate
+    /// of `--tls-cert` (experimental)
+    #[arg(long, env = "STORAGE_HTTP3_ADDR", requires = "tls_cert")]
+    pub http3_addr: Option<String>,
+}
+
+/// Binds `routes` to the QUIC endpoint of `--http3-addr`, if set
+///
+/// Connections authenticate with a client certificate when
+/// `--tls-client-ca` is set, passed to the routes as over HTTPS. The
+/// returned future runs the listener until `stopped` is set, then waits for
+/// the in-flight requests to be answered.
+pub(crate) fn serve(
+    config: &Http3Config,
+    tls: &TlsConfig,
+    routes: Router,
+    mut stopped: watch::Receiver<bool>,
+) -> Option<Pin<Box<dyn Future<Output = ()> + Send>>> {
+    let addr: SocketAddr = config
+        .http3_addr
+        .as_ref()?
+        .parse()
+        .expect("parsable HTTP/3 address");
+    let server_config = server_config(tls).expect("valid TLS");
+    let endpoint = quinn::Endpoint::server(server_config, addr)
+        .expect("bindable HTTP/3 address");
+    let addr = endpoint.local_addr().expect("bound address");
+    info!(event = "http3 listening", %addr);
+
+    Some(Box::pin(async move {
+        // Each connection and request holds a receiver, closing it once done
+        let (closing, closing_receiver) = watch::channel(());
+
+        loop {
+            let incoming = tokio::select! {
+                incoming = endpoint.accept() => incoming,
+                _ = stopped.wait_for(|stopped| *stopped) => break,
+            };
+            let Some(incoming) = incoming else {
+                break;
+            };
+
+            let routes = routes.clone();
+            let closing = closing_receiver.clone();
+            tokio::spawn(serve_connection(incoming, routes, closing));
+        }
+
+        drop(closing_receiver);
+        let _ = closing.send(());
+        closing.closed().await;
+        endpoint.wait_idle().await;
+    }))
+}
+
+/// Answers the requests of a connection until it is closed, or until the
+/// listener is closing
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    routes: Router,
+    mut closing: watch::Receiver<()>,
+) {
+    let remote = incoming.remote_address();
+    let conn = match incoming.await {
+        Ok(conn) => conn,
+        Err(err) => {
+            warn!(event = "QUIC handshake failed", %remote, ?err);
+            return;
+        }
+    };
+    let cert = conn
+        .peer_identity()
+        .and_then(|certs| certs.downcast::<Vec<CertificateDer>>().ok())
+        .and_then(|certs| certs.first().map(|cert| tls::fingerprint(cert)))
+        .map(ClientCert);
+
+    let mut conn = match h3::server::builder()
+        .build(h3_quinn::Connection::new(conn))
+        .await
+    {
+        Ok(conn) => conn,
+        Err(err) => {
+            warn!(event = "HTTP/3 connection failed", %remote, ?err);
+            return;
+        }
+    };
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = conn.accept() => accepted,
+            _ = closing.changed() => {
+                // Refuses new requests, the accepted ones are answered
+                let _ = conn.shutdown(0).await;
+                break;
+            }
+        };
+        match accepted {
+            Ok(Some(resolver)) => {
+                let routes = routes.clone();
+                let cert = cert.clone();
+                let closing = closing.clone();
+                tokio::spawn(async move {
+                    let served = serve_request(resolver, remote, |mut req| {
+                        if let Some(cert) = cert {
+                            req.extensions_mut().insert(cert);
+                        }
+                        routes.oneshot(req)
+                    });
+                    if let Err(err) = served.await {
+                        warn!(event = "HTTP/3 request failed", %remote, ?err);
+                    }
+                    drop(closing);
+                });
+            }
+            Ok(None) => break,
+            Err(err) if err.is_h3_no_error() => break,
+            Err(err) => {
+                warn!(event = "HTTP/3 connection failed", %remote, ?err);
+                break;
+            }
+        }
+    }
+}
+
+/// Answers a request with the reply of `call`, streaming both bodies
+async fn serve_request<F, R>(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    remote: SocketAddr,
+    call: F,
+) -> io::Result<()>
+where
+    F: FnOnce(Request<Body>) -> R,
+    R: Future<Output = Result<Response<Body>, Infallible>>,
+{
+    let (req, stream) =
+        resolver.resolve_request().await.map_err(io::Error::other)?;
+    let (mut send, mut recv) = stream.split();
+
+    let (sender, receiver) = mpsc::channel(1);
+    let receive = async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut data)) => {
+                    let data = data.copy_to_bytes(data.remaining());
+                    // The handler does not read the rest of the body
+                    if sender.send(Ok(data)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(err) => {
+                    warn!(event = "HTTP/3 body failed", %remote, ?err);
+                    let _ = sender.send(Err(io::Error::other(err))).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    let body = Body::from_stream(ReceiverStream::new(receiver));
+    let req = request(req, body, remote);
+    let respond = async move {
+        let Ok(res) = call(req).await;
+        let (head, mut body) = response(res);
+        send.send_response(head).await.map_err(io::Error::other)?;
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(io::Error::other)?;
+            if let Ok(data) = frame.into_data() {
+                send.send_data(data).await.map_err(io::Error::other)?;
+            }
+        }
+        send.finish().await.map_err(io::Error::other)
+    };
+
+    let ((), responded) = tokio::join!(receive, respond);
+    responded
+}
+
+/// Returns an HTTP/3 request as the routes receive it, with the address of
+/// the client
+fn request(req: Request<()>, body: Body, remote: SocketAddr) -> Request<Body> {
+    let (mut parts, ()) = req.into_parts();
+    parts.version = Version::HTTP_3;
+
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(RemoteAddr(remote));
+    req
+}
+
+/// Returns the head of a reply as HTTP/3 sends it, and its body
+fn response(res: Response<Body>) -> (Response<()>, Body) {
+    let (mut parts, body) = res.into_parts();
+    for name in CONNECTION_HEADERS {
+        parts.headers.remove(*name);
+    }
+
+    (Response::from_parts(parts, ()), body)
+}
+
+/// Returns the QUIC configuration of `--tls-cert` and `--tls-key`,
+/// requiring client certificates if `--tls-client-ca` is set
+fn server_config(config: &TlsConfig) -> io::Result<quinn::ServerConfig> {
+    let path = |path: &Option<String>| path.clone().unwrap_or_default();
+    let certs = tls::read_certs(&path(&config.tls_cert))?;
+    let key = tls::read_key(&path(&config.tls_key))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(io::Error::other)?;
+    let builder = match &config.tls_client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for ca in tls::read_certs(ca)? {
+                roots.add(ca).map_err(io::Error::other)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(roots),
+                provider,
+            )
+            .build()
+            .map_err(io::Error::other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut tls_config = builder
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto =
+        QuicServerConfig::try_from(tls_config).map_err(io::Error::other)?;
+
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let req = Request::builder()
+            .method("PUT")
+            .uri("https://localhost/upload/b/a.txt")
+            .header("x-expire-after", "60")
+            .body(())
+            .unwrap();
+        let remote: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let req = request(req, Body::empty(), remote);
+        assert_eq!(req.method(), axum::http::Method::PUT);
+        assert_eq!(req.uri().path(), "/upload/b/a.txt");
+        assert_eq!(req.headers()["x-expire-after"], "60");
+        assert_eq!(req.extensions().get::<RemoteAddr>().unwrap().0, remote);
+
+        let res = Response::builder()
+            .status(201)
+            .header("connection", "keep-alive")
+            .header("x-request-id", "abc")
+            .body(Body::empty())
+            .unwrap();
+        let (head, _) = response(res);
+        assert_eq!(head.status(), 201);
+        assert_eq!(head.headers()["x-request-id"], "abc");
+        assert!(!head.headers().contains_key("connection"));
+    }
+}
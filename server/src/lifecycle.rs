@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use utoipa::ToSchema;
+
+use crate::app::{self, ServerState};
+use crate::audit::{self, AuditOp, AuditRecord};
+use crate::blobs;
+use crate::events::Event;
+use crate::replication;
+
+#[derive(Args, Clone, Debug)]
+pub(crate) struct LifecycleConfig {
+    /// Seconds between two sweeps of the expired files, 0 disables the
+    /// expiration
+    #[arg(long, default_value_t = 60)]
+    pub expiration_interval: u64,
+}
+
+/// Retention of the files of a bucket, set through the admin API
+#[derive(
+    Clone, Debug, Default, PartialEq, Serialize, Deserialize, ToSchema,
+)]
+pub(crate) struct LifecyclePolicy {
+    /// Seconds a file is kept after its upload, unless the upload sets
+    /// `X-Expire-After`
+    pub expire_after: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Returns the unix time after which a file uploaded now expires
+pub(crate) fn expires_at(expire_after: Option<u64>) -> Option<u64> {
+    expire_after.map(|secs| now().saturating_add(secs))
+}
+
+/// Spawns the periodic sweep of the expired files, unless
+/// `--expiration-interval` is 0
+pub(crate) fn spawn(state: Arc<ServerState>, config: &LifecycleConfig) {
+    if config.expiration_interval == 0 {
+        return;
+    }
+    let interval = config.expiration_interval;
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval));
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            sweep(&state).await;
+        }
+    });
+}
+
+/// Deletes the expired files from their bucket
+///
+/// Each bucket is updated once for all its expired files: its Merkle tree
+/// is recomputed and its new root recorded when it is persisted, then the
+/// blobs of the files are released. Returns the number of deleted files.
+pub(crate) async fn sweep(state: &Arc<ServerState>) -> usize {
+    let expired = {
+        let db = state.db.read().await;
+        match db.expired_files(now()) {
+            Ok(expired) => expired,
+            Err(err) => {
+                error!(event = "failed to read expirations", err);
+                return 0;
+            }
+        }
+    };
+
+    let mut buckets: BTreeMap<String, Vec<[u8; 32]>> = BTreeMap::new();
+    for (bucket_id, hash) in expired {
+        buckets.entry(bucket_id).or_default().push(hash);
+    }
+
+    let mut count = 0;
+    for (bucket_id, hashes) in buckets {
+        count += expire_files(state, &bucket_id, &hashes).await;
+    }
+    count
+}
+
+async fn expire_files(
+    state: &Arc<ServerState>,
+    bucket_id: &str,
+    hashes: &[[u8; 32]],
+) -> usize {
+    let Some(bucket) =
+        app::get_bucket(bucket_id.to_owned(), state.clone()).await
+    else {
+        // The bucket was deleted meanwhile
+        let db = state.db.read().await;
+        for hash in hashes {
+            let _ = db.set_expiration(bucket_id, hash, None);
+        }
+        return 0;
+    };
+
+    let mut bucket = bucket.write().await;
+
+    let previous_root = bucket.merkle_tree.root_hash();
+    let mut removed = Vec::new();
+    for hash in hashes {
+        let Some(filename) = bucket.files.remove(hash) else {
+            // The file was deleted meanwhile
+            let _ = state.db.read().await.set_expiration(bucket_id, hash, None);
+            continue;
+        };
+
+        let file_size = {
+            let db = state.db.read().await;
+            blobs::size(&*state.store, &db, hash)
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_default()
+        };
+        bucket.bytes_used = bucket.bytes_used.saturating_sub(file_size);
+        info!(event = "file expired", bucket_id, filename);
+        removed.push((*hash, filename));
+    }
+    if removed.is_empty() {
+        return 0;
+    }
+
+    bucket.calculate_merkle_tree();
+    if let Err(err) = state.persist_bucket_lockless(&bucket).await {
+        error!(event = "failed to persist bucket", bucket_id, err);
+    }
+
+    // The blobs are removed once no bucket references them
+    for (hash, filename) in &removed {
+        let res = {
+            let db = state.db.write().await;
+            let record = AuditRecord::new(AuditOp::Expire, None)
+                .with_file(filename, hash)
+                .with_root(bucket.merkle_tree.root_hash());
+            audit::record(&db, bucket_id, record);
+            blobs::release(&*state.store, &db, hash).await
+        };
+        if let Err(err) = res {
+            error!(event = "failed to release blob", bucket_id, ?err);
+        }
+    }
+    replication::enqueue(state, bucket_id).await;
+
+    for (hash, filename) in &removed {
+        state.notify(Some(Event::FileDeleted {
+            bucket_id: bucket_id.to_owned(),
+            filename: filename.clone(),
+            hash: hex::encode(hash),
+            expired: true,
+        }));
+    }
+    let root = bucket.merkle_tree.root_hash();
+    state.notify(Event::root_changed(bucket_id, previous_root, root));
+
+    let root = root.map(hex::encode);
+    info!(
+        event = "files expired",
+        bucket_id,
+        count = removed.len(),
+        root
+    );
+    removed.len()
+}
@@ -0,0 +1,325 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::{Request, State};
+use axum::http::header::CONTENT_LENGTH;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use clap::Args;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::errors::{ApiError, ErrorCode};
+
+#[derive(Args, Clone, Debug)]
+pub(crate) struct LimitsConfig {
+    /// Maximum size in bytes of an uploaded file, unbounded by default
+    #[arg(long)]
+    pub max_upload_size: Option<u64>,
+
+    /// Maximum number of bytes per second received from all the uploads,
+    /// unbounded by default. Buckets may be limited further through
+    /// `/admin/bandwidth`
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_upload_rate: Option<u64>,
+
+    /// Maximum number of bytes per second sent by all the downloads,
+    /// unbounded by default. Buckets may be limited further through
+    /// `/admin/bandwidth`
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_download_rate: Option<u64>,
+
+    /// Seconds a read request (download, proofs, listings) may take before
+    /// it is answered with `503 Service Unavailable`
+    #[arg(long, default_value_t = 30)]
+    pub request_timeout: u64,
+
+    /// Seconds the body of an upload or of a chunk may take to be received
+    /// before it is answered with `408 Request Timeout`
+    #[arg(long, default_value_t = 600)]
+    pub upload_timeout: u64,
+
+    /// Seconds an upload session opened by `begin_upload` stays open
+    /// without requests, its uncommitted files are removed once it expires
+    #[arg(long, default_value_t = 600)]
+    pub upload_session_ttl: u64,
+
+    /// Seconds a resumable upload opened by `upload_init` stays open without
+    /// chunks or parts, its received data is removed once it expires
+    #[arg(long, default_value_t = 86400)]
+    pub resumable_upload_ttl: u64,
+
+    /// Maximum number of resumable uploads open at once on a bucket, further
+    /// ones are answered with `429 Too Many Requests`
+    #[arg(long, default_value_t = 100)]
+    pub max_resumable_uploads: usize,
+
+    /// Seconds a client may take to send the headers of a request before
+    /// its connection is closed
+    #[arg(long, default_value_t = 30)]
+    pub header_timeout: u64,
+
+    /// Seconds a connection may wait for the next bytes of a request, or
+    /// for the next request, before it is closed
+    #[arg(long, default_value_t = 60)]
+    pub idle_timeout: u64,
+
+    /// Seconds a connection may wait for the client to read the next bytes
+    /// of a reply before it is closed
+    #[arg(long, default_value_t = 60)]
+    pub write_timeout: u64,
+
+    /// Maximum number of uploads and chunks written to the blob store at
+    /// once, unbounded by default. Further ones are answered with `503
+    /// Service Unavailable`
+    #[arg(long)]
+    pub max_concurrent_writes: Option<usize>,
+
+    /// Maximum number of Merkle trees rebuilt at once by `complete_upload`
+    /// and the file deletions, unbounded by default. Further ones are
+    /// answered with `503 Service Unavailable`
+    #[arg(long)]
+    pub max_concurrent_merkle: Option<usize>,
+
+    /// Maximum number of proof requests answered at once, unbounded by
+    /// default. Further ones are answered with `503 Service Unavailable`
+    #[arg(long)]
+    pub max_concurrent_proofs: Option<usize>,
+}
+
+impl LimitsConfig {
+    pub(crate) fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout)
+    }
+
+    pub(crate) fn upload_timeout(&self) -> Duration {
+        Duration::from_secs(self.upload_timeout)
+    }
+
+    pub(crate) fn upload_session_ttl(&self) -> Duration {
+        Duration::from_secs(self.upload_session_ttl)
+    }
+
+    pub(crate) fn resumable_upload_ttl(&self) -> Duration {
+        Duration::from_secs(self.resumable_upload_ttl)
+    }
+
+    pub(crate) fn header_timeout(&self) -> Duration {
+        Duration::from_secs(self.header_timeout)
+    }
+
+    pub(crate) fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    pub(crate) fn write_timeout(&self) -> Duration {
+        Duration::from_secs(self.write_timeout)
+    }
+}
+
+/// Seconds a request refused by a concurrency limit is retried after
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Expensive operations whose concurrency is limited
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Work {
+    /// Writing an upload or a chunk to the blob store
+    Write,
+    /// Rebuilding the Merkle tree of a bucket
+    Merkle,
+    /// Generating proofs
+    Proof,
+}
+
+impl Work {
+    fn name(&self) -> &'static str {
+        match self {
+            Work::Write => "writes",
+            Work::Merkle => "Merkle rebuilds",
+            Work::Proof => "proof generations",
+        }
+    }
+}
+
+/// Bounds the operations of each [`Work`] running at once, see the
+/// `--max-concurrent-*` options
+pub(crate) struct Concurrency {
+    writes: Option<Arc<Semaphore>>,
+    merkle: Option<Arc<Semaphore>>,
+    proofs: Option<Arc<Semaphore>>,
+}
+
+impl Concurrency {
+    pub(crate) fn new(config: &LimitsConfig) -> Self {
+        let semaphore =
+            |max: Option<usize>| max.map(|max| Arc::new(Semaphore::new(max)));
+
+        Concurrency {
+            writes: semaphore(config.max_concurrent_writes),
+            merkle: semaphore(config.max_concurrent_merkle),
+            proofs: semaphore(config.max_concurrent_proofs),
+        }
+    }
+
+    /// Returns a permit to run `work`, held until the work is done
+    ///
+    /// Returns `503 Service Unavailable` with `Retry-After` if the limit of
+    /// `work` is reached, there is no permit if it is unbounded.
+    pub(crate) fn acquire(
+        &self,
+        work: Work,
+    ) -> Result<Option<OwnedSemaphorePermit>, ApiError> {
+        let semaphore = match work {
+            Work::Write => &self.writes,
+            Work::Merkle => &self.merkle,
+            Work::Proof => &self.proofs,
+        };
+        let Some(semaphore) = semaphore else {
+            return Ok(None);
+        };
+
+        match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => {
+                let name = work.name();
+                warn!(event = "concurrency limit reached", work = name);
+                Err(ApiError::new(
+                    ErrorCode::Unavailable,
+                    format!("too many concurrent {name}"),
+                )
+                .with_retry_after(RETRY_AFTER_SECS))
+            }
+        }
+    }
+}
+
+/// Refuses upfront the bodies whose `Content-Length` exceeds `max`
+///
+/// Bodies without `Content-Length` are let through, their size is checked
+/// while they are received.
+pub(crate) async fn content_length_limit(
+    State(max): State<Option<u64>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    match (length, max) {
+        (Some(length), Some(max)) if length > max => {
+            ApiError::new(ErrorCode::PayloadTooLarge, "payload too large")
+                .into_response()
+        }
+        _ => next.run(req).await,
+    }
+}
+
+/// Runs the handler of a request, replying `503 Service Unavailable` if it
+/// does not complete within `duration`
+///
+/// Only handlers that can be interrupted at any await point, like the read
+/// handlers, are run this way.
+pub(crate) async fn timeout(
+    State(duration): State<Duration>,
+    req: Request,
+    next: Next,
+) -> Response {
+    match tokio::time::timeout(duration, next.run(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            warn!(event = "request timed out", ?duration);
+            ApiError::new(ErrorCode::Unavailable, "request timed out")
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use axum::middleware;
+    use axum::routing::{get, post};
+    use axum::Router;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limits() {
+        let routes = Router::new().route("/", post(|| async { "done" })).layer(
+            middleware::from_fn_with_state(Some(4), content_length_limit),
+        );
+        let req = |length: &str| {
+            Request::post("/")
+                .header("content-length", length)
+                .body(Body::from(vec![0; length.parse().unwrap()]))
+                .unwrap()
+        };
+        let res = routes.clone().oneshot(req("4")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = routes.oneshot(req("5")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let err = res.extensions().get::<ApiError>().unwrap();
+        assert_eq!(err.code(), ErrorCode::PayloadTooLarge);
+
+        let routes = Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    "done"
+                }),
+            )
+            .route("/fast", get(|| async { "done" }))
+            .layer(middleware::from_fn_with_state(
+                Duration::from_millis(10),
+                timeout,
+            ));
+        let req = |path| Request::get(path).body(Body::empty()).unwrap();
+        let res = routes.clone().oneshot(req("/slow")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = routes.oneshot(req("/fast")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_concurrency() {
+        let config = LimitsConfig {
+            max_upload_size: None,
+            max_upload_rate: None,
+            max_download_rate: None,
+            request_timeout: 30,
+            upload_timeout: 600,
+            upload_session_ttl: 600,
+            resumable_upload_ttl: 86400,
+            max_resumable_uploads: 100,
+            header_timeout: 30,
+            idle_timeout: 60,
+            write_timeout: 60,
+            max_concurrent_writes: None,
+            max_concurrent_merkle: Some(2),
+            max_concurrent_proofs: Some(0),
+        };
+        let concurrency = Concurrency::new(&config);
+
+        // Unbounded work needs no permit
+        assert!(concurrency.acquire(Work::Write).unwrap().is_none());
+
+        let first = concurrency.acquire(Work::Merkle).unwrap();
+        let second = concurrency.acquire(Work::Merkle).unwrap();
+        assert!(first.is_some() && second.is_some());
+        let err = concurrency.acquire(Work::Merkle).unwrap_err();
+        assert_eq!(err.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let reply = err.reply("request");
+        let retry_after = reply.headers().get("retry-after").unwrap();
+        assert_eq!(retry_after, &RETRY_AFTER_SECS.to_string());
+
+        // The permit is given back once the work is done
+        drop(first);
+        assert!(concurrency.acquire(Work::Merkle).is_ok());
+
+        assert!(concurrency.acquire(Work::Proof).is_err());
+    }
+}
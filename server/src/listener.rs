@@ -0,0 +1,265 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper::Request;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::{sleep, Sleep};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::{error, warn};
+
+use crate::limits::LimitsConfig;
+use crate::tls::{self, ClientCert, RemoteAddr};
+
+/// Serves `routes` on `addr`, over HTTPS if `acceptor` is set
+///
+/// Connections are closed when a client is slower than the timeouts of
+/// `limits`: `--header-timeout` to send the headers of a request,
+/// `--idle-timeout` between the bytes of a request and between requests,
+/// and `--write-timeout` to read the bytes of a reply. The address of the
+/// client is passed to the routes as a [`RemoteAddr`] request extension,
+/// along with the fingerprint of its certificate as a [`ClientCert`] if the
+/// acceptor requires one. Once `stopped` completes, no connection is
+/// accepted and the future completes when the open connections have
+/// answered their in-flight requests.
+pub(crate) fn bind<F>(
+    addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    limits: &LimitsConfig,
+    routes: Router,
+    stopped: F,
+) -> (SocketAddr, impl Future<Output = ()>)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let listener = std::net::TcpListener::bind(addr).expect("bindable address");
+    listener.set_nonblocking(true).expect("non blocking socket");
+    let addr = listener.local_addr().expect("bound address");
+    let listener = TcpListener::from_std(listener).expect("tokio listener");
+
+    let mut http = auto::Builder::new(TokioExecutor::new());
+    http.http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(limits.header_timeout())
+        // The client is not read while a request is handled, a slow handler
+        // must not look like a slow client
+        .half_close(true);
+    http.http2()
+        .timer(TokioTimer::new())
+        // The pings answered by HTTP/2 clients are their sign of life
+        .keep_alive_interval(limits.idle_timeout() / 2)
+        .keep_alive_timeout(limits.idle_timeout() / 2);
+    let (idle_timeout, write_timeout) =
+        (limits.idle_timeout(), limits.write_timeout());
+
+    let server = async move {
+        // Each connection holds a receiver, closing it once served
+        let (closing, closing_receiver) = watch::channel(());
+        tokio::pin!(stopped);
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = &mut stopped => break,
+            };
+            let (stream, remote) = match accepted {
+                Ok(conn) => conn,
+                Err(err) => {
+                    error!(event = "failed to accept connection", ?err);
+                    continue;
+                }
+            };
+            // The TLS handshake is bounded as well
+            let stream = Deadlines::new(stream, idle_timeout, write_timeout);
+
+            let acceptor = acceptor.clone();
+            let http = http.clone();
+            let routes = routes.clone();
+            let mut closing = closing_receiver.clone();
+            tokio::spawn(async move {
+                let (stream, cert): (Box<dyn Io>, _) = match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => {
+                            let cert = stream
+                                .get_ref()
+                                .1
+                                .peer_certificates()
+                                .and_then(|certs| certs.first())
+                                .map(|cert| ClientCert(tls::fingerprint(cert)));
+                            (Box::new(stream), cert)
+                        }
+                        Err(err) => {
+                            warn!(event = "TLS handshake failed", %remote, ?err);
+                            return;
+                        }
+                    },
+                    None => (Box::new(stream), None),
+                };
+
+                let service = service_fn(move |mut req: Request<Incoming>| {
+                    if let Some(cert) = &cert {
+                        req.extensions_mut().insert(cert.clone());
+                    }
+                    req.extensions_mut().insert(RemoteAddr(remote));
+                    routes.clone().oneshot(req)
+                });
+                let conn = http.serve_connection(TokioIo::new(stream), service);
+                tokio::pin!(conn);
+                let res = tokio::select! {
+                    res = &mut conn => res,
+                    _ = closing.changed() => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                };
+                if let Err(err) = res {
+                    warn!(event = "connection failed", %remote, ?err);
+                }
+            });
+        }
+
+        drop(listener);
+        drop(closing_receiver);
+        let _ = closing.send(());
+        closing.closed().await;
+    };
+
+    (addr, server)
+}
+
+/// A plain or TLS connection
+trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// A connection failing with [`io::ErrorKind::TimedOut`] once a read waits
+/// for more than `read_timeout` or a write for more than `write_timeout`
+///
+/// The timeouts start over with each progress, only a stalled client is cut.
+struct Deadlines<S> {
+    inner: S,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    /// Deadline of the pending read, if any
+    read: Option<Pin<Box<Sleep>>>,
+    /// Deadline of the pending write, flush or shutdown, if any
+    write: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Deadlines<S> {
+    fn new(inner: S, read_timeout: Duration, write_timeout: Duration) -> Self {
+        Deadlines {
+            inner,
+            read_timeout,
+            write_timeout,
+            read: None,
+            write: None,
+        }
+    }
+}
+
+/// Returns the result of an IO operation, or a timeout once it was pending
+/// for `timeout`
+fn poll_deadline<T>(
+    res: Poll<io::Result<T>>,
+    deadline: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Duration,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<T>> {
+    if res.is_ready() {
+        *deadline = None;
+        return res;
+    }
+
+    let deadline = deadline.get_or_insert_with(|| Box::pin(sleep(timeout)));
+    match deadline.as_mut().poll(cx) {
+        Poll::Ready(()) => Poll::Ready(Err(io::ErrorKind::TimedOut.into())),
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Deadlines<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        poll_deadline(res, &mut this.read, this.read_timeout, cx)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deadlines<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        poll_deadline(res, &mut this.write, this.write_timeout, cx)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.inner).poll_flush(cx);
+        poll_deadline(res, &mut this.write, this.write_timeout, cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let res = Pin::new(&mut this.inner).poll_shutdown(cx);
+        poll_deadline(res, &mut this.write, this.write_timeout, cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_deadlines() {
+        let timeout = Duration::from_millis(200);
+        let (mut client, server) = tokio::io::duplex(4);
+        let mut server = Deadlines::new(server, timeout, timeout);
+
+        // Each byte received in time starts the timeout over
+        let mut buf = [0; 1];
+        for _ in 0..3 {
+            let read = server.read_exact(&mut buf);
+            let write = async {
+                tokio::time::sleep(timeout / 2).await;
+                client.write_all(b"a").await.unwrap();
+            };
+            let (read, ()) = tokio::join!(read, write);
+            read.unwrap();
+        }
+        let err = server.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // A client not reading the reply blocks the writes once the buffer
+        // is full
+        server.write_all(b"abcd").await.unwrap();
+        let err = server.write_all(b"e").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,244 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{Args, ValueEnum};
+use tracing::Subscriber;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+#[derive(Args, Clone, Debug)]
+pub(crate) struct LogConfig {
+    /// Level of the logs (`error`, `warn`, `info`, `debug`, `trace` or
+    /// `off`). The directives of `RUST_LOG` override it, e.g.
+    /// `RUST_LOG=server::app=debug` for the debug logs of a single module
+    #[arg(long, env = "STORAGE_LOG_LEVEL", default_value_t = LevelFilter::INFO)]
+    pub log_level: LevelFilter,
+
+    /// Format of the logs, one JSON object per line or multi-line human
+    /// readable text
+    #[arg(long, env = "STORAGE_LOG_FORMAT", value_enum, default_value_t = LogFormat::Json)]
+    pub log_format: LogFormat,
+
+    /// Write the JSON logs to this file instead of stderr
+    #[arg(long, env = "STORAGE_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Start a new log file every period, named after the log file and
+    /// suffixed with the date of the period
+    #[arg(long, value_enum, default_value_t = LogRotation::Daily)]
+    pub log_rotation: LogRotation,
+
+    /// Start a new log file once the current one would exceed this many
+    /// bytes instead of every period, the previous ones are suffixed with
+    /// `.1`, `.2`, .. from the newest
+    #[arg(long, conflicts_with = "log_rotation")]
+    pub log_max_size: Option<u64>,
+
+    /// Number of previous log files kept, the oldest are deleted
+    #[arg(long, default_value_t = 7)]
+    pub log_max_files: usize,
+}
+
+/// Format of the logs
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub(crate) enum LogFormat {
+    #[default]
+    Json,
+    Pretty,
+}
+
+/// Period of the log files
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub(crate) enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    Never,
+}
+
+/// Returns the filter of the logs, `--log-level` unless overridden by the
+/// directives of `RUST_LOG`
+pub(crate) fn filter(config: &LogConfig) -> EnvFilter {
+    let directives = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default();
+    EnvFilter::builder()
+        .parse_lossy(format!("{},{directives}", config.log_level))
+}
+
+/// Returns the layer writing the logs in `--log-format`
+///
+/// Log files are written by a background thread, the returned guard flushes
+/// the pending logs when dropped.
+pub(crate) fn layer<S>(
+    config: &LogConfig,
+) -> io::Result<(Box<dyn Layer<S> + Send + Sync>, Option<WorkerGuard>)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let (writer, guard) = writer(config)?;
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        // No color codes in the files
+        .with_ansi(config.log_file.is_none());
+
+    let layer = match config.log_format {
+        LogFormat::Json => layer.json().flatten_event(true).boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+    };
+    Ok((layer, guard))
+}
+
+/// Returns the writer of the logs, stderr unless `--log-file` is set
+fn writer(
+    config: &LogConfig,
+) -> io::Result<(BoxMakeWriter, Option<WorkerGuard>)> {
+    let Some(path) = &config.log_file else {
+        return Ok((BoxMakeWriter::new(io::stderr), None));
+    };
+
+    let file: Box<dyn Write + Send> = match config.log_max_size {
+        Some(max_size) => {
+            Box::new(SizeRotating::open(path, max_size, config.log_max_files)?)
+        }
+        None => Box::new(rolling(path, config)?),
+    };
+
+    let (writer, guard) = tracing_appender::non_blocking(file);
+    Ok((BoxMakeWriter::new(writer), Some(guard)))
+}
+
+/// Returns the log file starting over every `--log-rotation` period
+fn rolling(path: &Path, config: &LogConfig) -> io::Result<RollingFileAppender> {
+    let rotation = match config.log_rotation {
+        LogRotation::Minutely => Rotation::MINUTELY,
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let prefix = path
+        .file_name()
+        .ok_or_else(|| io::Error::other("the log file has no name"))?;
+
+    RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(prefix.to_string_lossy())
+        // The current file is one of the kept files
+        .max_log_files(config.log_max_files + 1)
+        .build(directory)
+        .map_err(io::Error::other)
+}
+
+/// A log file moved aside once it would exceed `max_size` bytes
+///
+/// The previous files are named `<path>.1` (the newest) to
+/// `<path>.<max_files>`, a line is never split over two files.
+struct SizeRotating {
+    path: PathBuf,
+    file: File,
+    /// Bytes in the current file
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl SizeRotating {
+    fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(SizeRotating {
+            path: path.to_owned(),
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    /// Returns the path of the `n`th previous file
+    fn previous(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    /// Shifts the previous files, dropping the oldest, and starts a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.max_files).rev() {
+            let from = self.previous(n);
+            if from.exists() {
+                fs::rename(from, self.previous(n + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.previous(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotating {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Each log line is written at once
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_size_rotation() {
+        let dir = TempDir::new("logs").unwrap();
+        let path = dir.path().join("server.log");
+
+        let mut file = SizeRotating::open(&path, 10, 2).unwrap();
+        for line in ["line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        // Two lines do not fit, the oldest line is dropped
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        assert_eq!(read(&path), "line 4\n");
+        assert_eq!(read(&file.previous(1)), "line 3\n");
+        assert_eq!(read(&file.previous(2)), "line 2\n");
+        assert!(!file.previous(3).exists());
+
+        // A line over the limit still goes to a file of its own
+        file.write_all(b"a longer line\n").unwrap();
+        assert_eq!(read(&path), "a longer line\n");
+
+        // Reopening appends to the current file
+        let mut file = SizeRotating::open(&path, 100, 2).unwrap();
+        file.write_all(b"line 5\n").unwrap();
+        assert_eq!(read(&path), "a longer line\nline 5\n");
+    }
+}
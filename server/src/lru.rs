@@ -0,0 +1,63 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Order of use of a set of keys, least recently used first
+pub(crate) struct Lru<K> {
+    tick: u64,
+    ticks: HashMap<K, u64>,
+    keys: BTreeMap<u64, K>,
+}
+
+impl<K: Hash + Eq + Clone> Lru<K> {
+    pub(crate) fn new() -> Self {
+        Lru {
+            tick: 0,
+            ticks: HashMap::new(),
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Marks `key` as the most recently used one
+    pub(crate) fn touch(&mut self, key: &K) {
+        self.tick += 1;
+        if let Some(tick) = self.ticks.insert(key.clone(), self.tick) {
+            self.keys.remove(&tick);
+        }
+        self.keys.insert(self.tick, key.clone());
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.keys.remove(&tick);
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    /// Returns the keys from the least to the most recently used
+    pub(crate) fn oldest(&self) -> impl Iterator<Item = &K> {
+        self.keys.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new();
+        for key in ["a", "b", "c", "d"] {
+            lru.touch(&key);
+        }
+        lru.touch(&"a");
+        lru.touch(&"c");
+        lru.remove(&"d");
+        lru.remove(&"e");
+
+        assert_eq!(lru.len(), 3);
+        assert_eq!(lru.oldest().collect::<Vec<_>>(), [&"b", &"a", &"c"]);
+    }
+}
@@ -1,28 +1,240 @@
+mod access;
+mod accounts;
+mod admin;
+mod anti_entropy;
 mod app;
+mod attestation;
+mod audit;
+mod backup;
+mod batch;
+mod blob_store;
+mod blobs;
+mod capabilities;
 mod client_bucket;
+mod cluster;
+mod compression;
+mod config_file;
+mod copy;
+mod cors;
 mod database;
+mod dav;
+mod disk;
+mod erasure;
+mod errors;
+mod events;
+mod export;
+mod extract;
+mod file_meta;
+mod gc;
+mod grpc;
+mod health;
+mod http3;
+mod jwt;
+mod lifecycle;
+mod limits;
+mod listener;
+mod logging;
+mod lru;
+mod metadata_cipher;
+mod metrics;
+mod naming;
+mod openapi;
+mod ownership;
+mod peers;
+mod presign;
+mod quota;
+mod replication;
+mod resumable;
+mod scrub;
+mod sharded_map;
+mod shutdown;
+mod telemetry;
+mod throttle;
+mod tls;
+mod versions;
+mod webhooks;
 
-use clap::Parser;
-use tracing_subscriber::fmt::Subscriber;
+use std::sync::Arc;
+
+use admin::AdminConfig;
+use attestation::AttestationConfig;
+use blob_store::MemoryStore;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use cluster::ClusterConfig;
+use cors::CorsConfig;
+use disk::DiskConfig;
+use erasure::ErasureConfig;
+use gc::GcConfig;
+use grpc::GrpcConfig;
+use http3::Http3Config;
+use jwt::JwtConfig;
+use lifecycle::LifecycleConfig;
+use limits::LimitsConfig;
+use logging::LogConfig;
+use peers::PeerConfig;
+use presign::PresignConfig;
+use quota::QuotaConfig;
+use scrub::ScrubConfig;
+use shutdown::ShutdownConfig;
+use telemetry::TelemetryConfig;
+use tls::TlsConfig;
+use tracing_subscriber::layer::SubscriberExt;
+use webhooks::WebhookConfig;
 
 #[derive(Parser)]
-struct Config {
+pub(crate) struct Config {
     /// Storage server URL
-    listen_addr: String,
+    #[arg(env = "STORAGE_LISTEN_ADDR", default_value = "127.0.0.1:7878")]
+    pub listen_addr: String,
+
+    /// TOML file of options, named as on the command line. Options given on
+    /// the command line or through their environment variable override it
+    #[arg(long, env = "STORAGE_CONFIG")]
+    pub config: Option<String>,
+
+    #[command(flatten)]
+    pub tls: TlsConfig,
+
+    #[command(flatten)]
+    pub grpc: GrpcConfig,
+
+    #[command(flatten)]
+    pub http3: Http3Config,
+
+    #[command(flatten)]
+    pub cors: CorsConfig,
+
+    #[command(flatten)]
+    pub limits: LimitsConfig,
+
+    #[command(flatten)]
+    pub disk: DiskConfig,
+
+    #[command(flatten)]
+    pub shutdown: ShutdownConfig,
+
+    #[command(flatten)]
+    pub quota: QuotaConfig,
+
+    #[command(flatten)]
+    pub admin: AdminConfig,
+
+    #[command(flatten)]
+    pub jwt: JwtConfig,
+
+    #[command(flatten)]
+    pub presign: PresignConfig,
+
+    #[command(flatten)]
+    pub attestation: AttestationConfig,
+
+    #[command(flatten)]
+    pub lifecycle: LifecycleConfig,
+
+    #[command(flatten)]
+    pub webhooks: WebhookConfig,
+
+    #[command(flatten)]
+    pub gc: GcConfig,
+
+    #[command(flatten)]
+    pub scrub: ScrubConfig,
+
+    #[command(flatten)]
+    pub peers: PeerConfig,
+
+    #[command(flatten)]
+    pub cluster: ClusterConfig,
+
+    #[command(flatten)]
+    pub telemetry: TelemetryConfig,
+
+    #[command(flatten)]
+    pub logging: LogConfig,
+
+    /// Hex encoded 32 bytes key encrypting the bucket records (file paths)
+    /// stored in the database
+    #[arg(long, env = "STORAGE_METADATA_KEY", value_parser = metadata_cipher::parse_key)]
+    pub metadata_key: Option<[u8; 32]>,
+
+    /// Maximum number of buckets kept in memory, the least recently used
+    /// ones are evicted to the database
+    #[arg(long, default_value_t = 10_000)]
+    pub max_resident_buckets: usize,
+
+    /// Number of the latest versions of each bucket whose files stay
+    /// readable with `?version=`, their blobs are kept until the version is
+    /// dropped. Only the roots of the versions are kept if 0
+    #[arg(long, default_value_t = 0)]
+    pub keep_versions: usize,
+
+    /// Folder of the database
+    #[arg(long, env = "STORAGE_DB_DIR", default_value = "./db")]
+    pub db_dir: String,
+
+    /// Folder of the file contents
+    #[arg(long, env = "STORAGE_BLOBS_DIR", default_value = "./buckets_data")]
+    pub blobs_dir: String,
+
+    #[command(flatten)]
+    pub erasure: ErasureConfig,
+
+    /// Folder of the files uploaded by versions storing them per bucket,
+    /// moved to the blobs folder when their bucket is loaded
+    #[arg(long, env = "STORAGE_UPLOADS_DIR", default_value = "./buckets")]
+    pub uploads_dir: String,
+
+    /// Files of at most this many bytes are stored in the database instead
+    /// of the blob store
+    #[arg(long, default_value_t = 64 * 1024)]
+    pub inline_threshold: u64,
+
+    /// Restore the backup in this folder before starting, the database must
+    /// not exist yet
+    #[arg(long)]
+    pub restore_from: Option<String>,
+
+    /// Keep the database and the files in memory, nothing is persisted
+    #[arg(long)]
+    pub in_memory: bool,
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Config::parse();
+    let args = match config_file::args::<Config>(std::env::args_os().collect())
+    {
+        Ok(args) => Config::parse_from(args),
+        Err(err) => Config::command().error(ErrorKind::Io, err).exit(),
+    };
 
-    let s = Subscriber::builder()
-        .with_max_level(tracing::Level::INFO)
-        .with_writer(std::io::stderr);
+    let (logs, _log_guard) =
+        logging::layer(&args.logging).expect("log file is writable");
+
+    let provider = telemetry::tracer_provider(&args.telemetry)
+        .expect("valid OTLP endpoint");
+    let otel = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(telemetry::tracer(provider))
+    });
 
     tracing::subscriber::set_global_default(
-        s.json().flatten_event(true).finish(),
+        tracing_subscriber::registry()
+            .with(logging::filter(&args.logging))
+            .with(logs)
+            .with(otel),
     )
     .expect("valid default subscriber");
 
-    app::run_server(&args.listen_addr).await;
+    if args.in_memory {
+        let store = Arc::new(MemoryStore::new());
+        let (_, server) = app::run_server_with_store(args, store).await;
+        server.await;
+    } else {
+        app::run_server(args).await;
+    }
+
+    // Flush the spans still batched
+    if let Some(provider) = provider {
+        let _ = provider.shutdown();
+    }
 }
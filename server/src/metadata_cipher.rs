@@ -0,0 +1,122 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+
+/// Prefix of an encrypted database value
+///
+/// A plain bincode bucket record starts with the 8 bytes length of the
+/// bucket id, which can't realistically be equal to this prefix.
+const ENCRYPTED_TAG: &[u8; 8] = b"ENCMETA1";
+const NONCE_LEN: usize = 12;
+
+/// Encrypts database values with a server key, so that a copy of the database
+/// does not reveal the tenants' file paths
+///
+/// The database key is bound to the value as associated data, so an
+/// encrypted value can't be moved under another key.
+pub(crate) struct MetadataCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl MetadataCipher {
+    pub(crate) fn new(key: &[u8; 32]) -> Self {
+        MetadataCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    pub(crate) fn encrypt(&self, db_key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: value,
+                    aad: db_key,
+                },
+            )
+            .expect("valid encryption");
+
+        let mut encrypted = Vec::with_capacity(
+            ENCRYPTED_TAG.len() + NONCE_LEN + ciphertext.len(),
+        );
+        encrypted.extend_from_slice(ENCRYPTED_TAG);
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        encrypted
+    }
+
+    /// Decrypts a database value
+    ///
+    /// Values written before encryption was enabled are returned as they are
+    pub(crate) fn decrypt(
+        &self,
+        db_key: &[u8],
+        value: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let Some(encrypted) = value.strip_prefix(ENCRYPTED_TAG) else {
+            return Ok(value.to_vec());
+        };
+
+        if encrypted.len() < NONCE_LEN {
+            return Err("Truncated encrypted value".to_string());
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: db_key,
+                },
+            )
+            .map_err(|_| "Failed to decrypt value".to_string())
+    }
+
+    /// Returns true if the value was written encrypted
+    pub(crate) fn is_encrypted(value: &[u8]) -> bool {
+        value.starts_with(ENCRYPTED_TAG)
+    }
+}
+
+/// Parses a hex encoded 32 bytes key
+pub(crate) fn parse_key(s: &str) -> Result<[u8; 32], String> {
+    let bytes = hex::decode(s.trim()).map_err(|err| err.to_string())?;
+    bytes
+        .try_into()
+        .map_err(|_| "the key must be 32 bytes long".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_cipher() {
+        let cipher = MetadataCipher::new(&[7u8; 32]);
+
+        let encrypted = cipher.encrypt(b"bucket", b"./buckets/bucket/file");
+        assert!(MetadataCipher::is_encrypted(&encrypted));
+        assert_eq!(
+            cipher.decrypt(b"bucket", &encrypted).unwrap(),
+            b"./buckets/bucket/file"
+        );
+
+        // The value is bound to its database key
+        assert!(cipher.decrypt(b"other", &encrypted).is_err());
+
+        // Wrong server key
+        let other = MetadataCipher::new(&[8u8; 32]);
+        assert!(other.decrypt(b"bucket", &encrypted).is_err());
+
+        // Plain values are passed through
+        assert_eq!(cipher.decrypt(b"bucket", b"plain").unwrap(), b"plain");
+
+        assert!(parse_key(&hex::encode([1u8; 32])).is_ok());
+        assert!(parse_key("0102").is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::Method;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use utoipa::OpenApi;
+
+use crate::app::ServerState;
+use crate::openapi;
+
+/// Upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 13] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+    10.0,
+];
+/// Upper bounds of the buckets of the number of leaves of a Merkle tree
+const LEAVES_BUCKETS: [f64; 6] =
+    [10.0, 100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0];
+
+/// Route label of the requests matching no route
+const UNMATCHED: &str = "unmatched";
+/// Route label of the routes missing from the OpenAPI document
+const OTHER: &str = "other";
+
+/// Distribution of observed values over fixed buckets
+struct Histogram {
+    /// Upper bounds of the buckets, increasing
+    bounds: &'static [f64],
+    /// Number of values in each bucket, not cumulated
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Writes the histogram in the Prometheus text format, `labels` being
+    /// empty or a list of `name="value"` pairs
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulated = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulated += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {cumulated}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count);
+    }
+}
+
+/// Labels of a request latency histogram
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct RequestLabels {
+    route: String,
+    method: String,
+    status: u16,
+}
+
+/// Latency and outcome of the requests, and duration of the Merkle tree
+/// recomputations, exposed by GET /metrics
+pub(crate) struct Metrics {
+    /// Route labels of the requests, the templates of the OpenAPI document
+    routes: Routes,
+    requests: Mutex<BTreeMap<RequestLabels, Histogram>>,
+    /// Merkle tree recomputations of `complete_upload`, in seconds
+    merkle_seconds: Mutex<Histogram>,
+    /// Number of leaves of the recomputed trees
+    merkle_leaves: Mutex<Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            routes: Routes::new(&openapi::document()),
+            requests: Mutex::default(),
+            merkle_seconds: Mutex::new(Histogram::new(&LATENCY_BUCKETS)),
+            merkle_leaves: Mutex::new(Histogram::new(&LEAVES_BUCKETS)),
+        }
+    }
+}
+
+impl Metrics {
+    /// Records a request answered with `status` after `elapsed`
+    pub(crate) fn observe_request(
+        &self,
+        route: &str,
+        method: &Method,
+        status: u16,
+        elapsed: Duration,
+    ) {
+        let labels = RequestLabels {
+            route: route.to_owned(),
+            method: method.to_string(),
+            status,
+        };
+        self.requests
+            .lock()
+            .expect("unpoisoned lock")
+            .entry(labels)
+            .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Records the recomputation of a Merkle tree of `leaves_count` leaves
+    pub(crate) fn observe_merkle(
+        &self,
+        elapsed: Duration,
+        leaves_count: usize,
+    ) {
+        self.merkle_seconds
+            .lock()
+            .expect("unpoisoned lock")
+            .observe(elapsed.as_secs_f64());
+        self.merkle_leaves
+            .lock()
+            .expect("unpoisoned lock")
+            .observe(leaves_count as f64);
+    }
+
+    /// Returns the metrics in the Prometheus text format
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP http_request_duration_seconds Time to the reply headers \
+             of the requests, by route, method and status\n\
+             # TYPE http_request_duration_seconds histogram\n",
+        );
+        for (labels, histogram) in
+            self.requests.lock().expect("unpoisoned lock").iter()
+        {
+            let labels = format!(
+                "route=\"{}\",method=\"{}\",status=\"{}\"",
+                labels.route, labels.method, labels.status
+            );
+            histogram.render(
+                &mut out,
+                "http_request_duration_seconds",
+                &labels,
+            );
+        }
+
+        out.push_str(
+            "# HELP merkle_recompute_duration_seconds Time to recompute the \
+             Merkle tree of a bucket on complete_upload\n\
+             # TYPE merkle_recompute_duration_seconds histogram\n",
+        );
+        self.merkle_seconds.lock().expect("unpoisoned lock").render(
+            &mut out,
+            "merkle_recompute_duration_seconds",
+            "",
+        );
+
+        out.push_str(
+            "# HELP merkle_recompute_leaves Number of leaves of the Merkle \
+             trees recomputed on complete_upload\n\
+             # TYPE merkle_recompute_leaves histogram\n",
+        );
+        self.merkle_leaves.lock().expect("unpoisoned lock").render(
+            &mut out,
+            "merkle_recompute_leaves",
+            "",
+        );
+
+        out
+    }
+}
+
+/// Path templates of the documented routes, the route labels
+struct Routes {
+    /// The templates with their segments, `None` for a path parameter
+    templates: Vec<(String, Vec<Option<String>>)>,
+}
+
+impl Routes {
+    fn new(document: &utoipa::openapi::OpenApi) -> Self {
+        let templates = document
+            .paths
+            .paths
+            .keys()
+            .map(|template| {
+                let segments = segments(template)
+                    .map(|segment| {
+                        (!segment.starts_with('{')).then(|| segment.to_owned())
+                    })
+                    .collect();
+                (template.clone(), segments)
+            })
+            .collect();
+
+        Routes { templates }
+    }
+
+    /// Returns the template matching `path`, `other` if none does
+    fn label(&self, path: &str) -> &str {
+        let path: Vec<&str> = segments(path).collect();
+        self.templates
+            .iter()
+            .find(|(_, template)| {
+                template.len() == path.len()
+                    && template.iter().zip(&path).all(|(expected, segment)| {
+                        expected
+                            .as_deref()
+                            .is_none_or(|expected| expected == *segment)
+                    })
+            })
+            .map_or(OTHER, |(template, _)| template.as_str())
+    }
+}
+
+/// Returns the non-empty segments of a path
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// Records the latency and the outcome of the requests
+///
+/// A request is labelled with the template of its route in the OpenAPI
+/// document, so bucket ids and file names do not make new series. The
+/// requests matching no route, answered by the fallback, have no matched
+/// path.
+pub(crate) async fn instrument(
+    State(state): State<Arc<ServerState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let method = req.method().clone();
+    let route = match req.extensions().get::<MatchedPath>() {
+        Some(_) => state.metrics.routes.label(req.uri().path()),
+        None => UNMATCHED,
+    };
+
+    let res = next.run(req).await;
+    state.metrics.observe_request(
+        route,
+        &method,
+        res.status().as_u16(),
+        started.elapsed(),
+    );
+    res
+}
+
+/// Metrics route of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_metrics))]
+pub(crate) struct ApiDoc;
+
+/// Returns the metrics route, which requires no credentials
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    // Metrics in the Prometheus text format
+    // GET /metrics
+    Router::new()
+        .route("/metrics", get(handle_metrics))
+        .with_state(state)
+}
+
+/// Handles metrics request
+///
+/// The error rate of a route is the share of its requests counted with a
+/// `4xx` or `5xx` status
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "server",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+    ),
+)]
+async fn handle_metrics(
+    State(state): State<Arc<ServerState>>,
+) -> impl IntoResponse {
+    let mut metrics = state.metrics.render();
+    state.disk.render(&mut metrics);
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app;
+    use crate::blob_store::MemoryStore;
+    use crate::Config;
+    use axum::body::Body;
+    use axum::http::Request;
+    use clap::Parser;
+    use http_body_util::BodyExt;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+
+    #[test]
+    fn test_route_labels() {
+        let routes = Routes::new(&openapi::document());
+        assert_eq!(routes.label("/file/b/0"), "/file/{bucket_id}/{file_index}");
+        assert_eq!(routes.label("/admin/gc"), "/admin/gc");
+        assert_eq!(routes.label("/healthz/"), "/healthz");
+        assert_eq!(routes.label("/dav/b/a.txt"), OTHER);
+        assert_eq!(routes.label("/file/b"), OTHER);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let config = Config::parse_from(["server", "127.0.0.1:0"]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = app::run_server_with_store(config, store).await;
+        tokio::spawn(server);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let request = |method: &str, path: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("http://{addr}{path}"))
+                .body(Body::from("a"))
+                .unwrap();
+            client.request(request)
+        };
+
+        request("POST", "/upload_file/a/a.txt").await.unwrap();
+        request("POST", "/complete_upload/a").await.unwrap();
+        request("GET", "/file/a/1").await.unwrap();
+        request("GET", "/nothing").await.unwrap();
+
+        let res = request("GET", "/metrics").await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let metrics = String::from_utf8(body.to_vec()).unwrap();
+
+        // Number of requests of a route answered with `status`
+        let count = |route: &str, method: &str, status: u16| {
+            let prefix = format!(
+                "http_request_duration_seconds_count{{route=\"{route}\",\
+                 method=\"{method}\",status=\"{status}\"}} "
+            );
+            metrics
+                .lines()
+                .find_map(|line| line.strip_prefix(&prefix))
+                .map(str::to_owned)
+        };
+        let upload = "/upload_file/{bucket_id}/{filename}";
+        assert_eq!(count(upload, "POST", 200).as_deref(), Some("1"));
+        let file = "/file/{bucket_id}/{file_index}";
+        assert_eq!(count(file, "GET", 404).as_deref(), Some("1"));
+        assert_eq!(count(UNMATCHED, "GET", 404).as_deref(), Some("1"));
+
+        assert!(metrics.contains("merkle_recompute_duration_seconds_count 1"));
+        let leaves = "merkle_recompute_leaves_bucket{le=\"10\"} 1";
+        assert!(metrics.contains(leaves));
+    }
+}
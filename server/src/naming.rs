@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::client_bucket::ClientBucket;
+use crate::errors::{ApiError, ErrorCode};
+
+/// Naming rules of the files of a bucket, set through the admin API
+#[derive(
+    Clone, Debug, Default, PartialEq, Serialize, Deserialize, ToSchema,
+)]
+pub(crate) struct NamingPolicy {
+    /// What an upload does when the bucket holds another file of the same
+    /// name
+    #[serde(default)]
+    pub duplicates: DuplicateNames,
+}
+
+/// Handling of a file named like another file of its bucket
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DuplicateNames {
+    /// The upload is refused with `409 Conflict`
+    #[default]
+    Reject,
+    /// The file replaces the other one, its leaf replaces the other leaf in
+    /// the Merkle tree
+    Overwrite,
+    /// The file is renamed `<name> (<n>).<extension>` with the first free
+    /// `n`, both files are leaves of the Merkle tree
+    Suffix,
+}
+
+/// Where a file goes in its bucket
+#[derive(Debug, PartialEq)]
+pub(crate) enum Placement {
+    /// The file is added under this name
+    New(String),
+    /// The file replaces the file of this hash, under the same name
+    Replace(String, [u8; 32]),
+}
+
+/// Returns where a file named `filename` goes in `bucket` under `duplicates`
+pub(crate) fn place(
+    bucket: &ClientBucket,
+    filename: String,
+    duplicates: DuplicateNames,
+) -> Result<Placement, ApiError> {
+    let Some(existing) = find(bucket, &filename) else {
+        return Ok(Placement::New(filename));
+    };
+
+    match duplicates {
+        DuplicateNames::Reject => Err(ApiError::new(
+            ErrorCode::DuplicateFilename,
+            "another file of the bucket has this name",
+        )
+        .with_detail("filename", filename)),
+        DuplicateNames::Overwrite => Ok(Placement::Replace(filename, existing)),
+        DuplicateNames::Suffix => {
+            let free = (1..)
+                .map(|n| suffixed(&filename, n))
+                .find(|name| find(bucket, name).is_none())
+                .expect("a free name");
+            Ok(Placement::New(free))
+        }
+    }
+}
+
+/// Returns the hash of the file named `filename`, if any
+fn find(bucket: &ClientBucket, filename: &str) -> Option<[u8; 32]> {
+    bucket
+        .files
+        .iter()
+        .find(|(_, name)| *name == filename)
+        .map(|(hash, _)| *hash)
+}
+
+/// Returns `filename` with ` (<n>)` before its extension
+fn suffixed(filename: &str, n: usize) -> String {
+    match filename.rsplit_once('.') {
+        // A leading dot starts a hidden name, not an extension
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{stem} ({n}).{extension}")
+        }
+        _ => format!("{filename} ({n})"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_place() {
+        let mut bucket = ClientBucket::new("bucket_id".to_string());
+        bucket.files.insert([1u8; 32], "a.txt".to_string());
+        bucket.files.insert([2u8; 32], "a (1).txt".to_string());
+
+        let place = |filename: &str, duplicates| {
+            place(&bucket, filename.to_string(), duplicates)
+        };
+        assert_eq!(
+            place("b.txt", DuplicateNames::Reject).unwrap(),
+            Placement::New("b.txt".to_string())
+        );
+        let err = place("a.txt", DuplicateNames::Reject).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::DuplicateFilename);
+        assert_eq!(
+            place("a.txt", DuplicateNames::Overwrite).unwrap(),
+            Placement::Replace("a.txt".to_string(), [1u8; 32])
+        );
+        assert_eq!(
+            place("a.txt", DuplicateNames::Suffix).unwrap(),
+            Placement::New("a (2).txt".to_string())
+        );
+
+        assert_eq!(suffixed("notes", 1), "notes (1)");
+        assert_eq!(suffixed(".env", 1), ".env (1)");
+        assert_eq!(suffixed("a.tar.gz", 3), "a.tar (3).gz");
+    }
+}
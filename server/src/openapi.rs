@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::http::header::{CONTENT_TYPE, LOCATION};
+use axum::http::{StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use tracing::error;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::Config as SwaggerConfig;
+
+use crate::errors::{ApiError, ErrorCode};
+use crate::extract::Json;
+use crate::{accounts, resumable};
+use crate::{admin, anti_entropy, app, attestation, audit, batch, copy};
+use crate::{
+    events, export, file_meta, health, metrics, ownership, peers, presign,
+};
+use crate::{replication, versions};
+
+/// Path of the OpenAPI document, loaded by the Swagger UI
+const OPENAPI_PATH: &str = "/openapi.json";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "storage-server",
+        description = "Stores files in buckets, each with a Merkle tree \
+                       proving the files it holds"
+    ),
+    modifiers(&Security),
+    tags(
+        (name = "files", description = "Uploads, downloads and deletions"),
+        (name = "uploads", description = "Uploads in chunks and upload sessions"),
+        (name = "buckets", description = "State and history of a bucket"),
+        (name = "proofs", description = "Merkle proofs of the files"),
+        (name = "server", description = "Capabilities and probes"),
+        (name = "accounts", description = "User accounts owning buckets"),
+        (name = "admin", description = "Administration, with `--admin-token`"),
+        (name = "peer", description = "Requests between the servers, with \
+                                       `--peer-token`"),
+    )
+)]
+struct ApiDoc;
+
+/// Declares the bearer credentials of the routes
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components =
+            openapi.components.get_or_insert_with(Default::default);
+        let schemes = [
+            ("api_key", "API key or JWT granting access to the bucket"),
+            ("account_key", "Key of the account"),
+            ("admin_token", "The `--admin-token` of the server"),
+            ("peer_token", "The `--peer-token` of the servers"),
+        ];
+        for (name, description) in schemes {
+            let scheme = HttpBuilder::new()
+                .scheme(HttpAuthScheme::Bearer)
+                .description(Some(description))
+                .build();
+            components.add_security_scheme(name, SecurityScheme::Http(scheme));
+        }
+    }
+}
+
+/// Returns the OpenAPI document of all the routes
+pub(crate) fn document() -> utoipa::openapi::OpenApi {
+    let mut document = ApiDoc::openapi();
+    // The package has no license, utoipa would declare an empty one
+    document.info.license = None;
+    for routes in [
+        app::ApiDoc::openapi(),
+        resumable::ApiDoc::openapi(),
+        batch::ApiDoc::openapi(),
+        copy::ApiDoc::openapi(),
+        export::ApiDoc::openapi(),
+        file_meta::ApiDoc::openapi(),
+        presign::ApiDoc::openapi(),
+        ownership::ApiDoc::openapi(),
+        audit::ApiDoc::openapi(),
+        events::ApiDoc::openapi(),
+        health::ApiDoc::openapi(),
+        metrics::ApiDoc::openapi(),
+        attestation::ApiDoc::openapi(),
+        admin::ApiDoc::openapi(),
+        accounts::ApiDoc::openapi(),
+        peers::ApiDoc::openapi(),
+        replication::ApiDoc::openapi(),
+        anti_entropy::ApiDoc::openapi(),
+        versions::ApiDoc::openapi(),
+    ] {
+        document.merge(routes);
+    }
+    document
+}
+
+/// Returns the routes of the OpenAPI document and of the Swagger UI, which
+/// require no credentials
+pub(crate) fn routes() -> Router {
+    let document = Arc::new(document());
+    let config = Arc::new(SwaggerConfig::from(OPENAPI_PATH));
+
+    Router::new()
+        // OpenAPI document
+        // GET /openapi.json
+        .route(
+            OPENAPI_PATH,
+            get(move || async move { Json(document.clone()) }),
+        )
+        // Swagger UI
+        // GET /docs
+        .route(
+            "/docs",
+            get(|| async { (StatusCode::FOUND, [(LOCATION, "/docs/")]) }),
+        )
+        .route("/docs/", get(handle_docs))
+        .route("/docs/*file", get(handle_docs))
+        .with_state(config)
+}
+
+/// Handles Swagger UI request
+///
+/// `/docs` is redirected to `/docs/`, so the files of the UI are loaded
+/// relative to it
+async fn handle_docs(
+    uri: Uri,
+    State(config): State<Arc<SwaggerConfig<'static>>>,
+) -> Result<Response, ApiError> {
+    let file = uri.path().strip_prefix("/docs/").unwrap_or_default();
+    match utoipa_swagger_ui::serve(file, config) {
+        Ok(Some(file)) => Ok((
+            [(CONTENT_TYPE, file.content_type)],
+            file.bytes.into_owned(),
+        )
+            .into_response()),
+        Ok(None) => Err(ApiError::new(
+            ErrorCode::NotFound,
+            format!("{} not found", uri.path()),
+        )),
+        Err(err) => {
+            error!(event = "failed to serve swagger ui", err);
+            Err(ApiError::internal())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document() {
+        let document = serde_json::to_value(document()).unwrap();
+        let paths = document["paths"].as_object().unwrap();
+
+        // Routes of every module, several methods of a path are merged
+        let file = &paths["/file/{bucket_id}/{file_index}"];
+        assert!(file["get"].is_object() && file["delete"].is_object());
+        let bucket = &paths["/peer/buckets/{bucket_id}"];
+        assert!(bucket["get"].is_object() && bucket["put"].is_object());
+        for path in ["/upload_chunk/{upload_id}", "/admin/keys", "/readyz"] {
+            assert!(paths.contains_key(path), "{path}");
+        }
+
+        let schemas = &document["components"]["schemas"];
+        assert!(schemas["ErrorBody"].is_object());
+        assert!(schemas["BucketRoot"].is_object());
+        let schemes = &document["components"]["securitySchemes"];
+        assert_eq!(schemes["api_key"]["scheme"], "bearer");
+    }
+}
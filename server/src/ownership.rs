@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::async_trait;
+use axum::extract::{DefaultBodyLimit, FromRequestParts, State};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{post, put};
+use axum::{middleware, Router};
+use ed25519_dalek::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::access::{self, Operation};
+use crate::app::{self, ServerState};
+use crate::errors::{ApiError, ErrorBody, ErrorCode};
+use crate::extract::{self, Json, Path};
+
+/// Header carrying the hex encoded ed25519 signature of a request
+pub(crate) const SIGNATURE_HEADER: &str = "x-signature";
+/// Header carrying the nonce covered by the signature, as
+/// `<unix seconds>:<random>`
+pub(crate) const NONCE_HEADER: &str = "x-signature-nonce";
+/// Seconds a nonce is accepted after, or before, its timestamp
+const NONCE_WINDOW: u64 = 300;
+
+/// Bucket operations requiring the signature of the owner
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Action {
+    Upload,
+    Complete,
+    DeleteFile,
+    DeleteBucket,
+    BeginUpload,
+    AbortUpload,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Upload => "upload",
+            Action::Complete => "complete",
+            Action::DeleteFile => "delete_file",
+            Action::DeleteBucket => "delete_bucket",
+            Action::BeginUpload => "begin_upload",
+            Action::AbortUpload => "abort_upload",
+        }
+    }
+}
+
+/// Signature headers of a request
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Signed {
+    signature: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Signed {
+    pub(crate) fn new(
+        signature: Option<String>,
+        nonce: Option<String>,
+    ) -> Self {
+        Signed { signature, nonce }
+    }
+}
+
+/// Public key registered by POST /owner/:bucket_id
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+struct Owner {
+    /// Hex encoded ed25519 public key
+    public_key: String,
+}
+
+/// Returns the message signed by the owner of a bucket for an operation
+///
+/// The file name and hash are empty for the operations on the whole bucket.
+pub(crate) fn message(
+    action: Action,
+    bucket_id: &str,
+    filename: &str,
+    hash: Option<&[u8; 32]>,
+    nonce: &str,
+) -> String {
+    let hash = hash.map(hex::encode).unwrap_or_default();
+    format!(
+        "storage-signature-v1\n{}\n{bucket_id}\n{filename}\n{hash}\n{nonce}",
+        action.as_str()
+    )
+}
+
+/// Extracts the signature headers, a request whose headers are not valid
+/// is unsigned
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Signed {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Infallible> {
+        let header = |name| extract::optional_header(&parts.headers, name);
+        match (header(SIGNATURE_HEADER), header(NONCE_HEADER)) {
+            (Ok(signature), Ok(nonce)) => Ok(Signed { signature, nonce }),
+            _ => Ok(Signed::default()),
+        }
+    }
+}
+
+/// Nonces seen within [`NONCE_WINDOW`], so a signed request cannot be
+/// replayed
+#[derive(Default)]
+pub(crate) struct Nonces {
+    /// Map `<bucket_id>/<nonce>` to the timestamp of the nonce
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl Nonces {
+    /// Records a nonce, returns false if it is reused or out of the window
+    fn check(&self, bucket_id: &str, nonce: &str, now: u64) -> bool {
+        let Some(timestamp) = nonce
+            .split_once(':')
+            .and_then(|(timestamp, _)| timestamp.parse::<u64>().ok())
+        else {
+            return false;
+        };
+        if timestamp.abs_diff(now) > NONCE_WINDOW {
+            return false;
+        }
+
+        let mut seen = self.seen.lock().expect("unpoisoned lock");
+        seen.retain(|_, timestamp| timestamp.abs_diff(now) <= NONCE_WINDOW);
+        seen.insert(format!("{bucket_id}/{nonce}"), timestamp)
+            .is_none()
+    }
+}
+
+/// Checks that a request on a bucket is signed by the owner of the bucket
+///
+/// Buckets without a registered owner accept any request.
+pub(crate) async fn verify(
+    state: &ServerState,
+    action: Action,
+    bucket_id: &str,
+    filename: &str,
+    hash: Option<&[u8; 32]>,
+    signed: &Signed,
+) -> Result<(), ApiError> {
+    let owner =
+        state
+            .db
+            .read()
+            .await
+            .bucket_owner(bucket_id)
+            .map_err(|err| {
+                tracing::error!(event = "failed to read owner", err);
+                ApiError::internal()
+            })?;
+    let Some(owner) = owner else {
+        return Ok(());
+    };
+
+    let (Some(signature), Some(nonce)) = (&signed.signature, &signed.nonce)
+    else {
+        warn!(
+            event = "unsigned request",
+            bucket_id,
+            action = action.as_str()
+        );
+        return Err(invalid_signature());
+    };
+    let signature = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(invalid_signature)?;
+    let key =
+        VerifyingKey::from_bytes(&owner).map_err(|_| invalid_signature())?;
+
+    let message = message(action, bucket_id, filename, hash, nonce);
+    if key.verify_strict(message.as_bytes(), &signature).is_err() {
+        warn!(
+            event = "invalid signature",
+            bucket_id,
+            action = action.as_str()
+        );
+        return Err(invalid_signature());
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    if !state.nonces.check(bucket_id, nonce, now) {
+        warn!(event = "rejected nonce", bucket_id, nonce);
+        return Err(invalid_signature());
+    }
+
+    Ok(())
+}
+
+/// Returns the error of a request missing the signature of the owner
+pub(crate) fn invalid_signature() -> ApiError {
+    ApiError::new(ErrorCode::InvalidSignature, "invalid signature")
+}
+
+/// Owner registration routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_register_owner, handle_claim))]
+pub(crate) struct ApiDoc;
+
+/// Returns the route registering the owner of a bucket
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    // Register the public key owning a bucket
+    // POST /owner/:bucket_id
+    Router::new()
+        .route("/owner/:bucket_id", post(handle_register_owner))
+        .route_layer(DefaultBodyLimit::max(1024))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Write),
+            access::bucket_access,
+        ))
+        .with_state(state)
+}
+
+/// Returns the owner route of the admin API, nested in /admin
+pub(crate) fn admin_routes(state: Arc<ServerState>) -> Router {
+    // Set the owner of a bucket, even one with files
+    // PUT /admin/owner/:bucket_id
+    Router::new()
+        .route("/owner/:bucket_id", put(handle_claim))
+        .route_layer(DefaultBodyLimit::max(1024))
+        .with_state(state)
+}
+
+/// Handles owner registration request
+///
+/// A bucket is claimed while it has no files, it is created if it does not
+/// exist yet. Registering the key of the owner again is accepted, any other
+/// key is refused with `409 Conflict`. Buckets which already have files are
+/// claimed through `PUT /admin/owner/:bucket_id`.
+#[utoipa::path(
+    post,
+    path = "/owner/{bucket_id}",
+    tag = "buckets",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    request_body = Owner,
+    responses(
+        (status = 200, description = "Owner registered", body = Owner),
+        (status = 400, description = "Invalid public key", body = ErrorBody),
+        (status = 409, description = "The bucket has another owner or has files", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_register_owner(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    Json(owner): Json<Owner>,
+) -> Result<Response, ApiError> {
+    let key = public_key(&owner)?;
+
+    // The bucket stays locked from the check of its files to the
+    // registration, uploads verify the owner under the same lock
+    let bucket =
+        app::get_or_create_bucket(bucket_id.clone(), state.clone()).await?;
+    let bucket = bucket.write().await;
+    let has_files = !bucket.files.is_empty();
+
+    let db = state.db.write().await;
+    match db.bucket_owner(&bucket_id) {
+        Ok(Some(current)) if current == key => {}
+        Ok(Some(_)) => {
+            return Err(ApiError::new(
+                ErrorCode::Conflict,
+                "bucket has an owner",
+            ))
+        }
+        Ok(None) if has_files => {
+            return Err(ApiError::new(ErrorCode::Conflict, "bucket has files"))
+        }
+        Ok(None) => {
+            if let Err(err) = db
+                .set_bucket_owner(&bucket_id, &key)
+                .and_then(|_| db.flush())
+            {
+                tracing::error!(event = "failed to register owner", err);
+                return Err(ApiError::internal());
+            }
+            info!(
+                event = "bucket owner registered",
+                bucket_id,
+                public_key = owner.public_key
+            );
+        }
+        Err(err) => {
+            tracing::error!(event = "failed to read owner", err);
+            return Err(ApiError::internal());
+        }
+    }
+
+    Ok(Json(owner).into_response())
+}
+
+/// Handles owner claim request
+///
+/// Sets the owner of a bucket whatever its files and its current owner, so
+/// the buckets filled before they were claimed can be given to an owner.
+#[utoipa::path(
+    put,
+    path = "/admin/owner/{bucket_id}",
+    tag = "admin",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    request_body = Owner,
+    responses(
+        (status = 200, description = "Owner set", body = Owner),
+        (status = 400, description = "Invalid public key", body = ErrorBody),
+        (status = 401, description = "Missing or invalid admin token", body = ErrorBody),
+    ),
+    security(("admin_token" = [])),
+)]
+async fn handle_claim(
+    Path(bucket_id): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    Json(owner): Json<Owner>,
+) -> Result<Response, ApiError> {
+    let key = public_key(&owner)?;
+
+    let bucket =
+        app::get_or_create_bucket(bucket_id.clone(), state.clone()).await?;
+    let _bucket = bucket.write().await;
+
+    let db = state.db.write().await;
+    if let Err(err) = db
+        .set_bucket_owner(&bucket_id, &key)
+        .and_then(|_| db.flush())
+    {
+        tracing::error!(event = "failed to claim bucket", err);
+        return Err(ApiError::internal());
+    }
+    info!(
+        event = "bucket claimed",
+        bucket_id,
+        public_key = owner.public_key
+    );
+
+    Ok(Json(owner).into_response())
+}
+
+/// Returns the ed25519 key of an owner
+fn public_key(owner: &Owner) -> Result<[u8; 32], ApiError> {
+    hex::decode(&owner.public_key)
+        .ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .filter(|key| VerifyingKey::from_bytes(key).is_ok())
+        .ok_or_else(|| ApiError::bad_request("invalid public key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use crate::database::DB;
+    use crate::Config;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use clap::Parser;
+    use ed25519_dalek::{Signer, SigningKey};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_message() {
+        let signing_key = SigningKey::from_bytes(&[7u8; 32]);
+        let message =
+            message(Action::Upload, "bucket", "file", Some(&[1u8; 32]), "1:a");
+        let signature = signing_key.sign(message.as_bytes());
+
+        let key = signing_key.verifying_key();
+        assert!(key.verify_strict(message.as_bytes(), &signature).is_ok());
+
+        // The signature is bound to the operation
+        let other = super::message(
+            Action::DeleteFile,
+            "bucket",
+            "file",
+            Some(&[1u8; 32]),
+            "1:a",
+        );
+        assert!(key.verify_strict(other.as_bytes(), &signature).is_err());
+    }
+
+    #[test]
+    fn test_nonces() {
+        let nonces = Nonces::default();
+        let now = 1_000_000;
+
+        assert!(nonces.check("bucket", "1000000:a", now));
+        assert!(!nonces.check("bucket", "1000000:a", now));
+        assert!(nonces.check("other", "1000000:a", now));
+        assert!(nonces.check("bucket", "999900:b", now));
+
+        // Out of the window or malformed
+        assert!(!nonces.check("bucket", "999000:c", now));
+        assert!(!nonces.check("bucket", "1001000:c", now));
+        assert!(!nonces.check("bucket", "c", now));
+
+        // Expired nonces are forgotten
+        assert!(nonces.check("bucket", "1000400:d", now + 400));
+        assert_eq!(nonces.seen.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_register_owner() {
+        let config = Config::parse_from([
+            "server",
+            "127.0.0.1:0",
+            "--admin-token",
+            "admin",
+        ]);
+        let store = Arc::new(MemoryStore::new());
+        let state =
+            Arc::new(ServerState::open(&config, DB::in_memory(), store).await);
+        let routes = app::routes(config, state.clone());
+        let request = |method: &str, path: &str, body: String| {
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .header("authorization", "Bearer admin")
+                .body(Body::from(body))
+                .unwrap();
+            routes.clone().oneshot(request)
+        };
+        let owner = |seed: u8| {
+            let key = SigningKey::from_bytes(&[seed; 32]).verifying_key();
+            serde_json::json!({ "public_key": hex::encode(key.as_bytes()) })
+                .to_string()
+        };
+
+        // A bucket is created with its owner, which may register again
+        let res = request("POST", "/owner/a", owner(1)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(state.bucket(&"a".to_owned()).await.unwrap().is_some());
+        let res = request("POST", "/owner/a", owner(1)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("POST", "/owner/a", owner(2)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // A bucket with files is claimed through the admin API
+        let res = request("POST", "/upload_file/b/b.txt", "b".into())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request("POST", "/owner/b", owner(1)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = request("PUT", "/admin/owner/b", owner(1)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let db = state.db.clone();
+        let key = SigningKey::from_bytes(&[1; 32]).verifying_key();
+        let registered = db.read().await.bucket_owner("b").unwrap();
+        assert_eq!(registered, Some(key.to_bytes()));
+
+        // Unsigned uploads are refused once the bucket is owned
+        let res = request("POST", "/upload_file/b/c.txt", "c".into())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
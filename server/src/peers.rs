@@ -0,0 +1,202 @@
+use std::io;
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::header::CONTENT_LENGTH;
+use axum::http::request::Builder;
+use axum::http::{Method, Request, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{middleware, Router};
+use clap::Args;
+use http_body_util::BodyExt;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+use utoipa::OpenApi;
+
+use crate::access;
+use crate::anti_entropy;
+use crate::app::ServerState;
+use crate::blob_store::BlobStore;
+use crate::blobs;
+use crate::errors::{ApiError, ErrorBody, ErrorCode};
+use crate::extract::Path;
+use crate::replication;
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct PeerConfig {
+    /// URLs of the peer servers holding copies of the blobs, corrupted blobs
+    /// are repaired from them
+    #[arg(long = "peer", value_delimiter = ',')]
+    pub peers: Vec<String>,
+
+    /// Bearer token authenticating the requests between peers, the /peer
+    /// routes are disabled if unset
+    #[arg(long, env = "STORAGE_PEER_TOKEN")]
+    pub peer_token: Option<String>,
+
+    /// URLs of the replica servers receiving the uploads and the bucket
+    /// states of this server
+    #[arg(long = "replica", value_delimiter = ',')]
+    pub replicas: Vec<String>,
+
+    /// Seconds between two reconciliations with the peers, taking the
+    /// buckets whose last root is more recent on a peer
+    #[arg(long)]
+    pub anti_entropy_interval: Option<u64>,
+}
+
+impl PeerConfig {
+    /// Returns a request to a peer, carrying the peer token
+    pub(crate) fn request(&self, method: Method, url: &str) -> Builder {
+        let req = Request::builder().method(method).uri(url);
+        match &self.peer_token {
+            Some(token) => {
+                req.header("Authorization", format!("Bearer {token}"))
+            }
+            None => req,
+        }
+    }
+
+    /// Downloads the blob of `hash` from the first peer holding a valid copy
+    /// into the blob `tmp_key`
+    ///
+    /// Returns false if no peer has a copy matching the hash.
+    pub(crate) async fn fetch_blob(
+        &self,
+        store: &dyn BlobStore,
+        hash: &[u8; 32],
+        tmp_key: &str,
+    ) -> bool {
+        for peer in &self.peers {
+            match self.fetch_blob_from(peer, store, hash, tmp_key).await {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(err) => {
+                    let key = blobs::blob_key(hash);
+                    warn!(event = "failed to fetch blob", peer, key, ?err);
+                }
+            }
+        }
+
+        let _ = store.delete(tmp_key).await;
+        false
+    }
+
+    /// Downloads the blob of `hash` from `peer` into the blob `tmp_key`
+    ///
+    /// Returns false if the peer has no copy matching the hash.
+    pub(crate) async fn fetch_blob_from(
+        &self,
+        peer: &str,
+        store: &dyn BlobStore,
+        hash: &[u8; 32],
+        tmp_key: &str,
+    ) -> io::Result<bool> {
+        let url = format!("{}/peer/blobs/{}", peer, hex::encode(hash));
+        let req = self
+            .request(Method::GET, &url)
+            .body(Body::empty())
+            .map_err(io::Error::other)?;
+
+        let res = Client::builder(TokioExecutor::new())
+            .build_http()
+            .request(req)
+            .await
+            .map_err(io::Error::other)?;
+        match res.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND => return Ok(false),
+            status => {
+                return Err(io::Error::other(format!("status {status}")));
+            }
+        }
+
+        let mut file = store.create(tmp_key).await?;
+        let mut hasher = Sha256::new();
+        let mut body = res.into_body();
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(io::Error::other)?;
+            if let Some(chunk) = frame.data_ref() {
+                hasher.update(chunk);
+                file.write_all(chunk).await?;
+            }
+        }
+        file.flush().await?;
+
+        // The copy of the peer may be corrupted as well
+        let actual: [u8; 32] = hasher.finalize().into();
+        if actual != *hash {
+            let key = blobs::blob_key(hash);
+            warn!(event = "corrupted peer blob", peer, key);
+            return Ok(false);
+        }
+
+        info!(event = "blob fetched", peer, key = blobs::blob_key(hash));
+        Ok(true)
+    }
+}
+
+/// Peer routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_download_blob))]
+pub(crate) struct ApiDoc;
+
+/// Returns the /peer route group, authenticated with `--peer-token`
+///
+/// It serves the repairs and the reconciliations of the peers and applies
+/// the pushes of a primary
+pub(crate) fn routes(config: PeerConfig, state: Arc<ServerState>) -> Router {
+    // Download a blob by its hash
+    // GET /peer/blobs/:hex_hash
+    Router::new()
+        .route("/peer/blobs/:hex_hash", get(handle_download_blob))
+        .with_state(state.clone())
+        .merge(replication::routes(state.clone()))
+        .merge(anti_entropy::routes(state))
+        .route_layer(middleware::from_fn_with_state(
+            config.peer_token,
+            access::bearer_auth,
+        ))
+}
+
+/// Handles blob download request of a peer
+///
+/// Returns `404 Not Found` if the blob is not stored
+#[utoipa::path(
+    get,
+    path = "/peer/blobs/{hex_hash}",
+    tag = "peer",
+    params(("hex_hash" = String, Path, description = "Hex encoded SHA-256 of the blob")),
+    responses(
+        (status = 200, description = "Content of the blob", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, description = "The blob is not stored", body = ErrorBody),
+    ),
+    security(("peer_token" = [])),
+)]
+async fn handle_download_blob(
+    Path(hex_hash): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let hash: [u8; 32] = hex::decode(&hex_hash)
+        .ok()
+        .and_then(|hash| hash.try_into().ok())
+        .ok_or_else(|| blob_not_found(&hex_hash))?;
+
+    let (size, stream) = {
+        let db = state.db.read().await;
+        blobs::stream(&*state.store, &db, &hash)
+            .await
+            .map_err(|_| blob_not_found(&hex_hash))?
+    };
+
+    Ok(([(CONTENT_LENGTH, size)], Body::from_stream(stream)))
+}
+
+fn blob_not_found(hex_hash: &str) -> ApiError {
+    ApiError::new(ErrorCode::NotFound, format!("blob {hex_hash} not found"))
+}
@@ -0,0 +1,297 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::{RawPathParams, Request, State};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use clap::Args;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::info;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::access::{self, AccessDenied, Operation};
+use crate::app::{self, ServerState};
+use crate::errors::{ApiError, ErrorBody};
+use crate::extract::{Json, Path, Query};
+use crate::metadata_cipher;
+
+/// Lifetime of a pre-signed URL when the request does not set one
+const DEFAULT_EXPIRES_IN: u64 = 3600;
+/// Longest lifetime of a pre-signed URL
+const MAX_EXPIRES_IN: u64 = 7 * 24 * 3600;
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct PresignConfig {
+    /// Hex encoded 32 bytes key signing the pre-signed download URLs, random
+    /// by default so URLs do not survive a restart. Nodes of a cluster must
+    /// share it
+    #[arg(long, env = "STORAGE_PRESIGN_KEY", value_parser = metadata_cipher::parse_key)]
+    pub presign_key: Option<[u8; 32]>,
+}
+
+/// Signs and verifies the pre-signed download URLs
+pub(crate) struct Presigner {
+    key: [u8; 32],
+}
+
+impl Presigner {
+    pub(crate) fn new(config: &PresignConfig) -> Self {
+        let key = config.presign_key.unwrap_or_else(|| {
+            let mut key = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            key
+        });
+
+        Presigner { key }
+    }
+
+    /// Returns the hex encoded signature of the download of a file until
+    /// `expires`
+    ///
+    /// The signature covers the hash of the file, so a URL does not serve
+    /// another file once its index is reused.
+    fn sign(
+        &self,
+        bucket_id: &str,
+        index: usize,
+        hash: &[u8; 32],
+        expires: u64,
+    ) -> String {
+        hex::encode(
+            self.mac(bucket_id, index, hash, expires)
+                .finalize()
+                .into_bytes(),
+        )
+    }
+
+    /// Returns true if `sig` signs the download of a file until `expires`,
+    /// which is not past
+    fn verify(
+        &self,
+        bucket_id: &str,
+        index: usize,
+        hash: &[u8; 32],
+        query: &PresignedQuery,
+    ) -> bool {
+        let Ok(sig) = hex::decode(&query.sig) else {
+            return false;
+        };
+
+        query.expires >= now()
+            && self
+                .mac(bucket_id, index, hash, query.expires)
+                .verify_slice(&sig)
+                .is_ok()
+    }
+
+    fn mac(
+        &self,
+        bucket_id: &str,
+        index: usize,
+        hash: &[u8; 32],
+        expires: u64,
+    ) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key)
+            .expect("HMAC accepts any key size");
+        let message = format!(
+            "GET\n/file/{bucket_id}/{index}\n{}\n{expires}",
+            hex::encode(hash)
+        );
+        mac.update(message.as_bytes());
+        mac
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Lifetime requested for a pre-signed URL
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PresignRequest {
+    /// Seconds the URL is valid, 3600 by default and 7 days at most
+    expires_in: Option<u64>,
+}
+
+/// Query of a pre-signed download URL
+#[derive(Deserialize)]
+struct PresignedQuery {
+    /// Unix timestamp after which the URL is refused
+    expires: u64,
+    /// Hex encoded HMAC-SHA256 of the download
+    sig: String,
+}
+
+/// Reply of the pre-sign request
+#[derive(Serialize, ToSchema)]
+struct PresignedUrl {
+    /// Path and query of the download, relative to the server URL
+    url: String,
+    expires: u64,
+}
+
+/// Pre-sign route of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_presign))]
+pub(crate) struct ApiDoc;
+
+/// Returns the pre-sign route
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    // Pre-signed URL of a file
+    // POST /presign/:bucket_id/:file_index?expires_in=
+    Router::new()
+        .route("/presign/:bucket_id/:file_index", post(handle_presign))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Read),
+            access::bucket_access,
+        ))
+        .with_state(state)
+}
+
+/// Handles pre-sign request
+///
+/// Returns `404 Not Found` if the (bucket_id-file_index) does not exist,
+/// `400 Bad Request` if `expires_in` exceeds 7 days
+#[utoipa::path(
+    post,
+    path = "/presign/{bucket_id}/{file_index}",
+    tag = "files",
+    params(("bucket_id" = String, Path, description = "Id of the bucket"), ("file_index" = usize, Path, description = "Leaf index of the file"), PresignRequest),
+    responses(
+        (status = 200, description = "Pre-signed download URL", body = PresignedUrl),
+        (status = 400, description = "`expires_in` exceeds 7 days", body = ErrorBody),
+        (status = 404, description = "The bucket or the file does not exist", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_presign(
+    Path((bucket_id, file_index)): Path<(String, usize)>,
+    Query(request): Query<PresignRequest>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<PresignedUrl>, ApiError> {
+    let expires_in = request.expires_in.unwrap_or(DEFAULT_EXPIRES_IN);
+    if expires_in > MAX_EXPIRES_IN {
+        return Err(ApiError::bad_request("expires_in exceeds 7 days")
+            .with_detail("max", MAX_EXPIRES_IN));
+    }
+
+    let hash = file_hash(&bucket_id, file_index, &state)
+        .await
+        .ok_or_else(|| ApiError::file_not_found(file_index))?;
+    let expires = now() + expires_in;
+    let sig = state.presigner.sign(&bucket_id, file_index, &hash, expires);
+
+    info!(event = "download presigned", bucket_id, file_index, expires);
+    Ok(Json(PresignedUrl {
+        url: format!(
+            "/file/{bucket_id}/{file_index}?expires={expires}&sig={sig}"
+        ),
+        expires,
+    }))
+}
+
+/// Serves the downloads through a pre-signed URL, layered on the download
+/// route
+///
+/// The access rules of the bucket are skipped, but not its rate limit.
+/// Requests without the pre-signed query are left to the download route.
+pub(crate) async fn presigned_download(
+    State(state): State<Arc<ServerState>>,
+    params: RawPathParams,
+    req: Request,
+    next: Next,
+) -> Response {
+    let bucket_id = access::path_param(&params, "bucket_id").to_owned();
+    let file_index = access::path_param(&params, "file_index").parse();
+    let query = axum::extract::Query::<PresignedQuery>::try_from_uri(req.uri());
+    let (Ok(file_index), Ok(axum::extract::Query(query))) = (file_index, query)
+    else {
+        return next.run(req).await;
+    };
+
+    handle_presigned_download(bucket_id, file_index, query, state)
+        .await
+        .unwrap_or_else(IntoResponse::into_response)
+}
+
+/// Handles download request through a pre-signed URL
+///
+/// Returns `401 Unauthorized` if the URL is expired or its signature is
+/// invalid
+async fn handle_presigned_download(
+    bucket_id: String,
+    file_index: usize,
+    query: PresignedQuery,
+    state: Arc<ServerState>,
+) -> Result<Response, ApiError> {
+    let hash = file_hash(&bucket_id, file_index, &state)
+        .await
+        .ok_or(AccessDenied::Unauthorized)?;
+    {
+        if !state
+            .presigner
+            .verify(&bucket_id, file_index, &hash, &query)
+        {
+            return Err(AccessDenied::Unauthorized.into());
+        }
+        state.access.rate_limit(&bucket_id)?;
+    }
+
+    app::handle_download_file(bucket_id, file_index.to_string(), None, state)
+        .await
+        .map(IntoResponse::into_response)
+}
+
+async fn file_hash(
+    bucket_id: &str,
+    file_index: usize,
+    state: &Arc<ServerState>,
+) -> Option<[u8; 32]> {
+    let bucket = app::get_bucket(bucket_id.to_owned(), state.clone()).await?;
+    let hash = bucket.read().await.get_file_hash(file_index);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presigner() {
+        let presigner = Presigner::new(&PresignConfig::default());
+        let hash = [1u8; 32];
+        let expires = now() + 60;
+
+        let query = PresignedQuery {
+            expires,
+            sig: presigner.sign("bucket", 0, &hash, expires),
+        };
+        assert!(presigner.verify("bucket", 0, &hash, &query));
+        assert!(!presigner.verify("bucket", 1, &hash, &query));
+        assert!(!presigner.verify("other", 0, &hash, &query));
+        assert!(!presigner.verify("bucket", 0, &[2u8; 32], &query));
+
+        // Another key, a changed expiration, or an expired URL
+        let other = Presigner::new(&PresignConfig::default());
+        assert!(!other.verify("bucket", 0, &hash, &query));
+        let extended = PresignedQuery {
+            expires: expires + 1,
+            sig: query.sig.clone(),
+        };
+        assert!(!presigner.verify("bucket", 0, &hash, &extended));
+        let expired = PresignedQuery {
+            expires: 1,
+            sig: presigner.sign("bucket", 0, &hash, 1),
+        };
+        assert!(!presigner.verify("bucket", 0, &hash, &expired));
+    }
+}
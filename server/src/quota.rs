@@ -0,0 +1,222 @@
+use axum::body::Body;
+use axum::http::{Method, Request, StatusCode};
+use clap::Args;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use tracing::{error, warn};
+
+use crate::client_bucket::ClientBucket;
+use crate::errors::{ApiError, ErrorCode};
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct QuotaConfig {
+    /// Maximum number of bytes stored per bucket
+    #[arg(long)]
+    pub quota_bytes: Option<u64>,
+
+    /// Maximum number of files stored per bucket
+    #[arg(long)]
+    pub quota_files: Option<usize>,
+
+    /// Usage thresholds, in percent of the quota, that trigger an alert
+    #[arg(long, value_delimiter = ',', default_value = "80,95")]
+    pub quota_alert_thresholds: Vec<u8>,
+
+    /// Webhook URL notified when a bucket crosses a usage threshold
+    #[arg(long)]
+    pub quota_webhook: Option<String>,
+}
+
+/// Current usage of a bucket compared to the configured quota
+#[derive(Clone, serde::Serialize, utoipa::ToSchema)]
+pub(crate) struct Usage {
+    pub bucket_id: String,
+    pub files_count: usize,
+    pub bytes_used: u64,
+    pub quota_bytes: Option<u64>,
+    pub quota_files: Option<usize>,
+    pub usage_percent: Option<f64>,
+}
+
+impl Usage {
+    /// Returns the usage of a bucket compared to `quota_bytes` and
+    /// `quota_files`
+    pub(crate) fn new(
+        bucket: &ClientBucket,
+        quota_bytes: Option<u64>,
+        quota_files: Option<usize>,
+    ) -> Self {
+        Usage {
+            bucket_id: bucket.bucket_id.clone(),
+            files_count: bucket.files.len(),
+            bytes_used: bucket.bytes_used,
+            quota_bytes,
+            quota_files,
+            usage_percent: quota_bytes
+                .filter(|quota| *quota > 0)
+                .map(|quota| bucket.bytes_used as f64 * 100.0 / quota as f64),
+        }
+    }
+
+    /// Returns the quota a new file of `file_size` bytes would exceed
+    pub(crate) fn exceeded_by(&self, file_size: u64) -> Option<QuotaExceeded> {
+        let bytes = self.bytes_used.saturating_add(file_size);
+        if self.quota_bytes.is_some_and(|quota| bytes > quota) {
+            return Some(QuotaExceeded::Bytes);
+        }
+        if self
+            .quota_files
+            .is_some_and(|quota| self.files_count >= quota)
+        {
+            return Some(QuotaExceeded::Files);
+        }
+        None
+    }
+}
+
+/// Quota refusing a new file
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QuotaExceeded {
+    /// The file does not fit in the bytes left to the bucket
+    Bytes,
+    /// The bucket holds as many files as allowed
+    Files,
+}
+
+impl QuotaExceeded {
+    /// Returns `413 Payload Too Large` for the bytes quota and `429 Too Many
+    /// Requests` for the files quota, with the current usage of the bucket
+    pub(crate) fn error(self, usage: &Usage) -> ApiError {
+        let status = match self {
+            QuotaExceeded::Bytes => StatusCode::PAYLOAD_TOO_LARGE,
+            QuotaExceeded::Files => StatusCode::TOO_MANY_REQUESTS,
+        };
+
+        ApiError::new(ErrorCode::QuotaExceeded, "quota exceeded")
+            .with_status(status)
+            .with_detail("quota", self)
+            .with_detail("usage", usage)
+    }
+}
+
+impl QuotaConfig {
+    /// Returns the thresholds of `quota` crossed when a bucket grows from
+    /// `before` to `after` bytes
+    pub(crate) fn crossed_thresholds(
+        &self,
+        quota: Option<u64>,
+        before: u64,
+        after: u64,
+    ) -> Vec<u8> {
+        let Some(quota) = quota.filter(|quota| *quota > 0) else {
+            return vec![];
+        };
+
+        self.quota_alert_thresholds
+            .iter()
+            .copied()
+            .filter(|threshold| {
+                let limit = quota as u128 * *threshold as u128 / 100;
+                (before as u128) < limit && (after as u128) >= limit
+            })
+            .collect()
+    }
+
+    /// Fires an alert for every threshold crossed by the last bucket update
+    ///
+    /// Alerts are always logged and, if configured, posted to the webhook
+    pub(crate) fn alert(&self, usage: &Usage, before: u64) {
+        let crossed = self.crossed_thresholds(
+            usage.quota_bytes,
+            before,
+            usage.bytes_used,
+        );
+
+        for threshold in crossed {
+            warn!(
+                event = "quota threshold crossed",
+                bucket_id = usage.bucket_id,
+                threshold,
+                bytes_used = usage.bytes_used,
+                quota_bytes = usage.quota_bytes,
+            );
+
+            if let Some(url) = self.quota_webhook.clone() {
+                let payload = serde_json::json!({
+                    "event": "quota_threshold_crossed",
+                    "threshold": threshold,
+                    "usage": usage,
+                });
+                tokio::spawn(notify_webhook(url, payload.to_string()));
+            }
+        }
+    }
+}
+
+/// Posts a JSON payload to a webhook
+async fn notify_webhook(url: String, payload: String) {
+    let req = match Request::builder()
+        .method(Method::POST)
+        .uri(&url)
+        .header("Content-Type", "application/json")
+        .body(Body::from(payload))
+    {
+        Ok(req) => req,
+        Err(err) => {
+            error!(event = "invalid webhook request", url, ?err);
+            return;
+        }
+    };
+
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    match client.request(req).await {
+        Ok(res) if res.status().is_success() => {}
+        Ok(res) => {
+            error!(event = "webhook failed", url, status = %res.status())
+        }
+        Err(err) => error!(event = "webhook failed", url, ?err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crossed_thresholds() {
+        let config = QuotaConfig {
+            quota_bytes: Some(1000),
+            quota_files: None,
+            quota_alert_thresholds: vec![80, 95],
+            quota_webhook: None,
+        };
+        let quota = config.quota_bytes;
+
+        assert!(config.crossed_thresholds(quota, 0, 799).is_empty());
+        assert_eq!(config.crossed_thresholds(quota, 0, 800), vec![80]);
+        assert_eq!(config.crossed_thresholds(quota, 799, 1000), vec![80, 95]);
+        assert_eq!(config.crossed_thresholds(quota, 800, 950), vec![95]);
+        assert!(config.crossed_thresholds(quota, 950, 2000).is_empty());
+
+        // No quota configured
+        assert!(config.crossed_thresholds(None, 0, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_exceeded_by() {
+        let mut bucket = ClientBucket::new("bucket".to_string());
+        bucket.bytes_used = 900;
+        bucket.files.insert([0u8; 32], "file".to_string());
+
+        let usage = Usage::new(&bucket, None, None);
+        assert!(usage.exceeded_by(u64::MAX).is_none());
+
+        let usage = Usage::new(&bucket, Some(1000), Some(2));
+        assert!(usage.exceeded_by(100).is_none());
+        assert!(matches!(usage.exceeded_by(101), Some(QuotaExceeded::Bytes)));
+
+        let usage = Usage::new(&bucket, Some(1000), Some(1));
+        assert!(matches!(usage.exceeded_by(0), Some(QuotaExceeded::Files)));
+    }
+}
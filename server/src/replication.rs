@@ -0,0 +1,673 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::put;
+use axum::Router;
+use bytes::{Buf, Bytes};
+use http_body_util::BodyExt;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio_stream::StreamExt;
+use tracing::{error, info, warn};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::app::{get_or_create_bucket, ServerState};
+use crate::audit::{self, AuditOp, AuditRecord, Peer};
+use crate::blob_store::BlobStore;
+use crate::blobs;
+use crate::client_bucket::{BucketRoot, BucketState, ClientBucket};
+use crate::errors::{ApiError, ErrorBody, ErrorCode};
+use crate::events::Event;
+use crate::extract::{Json, Path};
+use crate::peers::PeerConfig;
+
+/// Delay before pushing again to an unreachable replica, and between two
+/// scans of an idle queue
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// State of a bucket pushed by the primary to its replicas
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub(crate) struct ReplicatedBucket {
+    /// Map the hex encoded file hash to the file name
+    pub files: BTreeMap<String, String>,
+    pub bytes_used: u64,
+}
+
+impl ReplicatedBucket {
+    /// Returns the files keyed by their hash, `None` if a hash is invalid
+    pub(crate) fn decode_files(&self) -> Option<BTreeMap<[u8; 32], String>> {
+        self.files
+            .iter()
+            .map(|(hex_hash, filename)| {
+                let hash = hex::decode(hex_hash).ok()?.try_into().ok()?;
+                Some((hash, filename.clone()))
+            })
+            .collect()
+    }
+}
+
+impl From<&ClientBucket> for ReplicatedBucket {
+    fn from(bucket: &ClientBucket) -> Self {
+        ReplicatedBucket {
+            files: bucket
+                .files
+                .iter()
+                .map(|(hash, filename)| (hex::encode(hash), filename.clone()))
+                .collect(),
+            bytes_used: bucket.bytes_used,
+        }
+    }
+}
+
+/// Outcome of the last push to a replica
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ReplicaProgress {
+    /// Unix time in seconds of the last time the queue was emptied
+    pub caught_up_at: Option<u64>,
+    /// Error of the last push, if it failed
+    pub last_error: Option<String>,
+}
+
+/// Replication lag of a replica, as reported by GET /admin/replication
+#[derive(Debug, Serialize, ToSchema)]
+pub(crate) struct ReplicaStatus {
+    pub replica: String,
+    /// Buckets waiting to be pushed to the replica
+    pub queued: Vec<String>,
+    /// Unix time in seconds of the last time the replica was up to date,
+    /// unknown until it catches up after startup
+    pub caught_up_at: Option<u64>,
+    /// Error of the last push, if it failed
+    pub last_error: Option<String>,
+}
+
+/// Blobs a replica lacks to apply a bucket state
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct MissingBlobs {
+    /// Hex encoded hashes
+    missing: Vec<String>,
+}
+
+/// Queues a bucket to be pushed to all the replicas
+///
+/// Called once the bucket is persisted. The queue is stored in the database
+/// so replicas unreachable for a while, or a restart, catch up from it.
+pub(crate) async fn enqueue(state: &ServerState, bucket_id: &str) {
+    if state.peers.replicas.is_empty() {
+        return;
+    }
+
+    let db = state.db.read().await;
+    for replica in &state.peers.replicas {
+        if let Err(err) = db.queue_replication(replica, bucket_id) {
+            error!(event = "failed to queue replication", replica, err);
+        }
+    }
+    state.replication.notify_waiters();
+}
+
+/// Spawns a task pushing the queued buckets to each replica
+pub(crate) fn spawn(state: Arc<ServerState>, config: &PeerConfig) {
+    for replica in config.replicas.clone() {
+        tokio::spawn(run(state.clone(), replica));
+    }
+}
+
+async fn run(state: Arc<ServerState>, replica: String) {
+    let notify = state.replication.clone();
+
+    loop {
+        // Register before the scan so no queued bucket is missed
+        let mut notified = pin!(notify.notified());
+        notified.as_mut().enable();
+
+        let pushed = push_queue(&state, &replica).await;
+        record_progress(&state, &replica, &pushed);
+        match pushed {
+            Ok(()) => {
+                let _ = tokio::time::timeout(RETRY_INTERVAL, notified).await;
+            }
+            Err(err) => {
+                warn!(event = "replica unreachable", replica, ?err);
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Records the outcome of a push to a replica
+fn record_progress(
+    state: &ServerState,
+    replica: &str,
+    pushed: &io::Result<()>,
+) {
+    let mut status = state.replication_status.lock().expect("unpoisoned lock");
+    let progress = status.entry(replica.to_owned()).or_default();
+    match pushed {
+        Ok(()) => {
+            progress.caught_up_at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .ok();
+            progress.last_error = None;
+        }
+        Err(err) => progress.last_error = Some(err.to_string()),
+    }
+}
+
+/// Returns the replication lag of each replica
+pub(crate) async fn status(
+    state: &ServerState,
+) -> Result<Vec<ReplicaStatus>, String> {
+    let queues = {
+        let db = state.db.read().await;
+        state
+            .peers
+            .replicas
+            .iter()
+            .map(|replica| db.replication_queue(replica))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    let progress = state.replication_status.lock().expect("unpoisoned lock");
+    Ok(state
+        .peers
+        .replicas
+        .iter()
+        .zip(queues)
+        .map(|(replica, queue)| {
+            let progress = progress.get(replica).cloned().unwrap_or_default();
+            ReplicaStatus {
+                replica: replica.clone(),
+                queued: queue
+                    .into_iter()
+                    .map(|(bucket_id, _)| bucket_id)
+                    .collect(),
+                caught_up_at: progress.caught_up_at,
+                last_error: progress.last_error,
+            }
+        })
+        .collect())
+}
+
+/// Pushes the buckets queued for a replica, oldest entries first
+async fn push_queue(state: &ServerState, replica: &str) -> io::Result<()> {
+    let queue = {
+        let db = state.db.read().await;
+        db.replication_queue(replica).map_err(io::Error::other)?
+    };
+
+    for (bucket_id, seq) in queue {
+        push_bucket(state, replica, &bucket_id).await?;
+
+        let db = state.db.write().await;
+        db.dequeue_replication(replica, &bucket_id, seq)
+            .map_err(io::Error::other)?;
+        info!(event = "bucket replicated", replica, bucket_id);
+    }
+
+    Ok(())
+}
+
+/// Pushes the current state of a bucket, or its deletion, to a replica
+///
+/// The blobs the replica lacks are pushed before the state is pushed again.
+async fn push_bucket(
+    state: &ServerState,
+    replica: &str,
+    bucket_id: &str,
+) -> io::Result<()> {
+    let url = format!("{replica}/peer/buckets/{bucket_id}");
+
+    // A bucket failing to load is not deleted from the replica
+    let (peers, store, bucket) = {
+        let bucket = state.bucket(&bucket_id.to_owned()).await;
+        (state.peers.clone(), state.store.clone(), bucket)
+    };
+    let Some(bucket) = bucket.map_err(io::Error::other)? else {
+        let (status, _) =
+            send(&peers, Method::DELETE, &url, Body::empty()).await?;
+        return match status {
+            StatusCode::OK | StatusCode::NOT_FOUND => Ok(()),
+            status => Err(io::Error::other(format!("status {status}"))),
+        };
+    };
+    let body =
+        serde_json::to_vec(&ReplicatedBucket::from(&*bucket.read().await))
+            .map_err(io::Error::other)?;
+
+    let (status, reply) =
+        send(&peers, Method::PUT, &url, Body::from(body.clone())).await?;
+    match status {
+        StatusCode::OK => return Ok(()),
+        StatusCode::CONFLICT => {}
+        status => return Err(io::Error::other(format!("status {status}"))),
+    }
+
+    let missing: MissingBlobs =
+        serde_json::from_slice(&reply).map_err(io::Error::other)?;
+    for hex_hash in missing.missing {
+        let hash: [u8; 32] = hex::decode(&hex_hash)
+            .ok()
+            .and_then(|hash| hash.try_into().ok())
+            .ok_or_else(|| io::Error::other("invalid missing hash"))?;
+
+        // A blob released meanwhile is no longer part of the bucket state
+        let (_, stream) = {
+            let db = state.db.read().await;
+            match blobs::stream(&*store, &db, &hash).await {
+                Ok(blob) => blob,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            }
+        };
+        let url = format!("{replica}/peer/blobs/{hex_hash}");
+        let (status, _) =
+            send(&peers, Method::PUT, &url, Body::from_stream(stream)).await?;
+        if status != StatusCode::OK {
+            return Err(io::Error::other(format!("status {status}")));
+        }
+    }
+
+    // The bucket may have changed meanwhile, it is queued again then
+    let (status, _) = send(&peers, Method::PUT, &url, Body::from(body)).await?;
+    match status {
+        StatusCode::OK => Ok(()),
+        status => Err(io::Error::other(format!("status {status}"))),
+    }
+}
+
+/// Sends a request to a peer, returning the status and the body of the
+/// response
+async fn send(
+    peers: &PeerConfig,
+    method: Method,
+    url: &str,
+    body: Body,
+) -> io::Result<(StatusCode, Bytes)> {
+    let req = peers
+        .request(method, url)
+        .body(body)
+        .map_err(io::Error::other)?;
+    let res = Client::builder(TokioExecutor::new())
+        .build_http()
+        .request(req)
+        .await
+        .map_err(io::Error::other)?;
+    let status = res.status();
+    let body = res
+        .into_body()
+        .collect()
+        .await
+        .map_err(io::Error::other)?
+        .to_bytes();
+
+    Ok((status, body))
+}
+
+/// Replication routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_put_blob, handle_put_bucket, handle_delete_bucket,))]
+pub(crate) struct ApiDoc;
+
+/// Returns the routes applying the pushes of a primary, nested in the /peer
+/// route group
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    Router::new()
+        // Receive a blob, kept aside until a bucket state refers to it
+        // PUT /peer/blobs/:hex_hash
+        .route("/peer/blobs/:hex_hash", put(handle_put_blob))
+        // Apply the state of a bucket
+        // PUT /peer/buckets/:bucket_id
+        // Apply the deletion of a bucket
+        // DELETE /peer/buckets/:bucket_id
+        .route(
+            "/peer/buckets/:bucket_id",
+            put(handle_put_bucket).delete(handle_delete_bucket),
+        )
+        .with_state(state)
+}
+
+/// Handles a blob pushed by the primary
+///
+/// The blob is stored at [`blobs::replica_key`] once its hash is verified.
+#[utoipa::path(
+    put,
+    path = "/peer/blobs/{hex_hash}",
+    tag = "peer",
+    params(("hex_hash" = String, Path, description = "Hex encoded SHA-256 of the blob")),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Blob stored", body = String, content_type = "text/plain"),
+        (status = 400, description = "The content does not match the hash", body = ErrorBody),
+    ),
+    security(("peer_token" = [])),
+)]
+async fn handle_put_blob(
+    Path(hex_hash): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let hash: [u8; 32] = hex::decode(&hex_hash)
+        .ok()
+        .and_then(|hash| hash.try_into().ok())
+        .ok_or_else(|| ApiError::bad_request("invalid blob hash"))?;
+
+    let store = state.store.clone();
+    let tmp_key = blobs::tmp_key();
+    let mut body = body.into_data_stream();
+    let res = async {
+        let mut file = store.create(&tmp_key).await?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = body.next().await {
+            let mut chunk = chunk.map_err(io::Error::other)?;
+            while chunk.has_remaining() {
+                hasher.update(chunk.chunk());
+                file.write_all(chunk.chunk()).await?;
+                chunk.advance(chunk.chunk().len());
+            }
+        }
+        file.flush().await?;
+        Ok::<[u8; 32], io::Error>(hasher.finalize().into())
+    };
+
+    let err = match res.await {
+        Ok(actual) if actual == hash => {
+            match store.rename(&tmp_key, &blobs::replica_key(&hash)).await {
+                Ok(()) => return Ok(reply("Blob stored", StatusCode::OK)),
+                Err(err) => {
+                    error!(event = "failed to store blob", hex_hash, ?err);
+                    ApiError::internal()
+                }
+            }
+        }
+        Ok(_) => ApiError::bad_request("hash mismatch"),
+        Err(err) => {
+            error!(event = "failed to receive blob", hex_hash, ?err);
+            ApiError::bad_request("failed to receive blob")
+        }
+    };
+    let _ = store.delete(&tmp_key).await;
+
+    Err(err)
+}
+
+/// Handles a bucket state pushed by the primary
+///
+/// Replies `409 Conflict` with the hashes of the blobs to push first if the
+/// replica lacks some of them.
+#[utoipa::path(
+    put,
+    path = "/peer/buckets/{bucket_id}",
+    tag = "peer",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    request_body = ReplicatedBucket,
+    responses(
+        (status = 200, description = "Merkle root of the bucket", body = BucketRoot),
+        (status = 409, description = "The replica lacks the `missing` blobs", body = ErrorBody),
+    ),
+    security(("peer_token" = [])),
+)]
+async fn handle_put_bucket(
+    Path(bucket_id): Path<String>,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+    Json(replicated): Json<ReplicatedBucket>,
+) -> Result<Json<BucketRoot>, ApiError> {
+    let Some(files) = replicated.decode_files() else {
+        return Err(ApiError::bad_request("invalid file hash"));
+    };
+
+    let applied =
+        apply_bucket(&state, &bucket_id, files, replicated.bytes_used, peer)
+            .await;
+    match applied {
+        Ok(Applied::Done(root)) => Ok(Json(root)),
+        Ok(Applied::Missing(hashes)) => {
+            let missing: Vec<String> = hashes.iter().map(hex::encode).collect();
+            Err(ApiError::new(ErrorCode::MissingBlobs, "missing blobs")
+                .with_detail("missing", missing))
+        }
+        Err(()) => Err(ApiError::internal()),
+    }
+}
+
+/// Outcome of [`apply_bucket`]
+pub(crate) enum Applied {
+    /// The state was applied, with the resulting root
+    Done(BucketRoot),
+    /// Blobs to store at [`blobs::replica_key`] before applying the state
+    Missing(Vec<[u8; 32]>),
+}
+
+/// Replaces the files of a bucket with those of another server, creating
+/// the bucket if needed
+///
+/// The added files take the blobs stored at [`blobs::replica_key`] unless
+/// the blob is stored already. Nothing changes if some blobs are missing.
+/// The change is audited as coming from `source`.
+pub(crate) async fn apply_bucket(
+    state: &Arc<ServerState>,
+    bucket_id: &str,
+    files: BTreeMap<[u8; 32], String>,
+    bytes_used: u64,
+    source: Option<String>,
+) -> Result<Applied, ()> {
+    let Ok(bucket) =
+        get_or_create_bucket(bucket_id.to_owned(), state.clone()).await
+    else {
+        warn!(event = "replicated bucket being deleted", bucket_id);
+        return Err(());
+    };
+    let mut bucket = bucket.write().await;
+    if bucket.state == BucketState::Deleted {
+        warn!(event = "replicated bucket being deleted", bucket_id);
+        return Err(());
+    }
+
+    let added: Vec<[u8; 32]> = files
+        .keys()
+        .filter(|hash| !bucket.files.contains_key(*hash))
+        .copied()
+        .collect();
+    let removed: Vec<[u8; 32]> = bucket
+        .files
+        .keys()
+        .filter(|hash| !files.contains_key(*hash))
+        .copied()
+        .collect();
+
+    // The pushed blobs are staged, the others shared with the buckets
+    // storing them
+    let mut missing = Vec::new();
+    let mut pushed = Vec::new();
+    for hash in &added {
+        let replica_key = blobs::replica_key(hash);
+        if matches!(state.store.size(&replica_key).await, Ok(Some(_))) {
+            pushed.push(*hash);
+            continue;
+        }
+        let db = state.db.read().await;
+        let stored = db.blob_refs(hash).unwrap_or_default() > 0
+            && matches!(
+                blobs::size(&*state.store, &db, hash).await,
+                Ok(Some(_))
+            );
+        if !stored {
+            missing.push(*hash);
+        }
+    }
+    if !missing.is_empty() {
+        return Ok(Applied::Missing(missing));
+    }
+
+    let mut staged = BTreeMap::new();
+    for hash in pushed {
+        let replica_key = blobs::replica_key(&hash);
+        let store = &*state.store;
+        match blobs::stage(store, &replica_key, state.inline_threshold).await {
+            Ok(blob) => {
+                staged.insert(hash, blob);
+            }
+            Err(err) => {
+                error!(event = "failed to store blob", bucket_id, ?err);
+                discard(store, staged.values()).await;
+                return Err(());
+            }
+        }
+    }
+
+    let db = state.db.write().await;
+    for hash in &added {
+        let res = match staged.get(hash) {
+            Some(blob) => blobs::add_ref(&*state.store, &db, hash, blob).await,
+            None => blobs::share(&db, hash).map_err(io::Error::other).and_then(
+                |refs| {
+                    refs.ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "blob removed")
+                    })
+                },
+            ),
+        };
+        if let Err(err) = res {
+            error!(event = "failed to store blob", bucket_id, ?err);
+            drop(db);
+            discard(&*state.store, staged.values()).await;
+            return Err(());
+        }
+    }
+    for hash in &removed {
+        if let Err(err) = blobs::release(&*state.store, &db, hash).await {
+            let hash = hex::encode(hash);
+            error!(event = "failed to release blob", bucket_id, hash, ?err);
+        }
+    }
+
+    let previous_root = bucket.merkle_tree.root_hash();
+    bucket.files = files;
+    bucket.bytes_used = bytes_used;
+    bucket.calculate_merkle_tree();
+    if let Err(err) = db.update_bucket(&bucket).and_then(|_| db.flush()) {
+        error!(
+            event = "failed to persist replicated bucket",
+            bucket_id, err
+        );
+        return Err(());
+    }
+    if !added.is_empty() || !removed.is_empty() {
+        let record = AuditRecord::new(AuditOp::Replicate, source)
+            .with_root(bucket.merkle_tree.root_hash());
+        audit::record(&db, bucket_id, record);
+    }
+    drop(db);
+    discard(&*state.store, staged.values()).await;
+    let root = bucket.merkle_tree.root_hash();
+    state.notify(Event::root_changed(bucket_id, previous_root, root));
+
+    info!(
+        event = "bucket state applied",
+        bucket_id,
+        added = added.len(),
+        removed = removed.len(),
+        root = bucket.merkle_tree.root_hash().map(hex::encode)
+    );
+
+    Ok(Applied::Done(bucket.root()))
+}
+
+/// Deletes the staged blobs [`blobs::add_ref`] did not move into place
+async fn discard<'a>(
+    store: &dyn BlobStore,
+    staged: impl Iterator<Item = &'a blobs::Staged>,
+) {
+    for blob in staged {
+        blob.discard(store).await;
+    }
+}
+
+/// Handles a bucket deletion pushed by the primary
+///
+/// Returns `404 Not Found` if the bucket does not exist
+#[utoipa::path(
+    delete,
+    path = "/peer/buckets/{bucket_id}",
+    tag = "peer",
+    params(("bucket_id" = String, Path, description = "Id of the bucket")),
+    responses(
+        (status = 200, description = "Bucket deleted", body = String, content_type = "text/plain"),
+        (status = 404, description = "The bucket does not exist", body = ErrorBody),
+    ),
+    security(("peer_token" = [])),
+)]
+async fn handle_delete_bucket(
+    Path(bucket_id): Path<String>,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let loaded = state.bucket(&bucket_id).await.map_err(|err| {
+        error!(event = "failed to load bucket", bucket_id, err);
+        ApiError::internal()
+    })?;
+    let Some(bucket) = loaded else {
+        return Err(ApiError::bucket_not_found(&bucket_id));
+    };
+
+    if !state.unload_bucket(&bucket_id).await {
+        return Err(ApiError::bucket_not_found(&bucket_id));
+    }
+
+    // Wait for the requests in flight on the bucket, with no lock held.
+    // The bucket is neither loaded nor created again meanwhile
+    let mut bucket = bucket.write().await;
+
+    let erased = state.erase_bucket(&mut bucket, peer).await;
+    state.erased_bucket(&bucket_id).await;
+    if let Err(err) = erased {
+        error!(event = "failed to delete bucket", bucket_id, err);
+        return Err(ApiError::internal());
+    }
+
+    info!(event = "bucket deletion applied", bucket_id);
+    Ok(reply("Bucket deleted", StatusCode::OK))
+}
+
+fn reply(message: &'static str, status: StatusCode) -> Response {
+    (status, message).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replicated_bucket() {
+        let mut bucket = ClientBucket::new("bucket_id".to_string());
+        bucket.files.insert([1u8; 32], "file_1".to_string());
+        bucket.bytes_used = 10;
+
+        let replicated = ReplicatedBucket::from(&bucket);
+        assert_eq!(
+            replicated.files.get(&hex::encode([1u8; 32])).unwrap(),
+            "file_1"
+        );
+
+        let json = serde_json::to_vec(&replicated).unwrap();
+        let parsed: ReplicatedBucket = serde_json::from_slice(&json).unwrap();
+        assert_eq!(parsed, replicated);
+        assert_eq!(parsed.decode_files(), Some(bucket.files));
+
+        let mut invalid = parsed;
+        invalid.files.insert("zz".to_string(), "file_2".to_string());
+        assert_eq!(invalid.decode_files(), None);
+    }
+}
@@ -0,0 +1,918 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::{FromRequestParts, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{patch, post, put};
+use axum::{middleware, Router};
+use bytes::{Buf, Bytes, BytesMut};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio_stream::{Stream, StreamExt};
+use tracing::{error, info, warn};
+use utoipa::{OpenApi, ToSchema};
+
+use crate::access::{self, Operation};
+use crate::app::{self, ReceivedFile, ServerState, UploadedFile};
+use crate::audit::Peer;
+use crate::batch::SessionToken;
+use crate::blob_store::BlobStore;
+use crate::blobs;
+use crate::cluster;
+use crate::errors::{ApiError, ErrorBody, ErrorCode};
+use crate::extract::{self, Json, Path};
+use crate::file_meta::UploadMeta;
+use crate::limits::{self, LimitsConfig, Work};
+use crate::ownership::Signed;
+use crate::sharded_map::ShardedMap;
+use crate::tls::ClientCert;
+
+/// Upper bound of the body of a single `upload_chunk` or `upload_part`
+/// request
+pub(crate) const MAX_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+/// Upper bound of the number of a part of a multi-part upload
+pub(crate) const MAX_PARTS: u32 = 10_000;
+
+/// A file being uploaded in chunks
+pub(crate) struct UploadSession {
+    upload_id: String,
+    bucket_id: String,
+    filename: String,
+    /// Temporary blob receiving the chunks
+    tmp_key: String,
+    /// Number of bytes received so far
+    offset: u64,
+    hasher: Sha256,
+    /// Seconds the file is kept, given by `X-Expire-After`
+    expire_after: Option<u64>,
+    /// Content type and client metadata of the file
+    meta: UploadMeta,
+    /// Parts received by `upload_part` by number, the file is sent either in
+    /// chunks or in parts
+    parts: BTreeMap<u32, Part>,
+}
+
+/// A part of a multi-part upload
+struct Part {
+    /// Temporary blob holding the part
+    tmp_key: String,
+    size: u64,
+}
+
+/// An open upload session, with what is read without waiting for it
+pub(crate) struct OpenUpload {
+    bucket_id: String,
+    /// Last time a request used the session
+    last_active: StdMutex<Instant>,
+    session: Mutex<UploadSession>,
+}
+
+impl OpenUpload {
+    /// Records a request on the session, postponing its expiration
+    fn touch(&self) {
+        *self.last_active.lock().expect("unpoisoned lock") = Instant::now();
+    }
+
+    /// Returns true if no request used the session for `ttl`
+    ///
+    /// A session locked by a request is never idle.
+    fn is_idle(&self, ttl: Duration) -> bool {
+        let last_active = *self.last_active.lock().expect("unpoisoned lock");
+        last_active.elapsed() >= ttl && self.session.try_lock().is_ok()
+    }
+}
+
+/// Open upload sessions by upload id
+pub(crate) type UploadSessions = ShardedMap<String, Arc<OpenUpload>>;
+
+/// Reply of the upload_init request
+#[derive(Serialize, ToSchema)]
+struct UploadInit {
+    upload_id: String,
+    /// Offset of the first chunk
+    offset: u64,
+}
+
+/// Reply of the upload_chunk request
+#[derive(Serialize, ToSchema)]
+struct UploadOffset {
+    /// Offset of the next chunk
+    offset: u64,
+}
+
+/// Reply of the upload_part request
+#[derive(Serialize, ToSchema)]
+struct UploadPart {
+    /// Number of the part
+    part: u32,
+    /// Size of the part in bytes
+    size: u64,
+}
+
+/// Resumable upload routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(
+    handle_upload_init,
+    handle_upload_chunk,
+    handle_upload_part,
+    handle_upload_finish,
+))]
+pub(crate) struct ApiDoc;
+
+/// Returns the resumable upload route group
+///
+/// A file is uploaded in three steps: `upload_init` opens a session,
+/// `upload_chunk` appends the chunks one by one and `upload_finish` seals the
+/// file into the bucket. A chunk is written only if it was received
+/// completely, so a client that lost a connection can resume from the offset
+/// reported by the server. Instead of chunks, `upload_part` receives numbered
+/// parts in any order and at once, reassembled by `upload_finish`.
+pub(crate) fn routes(state: Arc<ServerState>) -> Router {
+    // Open an upload session
+    // POST /upload_init/:bucket_id/:filename
+    let init = Router::new()
+        .route(
+            "/upload_init/:bucket_id/:filename",
+            post(handle_upload_init),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Write),
+            access::bucket_access,
+        ));
+
+    let sessions = Router::new()
+        // Append a chunk at the offset given by the `Upload-Offset` header
+        // PATCH /upload_chunk/:upload_id
+        .route("/upload_chunk/:upload_id", patch(handle_upload_chunk))
+        // Store the part of the number given by the `Upload-Part` header
+        // PUT /upload_part/:upload_id
+        .route("/upload_part/:upload_id", put(handle_upload_part))
+        .route_layer(middleware::from_fn_with_state(
+            Some(MAX_CHUNK_SIZE),
+            limits::content_length_limit,
+        ))
+        // Seal the uploaded file into the bucket
+        // POST /upload_finish/:upload_id
+        .route("/upload_finish/:upload_id", post(handle_upload_finish));
+
+    init.merge(sessions).with_state(state)
+}
+
+/// Session of the upload id path parameter of a request, which must be
+/// allowed to access the bucket of the session
+struct SessionAccess(Arc<OpenUpload>);
+
+#[async_trait]
+impl FromRequestParts<Arc<ServerState>> for SessionAccess {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<ServerState>,
+    ) -> Result<Self, ApiError> {
+        let Path(upload_id) =
+            Path::<String>::from_request_parts(parts, state).await?;
+        let upload = state
+            .uploads
+            .get(&upload_id)
+            .ok_or_else(|| upload_not_found(&upload_id))?;
+        upload.touch();
+
+        let authorization = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|authorization| authorization.to_str().ok());
+        let client_cert = parts.extensions.get::<ClientCert>();
+        state.access.check(
+            &upload.bucket_id,
+            Operation::Write,
+            authorization,
+            client_cert.map(|cert| cert.0.as_str()),
+        )?;
+
+        Ok(SessionAccess(upload))
+    }
+}
+
+/// Handles upload session creation
+///
+/// Replies with the id of the new session, or `429 Too Many Requests` if the
+/// bucket already has `--max-resumable-uploads` open sessions
+#[utoipa::path(
+    post,
+    path = "/upload_init/{bucket_id}/{filename}",
+    tag = "uploads",
+    params(
+        ("bucket_id" = String, Path, description = "Id of the bucket"),
+        ("filename" = String, Path, description = "Name of the file"),
+        ("X-Expire-After" = Option<u64>, Header, description = "Seconds the file is kept"),
+        ("X-Meta-<key>" = Option<String>, Header, description = "Client metadata of the file, see `/meta`"),
+        ("X-Tags" = Option<String>, Header, description = "Comma separated tags of the file, 16 at most, see `/list`"),
+    ),
+    responses(
+        (status = 200, description = "Upload session opened", body = UploadInit),
+        (status = 400, description = "Client metadata over 2 KiB or not UTF-8, or invalid tags", body = ErrorBody),
+        (status = 401, description = "Missing or invalid credentials", body = ErrorBody),
+        (status = 429, description = "The bucket has `--max-resumable-uploads` open sessions", body = ErrorBody),
+        (status = 507, description = "The disk is over `--disk-high-watermark`", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_upload_init(
+    Path((bucket_id, filename)): Path<(String, String)>,
+    headers: HeaderMap,
+    meta: UploadMeta,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Response, ApiError> {
+    let expire_after = extract::optional_header(&headers, "x-expire-after")?;
+    state.disk.check_writable()?;
+    let bucket =
+        app::get_or_create_bucket(bucket_id.clone(), state.clone()).await?;
+
+    // In a cluster, the session must belong to this node as well, so the
+    // chunks are routed here
+    let upload_id = loop {
+        let upload_id = hex::encode(rand::random::<[u8; 16]>());
+        if cluster::owns(&state.cluster, &upload_id) {
+            break upload_id;
+        }
+    };
+    let tmp_key = blobs::tmp_key();
+
+    let store = state.store.clone();
+    if let Err(err) = store.create(&tmp_key).await {
+        error!(event = "failed to init upload", bucket_id, filename, ?err);
+        return Err(ApiError::internal());
+    }
+
+    // The bucket stays locked from the count of its sessions to the
+    // insertion of the new one, so concurrent requests cannot exceed the cap
+    let _bucket = bucket.write().await;
+    let max = state.limits.max_resumable_uploads;
+    let open = state.uploads.values();
+    if open.iter().filter(|u| u.bucket_id == bucket_id).count() >= max {
+        let _ = store.delete(&tmp_key).await;
+
+        return Err(ApiError::new(
+            ErrorCode::RateLimited,
+            format!("{max} uploads already open on the bucket"),
+        ));
+    }
+
+    info!(event = "upload initiated", bucket_id, filename, upload_id);
+
+    let session = UploadSession {
+        upload_id: upload_id.clone(),
+        bucket_id: bucket_id.clone(),
+        filename,
+        tmp_key,
+        offset: 0,
+        hasher: Sha256::new(),
+        expire_after,
+        meta,
+        parts: BTreeMap::new(),
+    };
+    let upload = OpenUpload {
+        bucket_id,
+        last_active: StdMutex::new(Instant::now()),
+        session: Mutex::new(session),
+    };
+    state.uploads.insert(upload_id.clone(), Arc::new(upload));
+
+    Ok(Json(UploadInit {
+        upload_id,
+        offset: 0,
+    })
+    .into_response())
+}
+
+/// Handles a chunk upload
+///
+/// Returns `409 Conflict` with the current offset of the session if the
+/// chunk does not start where the previous one ended, `413 Payload Too
+/// Large` if the file would exceed `--max-upload-size`, `408 Request
+/// Timeout` if the chunk is not received within `--upload-timeout`
+#[utoipa::path(
+    patch,
+    path = "/upload_chunk/{upload_id}",
+    tag = "uploads",
+    params(
+        ("upload_id" = String, Path, description = "Id of the upload session"),
+        ("Upload-Offset" = u64, Header, description = "Offset of the chunk in the file"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Chunk appended", body = UploadOffset),
+        (status = 404, description = "The upload session does not exist", body = ErrorBody),
+        (status = 408, description = "The chunk was not received within `--upload-timeout`", body = ErrorBody),
+        (status = 409, description = "The chunk does not start at the `offset` of the session, or the file is sent in parts", body = ErrorBody),
+        (status = 413, description = "The file exceeds `--max-upload-size`", body = ErrorBody),
+        (status = 503, description = "Too many concurrent writes, retry after `Retry-After` seconds", body = ErrorBody),
+        (status = 507, description = "The disk is over `--disk-high-watermark`", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_upload_chunk(
+    SessionAccess(upload): SessionAccess,
+    headers: HeaderMap,
+    State(state): State<Arc<ServerState>>,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let offset: u64 = extract::header(&headers, "upload-offset")?;
+    let (limits, body) = {
+        (
+            state.limits.clone(),
+            state
+                .throttle
+                .upload(&upload.bucket_id, body.into_data_stream()),
+        )
+    };
+
+    // Receive the chunk before locking the session, so a slow client does
+    // not hold it
+    let chunk = receive(&limits, body).await?;
+
+    let mut session = upload.session.lock().await;
+
+    // The session may have been finished or expired while the chunk was
+    // received
+    if state.uploads.get(&session.upload_id).is_none() {
+        return Err(upload_not_found(&session.upload_id));
+    }
+    if !session.parts.is_empty() {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            "the file is sent in parts",
+        ));
+    }
+    if offset != session.offset {
+        return Err(ApiError::new(
+            ErrorCode::OffsetMismatch,
+            format!("chunk does not start at offset {}", session.offset),
+        )
+        .with_detail("offset", session.offset));
+    }
+
+    let size = session.offset + chunk.len() as u64;
+    if limits.max_upload_size.is_some_and(|max| size > max) {
+        return Err(ApiError::new(
+            ErrorCode::PayloadTooLarge,
+            "payload too large",
+        ));
+    }
+
+    let (store, _permit) = {
+        state.disk.check_writable()?;
+        let permit = state.concurrency.acquire(Work::Write)?;
+        (state.store.clone(), permit)
+    };
+    let res = async {
+        let mut writer = store.append(&session.tmp_key).await?;
+        writer.write_all(&chunk).await?;
+        writer.flush().await
+    }
+    .await;
+
+    if let Err(err) = res {
+        error!(
+            event = "failed to write chunk",
+            bucket_id = session.bucket_id,
+            filename = session.filename,
+            ?err
+        );
+
+        // Drop the partially written chunk so that it can be sent again
+        let _ = store.truncate(&session.tmp_key, session.offset).await;
+
+        return Err(ApiError::internal());
+    }
+
+    session.hasher.update(&chunk);
+    session.offset += chunk.len() as u64;
+    upload.touch();
+
+    Ok(Json(UploadOffset {
+        offset: session.offset,
+    })
+    .into_response())
+}
+
+/// Handles a part upload
+///
+/// The parts are received at once and stored apart, a part sent again
+/// replaces the previous one. Returns `409 Conflict` if the file is sent in
+/// chunks, `413 Payload Too Large` if the parts would exceed
+/// `--max-upload-size`, `408 Request Timeout` if the part is not received
+/// within `--upload-timeout`
+#[utoipa::path(
+    put,
+    path = "/upload_part/{upload_id}",
+    tag = "uploads",
+    params(
+        ("upload_id" = String, Path, description = "Id of the upload session"),
+        ("Upload-Part" = u32, Header, description = "Number of the part, from 1 to 10000"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Part stored", body = UploadPart),
+        (status = 400, description = "The part number is not between 1 and 10000", body = ErrorBody),
+        (status = 404, description = "The upload session does not exist", body = ErrorBody),
+        (status = 408, description = "The part was not received within `--upload-timeout`", body = ErrorBody),
+        (status = 409, description = "The file is sent in chunks", body = ErrorBody),
+        (status = 413, description = "The file exceeds `--max-upload-size`", body = ErrorBody),
+        (status = 503, description = "Too many concurrent writes, retry after `Retry-After` seconds", body = ErrorBody),
+        (status = 507, description = "The disk is over `--disk-high-watermark`", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_upload_part(
+    SessionAccess(upload): SessionAccess,
+    headers: HeaderMap,
+    State(state): State<Arc<ServerState>>,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let number: u32 = extract::header(&headers, "upload-part")?;
+    if !(1..=MAX_PARTS).contains(&number) {
+        return Err(ApiError::bad_request(format!(
+            "part number not between 1 and {MAX_PARTS}"
+        )));
+    }
+    let (limits, body) = {
+        (
+            state.limits.clone(),
+            state
+                .throttle
+                .upload(&upload.bucket_id, body.into_data_stream()),
+        )
+    };
+
+    // The part is received and written without the session lock, so the
+    // parts of a file are sent at once
+    let data = receive(&limits, body).await?;
+    let size = data.len() as u64;
+
+    let (store, _permit) = {
+        state.disk.check_writable()?;
+        let permit = state.concurrency.acquire(Work::Write)?;
+        (state.store.clone(), permit)
+    };
+    let tmp_key = blobs::tmp_key();
+    if let Err(err) = store.put(&tmp_key, data).await {
+        let upload_id = upload.session.lock().await.upload_id.clone();
+        error!(event = "failed to write part", upload_id, number, ?err);
+        let _ = store.delete(&tmp_key).await;
+
+        return Err(ApiError::internal());
+    }
+
+    let mut session = upload.session.lock().await;
+
+    // The session may have been finished or expired while the part was
+    // written
+    let open = state.uploads.get(&session.upload_id).is_some();
+    let error = if !open {
+        Some(upload_not_found(&session.upload_id))
+    } else if session.offset > 0 {
+        Some(ApiError::new(
+            ErrorCode::Conflict,
+            "the file is sent in chunks",
+        ))
+    } else {
+        let others: u64 = session
+            .parts
+            .iter()
+            .filter(|(n, _)| **n != number)
+            .map(|(_, part)| part.size)
+            .sum();
+        limits
+            .max_upload_size
+            .is_some_and(|max| others + size > max)
+            .then(|| {
+                ApiError::new(ErrorCode::PayloadTooLarge, "payload too large")
+            })
+    };
+    if let Some(err) = error {
+        let _ = store.delete(&tmp_key).await;
+
+        return Err(err);
+    }
+
+    let part = Part { tmp_key, size };
+    if let Some(previous) = session.parts.insert(number, part) {
+        let _ = store.delete(&previous.tmp_key).await;
+    }
+    upload.touch();
+
+    Ok(Json(UploadPart { part: number, size }).into_response())
+}
+
+/// Receives the body of a chunk or a part within `--upload-timeout`
+async fn receive<S, B>(
+    limits: &LimitsConfig,
+    body: S,
+) -> Result<Bytes, ApiError>
+where
+    S: Stream<Item = Result<B, axum::Error>> + Unpin,
+    B: Buf,
+{
+    match tokio::time::timeout(limits.upload_timeout(), receive_chunk(body))
+        .await
+    {
+        Ok(Ok(chunk)) => Ok(chunk),
+        Ok(Err(_)) => Err(ApiError::bad_request("failed to receive body")),
+        Err(_) => {
+            Err(ApiError::new(ErrorCode::RequestTimeout, "upload timed out"))
+        }
+    }
+}
+
+/// Collects the body of a chunk, bounded by `MAX_CHUNK_SIZE`
+async fn receive_chunk<S, B>(mut body: S) -> Result<Bytes, axum::Error>
+where
+    S: Stream<Item = Result<B, axum::Error>> + Unpin,
+    B: Buf,
+{
+    let mut chunk = BytesMut::new();
+    while let Some(data) = body.next().await {
+        let mut data = data?;
+        while data.has_remaining() {
+            chunk.extend_from_slice(data.chunk());
+            data.advance(data.chunk().len());
+        }
+    }
+
+    Ok(chunk.freeze())
+}
+
+/// Aborts the open sessions on shutdown, deleting their received chunks
+///
+/// Sessions still receiving a chunk are left to the removal of the
+/// temporary blobs on startup.
+pub(crate) async fn abort_all(state: &Arc<ServerState>) {
+    for upload in state.uploads.drain() {
+        let Ok(session) = upload.session.try_lock() else {
+            continue;
+        };
+        info!(
+            event = "upload aborted",
+            bucket_id = session.bucket_id,
+            upload_id = session.upload_id
+        );
+        delete_received(&session, &*state.store).await;
+    }
+}
+
+/// Expires in the background the sessions without requests for
+/// `--resumable-upload-ttl`
+pub(crate) fn spawn(state: Arc<ServerState>, config: &LimitsConfig) {
+    let ttl = config.resumable_upload_ttl();
+
+    tokio::spawn(async move {
+        let period = ttl.min(Duration::from_secs(60));
+        let mut interval = tokio::time::interval(period);
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            expire(&state, ttl).await;
+        }
+    });
+}
+
+/// Closes the sessions without requests for `ttl`, deleting their received
+/// chunks and parts. Returns the number of expired sessions.
+pub(crate) async fn expire(state: &Arc<ServerState>, ttl: Duration) -> usize {
+    let (expired, store) = {
+        let expired: Vec<_> = state
+            .uploads
+            .keys()
+            .into_iter()
+            .filter_map(|upload_id| {
+                state
+                    .uploads
+                    .remove_if(&upload_id, |upload| upload.is_idle(ttl))
+            })
+            .collect();
+        (expired, state.store.clone())
+    };
+
+    for upload in &expired {
+        let session = upload.session.lock().await;
+        info!(
+            event = "upload expired",
+            bucket_id = session.bucket_id,
+            upload_id = session.upload_id
+        );
+        delete_received(&session, &*store).await;
+    }
+
+    expired.len()
+}
+
+/// Deletes the temporary blobs of the chunks and parts of a session
+async fn delete_received(session: &UploadSession, store: &dyn BlobStore) {
+    let parts = session.parts.values().map(|part| &part.tmp_key);
+    for tmp_key in parts.chain([&session.tmp_key]) {
+        if let Err(err) = store.delete(tmp_key).await {
+            warn!(
+                event = "failed to delete upload",
+                upload_id = session.upload_id,
+                ?err
+            );
+        }
+    }
+}
+
+/// Handles upload completion
+///
+/// The parts of a multi-part upload are first appended in order, they must
+/// be numbered from 1 without gap. The session is closed whatever the
+/// outcome of sealing the file, which is signed as a single upload by the
+/// owner of the bucket
+#[utoipa::path(
+    post,
+    path = "/upload_finish/{upload_id}",
+    tag = "uploads",
+    params(
+        ("upload_id" = String, Path, description = "Id of the upload session"),
+        ("X-Upload-Session" = Option<String>, Header, description = "Token of the upload session of the bucket, required while it has one"),
+        ("X-Signature" = Option<String>, Header, description = "Signature of the owner of the bucket, required on owned buckets"),
+        ("X-Signature-Nonce" = Option<String>, Header, description = "Nonce covered by the signature, `<unix seconds>:<random>`"),
+    ),
+    responses(
+        (status = 200, description = "File uploaded", body = UploadedFile),
+        (status = 401, description = "Missing or invalid credentials or owner signature", body = ErrorBody),
+        (status = 404, description = "The upload session does not exist", body = ErrorBody),
+        (status = 409, description = "A part is missing, the file is already in the bucket, another file has its name and the naming policy rejects duplicates, another upload session of the bucket is open, or the bucket is sealed and the request has no session", body = ErrorBody),
+        (status = 413, description = "The file exceeds the byte quota of the bucket", body = ErrorBody),
+        (status = 429, description = "The file exceeds the file quota of the bucket", body = ErrorBody),
+        (status = 503, description = "Too many concurrent writes while reassembling the parts, retry after `Retry-After` seconds", body = ErrorBody),
+        (status = 507, description = "The disk is over `--disk-high-watermark` while reassembling the parts", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_upload_finish(
+    SessionAccess(upload): SessionAccess,
+    SessionToken(token): SessionToken,
+    signed: Signed,
+    Peer(peer): Peer,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Json<UploadedFile>, ApiError> {
+    let mut session = upload.session.lock().await;
+
+    // A concurrent request may have finished the session already
+    state
+        .uploads
+        .remove(&session.upload_id)
+        .ok_or_else(|| upload_not_found(&session.upload_id))?;
+
+    if !session.parts.is_empty() {
+        if let Err(err) = reassemble(&mut session, &state).await {
+            let store = state.store.clone();
+            delete_received(&session, &*store).await;
+
+            return Err(err);
+        }
+    }
+    let file_hash = session.hasher.clone().finalize().into();
+
+    info!(
+        event = "upload finished",
+        bucket_id = session.bucket_id,
+        filename = session.filename,
+        upload_id = session.upload_id,
+        size = session.offset
+    );
+
+    let file = ReceivedFile {
+        filename: session.filename.clone(),
+        tmp_key: &session.tmp_key,
+        hash: file_hash,
+        size: session.offset,
+        expire_after: session.expire_after,
+        session: token,
+        meta: session.meta.clone(),
+        duplicates: None,
+    };
+    let uploaded = app::store_file(
+        session.bucket_id.clone(),
+        file,
+        &signed,
+        peer,
+        state.clone(),
+    )
+    .await?;
+
+    Ok(Json(uploaded))
+}
+
+/// Appends the parts of a session to its temporary blob in order, hashing
+/// them, and deletes them
+async fn reassemble(
+    session: &mut UploadSession,
+    state: &Arc<ServerState>,
+) -> Result<(), ApiError> {
+    let missing = (1..).zip(session.parts.keys()).find(|(n, key)| n != *key);
+    if let Some((missing, _)) = missing {
+        return Err(ApiError::new(
+            ErrorCode::Conflict,
+            format!("part {missing} is missing"),
+        )
+        .with_detail("missing_part", missing));
+    }
+
+    let (store, _permit) = {
+        state.disk.check_writable()?;
+        let permit = state.concurrency.acquire(Work::Write)?;
+        (state.store.clone(), permit)
+    };
+    let UploadSession {
+        upload_id,
+        tmp_key,
+        offset,
+        hasher,
+        parts,
+        ..
+    } = session;
+    let res = async {
+        let mut writer = store.append(tmp_key).await?;
+        for part in parts.values() {
+            let data = store.get(&part.tmp_key).await?;
+            hasher.update(&data);
+            writer.write_all(&data).await?;
+            *offset += data.len() as u64;
+        }
+        writer.flush().await
+    }
+    .await;
+    if let Err(err) = res {
+        let upload_id = upload_id.as_str();
+        error!(event = "failed to reassemble parts", upload_id, ?err);
+        return Err(ApiError::internal());
+    }
+
+    for part in std::mem::take(parts).into_values() {
+        let _ = store.delete(&part.tmp_key).await;
+    }
+    let upload_id = upload_id.as_str();
+    info!(event = "parts reassembled", upload_id, size = *offset);
+
+    Ok(())
+}
+
+fn upload_not_found(upload_id: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::UploadNotFound,
+        format!("upload {upload_id} not found"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use crate::database::DB;
+    use crate::Config;
+    use axum::body::Body;
+    use axum::http::{Request, Response, StatusCode};
+    use clap::Parser;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_multipart_upload() {
+        let config = Config::parse_from(["server", "127.0.0.1:0"]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = app::run_server_with_store(config, store).await;
+        tokio::spawn(server);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let request = |method: &str,
+                       path: &str,
+                       part: Option<u32>,
+                       body: &'static str| {
+            let mut request = Request::builder()
+                .method(method)
+                .uri(format!("http://{addr}{path}"));
+            if let Some(part) = part {
+                request = request.header("upload-part", part);
+            }
+            client.request(request.body(Body::from(body)).unwrap())
+        };
+        let json = |res: Response<Incoming>| async move {
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let res = request("POST", "/upload_init/b/a.txt", None, "")
+            .await
+            .unwrap();
+        let upload_id =
+            json(res).await["upload_id"].as_str().unwrap().to_owned();
+        let part = format!("/upload_part/{upload_id}");
+        let finish = format!("/upload_finish/{upload_id}");
+
+        // The parts are sent in any order, a part sent again replaces the
+        // previous one
+        let (first, third) = tokio::join!(
+            request("PUT", &part, Some(1), "hello "),
+            request("PUT", &part, Some(3), "world"),
+        );
+        assert_eq!(json(first.unwrap()).await["size"], 6);
+        assert_eq!(third.unwrap().status(), StatusCode::OK);
+        let res = request("PUT", &part, Some(0), "a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let chunk =
+            Request::patch(format!("http://{addr}/upload_chunk/{upload_id}"))
+                .header("upload-offset", 0)
+                .body(Body::from("hello "))
+                .unwrap();
+        let res = client.request(chunk).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        request("PUT", &part, Some(2), "big ").await.unwrap();
+        let res = request("PUT", &part, Some(2), "small ").await.unwrap();
+        assert_eq!(json(res).await["size"], 6);
+
+        let res = request("POST", &finish, None, "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let uploaded = json(res).await;
+        let hash = Sha256::digest(b"hello small world");
+        assert_eq!(uploaded["hash"], hex::encode(hash));
+        request("POST", "/complete_upload/b", None, "")
+            .await
+            .unwrap();
+
+        let res = request("GET", "/file/b/0", None, "").await.unwrap();
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello small world");
+
+        // A file with a missing part is not stored
+        let res = request("POST", "/upload_init/c/c.txt", None, "")
+            .await
+            .unwrap();
+        let upload_id =
+            json(res).await["upload_id"].as_str().unwrap().to_owned();
+        let part = format!("/upload_part/{upload_id}");
+        request("PUT", &part, Some(2), "b").await.unwrap();
+        let res =
+            request("POST", &format!("/upload_finish/{upload_id}"), None, "")
+                .await
+                .unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(json(res).await["missing_part"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_upload_expiry() {
+        let config = Config::parse_from([
+            "server",
+            "127.0.0.1:0",
+            "--max-resumable-uploads",
+            "1",
+        ]);
+        let store = Arc::new(MemoryStore::new());
+        let state =
+            Arc::new(ServerState::open(&config, DB::in_memory(), store).await);
+        let routes = app::routes(config, state.clone());
+        let init = |bucket_id: &str| {
+            let request =
+                Request::post(format!("/upload_init/{bucket_id}/a.txt"))
+                    .body(Body::empty())
+                    .unwrap();
+            let routes = routes.clone();
+            async move { routes.oneshot(request).await.unwrap() }
+        };
+
+        // The sessions are capped per bucket
+        let res = init("b").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let upload_id = body["upload_id"].as_str().unwrap().to_owned();
+        assert_eq!(init("b").await.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(init("c").await.status(), StatusCode::OK);
+
+        // Idle sessions expire along with their chunks
+        assert_eq!(expire(&state, Duration::from_secs(60)).await, 0);
+        assert_eq!(expire(&state, Duration::ZERO).await, 2);
+        let request = Request::patch(format!("/upload_chunk/{upload_id}"))
+            .header("upload-offset", 0)
+            .body(Body::from("a"))
+            .unwrap();
+        let res = routes.clone().oneshot(request).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(init("b").await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,295 @@
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use tokio_stream::StreamExt;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::app::ServerState;
+use crate::blob_store::BlobStore;
+use crate::blobs;
+use crate::database::DB;
+use crate::peers::PeerConfig;
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct ScrubConfig {
+    /// Seconds between two verifications of all the stored blobs, the
+    /// scrubber only runs through POST /admin/scrub if unset
+    #[arg(long)]
+    pub scrub_interval: Option<u64>,
+
+    /// Move the corrupted blobs under `quarantine/` in the blob store
+    /// instead of only reporting them
+    #[arg(long)]
+    pub scrub_quarantine: bool,
+}
+
+/// Outcome of the scrubber passes, as reported by GET /admin/scrub
+#[derive(Clone, Debug, Default, Serialize, ToSchema)]
+pub(crate) struct ScrubStatus {
+    /// Number of completed passes
+    pub passes: u64,
+    /// Unix time in seconds of the end of the last pass
+    pub last_pass: Option<u64>,
+    /// Number of blobs verified by the last pass
+    pub blobs_checked: usize,
+    /// Keys of the corrupted blobs found by the last pass
+    pub corrupted: Vec<String>,
+    /// Number of corrupted blobs found since startup
+    pub corrupted_total: u64,
+    /// Keys of the corrupted blobs replaced with the copy of a peer by the
+    /// last pass
+    pub repaired: Vec<String>,
+    /// Number of blobs repaired since startup
+    pub repaired_total: u64,
+}
+
+/// Spawns the periodic scrubbing, if `--scrub-interval` is set
+pub(crate) fn spawn(state: Arc<ServerState>, config: &ScrubConfig) {
+    let Some(interval) = config.scrub_interval.filter(|secs| *secs > 0) else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval));
+        // The first tick completes immediately
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            if let Err(err) = scrub(&state).await {
+                error!(event = "failed to scrub blobs", ?err);
+            }
+        }
+    });
+}
+
+/// Re-reads all the stored blobs and compares their SHA-256 with their
+/// hash, which is also their leaf hash in the buckets
+///
+/// Blobs are read one at a time, without holding the database lock, so the
+/// pass yields to the requests. Corrupted blobs are replaced with the copy
+/// of a peer if one has a valid copy. Otherwise they are reported in the
+/// status and quarantined with `--scrub-quarantine`.
+pub(crate) async fn scrub(state: &ServerState) -> io::Result<ScrubStatus> {
+    let quarantine = state.scrub.scrub_quarantine;
+    let pass =
+        scrub_blobs(&*state.store, &state.db, &state.peers, quarantine).await?;
+
+    let mut status = state.scrub_status.lock().expect("unpoisoned lock");
+    status.passes += 1;
+    status.last_pass = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .ok();
+    status.blobs_checked = pass.checked;
+    status.corrupted_total += pass.corrupted.len() as u64;
+    status.corrupted = pass.corrupted;
+    status.repaired_total += pass.repaired.len() as u64;
+    status.repaired = pass.repaired;
+
+    info!(
+        event = "blobs scrubbed",
+        blobs_checked = status.blobs_checked,
+        corrupted = status.corrupted.len(),
+        repaired = status.repaired.len()
+    );
+    Ok(status.clone())
+}
+
+/// Outcome of the verification of all the blobs
+#[derive(Debug, Default)]
+struct Pass {
+    checked: usize,
+    /// Keys of the corrupted blobs, including the repaired ones
+    corrupted: Vec<String>,
+    /// Keys of the blobs replaced with the copy of a peer
+    repaired: Vec<String>,
+}
+
+/// Verifies the blobs of the store and of the database
+async fn scrub_blobs(
+    store: &dyn BlobStore,
+    db: &RwLock<DB>,
+    peers: &PeerConfig,
+    quarantine: bool,
+) -> io::Result<Pass> {
+    let mut pass = Pass::default();
+
+    // Uploads in progress and quarantined blobs have no blob key
+    for key in store.list("").await? {
+        let Some(hash) = blobs::key_hash(&key) else {
+            continue;
+        };
+
+        let actual = match hash_blob(store, &key).await {
+            Ok(actual) => actual,
+            // Removed since it was listed
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                error!(event = "failed to scrub blob", key, ?err);
+                continue;
+            }
+        };
+        pass.checked += 1;
+
+        if actual != hash {
+            error!(event = "corrupted blob", key, actual = hex::encode(actual));
+            if repair(store, db, peers, &hash).await {
+                pass.repaired.push(key.clone());
+            } else if quarantine {
+                // The blob is read again once a client uploads the file
+                let _db = db.write().await;
+                store.rename(&key, &blobs::quarantine_key(&key)).await?;
+                warn!(event = "corrupted blob quarantined", key);
+            }
+            pass.corrupted.push(key);
+        }
+        tokio::task::yield_now().await;
+    }
+
+    let hashes = db
+        .read()
+        .await
+        .inline_blob_hashes()
+        .map_err(io::Error::other)?;
+    for hash in hashes {
+        let data = db
+            .read()
+            .await
+            .inline_blob(&hash)
+            .map_err(io::Error::other)?;
+        let Some(data) = data else {
+            continue;
+        };
+        pass.checked += 1;
+
+        let actual: [u8; 32] = Sha256::digest(&data).into();
+        if actual != hash {
+            let key = blobs::blob_key(&hash);
+            error!(event = "corrupted blob", key, actual = hex::encode(actual));
+            if repair(store, db, peers, &hash).await {
+                pass.repaired.push(key.clone());
+            } else if quarantine {
+                let db = db.write().await;
+                store.put(&blobs::quarantine_key(&key), data.into()).await?;
+                db.set_inline_blob(&hash, None).map_err(io::Error::other)?;
+                warn!(event = "corrupted blob quarantined", key);
+            }
+            pass.corrupted.push(key);
+        }
+        tokio::task::yield_now().await;
+    }
+
+    Ok(pass)
+}
+
+/// Replaces a corrupted blob with the copy of a peer, verified against its
+/// hash
+///
+/// Returns false if no peer has a valid copy
+async fn repair(
+    store: &dyn BlobStore,
+    db: &RwLock<DB>,
+    peers: &PeerConfig,
+    hash: &[u8; 32],
+) -> bool {
+    if peers.peers.is_empty() {
+        return false;
+    }
+
+    let tmp_key = blobs::tmp_key();
+    if !peers.fetch_blob(store, hash, &tmp_key).await {
+        return false;
+    }
+
+    let db = db.write().await;
+    let res = async {
+        if db.inline_blob(hash).map_err(io::Error::other)?.is_some() {
+            let data = store.get(&tmp_key).await?;
+            db.set_inline_blob(hash, Some(&data))
+                .map_err(io::Error::other)?;
+            store.delete(&tmp_key).await
+        } else {
+            store.rename(&tmp_key, &blobs::blob_key(hash)).await
+        }
+    };
+
+    let key = blobs::blob_key(hash);
+    match res.await {
+        Ok(()) => {
+            info!(event = "corrupted blob repaired", key);
+            true
+        }
+        Err(err) => {
+            error!(event = "failed to repair blob", key, ?err);
+            let _ = store.delete(&tmp_key).await;
+            false
+        }
+    }
+}
+
+/// Returns the SHA-256 of the content of a blob
+async fn hash_blob(store: &dyn BlobStore, key: &str) -> io::Result<[u8; 32]> {
+    let (_, mut stream) = store.stream(key).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        hasher.update(chunk?);
+    }
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::MemoryStore;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_scrub_blobs() {
+        let store = MemoryStore::new();
+        let db = RwLock::new(DB::in_memory());
+
+        let hash: [u8; 32] = Sha256::digest(b"data").into();
+        let damaged: [u8; 32] = Sha256::digest(b"date").into();
+        for hash in [hash, damaged] {
+            let key = blobs::blob_key(&hash);
+            store.put(&key, Bytes::from("data")).await.unwrap();
+        }
+        let small: [u8; 32] = Sha256::digest(b"small").into();
+        let db_damaged: [u8; 32] = Sha256::digest(b"smell").into();
+        for hash in [small, db_damaged] {
+            let db = db.read().await;
+            assert!(db.set_inline_blob(&hash, Some(b"small")).is_ok());
+        }
+
+        let peers = PeerConfig::default();
+        let pass = scrub_blobs(&store, &db, &peers, false).await.unwrap();
+        assert_eq!(pass.checked, 4);
+        let expected =
+            vec![blobs::blob_key(&damaged), blobs::blob_key(&db_damaged)];
+        assert_eq!(pass.corrupted, expected);
+        assert!(pass.repaired.is_empty());
+
+        let pass = scrub_blobs(&store, &db, &peers, true).await.unwrap();
+        assert_eq!((pass.checked, pass.corrupted.len()), (4, 2));
+
+        // Quarantined blobs are not verified again
+        let pass = scrub_blobs(&store, &db, &peers, true).await.unwrap();
+        assert_eq!((pass.checked, pass.corrupted.len()), (2, 0));
+
+        let mut keys = vec![
+            blobs::blob_key(&hash),
+            blobs::quarantine_key(&blobs::blob_key(&damaged)),
+            blobs::quarantine_key(&blobs::blob_key(&db_damaged)),
+        ];
+        keys.sort();
+        assert_eq!(store.list("").await.unwrap(), keys);
+        assert_eq!(db.read().await.inline_blob(&db_damaged), Ok(None));
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::RwLock;
+
+/// Number of shards of a [`ShardedMap`]
+const SHARDS: usize = 16;
+
+/// Concurrent hash map split in independently locked shards
+///
+/// Requests on keys of different shards never wait for each other. The locks
+/// are only held for the duration of a map operation, never across an
+/// `.await`, so values are handed out as clones (typically `Arc`s).
+pub(crate) struct ShardedMap<K, V> {
+    shards: Vec<RwLock<HashMap<K, V>>>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedMap<K, V> {
+    pub(crate) fn new() -> Self {
+        ShardedMap {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    /// Returns a clone of the value of `key`
+    pub(crate) fn get(&self, key: &K) -> Option<V> {
+        let shard = self.shard(key).read().expect("unpoisoned lock");
+        shard.get(key).cloned()
+    }
+
+    /// Returns the value of `key`, inserting the one built by `f` if there is
+    /// none
+    pub(crate) fn get_or_insert_with<F>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> V,
+    {
+        if let Some(value) = self.get(&key) {
+            return value;
+        }
+
+        let mut shard = self.shard(&key).write().expect("unpoisoned lock");
+        shard.entry(key).or_insert_with(f).clone()
+    }
+
+    /// Inserts a value, returning the one it replaces
+    pub(crate) fn insert(&self, key: K, value: V) -> Option<V> {
+        let mut shard = self.shard(&key).write().expect("unpoisoned lock");
+        shard.insert(key, value)
+    }
+
+    /// Removes the value of `key`
+    pub(crate) fn remove(&self, key: &K) -> Option<V> {
+        let mut shard = self.shard(key).write().expect("unpoisoned lock");
+        shard.remove(key)
+    }
+
+    /// Returns a clone of all the keys
+    pub(crate) fn keys(&self) -> Vec<K>
+    where
+        K: Clone,
+    {
+        let mut keys = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.read().expect("unpoisoned lock");
+            keys.extend(shard.keys().cloned());
+        }
+        keys
+    }
+
+    /// Returns a clone of all the values
+    pub(crate) fn values(&self) -> Vec<V> {
+        let mut values = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.read().expect("unpoisoned lock");
+            values.extend(shard.values().cloned());
+        }
+        values
+    }
+
+    /// Removes and returns all the values
+    pub(crate) fn drain(&self) -> Vec<V> {
+        let mut values = Vec::new();
+        for shard in &self.shards {
+            let mut shard = shard.write().expect("unpoisoned lock");
+            values.extend(shard.drain().map(|(_, value)| value));
+        }
+        values
+    }
+
+    /// Removes the value of `key` if it satisfies `f`
+    ///
+    /// The shard stays locked while `f` runs, so the value cannot be read
+    /// concurrently.
+    pub(crate) fn remove_if<F>(&self, key: &K, f: F) -> Option<V>
+    where
+        F: FnOnce(&V) -> bool,
+    {
+        let mut shard = self.shard(key).write().expect("unpoisoned lock");
+        if !f(shard.get(key)?) {
+            return None;
+        }
+        shard.remove(key)
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> FromIterator<(K, V)> for ShardedMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let map = ShardedMap::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_sharded_map() {
+        let map: ShardedMap<String, usize> =
+            (0..100).map(|i| (i.to_string(), i)).collect();
+
+        assert_eq!(map.get(&"42".to_owned()), Some(42));
+        assert_eq!(map.get(&"100".to_owned()), None);
+
+        assert_eq!(map.get_or_insert_with("42".to_owned(), || 0), 42);
+        assert_eq!(map.get_or_insert_with("100".to_owned(), || 100), 100);
+
+        assert_eq!(map.insert("1".to_owned(), 10), Some(1));
+        assert_eq!(map.remove(&"1".to_owned()), Some(10));
+        assert_eq!(map.remove(&"1".to_owned()), None);
+
+        assert_eq!(map.remove_if(&"2".to_owned(), |value| *value == 0), None);
+        assert_eq!(
+            map.remove_if(&"2".to_owned(), |value| *value == 2),
+            Some(2)
+        );
+        assert_eq!(map.get(&"2".to_owned()), None);
+
+        assert_eq!(map.keys().len(), 99);
+        assert_eq!(map.drain().len(), 99);
+        assert_eq!(map.get(&"3".to_owned()), None);
+
+        // Concurrent insertions of the same key keep a single value
+        let map = Arc::new(ShardedMap::new());
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let map = map.clone();
+                std::thread::spawn(move || {
+                    map.get_or_insert_with("key".to_owned(), || i)
+                })
+            })
+            .collect();
+        let values: Vec<usize> =
+            threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(values.iter().all(|value| *value == values[0]));
+    }
+}
@@ -0,0 +1,115 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Args;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::app::ServerState;
+use crate::resumable;
+
+#[derive(Args, Clone, Debug)]
+pub(crate) struct ShutdownConfig {
+    /// Seconds the in-flight requests are given to complete on SIGTERM or
+    /// SIGINT, before the state is persisted and the server exits
+    #[arg(long, default_value_t = 30)]
+    pub shutdown_timeout: u64,
+}
+
+impl ShutdownConfig {
+    pub(crate) fn timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout)
+    }
+}
+
+/// Returns the sender triggering the shutdown and the future the listener
+/// waits for to stop accepting connections
+pub(crate) fn trigger() -> (watch::Sender<bool>, impl Future<Output = ()>) {
+    let (sender, mut receiver) = watch::channel(false);
+    let stopped = async move {
+        let _ = receiver.wait_for(|stopped| *stopped).await;
+    };
+    (sender, stopped)
+}
+
+/// Waits for SIGTERM or SIGINT
+async fn terminated() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => info!(event = "SIGTERM received"),
+        _ = tokio::signal::ctrl_c() => info!(event = "SIGINT received"),
+    }
+}
+
+/// Runs the server until SIGTERM or SIGINT, then shuts it down gracefully
+///
+/// The listener stops accepting connections and the in-flight requests are
+/// given `timeout` to complete. The open upload sessions are then aborted,
+/// the buckets with journaled uploads are persisted and the database is
+/// flushed, so that no accepted file is left unrecorded.
+pub(crate) async fn run<F>(
+    state: Arc<ServerState>,
+    timeout: Duration,
+    trigger: watch::Sender<bool>,
+    server: F,
+) where
+    F: Future<Output = ()>,
+{
+    tokio::pin!(server);
+    tokio::select! {
+        _ = &mut server => return,
+        _ = terminated() => {}
+    }
+
+    info!(event = "shutting down", ?timeout);
+    let _ = trigger.send(true);
+    if tokio::time::timeout(timeout, &mut server).await.is_err() {
+        warn!(event = "in-flight requests abandoned", ?timeout);
+    }
+
+    persist(&state).await;
+    info!(event = "shut down");
+}
+
+/// Aborts the upload sessions, persists the buckets with journaled uploads
+/// and flushes the database
+pub(crate) async fn persist(state: &Arc<ServerState>) {
+    resumable::abort_all(state).await;
+
+    let journaled = {
+        let db = state.db.read().await;
+        db.journaled_files()
+    };
+    let mut bucket_ids = match journaled {
+        Ok(files) => files
+            .into_iter()
+            .map(|(bucket_id, _, _)| bucket_id)
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            error!(event = "failed to read journal", err);
+            Vec::new()
+        }
+    };
+    bucket_ids.dedup();
+
+    for bucket_id in bucket_ids {
+        // Buckets not in memory are recovered from the journal on startup
+        let Some(bucket) = state.buckets.get(&bucket_id) else {
+            continue;
+        };
+        let bucket = bucket.write().await;
+        match state.persist_bucket_lockless(&bucket).await {
+            Ok(()) => info!(event = "bucket persisted", bucket_id),
+            Err(err) => {
+                error!(event = "failed to persist bucket", bucket_id, err)
+            }
+        }
+    }
+
+    let res = state.db.read().await.flush();
+    if let Err(err) = res {
+        error!(event = "failed to flush database", err);
+    }
+}
@@ -0,0 +1,129 @@
+use axum::extract::Request;
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+use clap::Args;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::{field, info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct TelemetryConfig {
+    /// Export the spans of the requests to the OTLP collector at this gRPC
+    /// endpoint (e.g. `http://localhost:4317`). Requests carrying a W3C
+    /// `traceparent` header continue the trace of the client
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Name of the server in the exported spans
+    #[arg(long, env = "OTEL_SERVICE_NAME", default_value = "storage-server")]
+    pub otlp_service_name: String,
+}
+
+/// Returns the provider exporting the spans, if an OTLP endpoint is set
+pub(crate) fn tracer_provider(
+    config: &TelemetryConfig,
+) -> Result<Option<TracerProvider>, TraceError> {
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+
+    Ok(Some(
+        TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                config.otlp_service_name.clone(),
+            )]))
+            .build(),
+    ))
+}
+
+/// Returns the tracer of the server spans
+pub(crate) fn tracer(provider: &TracerProvider) -> Tracer {
+    provider.tracer("storage-server")
+}
+
+/// Runs a request in its span, see [`request_span`]
+pub(crate) async fn trace(req: Request, next: Next) -> Response {
+    let span = request_span(&req);
+    next.run(req).instrument(span).await
+}
+
+/// Returns the span of a request, child of the span of the client given by
+/// the `traceparent` header, if any
+///
+/// The `request_id` of the span is recorded once known, see
+/// [`crate::errors::handle`].
+fn request_span(req: &Request) -> Span {
+    let span = info_span!(
+        "request",
+        otel.kind = "server",
+        method = %req.method(),
+        path = req.uri().path(),
+        request_id = field::Empty,
+    );
+
+    let context =
+        TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+    span.set_parent(context);
+    span
+}
+
+/// Reads the trace context from the headers of an http request
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_trace_context() {
+        use opentelemetry::trace::TraceContextExt;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+
+        let context =
+            TraceContextPropagator::new().extract(&HeaderExtractor(&headers));
+        let span = context.span();
+        let span_context = span.span_context();
+        assert!(span_context.is_remote());
+        assert_eq!(
+            span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(span_context.span_id().to_string(), "00f067aa0ba902b7");
+
+        // Without the header the request starts a new trace
+        let context = TraceContextPropagator::new()
+            .extract(&HeaderExtractor(&HeaderMap::new()));
+        assert!(!context.span().span_context().is_valid());
+    }
+}
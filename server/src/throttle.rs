@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Buf;
+use serde::{Deserialize, Serialize};
+use tokio::time::Sleep;
+use tokio_stream::Stream;
+use utoipa::ToSchema;
+
+use crate::limits::LimitsConfig;
+
+/// Per-bucket byte rates set through the admin API, on top of the
+/// server-wide `--max-upload-rate` and `--max-download-rate`
+#[derive(
+    Clone, Debug, Default, PartialEq, Serialize, Deserialize, ToSchema,
+)]
+pub(crate) struct BandwidthLimits {
+    /// Bytes per second received from the uploads of the bucket
+    #[serde(default)]
+    pub upload_rate: Option<u64>,
+    /// Bytes per second sent by the downloads of the bucket
+    #[serde(default)]
+    pub download_rate: Option<u64>,
+}
+
+/// Paces the bytes going through it at `rate` bytes per second, whatever
+/// the number of bodies sharing it
+#[derive(Debug)]
+struct Limiter {
+    rate: u64,
+    /// When the bytes counted so far have gone through at `rate`
+    free_at: Mutex<Instant>,
+}
+
+impl Limiter {
+    fn new(rate: u64) -> Arc<Self> {
+        Arc::new(Limiter {
+            rate,
+            free_at: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Counts `bytes` gone through, returns how long the next bytes wait
+    fn reserve(&self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let mut free_at = self.free_at.lock().expect("valid lock");
+
+        let spent = Duration::from_secs_f64(bytes as f64 / self.rate as f64);
+        *free_at = (*free_at).max(now) + spent;
+        free_at.duration_since(now)
+    }
+}
+
+/// Limiters of a bucket
+struct BucketLimiters {
+    limits: BandwidthLimits,
+    upload: Option<Arc<Limiter>>,
+    download: Option<Arc<Limiter>>,
+}
+
+impl BucketLimiters {
+    fn new(limits: BandwidthLimits) -> Self {
+        BucketLimiters {
+            upload: limits.upload_rate.map(Limiter::new),
+            download: limits.download_rate.map(Limiter::new),
+            limits,
+        }
+    }
+}
+
+/// Bounds the byte rates of the uploaded and downloaded bodies, server-wide
+/// and per bucket, so that one tenant does not saturate the network and the
+/// disks
+pub(crate) struct Throttle {
+    upload: Option<Arc<Limiter>>,
+    download: Option<Arc<Limiter>>,
+    buckets: Mutex<HashMap<String, BucketLimiters>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(
+        config: &LimitsConfig,
+        limits: HashMap<String, BandwidthLimits>,
+    ) -> Self {
+        Throttle {
+            upload: config.max_upload_rate.map(Limiter::new),
+            download: config.max_download_rate.map(Limiter::new),
+            buckets: Mutex::new(
+                limits
+                    .into_iter()
+                    .map(|(bucket_id, limits)| {
+                        (bucket_id, BucketLimiters::new(limits))
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    pub(crate) fn limits(&self, bucket_id: &str) -> BandwidthLimits {
+        let buckets = self.buckets.lock().expect("valid lock");
+        buckets
+            .get(bucket_id)
+            .map(|bucket| bucket.limits.clone())
+            .unwrap_or_default()
+    }
+
+    /// Replaces the limits of a bucket, the bodies in progress keep the
+    /// previous ones
+    pub(crate) fn set_limits(
+        &self,
+        bucket_id: String,
+        limits: BandwidthLimits,
+    ) {
+        let mut buckets = self.buckets.lock().expect("valid lock");
+        if limits == BandwidthLimits::default() {
+            buckets.remove(&bucket_id);
+        } else {
+            buckets.insert(bucket_id, BucketLimiters::new(limits));
+        }
+    }
+
+    /// Throttles the body of an upload to `bucket_id`
+    pub(crate) fn upload<S>(&self, bucket_id: &str, body: S) -> Throttled<S> {
+        let buckets = self.buckets.lock().expect("valid lock");
+        let bucket = buckets.get(bucket_id).and_then(|b| b.upload.clone());
+        Throttled::new(body, [self.upload.clone(), bucket])
+    }
+
+    /// Throttles the body of a download from `bucket_id`
+    pub(crate) fn download<S>(&self, bucket_id: &str, body: S) -> Throttled<S> {
+        let buckets = self.buckets.lock().expect("valid lock");
+        let bucket = buckets.get(bucket_id).and_then(|b| b.download.clone());
+        Throttled::new(body, [self.download.clone(), bucket])
+    }
+}
+
+/// A body stream whose chunks are paced by limiters
+///
+/// A chunk goes through at once, the next one waits until the slowest
+/// limiter has let the bytes of the previous ones through. Not polling the
+/// body of an upload leaves its bytes in the TCP window, so the client is
+/// slowed down as well.
+pub(crate) struct Throttled<S> {
+    inner: S,
+    limiters: Vec<Arc<Limiter>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    fn new(inner: S, limiters: [Option<Arc<Limiter>>; 2]) -> Self {
+        Throttled {
+            inner,
+            limiters: limiters.into_iter().flatten().collect(),
+            delay: None,
+        }
+    }
+}
+
+impl<S, B, E> Stream for Throttled<S>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: Buf,
+{
+    type Item = Result<B, E>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+
+        let item = ready!(Pin::new(&mut self.inner).poll_next(cx));
+        if let Some(Ok(chunk)) = &item {
+            let bytes = chunk.remaining() as u64;
+            let wait = self
+                .limiters
+                .iter()
+                .map(|limiter| limiter.reserve(bytes))
+                .max()
+                .unwrap_or_default();
+            if !wait.is_zero() {
+                self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use tokio_stream::StreamExt;
+
+    fn config(max_upload_rate: Option<u64>) -> LimitsConfig {
+        LimitsConfig {
+            max_upload_size: None,
+            max_upload_rate,
+            max_download_rate: None,
+            request_timeout: 30,
+            upload_timeout: 600,
+            upload_session_ttl: 600,
+            resumable_upload_ttl: 86400,
+            max_resumable_uploads: 100,
+            header_timeout: 30,
+            idle_timeout: 60,
+            write_timeout: 60,
+            max_concurrent_writes: None,
+            max_concurrent_merkle: None,
+            max_concurrent_proofs: None,
+        }
+    }
+
+    /// Returns the time taken to read 4 chunks of 100 bytes
+    async fn read(
+        body: Throttled<impl Stream<Item = Result<Bytes, ()>> + Unpin>,
+    ) -> Duration {
+        let start = Instant::now();
+        let chunks: Vec<_> = body.collect().await;
+        assert_eq!(chunks.len(), 4);
+        start.elapsed()
+    }
+
+    fn body() -> impl Stream<Item = Result<Bytes, ()>> + Unpin {
+        tokio_stream::iter(vec![Ok(Bytes::from(vec![0u8; 100])); 4])
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        let throttle = Throttle::new(&config(None), HashMap::new());
+        assert!(
+            read(throttle.upload("bucket_id", body())).await
+                < Duration::from_millis(100)
+        );
+
+        // The last chunk waits for the first three at 1000 bytes per second
+        let throttle = Throttle::new(&config(Some(1000)), HashMap::new());
+        let elapsed = read(throttle.upload("bucket_id", body())).await;
+        assert!(elapsed >= Duration::from_millis(300), "{elapsed:?}");
+
+        let limits = BandwidthLimits {
+            upload_rate: None,
+            download_rate: Some(2000),
+        };
+        throttle.set_limits("bucket_id".to_string(), limits.clone());
+        assert_eq!(throttle.limits("bucket_id"), limits);
+        let elapsed = read(throttle.download("bucket_id", body())).await;
+        assert!(elapsed >= Duration::from_millis(150), "{elapsed:?}");
+        assert!(
+            read(throttle.download("other", body())).await
+                < Duration::from_millis(100)
+        );
+
+        throttle
+            .set_limits("bucket_id".to_string(), BandwidthLimits::default());
+        assert_eq!(throttle.limits("bucket_id"), BandwidthLimits::default());
+    }
+}
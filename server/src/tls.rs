@@ -0,0 +1,135 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use clap::Args;
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct TlsConfig {
+    /// PEM certificate chain served over HTTPS, with `--tls-key`
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<String>,
+
+    /// PEM private key of `--tls-cert`
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<String>,
+
+    /// PEM certificates of the authorities issuing the client certificates,
+    /// connections without a valid client certificate are refused
+    #[arg(long, requires = "tls_cert")]
+    pub tls_client_ca: Option<String>,
+
+    /// Grant a client certificate access to a bucket, as
+    /// `<SHA-256 fingerprint>=<bucket_id>`. Buckets with granted
+    /// certificates only accept requests authenticated with one of them
+    #[arg(long = "client-cert", value_parser = parse_grant, requires = "tls_client_ca")]
+    pub client_certs: Vec<(String, String)>,
+}
+
+/// Hex encoded SHA-256 fingerprint of the certificate authenticating the
+/// connection of a request, set as a request extension
+#[derive(Clone, Debug)]
+pub(crate) struct ClientCert(pub String);
+
+/// Address of the client of a connection accepted by
+/// [`crate::listener::bind`], set as a request extension since the routes
+/// do not see the connection
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RemoteAddr(pub SocketAddr);
+
+/// Parses a `<fingerprint>=<bucket_id>` grant, the fingerprint may be
+/// written with colons as printed by `openssl x509 -fingerprint`
+fn parse_grant(grant: &str) -> Result<(String, String), String> {
+    let (fingerprint, bucket_id) = grant
+        .split_once('=')
+        .ok_or("expected <fingerprint>=<bucket_id>")?;
+    let fingerprint = fingerprint.replace(':', "").to_ascii_lowercase();
+
+    match hex::decode(&fingerprint) {
+        Ok(bytes) if bytes.len() == 32 && !bucket_id.is_empty() => {
+            Ok((fingerprint, bucket_id.to_owned()))
+        }
+        _ => Err("expected a SHA-256 fingerprint and a bucket id".to_owned()),
+    }
+}
+
+/// Returns the fingerprint of a DER encoded certificate
+pub(crate) fn fingerprint(cert: &[u8]) -> String {
+    hex::encode(Sha256::digest(cert))
+}
+
+/// Returns the acceptor of the HTTPS connections, requiring client
+/// certificates issued by `--tls-client-ca` if set
+pub(crate) fn acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(server_config(config)?)))
+}
+
+fn server_config(config: &TlsConfig) -> io::Result<ServerConfig> {
+    let path = |path: &Option<String>| path.clone().unwrap_or_default();
+    let certs = read_certs(&path(&config.tls_cert))?;
+    let key = read_key(&path(&config.tls_key))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &config.tls_client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for ca in read_certs(ca)? {
+                roots.add(ca).map_err(io::Error::other)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(io::Error::other)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+pub(crate) fn read_certs(
+    path: &str,
+) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+pub(crate) fn read_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| io::Error::other(format!("no private key in {path}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_grant() {
+        let fingerprint = fingerprint(b"certificate");
+        let upper = fingerprint.to_ascii_uppercase();
+        let colons: Vec<&str> =
+            (0..32).map(|i| &upper[i * 2..i * 2 + 2]).collect();
+
+        let expected = (fingerprint.clone(), "bucket_id".to_string());
+        let grant = format!("{fingerprint}=bucket_id");
+        assert_eq!(parse_grant(&grant), Ok(expected.clone()));
+        let grant = format!("{}=bucket_id", colons.join(":"));
+        assert_eq!(parse_grant(&grant), Ok(expected));
+
+        assert!(parse_grant(&fingerprint).is_err());
+        assert!(parse_grant(&format!("{fingerprint}=")).is_err());
+        assert!(parse_grant("abcd=bucket_id").is_err());
+    }
+}
@@ -0,0 +1,426 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::routing::get;
+use axum::{middleware, Router};
+use merkle::tree as merkle;
+use serde::Deserialize;
+use tracing::{error, info};
+use utoipa::{IntoParams, OpenApi};
+
+use crate::access::{self, Operation};
+use crate::app::{get_bucket, ServerState};
+use crate::blob_store::BlobStore;
+use crate::blobs;
+use crate::client_bucket::ClientBucket;
+use crate::database::DB;
+use crate::errors::{ApiError, ErrorBody, ErrorCode};
+use crate::extract::{Path, Query};
+use crate::limits;
+
+/// Query selecting a version of a bucket
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(crate) struct VersionQuery {
+    /// Version of the bucket, the position of its root in `/roots` counted
+    /// from 0. The current state by default
+    pub version: Option<u64>,
+}
+
+/// Query of the retention request
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RetentionQuery {
+    /// Older version of the bucket
+    from: u64,
+    /// Newer version of the bucket, the current version by default
+    to: Option<u64>,
+}
+
+/// Version routes of the API
+#[derive(OpenApi)]
+#[openapi(paths(handle_retention))]
+pub(crate) struct ApiDoc;
+
+/// Returns the retention proof route
+pub(crate) fn routes(
+    state: Arc<ServerState>,
+    read_timeout: Duration,
+) -> Router {
+    // Retention proof of the files of a version in a later one
+    // GET /retention/:bucket_id?from=&to=
+    Router::new()
+        .route("/retention/:bucket_id", get(handle_retention))
+        .route_layer(middleware::from_fn_with_state(
+            read_timeout,
+            limits::timeout,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Operation::Read),
+            access::bucket_access,
+        ))
+        .with_state(state)
+}
+
+/// Records the files of the current version of a persisted bucket, and
+/// drops its versions beyond `--keep-versions`
+///
+/// The version of a bucket is the sequence number of its last recorded root.
+/// Each kept version references the blobs of its files, so they outlive the
+/// deletion of the files until the version is dropped.
+pub(crate) async fn record(
+    state: &ServerState,
+    bucket: &ClientBucket,
+) -> Result<(), String> {
+    if state.keep_versions == 0 {
+        return Ok(());
+    }
+
+    let bucket_id = &bucket.bucket_id;
+    let db = state.db.write().await;
+    let Some((version, record)) = db.last_root(bucket_id)? else {
+        return Ok(());
+    };
+
+    let mut kept = db.kept_versions(bucket_id)?;
+    if Some(record.root) == bucket.merkle_tree.root_hash()
+        && !kept.contains(&version)
+    {
+        // Files uploaded but not completed yet are not leaves of the version
+        let files: Vec<([u8; 32], String)> = bucket
+            .merkle_tree
+            .leaves()
+            .into_iter()
+            .filter_map(|hash| {
+                let filename = bucket.files.get(&hash)?;
+                Some((hash, filename.clone()))
+            })
+            .collect();
+        for (hash, _) in &files {
+            blobs::share(&db, hash)?;
+        }
+        db.set_version_files(bucket_id, version, Some(&files))?;
+        kept.push(version);
+        info!(event = "version recorded", bucket_id, version);
+    }
+
+    // Versions are kept oldest first
+    let excess = kept.len().saturating_sub(state.keep_versions);
+    for version in kept.into_iter().take(excess) {
+        drop_version(&*state.store, &db, bucket_id, version).await?;
+    }
+
+    Ok(())
+}
+
+/// Drops all the kept versions of a bucket, before it is deleted
+pub(crate) async fn forget(
+    store: &dyn BlobStore,
+    db: &DB,
+    bucket_id: &str,
+) -> Result<(), String> {
+    for version in db.kept_versions(bucket_id)? {
+        drop_version(store, db, bucket_id, version).await?;
+    }
+    Ok(())
+}
+
+/// Drops a kept version of a bucket, releasing the blobs of its files
+///
+/// Callers hold the database write lock, as for any reference update.
+async fn drop_version(
+    store: &dyn BlobStore,
+    db: &DB,
+    bucket_id: &str,
+    version: u64,
+) -> Result<(), String> {
+    let files = db.version_files(bucket_id, version)?.unwrap_or_default();
+    db.set_version_files(bucket_id, version, None)?;
+    for (hash, _) in files {
+        blobs::release(store, db, &hash)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+
+    info!(event = "version dropped", bucket_id, version);
+    Ok(())
+}
+
+/// Returns the hash of the file at `index` in a version of a bucket
+///
+/// Returns `404 Not Found` if the version is not kept or has no such file
+pub(crate) fn file_hash(
+    db: &DB,
+    bucket_id: &str,
+    version: u64,
+    index: usize,
+) -> Result<[u8; 32], ApiError> {
+    let files = db
+        .version_files(bucket_id, version)
+        .map_err(|err| {
+            error!(event = "failed to read version", bucket_id, version, err);
+            ApiError::internal()
+        })?
+        .ok_or_else(|| version_not_found(bucket_id, version))?;
+
+    files
+        .get(index)
+        .map(|(hash, _)| *hash)
+        .ok_or_else(|| ApiError::file_not_found(index))
+}
+
+/// Returns the Merkle tree of a bucket at `version`
+///
+/// The tree is the current tree of the bucket if it has the root of the
+/// version, otherwise it is rebuilt from the files of the version. Returns
+/// `404 Not Found` if the version is not recorded, or its files not kept.
+fn version_tree(
+    db: &DB,
+    bucket: &ClientBucket,
+    version: u64,
+) -> Result<merkle::Tree, ApiError> {
+    let bucket_id = &bucket.bucket_id;
+    let record = db
+        .root_version(bucket_id, version)
+        .map_err(|err| {
+            error!(event = "failed to read root", bucket_id, version, err);
+            ApiError::internal()
+        })?
+        .ok_or_else(|| version_not_found(bucket_id, version))?;
+    if bucket.merkle_tree.root_hash() == Some(record.root) {
+        return Ok(bucket.merkle_tree.clone());
+    }
+
+    let files = db
+        .version_files(bucket_id, version)
+        .map_err(|err| {
+            error!(event = "failed to read version", bucket_id, version, err);
+            ApiError::internal()
+        })?
+        .ok_or_else(|| version_not_found(bucket_id, version))?;
+    let leaves = files.into_iter().map(|(hash, _)| hash).collect();
+    let tree = merkle::Tree::build_from_leaves(leaves);
+    if tree.root_hash() != Some(record.root) {
+        error!(
+            event = "version does not match its root",
+            bucket_id, version
+        );
+        return Err(ApiError::internal());
+    }
+
+    Ok(tree)
+}
+
+/// Handles retention proof request
+///
+/// Proves that version `to` of the bucket kept every file of version `from`.
+/// Replies with the leaves of version `from` and the Merkle proof of each
+/// one in version `to`, bincode encoded. The client rebuilds the root of
+/// `from` from its leaves, checks it against a root of `from` it trusts, and
+/// verifies the proofs against the root of `to`. See
+/// `merkle::Tree::verify_retention` for what the proof does not cover.
+/// Both versions must be the current one or kept with `--keep-versions`.
+#[utoipa::path(
+    get,
+    path = "/retention/{bucket_id}",
+    tag = "proofs",
+    params(("bucket_id" = String, Path, description = "Id of the bucket"), RetentionQuery),
+    responses(
+        (status = 200, description = "Bincode encoded leaves of version `from` and their proofs in version `to`, a `(Vec<[u8; 32]>, Vec<Vec<([u8; 32], u8)>>)`", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 400, description = "`from` is after `to`", body = ErrorBody),
+        (status = 404, description = "The bucket or a version does not exist, or its files are not kept", body = ErrorBody),
+        (status = 409, description = "Version `to` lacks files of version `from`", body = ErrorBody),
+    ),
+    security((), ("api_key" = [])),
+)]
+async fn handle_retention(
+    Path(bucket_id): Path<String>,
+    Query(query): Query<RetentionQuery>,
+    State(state): State<Arc<ServerState>>,
+) -> Result<Vec<u8>, ApiError> {
+    let bucket = get_bucket(bucket_id.clone(), state.clone())
+        .await
+        .ok_or_else(|| ApiError::bucket_not_found(&bucket_id))?;
+    let bucket = bucket.read().await;
+
+    info!(
+        request = "retention",
+        bucket_id,
+        from = query.from,
+        to = query.to
+    );
+
+    let db = state.db.read().await;
+    let to = match query.to {
+        Some(to) => to,
+        None => db
+            .last_root(&bucket_id)
+            .map_err(|err| {
+                error!(event = "failed to read root", bucket_id, err);
+                ApiError::internal()
+            })?
+            .map(|(version, _)| version)
+            .ok_or_else(|| version_not_found(&bucket_id, query.from))?,
+    };
+    if query.from > to {
+        return Err(ApiError::bad_request(format!(
+            "version {} is after version {to}",
+            query.from
+        )));
+    }
+
+    let old_leaves = version_tree(&db, &bucket, query.from)?.leaves();
+    let proofs = version_tree(&db, &bucket, to)?
+        .retention_proof(&old_leaves)
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::Conflict,
+                format!("version {to} lacks files of version {}", query.from),
+            )
+        })?;
+
+    let bytes = bincode::serialize(&(old_leaves, proofs))
+        .expect("valid proof serialization");
+
+    Ok(bytes)
+}
+
+/// Returns the error of a version not recorded or no longer kept
+pub(crate) fn version_not_found(bucket_id: &str, version: u64) -> ApiError {
+    ApiError::new(
+        ErrorCode::NotFound,
+        format!("version {version} of bucket {bucket_id} not found"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app;
+    use crate::batch::SESSION_HEADER;
+    use crate::blob_store::MemoryStore;
+    use crate::Config;
+    use axum::body::Body;
+    use axum::http::{Request, Response, StatusCode};
+    use clap::Parser;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+
+    #[tokio::test]
+    async fn test_versions() {
+        let config = Config::parse_from([
+            "server",
+            "127.0.0.1:0",
+            "--keep-versions",
+            "2",
+        ]);
+        let store = Arc::new(MemoryStore::new());
+        let (addr, server) = app::run_server_with_store(config, store).await;
+        tokio::spawn(server);
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+        let request = |method: &str, path: &str, body: &'static str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(format!("http://{addr}{path}"))
+                .body(Body::from(body))
+                .unwrap();
+            client.request(request)
+        };
+        let body = |res: Response<Incoming>| async move {
+            res.into_body().collect().await.unwrap().to_bytes()
+        };
+        // Each completion seals the bucket, each upload opens a session
+        let upload = |filename: &'static str, content: &'static str| {
+            let client = client.clone();
+            async move {
+                let begin =
+                    Request::post(format!("http://{addr}/begin_upload/b"))
+                        .body(Body::empty())
+                        .unwrap();
+                let res = client.request(begin).await.unwrap();
+                let begin: serde_json::Value = serde_json::from_slice(
+                    &res.into_body().collect().await.unwrap().to_bytes(),
+                )
+                .unwrap();
+                let session = begin["session"].as_str().unwrap().to_owned();
+
+                let paths = [
+                    (format!("/upload_file/b/{filename}"), content),
+                    ("/complete_upload/b".to_owned(), ""),
+                ];
+                for (path, content) in paths {
+                    let request = Request::post(format!("http://{addr}{path}"))
+                        .header(SESSION_HEADER, &session)
+                        .body(Body::from(content))
+                        .unwrap();
+                    let res = client.request(request).await.unwrap();
+                    assert_eq!(res.status(), StatusCode::OK);
+                }
+            }
+        };
+
+        // Versions 0 and 1, then version 2 drops version 0
+        upload("a.txt", "a").await;
+        upload("b.txt", "b").await;
+        let res = request("DELETE", "/file/b/0", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = request("GET", "/root/b?version=1", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let root: serde_json::Value =
+            serde_json::from_slice(&body(res).await).unwrap();
+        assert_eq!(root["leaves_count"], 2);
+        let res = request("GET", "/root/b?version=3", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // The deleted file stays readable at version 1
+        let mut contents = Vec::new();
+        for index in 0..2 {
+            let path = format!("/file/b/{index}?version=1");
+            let res = request("GET", &path, "").await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            contents.push(body(res).await);
+        }
+        contents.sort();
+        assert_eq!(contents, ["a", "b"]);
+
+        let res = request("GET", "/file/b/1", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = request("GET", "/file/b/0?version=0", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // Version 2 deleted a file of version 1
+        let res = request("GET", "/retention/b?from=1&to=2", "")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        // Version 3 only adds a file to version 2
+        upload("c.txt", "c").await;
+        let res = request("GET", "/retention/b?from=2", "").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let (leaves, proofs): (merkle::Level, Vec<Vec<(merkle::Hash, u8)>>) =
+            bincode::deserialize(&body(res).await).unwrap();
+
+        let mut roots = Vec::new();
+        for path in ["/root/b?version=2", "/root/b"] {
+            let res = request("GET", path, "").await.unwrap();
+            let root: serde_json::Value =
+                serde_json::from_slice(&body(res).await).unwrap();
+            let root = hex::decode(root["root"].as_str().unwrap()).unwrap();
+            roots.push(<[u8; 32]>::try_from(root).unwrap());
+        }
+        assert!(merkle::Tree::verify_retention(
+            &leaves, &roots[0], &proofs, &roots[1]
+        ));
+
+        let res = request("GET", "/retention/b?from=3&to=2", "")
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,187 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::body::Body;
+use axum::http::{Method, Request};
+use clap::Args;
+use hmac::{Hmac, Mac};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use sha2::Sha256;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::app::ServerState;
+use crate::events::Event;
+
+/// Number of events waiting for delivery, further events are dropped
+const QUEUE_SIZE: usize = 1024;
+/// Number of deliveries of an event to a webhook before giving up
+const ATTEMPTS: u32 = 3;
+/// Delay before the first retry, doubled at each retry
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Args, Clone, Debug, Default)]
+pub(crate) struct WebhookConfig {
+    /// URL receiving the bucket events (repeatable or comma separated)
+    #[arg(long, value_delimiter = ',', requires = "webhook_secret")]
+    pub webhook_url: Vec<String>,
+
+    /// Secret signing the webhook payloads with HMAC-SHA256
+    #[arg(long, env = "STORAGE_WEBHOOK_SECRET")]
+    pub webhook_secret: Option<String>,
+}
+
+/// Queue of the events to post to the webhooks
+pub(crate) struct Webhooks {
+    sender: Option<mpsc::Sender<Event>>,
+    /// Taken by the delivery task
+    receiver: Mutex<Option<mpsc::Receiver<Event>>>,
+}
+
+impl Webhooks {
+    /// Events are only queued if a webhook is configured
+    pub(crate) fn new(config: &WebhookConfig) -> Self {
+        if config.webhook_url.is_empty() {
+            return Webhooks {
+                sender: None,
+                receiver: Mutex::new(None),
+            };
+        }
+
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        Webhooks {
+            sender: Some(sender),
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+
+    /// Queues an event, without waiting for its delivery
+    pub(crate) fn notify(&self, event: Event) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if let Err(err) = sender.try_send(event) {
+            warn!(event = "webhook event dropped", ?err);
+        }
+    }
+}
+
+/// Returns the hex encoded HMAC-SHA256 of `<timestamp>.<payload>`
+fn sign(secret: &str, timestamp: u64, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts any key size");
+    mac.update(format!("{timestamp}.{payload}").as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Spawns the delivery of the queued events, if a webhook is configured
+pub(crate) fn spawn(state: Arc<ServerState>, config: &WebhookConfig) {
+    let (urls, Some(secret)) =
+        (config.webhook_url.clone(), config.webhook_secret.clone())
+    else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let receiver = state
+            .webhooks
+            .receiver
+            .lock()
+            .expect("unpoisoned lock")
+            .take();
+        let Some(mut receiver) = receiver else {
+            return;
+        };
+
+        while let Some(event) = receiver.recv().await {
+            let payload = match serde_json::to_string(&event) {
+                Ok(payload) => Arc::new(payload),
+                Err(err) => {
+                    error!(event = "invalid webhook event", ?err);
+                    continue;
+                }
+            };
+
+            // A slow webhook does not delay the others
+            for url in &urls {
+                tokio::spawn(deliver(
+                    url.clone(),
+                    secret.clone(),
+                    payload.clone(),
+                ));
+            }
+        }
+    });
+}
+
+/// Posts a payload to a webhook, retrying with an exponential backoff
+///
+/// The payload is signed with the `X-Webhook-Timestamp` and
+/// `X-Webhook-Signature` headers.
+async fn deliver(url: String, secret: String, payload: Arc<String>) {
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: Client<_, Body> =
+        Client::builder(TokioExecutor::new()).build(https);
+
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..=ATTEMPTS {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(&url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Timestamp", timestamp)
+            .header(
+                "X-Webhook-Signature",
+                format!("sha256={}", sign(&secret, timestamp, &payload)),
+            )
+            .body(Body::from(payload.to_string()));
+        let req = match req {
+            Ok(req) => req,
+            Err(err) => {
+                error!(event = "invalid webhook request", url, ?err);
+                return;
+            }
+        };
+
+        match client.request(req).await {
+            Ok(res) if res.status().is_success() => {
+                info!(event = "webhook delivered", url);
+                return;
+            }
+            Ok(res) => {
+                warn!(event = "webhook failed", url, attempt, status = %res.status())
+            }
+            Err(err) => warn!(event = "webhook failed", url, attempt, ?err),
+        }
+
+        if attempt < ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    error!(event = "webhook abandoned", url, payload = payload.as_str());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // The signature covers the timestamp and the payload
+        let signature = sign("secret", 1, "{}");
+        assert_eq!(signature.len(), 64);
+        assert_ne!(signature, sign("secret", 2, "{}"));
+        assert_ne!(signature, sign("other", 1, "{}"));
+        assert_ne!(signature, sign("secret", 1, "{ }"));
+    }
+}